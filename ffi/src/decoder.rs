@@ -30,7 +30,7 @@ use v4l2r::{
     },
     device::queue::{direction::Capture, dqbuf::DqBuffer, qbuf::OutputQueueable, FormatBuilder},
     memory::DmaBufHandle,
-    PixelFormat, PlaneLayout, Rect,
+    FormatField, PixelFormat, PlaneLayout, Rect,
 };
 
 use crate::memory::{
@@ -271,33 +271,27 @@ fn v4l2r_decoder_new_safe(
         input_buffer_size
     );
 
-    let format_builder = |f: FormatBuilder| {
-        let pixel_format = input_format_fourcc.into();
-        let format = match f
-            .set_pixelformat(pixel_format)
+    let pixel_format = PixelFormat::from(input_format_fourcc);
+    let decoder = match decoder.set_output_format(|f| {
+        f.set_pixelformat(pixel_format)
             .set_planes_layout(vec![PlaneLayout {
                 sizeimage: input_buffer_size as u32,
                 ..Default::default()
             }])
-            .apply::<v4l2r::Format>()
-        {
-            Ok(format) if format.pixelformat == pixel_format => format,
-            Ok(_) => {
-                return Err(anyhow::anyhow!(
-                    "Unrecognized OUTPUT format {:?}",
-                    pixel_format
-                ))
+    }) {
+        Ok((decoder, outcome)) => {
+            match outcome.ensure_unchanged(|f| *f == FormatField::PixelFormat) {
+                Ok(format) => debug!(
+                    "Decoder requires input buffer size of: {}",
+                    format.plane_fmt[0].sizeimage
+                ),
+                Err(_) => {
+                    error!("Unrecognized OUTPUT format {:?}", pixel_format);
+                    return std::ptr::null_mut();
+                }
             }
-            Err(e) => return Err(e.into()),
-        };
-        debug!(
-            "Decoder requires input buffer size of: {}",
-            format.plane_fmt[0].sizeimage
-        );
-        Ok(())
-    };
-    let decoder = match decoder.set_output_format(format_builder) {
-        Ok(decoder) => decoder,
+            decoder
+        }
         Err(e) => {
             error!("Error while setting output format: {}", e);
             return std::ptr::null_mut();
@@ -329,9 +323,14 @@ fn v4l2r_decoder_new_safe(
         let decoder = unsafe { decoder_ptr.0.as_mut().unwrap() };
 
         match event {
-            DecoderEvent::FrameDecoded(dqbuf) => {
+            DecoderEvent::FrameDecoded(dqbuf, _) => {
                 frame_decoded_cb(decoder, dqbuf, event_cb, cb_data.0)
             }
+            // The C API has no way to report corrupted frames yet.
+            DecoderEvent::FrameCorrupted(error, _) => {
+                warn!("Frame {} decoded with errors", error.buffer.data.index());
+                frame_decoded_cb(decoder, error.buffer, event_cb, cb_data.0)
+            }
             DecoderEvent::EndOfStream => event_cb(cb_data.0, &mut v4l2r_decoder_event::EndOfStream),
         };
     };
@@ -0,0 +1,34 @@
+//! Runs the sanity checks of `v4l2r::compliance` against a device and prints
+//! their results.
+//!
+//! The exit status is non-zero if any check failed.
+use std::fs::OpenOptions;
+
+use clap::{App, Arg};
+use v4l2r::compliance;
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("V4L2 compliance checks")
+        .arg(
+            Arg::with_name("device")
+                .required(true)
+                .help("Path to the device to check"),
+        )
+        .get_matches();
+
+    let device_path = matches.value_of("device").unwrap_or("/dev/video0");
+    let mut device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device_path)
+        .expect("Failed to open device");
+
+    let report = compliance::run(&mut device);
+    println!("{}", report);
+
+    if !report.is_success() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,308 @@
+//! Minimal bindings to EGL and OpenGL ES, covering what is needed to import
+//! dmabufs as textures.
+use std::{
+    ffi::{c_char, c_void, CStr},
+    os::unix::io::RawFd,
+    ptr,
+};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use v4l2r::Format;
+
+type EGLDisplay = *mut c_void;
+type EGLConfig = *mut c_void;
+type EGLContext = *mut c_void;
+type EGLImageKHR = *mut c_void;
+type EGLint = i32;
+type EGLenum = u32;
+type EGLBoolean = u32;
+type EGLAttrib = isize;
+type GLenum = u32;
+type GLuint = u32;
+
+const EGL_TRUE: EGLBoolean = 1;
+const EGL_NONE: EGLint = 0x3038;
+const EGL_EXTENSIONS: EGLint = 0x3055;
+const EGL_RENDERABLE_TYPE: EGLint = 0x3040;
+const EGL_OPENGL_ES2_BIT: EGLint = 0x0004;
+const EGL_OPENGL_ES_API: EGLenum = 0x30A0;
+const EGL_CONTEXT_CLIENT_VERSION: EGLint = 0x3098;
+const EGL_PLATFORM_SURFACELESS_MESA: EGLenum = 0x31DD;
+const EGL_WIDTH: EGLint = 0x3057;
+const EGL_HEIGHT: EGLint = 0x3056;
+const EGL_LINUX_DMA_BUF_EXT: EGLenum = 0x3270;
+const EGL_LINUX_DRM_FOURCC_EXT: EGLint = 0x3271;
+/// FD, offset and pitch attributes of each dmabuf plane.
+const EGL_DMA_BUF_PLANE_ATTRIBS: [[EGLint; 3]; 3] = [
+    [0x3272, 0x3273, 0x3274],
+    [0x3275, 0x3276, 0x3277],
+    [0x3278, 0x3279, 0x327A],
+];
+const GL_TEXTURE_EXTERNAL_OES: GLenum = 0x8D65;
+
+#[link(name = "EGL")]
+extern "C" {
+    fn eglGetPlatformDisplay(
+        platform: EGLenum,
+        native_display: *mut c_void,
+        attrib_list: *const EGLAttrib,
+    ) -> EGLDisplay;
+    fn eglInitialize(dpy: EGLDisplay, major: *mut EGLint, minor: *mut EGLint) -> EGLBoolean;
+    fn eglTerminate(dpy: EGLDisplay) -> EGLBoolean;
+    fn eglQueryString(dpy: EGLDisplay, name: EGLint) -> *const c_char;
+    fn eglBindAPI(api: EGLenum) -> EGLBoolean;
+    fn eglChooseConfig(
+        dpy: EGLDisplay,
+        attrib_list: *const EGLint,
+        configs: *mut EGLConfig,
+        config_size: EGLint,
+        num_config: *mut EGLint,
+    ) -> EGLBoolean;
+    fn eglCreateContext(
+        dpy: EGLDisplay,
+        config: EGLConfig,
+        share_context: EGLContext,
+        attrib_list: *const EGLint,
+    ) -> EGLContext;
+    fn eglDestroyContext(dpy: EGLDisplay, ctx: EGLContext) -> EGLBoolean;
+    fn eglMakeCurrent(
+        dpy: EGLDisplay,
+        draw: *mut c_void,
+        read: *mut c_void,
+        ctx: EGLContext,
+    ) -> EGLBoolean;
+    fn eglGetProcAddress(procname: *const c_char) -> *mut c_void;
+    fn eglGetError() -> EGLint;
+}
+
+#[link(name = "GLESv2")]
+extern "C" {
+    fn glGenTextures(n: i32, textures: *mut GLuint);
+    fn glBindTexture(target: GLenum, texture: GLuint);
+    fn glDeleteTextures(n: i32, textures: *const GLuint);
+    fn glGetError() -> GLenum;
+}
+
+type CreateImageKhr = unsafe extern "C" fn(
+    dpy: EGLDisplay,
+    ctx: EGLContext,
+    target: EGLenum,
+    buffer: *mut c_void,
+    attrib_list: *const EGLint,
+) -> EGLImageKHR;
+type DestroyImageKhr = unsafe extern "C" fn(dpy: EGLDisplay, image: EGLImageKHR) -> EGLBoolean;
+type ImageTargetTexture2DOes = unsafe extern "C" fn(target: GLenum, image: EGLImageKHR);
+
+fn egl_error(what: &str) -> anyhow::Error {
+    // SAFETY: no precondition.
+    anyhow!("{} failed with EGL error {:#x}", what, unsafe {
+        eglGetError()
+    })
+}
+
+/// Returns the extension function `name`, a nul-terminated string, which must
+/// be of type `F`.
+unsafe fn get_proc<F>(name: &[u8]) -> Result<F> {
+    let name = CStr::from_bytes_with_nul(name)?;
+    let proc = eglGetProcAddress(name.as_ptr());
+    ensure!(!proc.is_null(), "{:?} is not available", name);
+
+    Ok(std::mem::transmute_copy(&proc))
+}
+
+/// A surfaceless OpenGL ES context able to import dmabufs, current on the
+/// thread that created it.
+pub struct EglImporter {
+    display: EGLDisplay,
+    context: EGLContext,
+    create_image: CreateImageKhr,
+    destroy_image: DestroyImageKhr,
+    image_target_texture: ImageTargetTexture2DOes,
+}
+
+impl EglImporter {
+    pub fn new() -> Result<Self> {
+        // SAFETY: all the pointers passed to EGL are either valid or null
+        // where EGL allows it.
+        unsafe {
+            let display =
+                eglGetPlatformDisplay(EGL_PLATFORM_SURFACELESS_MESA, ptr::null_mut(), ptr::null());
+            if display.is_null() {
+                return Err(egl_error("eglGetPlatformDisplay"));
+            }
+            let (mut major, mut minor) = (0, 0);
+            if eglInitialize(display, &mut major, &mut minor) != EGL_TRUE {
+                return Err(egl_error("eglInitialize"));
+            }
+            println!("Initialized EGL {}.{}", major, minor);
+
+            let extensions = CStr::from_ptr(eglQueryString(display, EGL_EXTENSIONS))
+                .to_string_lossy()
+                .into_owned();
+            for extension in [
+                "EGL_EXT_image_dma_buf_import",
+                "EGL_KHR_surfaceless_context",
+            ] {
+                if !extensions.split(' ').any(|e| e == extension) {
+                    eglTerminate(display);
+                    bail!("{} is not supported", extension);
+                }
+            }
+
+            eglBindAPI(EGL_OPENGL_ES_API);
+            let config_attribs = [EGL_RENDERABLE_TYPE, EGL_OPENGL_ES2_BIT, EGL_NONE];
+            let mut config = ptr::null_mut();
+            let mut num_configs = 0;
+            if eglChooseConfig(
+                display,
+                config_attribs.as_ptr(),
+                &mut config,
+                1,
+                &mut num_configs,
+            ) != EGL_TRUE
+                || num_configs == 0
+            {
+                eglTerminate(display);
+                return Err(egl_error("eglChooseConfig"));
+            }
+
+            let context_attribs = [EGL_CONTEXT_CLIENT_VERSION, 2, EGL_NONE];
+            let context =
+                eglCreateContext(display, config, ptr::null_mut(), context_attribs.as_ptr());
+            if context.is_null() {
+                eglTerminate(display);
+                return Err(egl_error("eglCreateContext"));
+            }
+            if eglMakeCurrent(display, ptr::null_mut(), ptr::null_mut(), context) != EGL_TRUE {
+                eglDestroyContext(display, context);
+                eglTerminate(display);
+                return Err(egl_error("eglMakeCurrent"));
+            }
+
+            Ok(EglImporter {
+                display,
+                context,
+                create_image: get_proc(b"eglCreateImageKHR\0")?,
+                destroy_image: get_proc(b"eglDestroyImageKHR\0")?,
+                image_target_texture: get_proc(b"glEGLImageTargetTexture2DOES\0")?,
+            })
+        }
+    }
+
+    /// Import the frame of `format` stored in the dmabufs `fds`, one per
+    /// memory plane, as an external texture.
+    pub fn import(&self, format: &Format, fds: &[RawFd]) -> Result<DmaBufTexture<'_>> {
+        let drm_fourcc = format
+            .pixelformat
+            .to_drm_fourcc()
+            .ok_or_else(|| anyhow!("no DRM equivalent to {}", format.pixelformat))?;
+        let planes = format
+            .color_plane_layouts()
+            .ok_or_else(|| anyhow!("unknown layout for {}", format.pixelformat))?;
+        ensure!(
+            planes.len() <= EGL_DMA_BUF_PLANE_ATTRIBS.len(),
+            "too many planes"
+        );
+
+        let mut attribs = vec![
+            EGL_WIDTH,
+            format.width as EGLint,
+            EGL_HEIGHT,
+            format.height as EGLint,
+            EGL_LINUX_DRM_FOURCC_EXT,
+            drm_fourcc as EGLint,
+        ];
+        for (plane, [fd_attrib, offset_attrib, pitch_attrib]) in
+            planes.iter().zip(EGL_DMA_BUF_PLANE_ATTRIBS)
+        {
+            let fd = *fds
+                .get(plane.mem_plane)
+                .ok_or_else(|| anyhow!("no dmabuf for memory plane {}", plane.mem_plane))?;
+            attribs.extend_from_slice(&[
+                fd_attrib,
+                fd,
+                offset_attrib,
+                plane.offset as EGLint,
+                pitch_attrib,
+                plane.pitch as EGLint,
+            ]);
+        }
+        attribs.push(EGL_NONE);
+
+        // SAFETY: the attribute list is terminated by `EGL_NONE`, and EGL
+        // duplicates the file descriptors it imports.
+        let image = unsafe {
+            (self.create_image)(
+                self.display,
+                ptr::null_mut(),
+                EGL_LINUX_DMA_BUF_EXT,
+                ptr::null_mut(),
+                attribs.as_ptr(),
+            )
+        };
+        if image.is_null() {
+            return Err(egl_error("eglCreateImageKHR"));
+        }
+
+        let mut texture = 0;
+        // SAFETY: our context is current and `image` is valid.
+        let error = unsafe {
+            glGenTextures(1, &mut texture);
+            glBindTexture(GL_TEXTURE_EXTERNAL_OES, texture);
+            (self.image_target_texture)(GL_TEXTURE_EXTERNAL_OES, image);
+            glGetError()
+        };
+        let texture = DmaBufTexture {
+            importer: self,
+            image,
+            texture,
+        };
+        ensure!(
+            error == 0,
+            "glEGLImageTargetTexture2DOES failed with GL error {:#x}",
+            error
+        );
+
+        Ok(texture)
+    }
+}
+
+impl Drop for EglImporter {
+    fn drop(&mut self) {
+        // SAFETY: the display and context are valid until now.
+        unsafe {
+            eglMakeCurrent(
+                self.display,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+            eglDestroyContext(self.display, self.context);
+            eglTerminate(self.display);
+        }
+    }
+}
+
+/// An imported dmabuf, usable as a `GL_TEXTURE_EXTERNAL_OES` texture until
+/// dropped.
+pub struct DmaBufTexture<'a> {
+    importer: &'a EglImporter,
+    image: EGLImageKHR,
+    texture: GLuint,
+}
+
+impl<'a> DmaBufTexture<'a> {
+    pub fn texture(&self) -> GLuint {
+        self.texture
+    }
+}
+
+impl<'a> Drop for DmaBufTexture<'a> {
+    fn drop(&mut self) {
+        // SAFETY: the texture and image have been created by `importer`.
+        unsafe {
+            glDeleteTextures(1, &self.texture);
+            (self.importer.destroy_image)(self.importer.display, self.image);
+        }
+    }
+}
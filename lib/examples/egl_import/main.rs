@@ -0,0 +1,214 @@
+//! Decodes a stream into dmabufs and imports each decoded frame into OpenGL ES
+//! through `EGL_EXT_image_dma_buf_import`, the usual zero-copy path to render
+//! decoded video.
+//!
+//! The frames are only imported and not rendered, since that part depends on
+//! the windowing system of the application.
+mod egl;
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Write},
+    os::unix::io::AsRawFd,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use nix::sys::time::{TimeVal, TimeValLike};
+use v4l2r::{
+    decoder::{
+        format::{fwht::FwhtFrameParser, h264::H264FrameSplitter, StreamSplitter},
+        stateful::{Decoder, GetBufferError},
+        DecoderEvent, FormatChangedReply,
+    },
+    device::{
+        poller::PollError,
+        queue::{handles_provider::PooledHandlesProvider, FormatBuilder},
+    },
+    memory::{DmaBufHandle, MemoryType, MmapHandle},
+    shutdown::ShutdownToken,
+    Format, FormatField, PixelFormat, PlaneLayout, Rect,
+};
+
+use clap::{App, Arg};
+
+use egl::EglImporter;
+
+type CaptureProvider = PooledHandlesProvider<Vec<DmaBufHandle<File>>>;
+
+/// Importer living on the decoder thread, which creates it when the first
+/// frame is decoded.
+struct ThreadImporter(Option<anyhow::Result<EglImporter>>);
+
+// The importer is only created and used on the decoder thread, so its EGL
+// context never changes threads.
+unsafe impl Send for ThreadImporter {}
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("V4L2 decoder to EGLImage")
+        .arg(
+            Arg::with_name("stream")
+                .required(true)
+                .help("Path to the encoded stream to decode"),
+        )
+        .arg(
+            Arg::with_name("device")
+                .required(true)
+                .help("Path to the decoder device file"),
+        )
+        .arg(
+            Arg::with_name("input_format")
+                .long("input_format")
+                .required(false)
+                .takes_value(true)
+                .default_value("fwht")
+                .help("Format of the encoded stream (fwht or h264)"),
+        )
+        .get_matches();
+
+    let stream_path = matches
+        .value_of("stream")
+        .expect("Stream argument not specified");
+    let device_path = matches
+        .value_of("device")
+        .expect("Device argument not specified");
+    let stream = BufReader::new(File::open(stream_path).expect("Compressed stream not found"));
+    let (pixel_format, parser): (PixelFormat, Box<dyn StreamSplitter>) =
+        match matches.value_of("input_format") {
+            Some("fwht") => (
+                b"FWHT".into(),
+                Box::new(FwhtFrameParser::new(stream).expect("No FWHT stream detected")),
+            ),
+            Some("h264") => (
+                b"H264".into(),
+                Box::new(H264FrameSplitter::new(stream).expect("No H.264 stream detected")),
+            ),
+            _ => panic!("Invalid input format specified"),
+        };
+
+    let shutdown = ShutdownToken::install_signal_handlers().expect("Failed to set Ctrl-C handler.");
+
+    // Format of the CAPTURE buffers, required to describe their planes to EGL.
+    let capture_format: Arc<Mutex<Option<Format>>> = Default::default();
+
+    let set_capture_format_cb = {
+        let capture_format = Arc::clone(&capture_format);
+        move |f: FormatBuilder,
+              visible_rect: Rect,
+              min_num_buffers: usize|
+              -> anyhow::Result<FormatChangedReply<CaptureProvider>> {
+            let format = f.format().clone();
+            println!(
+                "New CAPTURE format: {:?} (visible rect: {})",
+                format, visible_rect
+            );
+            format
+                .pixelformat
+                .to_drm_fourcc()
+                .ok_or_else(|| anyhow!("{} cannot be imported into EGL", format.pixelformat))?;
+
+            let dmabufs = utils::dmabuf_exporter::export_dmabufs(&format, min_num_buffers)?;
+            *capture_format.lock().unwrap() = Some(format);
+
+            Ok(FormatChangedReply {
+                provider: PooledHandlesProvider::new(dmabufs),
+                mem_type: MemoryType::DmaBuf,
+                num_buffers: min_num_buffers,
+            })
+        }
+    };
+
+    let mut importer = ThreadImporter(None);
+    let mut num_imported = 0usize;
+    let decoder_event_cb = move |event: DecoderEvent<CaptureProvider>| {
+        let mut dqbuf = match event {
+            DecoderEvent::FrameDecoded(dqbuf, _) => dqbuf,
+            DecoderEvent::FrameCorrupted(e, _) => {
+                eprintln!("Skipping frame {}: {}", e.buffer.data.index(), e);
+                return;
+            }
+            DecoderEvent::EndOfStream => return,
+        };
+        if *dqbuf.data.get_first_plane().bytesused == 0 {
+            return;
+        }
+
+        let importer = match importer.0.get_or_insert_with(EglImporter::new) {
+            Ok(importer) => importer,
+            Err(e) => panic!("Failed to initialize EGL: {:#}", e),
+        };
+        let format = capture_format
+            .lock()
+            .unwrap()
+            .clone()
+            .expect("Frame decoded before the CAPTURE format is set");
+        let handles = dqbuf.take_handles().expect("Decoded buffer has no handles");
+        let fds: Vec<_> = handles
+            .handles()
+            .iter()
+            .map(|handle| handle.0.as_raw_fd())
+            .collect();
+
+        // The texture is released before the dmabufs may be reused by the
+        // decoder, which happens once `handles` is dropped.
+        let texture = importer
+            .import(&format, &fds)
+            .expect("Failed to import decoded frame");
+        num_imported += 1;
+        print!(
+            "\rImported frame {:#5} into texture {}",
+            num_imported,
+            texture.texture()
+        );
+        io::stdout().flush().unwrap();
+    };
+
+    let (decoder, output_outcome) = Decoder::open(Path::new(device_path))
+        .expect("Failed to open device")
+        .set_output_format(|f| {
+            f.set_pixelformat(pixel_format)
+                .set_planes_layout(vec![PlaneLayout {
+                    sizeimage: 1024 * 1024,
+                    ..Default::default()
+                }])
+        })
+        .expect("Failed to set output format");
+    output_outcome
+        .ensure_unchanged(|f| *f == FormatField::PixelFormat)
+        .unwrap_or_else(|_| panic!("{} format not supported by device", pixel_format));
+
+    let mut decoder = decoder
+        .allocate_output_buffers::<Vec<MmapHandle>>(4)
+        .expect("Failed to allocate output buffers")
+        .start(|_| (), decoder_event_cb, set_capture_format_cb)
+        .expect("Failed to start decoder");
+
+    for (bitstream_id, frame) in parser.enumerate() {
+        if shutdown.is_requested() {
+            break;
+        }
+
+        let v4l2_buffer = match decoder.get_buffer() {
+            Ok(buffer) => buffer,
+            Err(GetBufferError::PollError(PollError::EPollWait(nix::errno::Errno::EINTR))) => break,
+            Err(e) => panic!("{}", e),
+        };
+        let mut mapping = v4l2_buffer
+            .get_plane_mapping(0)
+            .expect("Failed to get OUTPUT buffer mapping");
+        mapping.as_mut()[0..frame.len()].copy_from_slice(&frame);
+        drop(mapping);
+
+        v4l2_buffer
+            .set_timestamp(TimeVal::seconds(bitstream_id as i64))
+            .queue(&[frame.len()])
+            .expect("Failed to queue input frame");
+    }
+
+    decoder.drain(true).unwrap();
+    decoder.stop().unwrap();
+    println!();
+}
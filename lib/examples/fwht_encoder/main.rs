@@ -1,18 +1,18 @@
 use utils::framegen::FrameGenerator;
+use utils::segment::{SegmentLimit, SegmentedWriter};
 
 use std::fs::File;
 use std::io::{self, Write};
 use std::path::Path;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::{cell::RefCell, collections::VecDeque, time::Instant};
+use std::time::Duration;
+use std::{cell::RefCell, collections::VecDeque};
 
 use v4l2r::{
     device::{
         poller::PollError,
         queue::{
-            direction::Capture,
-            dqbuf::DqBuffer,
             generic::{GenericBufferHandles, GenericQBuffer, GenericSupportedMemoryType},
             handles_provider::MmapProvider,
             qbuf::OutputQueueable,
@@ -20,12 +20,31 @@ use v4l2r::{
     },
     encoder::*,
     memory::{MmapHandle, UserPtrHandle},
-    Format,
+    shutdown::ShutdownToken,
 };
 
-use anyhow::ensure;
 use clap::{App, Arg};
 
+/// Destination of the encoded stream.
+enum OutputFile {
+    Single(File),
+    Segmented(SegmentedWriter),
+}
+
+impl OutputFile {
+    /// Write the encoded data of `frame` straight from its mapped planes.
+    fn write_frame(&mut self, frame: &CapturedFrame<Vec<MmapHandle>>) -> io::Result<()> {
+        match self {
+            OutputFile::Single(file) => frame.write_to(file).map(|_| ()),
+            OutputFile::Segmented(writer) => {
+                frame.write_to(writer)?;
+                writer.end_frame();
+                Ok(())
+            }
+        }
+    }
+}
+
 fn main() {
     env_logger::init();
 
@@ -56,6 +75,29 @@ fn main() {
                 .takes_value(true)
                 .help("Save the encoded stream to a file"),
         )
+        .arg(
+            Arg::with_name("segment_frames")
+                .long("segment_frames")
+                .takes_value(true)
+                .requires("output_file")
+                .conflicts_with_all(&["segment_bytes", "segment_secs"])
+                .help("Start a new output file every given number of frames"),
+        )
+        .arg(
+            Arg::with_name("segment_bytes")
+                .long("segment_bytes")
+                .takes_value(true)
+                .requires("output_file")
+                .conflicts_with("segment_secs")
+                .help("Start a new output file every given number of bytes"),
+        )
+        .arg(
+            Arg::with_name("segment_secs")
+                .long("segment_secs")
+                .takes_value(true)
+                .requires("output_file")
+                .help("Start a new output file every given number of seconds"),
+        )
         .arg(
             Arg::with_name("output_mem")
                 .long("output_mem")
@@ -68,7 +110,7 @@ fn main() {
 
     let device_path = matches.value_of("device").unwrap_or("/dev/video0");
 
-    let mut stop_after = match clap::value_t!(matches.value_of("num_frames"), usize) {
+    let stop_after = match clap::value_t!(matches.value_of("num_frames"), usize) {
         Ok(v) => Some(v),
         Err(e) if e.kind == clap::ErrorKind::ArgumentNotFound => None,
         Err(e) => panic!("Invalid value for stop_after: {}", e),
@@ -89,9 +131,30 @@ fn main() {
         })
         .unwrap();
 
+    let segment_limit = if let Some(frames) = matches.value_of("segment_frames") {
+        Some(SegmentLimit::Frames(
+            frames.parse().expect("Invalid value for segment_frames"),
+        ))
+    } else if let Some(bytes) = matches.value_of("segment_bytes") {
+        Some(SegmentLimit::Bytes(
+            bytes.parse().expect("Invalid value for segment_bytes"),
+        ))
+    } else {
+        matches.value_of("segment_secs").map(|secs| {
+            SegmentLimit::Duration(Duration::from_secs(
+                secs.parse().expect("Invalid value for segment_secs"),
+            ))
+        })
+    };
+
+    // When segmenting, the output file name is used as a pattern for the
+    // names of the segments.
     let mut output_file = matches
         .value_of("output_file")
-        .map(|s| File::create(s).expect("Invalid output file specified."));
+        .map(|s| match segment_limit {
+            Some(limit) => OutputFile::Segmented(SegmentedWriter::new(s, limit)),
+            None => OutputFile::Single(File::create(s).expect("Invalid output file specified.")),
+        });
 
     let output_mem = match matches.value_of("output_mem") {
         Some("mmap") => GenericSupportedMemoryType::Mmap,
@@ -100,47 +163,25 @@ fn main() {
         _ => panic!("Invalid value for output_mem"),
     };
 
-    let lets_quit = Arc::new(AtomicBool::new(false));
-    // Setup the Ctrl+c handler.
-    {
-        let lets_quit_handler = lets_quit.clone();
-        ctrlc::set_handler(move || {
-            lets_quit_handler.store(true, Ordering::SeqCst);
-        })
-        .expect("Failed to set Ctrl-C handler.");
-    }
+    let shutdown = ShutdownToken::install_signal_handlers().expect("Failed to set Ctrl-C handler.");
 
-    let encoder = Encoder::open(Path::new(&device_path))
+    let (encoder, capture_outcome) = Encoder::open(Path::new(&device_path))
         .expect("Failed to open device")
-        .set_capture_format(|f| {
-            let format: Format = f.set_pixelformat(b"FWHT").apply()?;
-
-            ensure!(
-                format.pixelformat == b"FWHT".into(),
-                "FWHT format not supported"
-            );
-
-            Ok(())
-        })
-        .expect("Failed to set capture format")
+        .set_capture_format(|f| f.set_pixelformat(b"FWHT"))
+        .expect("Failed to set capture format");
+    capture_outcome
+        .ensure_exact()
+        .expect("FWHT format not supported");
+    let (encoder, output_outcome) = encoder
         .set_output_format(|f| {
-            let format: Format = f
-                .set_pixelformat(b"RGB3")
+            f.set_pixelformat(b"RGB3")
                 .set_size(frame_size.0, frame_size.1)
-                .apply()?;
-
-            ensure!(
-                format.pixelformat == b"RGB3".into(),
-                "RGB3 format not supported"
-            );
-            ensure!(
-                format.width as usize == frame_size.0 && format.height as usize == frame_size.1,
-                "Output frame resolution not supported"
-            );
-
-            Ok(())
         })
         .expect("Failed to set output format");
+    // Fails if either the RGB3 format or the frame resolution is not supported.
+    output_outcome
+        .ensure_exact()
+        .expect("Output format not supported");
 
     let output_format = encoder
         .get_output_format()
@@ -209,65 +250,46 @@ fn main() {
         };
     };
 
-    let mut total_size = 0usize;
-    let start_time = Instant::now();
     let poll_count_reader = Arc::new(AtomicUsize::new(0));
     let poll_count_writer = Arc::clone(&poll_count_reader);
-    let mut frame_counter = 0usize;
-    let output_ready_cb = move |cap_dqbuf: DqBuffer<Capture, Vec<MmapHandle>>| {
-        let bytes_used = *cap_dqbuf.data.get_first_plane().bytesused as usize;
-        // Ignore zero-sized buffers.
-        if bytes_used == 0 {
-            return;
-        }
-
-        total_size = total_size.wrapping_add(bytes_used);
-        let elapsed = start_time.elapsed();
-        frame_counter += 1;
-        let fps = frame_counter as f32 / elapsed.as_millis() as f32 * 1000.0;
-        let ppf = poll_count_reader.load(Ordering::SeqCst) as f32 / frame_counter as f32;
-        print!(
-            "\rEncoded buffer {:#5}, index: {:#2}), bytes used:{:#6} total encoded size:{:#8} fps: {:#5.2} ppf: {:#4.2}" ,
-            cap_dqbuf.data.sequence(),
-            cap_dqbuf.data.index(),
-            bytes_used,
-            total_size,
-            fps,
-            ppf,
-        );
-        io::stdout().flush().unwrap();
-
+    let output_ready_cb = move |cap_dqbuf: CapturedFrame<Vec<MmapHandle>>| {
+        // Corrupted frames would break the decoding of the saved stream.
+        let cap_dqbuf = match cap_dqbuf.into_result() {
+            Ok(frame) => frame,
+            Err(e) => {
+                eprintln!("Skipping frame {}: {}", e.buffer.index(), e);
+                return;
+            }
+        };
         if let Some(ref mut output) = output_file {
-            let mapping = cap_dqbuf
-                .get_plane_mapping(0)
-                .expect("Failed to map capture buffer");
             output
-                .write_all(mapping.as_ref())
+                .write_frame(&cap_dqbuf)
                 .expect("Error while writing output data");
         }
     };
 
-    let mut encoder = encoder
+    let encoder = encoder
         .allocate_output_buffers_generic::<GenericBufferHandles>(output_mem, NUM_BUFFERS)
         .expect("Failed to allocate OUTPUT buffers")
         .allocate_capture_buffers(NUM_BUFFERS, MmapProvider::new(&capture_format))
         .expect("Failed to allocate CAPTURE buffers")
         .set_poll_counter(poll_count_writer)
+        .set_shutdown_token(shutdown.clone());
+    let encoder = match stop_after {
+        Some(limit) => encoder.set_frame_limit(limit),
+        None => encoder,
+    };
+    let mut encoder = encoder
         .start(input_done_cb, output_ready_cb)
         .expect("Failed to start encoder");
 
-    while !lets_quit.load(Ordering::SeqCst) {
-        if let Some(max_cpt) = &mut stop_after {
-            if *max_cpt == 0 {
-                break;
-            }
-            *max_cpt -= 1;
-        }
-
+    while !shutdown.is_requested() {
         let v4l2_buffer = match encoder.get_buffer() {
             Ok(buffer) => buffer,
+            // All the requested frames have been submitted.
+            Err(GetBufferError::FrameLimitReached) => break,
             // If we got interrupted while waiting for a buffer, just exit normally.
-            Err(GetBufferError::PollError(PollError::EPollWait(nix::errno::Errno::EINTR))) => break,
+            Err(GetBufferError::PollError(PollError::Interrupted)) => break,
             Err(e) => panic!("{}", e),
         };
         let bytes_used = frame_gen.frame_size();
@@ -313,6 +335,14 @@ fn main() {
                     .expect("Failed to queue input frame");
             }
         }
+
+        let stats = encoder.stats();
+        let ppf = poll_count_reader.load(Ordering::SeqCst) as f32 / stats.frames_out.max(1) as f32;
+        print!(
+            "\rEncoded frames {:#5}, total encoded size:{:#8} bitrate: {:#8} fps: {:#5.2} ppf: {:#4.2}",
+            stats.frames_out, stats.bytes_out, stats.bitrate, stats.fps, ppf,
+        );
+        io::stdout().flush().unwrap();
     }
 
     encoder.stop().unwrap();
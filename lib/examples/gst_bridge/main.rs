@@ -0,0 +1,357 @@
+//! Example connecting a V4L2 stateful encoder to GStreamer pipelines.
+//!
+//! Frames to encode are either pulled from the `appsink` element of the input
+//! pipeline, or generated if no input pipeline is given. The encoded FWHT
+//! frames are pushed into the `appsrc` element of the output pipeline, if any.
+//! The GStreamer timestamps of the input frames are carried through the
+//! encoder using the V4L2 buffer timestamps.
+use utils::framegen::FrameGenerator;
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use gst::prelude::*;
+use nix::sys::time::{TimeVal, TimeValLike};
+use v4l2r::{
+    device::{poller::PollError, queue::handles_provider::MmapProvider},
+    encoder::*,
+    memory::MmapHandle,
+};
+
+use clap::{App, Arg};
+
+/// Name of the `appsink` element frames are pulled from in the input pipeline.
+const APPSINK_NAME: &str = "sink";
+/// Name of the `appsrc` element encoded frames are pushed into in the output
+/// pipeline.
+const APPSRC_NAME: &str = "src";
+
+/// Duration of the generated frames, in microseconds, when there is no input
+/// pipeline.
+const GENERATED_FRAME_DURATION_US: i64 = 33_333;
+
+fn launch_pipeline(description: &str) -> gst::Pipeline {
+    gst::parse_launch(description)
+        .expect("Invalid pipeline description")
+        .downcast::<gst::Pipeline>()
+        .expect("Pipeline description must contain more than one element")
+}
+
+fn get_app_element<T: IsA<gst::Element>>(pipeline: &gst::Pipeline, name: &str) -> T {
+    pipeline
+        .by_name(name)
+        .unwrap_or_else(|| panic!("No element named \"{}\" in the pipeline", name))
+        .dynamic_cast::<T>()
+        .unwrap_or_else(|_| panic!("Element \"{}\" has the wrong type", name))
+}
+
+fn clock_time_to_timeval(time: gst::ClockTime) -> TimeVal {
+    TimeVal::microseconds(time.useconds() as i64)
+}
+
+fn timeval_to_clock_time(time: &v4l2r::bindings::timeval) -> gst::ClockTime {
+    gst::ClockTime::from_useconds(time.tv_sec as u64 * 1_000_000 + time.tv_usec as u64)
+}
+
+/// Waits until `pipeline` has processed its end of stream, and stops it.
+fn finish_pipeline(pipeline: &gst::Pipeline) {
+    let bus = pipeline.bus().unwrap();
+    if let Some(msg) = bus.timed_pop_filtered(
+        gst::ClockTime::NONE,
+        &[gst::MessageType::Eos, gst::MessageType::Error],
+    ) {
+        if let gst::MessageView::Error(err) = msg.view() {
+            eprintln!("Error in pipeline: {} ({:?})", err.error(), err.debug());
+        }
+    }
+    pipeline
+        .set_state(gst::State::Null)
+        .expect("Failed to stop pipeline");
+}
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("V4L2 encoder to GStreamer bridge")
+        .arg(
+            Arg::with_name("num_frames")
+                .long("stop_after")
+                .takes_value(true)
+                .help("Stop after encoding a given number of buffers"),
+        )
+        .arg(
+            Arg::with_name("device")
+                .required(true)
+                .help("Path to the vicodec device file"),
+        )
+        .arg(
+            Arg::with_name("frame_size")
+                .long("frame_size")
+                .required(false)
+                .takes_value(true)
+                .default_value("640x480")
+                .help("Size of the frames to encode (e.g. \"640x480\")"),
+        )
+        .arg(
+            Arg::with_name("input_pipeline")
+                .long("input")
+                .required(false)
+                .takes_value(true)
+                .help("Pipeline producing the frames to encode, ending with \"appsink name=sink\""),
+        )
+        .arg(
+            Arg::with_name("output_pipeline")
+                .long("output")
+                .required(false)
+                .takes_value(true)
+                .help("Pipeline consuming the encoded frames, starting with \"appsrc name=src\""),
+        )
+        .get_matches();
+
+    let device_path = matches.value_of("device").unwrap_or("/dev/video0");
+
+    let mut stop_after = match clap::value_t!(matches.value_of("num_frames"), usize) {
+        Ok(v) => Some(v),
+        Err(e) if e.kind == clap::ErrorKind::ArgumentNotFound => None,
+        Err(e) => panic!("Invalid value for stop_after: {}", e),
+    };
+
+    let frame_size = matches
+        .value_of("frame_size")
+        .map(|s| {
+            const ERROR_MSG: &str = "Invalid parameter for frame_size";
+            let split: Vec<&str> = s.split('x').collect();
+            if split.len() != 2 {
+                panic!("{}", ERROR_MSG);
+            }
+            let width: usize = split[0].parse().expect(ERROR_MSG);
+            let height: usize = split[1].parse().expect(ERROR_MSG);
+
+            (width, height)
+        })
+        .unwrap();
+
+    gst::init().expect("Failed to initialize GStreamer");
+
+    let lets_quit = Arc::new(AtomicBool::new(false));
+    // Setup the Ctrl+c handler.
+    {
+        let lets_quit_handler = lets_quit.clone();
+        ctrlc::set_handler(move || {
+            lets_quit_handler.store(true, Ordering::SeqCst);
+        })
+        .expect("Failed to set Ctrl-C handler.");
+    }
+
+    let (encoder, capture_outcome) = Encoder::open(Path::new(&device_path))
+        .expect("Failed to open device")
+        .set_capture_format(|f| f.set_pixelformat(b"FWHT"))
+        .expect("Failed to set capture format");
+    capture_outcome
+        .ensure_exact()
+        .expect("FWHT format not supported");
+    let (encoder, output_outcome) = encoder
+        .set_output_format(|f| {
+            f.set_pixelformat(b"RGB3")
+                .set_size(frame_size.0, frame_size.1)
+        })
+        .expect("Failed to set output format");
+    // Fails if either the RGB3 format or the frame resolution is not supported.
+    output_outcome
+        .ensure_exact()
+        .expect("Output format not supported");
+
+    let output_format = encoder
+        .get_output_format()
+        .expect("Failed to get output format");
+    let capture_format = encoder
+        .get_capture_format()
+        .expect("Failed to get capture format");
+    let (width, height) = (output_format.width, output_format.height);
+    let v4l2_stride = output_format.plane_fmt[0].bytesperline as usize;
+
+    // Rows of GStreamer RGB frames are aligned to 4 bytes.
+    let gst_stride = (width as usize * 3 + 3) & !3;
+
+    let input = matches.value_of("input_pipeline").map(|description| {
+        let pipeline = launch_pipeline(description);
+        let appsink: gst_app::AppSink = get_app_element(&pipeline, APPSINK_NAME);
+        // Have the input pipeline produce frames in the format of the encoder.
+        appsink.set_caps(Some(
+            &gst::Caps::builder("video/x-raw")
+                .field("format", "RGB")
+                .field("width", width as i32)
+                .field("height", height as i32)
+                .build(),
+        ));
+        pipeline
+            .set_state(gst::State::Playing)
+            .expect("Failed to start input pipeline");
+
+        (pipeline, appsink)
+    });
+
+    let output = matches.value_of("output_pipeline").map(|description| {
+        let pipeline = launch_pipeline(description);
+        let appsrc: gst_app::AppSrc = get_app_element(&pipeline, APPSRC_NAME);
+        appsrc.set_caps(Some(
+            &gst::Caps::builder("video/x-fwht")
+                .field("width", width as i32)
+                .field("height", height as i32)
+                .build(),
+        ));
+        appsrc.set_format(gst::Format::Time);
+        pipeline
+            .set_state(gst::State::Playing)
+            .expect("Failed to start output pipeline");
+
+        (pipeline, appsrc)
+    });
+
+    let mut frame_gen = match input {
+        Some(_) => None,
+        None => Some(
+            FrameGenerator::new(width as usize, height as usize, v4l2_stride)
+                .expect("Failed to create frame generator"),
+        ),
+    };
+
+    // MMAP OUTPUT buffers do not need to be recycled.
+    let input_done_cb = |_buffer: CompletedOutputBuffer<Vec<MmapHandle>>| {};
+
+    let appsrc = output.as_ref().map(|(_, appsrc)| appsrc.clone());
+    let mut frame_counter = 0usize;
+    let output_ready_cb = move |cap_dqbuf: CapturedFrame<Vec<MmapHandle>>| {
+        let bytes_used = *cap_dqbuf.data.get_first_plane().bytesused as usize;
+        // Ignore zero-sized buffers.
+        if bytes_used == 0 {
+            return;
+        }
+
+        frame_counter += 1;
+        print!(
+            "\rEncoded buffer {:#5}, index: {:#2}, bytes used:{:#6}",
+            frame_counter,
+            cap_dqbuf.data.index(),
+            bytes_used,
+        );
+        io::stdout().flush().unwrap();
+
+        if let Some(appsrc) = &appsrc {
+            let mapping = cap_dqbuf
+                .get_plane_mapping(0)
+                .expect("Failed to map capture buffer");
+            let mut buffer = gst::Buffer::from_mut_slice(mapping.as_ref()[..bytes_used].to_vec());
+            // The encoder copies the timestamp of the OUTPUT buffer a frame
+            // has been encoded from.
+            buffer
+                .get_mut()
+                .unwrap()
+                .set_pts(timeval_to_clock_time(&cap_dqbuf.timestamp()));
+            if let Err(e) = appsrc.push_buffer(buffer) {
+                eprintln!("Failed to push encoded frame: {:?}", e);
+            }
+        }
+    };
+
+    let mut encoder = encoder
+        .allocate_output_buffers::<Vec<MmapHandle>>(2)
+        .expect("Failed to allocate OUTPUT buffers")
+        .allocate_capture_buffers(2, MmapProvider::new(&capture_format))
+        .expect("Failed to allocate CAPTURE buffers")
+        .start(input_done_cb, output_ready_cb)
+        .expect("Failed to start encoder");
+
+    let mut generated_frames = 0i64;
+    while !lets_quit.load(Ordering::SeqCst) {
+        if let Some(max_cpt) = &mut stop_after {
+            if *max_cpt == 0 {
+                break;
+            }
+            *max_cpt -= 1;
+        }
+
+        // Get the next frame from the input pipeline before waiting for a
+        // V4L2 buffer, as it may never come.
+        let sample = match &input {
+            Some((_, appsink)) => match appsink.pull_sample() {
+                Ok(sample) => Some(sample),
+                // End of stream.
+                Err(_) => break,
+            },
+            None => None,
+        };
+
+        let v4l2_buffer = match encoder.get_buffer() {
+            Ok(buffer) => buffer,
+            // If we got interrupted while waiting for a buffer, just exit normally.
+            Err(GetBufferError::PollError(PollError::EPollWait(nix::errno::Errno::EINTR))) => break,
+            Err(e) => panic!("{}", e),
+        };
+        let mut mapping = v4l2_buffer
+            .get_plane_mapping(0)
+            .expect("Failed to get MMAP mapping");
+
+        let (bytes_used, timestamp) = match (&sample, &mut frame_gen) {
+            (Some(sample), _) => {
+                let buffer = sample.buffer().expect("Sample without buffer");
+                let map = buffer.map_readable().expect("Failed to map sample");
+                // Copy line by line as the strides of GStreamer and V4L2
+                // frames may differ.
+                for (src, dst) in map
+                    .as_slice()
+                    .chunks(gst_stride)
+                    .zip(mapping.as_mut().chunks_mut(v4l2_stride))
+                    .take(height as usize)
+                {
+                    let line_size = width as usize * 3;
+                    dst[..line_size].copy_from_slice(&src[..line_size]);
+                }
+
+                (
+                    v4l2_stride * height as usize,
+                    buffer
+                        .pts()
+                        .map(clock_time_to_timeval)
+                        .unwrap_or_else(TimeVal::zero),
+                )
+            }
+            (None, Some(frame_gen)) => {
+                frame_gen
+                    .next_frame(&mut mapping)
+                    .expect("Failed to generate frame");
+                generated_frames += 1;
+
+                (
+                    frame_gen.frame_size(),
+                    TimeVal::microseconds(generated_frames * GENERATED_FRAME_DURATION_US),
+                )
+            }
+            (None, None) => unreachable!(),
+        };
+        drop(mapping);
+
+        v4l2_buffer
+            .set_timestamp(timestamp)
+            .queue(&[bytes_used])
+            .expect("Failed to queue input frame");
+    }
+
+    encoder.stop().unwrap();
+
+    // Insert new line since we were overwriting the same one
+    println!();
+
+    if let Some((pipeline, _)) = input {
+        pipeline
+            .set_state(gst::State::Null)
+            .expect("Failed to stop input pipeline");
+    }
+
+    if let Some((pipeline, appsrc)) = output {
+        let _ = appsrc.end_of_stream();
+        finish_pipeline(&pipeline);
+    }
+}
@@ -0,0 +1,278 @@
+//! Encodes generated frames with a stateful encoder and streams them over RTP.
+//!
+//! FWHT frames (e.g. from `vicodec`) are sent as a raw payload, while H.264
+//! access units are packetized following RFC 6184. The encoded data is sent
+//! directly from the mapped CAPTURE buffers using vectored I/O, and the RTP
+//! timestamp of each frame is retrieved from the timestamp of its CAPTURE
+//! buffer with a `TimestampTracker`.
+//!
+//! The H.264 stream can be played with e.g.
+//! `gst-launch-1.0 udpsrc port=5004 caps="application/x-rtp,encoding-name=H264" ! rtph264depay ! avdec_h264 ! autovideosink`.
+mod rtp;
+
+use std::{
+    convert::TryInto,
+    io::{self, Write},
+    net::UdpSocket,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use clap::{App, Arg};
+use utils::framegen::FrameGenerator;
+use v4l2r::{
+    device::{poller::PollError, queue::handles_provider::MmapProvider},
+    encoder::*,
+    memory::MmapHandle,
+    shutdown::ShutdownToken,
+    timestamp::TimestampTracker,
+};
+
+use rtp::{RtpSender, MIN_MTU, VIDEO_CLOCK_RATE};
+
+const NUM_BUFFERS: usize = 4;
+/// Dynamic payload type used for the stream.
+const PAYLOAD_TYPE: u8 = 96;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Fwht,
+    H264,
+}
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("V4L2 encoder RTP streamer")
+        .arg(
+            Arg::with_name("device")
+                .required(true)
+                .help("Path to the encoder device file"),
+        )
+        .arg(
+            Arg::with_name("destination")
+                .long("destination")
+                .takes_value(true)
+                .default_value("127.0.0.1:5004")
+                .help("Address to send the RTP packets to"),
+        )
+        .arg(
+            Arg::with_name("codec")
+                .long("codec")
+                .takes_value(true)
+                .default_value("fwht")
+                .help("Codec to encode with (fwht or h264)"),
+        )
+        .arg(
+            Arg::with_name("input_format")
+                .long("input_format")
+                .takes_value(true)
+                .default_value("YU12")
+                .help("Pixel format of the frames to encode"),
+        )
+        .arg(
+            Arg::with_name("frame_size")
+                .long("frame_size")
+                .takes_value(true)
+                .default_value("640x480")
+                .help("Size of the frames to encode (e.g. \"640x480\")"),
+        )
+        .arg(
+            Arg::with_name("framerate")
+                .long("framerate")
+                .takes_value(true)
+                .default_value("30")
+                .help("Frame rate of the stream, used for the RTP timestamps"),
+        )
+        .arg(
+            Arg::with_name("mtu")
+                .long("mtu")
+                .takes_value(true)
+                .default_value("1400")
+                .help("Maximum size of the RTP packets"),
+        )
+        .arg(
+            Arg::with_name("num_frames")
+                .long("stop_after")
+                .takes_value(true)
+                .help("Stop after encoding a given number of frames"),
+        )
+        .get_matches();
+
+    let device_path = matches.value_of("device").unwrap();
+    let codec = match matches.value_of("codec") {
+        Some("fwht") => Codec::Fwht,
+        Some("h264") => Codec::H264,
+        _ => panic!("Invalid value for codec"),
+    };
+    let input_format = matches.value_of("input_format").unwrap().as_bytes();
+    let input_format: [u8; 4] = input_format
+        .try_into()
+        .expect("Pixel format must be a 4 characters code");
+    let frame_size = matches
+        .value_of("frame_size")
+        .map(|s| {
+            const ERROR_MSG: &str = "Invalid parameter for frame_size";
+            let split: Vec<&str> = s.split('x').collect();
+            if split.len() != 2 {
+                panic!("{}", ERROR_MSG);
+            }
+            let width: usize = split[0].parse().expect(ERROR_MSG);
+            let height: usize = split[1].parse().expect(ERROR_MSG);
+
+            (width, height)
+        })
+        .unwrap();
+    let framerate: u32 =
+        clap::value_t!(matches.value_of("framerate"), u32).expect("Invalid framerate");
+    let mtu: usize = clap::value_t!(matches.value_of("mtu"), usize).expect("Invalid MTU");
+    if mtu < MIN_MTU {
+        panic!("MTU must be at least {} bytes", MIN_MTU);
+    }
+    let stop_after = match clap::value_t!(matches.value_of("num_frames"), usize) {
+        Ok(v) => Some(v),
+        Err(e) if e.kind == clap::ErrorKind::ArgumentNotFound => None,
+        Err(e) => panic!("Invalid value for stop_after: {}", e),
+    };
+
+    let socket = UdpSocket::bind("0.0.0.0:0").expect("Failed to create socket");
+    socket
+        .connect(matches.value_of("destination").unwrap())
+        .expect("Failed to connect socket");
+    let mut sender = RtpSender::new(socket, PAYLOAD_TYPE, mtu);
+
+    let shutdown = ShutdownToken::install_signal_handlers().expect("Failed to set Ctrl-C handler.");
+
+    let (encoder, capture_outcome) = Encoder::open(Path::new(device_path))
+        .expect("Failed to open device")
+        .set_capture_format(|f| {
+            f.set_pixelformat(match codec {
+                Codec::Fwht => b"FWHT",
+                Codec::H264 => b"H264",
+            })
+        })
+        .expect("Failed to set capture format");
+    capture_outcome.ensure_exact().expect("Codec not supported");
+    let (encoder, output_outcome) = encoder
+        .set_output_format(|f| {
+            f.set_pixelformat(&input_format)
+                .set_size(frame_size.0, frame_size.1)
+        })
+        .expect("Failed to set output format");
+    output_outcome
+        .ensure_exact()
+        .expect("Output format not supported");
+
+    let output_format = encoder
+        .get_output_format()
+        .expect("Failed to get output format");
+    let capture_format = encoder
+        .get_capture_format()
+        .expect("Failed to get capture format");
+    let mut frame_gen =
+        FrameGenerator::from_format(&output_format).expect("Failed to create frame generator");
+
+    // Associates the OUTPUT buffers with the RTP timestamp of their frame.
+    let tracker = Arc::new(Mutex::new(TimestampTracker::<u32>::new()));
+
+    let output_tracker = Arc::clone(&tracker);
+    let output_ready_cb = move |frame: CapturedFrame<Vec<MmapHandle>>| {
+        // Always retrieve the RTP timestamp, so skipped frames do not leave
+        // their entry in the tracker.
+        let rtp_timestamp = output_tracker.lock().unwrap().take(&frame.timestamp());
+        let frame = match frame.into_result() {
+            Ok(frame) => frame,
+            Err(e) => {
+                eprintln!("Skipping frame {}: {}", e.buffer.index(), e);
+                return;
+            }
+        };
+        let rtp_timestamp = match rtp_timestamp {
+            Some(rtp_timestamp) => rtp_timestamp,
+            None => {
+                eprintln!("Encoded frame with unknown timestamp");
+                return;
+            }
+        };
+
+        let res = match codec {
+            Codec::Fwht => frame
+                .write_to(&mut sender.raw_frame(rtp_timestamp, frame.bytes_used()))
+                .map(|_| ()),
+            Codec::H264 => match frame.get_plane_mappings() {
+                Some(mappings) => match mappings.contiguous() {
+                    Some(access_unit) => sender.send_h264(rtp_timestamp, access_unit),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "encoded frame is not contiguous",
+                    )),
+                },
+                None => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "failed to map encoded frame",
+                )),
+            },
+        };
+        if let Err(e) = res {
+            eprintln!("Failed to send frame: {}", e);
+        }
+
+        print!("\rSent {:#8} RTP packets", sender.packets_sent());
+        io::stdout().flush().unwrap();
+    };
+
+    let encoder = encoder
+        .allocate_output_buffers::<Vec<MmapHandle>>(NUM_BUFFERS)
+        .expect("Failed to allocate OUTPUT buffers")
+        .allocate_capture_buffers(NUM_BUFFERS, MmapProvider::new(&capture_format))
+        .expect("Failed to allocate CAPTURE buffers")
+        .set_shutdown_token(shutdown.clone());
+    let encoder = match stop_after {
+        Some(limit) => encoder.set_frame_limit(limit),
+        None => encoder,
+    };
+    let mut encoder = encoder
+        .start(|_| (), output_ready_cb)
+        .expect("Failed to start encoder");
+
+    let start = Instant::now();
+    let mut frame_index: u64 = 0;
+    while !shutdown.is_requested() {
+        // Generate the frames in real time, as a camera would.
+        let frame_time = start + Duration::from_secs(frame_index) / framerate;
+        if let Some(delay) = frame_time.checked_duration_since(Instant::now()) {
+            std::thread::sleep(delay);
+        }
+
+        let v4l2_buffer = match encoder.get_buffer() {
+            Ok(buffer) => buffer,
+            // All the requested frames have been submitted.
+            Err(GetBufferError::FrameLimitReached) => break,
+            // If we got interrupted while waiting for a buffer, just exit normally.
+            Err(GetBufferError::PollError(PollError::Interrupted)) => break,
+            Err(e) => panic!("{}", e),
+        };
+        let mut mapping = v4l2_buffer
+            .get_plane_mapping(0)
+            .expect("Failed to get OUTPUT buffer mapping");
+        frame_gen
+            .next_frame(&mut mapping)
+            .expect("Failed to generate frame");
+        drop(mapping);
+
+        // The RTP timestamp wraps around, which is expected by receivers.
+        let rtp_timestamp = (frame_index * VIDEO_CLOCK_RATE as u64 / framerate as u64) as u32;
+        let timestamp = tracker.lock().unwrap().submit(rtp_timestamp);
+        v4l2_buffer
+            .set_timestamp(timestamp)
+            .queue(&[frame_gen.frame_size()])
+            .expect("Failed to queue input frame");
+        frame_index += 1;
+    }
+
+    encoder.stop().unwrap();
+
+    // Insert new line since we were overwriting the same one
+    println!();
+}
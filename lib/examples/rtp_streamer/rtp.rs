@@ -0,0 +1,230 @@
+//! Minimal RTP (RFC 3550) packetization of encoded frames.
+//!
+//! Packets are sent with `sendmsg`, gathering the RTP header and the payload
+//! straight from the mapped CAPTURE buffer, so the encoded data is never copied
+//! in user space.
+use std::{
+    cmp::min,
+    io::{self, IoSlice, Write},
+    net::UdpSocket,
+    os::unix::io::AsRawFd,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use nix::libc;
+
+/// Size of the fixed RTP header, without CSRCs or extensions.
+const HEADER_SIZE: usize = 12;
+/// Size of the FU indicator and FU header of H.264 FU-A packets.
+const FU_A_HEADER_SIZE: usize = 2;
+/// NAL unit type of H.264 FU-A packets.
+const FU_A_TYPE: u8 = 28;
+
+/// Smallest MTU packets can be sent with, which leaves room for one byte of
+/// payload in the H.264 FU-A packets.
+pub const MIN_MTU: usize = HEADER_SIZE + FU_A_HEADER_SIZE + 1;
+
+/// RTP clock rate of video payloads.
+pub const VIDEO_CLOCK_RATE: u32 = 90_000;
+
+/// Sends the packets of a single RTP stream to the peer `socket` is
+/// connected to.
+pub struct RtpSender {
+    socket: UdpSocket,
+    payload_type: u8,
+    ssrc: u32,
+    sequence: u16,
+    mtu: usize,
+    packets_sent: u64,
+}
+
+impl RtpSender {
+    /// Create a sender for payload type `payload_type` (usually a dynamic one,
+    /// i.e. between 96 and 127) whose packets fit in `mtu` bytes.
+    ///
+    /// `mtu` must be at least `MIN_MTU`.
+    pub fn new(socket: UdpSocket, payload_type: u8, mtu: usize) -> Self {
+        // The SSRC only needs to be unique among the participants of the
+        // session, which the time and our PID are good enough for.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let ssrc = now.subsec_nanos() ^ std::process::id().rotate_left(16);
+
+        RtpSender {
+            socket,
+            payload_type: payload_type & 0x7f,
+            ssrc,
+            sequence: (ssrc >> 16) as u16,
+            mtu,
+            packets_sent: 0,
+        }
+    }
+
+    pub fn packets_sent(&self) -> u64 {
+        self.packets_sent
+    }
+
+    /// Maximum number of payload bytes per packet.
+    fn max_payload(&self) -> usize {
+        self.mtu - HEADER_SIZE
+    }
+
+    /// Send one packet made of the RTP header followed by `payload`.
+    fn send(&mut self, timestamp: u32, marker: bool, payload: &[&[u8]]) -> io::Result<()> {
+        let mut header = [0u8; HEADER_SIZE];
+        // Version 2, no padding, extension or CSRC.
+        header[0] = 2 << 6;
+        header[1] = self.payload_type | if marker { 0x80 } else { 0 };
+        header[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        header[4..8].copy_from_slice(&timestamp.to_be_bytes());
+        header[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+
+        let mut iov: Vec<IoSlice> = std::iter::once(IoSlice::new(&header))
+            .chain(payload.iter().map(|part| IoSlice::new(part)))
+            .collect();
+        // Safe because `msghdr` is a plain C structure for which all zeroes
+        // means no address, control data or flags.
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        // `IoSlice` is guaranteed to be ABI-compatible with `iovec`.
+        msg.msg_iov = iov.as_mut_ptr() as *mut libc::iovec;
+        msg.msg_iovlen = iov.len() as _;
+
+        // Safe because `msg` points to `iov`, which outlives the call and whose
+        // entries all point to valid memory.
+        let ret = unsafe { libc::sendmsg(self.socket.as_raw_fd(), &msg, 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+        self.packets_sent += 1;
+        Ok(())
+    }
+
+    /// Returns a writer sending the `len` bytes of a frame written into it as
+    /// raw payload packets, the last of which has the marker bit set.
+    ///
+    /// Each vectored write into it sends one packet gathering as much of the
+    /// provided buffers as fits, which makes it a good match for
+    /// `CapturedFrame::write_to`.
+    pub fn raw_frame(&mut self, timestamp: u32, len: usize) -> RawFrameWriter<'_> {
+        RawFrameWriter {
+            sender: self,
+            timestamp,
+            remaining: len,
+        }
+    }
+
+    /// Send an H.264 access unit in Annex B format as described by RFC 6184:
+    /// NAL units that fit in a packet are sent as-is, the others are split
+    /// into FU-A fragments.
+    pub fn send_h264(&mut self, timestamp: u32, access_unit: &[u8]) -> io::Result<()> {
+        let nal_units = annex_b_nal_units(access_unit);
+        let max_payload = self.max_payload();
+
+        for (index, nal) in nal_units.iter().enumerate() {
+            let last_nal = index == nal_units.len() - 1;
+
+            if nal.len() <= max_payload {
+                self.send(timestamp, last_nal, &[nal])?;
+                continue;
+            }
+
+            // The header of the NAL unit is replaced by the FU indicator and
+            // the FU header, which carry its fields.
+            let indicator = (nal[0] & 0xe0) | FU_A_TYPE;
+            let nal_type = nal[0] & 0x1f;
+            let mut fragments = nal[1..].chunks(max_payload - FU_A_HEADER_SIZE).peekable();
+            let mut start = true;
+            while let Some(fragment) = fragments.next() {
+                let end = fragments.peek().is_none();
+                let fu_header =
+                    nal_type | if start { 0x80 } else { 0 } | if end { 0x40 } else { 0 };
+                self.send(
+                    timestamp,
+                    last_nal && end,
+                    &[&[indicator, fu_header], fragment],
+                )?;
+                start = false;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writer returned by `RtpSender::raw_frame`.
+pub struct RawFrameWriter<'a> {
+    sender: &'a mut RtpSender,
+    timestamp: u32,
+    /// Number of bytes of the frame left to send.
+    remaining: usize,
+}
+
+impl<'a> Write for RawFrameWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let max_payload = min(self.sender.max_payload(), self.remaining);
+
+        let mut parts: Vec<&[u8]> = Vec::new();
+        let mut len = 0;
+        for buf in bufs {
+            if len == max_payload {
+                break;
+            }
+            let part = &buf[..min(buf.len(), max_payload - len)];
+            if !part.is_empty() {
+                parts.push(part);
+                len += part.len();
+            }
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+
+        self.remaining -= len;
+        self.sender
+            .send(self.timestamp, self.remaining == 0, &parts)?;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Split an Annex B byte stream into its NAL units, without start codes.
+fn annex_b_nal_units(stream: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= stream.len() {
+        if stream[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(index, &start)| {
+            let end = starts
+                .get(index + 1)
+                .map(|next| next - 3)
+                .unwrap_or(stream.len());
+            let nal = &stream[start..end];
+            // Zero bytes before a start code belong to it (4-byte start codes
+            // or trailing_zero_8bits), not to the NAL unit.
+            let len = nal.iter().rposition(|&b| b != 0).map_or(0, |pos| pos + 1);
+            &nal[..len]
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
@@ -9,13 +9,12 @@ use std::{
     sync::Arc,
 };
 
-use anyhow::ensure;
 use nix::sys::time::{TimeVal, TimeValLike};
 use v4l2r::{
     decoder::{format::fwht::FwhtFrameParser, FormatChangedReply},
     device::queue::{handles_provider::MmapProvider, FormatBuilder},
     memory::{MemoryType, MmapHandle},
-    PlaneLayout,
+    FormatField, PlaneLayout,
 };
 use v4l2r::{
     decoder::{
@@ -30,7 +29,7 @@ use v4l2r::{
         poller::PollError,
         queue::{direction::Capture, dqbuf::DqBuffer},
     },
-    Format, Rect,
+    Rect,
 };
 
 use clap::{App, Arg};
@@ -141,7 +140,11 @@ fn main() {
         }
     };
     let decoder_event_cb = move |event: DecoderEvent<MmapProvider>| match event {
-        DecoderEvent::FrameDecoded(dqbuf) => output_ready_cb(dqbuf),
+        DecoderEvent::FrameDecoded(dqbuf, _) => output_ready_cb(dqbuf),
+        DecoderEvent::FrameCorrupted(e, _) => {
+            eprintln!("Frame {} decoded with errors", e.buffer.data.index());
+            output_ready_cb(e.buffer)
+        }
         DecoderEvent::EndOfStream => (),
     };
     let set_capture_format_cb = move |f: FormatBuilder,
@@ -165,32 +168,27 @@ fn main() {
         })
     };
 
-    let mut decoder = Decoder::open(Path::new(device_path))
+    let pixel_format: PixelFormat = match codec {
+        Codec::Fwht => b"FWHT".into(),
+        Codec::H264 => b"H264".into(),
+    };
+    let (decoder, output_outcome) = Decoder::open(Path::new(device_path))
         .expect("Failed to open device")
         .set_output_format(|f| {
-            let pixel_format: PixelFormat = match codec {
-                Codec::Fwht => b"FWHT".into(),
-                Codec::H264 => b"H264".into(),
-            };
-            let format: Format = f
-                .set_pixelformat(pixel_format)
+            f.set_pixelformat(pixel_format)
                 // 1 MB per decoding unit should be enough for most streams.
                 .set_planes_layout(vec![PlaneLayout {
                     sizeimage: 1024 * 1024,
                     ..Default::default()
                 }])
-                .apply()?;
-
-            ensure!(
-                format.pixelformat == pixel_format,
-                format!("{} format not supported by device", pixel_format)
-            );
-
-            println!("Tentative OUTPUT format: {:?}", format);
-
-            Ok(())
         })
-        .expect("Failed to set output format")
+        .expect("Failed to set output format");
+    let format = output_outcome
+        .ensure_unchanged(|f| *f == FormatField::PixelFormat)
+        .unwrap_or_else(|_| panic!("{} format not supported by device", pixel_format));
+    println!("Tentative OUTPUT format: {:?}", format);
+
+    let mut decoder = decoder
         .allocate_output_buffers::<Vec<MmapHandle>>(NUM_OUTPUT_BUFFERS)
         .expect("Failed to allocate output buffers")
         .set_poll_counter(poll_count_writer)
@@ -0,0 +1,264 @@
+//! Runs several encoder instances concurrently against the same memory-to-memory
+//! driver, e.g. `vicodec`, and prints the frame rate and latency of each
+//! stream.
+//!
+//! This validates that independent encoders can run from different threads,
+//! and helps finding the number of buffers required to sustain a given
+//! number of streams.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::sys::time::{TimeVal, TimeValLike};
+use utils::framegen::FrameGenerator;
+use v4l2r::{
+    device::{poller::PollError, queue::handles_provider::MmapProvider},
+    encoder::*,
+    memory::MmapHandle,
+    FormatField,
+};
+
+use clap::{App, Arg};
+
+/// Statistics of one encoding stream.
+#[derive(Debug, Default)]
+struct StreamStats {
+    frames: usize,
+    elapsed: Duration,
+    total_latency: Duration,
+    max_latency: Duration,
+}
+
+impl StreamStats {
+    fn fps(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn avg_latency(&self) -> Duration {
+        self.total_latency
+            .checked_div(self.frames as u32)
+            .unwrap_or_default()
+    }
+}
+
+struct StreamConfig {
+    device: PathBuf,
+    frame_size: (usize, usize),
+    num_frames: usize,
+    num_buffers: usize,
+}
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("V4L2 multi-stream encoder stress test")
+        .arg(
+            Arg::with_name("device")
+                .required(true)
+                .help("Path to the encoder device file"),
+        )
+        .arg(
+            Arg::with_name("num_streams")
+                .long("streams")
+                .takes_value(true)
+                .default_value("4")
+                .help("Number of concurrent encoding streams"),
+        )
+        .arg(
+            Arg::with_name("num_frames")
+                .long("num_frames")
+                .takes_value(true)
+                .default_value("300")
+                .help("Number of frames to encode per stream"),
+        )
+        .arg(
+            Arg::with_name("num_buffers")
+                .long("num_buffers")
+                .takes_value(true)
+                .default_value("2")
+                .help("Number of OUTPUT and CAPTURE buffers of each stream"),
+        )
+        .arg(
+            Arg::with_name("frame_size")
+                .long("frame_size")
+                .takes_value(true)
+                .default_value("640x480")
+                .help("Size of the frames to encode (e.g. \"640x480\")"),
+        )
+        .get_matches();
+
+    let device = PathBuf::from(matches.value_of("device").unwrap());
+    let num_streams = clap::value_t!(matches.value_of("num_streams"), usize)
+        .unwrap_or_else(|e| panic!("Invalid value for streams: {}", e));
+    let num_frames = clap::value_t!(matches.value_of("num_frames"), usize)
+        .unwrap_or_else(|e| panic!("Invalid value for num_frames: {}", e));
+    let num_buffers = clap::value_t!(matches.value_of("num_buffers"), usize)
+        .unwrap_or_else(|e| panic!("Invalid value for num_buffers: {}", e));
+    let frame_size = matches
+        .value_of("frame_size")
+        .map(|s| {
+            const ERROR_MSG: &str = "Invalid parameter for frame_size";
+            let split: Vec<&str> = s.split('x').collect();
+            if split.len() != 2 {
+                panic!("{}", ERROR_MSG);
+            }
+            let width: usize = split[0].parse().expect(ERROR_MSG);
+            let height: usize = split[1].parse().expect(ERROR_MSG);
+
+            (width, height)
+        })
+        .unwrap();
+
+    let lets_quit = Arc::new(AtomicBool::new(false));
+    // Setup the Ctrl+c handler.
+    {
+        let lets_quit_handler = lets_quit.clone();
+        ctrlc::set_handler(move || {
+            lets_quit_handler.store(true, Ordering::SeqCst);
+        })
+        .expect("Failed to set Ctrl-C handler.");
+    }
+
+    println!(
+        "Running {} streams of {} frames with {} buffers each",
+        num_streams, num_frames, num_buffers
+    );
+
+    let streams: Vec<_> = (0..num_streams)
+        .map(|stream| {
+            let config = StreamConfig {
+                device: device.clone(),
+                frame_size,
+                num_frames,
+                num_buffers,
+            };
+            let lets_quit = Arc::clone(&lets_quit);
+            thread::Builder::new()
+                .name(format!("stream {}", stream))
+                .spawn(move || run_stream(&config, &lets_quit))
+                .expect("Failed to spawn stream thread")
+        })
+        .collect();
+
+    let mut failed = false;
+    println!(
+        "{:>6} {:>8} {:>8} {:>14} {:>14}",
+        "stream", "frames", "fps", "avg latency", "max latency"
+    );
+    for (stream, handle) in streams.into_iter().enumerate() {
+        match handle.join() {
+            Ok(Ok(stats)) => println!(
+                "{:>6} {:>8} {:>8.2} {:>14?} {:>14?}",
+                stream,
+                stats.frames,
+                stats.fps(),
+                stats.avg_latency(),
+                stats.max_latency
+            ),
+            Ok(Err(e)) => {
+                println!("{:>6} failed: {:#}", stream, e);
+                failed = true;
+            }
+            Err(_) => {
+                println!("{:>6} panicked", stream);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Encodes `config.num_frames` generated frames and returns the statistics of
+/// the stream.
+fn run_stream(config: &StreamConfig, lets_quit: &AtomicBool) -> anyhow::Result<StreamStats> {
+    let (width, height) = config.frame_size;
+    let (encoder, capture_outcome) = Encoder::open(Path::new(&config.device))?
+        .set_capture_format(|f| f.set_pixelformat(b"FWHT"))?;
+    capture_outcome.ensure_unchanged(|f| *f == FormatField::PixelFormat)?;
+    let (encoder, output_outcome) =
+        encoder.set_output_format(|f| f.set_pixelformat(b"RGB3").set_size(width, height))?;
+    output_outcome.ensure_unchanged(|f| *f == FormatField::PixelFormat)?;
+
+    let output_format = encoder.get_output_format()?;
+    let capture_format = encoder.get_capture_format()?;
+    let mut frame_gen = FrameGenerator::new(
+        output_format.width as usize,
+        output_format.height as usize,
+        output_format.plane_fmt[0].bytesperline as usize,
+    )?;
+
+    // Time at which each frame has been queued, indexed by timestamp.
+    let queued_at: Arc<Mutex<HashMap<u64, Instant>>> = Default::default();
+    let stats: Arc<Mutex<StreamStats>> = Default::default();
+
+    let output_ready_cb = {
+        let queued_at = Arc::clone(&queued_at);
+        let stats = Arc::clone(&stats);
+
+        move |frame: CapturedFrame<Vec<MmapHandle>>| {
+            if frame.bytes_used() == 0 {
+                return;
+            }
+
+            let timestamp = frame.timestamp().tv_sec as u64;
+            let mut stats = stats.lock().unwrap();
+            stats.frames += 1;
+            if let Some(queued_at) = queued_at.lock().unwrap().remove(&timestamp) {
+                let latency = queued_at.elapsed();
+                stats.total_latency += latency;
+                stats.max_latency = stats.max_latency.max(latency);
+            }
+        }
+    };
+
+    let mut encoder = encoder
+        .allocate_output_buffers::<Vec<MmapHandle>>(config.num_buffers)?
+        .allocate_capture_buffers(config.num_buffers, MmapProvider::new(&capture_format))?
+        .start(|_| (), output_ready_cb)?;
+
+    let start_time = Instant::now();
+    for frame_index in 0..config.num_frames {
+        if lets_quit.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let v4l2_buffer = match encoder.get_buffer() {
+            Ok(buffer) => buffer,
+            // If we got interrupted while waiting for a buffer, just exit normally.
+            Err(GetBufferError::PollError(PollError::EPollWait(nix::errno::Errno::EINTR))) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let mut mapping = v4l2_buffer
+            .get_plane_mapping(0)
+            .ok_or_else(|| anyhow::anyhow!("Failed to map OUTPUT buffer"))?;
+        frame_gen.next_frame(&mut mapping)?;
+        drop(mapping);
+
+        queued_at
+            .lock()
+            .unwrap()
+            .insert(frame_index as u64, Instant::now());
+        v4l2_buffer
+            .set_timestamp(TimeVal::seconds(frame_index as i64))
+            .queue(&[frame_gen.frame_size()])?;
+    }
+
+    encoder
+        .stop()
+        .map_err(|e| anyhow::anyhow!("Failed to stop encoder: {}", e))?;
+
+    let mut stats = std::mem::take(&mut *stats.lock().unwrap());
+    stats.elapsed = start_time.elapsed();
+
+    Ok(stats)
+}
@@ -0,0 +1,331 @@
+//! Encodes generated frames with a stateful encoder, decodes them back with a
+//! stateful decoder, and compares the decoded frames against the source ones.
+//!
+//! With the `vicodec` virtual codec driver, this exercises the encoder and
+//! decoder APIs together and doubles as an integration test: the program exits
+//! with an error if a frame is lost or if the quality of a decoded frame falls
+//! below the requested PSNR.
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::ensure;
+use nix::sys::time::{TimeVal, TimeValLike};
+use utils::framegen::FrameGenerator;
+use v4l2r::{
+    decoder::{format::fwht::FwhtFrameParser, stateful::Decoder, DecoderEvent, FormatChangedReply},
+    device::queue::{handles_provider::MmapProvider, FormatBuilder},
+    encoder::*,
+    memory::{MemoryType, MmapHandle},
+    Format, FormatField, PlaneLayout, Rect,
+};
+
+use clap::{App, Arg};
+
+/// Pixel format of the raw frames, supported by both sides of vicodec.
+const RAW_FORMAT: &[u8; 4] = b"YU12";
+const NUM_BUFFERS: usize = 4;
+
+/// Luma plane of a raw frame, which is what the quality is measured on.
+struct Luma {
+    data: Vec<u8>,
+    stride: usize,
+}
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("V4L2 transcode round trip")
+        .arg(
+            Arg::with_name("encoder")
+                .required(true)
+                .help("Path to the vicodec encoder device file"),
+        )
+        .arg(
+            Arg::with_name("decoder")
+                .required(true)
+                .help("Path to the vicodec decoder device file"),
+        )
+        .arg(
+            Arg::with_name("frame_size")
+                .long("frame_size")
+                .required(false)
+                .takes_value(true)
+                .default_value("640x480")
+                .help("Size of the frames to encode (e.g. \"640x480\")"),
+        )
+        .arg(
+            Arg::with_name("num_frames")
+                .long("num_frames")
+                .required(false)
+                .takes_value(true)
+                .default_value("30")
+                .help("Number of frames to encode and decode back"),
+        )
+        .arg(
+            Arg::with_name("min_psnr")
+                .long("min_psnr")
+                .required(false)
+                .takes_value(true)
+                .default_value("30")
+                .help("Minimum PSNR in dB of the luma of each decoded frame"),
+        )
+        .get_matches();
+
+    let encoder_path = matches.value_of("encoder").unwrap();
+    let decoder_path = matches.value_of("decoder").unwrap();
+    let frame_size = matches
+        .value_of("frame_size")
+        .map(|s| {
+            const ERROR_MSG: &str = "Invalid parameter for frame_size";
+            let split: Vec<&str> = s.split('x').collect();
+            if split.len() != 2 {
+                panic!("{}", ERROR_MSG);
+            }
+            let width: usize = split[0].parse().expect(ERROR_MSG);
+            let height: usize = split[1].parse().expect(ERROR_MSG);
+
+            (width, height)
+        })
+        .unwrap();
+    let num_frames = clap::value_t!(matches.value_of("num_frames"), usize)
+        .unwrap_or_else(|e| panic!("Invalid value for num_frames: {}", e));
+    let min_psnr = clap::value_t!(matches.value_of("min_psnr"), f64)
+        .unwrap_or_else(|e| panic!("Invalid value for min_psnr: {}", e));
+
+    let (source, stream) = encode(Path::new(encoder_path), frame_size, num_frames);
+    println!(
+        "Encoded {} frames into {} bytes (checksum {:016x})",
+        source.len(),
+        stream.len(),
+        checksum(&stream)
+    );
+
+    let decoded = decode(Path::new(decoder_path), stream);
+    println!("Decoded {} frames", decoded.len());
+
+    let mut failed = decoded.len() != source.len();
+    if failed {
+        eprintln!(
+            "Expected {} decoded frames, got {}",
+            source.len(),
+            decoded.len()
+        );
+    }
+
+    for (i, (source, decoded)) in source.iter().zip(decoded.iter()).enumerate() {
+        let psnr = psnr(source, decoded, frame_size);
+        println!(
+            "Frame {:#4}: source checksum {:016x}, decoded checksum {:016x}, PSNR {:.2} dB",
+            i,
+            checksum(&source.data),
+            checksum(&decoded.data),
+            psnr,
+        );
+        if psnr < min_psnr {
+            eprintln!("Frame {} is below the minimum PSNR of {} dB", i, min_psnr);
+            failed = true;
+        }
+    }
+
+    if failed {
+        eprintln!("Round trip failed");
+        std::process::exit(1);
+    }
+    println!("Round trip succeeded");
+}
+
+/// Encodes `num_frames` generated frames of `frame_size` into a FWHT stream,
+/// and returns the luma of the source frames along with the stream.
+fn encode(device: &Path, frame_size: (usize, usize), num_frames: usize) -> (Vec<Luma>, Vec<u8>) {
+    let (encoder, capture_outcome) = Encoder::open(device)
+        .expect("Failed to open encoder")
+        .set_capture_format(|f| f.set_pixelformat(b"FWHT"))
+        .expect("Failed to set capture format");
+    capture_outcome
+        .ensure_exact()
+        .expect("FWHT format not supported");
+    let (encoder, output_outcome) = encoder
+        .set_output_format(|f| {
+            f.set_pixelformat(RAW_FORMAT)
+                .set_size(frame_size.0, frame_size.1)
+        })
+        .expect("Failed to set output format");
+    // Fails if either the YU12 format or the frame resolution is not supported.
+    output_outcome
+        .ensure_exact()
+        .expect("Output format not supported");
+
+    let output_format = encoder
+        .get_output_format()
+        .expect("Failed to get output format");
+    let capture_format = encoder
+        .get_capture_format()
+        .expect("Failed to get capture format");
+    let luma_stride = output_format.plane_fmt[0].bytesperline as usize;
+    let mut frame_gen =
+        FrameGenerator::from_format(&output_format).expect("Failed to create frame generator");
+
+    // Each encoded frame holds a CAPTURE buffer until it is received, so a
+    // channel as large as the number of CAPTURE buffers never blocks the
+    // encoder.
+    let (mut encoder, encoded_frames) = encoder
+        .allocate_output_buffers::<Vec<MmapHandle>>(NUM_BUFFERS)
+        .expect("Failed to allocate OUTPUT buffers")
+        .allocate_capture_buffers(NUM_BUFFERS, MmapProvider::new(&capture_format))
+        .expect("Failed to allocate CAPTURE buffers")
+        .start_with_output_channel(|_| (), NUM_BUFFERS)
+        .expect("Failed to start encoder");
+
+    let mut source = Vec::with_capacity(num_frames);
+    let mut stream = Vec::new();
+    for frame_index in 0..num_frames {
+        let v4l2_buffer = encoder.get_buffer().expect("Failed to get OUTPUT buffer");
+        let mut mapping = v4l2_buffer
+            .get_plane_mapping(0)
+            .expect("Failed to get OUTPUT buffer mapping");
+        frame_gen
+            .next_frame(&mut mapping)
+            .expect("Failed to generate frame");
+        source.push(Luma {
+            data: mapping.as_ref()[..luma_stride * frame_size.1].to_vec(),
+            stride: luma_stride,
+        });
+        drop(mapping);
+
+        v4l2_buffer
+            .set_timestamp(TimeVal::seconds(frame_index as i64))
+            .queue(&[frame_gen.frame_size()])
+            .expect("Failed to queue input frame");
+
+        for frame in encoded_frames.try_iter() {
+            stream.extend(frame.detach_copy().expect("Failed to map encoded frame"));
+        }
+    }
+
+    encoder.stop().expect("Failed to stop encoder");
+    for frame in encoded_frames.try_iter() {
+        stream.extend(frame.detach_copy().expect("Failed to map encoded frame"));
+    }
+
+    (source, stream)
+}
+
+/// Decodes the FWHT `stream`, and returns the luma of the decoded frames.
+fn decode(device: &Path, stream: Vec<u8>) -> Vec<Luma> {
+    let decoded = Arc::new(Mutex::new(Vec::new()));
+    let luma_stride = Arc::new(Mutex::new(0usize));
+
+    let decoder_event_cb = {
+        let decoded = Arc::clone(&decoded);
+        let luma_stride = Arc::clone(&luma_stride);
+
+        move |event: DecoderEvent<MmapProvider>| {
+            if let DecoderEvent::FrameDecoded(cap_dqbuf, _) = event {
+                if *cap_dqbuf.data.get_first_plane().bytesused == 0 {
+                    return;
+                }
+                let mapping = cap_dqbuf
+                    .get_plane_mapping(0)
+                    .expect("Failed to map decoded frame");
+                decoded.lock().unwrap().push(Luma {
+                    data: mapping.as_ref().to_vec(),
+                    stride: *luma_stride.lock().unwrap(),
+                });
+            }
+        }
+    };
+
+    let set_capture_format_cb = move |f: FormatBuilder,
+                                      _visible_rect: Rect,
+                                      min_num_buffers: usize|
+          -> anyhow::Result<FormatChangedReply<MmapProvider>> {
+        let format: Format = f.set_pixelformat(RAW_FORMAT).apply()?;
+        ensure!(
+            format.pixelformat == RAW_FORMAT.into(),
+            "YU12 decoding not supported"
+        );
+        *luma_stride.lock().unwrap() = format.plane_fmt[0].bytesperline as usize;
+
+        Ok(FormatChangedReply {
+            provider: MmapProvider::new(&format),
+            mem_type: MemoryType::Mmap,
+            num_buffers: min_num_buffers,
+        })
+    };
+
+    let (decoder, output_outcome) = Decoder::open(device)
+        .expect("Failed to open decoder")
+        .set_output_format(|f| {
+            f.set_pixelformat(b"FWHT")
+                .set_planes_layout(vec![PlaneLayout {
+                    sizeimage: 1024 * 1024,
+                    ..Default::default()
+                }])
+        })
+        .expect("Failed to set output format");
+    output_outcome
+        .ensure_unchanged(|f| *f == FormatField::PixelFormat)
+        .expect("FWHT format not supported");
+    let mut decoder = decoder
+        .allocate_output_buffers::<Vec<MmapHandle>>(NUM_BUFFERS)
+        .expect("Failed to allocate OUTPUT buffers")
+        .start(|_| (), decoder_event_cb, set_capture_format_cb)
+        .expect("Failed to start decoder");
+
+    let parser = FwhtFrameParser::new(std::io::Cursor::new(stream))
+        .expect("Encoder did not produce a FWHT stream");
+    for (frame_index, frame) in parser.enumerate() {
+        let v4l2_buffer = decoder.get_buffer().expect("Failed to get OUTPUT buffer");
+        let mut mapping = v4l2_buffer
+            .get_plane_mapping(0)
+            .expect("Failed to get OUTPUT buffer mapping");
+        mapping.as_mut()[0..frame.len()].copy_from_slice(&frame);
+        drop(mapping);
+
+        v4l2_buffer
+            .set_timestamp(TimeVal::seconds(frame_index as i64))
+            .queue(&[frame.len()])
+            .expect("Failed to queue encoded frame");
+    }
+
+    decoder.drain(true).expect("Failed to drain decoder");
+    decoder.stop().expect("Failed to stop decoder");
+
+    let decoded = std::mem::take(&mut *decoded.lock().unwrap());
+
+    decoded
+}
+
+/// Returns the FNV-1a hash of `data`.
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Returns the PSNR in dB between the luma planes of `a` and `b`, or infinity
+/// if they are identical.
+fn psnr(a: &Luma, b: &Luma, (width, height): (usize, usize)) -> f64 {
+    let mut squared_error = 0u64;
+    for y in 0..height {
+        let line_a = &a.data[y * a.stride..][..width];
+        let line_b = match b.data.get(y * b.stride..y * b.stride + width) {
+            Some(line) => line,
+            // Truncated frame.
+            None => return 0.0,
+        };
+        squared_error += line_a
+            .iter()
+            .zip(line_b)
+            .map(|(a, b)| (*a as i64 - *b as i64).pow(2) as u64)
+            .sum::<u64>();
+    }
+
+    if squared_error == 0 {
+        return f64::INFINITY;
+    }
+    let mse = squared_error as f64 / (width * height) as f64;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
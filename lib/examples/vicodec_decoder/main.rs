@@ -0,0 +1,255 @@
+//! Decodes a FWHT stream, like the one saved by the `fwht_encoder` example,
+//! using the stateful decoder of the `vicodec` virtual codec driver, and
+//! writes the decoded raw frames to a file.
+//!
+//! This serves as an end-to-end test of the stateful decoder API: the program
+//! exits with an error if the number of decoded frames does not match the
+//! number of encoded frames, or if the frames are not returned in order.
+use std::{
+    fs::File,
+    io::{self, BufReader, Write},
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::Arc,
+};
+
+use anyhow::ensure;
+use nix::sys::time::{TimeVal, TimeValLike};
+use v4l2r::{
+    decoder::{
+        format::fwht::FwhtFrameParser,
+        stateful::{Decoder, GetBufferError},
+        DecoderEvent, FormatChangedReply,
+    },
+    device::{
+        poller::PollError,
+        queue::{handles_provider::MmapProvider, FormatBuilder},
+    },
+    memory::{MemoryType, MmapHandle},
+    Format, FormatField, PixelFormat, PlaneLayout, Rect,
+};
+
+use clap::{App, Arg};
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("vicodec FWHT decoder")
+        .arg(
+            Arg::with_name("stream")
+                .required(true)
+                .help("Path to the FWHT stream to decode"),
+        )
+        .arg(
+            Arg::with_name("device")
+                .required(true)
+                .help("Path to the vicodec decoder device file"),
+        )
+        .arg(
+            Arg::with_name("pixel_format")
+                .long("pixel_format")
+                .required(false)
+                .takes_value(true)
+                .help("Pixel format of the decoded frames (default: chosen by the decoder)"),
+        )
+        .arg(
+            Arg::with_name("num_frames")
+                .long("stop_after")
+                .takes_value(true)
+                .help("Stop after decoding a given number of frames"),
+        )
+        .arg(
+            Arg::with_name("output_file")
+                .long("save")
+                .required(false)
+                .takes_value(true)
+                .help("Save the decoded raw frames to a file"),
+        )
+        .get_matches();
+
+    let stream_path = matches
+        .value_of("stream")
+        .expect("Stream argument not specified");
+    let device_path = matches
+        .value_of("device")
+        .expect("Device argument not specified");
+    let capture_pixel_format: Option<PixelFormat> =
+        matches
+            .value_of("pixel_format")
+            .map(|s| match s.as_bytes() {
+                [a, b, c, d] => PixelFormat::from_fourcc(&[*a, *b, *c, *d]),
+                _ => panic!("Invalid pixel format {}", s),
+            });
+    let stop_after = match clap::value_t!(matches.value_of("num_frames"), usize) {
+        Ok(v) => Some(v),
+        Err(e) if e.kind == clap::ErrorKind::ArgumentNotFound => None,
+        Err(e) => panic!("Invalid value for stop_after: {}", e),
+    };
+
+    let stream = BufReader::new(File::open(stream_path).expect("FWHT stream not found"));
+
+    let mut output_file: Option<File> = matches
+        .value_of("output_file")
+        .map(|path| File::create(path).expect("Invalid output file specified."));
+
+    let lets_quit = Arc::new(AtomicBool::new(false));
+    // Setup the Ctrl+c handler.
+    {
+        let lets_quit_handler = lets_quit.clone();
+        ctrlc::set_handler(move || {
+            lets_quit_handler.store(true, Ordering::SeqCst);
+        })
+        .expect("Failed to set Ctrl-C handler.");
+    }
+
+    const NUM_OUTPUT_BUFFERS: usize = 4;
+
+    let decoded_frames = Arc::new(AtomicUsize::new(0));
+    let out_of_order = Arc::new(AtomicBool::new(false));
+
+    let decoder_event_cb = {
+        let decoded_frames = Arc::clone(&decoded_frames);
+        let out_of_order = Arc::clone(&out_of_order);
+
+        move |event: DecoderEvent<MmapProvider>| match event {
+            DecoderEvent::FrameDecoded(cap_dqbuf, visible_rect) => {
+                let bytes_used = *cap_dqbuf.data.get_first_plane().bytesused as usize;
+                // Ignore zero-sized buffers.
+                if bytes_used == 0 {
+                    return;
+                }
+
+                // The timestamp of each encoded frame is its index in the
+                // stream, and decoded frames must come out in the same order.
+                let frame_index = decoded_frames.fetch_add(1, Ordering::SeqCst);
+                let timestamp = cap_dqbuf.timestamp().tv_sec as usize;
+                if timestamp != frame_index {
+                    eprintln!(
+                        "\nFrame {} has the timestamp of frame {}",
+                        frame_index, timestamp
+                    );
+                    out_of_order.store(true, Ordering::SeqCst);
+                }
+
+                print!(
+                    "\rDecoded frame {:#5}, index: {:#2}, visible rect: {}, bytes used:{:#8}",
+                    frame_index,
+                    cap_dqbuf.index(),
+                    visible_rect,
+                    bytes_used,
+                );
+                io::stdout().flush().unwrap();
+
+                if let Some(ref mut output) = output_file {
+                    let mappings = cap_dqbuf
+                        .get_plane_mappings()
+                        .expect("Failed to map capture buffer");
+                    for plane in mappings.iter() {
+                        output
+                            .write_all(plane)
+                            .expect("Error while writing output data");
+                    }
+                }
+            }
+            DecoderEvent::FrameCorrupted(_, _) => {
+                let frame_index = decoded_frames.fetch_add(1, Ordering::SeqCst);
+                eprintln!("\nFrame {} decoded with errors", frame_index);
+            }
+            DecoderEvent::EndOfStream => println!("\nEnd of stream"),
+        }
+    };
+
+    let set_capture_format_cb = move |f: FormatBuilder,
+                                      visible_rect: Rect,
+                                      min_num_buffers: usize|
+          -> anyhow::Result<FormatChangedReply<MmapProvider>> {
+        let format: Format = match capture_pixel_format {
+            Some(pixel_format) => {
+                let format: Format = f.set_pixelformat(pixel_format).apply()?;
+                ensure!(
+                    format.pixelformat == pixel_format,
+                    format!("{} decoding not supported by device", pixel_format)
+                );
+                format
+            }
+            // Keep the pixel format that the decoder found convenient.
+            None => f.format().clone(),
+        };
+
+        println!(
+            "New CAPTURE format: {:?} (visible rect: {})",
+            format, visible_rect
+        );
+
+        Ok(FormatChangedReply {
+            provider: MmapProvider::new(&format),
+            mem_type: MemoryType::Mmap,
+            num_buffers: min_num_buffers,
+        })
+    };
+
+    let (decoder, output_outcome) = Decoder::open(Path::new(device_path))
+        .expect("Failed to open device")
+        .set_output_format(|f| {
+            f.set_pixelformat(b"FWHT")
+                // 1 MB per frame is enough for the resolutions vicodec supports.
+                .set_planes_layout(vec![PlaneLayout {
+                    sizeimage: 1024 * 1024,
+                    ..Default::default()
+                }])
+        })
+        .expect("Failed to set output format");
+    output_outcome
+        .ensure_unchanged(|f| *f == FormatField::PixelFormat)
+        .expect("FWHT format not supported by device");
+
+    let mut decoder = decoder
+        .allocate_output_buffers::<Vec<MmapHandle>>(NUM_OUTPUT_BUFFERS)
+        .expect("Failed to allocate output buffers")
+        .start(|_| (), decoder_event_cb, set_capture_format_cb)
+        .expect("Failed to start decoder");
+
+    let parser = FwhtFrameParser::new(stream)
+        .unwrap_or_else(|| panic!("No FWHT stream detected in {}", stream_path));
+
+    let mut encoded_frames = 0usize;
+    for (frame_index, frame) in parser.enumerate() {
+        // Ctrl-c ?
+        if lets_quit.load(Ordering::SeqCst) || Some(frame_index) == stop_after {
+            break;
+        }
+
+        let v4l2_buffer = match decoder.get_buffer() {
+            Ok(buffer) => buffer,
+            // If we got interrupted while waiting for a buffer, just exit normally.
+            Err(GetBufferError::PollError(PollError::EPollWait(nix::errno::Errno::EINTR))) => break,
+            Err(e) => panic!("{}", e),
+        };
+
+        let mut mapping = v4l2_buffer
+            .get_plane_mapping(0)
+            .expect("Failed to get OUTPUT buffer mapping");
+        mapping.as_mut()[0..frame.len()].copy_from_slice(&frame);
+        drop(mapping);
+
+        v4l2_buffer
+            .set_timestamp(TimeVal::seconds(frame_index as i64))
+            .queue(&[frame.len()])
+            .expect("Failed to queue input frame");
+        encoded_frames += 1;
+    }
+
+    // Wait for all the frames to be decoded.
+    decoder.drain(true).expect("Failed to drain decoder");
+    decoder.stop().expect("Failed to stop decoder");
+
+    let decoded_frames = decoded_frames.load(Ordering::SeqCst);
+    println!(
+        "Decoded {} frames out of {} encoded frames",
+        decoded_frames, encoded_frames
+    );
+    if decoded_frames != encoded_frames || out_of_order.load(Ordering::SeqCst) {
+        eprintln!("Decoding failed");
+        std::process::exit(1);
+    }
+}
@@ -1,3 +1,4 @@
+use std::fs::File;
 use std::io::{self, Write};
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -6,16 +7,32 @@ use std::time::Instant;
 use utils::framegen::FrameGenerator;
 
 use qbuf::{get_free::GetFreeCaptureBuffer, get_indexed::GetOutputBufferByIndex};
-use v4l2r::{device::queue::qbuf::OutputQueueable, memory::MemoryType, Format};
-use v4l2r::{device::queue::*, memory::MmapHandle};
+use v4l2r::{
+    device::queue::qbuf::{CaptureQueueable, OutputQueueable},
+    memory::MemoryType,
+    Format,
+};
+use v4l2r::{
+    device::queue::{direction::Capture, *},
+    memory::MmapHandle,
+};
 use v4l2r::{
     device::{
         queue::generic::{GenericBufferHandles, GenericQBuffer, GenericSupportedMemoryType},
         AllocatedQueue, Device, DeviceConfig, Stream, TryDequeue,
     },
-    memory::UserPtrHandle,
+    memory::{DmaBufHandle, UserPtrHandle},
 };
 
+/// The CAPTURE queue, whose type depends on the memory selected at runtime.
+///
+/// Contrary to the OUTPUT queue, we need to map the buffers we dequeue, which
+/// generic handles do not allow.
+enum CaptureQueue {
+    Mmap(Queue<Capture, BuffersAllocated<Vec<MmapHandle>>>),
+    DmaBuf(Queue<Capture, BuffersAllocated<Vec<DmaBufHandle<File>>>>),
+}
+
 /// Run a sample encoder on device `device_path`, which must be a `vicodec`
 /// encoder instance. `lets_quit` will turn to true when Ctrl+C is pressed.
 pub fn run<F: FnMut(&[u8])>(
@@ -115,16 +132,12 @@ pub fn run<F: FnMut(&[u8])>(
 
     let output_image_size = output_format.plane_fmt[0].sizeimage as usize;
 
-    match capture_mem {
-        MemoryType::Mmap => (),
-        m => panic!("Unsupported CAPTURE memory type {:?}", m),
-    }
-
     // Move the queues into their "allocated" state.
 
     let output_mem = match output_mem {
         MemoryType::Mmap => GenericSupportedMemoryType::Mmap,
         MemoryType::UserPtr => GenericSupportedMemoryType::UserPtr,
+        MemoryType::DmaBuf => GenericSupportedMemoryType::DmaBuf,
         m => panic!("Unsupported OUTPUT memory type {:?}", m),
     };
 
@@ -132,26 +145,58 @@ pub fn run<F: FnMut(&[u8])>(
         .request_buffers_generic::<GenericBufferHandles>(output_mem, 2)
         .expect("Failed to allocate output buffers");
 
-    let capture_queue = capture_queue
-        .request_buffers::<Vec<MmapHandle>>(2)
-        .expect("Failed to allocate output buffers");
+    let capture_queue = match capture_mem {
+        MemoryType::Mmap => CaptureQueue::Mmap(
+            capture_queue
+                .request_buffers::<Vec<MmapHandle>>(2)
+                .expect("Failed to allocate capture buffers"),
+        ),
+        MemoryType::DmaBuf => CaptureQueue::DmaBuf(
+            capture_queue
+                .request_buffers::<Vec<DmaBufHandle<File>>>(2)
+                .expect("Failed to allocate capture buffers"),
+        ),
+        m => panic!("Unsupported CAPTURE memory type {:?}", m),
+    };
+    let num_capture_buffers = match &capture_queue {
+        CaptureQueue::Mmap(queue) => queue.num_buffers(),
+        CaptureQueue::DmaBuf(queue) => queue.num_buffers(),
+    };
     println!(
         "Using {} output and {} capture buffers.",
         output_queue.num_buffers(),
-        capture_queue.num_buffers()
+        num_capture_buffers
     );
 
-    // If we use UserPtr OUTPUT buffers, create backing memory.
+    // If we use UserPtr or DmaBuf OUTPUT buffers, create backing memory.
     let mut output_frame = match output_mem {
-        GenericSupportedMemoryType::Mmap => None,
         GenericSupportedMemoryType::UserPtr => Some(vec![0u8; output_image_size]),
-        GenericSupportedMemoryType::DmaBuf => todo!(),
+        GenericSupportedMemoryType::Mmap | GenericSupportedMemoryType::DmaBuf => None,
+    };
+    let mut output_dmabuf = match output_mem {
+        GenericSupportedMemoryType::DmaBuf => {
+            utils::dmabuf_exporter::export_dmabufs(&output_format, 1)
+                .expect("Failed to allocate OUTPUT dmabuf")
+                .pop()
+        }
+        GenericSupportedMemoryType::Mmap | GenericSupportedMemoryType::UserPtr => None,
+    };
+    // Same thing for DmaBuf CAPTURE buffers.
+    let mut capture_dmabuf = match capture_queue {
+        CaptureQueue::DmaBuf(_) => utils::dmabuf_exporter::export_dmabufs(&capture_format, 1)
+            .expect("Failed to allocate CAPTURE dmabuf")
+            .pop(),
+        CaptureQueue::Mmap(_) => None,
     };
 
     output_queue
         .stream_on()
         .expect("Failed to start output_queue");
-    capture_queue.stream_on().expect("Failed to start capture");
+    match &capture_queue {
+        CaptureQueue::Mmap(queue) => queue.stream_on(),
+        CaptureQueue::DmaBuf(queue) => queue.stream_on(),
+    }
+    .expect("Failed to start capture");
 
     let mut frame_gen = FrameGenerator::new(
         output_format.width as usize,
@@ -172,12 +217,24 @@ pub fn run<F: FnMut(&[u8])>(
         }
 
         // There is no information to set on MMAP capture buffers: just queue
-        // them as soon as we get them.
-        capture_queue
-            .try_get_free_buffer()
-            .expect("Failed to obtain capture buffer")
-            .queue()
-            .expect("Failed to queue capture buffer");
+        // them as soon as we get them. DmaBuf ones need to be given their
+        // backing memory.
+        match &capture_queue {
+            CaptureQueue::Mmap(queue) => queue
+                .try_get_free_buffer()
+                .expect("Failed to obtain capture buffer")
+                .queue()
+                .expect("Failed to queue capture buffer"),
+            CaptureQueue::DmaBuf(queue) => queue
+                .try_get_free_buffer()
+                .expect("Failed to obtain capture buffer")
+                .queue_with_handles(
+                    capture_dmabuf
+                        .take()
+                        .expect("Capture dmabuf not available. This is a bug."),
+                )
+                .expect("Failed to queue capture buffer"),
+        }
 
         // USERPTR output buffers, on the other hand, must be set up with
         // a user buffer and bytes_used.
@@ -216,7 +273,23 @@ pub fn run<F: FnMut(&[u8])>(
                 )
                 .expect("Failed to queue output buffer");
             }
-            GenericQBuffer::DmaBuf(_) => todo!(),
+            GenericQBuffer::DmaBuf(buf) => {
+                let dmabuf = output_dmabuf
+                    .take()
+                    .expect("Output dmabuf not available. This is a bug.");
+                let mut mapping = dmabuf[0].map().expect("Failed to map output dmabuf");
+
+                frame_gen
+                    .next_frame(&mut mapping)
+                    .expect("Failed to generate frame");
+                drop(mapping);
+
+                buf.queue_with_handles(
+                    GenericBufferHandles::from(dmabuf),
+                    &[frame_gen.frame_size()],
+                )
+                .expect("Failed to queue output buffer");
+            }
         }
 
         // Now dequeue the work that we just scheduled.
@@ -234,21 +307,48 @@ pub fn run<F: FnMut(&[u8])>(
             GenericBufferHandles::User(u) => {
                 assert_eq!(output_frame.replace(u.remove(0).0), None);
             }
-            GenericBufferHandles::DmaBuf(_) => todo!(),
+            // Same thing for DmaBuf buffers.
+            GenericBufferHandles::DmaBuf(d) => {
+                assert!(output_dmabuf.replace(std::mem::take(d)).is_none());
+            }
         }
 
-        let cap_dqbuf = capture_queue
-            .try_dequeue()
-            .expect("Failed to dequeue capture buffer");
-        let cap_index = cap_dqbuf.data.index() as usize;
-        let bytes_used = *cap_dqbuf.data.get_first_plane().bytesused as usize;
+        let (cap_dqbuf, cap_mapping) = match &capture_queue {
+            CaptureQueue::Mmap(queue) => {
+                let cap_dqbuf = queue
+                    .try_dequeue()
+                    .expect("Failed to dequeue capture buffer");
+                let cap_mapping = cap_dqbuf
+                    .get_plane_mapping(0)
+                    .expect("Failed to map capture buffer");
+                (cap_dqbuf.data.clone(), cap_mapping)
+            }
+            CaptureQueue::DmaBuf(queue) => {
+                let mut cap_dqbuf = queue
+                    .try_dequeue()
+                    .expect("Failed to dequeue capture buffer");
+                // unwrap() is safe here as we just dequeued the buffer.
+                let dmabuf = cap_dqbuf.take_handles().unwrap();
+                let plane = cap_dqbuf.data.get_first_plane();
+                let start = *plane.data_offset.unwrap_or(&0) as usize;
+                let end = start + *plane.bytesused as usize;
+                let cap_mapping = dmabuf[0]
+                    .map()
+                    .expect("Failed to map capture dmabuf")
+                    .restrict(start, end);
+                capture_dmabuf = Some(dmabuf);
+                (cap_dqbuf.data.clone(), cap_mapping)
+            }
+        };
+        let cap_index = cap_dqbuf.index() as usize;
+        let bytes_used = *cap_dqbuf.get_first_plane().bytesused as usize;
 
         total_size = total_size.wrapping_add(bytes_used);
         let elapsed = start_time.elapsed();
         let fps = cpt as f64 / elapsed.as_millis() as f64 * 1000.0;
         print!(
             "\rEncoded buffer {:#5}, {:#2} -> {:#2}), bytes used:{:#6} total encoded size:{:#8} fps: {:#5.2}",
-            cap_dqbuf.data.sequence(),
+            cap_dqbuf.sequence(),
             out_dqbuf.data.index(),
             cap_index,
             bytes_used,
@@ -257,17 +357,16 @@ pub fn run<F: FnMut(&[u8])>(
         );
         io::stdout().flush().unwrap();
 
-        let cap_mapping = cap_dqbuf
-            .get_plane_mapping(0)
-            .expect("Failed to map capture buffer");
         save_output(cap_mapping.as_ref());
 
         cpt = cpt.wrapping_add(1);
     }
 
-    capture_queue
-        .stream_off()
-        .expect("Failed to stop output_queue");
+    match &capture_queue {
+        CaptureQueue::Mmap(queue) => queue.stream_off().map(|_| ()),
+        CaptureQueue::DmaBuf(queue) => queue.stream_off().map(|_| ()),
+    }
+    .expect("Failed to stop capture_queue");
     output_queue
         .stream_off()
         .expect("Failed to stop output_queue");
@@ -10,7 +10,7 @@ use std::sync::Arc;
 use std::time::Instant;
 use utils::framegen::FrameGenerator;
 
-use v4l2r::memory::{MemoryType, MmapHandle};
+use v4l2r::memory::{DmaBufHandle, MemoryType, MmapHandle};
 use v4l2r::{ioctl::*, memory::UserPtrHandle};
 use v4l2r::{Format, QueueType::*};
 
@@ -120,11 +120,13 @@ pub fn run<F: FnMut(&[u8])>(
     match output_mem {
         MemoryType::Mmap => (),
         MemoryType::UserPtr => (),
+        MemoryType::DmaBuf => (),
         m => panic!("Unsupported OUTPUT memory type {:?}", m),
     }
 
     match capture_mem {
         MemoryType::Mmap => (),
+        MemoryType::DmaBuf => (),
         m => panic!("Unsupported CAPTURE memory type {:?}", m),
     }
 
@@ -140,22 +142,46 @@ pub fn run<F: FnMut(&[u8])>(
         num_output_buffers, num_capture_buffers
     );
 
+    // DmaBuf buffers are allocated by us, with one dmabuf per buffer since
+    // vicodec formats have a single plane.
+    let allocate_dmabufs = |format: &Format, num_buffers: usize| -> Vec<DmaBufHandle<File>> {
+        utils::dmabuf_exporter::export_dmabufs(format, num_buffers)
+            .expect("Failed to allocate dmabufs")
+            .into_iter()
+            .map(|mut planes| planes.remove(0))
+            .collect()
+    };
+    let capture_dmabufs = match capture_mem {
+        MemoryType::DmaBuf => allocate_dmabufs(&capture_format, num_capture_buffers),
+        _ => Default::default(),
+    };
+
     let mut capture_mappings = Vec::new();
-    for i in 0..num_capture_buffers {
-        let query_buf: QueryBuffer =
-            querybuf(&fd, capture_queue, i).expect("Failed to query buffer");
-        println!(
-            "Capture buffer {} at offset 0x{:0x}, length 0x{:0x}",
-            i, query_buf.planes[0].mem_offset, query_buf.planes[0].length
-        );
-        capture_mappings.push(
-            mmap(
-                &fd,
-                query_buf.planes[0].mem_offset,
-                query_buf.planes[0].length,
-            )
-            .expect("Failed to map buffer"),
-        );
+    match capture_mem {
+        MemoryType::Mmap => {
+            for i in 0..num_capture_buffers {
+                let query_buf: QueryBuffer =
+                    querybuf(&fd, capture_queue, i).expect("Failed to query buffer");
+                println!(
+                    "Capture buffer {} at offset 0x{:0x}, length 0x{:0x}",
+                    i, query_buf.planes[0].mem_offset, query_buf.planes[0].length
+                );
+                capture_mappings.push(
+                    mmap(
+                        &fd,
+                        query_buf.planes[0].mem_offset,
+                        query_buf.planes[0].length,
+                    )
+                    .expect("Failed to map buffer"),
+                );
+            }
+        }
+        MemoryType::DmaBuf => {
+            for dmabuf in &capture_dmabufs {
+                capture_mappings.push(dmabuf.map().expect("Failed to map buffer"));
+            }
+        }
+        _ => unreachable!(),
     }
 
     let output_image_size = output_format.plane_fmt[0].sizeimage as usize;
@@ -165,8 +191,13 @@ pub fn run<F: FnMut(&[u8])>(
             .take(num_output_buffers)
             .map(UserPtrHandle::from)
             .collect(),
+        MemoryType::DmaBuf => Default::default(),
         _ => unreachable!(),
     };
+    let output_dmabufs = match output_mem {
+        MemoryType::DmaBuf => allocate_dmabufs(&output_format, num_output_buffers),
+        _ => Default::default(),
+    };
 
     // Start streaming.
     streamon(&fd, output_queue).expect("Failed to start output queue");
@@ -231,14 +262,48 @@ pub fn run<F: FnMut(&[u8])>(
 
                 qbuf::<_, ()>(&fd, out_qbuf)
             }
+            MemoryType::DmaBuf => {
+                let output_buffer = &output_dmabufs[output_buffer_index];
+                let mut mapping = output_buffer.map().expect("Failed to map output dmabuf");
+
+                frame_gen
+                    .next_frame(&mut mapping)
+                    .expect("Failed to generate frame");
+
+                let mut out_qbuf =
+                    QBuffer::<DmaBufHandle<File>>::new(output_queue, output_buffer_index as u32);
+                out_qbuf.planes = vec![QBufPlane::new_from_handle(
+                    output_buffer,
+                    frame_gen.frame_size(),
+                )];
+
+                qbuf::<_, ()>(&fd, out_qbuf)
+            }
             _ => unreachable!(),
         }
         .expect("Error queueing output buffer");
 
-        let mut cap_qbuf = QBuffer::<MmapHandle>::new(capture_queue, capture_buffer_index as u32);
-        cap_qbuf.planes = vec![QBufPlane::new(0)];
+        match capture_mem {
+            MemoryType::Mmap => {
+                let mut cap_qbuf =
+                    QBuffer::<MmapHandle>::new(capture_queue, capture_buffer_index as u32);
+                cap_qbuf.planes = vec![QBufPlane::new(0)];
+
+                qbuf::<_, ()>(&fd, cap_qbuf)
+            }
+            MemoryType::DmaBuf => {
+                let mut cap_qbuf =
+                    QBuffer::<DmaBufHandle<File>>::new(capture_queue, capture_buffer_index as u32);
+                cap_qbuf.planes = vec![QBufPlane::new_from_handle(
+                    &capture_dmabufs[capture_buffer_index],
+                    0,
+                )];
 
-        qbuf::<_, ()>(&fd, cap_qbuf).expect("Error queueing capture buffer");
+                qbuf::<_, ()>(&fd, cap_qbuf)
+            }
+            _ => unreachable!(),
+        }
+        .expect("Error queueing capture buffer");
 
         // Now dequeue the work that we just scheduled.
 
@@ -273,10 +338,8 @@ pub fn run<F: FnMut(&[u8])>(
     drop(capture_mappings);
 
     // Free the buffers.
-    reqbufs::<()>(&fd, capture_queue, MemoryType::Mmap, 0)
-        .expect("Failed to release capture buffers");
-    reqbufs::<()>(&fd, output_queue, MemoryType::UserPtr, 0)
-        .expect("Failed to release output buffers");
+    reqbufs::<()>(&fd, capture_queue, capture_mem, 0).expect("Failed to release capture buffers");
+    reqbufs::<()>(&fd, output_queue, output_mem, 0).expect("Failed to release output buffers");
 
     // The fd will be closed as the File instance gets out of scope.
 }
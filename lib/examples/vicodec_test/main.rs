@@ -4,6 +4,10 @@
 //! There are two variants doing the same thing: one using the higher-level
 //! `device` abstraction (used by default), the other using the low-level
 //! `ioctl` abstraction (used if `--use_ioctl` is specified).
+//!
+//! All the memory types supported by each queue can be selected with
+//! `--output_mem` and `--capture_mem`, which makes this program a convenient
+//! smoke test for the memory backends of the crate.
 mod device_api;
 mod ioctl_api;
 
@@ -44,18 +48,20 @@ fn main() {
         .arg(
             Arg::with_name("output_mem")
                 .long("output_mem")
+                .alias("output-mem")
                 .required(false)
                 .takes_value(true)
                 .default_value("user")
-                .help("Type of memory to use for the OUTPUT queue (mmap or user)"),
+                .help("Type of memory to use for the OUTPUT queue (mmap, user or dmabuf)"),
         )
         .arg(
             Arg::with_name("capture_mem")
                 .long("capture_mem")
+                .alias("capture-mem")
                 .required(false)
                 .takes_value(true)
                 .default_value("mmap")
-                .help("Type of memory to use for the CAPTURE queue (mmap)"),
+                .help("Type of memory to use for the CAPTURE queue (mmap or dmabuf)"),
         )
         .get_matches();
 
@@ -74,10 +80,12 @@ fn main() {
     let output_mem = match matches.value_of("output_mem") {
         Some("mmap") => MemoryType::Mmap,
         Some("user") => MemoryType::UserPtr,
+        Some("dmabuf") => MemoryType::DmaBuf,
         _ => panic!("Invalid value for output_mem"),
     };
     let capture_mem = match matches.value_of("capture_mem") {
         Some("mmap") => MemoryType::Mmap,
+        Some("dmabuf") => MemoryType::DmaBuf,
         _ => panic!("Invalid value for capture_mem"),
     };
 
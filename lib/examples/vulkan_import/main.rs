@@ -0,0 +1,205 @@
+//! Exports the buffers of a V4L2 queue as dmabufs and imports them into Vulkan
+//! through `VK_EXT_external_memory_dma_buf`, so they can be used for compute
+//! or display without copies.
+//!
+//! Each buffer is imported as a linear image whose planes are laid out as
+//! computed by the format database of the crate, and the layout reported by
+//! the Vulkan driver is then compared against it. This validates that the
+//! exported dmabufs come with all the metadata Vulkan needs.
+mod vulkan;
+
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{Seek, SeekFrom},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{ensure, Result};
+use clap::{App, Arg};
+use v4l2r::{
+    device::{
+        queue::{direction::Direction, Queue, QueueInit},
+        AllocatedQueue, Device, DeviceConfig,
+    },
+    ioctl::{self, ExpbufFlags},
+    memory::MmapHandle,
+    FormatField, PixelFormat,
+};
+
+use vulkan::VulkanImporter;
+
+/// Set the format of `queue`, allocate `num_buffers` buffers on it and import
+/// each of them into `importer`.
+fn import_buffers<D: Direction>(
+    device: &Device,
+    mut queue: Queue<D, QueueInit>,
+    pixelformat: PixelFormat,
+    (width, height): (usize, usize),
+    num_buffers: usize,
+    importer: &VulkanImporter,
+) -> Result<()> {
+    let format = queue
+        .change_format()?
+        .set_size(width, height)
+        .set_pixelformat(pixelformat)
+        .apply_checked()?
+        .ensure_unchanged(|f| *f == FormatField::PixelFormat)?;
+    println!("Format of the {:?} queue: {:?}", queue.get_type(), format);
+
+    let queue = queue.request_buffers::<Vec<MmapHandle>>(num_buffers as u32)?;
+    let mut mismatches = 0;
+    for index in 0..queue.num_buffers() {
+        let dmabufs = (0..format.plane_fmt.len())
+            .map(|plane| {
+                ioctl::expbuf::<File>(
+                    device,
+                    queue.get_type(),
+                    index,
+                    plane,
+                    ExpbufFlags::CLOEXEC | ExpbufFlags::RDWR,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (plane, (mut dmabuf, plane_fmt)) in dmabufs.iter().zip(&format.plane_fmt).enumerate() {
+            let size = dmabuf.seek(SeekFrom::End(0))?;
+            ensure!(
+                size >= plane_fmt.sizeimage as u64,
+                "dmabuf of plane {} is smaller than its sizeimage ({} < {})",
+                plane,
+                size,
+                plane_fmt.sizeimage
+            );
+        }
+
+        let image = importer.import(&format, &dmabufs)?;
+        let expected: Vec<(u64, u64)> = image
+            .expected_layouts()
+            .iter()
+            .map(|plane| (plane.offset as u64, plane.pitch as u64))
+            .collect();
+        let actual = image.plane_layouts();
+        if actual == expected {
+            println!(
+                "Buffer {}: imported, planes (offset, pitch) {:?}",
+                index, actual
+            );
+        } else {
+            mismatches += 1;
+            println!(
+                "Buffer {}: Vulkan reports planes (offset, pitch) {:?}, expected {:?}",
+                index, actual, expected
+            );
+        }
+    }
+    ensure!(
+        mismatches == 0,
+        "{} buffers have a different layout in Vulkan",
+        mismatches
+    );
+
+    Ok(())
+}
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("V4L2 dmabuf to Vulkan import")
+        .arg(
+            Arg::with_name("device")
+                .required(true)
+                .help("Path to the device file"),
+        )
+        .arg(
+            Arg::with_name("pixel_format")
+                .long("pixel_format")
+                .takes_value(true)
+                .default_value("NV12")
+                .help("Pixel format of the buffers to import"),
+        )
+        .arg(
+            Arg::with_name("frame_size")
+                .long("frame_size")
+                .takes_value(true)
+                .default_value("640x480")
+                .help("Size of the frames to import"),
+        )
+        .arg(
+            Arg::with_name("num_buffers")
+                .long("num_buffers")
+                .takes_value(true)
+                .default_value("4")
+                .help("Number of buffers to allocate and import"),
+        )
+        .arg(
+            Arg::with_name("capture")
+                .long("capture")
+                .help("Use the CAPTURE queue instead of the OUTPUT one"),
+        )
+        .get_matches();
+
+    let device_path = matches.value_of("device").unwrap();
+    let pixelformat = matches
+        .value_of("pixel_format")
+        .map(|s| {
+            let fourcc: [u8; 4] = s
+                .as_bytes()
+                .try_into()
+                .expect("Pixel format must be a 4 characters code");
+            PixelFormat::from(&fourcc)
+        })
+        .unwrap();
+    let frame_size = matches
+        .value_of("frame_size")
+        .map(|s| {
+            const ERROR_MSG: &str = "Invalid parameter for frame_size";
+            let split: Vec<&str> = s.split('x').collect();
+            if split.len() != 2 {
+                panic!("{}", ERROR_MSG);
+            }
+            let width: usize = split[0].parse().expect(ERROR_MSG);
+            let height: usize = split[1].parse().expect(ERROR_MSG);
+
+            (width, height)
+        })
+        .unwrap();
+    let num_buffers: usize =
+        clap::value_t!(matches.value_of("num_buffers"), usize).expect("Invalid number of buffers");
+
+    let importer = VulkanImporter::new().expect("Failed to initialize Vulkan");
+    let device = Arc::new(
+        Device::open(Path::new(device_path), DeviceConfig::new()).expect("Failed to open device"),
+    );
+
+    let result = if matches.is_present("capture") {
+        let queue = Queue::get_capture_mplane_queue(Arc::clone(&device))
+            .or_else(|_| Queue::get_capture_queue(Arc::clone(&device)))
+            .expect("Failed to obtain CAPTURE queue");
+        import_buffers(
+            &device,
+            queue,
+            pixelformat,
+            frame_size,
+            num_buffers,
+            &importer,
+        )
+    } else {
+        let queue = Queue::get_output_mplane_queue(Arc::clone(&device))
+            .or_else(|_| Queue::get_output_queue(Arc::clone(&device)))
+            .expect("Failed to obtain OUTPUT queue");
+        import_buffers(
+            &device,
+            queue,
+            pixelformat,
+            frame_size,
+            num_buffers,
+            &importer,
+        )
+    };
+
+    if let Err(e) = result {
+        eprintln!("Import failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
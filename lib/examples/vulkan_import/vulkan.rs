@@ -0,0 +1,672 @@
+//! Minimal bindings to Vulkan, covering what is needed to import dmabufs as
+//! images through `VK_EXT_external_memory_dma_buf`.
+use std::{
+    ffi::{c_char, c_void, CStr},
+    fs::File,
+    os::unix::io::{AsRawFd, IntoRawFd},
+    ptr,
+};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use v4l2r::{format_info::ColorPlaneLayout, Format};
+
+type VkInstance = *mut c_void;
+type VkPhysicalDevice = *mut c_void;
+type VkDevice = *mut c_void;
+type VkImage = u64;
+type VkDeviceMemory = u64;
+type VkResult = i32;
+type VkFormat = i32;
+type VkStructureType = i32;
+
+const VK_SUCCESS: VkResult = 0;
+const VK_API_VERSION_1_1: u32 = (1 << 22) | (1 << 12);
+
+const VK_STRUCTURE_TYPE_APPLICATION_INFO: VkStructureType = 0;
+const VK_STRUCTURE_TYPE_INSTANCE_CREATE_INFO: VkStructureType = 1;
+const VK_STRUCTURE_TYPE_DEVICE_QUEUE_CREATE_INFO: VkStructureType = 2;
+const VK_STRUCTURE_TYPE_DEVICE_CREATE_INFO: VkStructureType = 3;
+const VK_STRUCTURE_TYPE_MEMORY_ALLOCATE_INFO: VkStructureType = 5;
+const VK_STRUCTURE_TYPE_IMAGE_CREATE_INFO: VkStructureType = 14;
+const VK_STRUCTURE_TYPE_EXTERNAL_MEMORY_IMAGE_CREATE_INFO: VkStructureType = 1000072001;
+const VK_STRUCTURE_TYPE_IMPORT_MEMORY_FD_INFO_KHR: VkStructureType = 1000074000;
+const VK_STRUCTURE_TYPE_MEMORY_FD_PROPERTIES_KHR: VkStructureType = 1000074001;
+const VK_STRUCTURE_TYPE_MEMORY_DEDICATED_ALLOCATE_INFO: VkStructureType = 1000127001;
+const VK_STRUCTURE_TYPE_IMAGE_DRM_FORMAT_MODIFIER_EXPLICIT_CREATE_INFO_EXT: VkStructureType =
+    1000158004;
+
+const VK_IMAGE_TYPE_2D: i32 = 1;
+const VK_SAMPLE_COUNT_1_BIT: u32 = 0x1;
+const VK_IMAGE_TILING_DRM_FORMAT_MODIFIER_EXT: i32 = 1000158000;
+const VK_IMAGE_USAGE_TRANSFER_SRC_BIT: u32 = 0x1;
+const VK_IMAGE_USAGE_SAMPLED_BIT: u32 = 0x4;
+const VK_SHARING_MODE_EXCLUSIVE: i32 = 0;
+const VK_IMAGE_LAYOUT_UNDEFINED: i32 = 0;
+const VK_EXTERNAL_MEMORY_HANDLE_TYPE_DMA_BUF_BIT_EXT: u32 = 0x200;
+/// Aspects of the memory planes of an image with a DRM format modifier.
+const VK_IMAGE_ASPECT_MEMORY_PLANE_BITS_EXT: [u32; 3] = [0x80, 0x100, 0x200];
+
+/// `DRM_FORMAT_MOD_LINEAR`, the layout V4L2 drivers use unless told otherwise.
+const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+/// Device extensions required to import dmabufs with an explicit layout.
+const DEVICE_EXTENSIONS: [&[u8]; 4] = [
+    b"VK_KHR_external_memory_fd\0",
+    b"VK_EXT_external_memory_dma_buf\0",
+    b"VK_KHR_image_format_list\0",
+    b"VK_EXT_image_drm_format_modifier\0",
+];
+
+/// Vulkan formats equivalent to the DRM fourccs we can import.
+static VK_FORMATS: &[(&[u8; 4], VkFormat)] = &[
+    (b"R8  ", 9),          // VK_FORMAT_R8_UNORM
+    (b"AB24", 37),         // VK_FORMAT_R8G8B8A8_UNORM
+    (b"XB24", 37),         // VK_FORMAT_R8G8B8A8_UNORM
+    (b"AR24", 44),         // VK_FORMAT_B8G8R8A8_UNORM
+    (b"XR24", 44),         // VK_FORMAT_B8G8R8A8_UNORM
+    (b"YUYV", 1000156000), // VK_FORMAT_G8B8G8R8_422_UNORM
+    (b"YU12", 1000156002), // VK_FORMAT_G8_B8_R8_3PLANE_420_UNORM
+    (b"NV12", 1000156003), // VK_FORMAT_G8_B8R8_2PLANE_420_UNORM
+    (b"YU16", 1000156004), // VK_FORMAT_G8_B8_R8_3PLANE_422_UNORM
+    (b"NV16", 1000156005), // VK_FORMAT_G8_B8R8_2PLANE_422_UNORM
+    (b"YU24", 1000156006), // VK_FORMAT_G8_B8_R8_3PLANE_444_UNORM
+];
+
+#[repr(C)]
+struct VkApplicationInfo {
+    s_type: VkStructureType,
+    p_next: *const c_void,
+    p_application_name: *const c_char,
+    application_version: u32,
+    p_engine_name: *const c_char,
+    engine_version: u32,
+    api_version: u32,
+}
+
+#[repr(C)]
+struct VkInstanceCreateInfo {
+    s_type: VkStructureType,
+    p_next: *const c_void,
+    flags: u32,
+    p_application_info: *const VkApplicationInfo,
+    enabled_layer_count: u32,
+    pp_enabled_layer_names: *const *const c_char,
+    enabled_extension_count: u32,
+    pp_enabled_extension_names: *const *const c_char,
+}
+
+#[repr(C)]
+struct VkExtensionProperties {
+    extension_name: [c_char; 256],
+    spec_version: u32,
+}
+
+#[repr(C)]
+struct VkDeviceQueueCreateInfo {
+    s_type: VkStructureType,
+    p_next: *const c_void,
+    flags: u32,
+    queue_family_index: u32,
+    queue_count: u32,
+    p_queue_priorities: *const f32,
+}
+
+#[repr(C)]
+struct VkDeviceCreateInfo {
+    s_type: VkStructureType,
+    p_next: *const c_void,
+    flags: u32,
+    queue_create_info_count: u32,
+    p_queue_create_infos: *const VkDeviceQueueCreateInfo,
+    enabled_layer_count: u32,
+    pp_enabled_layer_names: *const *const c_char,
+    enabled_extension_count: u32,
+    pp_enabled_extension_names: *const *const c_char,
+    p_enabled_features: *const c_void,
+}
+
+#[repr(C)]
+struct VkExtent3D {
+    width: u32,
+    height: u32,
+    depth: u32,
+}
+
+#[repr(C)]
+struct VkImageCreateInfo {
+    s_type: VkStructureType,
+    p_next: *const c_void,
+    flags: u32,
+    image_type: i32,
+    format: VkFormat,
+    extent: VkExtent3D,
+    mip_levels: u32,
+    array_layers: u32,
+    samples: u32,
+    tiling: i32,
+    usage: u32,
+    sharing_mode: i32,
+    queue_family_index_count: u32,
+    p_queue_family_indices: *const u32,
+    initial_layout: i32,
+}
+
+#[repr(C)]
+struct VkExternalMemoryImageCreateInfo {
+    s_type: VkStructureType,
+    p_next: *const c_void,
+    handle_types: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VkSubresourceLayout {
+    offset: u64,
+    size: u64,
+    row_pitch: u64,
+    array_pitch: u64,
+    depth_pitch: u64,
+}
+
+#[repr(C)]
+struct VkImageDrmFormatModifierExplicitCreateInfoEXT {
+    s_type: VkStructureType,
+    p_next: *const c_void,
+    drm_format_modifier: u64,
+    drm_format_modifier_plane_count: u32,
+    p_plane_layouts: *const VkSubresourceLayout,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VkMemoryRequirements {
+    size: u64,
+    alignment: u64,
+    memory_type_bits: u32,
+}
+
+#[repr(C)]
+struct VkImportMemoryFdInfoKHR {
+    s_type: VkStructureType,
+    p_next: *const c_void,
+    handle_type: u32,
+    fd: i32,
+}
+
+#[repr(C)]
+struct VkMemoryFdPropertiesKHR {
+    s_type: VkStructureType,
+    p_next: *mut c_void,
+    memory_type_bits: u32,
+}
+
+#[repr(C)]
+struct VkMemoryDedicatedAllocateInfo {
+    s_type: VkStructureType,
+    p_next: *const c_void,
+    image: VkImage,
+    buffer: u64,
+}
+
+#[repr(C)]
+struct VkMemoryAllocateInfo {
+    s_type: VkStructureType,
+    p_next: *const c_void,
+    allocation_size: u64,
+    memory_type_index: u32,
+}
+
+#[repr(C)]
+struct VkImageSubresource {
+    aspect_mask: u32,
+    mip_level: u32,
+    array_layer: u32,
+}
+
+#[link(name = "vulkan")]
+extern "C" {
+    fn vkCreateInstance(
+        create_info: *const VkInstanceCreateInfo,
+        allocator: *const c_void,
+        instance: *mut VkInstance,
+    ) -> VkResult;
+    fn vkDestroyInstance(instance: VkInstance, allocator: *const c_void);
+    fn vkEnumeratePhysicalDevices(
+        instance: VkInstance,
+        count: *mut u32,
+        devices: *mut VkPhysicalDevice,
+    ) -> VkResult;
+    fn vkEnumerateDeviceExtensionProperties(
+        physical_device: VkPhysicalDevice,
+        layer_name: *const c_char,
+        count: *mut u32,
+        properties: *mut VkExtensionProperties,
+    ) -> VkResult;
+    fn vkCreateDevice(
+        physical_device: VkPhysicalDevice,
+        create_info: *const VkDeviceCreateInfo,
+        allocator: *const c_void,
+        device: *mut VkDevice,
+    ) -> VkResult;
+    fn vkDestroyDevice(device: VkDevice, allocator: *const c_void);
+    fn vkGetDeviceProcAddr(device: VkDevice, name: *const c_char) -> *mut c_void;
+    fn vkCreateImage(
+        device: VkDevice,
+        create_info: *const VkImageCreateInfo,
+        allocator: *const c_void,
+        image: *mut VkImage,
+    ) -> VkResult;
+    fn vkDestroyImage(device: VkDevice, image: VkImage, allocator: *const c_void);
+    fn vkGetImageMemoryRequirements(
+        device: VkDevice,
+        image: VkImage,
+        requirements: *mut VkMemoryRequirements,
+    );
+    fn vkAllocateMemory(
+        device: VkDevice,
+        allocate_info: *const VkMemoryAllocateInfo,
+        allocator: *const c_void,
+        memory: *mut VkDeviceMemory,
+    ) -> VkResult;
+    fn vkFreeMemory(device: VkDevice, memory: VkDeviceMemory, allocator: *const c_void);
+    fn vkBindImageMemory(
+        device: VkDevice,
+        image: VkImage,
+        memory: VkDeviceMemory,
+        offset: u64,
+    ) -> VkResult;
+    fn vkGetImageSubresourceLayout(
+        device: VkDevice,
+        image: VkImage,
+        subresource: *const VkImageSubresource,
+        layout: *mut VkSubresourceLayout,
+    );
+}
+
+type GetMemoryFdPropertiesKhr = unsafe extern "C" fn(
+    device: VkDevice,
+    handle_type: u32,
+    fd: i32,
+    properties: *mut VkMemoryFdPropertiesKHR,
+) -> VkResult;
+
+fn check(what: &str, result: VkResult) -> Result<()> {
+    ensure!(result == VK_SUCCESS, "{} failed with {}", what, result);
+    Ok(())
+}
+
+/// Returns the Vulkan format equivalent to `format`, if it can be imported.
+fn vk_format(format: &Format) -> Option<VkFormat> {
+    let drm_fourcc = format.pixelformat.to_drm_fourcc()?;
+    VK_FORMATS
+        .iter()
+        .find(|(fourcc, _)| u32::from_le_bytes(**fourcc) == drm_fourcc)
+        .map(|(_, vk_format)| *vk_format)
+}
+
+/// A Vulkan device able to import dmabufs.
+pub struct VulkanImporter {
+    instance: VkInstance,
+    device: VkDevice,
+    get_memory_fd_properties: GetMemoryFdPropertiesKhr,
+}
+
+impl VulkanImporter {
+    pub fn new() -> Result<Self> {
+        let app_info = VkApplicationInfo {
+            s_type: VK_STRUCTURE_TYPE_APPLICATION_INFO,
+            p_next: ptr::null(),
+            p_application_name: b"v4l2r\0".as_ptr() as *const c_char,
+            application_version: 0,
+            p_engine_name: ptr::null(),
+            engine_version: 0,
+            api_version: VK_API_VERSION_1_1,
+        };
+        let instance_info = VkInstanceCreateInfo {
+            s_type: VK_STRUCTURE_TYPE_INSTANCE_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: 0,
+            p_application_info: &app_info,
+            enabled_layer_count: 0,
+            pp_enabled_layer_names: ptr::null(),
+            enabled_extension_count: 0,
+            pp_enabled_extension_names: ptr::null(),
+        };
+        let mut instance = ptr::null_mut();
+        // SAFETY: `instance_info` and the structures it points to are valid.
+        check("vkCreateInstance", unsafe {
+            vkCreateInstance(&instance_info, ptr::null(), &mut instance)
+        })?;
+
+        match Self::create_device(instance) {
+            Ok((device, get_memory_fd_properties)) => Ok(VulkanImporter {
+                instance,
+                device,
+                get_memory_fd_properties,
+            }),
+            Err(e) => {
+                // SAFETY: the instance has no child object.
+                unsafe { vkDestroyInstance(instance, ptr::null()) };
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns whether `physical_device` supports all of `DEVICE_EXTENSIONS`.
+    fn supports_extensions(physical_device: VkPhysicalDevice) -> Result<bool> {
+        let mut count = 0;
+        // SAFETY: a null properties pointer only queries their number, and the
+        // second call writes at most `count` of them.
+        let properties = unsafe {
+            check(
+                "vkEnumerateDeviceExtensionProperties",
+                vkEnumerateDeviceExtensionProperties(
+                    physical_device,
+                    ptr::null(),
+                    &mut count,
+                    ptr::null_mut(),
+                ),
+            )?;
+            let mut properties = Vec::with_capacity(count as usize);
+            check(
+                "vkEnumerateDeviceExtensionProperties",
+                vkEnumerateDeviceExtensionProperties(
+                    physical_device,
+                    ptr::null(),
+                    &mut count,
+                    properties.as_mut_ptr(),
+                ),
+            )?;
+            properties.set_len(count as usize);
+            properties
+        };
+
+        Ok(DEVICE_EXTENSIONS.iter().all(|extension| {
+            properties.iter().any(|p| {
+                // SAFETY: extension names are nul-terminated.
+                let name = unsafe { CStr::from_ptr(p.extension_name.as_ptr()) };
+                name.to_bytes_with_nul() == *extension
+            })
+        }))
+    }
+
+    /// Create a device on the first physical device supporting dmabuf import.
+    fn create_device(instance: VkInstance) -> Result<(VkDevice, GetMemoryFdPropertiesKhr)> {
+        let mut count = 0;
+        // SAFETY: same as `supports_extensions`.
+        let physical_devices = unsafe {
+            check(
+                "vkEnumeratePhysicalDevices",
+                vkEnumeratePhysicalDevices(instance, &mut count, ptr::null_mut()),
+            )?;
+            let mut physical_devices = vec![ptr::null_mut(); count as usize];
+            check(
+                "vkEnumeratePhysicalDevices",
+                vkEnumeratePhysicalDevices(instance, &mut count, physical_devices.as_mut_ptr()),
+            )?;
+            physical_devices.truncate(count as usize);
+            physical_devices
+        };
+
+        let mut physical_device = None;
+        for candidate in physical_devices {
+            if Self::supports_extensions(candidate)? {
+                physical_device = Some(candidate);
+                break;
+            }
+        }
+        let physical_device = physical_device
+            .ok_or_else(|| anyhow!("no Vulkan device supports importing dmabufs"))?;
+
+        // We never submit work, but a device needs at least one queue.
+        let priority = 1.0f32;
+        let queue_info = VkDeviceQueueCreateInfo {
+            s_type: VK_STRUCTURE_TYPE_DEVICE_QUEUE_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: 0,
+            queue_family_index: 0,
+            queue_count: 1,
+            p_queue_priorities: &priority,
+        };
+        let extensions: Vec<*const c_char> = DEVICE_EXTENSIONS
+            .iter()
+            .map(|e| e.as_ptr() as *const c_char)
+            .collect();
+        let device_info = VkDeviceCreateInfo {
+            s_type: VK_STRUCTURE_TYPE_DEVICE_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: 0,
+            queue_create_info_count: 1,
+            p_queue_create_infos: &queue_info,
+            enabled_layer_count: 0,
+            pp_enabled_layer_names: ptr::null(),
+            enabled_extension_count: extensions.len() as u32,
+            pp_enabled_extension_names: extensions.as_ptr(),
+            p_enabled_features: ptr::null(),
+        };
+
+        let mut device = ptr::null_mut();
+        // SAFETY: `device_info` and the structures it points to are valid.
+        check("vkCreateDevice", unsafe {
+            vkCreateDevice(physical_device, &device_info, ptr::null(), &mut device)
+        })?;
+
+        // SAFETY: the name is nul-terminated, and the function has the type
+        // given by the specification.
+        let get_memory_fd_properties = unsafe {
+            let proc = vkGetDeviceProcAddr(
+                device,
+                b"vkGetMemoryFdPropertiesKHR\0".as_ptr() as *const c_char,
+            );
+            if proc.is_null() {
+                vkDestroyDevice(device, ptr::null());
+                bail!("vkGetMemoryFdPropertiesKHR is not available");
+            }
+            std::mem::transmute::<*mut c_void, GetMemoryFdPropertiesKhr>(proc)
+        };
+
+        Ok((device, get_memory_fd_properties))
+    }
+
+    /// Import the frame of `format` stored in `dmabufs`, one per memory plane,
+    /// as a linear image whose planes are laid out as `format` says.
+    ///
+    /// Vulkan duplicates the file descriptors, so `dmabufs` can be closed once
+    /// this returns.
+    pub fn import(&self, format: &Format, dmabufs: &[File]) -> Result<DmaBufImage<'_>> {
+        let vk_format = vk_format(format)
+            .ok_or_else(|| anyhow!("{} cannot be imported into Vulkan", format.pixelformat))?;
+        let planes = format
+            .color_plane_layouts()
+            .ok_or_else(|| anyhow!("unknown layout for {}", format.pixelformat))?;
+        // Buffers with several memory planes would need to be imported as
+        // disjoint images, using one allocation per plane.
+        ensure!(
+            dmabufs.len() == 1,
+            "importing formats with several memory planes is not supported"
+        );
+        ensure!(
+            planes.len() <= VK_IMAGE_ASPECT_MEMORY_PLANE_BITS_EXT.len(),
+            "too many planes"
+        );
+
+        let plane_layouts: Vec<VkSubresourceLayout> = planes
+            .iter()
+            .map(|plane| VkSubresourceLayout {
+                offset: plane.offset as u64,
+                row_pitch: plane.pitch as u64,
+                ..Default::default()
+            })
+            .collect();
+        let modifier_info = VkImageDrmFormatModifierExplicitCreateInfoEXT {
+            s_type: VK_STRUCTURE_TYPE_IMAGE_DRM_FORMAT_MODIFIER_EXPLICIT_CREATE_INFO_EXT,
+            p_next: ptr::null(),
+            drm_format_modifier: DRM_FORMAT_MOD_LINEAR,
+            drm_format_modifier_plane_count: plane_layouts.len() as u32,
+            p_plane_layouts: plane_layouts.as_ptr(),
+        };
+        let external_info = VkExternalMemoryImageCreateInfo {
+            s_type: VK_STRUCTURE_TYPE_EXTERNAL_MEMORY_IMAGE_CREATE_INFO,
+            p_next: &modifier_info as *const _ as *const c_void,
+            handle_types: VK_EXTERNAL_MEMORY_HANDLE_TYPE_DMA_BUF_BIT_EXT,
+        };
+        let image_info = VkImageCreateInfo {
+            s_type: VK_STRUCTURE_TYPE_IMAGE_CREATE_INFO,
+            p_next: &external_info as *const _ as *const c_void,
+            flags: 0,
+            image_type: VK_IMAGE_TYPE_2D,
+            format: vk_format,
+            extent: VkExtent3D {
+                width: format.width,
+                height: format.height,
+                depth: 1,
+            },
+            mip_levels: 1,
+            array_layers: 1,
+            samples: VK_SAMPLE_COUNT_1_BIT,
+            tiling: VK_IMAGE_TILING_DRM_FORMAT_MODIFIER_EXT,
+            usage: VK_IMAGE_USAGE_SAMPLED_BIT | VK_IMAGE_USAGE_TRANSFER_SRC_BIT,
+            sharing_mode: VK_SHARING_MODE_EXCLUSIVE,
+            queue_family_index_count: 0,
+            p_queue_family_indices: ptr::null(),
+            initial_layout: VK_IMAGE_LAYOUT_UNDEFINED,
+        };
+
+        let mut image = 0;
+        // SAFETY: `image_info` and the structures it points to are valid.
+        check("vkCreateImage", unsafe {
+            vkCreateImage(self.device, &image_info, ptr::null(), &mut image)
+        })?;
+        let mut image = DmaBufImage {
+            importer: self,
+            image,
+            memory: 0,
+            planes,
+        };
+        image.bind(&dmabufs[0])?;
+
+        Ok(image)
+    }
+}
+
+impl Drop for VulkanImporter {
+    fn drop(&mut self) {
+        // SAFETY: all the images have been destroyed, since they borrow us.
+        unsafe {
+            vkDestroyDevice(self.device, ptr::null());
+            vkDestroyInstance(self.instance, ptr::null());
+        }
+    }
+}
+
+/// A dmabuf imported as a Vulkan image, valid until dropped.
+pub struct DmaBufImage<'a> {
+    importer: &'a VulkanImporter,
+    image: VkImage,
+    memory: VkDeviceMemory,
+    planes: Vec<ColorPlaneLayout>,
+}
+
+impl<'a> DmaBufImage<'a> {
+    /// Import `dmabuf` and bind it to the image.
+    fn bind(&mut self, dmabuf: &File) -> Result<()> {
+        let device = self.importer.device;
+
+        let mut requirements = VkMemoryRequirements::default();
+        let mut fd_properties = VkMemoryFdPropertiesKHR {
+            s_type: VK_STRUCTURE_TYPE_MEMORY_FD_PROPERTIES_KHR,
+            p_next: ptr::null_mut(),
+            memory_type_bits: 0,
+        };
+        // SAFETY: the image is valid and the output structures are writable.
+        unsafe {
+            vkGetImageMemoryRequirements(device, self.image, &mut requirements);
+            check(
+                "vkGetMemoryFdPropertiesKHR",
+                (self.importer.get_memory_fd_properties)(
+                    device,
+                    VK_EXTERNAL_MEMORY_HANDLE_TYPE_DMA_BUF_BIT_EXT,
+                    dmabuf.as_raw_fd(),
+                    &mut fd_properties,
+                ),
+            )?;
+        }
+        let memory_types = requirements.memory_type_bits & fd_properties.memory_type_bits;
+        ensure!(memory_types != 0, "no memory type can import the dmabuf");
+
+        // Vulkan takes ownership of the file descriptor if the import succeeds.
+        let fd = dmabuf.try_clone()?;
+        let dedicated_info = VkMemoryDedicatedAllocateInfo {
+            s_type: VK_STRUCTURE_TYPE_MEMORY_DEDICATED_ALLOCATE_INFO,
+            p_next: ptr::null(),
+            image: self.image,
+            buffer: 0,
+        };
+        let import_info = VkImportMemoryFdInfoKHR {
+            s_type: VK_STRUCTURE_TYPE_IMPORT_MEMORY_FD_INFO_KHR,
+            p_next: &dedicated_info as *const _ as *const c_void,
+            handle_type: VK_EXTERNAL_MEMORY_HANDLE_TYPE_DMA_BUF_BIT_EXT,
+            fd: fd.as_raw_fd(),
+        };
+        let allocate_info = VkMemoryAllocateInfo {
+            s_type: VK_STRUCTURE_TYPE_MEMORY_ALLOCATE_INFO,
+            p_next: &import_info as *const _ as *const c_void,
+            allocation_size: requirements.size,
+            memory_type_index: memory_types.trailing_zeros(),
+        };
+        // SAFETY: `allocate_info` and the structures it points to are valid.
+        check("vkAllocateMemory", unsafe {
+            vkAllocateMemory(device, &allocate_info, ptr::null(), &mut self.memory)
+        })?;
+        let _ = fd.into_raw_fd();
+
+        // SAFETY: the image and memory are valid, and the memory is dedicated
+        // to the image.
+        check("vkBindImageMemory", unsafe {
+            vkBindImageMemory(device, self.image, self.memory, 0)
+        })
+    }
+
+    /// Returns the layout of each plane as seen by the Vulkan driver, which
+    /// should match the one the image has been created with.
+    pub fn plane_layouts(&self) -> Vec<(u64, u64)> {
+        VK_IMAGE_ASPECT_MEMORY_PLANE_BITS_EXT
+            .iter()
+            .take(self.planes.len())
+            .map(|aspect| {
+                let subresource = VkImageSubresource {
+                    aspect_mask: *aspect,
+                    mip_level: 0,
+                    array_layer: 0,
+                };
+                let mut layout = VkSubresourceLayout::default();
+                // SAFETY: the image has as many memory planes as `planes`.
+                unsafe {
+                    vkGetImageSubresourceLayout(
+                        self.importer.device,
+                        self.image,
+                        &subresource,
+                        &mut layout,
+                    )
+                };
+                (layout.offset, layout.row_pitch)
+            })
+            .collect()
+    }
+
+    /// Returns the layout the image has been created with.
+    pub fn expected_layouts(&self) -> &[ColorPlaneLayout] {
+        &self.planes
+    }
+}
+
+impl<'a> Drop for DmaBufImage<'a> {
+    fn drop(&mut self) {
+        // SAFETY: the image and memory have been created by `importer`, and
+        // freeing a null memory handle is a no-op.
+        unsafe {
+            vkDestroyImage(self.importer.device, self.image, ptr::null());
+            vkFreeMemory(self.importer.device, self.memory, ptr::null());
+        }
+    }
+}
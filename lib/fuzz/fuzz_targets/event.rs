@@ -0,0 +1,19 @@
+//! Parses arbitrary `v4l2_event`s and `v4l2_event_subscription`s.
+#![no_main]
+
+use std::convert::TryFrom;
+
+use libfuzzer_sys::fuzz_target;
+use v4l2r::{
+    bindings::{v4l2_event, v4l2_event_subscription},
+    ioctl::{Event, EventType},
+};
+use v4l2r_fuzz::from_bytes;
+
+fuzz_target!(|data: &[u8]| {
+    let event: v4l2_event = unsafe { from_bytes(data) };
+    let _ = Event::try_from(event);
+
+    let subscription: v4l2_event_subscription = unsafe { from_bytes(data) };
+    let _ = EventType::try_from(&subscription);
+});
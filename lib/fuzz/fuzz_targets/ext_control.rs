@@ -0,0 +1,94 @@
+//! Builds and validates extended controls from arbitrary payloads, and marshals them through the
+//! extended controls ioctls.
+#![no_main]
+
+use std::convert::TryFrom;
+use std::fs::File;
+
+use libfuzzer_sys::fuzz_target;
+use v4l2r::{
+    bindings::{v4l2_ctrl_fwht_params, v4l2_ext_control, v4l2_ext_controls},
+    controls::{
+        camera::ExposureMode, codec::FwhtParams, flash::LedMode, jpeg::ChromaSubsampling,
+        user::Brightness, AsV4l2ControlSlice, ControlTransaction, ExtControlTrait, SafeExtControl,
+    },
+    ioctl::{self, CtrlWhich, ValidControl},
+};
+use v4l2r_fuzz::from_bytes;
+
+#[repr(C)]
+struct Controls {
+    brightness: SafeExtControl<Brightness>,
+    fwht: SafeExtControl<FwhtParams>,
+}
+
+impl AsV4l2ControlSlice for &mut Controls {
+    fn as_v4l2_control_slice(&mut self) -> &mut [v4l2_ext_control] {
+        let ptr = (*self) as *mut Controls as *mut v4l2_ext_control;
+        unsafe { std::slice::from_raw_parts_mut(ptr, 2) }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let value: i32 = unsafe { from_bytes(data) };
+    let _ = ChromaSubsampling::try_from(value);
+    let _ = LedMode::try_from(value);
+    let _ = ExposureMode::try_from(value);
+    assert_eq!(
+        SafeExtControl::<Brightness>::from_value(value).value(),
+        value
+    );
+
+    let params: v4l2_ctrl_fwht_params = unsafe { from_bytes(data) };
+    let control = SafeExtControl::<FwhtParams>::from(params);
+    assert_eq!(*control.fwht_params(), params);
+    let _ = control.flags();
+    // The accessors of a validated control must not fail.
+    if let Ok(valid) = ValidControl::try_from(params) {
+        let _ = valid.flags();
+        let _ = valid.colorspace();
+        let _ = valid.xfer_func();
+        let _ = valid.ycbcr_enc();
+        let _ = valid.quantization();
+    }
+
+    let raw_controls: v4l2_ext_controls = unsafe { from_bytes(data) };
+    let which = CtrlWhich::try_from(&raw_controls).unwrap_or(CtrlWhich::Current);
+
+    // The controls must be marshalled as the ioctls expect them.
+    let mut controls = Controls {
+        brightness: SafeExtControl::from_value(value),
+        fwht: control,
+    };
+    let mut controls_ref = &mut controls;
+    let controls_slice = controls_ref.as_v4l2_control_slice();
+    let ptr = controls_slice.as_mut_ptr();
+    let ext_controls = which.ext_controls(controls_slice);
+    assert_eq!(ext_controls.count, 2);
+    assert_eq!(ext_controls.controls, ptr);
+    assert_eq!(CtrlWhich::try_from(&ext_controls), Ok(which));
+    let marshalled =
+        unsafe { std::slice::from_raw_parts(ext_controls.controls, ext_controls.count as usize) };
+    assert_eq!({ marshalled[0].id }, Brightness::ID);
+    assert_eq!(unsafe { marshalled[0].__bindgen_anon_1.value }, value);
+    assert_eq!({ marshalled[1].id }, FwhtParams::ID);
+    assert_eq!(unsafe { *marshalled[1].__bindgen_anon_1.p_fwht_params }, params);
+
+    // Run the actual ioctls on a file that does not support them, which must fail without
+    // altering the controls.
+    let file = File::open("/dev/null").unwrap();
+    assert!(ioctl::g_ext_ctrls(&file, which, &mut controls).is_err());
+    assert!(ioctl::s_ext_ctrls(&file, which, &mut controls).is_err());
+    assert!(ioctl::try_ext_ctrls(&file, which, &mut controls).is_err());
+    assert_eq!(controls.brightness.value(), value);
+    assert_eq!(*controls.fwht.fwht_params(), params);
+
+    let error = ControlTransaction::new()
+        .set::<Brightness>(value)
+        .with_control(SafeExtControl::<FwhtParams>::from(params))
+        .commit(&file, which)
+        .unwrap_err();
+    assert!(error
+        .failed_control
+        .map_or(true, |id| id == Brightness::ID || id == FwhtParams::ID));
+});
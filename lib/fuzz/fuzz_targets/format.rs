@@ -0,0 +1,24 @@
+//! Converts arbitrary `v4l2_format`s into `Format`s and back.
+#![no_main]
+
+use std::convert::TryFrom;
+
+use libfuzzer_sys::fuzz_target;
+use v4l2r::{bindings::v4l2_format, ioctl::V4l2MplaneFormat, Format, QueueType};
+use v4l2r_fuzz::from_bytes;
+
+fuzz_target!(|data: &[u8]| {
+    let v4l2_format: v4l2_format = unsafe { from_bytes(data) };
+    let _ = V4l2MplaneFormat::try_from(v4l2_format);
+
+    let format = match Format::try_from(v4l2_format) {
+        Ok(format) => format,
+        Err(_) => return,
+    };
+    // A successful conversion implies a valid queue type.
+    let queue = QueueType::n(v4l2_format.type_).unwrap();
+
+    // Converting the format back must produce the same format.
+    let converted = v4l2_format::try_from((queue, &format)).unwrap();
+    assert_eq!(Format::try_from(converted).unwrap(), format);
+});
@@ -0,0 +1,21 @@
+//! Helpers shared by the fuzz targets of `v4l2r`.
+//!
+//! The targets are run with [cargo-fuzz](https://github.com/rust-fuzz/cargo-fuzz) from the `lib`
+//! directory, e.g.:
+//!
+//!     cargo +nightly fuzz run format
+
+/// Build a `T` from the raw bytes received from the driver, the way the ioctl wrappers receive
+/// them. Missing bytes are set to zero and extra bytes are ignored.
+///
+/// # Safety
+///
+/// `T` must be valid for any bit pattern, which is the case of all the V4L2 structures that do not
+/// contain references.
+pub unsafe fn from_bytes<T>(data: &[u8]) -> T {
+    let mut value: T = std::mem::zeroed();
+    let len = data.len().min(std::mem::size_of::<T>());
+    std::ptr::copy_nonoverlapping(data.as_ptr(), &mut value as *mut T as *mut u8, len);
+
+    value
+}
@@ -0,0 +1,210 @@
+//! Execution contexts for the callbacks of the encoder and decoder.
+//!
+//! Callbacks like the encoder's `output_ready_cb` are invoked inline on the
+//! thread that dequeues the buffers, which cannot dequeue anything else until
+//! they return. Heavyweight callbacks (file I/O, network...) can instead be
+//! moved to a dedicated worker thread with [`WorkerCallback`], or replaced by
+//! a bounded channel that the client drains at its own pace with
+//! [`bounded_channel`].
+
+use std::{
+    io,
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{debug, error};
+
+/// Callback running a closure on a dedicated worker thread.
+///
+/// Values passed to `call` are queued and processed by the worker thread in
+/// order. Dropping the `WorkerCallback` waits for all the queued values to be
+/// processed.
+pub struct WorkerCallback<T: Send + 'static> {
+    sender: Option<mpsc::Sender<T>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> WorkerCallback<T> {
+    /// Spawn a worker thread named `name` invoking `cb` for every value
+    /// passed to `call`.
+    pub fn new<F: FnMut(T) + Send + 'static>(name: &str, mut cb: F) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<T>();
+        let worker = thread::Builder::new().name(name.into()).spawn(move || {
+            // Returns once the sender is dropped.
+            for value in receiver {
+                cb(value);
+            }
+        })?;
+
+        Ok(Self {
+            sender: Some(sender),
+            worker: Some(worker),
+        })
+    }
+
+    /// Queue `value` to be processed by the worker thread.
+    pub fn call(&self, value: T) {
+        // The worker only stops after the sender is dropped, unless the
+        // callback panicked.
+        if self.sender.as_ref().unwrap().send(value).is_err() {
+            error!("Worker thread of callback is dead, value dropped");
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for WorkerCallback<T> {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("Worker thread of callback panicked");
+            }
+        }
+    }
+}
+
+/// Callback sending its values into a bounded channel, created with
+/// [`bounded_channel`].
+///
+/// `call` blocks while the channel is full, until either the client receives
+/// a value or the channel is closed with its [`ChannelCloser`]. Once closed,
+/// values that do not fit in the channel are dropped instead of waiting.
+pub struct ChannelCallback<T> {
+    sender: mpsc::SyncSender<T>,
+    closed: Arc<ClosedFlag>,
+}
+
+/// Whether a channel has been closed, and the condition `call` waits on.
+#[derive(Default)]
+struct ClosedFlag {
+    closed: Mutex<bool>,
+    cond: Condvar,
+}
+
+/// How long `call` waits before checking whether a full channel has been
+/// drained again. The receiver does not signal it receives values.
+const FULL_CHANNEL_RETRY_PERIOD: Duration = Duration::from_millis(5);
+
+impl<T> ChannelCallback<T> {
+    /// Send `value` into the channel, blocking while the channel is full and
+    /// has not been closed.
+    ///
+    /// The value is dropped if the receiving end has been dropped, or if the
+    /// channel is full and has been closed.
+    pub fn call(&self, mut value: T) {
+        loop {
+            match self.sender.try_send(value) {
+                Ok(()) => return,
+                Err(mpsc::TrySendError::Disconnected(_)) => {
+                    error!("Receiver of callback channel dropped, value dropped");
+                    return;
+                }
+                Err(mpsc::TrySendError::Full(v)) => value = v,
+            }
+
+            let closed = self.closed.closed.lock().unwrap();
+            if *closed {
+                debug!("Callback channel full after being closed, value dropped");
+                return;
+            }
+            let _ = self
+                .closed
+                .cond
+                .wait_timeout(closed, FULL_CHANNEL_RETRY_PERIOD)
+                .unwrap();
+        }
+    }
+
+    /// Returns a handle that can close the channel from another thread.
+    pub fn closer(&self) -> ChannelCloser {
+        ChannelCloser(Arc::clone(&self.closed))
+    }
+}
+
+/// Closes the channel of a [`ChannelCallback`], so it stops waiting for the
+/// client to make room in it.
+///
+/// Values already in the channel can still be received.
+#[derive(Clone)]
+pub struct ChannelCloser(Arc<ClosedFlag>);
+
+impl ChannelCloser {
+    pub fn close(&self) {
+        *self.0.closed.lock().unwrap() = true;
+        self.0.cond.notify_all();
+    }
+}
+
+/// Create a callback delivering its values through a channel able to hold
+/// `capacity` values, and the receiver the client drains them from.
+pub fn bounded_channel<T>(capacity: usize) -> (ChannelCallback<T>, mpsc::Receiver<T>) {
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+
+    (
+        ChannelCallback {
+            sender,
+            closed: Default::default(),
+        },
+        receiver,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn worker_callback() {
+        let values = Arc::new(Mutex::new(Vec::new()));
+        let worker_values = Arc::clone(&values);
+        let cb = WorkerCallback::new("test worker", move |value: u32| {
+            worker_values.lock().unwrap().push(value);
+        })
+        .unwrap();
+
+        for value in 0..16 {
+            cb.call(value);
+        }
+        // Dropping waits for all the values to be processed.
+        drop(cb);
+
+        assert_eq!(*values.lock().unwrap(), (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn channel_callback() {
+        let (cb, receiver) = bounded_channel(2);
+        cb.call(1u32);
+        cb.call(2);
+        assert_eq!(receiver.try_recv(), Ok(1));
+        cb.call(3);
+        drop(cb);
+
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn channel_callback_close() {
+        let (cb, receiver) = bounded_channel(1);
+        let closer = cb.closer();
+        cb.call(1u32);
+
+        // The channel is full and nobody drains it, so this blocks until the
+        // channel is closed, like the encoder thread does while stopping.
+        let sender = thread::spawn(move || {
+            cb.call(2);
+            cb.call(3);
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(!sender.is_finished());
+        closer.close();
+        sender.join().unwrap();
+
+        // The values that did not fit have been dropped.
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), vec![1]);
+    }
+}
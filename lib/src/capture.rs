@@ -0,0 +1,378 @@
+//! High-level interface for streaming capture devices like cameras or video
+//! grabbers.
+//!
+//! [`CaptureStream`] keeps the CAPTURE queue of a device fed with buffers and
+//! returns the captured frames to the client. Live sources keep producing
+//! frames regardless of whether the client keeps up, and some drivers report
+//! errors if they run out of buffers to capture into. The [`FrameDropPolicy`]
+//! of the stream decides what happens when the client holds or has not
+//! consumed enough frames for the driver to keep capturing.
+use std::{collections::VecDeque, convert::Infallible, sync::Arc, time::Duration};
+
+use log::debug;
+use nix::errno::Errno;
+use thiserror::Error;
+
+use crate::{
+    device::{
+        poller::{DeviceEvent, PollError, PollEvent, Poller},
+        queue::{
+            direction::Capture,
+            dqbuf::DqBuffer,
+            qbuf::{
+                get_free::{GetFreeBufferError, GetFreeCaptureBuffer},
+                CaptureQueueable,
+            },
+            BuffersAllocated, Queue,
+        },
+        AllocatedQueue, Device, Stream, TryDequeue,
+    },
+    ioctl::{
+        self, DqBufError, DqBufIoctlError, OsError, StreamOffError, StreamOnError,
+        V4l2BufferFromError,
+    },
+    memory::{PlaneHandle, PrimitiveBufferHandles, SelfBacked},
+    shutdown::ShutdownToken,
+};
+
+/// What to do with captured frames when the driver has no buffer left to
+/// capture into, because the client is slower than the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrameDropPolicy {
+    /// Keep all the captured frames until the client consumes them, leaving
+    /// the driver without buffers in the meantime.
+    #[default]
+    Block,
+    /// Drop the oldest frame not yet returned to the client, so the client
+    /// always receives the most recent frames.
+    DropOldest,
+    /// Drop the most recent frame not yet returned to the client, so the
+    /// client receives frames in capture order without gaps until it catches
+    /// up.
+    DropNewest,
+}
+
+impl FrameDropPolicy {
+    /// Returns the frame of `pending` to drop in order to give a buffer back to
+    /// the driver, if any.
+    fn select_dropped<T>(&self, pending: &mut VecDeque<T>) -> Option<T> {
+        match self {
+            FrameDropPolicy::Block => None,
+            FrameDropPolicy::DropOldest => pending.pop_front(),
+            FrameDropPolicy::DropNewest => pending.pop_back(),
+        }
+    }
+}
+
+/// Statistics of a `CaptureStream`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CaptureStats {
+    /// Number of frames dequeued from the driver.
+    pub captured: usize,
+    /// Number of frames dropped according to the `FrameDropPolicy`.
+    pub dropped: usize,
+    /// Number of captured frames the driver reported an error for.
+    pub corrupted: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum CaptureStreamError {
+    #[error("error while creating the poller")]
+    PollerCreation(#[source] Errno),
+    #[error("error while registering the shutdown token")]
+    ShutdownToken(#[source] Errno),
+    #[error("error during poll")]
+    PollError(#[from] PollError),
+    #[error("error while dequeueing buffer")]
+    DequeueError(#[from] DqBufError<V4l2BufferFromError>),
+    #[error("error while obtaining a free buffer")]
+    GetFreeBufferError(#[from] GetFreeBufferError),
+    #[error("error while queueing buffer")]
+    QueueError(#[from] ioctl::QBufError<Infallible>),
+    #[error("error while starting streaming")]
+    StreamOnError(#[from] StreamOnError),
+    #[error("all the buffers are held by the client")]
+    Starved,
+}
+
+impl OsError for CaptureStreamError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            CaptureStreamError::PollerCreation(e) | CaptureStreamError::ShutdownToken(e) => {
+                Some(*e)
+            }
+            CaptureStreamError::PollError(e) => e.errno(),
+            CaptureStreamError::DequeueError(e) => e.errno(),
+            CaptureStreamError::GetFreeBufferError(e) => e.errno(),
+            CaptureStreamError::QueueError(e) => e.errno(),
+            CaptureStreamError::StreamOnError(e) => e.errno(),
+            CaptureStreamError::Starved => None,
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(CaptureStreamError);
+
+/// A streaming CAPTURE queue using driver-allocated (e.g. MMAP) buffers.
+///
+/// Frames returned by `next_frame` are borrowed from the driver: the buffer
+/// is given back to the driver once the returned `DqBuffer` is dropped.
+pub struct CaptureStream<P>
+where
+    P: PrimitiveBufferHandles + Default,
+    <P::HandleType as PlaneHandle>::Memory: SelfBacked,
+{
+    queue: Queue<Capture, BuffersAllocated<P>>,
+    poller: Poller,
+    policy: FrameDropPolicy,
+    /// Frames dequeued from the driver and not returned to the client yet.
+    pending: VecDeque<DqBuffer<Capture, P>>,
+    stats: CaptureStats,
+}
+
+impl<P> CaptureStream<P>
+where
+    P: PrimitiveBufferHandles + Default,
+    <P::HandleType as PlaneHandle>::Memory: SelfBacked,
+    for<'a> Queue<Capture, BuffersAllocated<P>>: GetFreeCaptureBuffer<'a, P>,
+{
+    /// Queue all the buffers of `queue`, which belongs to `device`, and start
+    /// streaming.
+    pub fn new(
+        device: Arc<Device>,
+        queue: Queue<Capture, BuffersAllocated<P>>,
+        policy: FrameDropPolicy,
+    ) -> Result<Self, CaptureStreamError> {
+        let mut poller = Poller::new(device).map_err(CaptureStreamError::PollerCreation)?;
+        poller
+            .enable_event(DeviceEvent::CaptureReady)
+            .map_err(CaptureStreamError::PollerCreation)?;
+
+        let mut stream = CaptureStream {
+            queue,
+            poller,
+            policy,
+            pending: VecDeque::new(),
+            stats: Default::default(),
+        };
+        stream.queue_free_buffers()?;
+        stream.queue.stream_on()?;
+
+        Ok(stream)
+    }
+
+    /// Returns the policy applied when the driver runs out of buffers.
+    pub fn policy(&self) -> FrameDropPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: FrameDropPolicy) {
+        self.policy = policy;
+    }
+
+    /// Makes `next_frame()` fail with `PollError::Interrupted` instead of
+    /// waiting once shutdown is requested on `token`.
+    pub fn set_shutdown_token(&mut self, token: ShutdownToken) -> Result<(), CaptureStreamError> {
+        self.poller
+            .set_shutdown_token(token)
+            .map_err(CaptureStreamError::ShutdownToken)
+    }
+
+    /// Returns the number of frames captured and dropped so far.
+    pub fn stats(&self) -> CaptureStats {
+        self.stats
+    }
+
+    /// Give all the free buffers back to the driver.
+    fn queue_free_buffers(&mut self) -> Result<(), CaptureStreamError> {
+        while self.queue.num_free_buffers() > 0 {
+            self.queue
+                .try_get_free_buffer()?
+                .queue_with_handles(P::default())
+                .map_err(|e| e.error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Dequeue all the frames that are ready, and make sure the driver keeps
+    /// at least one buffer to capture into, dropping pending frames if the
+    /// policy allows it.
+    fn process_ready_frames(&mut self) -> Result<(), CaptureStreamError> {
+        loop {
+            let ready = self
+                .poller
+                .poll(Some(Duration::ZERO))?
+                .any(|event| event == PollEvent::Device(DeviceEvent::CaptureReady));
+            if !ready {
+                break;
+            }
+
+            match self.queue.try_dequeue() {
+                Ok(frame) => {
+                    self.stats.captured += 1;
+                    if frame.has_error() {
+                        self.stats.corrupted += 1;
+                    }
+                    self.pending.push_back(frame);
+                }
+                Err(DqBufError::IoctlError(DqBufIoctlError::NotReady)) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        self.queue_free_buffers()?;
+        if self.queue.num_queued_buffers() == 0 {
+            if let Some(frame) = self.policy.select_dropped(&mut self.pending) {
+                debug!("Dropping frame {} to keep capturing", frame.index());
+                drop(frame);
+                self.stats.dropped += 1;
+                self.queue_free_buffers()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next captured frame, or `None` if no frame is currently
+    /// available.
+    pub fn try_next_frame(&mut self) -> Result<Option<DqBuffer<Capture, P>>, CaptureStreamError> {
+        self.process_ready_frames()?;
+
+        Ok(self.pending.pop_front())
+    }
+
+    /// Returns the next captured frame, waiting for it if needed.
+    ///
+    /// Fails with `Starved` if all the buffers are held by the client, as no
+    /// frame can be captured until some of them are dropped.
+    pub fn next_frame(&mut self) -> Result<DqBuffer<Capture, P>, CaptureStreamError> {
+        loop {
+            if let Some(frame) = self.try_next_frame()? {
+                return Ok(frame);
+            }
+            if self.queue.num_queued_buffers() == 0 {
+                return Err(CaptureStreamError::Starved);
+            }
+
+            self.poller.poll(None)?;
+        }
+    }
+
+    /// Returns a blocking iterator over the captured frames, so simple tools
+    /// like snapshot grabbers can be written as a `for` loop.
+    ///
+    /// Each frame is given back to the driver once dropped. The iterator ends
+    /// after returning an error.
+    ///
+    /// This lives on the stream rather than on the CAPTURE queue because
+    /// iterating requires what only the stream provides: a poller to wait for
+    /// frames, and requeueing the buffers the client drops so the driver
+    /// keeps capturing. A bare queue would stop yielding frames once all its
+    /// buffers have been dequeued.
+    ///
+    /// ```no_run
+    /// # use v4l2r::{capture::CaptureStream, memory::MmapHandle};
+    /// # fn grab(stream: &mut CaptureStream<Vec<MmapHandle>>) -> Result<(), Box<dyn std::error::Error>> {
+    /// for frame in stream.frames().take(10) {
+    ///     let frame = frame?;
+    ///     println!("frame {} captured", frame.sequence());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn frames(&mut self) -> Frames<'_, P> {
+        Frames {
+            stream: self,
+            failed: false,
+        }
+    }
+
+    /// Stop streaming and return the queue. Frames not returned to the client
+    /// yet are dropped.
+    pub fn stop(self) -> Result<Queue<Capture, BuffersAllocated<P>>, StreamOffError> {
+        self.queue.stream_off()?;
+        drop(self.pending);
+
+        Ok(self.queue)
+    }
+}
+
+/// Blocking iterator over the frames of a `CaptureStream`, returned by
+/// `CaptureStream::frames()`.
+pub struct Frames<'a, P>
+where
+    P: PrimitiveBufferHandles + Default,
+    <P::HandleType as PlaneHandle>::Memory: SelfBacked,
+{
+    stream: &'a mut CaptureStream<P>,
+    failed: bool,
+}
+
+impl<'a, P> Iterator for Frames<'a, P>
+where
+    P: PrimitiveBufferHandles + Default,
+    <P::HandleType as PlaneHandle>::Memory: SelfBacked,
+    for<'b> Queue<Capture, BuffersAllocated<P>>: GetFreeCaptureBuffer<'b, P>,
+{
+    type Item = Result<DqBuffer<Capture, P>, CaptureStreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let stream = &mut *self.stream;
+        next_until_error(&mut self.failed, || stream.next_frame())
+    }
+}
+
+/// Returns the result of `next`, unless a previous call returned an error as
+/// recorded in `failed`.
+fn next_until_error<T, E>(
+    failed: &mut bool,
+    next: impl FnOnce() -> Result<T, E>,
+) -> Option<Result<T, E>> {
+    if *failed {
+        return None;
+    }
+
+    let item = next();
+    *failed = item.is_err();
+
+    Some(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_drop_policy() {
+        let mut pending: VecDeque<_> = (0..3).collect();
+
+        assert_eq!(FrameDropPolicy::Block.select_dropped(&mut pending), None);
+        assert_eq!(
+            FrameDropPolicy::DropOldest.select_dropped(&mut pending),
+            Some(0)
+        );
+        assert_eq!(
+            FrameDropPolicy::DropNewest.select_dropped(&mut pending),
+            Some(2)
+        );
+        assert_eq!(pending, vec![1]);
+        assert_eq!(
+            FrameDropPolicy::DropNewest.select_dropped(&mut VecDeque::<u32>::new()),
+            None
+        );
+    }
+
+    #[test]
+    fn frames_end_after_error() {
+        let mut results = vec![Ok(1), Ok(2), Err("starved"), Ok(3)].into_iter();
+        let mut failed = false;
+        let frames: Vec<_> =
+            std::iter::from_fn(|| next_until_error(&mut failed, || results.next().unwrap()))
+                .collect();
+
+        assert_eq!(frames, vec![Ok(1), Ok(2), Err("starved")]);
+        // The frame following the error is never requested.
+        assert_eq!(results.next(), Some(Ok(3)));
+    }
+}
@@ -0,0 +1,434 @@
+//! Sanity checks of the behavior of a V4L2 device, in the spirit of `v4l2-compliance`.
+//!
+//! `run` exercises the parts of the V4L2 API this crate relies on: capabilities reporting,
+//! format enumeration and negotiation, the allocation of buffers and their state right after it,
+//! and the acceptance of the STOP command of memory-to-memory codecs. This is useful both to
+//! driver developers and to check that the assumptions of this crate hold on new hardware.
+//!
+//! The checks do not stream: the STOP command is only tried with `VIDIOC_TRY_DECODER_CMD` or
+//! `VIDIOC_TRY_ENCODER_CMD`, so no actual drain is performed. Formats are only ever set to their
+//! current value, and the buffer checks are skipped on queues whose buffers are in use. They
+//! still allocate and free buffers, so the device should not be used by anyone else while they
+//! run.
+//!
+//! ```no_run
+//! # use std::fs::File;
+//! #
+//! # use v4l2r::compliance;
+//! #
+//! let mut device = File::open("/dev/video0").unwrap();
+//! let report = compliance::run(&mut device);
+//! println!("{}", report);
+//! ```
+use std::collections::BTreeSet;
+use std::fmt;
+use std::os::unix::io::AsRawFd;
+
+use nix::errno::Errno;
+
+use crate::ioctl;
+use crate::ioctl::BufferFlags;
+use crate::ioctl::Capabilities;
+use crate::ioctl::Capability;
+use crate::ioctl::DecoderCmd;
+use crate::ioctl::EncoderCommand;
+use crate::ioctl::FormatFlags;
+use crate::ioctl::QueryBuffer;
+use crate::memory::MemoryType;
+use crate::Format;
+use crate::PixelFormat;
+use crate::QueueClass;
+use crate::QueueDirection;
+use crate::QueueType;
+
+/// Number of buffers allocated by the buffer checks.
+const NUM_TEST_BUFFERS: u32 = 2;
+
+/// Outcome of a single check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckStatus {
+    Passed,
+    /// The device does not behave as expected, for the given reason.
+    Failed(String),
+    /// The check does not apply to the device, for the given reason.
+    Skipped(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub name: &'static str,
+    /// Queue the check has been run on, if any.
+    pub queue: Option<QueueType>,
+    pub status: CheckStatus,
+}
+
+/// Results of all the checks run against a device.
+#[derive(Debug, Clone, Default)]
+pub struct ComplianceReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl ComplianceReport {
+    fn push(&mut self, name: &'static str, queue: Option<QueueType>, status: CheckStatus) {
+        self.results.push(CheckResult {
+            name,
+            queue,
+            status,
+        });
+    }
+
+    /// Returns the checks that failed.
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+        self.results
+            .iter()
+            .filter(|r| matches!(r.status, CheckStatus::Failed(_)))
+    }
+
+    /// Returns whether all the checks passed or were skipped.
+    pub fn is_success(&self) -> bool {
+        self.failures().next().is_none()
+    }
+}
+
+impl fmt::Display for ComplianceReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for result in &self.results {
+            let name = match result.queue {
+                Some(queue) => format!("{} ({:?})", result.name, queue),
+                None => result.name.to_string(),
+            };
+            match &result.status {
+                CheckStatus::Passed => writeln!(f, "{}: OK", name)?,
+                CheckStatus::Failed(reason) => writeln!(f, "{}: FAIL: {}", name, reason)?,
+                CheckStatus::Skipped(reason) => writeln!(f, "{}: skipped: {}", name, reason)?,
+            }
+        }
+
+        let num_failed = self.failures().count();
+        write!(
+            f,
+            "{} checks, {} passed, {} failed",
+            self.results.len(),
+            self.results
+                .iter()
+                .filter(|r| r.status == CheckStatus::Passed)
+                .count(),
+            num_failed
+        )
+    }
+}
+
+/// Returns a `CheckStatus` from the result of a check.
+fn status(res: Result<(), String>) -> CheckStatus {
+    match res {
+        Ok(()) => CheckStatus::Passed,
+        Err(reason) => CheckStatus::Failed(reason),
+    }
+}
+
+/// Returns the video queues of a device with capabilities `caps`, the only ones
+/// the checks apply to.
+fn queues(caps: Capabilities) -> Vec<QueueType> {
+    caps.queue_types()
+        .into_iter()
+        .filter(|queue| matches!(queue.class(), QueueClass::Video | QueueClass::VideoMplane))
+        .collect()
+}
+
+fn check_capabilities(cap: &Capability) -> Result<(), String> {
+    if cap.driver.is_empty() {
+        return Err("empty driver name".into());
+    }
+    if cap.card.is_empty() {
+        return Err("empty card name".into());
+    }
+    if let Some(device_caps) = cap.device_caps {
+        if !cap.capabilities.contains(Capabilities::DEVICE_CAPS) {
+            return Err("device_caps set without the DEVICE_CAPS capability".into());
+        }
+        if !cap.capabilities.contains(device_caps) {
+            return Err(format!(
+                "device capabilities {:?} are not a subset of the physical device ones {:?}",
+                device_caps, cap.capabilities
+            ));
+        }
+    }
+    if !cap.device_caps().contains(Capabilities::STREAMING) {
+        return Err("device does not support streaming I/O".into());
+    }
+
+    Ok(())
+}
+
+fn check_enum_fmt(fd: &impl AsRawFd, queue: QueueType) -> Result<Vec<PixelFormat>, String> {
+    let formats: Vec<_> = ioctl::FormatIterator::new(fd, queue).collect();
+    if formats.is_empty() {
+        return Err("no format enumerated".into());
+    }
+
+    let mut seen = BTreeSet::new();
+    for format in &formats {
+        if !seen.insert(u32::from(format.pixelformat)) {
+            return Err(format!("format {} enumerated twice", format.pixelformat));
+        }
+        if format.description.is_empty() {
+            return Err(format!("format {} has no description", format.pixelformat));
+        }
+    }
+
+    Ok(formats.iter().map(|f| f.pixelformat).collect())
+}
+
+/// Checks that the current format is accepted unchanged by `TRY_FMT` and `S_FMT`.
+fn check_format_round_trip(fd: &mut impl AsRawFd, queue: QueueType) -> Result<(), String> {
+    let current: Format = ioctl::g_fmt(fd, queue).map_err(|e| format!("G_FMT failed: {}", e))?;
+
+    let tried: Format = ioctl::try_fmt(&*fd, (queue, &current))
+        .map_err(|e| format!("TRY_FMT of the current format failed: {}", e))?;
+    if tried != current {
+        return Err(format!(
+            "TRY_FMT changed the current format {:?} into {:?}",
+            current, tried
+        ));
+    }
+
+    let set: Format = ioctl::s_fmt(fd, (queue, &current))
+        .map_err(|e| format!("S_FMT of the current format failed: {}", e))?;
+    if set != current {
+        return Err(format!(
+            "S_FMT changed the current format {:?} into {:?}",
+            current, set
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that `TRY_FMT` adjusts an unsupported pixel format instead of rejecting it.
+fn check_try_fmt_adjusts(
+    fd: &impl AsRawFd,
+    queue: QueueType,
+    formats: &[PixelFormat],
+) -> Result<(), String> {
+    let mut format: Format = ioctl::g_fmt(fd, queue).map_err(|e| format!("G_FMT failed: {}", e))?;
+    format.pixelformat = PixelFormat::from_fourcc(b"XXXX");
+
+    let adjusted: Format = ioctl::try_fmt(fd, (queue, &format))
+        .map_err(|e| format!("TRY_FMT rejected an unsupported pixel format: {}", e))?;
+    if !formats.contains(&adjusted.pixelformat) {
+        return Err(format!(
+            "TRY_FMT returned non-enumerated pixel format {}",
+            adjusted.pixelformat
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks the allocation of buffers and their state right after allocation.
+fn check_buffers(fd: &impl AsRawFd, queue: QueueType) -> CheckStatus {
+    match ioctl::reqbufs::<()>(fd, queue, MemoryType::Mmap, 0) {
+        Ok(()) => status(check_buffer_allocation(fd, queue)),
+        Err(ioctl::ReqbufsError::IoctlError(Errno::EBUSY)) => {
+            CheckStatus::Skipped("buffers of the queue are in use".into())
+        }
+        Err(e) => CheckStatus::Failed(format!("freeing buffers failed: {}", e)),
+    }
+}
+
+fn check_buffer_allocation(fd: &impl AsRawFd, queue: QueueType) -> Result<(), String> {
+    let count: usize = ioctl::reqbufs(fd, queue, MemoryType::Mmap, NUM_TEST_BUFFERS)
+        .map_err(|e| format!("allocating MMAP buffers failed: {}", e))?;
+    let res = (|| {
+        if count == 0 {
+            return Err("no buffer allocated".to_string());
+        }
+
+        for index in 0..count {
+            let buffer: QueryBuffer = ioctl::querybuf(fd, queue, index)
+                .map_err(|e| format!("QUERYBUF of buffer {} failed: {}", index, e))?;
+            if buffer.index != index {
+                return Err(format!(
+                    "QUERYBUF of buffer {} returned {}",
+                    index, buffer.index
+                ));
+            }
+            if buffer
+                .flags
+                .intersects(BufferFlags::QUEUED | BufferFlags::DONE)
+            {
+                return Err(format!(
+                    "buffer {} is in state {:?} right after allocation",
+                    index, buffer.flags
+                ));
+            }
+            if buffer.planes.iter().any(|plane| plane.length == 0) {
+                return Err(format!("buffer {} has an empty plane", index));
+            }
+        }
+
+        if ioctl::querybuf::<QueryBuffer>(fd, queue, count).is_ok() {
+            return Err(format!(
+                "QUERYBUF of out-of-range buffer {} succeeded",
+                count
+            ));
+        }
+
+        Ok(())
+    })();
+
+    ioctl::reqbufs::<()>(fd, queue, MemoryType::Mmap, 0)
+        .map_err(|e| format!("freeing buffers failed: {}", e))?;
+
+    res
+}
+
+/// Checks that `STREAMOFF` succeeds on a queue that is not streaming.
+fn check_streamoff_idle(fd: &impl AsRawFd, queue: QueueType) -> Result<(), String> {
+    ioctl::streamoff(fd, queue).map_err(|e| format!("STREAMOFF of an idle queue failed: {}", e))
+}
+
+/// Kind of memory-to-memory codec, guessed from the formats of its queues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CodecKind {
+    Decoder,
+    Encoder,
+}
+
+fn codec_kind(fd: &impl AsRawFd, queues: &[QueueType]) -> Option<CodecKind> {
+    let has_compressed = |direction: QueueDirection| {
+        queues
+            .iter()
+            .filter(|queue| queue.direction() == direction)
+            .any(|queue| {
+                ioctl::FormatIterator::new(fd, *queue)
+                    .any(|fmt| fmt.flags.contains(FormatFlags::COMPRESSED))
+            })
+    };
+
+    match (
+        has_compressed(QueueDirection::Output),
+        has_compressed(QueueDirection::Capture),
+    ) {
+        (true, false) => Some(CodecKind::Decoder),
+        (false, true) => Some(CodecKind::Encoder),
+        _ => None,
+    }
+}
+
+/// Checks that the codec accepts the STOP command used to drain it.
+fn check_drain(fd: &impl AsRawFd, kind: CodecKind) -> Result<(), String> {
+    match kind {
+        CodecKind::Decoder => ioctl::try_decoder_cmd::<_, ()>(fd, DecoderCmd::stop())
+            .map_err(|e| format!("TRY_DECODER_CMD(STOP) failed: {}", e)),
+        CodecKind::Encoder => ioctl::try_encoder_cmd::<_, ()>(fd, &EncoderCommand::Stop(false))
+            .map_err(|e| format!("TRY_ENCODER_CMD(STOP) failed: {}", e)),
+    }
+}
+
+/// Runs all the checks against the device opened as `fd`.
+pub fn run(fd: &mut impl AsRawFd) -> ComplianceReport {
+    let mut report = ComplianceReport::default();
+
+    let cap: Capability = match ioctl::querycap(&*fd) {
+        Ok(cap) => cap,
+        Err(e) => {
+            report.push(
+                "querycap",
+                None,
+                CheckStatus::Failed(format!("QUERYCAP failed: {}", e)),
+            );
+            return report;
+        }
+    };
+    report.push("querycap", None, status(check_capabilities(&cap)));
+
+    let queues = queues(cap.device_caps());
+    if queues.is_empty() {
+        report.push(
+            "queues",
+            None,
+            CheckStatus::Skipped("device has no video queue".into()),
+        );
+        return report;
+    }
+
+    for &queue in &queues {
+        let formats = check_enum_fmt(&*fd, queue);
+        report.push(
+            "enum_fmt",
+            Some(queue),
+            status(formats.as_ref().map(|_| ()).map_err(Clone::clone)),
+        );
+
+        report.push(
+            "format_round_trip",
+            Some(queue),
+            status(check_format_round_trip(fd, queue)),
+        );
+
+        report.push(
+            "try_fmt_adjusts",
+            Some(queue),
+            match &formats {
+                Ok(formats) => status(check_try_fmt_adjusts(&*fd, queue, formats)),
+                Err(_) => CheckStatus::Skipped("no enumerated format".into()),
+            },
+        );
+
+        report.push("buffers", Some(queue), check_buffers(&*fd, queue));
+        report.push(
+            "streamoff_idle",
+            Some(queue),
+            status(check_streamoff_idle(&*fd, queue)),
+        );
+    }
+
+    let is_m2m = cap
+        .device_caps()
+        .intersects(Capabilities::VIDEO_M2M | Capabilities::VIDEO_M2M_MPLANE);
+    report.push(
+        "drain",
+        None,
+        match codec_kind(&*fd, &queues) {
+            Some(kind) if is_m2m => status(check_drain(&*fd, kind)),
+            _ => CheckStatus::Skipped("device is not a codec".into()),
+        },
+    );
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_from_caps() {
+        assert_eq!(
+            queues(Capabilities::VIDEO_M2M_MPLANE | Capabilities::STREAMING),
+            vec![QueueType::VideoCaptureMplane, QueueType::VideoOutputMplane]
+        );
+        assert_eq!(
+            queues(Capabilities::VIDEO_CAPTURE),
+            vec![QueueType::VideoCapture]
+        );
+        assert!(queues(Capabilities::RDS_CAPTURE).is_empty());
+    }
+
+    #[test]
+    fn report_success() {
+        let mut report = ComplianceReport::default();
+        report.push("a", None, CheckStatus::Passed);
+        report.push("b", None, CheckStatus::Skipped("n/a".into()));
+        assert!(report.is_success());
+
+        report.push(
+            "c",
+            Some(QueueType::VideoCapture),
+            CheckStatus::Failed("broken".into()),
+        );
+        assert!(!report.is_success());
+        assert!(report.to_string().ends_with("3 checks, 1 passed, 1 failed"));
+    }
+}
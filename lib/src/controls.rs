@@ -57,11 +57,22 @@
 //! Sub-modules contain the type definitions for each control, organized by control class. Due to
 //! the large number of controls they are not all defined, so please add those you need if they are
 //! missing.
+//!
+//! Controls that are only known at runtime can be accessed through the [`descriptor`] module.
 
+pub mod camera;
 pub mod codec;
+pub mod descriptor;
+pub mod flash;
+pub mod image_proc;
+pub mod image_source;
+pub mod jpeg;
 pub mod user;
 
 use std::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
+
+use thiserror::Error;
 
 use crate::bindings;
 use crate::bindings::v4l2_ctrl_fwht_params;
@@ -69,6 +80,9 @@ use crate::bindings::v4l2_ctrl_vp8_frame;
 use crate::bindings::v4l2_ext_control;
 use crate::bindings::v4l2_ext_control__bindgen_ty_1;
 use crate::controls::codec::FwhtFlags;
+use crate::ioctl;
+use crate::ioctl::CtrlWhich;
+use crate::ioctl::ExtControlError;
 
 /// Trait implemented by types that can be passed to the
 /// [`g/s/try_ext_ctrls`](crate::ioctl::g_ext_ctrls) family of functions.
@@ -244,6 +258,32 @@ where
     }
 }
 
+/// Returns the validated ID of control `T`.
+pub(crate) fn ctrl_id<T: ExtControlTrait>() -> ioctl::CtrlId {
+    // Control IDs defined in the bindings are always valid.
+    ioctl::CtrlId::new(T::ID).unwrap()
+}
+
+/// Reads the current value of the 32-bit control `T`.
+pub(crate) fn get_current_value<T: ExtControlTrait<PAYLOAD = i32>>(
+    fd: &impl AsRawFd,
+) -> Result<i32, ExtControlError> {
+    let mut control = SafeExtControl::<T>::from_value(0);
+    ioctl::g_ext_ctrls(fd, CtrlWhich::Current, &mut control)?;
+    Ok(control.value())
+}
+
+/// Sets the current value of the 32-bit control `T` and returns the value actually applied by the
+/// driver.
+pub(crate) fn set_current_value<T: ExtControlTrait<PAYLOAD = i32>>(
+    fd: &impl AsRawFd,
+    value: i32,
+) -> Result<i32, ExtControlError> {
+    let mut control = SafeExtControl::<T>::from_value(value);
+    ioctl::s_ext_ctrls(fd, CtrlWhich::Current, &mut control)?;
+    Ok(control.value())
+}
+
 // Due to a limitation of the type system we cannot conditionally implement the `Drop` trait on
 // e.g. `where T: ControlTrait<PAYLOAD = v4l2_ctrl_fwht_params>`, so we need this global implementation.
 impl<T: ExtControlTrait> Drop for SafeExtControl<T> {
@@ -265,3 +305,146 @@ impl<T: ExtControlTrait> Drop for SafeExtControl<T> {
         }
     }
 }
+
+/// Type-erased owner of a `SafeExtControl`, used to keep the payload of compound controls alive
+/// while they are part of a `ControlTransaction`.
+trait OwnedControl {}
+
+impl<T: ExtControlTrait> OwnedControl for SafeExtControl<T> {}
+
+#[derive(Debug, Error)]
+#[error("failed to commit control transaction: {error}")]
+pub struct ControlTransactionError {
+    #[source]
+    pub error: ExtControlError,
+    /// ID of the control that caused the failure, if the driver could identify it. `None` means
+    /// that the failure is not related to a specific control, or that it happened before any
+    /// control was validated.
+    pub failed_control: Option<u32>,
+}
+
+impl ioctl::OsError for ControlTransactionError {
+    fn errno(&self) -> Option<nix::errno::Errno> {
+        ioctl::OsError::errno(&self.error)
+    }
+}
+
+ioctl::impl_into_io_error!(ControlTransactionError);
+
+/// Accumulates several control writes and commits them atomically with a single
+/// `VIDIOC_S_EXT_CTRLS` call.
+///
+/// If any control is rejected by the driver, then none of them is applied.
+///
+/// ```no_run
+/// # use std::path::Path;
+/// #
+/// # use v4l2r::controls::ControlTransaction;
+/// # use v4l2r::controls::user::Brightness;
+/// # use v4l2r::controls::user::Contrast;
+/// # use v4l2r::device::Device;
+/// # use v4l2r::ioctl::CtrlWhich;
+/// #
+/// # let device = Device::open(Path::new("/dev/video0"), Default::default()).unwrap();
+/// #
+/// ControlTransaction::new()
+///     .set::<Brightness>(128)
+///     .set::<Contrast>(64)
+///     .commit(&device, CtrlWhich::Current)
+///     .unwrap();
+/// ```
+#[derive(Default)]
+pub struct ControlTransaction {
+    controls: Vec<v4l2_ext_control>,
+    owners: Vec<Box<dyn OwnedControl>>,
+}
+
+impl ControlTransaction {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds `control` to the transaction. Its payload, if any, is kept alive until the
+    /// transaction is dropped.
+    pub fn with_control<T: ExtControlTrait + 'static>(
+        mut self,
+        control: SafeExtControl<T>,
+    ) -> Self {
+        // The copy shares the payload pointer of `control`, which we keep in `owners`.
+        self.controls.push(control.0);
+        self.owners.push(Box::new(control));
+        self
+    }
+
+    /// Adds a write of `value` to the 32-bit control `T` to the transaction.
+    pub fn set<T: ExtControlTrait<PAYLOAD = i32> + 'static>(self, value: i32) -> Self {
+        self.with_control(SafeExtControl::<T>::from_value(value))
+    }
+
+    /// Adds a write of `value` to the 64-bit control `T` to the transaction.
+    pub fn set64<T: ExtControlTrait<PAYLOAD = i64> + 'static>(self, value: i64) -> Self {
+        self.with_control(SafeExtControl::<T>::from_value64(value))
+    }
+
+    /// Returns the number of controls in the transaction.
+    pub fn len(&self) -> usize {
+        self.controls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.controls.is_empty()
+    }
+
+    /// Sets all the controls of the transaction with a single `VIDIOC_S_EXT_CTRLS` call.
+    ///
+    /// Use `CtrlWhich::Request` to apply the controls to a request instead of immediately.
+    pub fn commit(
+        mut self,
+        fd: &impl AsRawFd,
+        which: CtrlWhich,
+    ) -> Result<(), ControlTransactionError> {
+        if self.controls.is_empty() {
+            return Ok(());
+        }
+
+        ioctl::s_ext_ctrls(fd, which, self.controls.as_mut_slice()).map_err(|error| {
+            ControlTransactionError {
+                // An index equal to the number of controls means that the failure was not caused
+                // by a specific control.
+                failed_control: self
+                    .controls
+                    .get(error.error_idx as usize)
+                    .map(|control| control.id),
+                error,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controls::codec::FwhtParams;
+    use crate::controls::user::Brightness;
+
+    #[test]
+    fn control_transaction() {
+        let transaction = ControlTransaction::new()
+            .set::<Brightness>(128)
+            .with_control(SafeExtControl::<FwhtParams>::from(v4l2_ctrl_fwht_params {
+                width: 640,
+                ..Default::default()
+            }));
+        assert_eq!(transaction.len(), 2);
+
+        let controls = &transaction.controls;
+        assert_eq!({ controls[0].id }, bindings::V4L2_CID_BRIGHTNESS);
+        assert_eq!(unsafe { controls[0].__bindgen_anon_1.value }, 128);
+        assert_eq!({ controls[1].id }, bindings::V4L2_CID_STATELESS_FWHT_PARAMS);
+        // The payload must still be valid while the transaction is alive.
+        assert_eq!(
+            unsafe { (*controls[1].__bindgen_anon_1.p_fwht_params).width },
+            640
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Definition of CAMERA class controls, and helpers to drive the 3A (auto-exposure, auto white
+//! balance and auto focus) algorithms of a camera.
+//!
+//! Each automatic algorithm has a manual counterpart (e.g. `ExposureAuto` and `ExposureAbsolute`)
+//! which is marked as inactive by the driver as long as the automatic mode is enabled. Writing a
+//! manual value while the control is inactive is either rejected or silently ignored depending on
+//! the driver, so the `set_manual_*` helpers first disable the automatic mode, check that the
+//! manual control became active, and only then write the value.
+//!
+//! ```no_run
+//! # use std::path::Path;
+//! #
+//! # use v4l2r::controls::camera;
+//! # use v4l2r::device::Device;
+//! #
+//! # let device = Device::open(Path::new("/dev/video0"), Default::default()).unwrap();
+//! #
+//! // Fixed 10ms exposure, automatic white balance.
+//! let exposure = camera::set_manual_exposure(&device, 100).unwrap();
+//! println!("exposure set to {}00us", exposure);
+//! camera::set_auto_white_balance(&device, true).unwrap();
+//! ```
+use std::convert::TryFrom;
+use std::os::unix::io::AsRawFd;
+
+use nix::errno::Errno;
+use thiserror::Error;
+
+use crate::bindings;
+use crate::controls;
+use crate::controls::user::{AutoWhiteBalance, Autogain, Gain, WhiteBalanceTemperature};
+use crate::controls::ExtControlTrait;
+use crate::ioctl;
+use crate::ioctl::{
+    ControlFlags, ControlInfo, ExtControlError, OsError, QueryCtrlError, QueryCtrlFlags,
+};
+
+pub struct ExposureAuto;
+impl ExtControlTrait for ExposureAuto {
+    const ID: u32 = bindings::V4L2_CID_EXPOSURE_AUTO;
+    type PAYLOAD = i32;
+}
+
+/// Exposure time, in units of 100µs.
+pub struct ExposureAbsolute;
+impl ExtControlTrait for ExposureAbsolute {
+    const ID: u32 = bindings::V4L2_CID_EXPOSURE_ABSOLUTE;
+    type PAYLOAD = i32;
+}
+
+pub struct FocusAuto;
+impl ExtControlTrait for FocusAuto {
+    const ID: u32 = bindings::V4L2_CID_FOCUS_AUTO;
+    type PAYLOAD = i32;
+}
+
+pub struct FocusAbsolute;
+impl ExtControlTrait for FocusAbsolute {
+    const ID: u32 = bindings::V4L2_CID_FOCUS_ABSOLUTE;
+    type PAYLOAD = i32;
+}
+
+/// Values of the `ExposureAuto` control.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum ExposureMode {
+    /// Automatic exposure time and iris aperture.
+    Auto = bindings::v4l2_exposure_auto_type_V4L2_EXPOSURE_AUTO as i32,
+    /// Manual exposure time and iris aperture.
+    Manual = bindings::v4l2_exposure_auto_type_V4L2_EXPOSURE_MANUAL as i32,
+    /// Manual exposure time, automatic iris aperture.
+    ShutterPriority = bindings::v4l2_exposure_auto_type_V4L2_EXPOSURE_SHUTTER_PRIORITY as i32,
+    /// Automatic exposure time, manual iris aperture.
+    AperturePriority = bindings::v4l2_exposure_auto_type_V4L2_EXPOSURE_APERTURE_PRIORITY as i32,
+}
+
+impl TryFrom<i32> for ExposureMode {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        [
+            ExposureMode::Auto,
+            ExposureMode::Manual,
+            ExposureMode::ShutterPriority,
+            ExposureMode::AperturePriority,
+        ]
+        .iter()
+        .copied()
+        .find(|mode| *mode as i32 == value)
+        .ok_or(value)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Camera3aError {
+    #[error("failed to get control 0x{0:08x}: {1}")]
+    GetControl(u32, #[source] ExtControlError),
+    #[error("failed to set control 0x{0:08x}: {1}")]
+    SetControl(u32, #[source] ExtControlError),
+    #[error("failed to query control 0x{0:08x}: {1}")]
+    QueryControl(u32, #[source] QueryCtrlError),
+    #[error("control 0x{0:08x} is still inactive after disabling its automatic mode")]
+    Inactive(u32),
+    #[error("unknown exposure mode {0}")]
+    UnknownExposureMode(i32),
+}
+
+impl OsError for Camera3aError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            Camera3aError::GetControl(_, e) | Camera3aError::SetControl(_, e) => e.errno(),
+            Camera3aError::QueryControl(_, e) => e.errno(),
+            Camera3aError::Inactive(_) | Camera3aError::UnknownExposureMode(_) => None,
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(Camera3aError);
+
+fn get_control<T: ExtControlTrait<PAYLOAD = i32>>(fd: &impl AsRawFd) -> Result<i32, Camera3aError> {
+    controls::get_current_value::<T>(fd).map_err(|e| Camera3aError::GetControl(T::ID, e))
+}
+
+/// Sets control `T` to `value` and returns the value actually applied by the driver.
+fn set_control<T: ExtControlTrait<PAYLOAD = i32>>(
+    fd: &impl AsRawFd,
+    value: i32,
+) -> Result<i32, Camera3aError> {
+    controls::set_current_value::<T>(fd, value).map_err(|e| Camera3aError::SetControl(T::ID, e))
+}
+
+/// Writes `manual_mode` into the automatic control `A`, then `value` into the manual control `M`
+/// once it has become active. Returns the value actually applied to `M`.
+///
+/// This needs to be two separate `VIDIOC_S_EXT_CTRLS` calls: when both controls are set at once,
+/// the driver validates the manual value against the state of the controls before the call.
+fn set_manual<A, M>(fd: &impl AsRawFd, manual_mode: i32, value: i32) -> Result<i32, Camera3aError>
+where
+    A: ExtControlTrait<PAYLOAD = i32>,
+    M: ExtControlTrait<PAYLOAD = i32>,
+{
+    set_control::<A>(fd, manual_mode)?;
+
+    let info: ControlInfo =
+        ioctl::query_ext_ctrl(fd, controls::ctrl_id::<M>(), QueryCtrlFlags::empty())
+            .map_err(|e| Camera3aError::QueryControl(M::ID, e))?;
+    if info.flags.contains(ControlFlags::INACTIVE) {
+        return Err(Camera3aError::Inactive(M::ID));
+    }
+
+    set_control::<M>(fd, value)
+}
+
+/// Returns the current exposure mode.
+pub fn exposure_mode(fd: &impl AsRawFd) -> Result<ExposureMode, Camera3aError> {
+    let value = get_control::<ExposureAuto>(fd)?;
+    ExposureMode::try_from(value).map_err(Camera3aError::UnknownExposureMode)
+}
+
+/// Sets the exposure mode.
+pub fn set_exposure_mode(fd: &impl AsRawFd, mode: ExposureMode) -> Result<(), Camera3aError> {
+    set_control::<ExposureAuto>(fd, mode as i32).map(|_| ())
+}
+
+/// Switches to manual exposure and sets the exposure time to `exposure`, in units of 100µs.
+///
+/// Returns the exposure time actually applied by the driver.
+pub fn set_manual_exposure(fd: &impl AsRawFd, exposure: i32) -> Result<i32, Camera3aError> {
+    set_manual::<ExposureAuto, ExposureAbsolute>(fd, ExposureMode::Manual as i32, exposure)
+}
+
+/// Enables or disables the automatic white balance.
+pub fn set_auto_white_balance(fd: &impl AsRawFd, enable: bool) -> Result<(), Camera3aError> {
+    set_control::<AutoWhiteBalance>(fd, enable as i32).map(|_| ())
+}
+
+/// Disables the automatic white balance and sets the white balance to `temperature`, in Kelvin.
+///
+/// Returns the temperature actually applied by the driver.
+pub fn set_manual_white_balance(fd: &impl AsRawFd, temperature: i32) -> Result<i32, Camera3aError> {
+    set_manual::<AutoWhiteBalance, WhiteBalanceTemperature>(fd, 0, temperature)
+}
+
+/// Enables or disables the automatic gain.
+pub fn set_auto_gain(fd: &impl AsRawFd, enable: bool) -> Result<(), Camera3aError> {
+    set_control::<Autogain>(fd, enable as i32).map(|_| ())
+}
+
+/// Disables the automatic gain and sets the gain to `gain`.
+///
+/// Returns the gain actually applied by the driver.
+pub fn set_manual_gain(fd: &impl AsRawFd, gain: i32) -> Result<i32, Camera3aError> {
+    set_manual::<Autogain, Gain>(fd, 0, gain)
+}
+
+/// Enables or disables the continuous automatic focus.
+pub fn set_auto_focus(fd: &impl AsRawFd, enable: bool) -> Result<(), Camera3aError> {
+    set_control::<FocusAuto>(fd, enable as i32).map(|_| ())
+}
+
+/// Disables the continuous automatic focus and moves the lens to `position`, in driver-specific
+/// units.
+///
+/// Returns the position actually applied by the driver.
+pub fn set_manual_focus(fd: &impl AsRawFd, position: i32) -> Result<i32, Camera3aError> {
+    set_manual::<FocusAuto, FocusAbsolute>(fd, 0, position)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::ExposureMode;
+
+    #[test]
+    fn exposure_mode_from_value() {
+        assert_eq!(ExposureMode::try_from(1), Ok(ExposureMode::Manual));
+        assert_eq!(
+            ExposureMode::try_from(ExposureMode::AperturePriority as i32),
+            Ok(ExposureMode::AperturePriority)
+        );
+        assert_eq!(ExposureMode::try_from(4), Err(4));
+    }
+}
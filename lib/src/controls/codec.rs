@@ -25,6 +25,59 @@ bitflags! {
     }
 }
 
+pub struct VideoBitrate;
+impl ExtControlTrait for VideoBitrate {
+    const ID: u32 = bindings::V4L2_CID_MPEG_VIDEO_BITRATE;
+    type PAYLOAD = i32;
+}
+
+pub struct VideoBitratePeak;
+impl ExtControlTrait for VideoBitratePeak {
+    const ID: u32 = bindings::V4L2_CID_MPEG_VIDEO_BITRATE_PEAK;
+    type PAYLOAD = i32;
+}
+
+/// Decoding granularity of a stateless H.264 decoder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum H264DecodeMode {
+    /// One OUTPUT buffer per slice, each with its own slice parameters.
+    SliceBased =
+        bindings::v4l2_stateless_h264_decode_mode_V4L2_STATELESS_H264_DECODE_MODE_SLICE_BASED
+            as i32,
+    /// One OUTPUT buffer containing all the slices of a frame.
+    FrameBased =
+        bindings::v4l2_stateless_h264_decode_mode_V4L2_STATELESS_H264_DECODE_MODE_FRAME_BASED
+            as i32,
+}
+
+pub struct StatelessH264DecodeMode;
+impl ExtControlTrait for StatelessH264DecodeMode {
+    const ID: u32 = bindings::V4L2_CID_STATELESS_H264_DECODE_MODE;
+    type PAYLOAD = i32;
+}
+
+pub struct VideoHeaderMode;
+impl ExtControlTrait for VideoHeaderMode {
+    const ID: u32 = bindings::V4L2_CID_MPEG_VIDEO_HEADER_MODE;
+    type PAYLOAD = i32;
+}
+
+/// Whether the display delay of `VideoDecDisplayDelay` is applied by a stateful decoder.
+pub struct VideoDecDisplayDelayEnable;
+impl ExtControlTrait for VideoDecDisplayDelayEnable {
+    const ID: u32 = bindings::V4L2_CID_MPEG_VIDEO_DEC_DISPLAY_DELAY_ENABLE;
+    type PAYLOAD = i32;
+}
+
+/// Number of OUTPUT buffers after which a stateful decoder returns the CAPTURE buffer of a
+/// frame, even if it is not the next one in display order.
+pub struct VideoDecDisplayDelay;
+impl ExtControlTrait for VideoDecDisplayDelay {
+    const ID: u32 = bindings::V4L2_CID_MPEG_VIDEO_DEC_DISPLAY_DELAY;
+    type PAYLOAD = i32;
+}
+
 pub struct FwhtParams;
 impl ExtControlTrait for FwhtParams {
     const ID: u32 = bindings::V4L2_CID_STATELESS_FWHT_PARAMS;
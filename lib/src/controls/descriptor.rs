@@ -0,0 +1,664 @@
+//! Access to controls whose type is only known at runtime.
+//!
+//! [`SafeExtControl`](super::SafeExtControl) requires the control to be known at compile-time.
+//! Programs that need to work with all the controls exposed by a device, e.g. to list them or to
+//! reset them to their default value, can use [`ControlDescriptor`] instead, which reads and
+//! writes values according to the control information returned by `VIDIOC_QUERY_EXT_CTRL`:
+//!
+//! ```no_run
+//! # use std::path::Path;
+//! #
+//! # use v4l2r::controls::descriptor::ControlDescriptor;
+//! # use v4l2r::device::Device;
+//! # use v4l2r::ioctl::ControlIterator;
+//! #
+//! # let device = Device::open(Path::new("/dev/video0"), Default::default()).unwrap();
+//! #
+//! for control in ControlIterator::new(&device).map(ControlDescriptor::from) {
+//!     if control.has_value() {
+//!         println!("{}: {:?}", control.info().name, control.default_value(&device));
+//!     }
+//! }
+//! ```
+use std::os::unix::io::AsRawFd;
+
+use nix::errno::Errno;
+use thiserror::Error;
+
+use crate::bindings;
+use crate::bindings::v4l2_ext_control;
+use crate::bindings::v4l2_ext_control__bindgen_ty_1;
+use crate::controls::ControlTransactionError;
+use crate::ioctl;
+use crate::ioctl::ControlFlags;
+use crate::ioctl::ControlInfo;
+use crate::ioctl::ControlIterator;
+use crate::ioctl::CtrlId;
+use crate::ioctl::CtrlWhich;
+use crate::ioctl::ExtControlError;
+use crate::ioctl::OsError;
+use crate::ioctl::QueryCtrlError;
+use crate::ioctl::QueryCtrlFlags;
+
+/// Value of a control, as read from or written to the driver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlValue {
+    /// Value of integer, boolean, menu and bitmask controls.
+    Integer(i32),
+    /// Value of 64-bit integer controls.
+    Integer64(i64),
+    /// Value of string controls.
+    String(String),
+    /// Value of area controls, e.g. the unit cell size of a sensor.
+    Area { width: u32, height: u32 },
+    /// Raw payload of other compound and array controls.
+    Payload(Vec<u8>),
+}
+
+/// A `v4l2_ext_control` holding a `ControlValue`, along with the buffer its payload points to.
+struct RawControl {
+    control: v4l2_ext_control,
+    /// Buffer pointed to by `control` for values passed as a payload. Its heap allocation does
+    /// not move with `self`, so `control` remains valid as long as `self` is alive.
+    payload: Vec<u8>,
+    /// Value `control` has been created from, telling how to decode it.
+    value: ControlValue,
+}
+
+impl RawControl {
+    /// Create a control `id` holding `value`. The payload buffer, if any, is at least
+    /// `payload_size` bytes large, so the driver can return any valid value into it.
+    fn new(id: u32, value: ControlValue, payload_size: usize) -> Self {
+        let mut payload = match &value {
+            ControlValue::Integer(_) | ControlValue::Integer64(_) => Vec::new(),
+            // Strings are passed with their terminating NUL character.
+            ControlValue::String(string) => {
+                let mut payload = string.as_bytes().to_vec();
+                payload.push(0);
+                payload
+            }
+            ControlValue::Area { width, height } => {
+                [width.to_ne_bytes(), height.to_ne_bytes()].concat()
+            }
+            ControlValue::Payload(payload) => payload.clone(),
+        };
+
+        let union = match &value {
+            ControlValue::Integer(value) => v4l2_ext_control__bindgen_ty_1 { value: *value },
+            ControlValue::Integer64(value64) => {
+                v4l2_ext_control__bindgen_ty_1 { value64: *value64 }
+            }
+            _ => {
+                if payload.len() < payload_size {
+                    payload.resize(payload_size, 0);
+                }
+                v4l2_ext_control__bindgen_ty_1 {
+                    p_u8: payload.as_mut_ptr(),
+                }
+            }
+        };
+
+        RawControl {
+            control: v4l2_ext_control {
+                id,
+                size: payload.len() as u32,
+                __bindgen_anon_1: union,
+                ..Default::default()
+            },
+            payload,
+            value,
+        }
+    }
+
+    /// Returns the value of the control, as updated by the driver.
+    fn into_value(self) -> ControlValue {
+        // SAFETY: the type of `self.value` tells which member of the union has been set.
+        match self.value {
+            ControlValue::Integer(_) => {
+                ControlValue::Integer(unsafe { self.control.__bindgen_anon_1.value })
+            }
+            ControlValue::Integer64(_) => {
+                ControlValue::Integer64(unsafe { self.control.__bindgen_anon_1.value64 })
+            }
+            ControlValue::String(_) => {
+                let len = self
+                    .payload
+                    .iter()
+                    .position(|c| *c == 0)
+                    .unwrap_or(self.payload.len());
+                ControlValue::String(String::from_utf8_lossy(&self.payload[..len]).into_owned())
+            }
+            ControlValue::Area { .. } => {
+                let field = |offset: usize| {
+                    let mut bytes = [0u8; 4];
+                    bytes.copy_from_slice(&self.payload[offset..offset + 4]);
+                    u32::from_ne_bytes(bytes)
+                };
+                ControlValue::Area {
+                    width: field(0),
+                    height: field(4),
+                }
+            }
+            // The driver wrote the payload in place, but may have shortened it for dynamic arrays.
+            ControlValue::Payload(_) => {
+                let mut payload = self.payload;
+                payload.truncate(self.control.size as usize);
+                ControlValue::Payload(payload)
+            }
+        }
+    }
+}
+
+/// Describes a control of a device, allowing to read and write its value without knowing its type
+/// at compile-time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlDescriptor(ControlInfo);
+
+impl From<ControlInfo> for ControlDescriptor {
+    fn from(info: ControlInfo) -> Self {
+        ControlDescriptor(info)
+    }
+}
+
+impl ControlDescriptor {
+    /// Query the description of control `id` from the device.
+    pub fn query(fd: &impl AsRawFd, id: CtrlId) -> Result<Self, QueryCtrlError> {
+        ioctl::query_ext_ctrl::<ControlInfo>(fd, id, QueryCtrlFlags::empty()).map(Self)
+    }
+
+    /// Returns the information reported by the driver for this control.
+    pub fn info(&self) -> &ControlInfo {
+        &self.0
+    }
+
+    pub fn id(&self) -> u32 {
+        self.0.id
+    }
+
+    /// Returns whether this control holds a value. Buttons and class controls do not.
+    pub fn has_value(&self) -> bool {
+        !matches!(
+            self.0.ctrl_type,
+            bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_BUTTON
+                | bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_CTRL_CLASS
+        )
+    }
+
+    /// Returns whether the value of this control can be both read and written by the client.
+    pub fn is_read_write(&self) -> bool {
+        self.has_value()
+            && !self.0.flags.intersects(
+                ControlFlags::DISABLED | ControlFlags::READ_ONLY | ControlFlags::WRITE_ONLY,
+            )
+    }
+
+    /// Returns whether the value of this control is passed as a payload, i.e. whether it is a
+    /// compound, string or array control.
+    pub fn has_payload(&self) -> bool {
+        self.0.flags.contains(ControlFlags::HAS_PAYLOAD)
+    }
+
+    /// Returns the size in bytes of the payload of this control, or zero if it has none.
+    pub fn payload_size(&self) -> usize {
+        if self.has_payload() {
+            self.0.elem_size as usize * self.0.elems as usize
+        } else {
+            0
+        }
+    }
+
+    /// Returns the range of valid lengths for the value of a string control, as a
+    /// `(minimum, maximum, step)` tuple, or `None` if this is not a string control.
+    pub fn string_length_range(&self) -> Option<(usize, usize, usize)> {
+        if self.0.ctrl_type != bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_STRING {
+            return None;
+        }
+
+        Some((
+            self.0.minimum as usize,
+            self.0.maximum as usize,
+            self.0.step as usize,
+        ))
+    }
+
+    /// Returns whether `string` has a valid length for this string control. Drivers reject other
+    /// lengths with `ERANGE`.
+    pub fn is_valid_string(&self, string: &str) -> bool {
+        match self.string_length_range() {
+            Some((minimum, maximum, step)) => {
+                let len = string.len();
+                (minimum..=maximum).contains(&len) && (step == 0 || (len - minimum) % step == 0)
+            }
+            None => false,
+        }
+    }
+
+    /// Returns a zero value of the right type for this control, suitable to read its value into.
+    fn empty_value(&self) -> ControlValue {
+        // Arrays of strings and areas are handled as raw payloads.
+        if self.has_payload() && self.0.elems == 1 {
+            match self.0.ctrl_type {
+                bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_STRING => {
+                    return ControlValue::String(String::new())
+                }
+                bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_AREA => {
+                    return ControlValue::Area {
+                        width: 0,
+                        height: 0,
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if self.has_payload() {
+            ControlValue::Payload(vec![0u8; self.payload_size()])
+        } else if self.0.ctrl_type == bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER64 {
+            ControlValue::Integer64(0)
+        } else {
+            ControlValue::Integer(0)
+        }
+    }
+
+    /// Read the value of this control using `VIDIOC_G_EXT_CTRLS`.
+    pub fn get_value(
+        &self,
+        fd: &impl AsRawFd,
+        which: CtrlWhich,
+    ) -> Result<ControlValue, ExtControlError> {
+        let mut control = RawControl::new(self.0.id, self.empty_value(), self.payload_size());
+        ioctl::g_ext_ctrls(fd, which, std::slice::from_mut(&mut control.control))?;
+
+        Ok(control.into_value())
+    }
+
+    /// Returns the current value of this control.
+    pub fn current_value(&self, fd: &impl AsRawFd) -> Result<ControlValue, ExtControlError> {
+        self.get_value(fd, CtrlWhich::Current)
+    }
+
+    /// Returns the default value of this control.
+    ///
+    /// Contrary to the `default_value` member of `ControlInfo`, this also works for compound
+    /// controls, whose default value can only be obtained with `V4L2_CTRL_WHICH_DEF_VAL`.
+    pub fn default_value(&self, fd: &impl AsRawFd) -> Result<ControlValue, ExtControlError> {
+        self.get_value(fd, CtrlWhich::Default)
+    }
+
+    /// Write `value` into this control using `VIDIOC_S_EXT_CTRLS`, and returns the value actually
+    /// applied by the driver.
+    pub fn set_value(
+        &self,
+        fd: &impl AsRawFd,
+        which: CtrlWhich,
+        value: ControlValue,
+    ) -> Result<ControlValue, ExtControlError> {
+        let mut control = RawControl::new(self.0.id, value, self.payload_size());
+        ioctl::s_ext_ctrls(fd, which, std::slice::from_mut(&mut control.control))?;
+
+        Ok(control.into_value())
+    }
+
+    /// Reset the current value of this control to its default.
+    pub fn reset_to_default(&self, fd: &impl AsRawFd) -> Result<ControlValue, ExtControlError> {
+        let default = self.default_value(fd)?;
+        self.set_value(fd, CtrlWhich::Current, default)
+    }
+
+    /// Checks that this control can currently be set to the integer `value`.
+    pub fn check_value(&self, value: i64) -> Result<(), ControlValidationError> {
+        let id = self.0.id;
+        if self.0.flags.contains(ControlFlags::DISABLED) {
+            return Err(ControlValidationError::Unsupported(id));
+        }
+        if self
+            .0
+            .flags
+            .intersects(ControlFlags::READ_ONLY | ControlFlags::GRABBED)
+        {
+            return Err(ControlValidationError::NotWritable(id));
+        }
+        if value < self.0.minimum || value > self.0.maximum {
+            return Err(ControlValidationError::OutOfRange {
+                id,
+                value,
+                min: self.0.minimum,
+                max: self.0.maximum,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ControlValidationError {
+    #[error("control 0x{0:08x} is not supported by the device")]
+    Unsupported(u32),
+    #[error("control 0x{0:08x} cannot be changed at the moment")]
+    NotWritable(u32),
+    #[error("value {value} is out of range [{min}, {max}] for control 0x{id:08x}")]
+    OutOfRange {
+        id: u32,
+        value: i64,
+        min: i64,
+        max: i64,
+    },
+    #[error("error while querying control")]
+    QueryCtrlError(#[source] QueryCtrlError),
+}
+
+impl OsError for ControlValidationError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            ControlValidationError::Unsupported(_)
+            | ControlValidationError::NotWritable(_)
+            | ControlValidationError::OutOfRange { .. } => None,
+            ControlValidationError::QueryCtrlError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(ControlValidationError);
+
+/// Checks that the control `id` is supported by `fd` and can currently be set
+/// to the integer `value`, e.g. to validate several controls before changing
+/// any of them.
+pub fn validate_control(
+    fd: &impl AsRawFd,
+    id: u32,
+    value: i64,
+) -> Result<(), ControlValidationError> {
+    let ctrl_id = CtrlId::new(id).map_err(|_| ControlValidationError::Unsupported(id))?;
+    let descriptor = match ControlDescriptor::query(fd, ctrl_id) {
+        Ok(descriptor) => descriptor,
+        Err(QueryCtrlError::IoctlError(Errno::EINVAL)) => {
+            return Err(ControlValidationError::Unsupported(id))
+        }
+        Err(e) => return Err(ControlValidationError::QueryCtrlError(e)),
+    };
+
+    descriptor.check_value(value)
+}
+
+#[derive(Debug, Error)]
+pub enum ControlSnapshotError {
+    #[error("failed to read control 0x{0:08x}: {1}")]
+    GetControl(u32, #[source] ExtControlError),
+    #[error("failed to restore controls")]
+    SetControls(#[from] ControlTransactionError),
+}
+
+impl OsError for ControlSnapshotError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            ControlSnapshotError::GetControl(_, e) => e.errno(),
+            ControlSnapshotError::SetControls(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(ControlSnapshotError);
+
+/// Values of all the controls of a device that can be read and written, including compound ones,
+/// as returned by `ControlSnapshot::save`.
+///
+/// Read-only, write-only and volatile controls, as well as buttons, are not part of the snapshot
+/// since their value cannot be restored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ControlSnapshot {
+    controls: Vec<(ControlDescriptor, ControlValue)>,
+}
+
+impl ControlSnapshot {
+    /// Returns whether the value of `control` is saved in snapshots.
+    fn is_saved(control: &ControlDescriptor) -> bool {
+        control.is_read_write() && !control.info().flags.contains(ControlFlags::VOLATILE)
+    }
+
+    /// Save the current value of all the controls of `fd` that can be restored.
+    pub fn save(fd: &impl AsRawFd) -> Result<Self, ControlSnapshotError> {
+        let controls = ControlIterator::new(fd)
+            .map(ControlDescriptor::from)
+            .filter(Self::is_saved)
+            .map(|control| {
+                control
+                    .current_value(fd)
+                    .map(|value| (control.clone(), value))
+                    .map_err(|e| ControlSnapshotError::GetControl(control.id(), e))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(ControlSnapshot { controls })
+    }
+
+    /// Restore the saved values into the controls of `fd` with a single `VIDIOC_S_EXT_CTRLS`
+    /// call, so the driver applies them atomically and in the right order for controls that
+    /// depend on each other (e.g. an automatic mode and its manual value).
+    pub fn restore(&self, fd: &impl AsRawFd) -> Result<(), ControlSnapshotError> {
+        if self.controls.is_empty() {
+            return Ok(());
+        }
+
+        // Keep the payloads alive until the controls are set.
+        let raw_controls: Vec<RawControl> = self
+            .controls
+            .iter()
+            .map(|(control, value)| {
+                RawControl::new(control.id(), value.clone(), control.payload_size())
+            })
+            .collect();
+        let mut v4l2_controls: Vec<v4l2_ext_control> =
+            raw_controls.iter().map(|raw| raw.control).collect();
+
+        ioctl::s_ext_ctrls(fd, CtrlWhich::Current, v4l2_controls.as_mut_slice()).map_err(
+            |error| ControlTransactionError {
+                failed_control: self
+                    .controls
+                    .get(error.error_idx as usize)
+                    .map(|(control, _)| control.id()),
+                error,
+            },
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the saved controls and their values, in increasing ID order.
+    pub fn iter(&self) -> impl Iterator<Item = (&ControlDescriptor, &ControlValue)> {
+        self.controls
+            .iter()
+            .map(|(control, value)| (control, value))
+    }
+
+    /// Returns the number of saved controls.
+    pub fn len(&self) -> usize {
+        self.controls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.controls.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(
+        ctrl_type: u32,
+        flags: ControlFlags,
+        elem_size: u32,
+        elems: u32,
+    ) -> ControlDescriptor {
+        ControlDescriptor(ControlInfo {
+            id: bindings::V4L2_CID_BRIGHTNESS,
+            ctrl_type,
+            name: "Control".into(),
+            minimum: 0,
+            maximum: 0,
+            step: 0,
+            default_value: 0,
+            flags,
+            elem_size,
+            elems,
+        })
+    }
+
+    #[test]
+    fn control_value_layout() {
+        let integer = descriptor(
+            bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER,
+            ControlFlags::empty(),
+            4,
+            1,
+        );
+        assert_eq!(integer.empty_value(), ControlValue::Integer(0));
+        assert_eq!(integer.payload_size(), 0);
+
+        let integer64 = descriptor(
+            bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER64,
+            ControlFlags::empty(),
+            8,
+            1,
+        );
+        assert_eq!(integer64.empty_value(), ControlValue::Integer64(0));
+
+        let array = descriptor(
+            bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_U16,
+            ControlFlags::HAS_PAYLOAD,
+            2,
+            8,
+        );
+        assert_eq!(array.payload_size(), 16);
+        let mut control = RawControl::new(array.id(), array.empty_value(), array.payload_size());
+        assert_eq!({ control.control.size }, 16);
+        // Simulate a dynamic array shortened by the driver.
+        unsafe { *control.control.__bindgen_anon_1.p_u8 = 42 };
+        control.control.size = 4;
+        assert_eq!(
+            control.into_value(),
+            ControlValue::Payload(vec![42, 0, 0, 0])
+        );
+
+        assert!(!descriptor(
+            bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_BUTTON,
+            ControlFlags::WRITE_ONLY,
+            0,
+            1
+        )
+        .has_value());
+    }
+
+    #[test]
+    fn string_and_area_values() {
+        let mut string = descriptor(
+            bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_STRING,
+            ControlFlags::HAS_PAYLOAD,
+            9,
+            1,
+        );
+        string.0.minimum = 2;
+        string.0.maximum = 8;
+        string.0.step = 2;
+        assert_eq!(string.string_length_range(), Some((2, 8, 2)));
+        assert!(string.is_valid_string("abcd"));
+        assert!(!string.is_valid_string("abc"));
+        assert!(!string.is_valid_string("abcdefghij"));
+
+        // Room is left for the longest string the driver may return.
+        let control = RawControl::new(string.id(), string.empty_value(), string.payload_size());
+        assert_eq!({ control.control.size }, 9);
+        let mut control = RawControl::new(string.id(), ControlValue::String("abcd".into()), 9);
+        assert_eq!(&control.payload[..6], b"abcd\0\0");
+        control.payload[..3].copy_from_slice(b"xy\0");
+        assert_eq!(control.into_value(), ControlValue::String("xy".into()));
+
+        let area = descriptor(
+            bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_AREA,
+            ControlFlags::HAS_PAYLOAD,
+            8,
+            1,
+        );
+        let value = ControlValue::Area {
+            width: 1920,
+            height: 1080,
+        };
+        assert!(!area.is_valid_string("abcd"));
+        assert_eq!(
+            area.empty_value(),
+            ControlValue::Area {
+                width: 0,
+                height: 0
+            }
+        );
+        let control = RawControl::new(area.id(), value.clone(), area.payload_size());
+        assert_eq!({ control.control.size }, 8);
+        assert_eq!(control.into_value(), value);
+    }
+
+    #[test]
+    fn saved_controls() {
+        let integer =
+            |flags| descriptor(bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER, flags, 4, 1);
+
+        assert!(ControlSnapshot::is_saved(&integer(ControlFlags::empty())));
+        assert!(ControlSnapshot::is_saved(&integer(ControlFlags::INACTIVE)));
+        assert!(ControlSnapshot::is_saved(&descriptor(
+            bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_U8,
+            ControlFlags::HAS_PAYLOAD,
+            1,
+            16
+        )));
+        assert!(!ControlSnapshot::is_saved(&integer(
+            ControlFlags::READ_ONLY
+        )));
+        assert!(!ControlSnapshot::is_saved(&integer(
+            ControlFlags::WRITE_ONLY
+        )));
+        assert!(!ControlSnapshot::is_saved(&integer(ControlFlags::VOLATILE)));
+        assert!(!ControlSnapshot::is_saved(&integer(ControlFlags::DISABLED)));
+        assert!(!ControlSnapshot::is_saved(&descriptor(
+            bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_BUTTON,
+            ControlFlags::empty(),
+            0,
+            1
+        )));
+    }
+
+    #[test]
+    fn check_value() {
+        let control = |flags| {
+            let mut control =
+                descriptor(bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER, flags, 4, 1);
+            control.0.minimum = -2;
+            control.0.maximum = 16;
+            control
+        };
+        let id = bindings::V4L2_CID_BRIGHTNESS;
+
+        assert!(control(ControlFlags::empty()).check_value(-2).is_ok());
+        assert!(control(ControlFlags::VOLATILE).check_value(16).is_ok());
+        assert!(matches!(
+            control(ControlFlags::empty()).check_value(17),
+            Err(ControlValidationError::OutOfRange {
+                value: 17,
+                min: -2,
+                max: 16,
+                ..
+            })
+        ));
+        assert!(matches!(
+            control(ControlFlags::DISABLED).check_value(0),
+            Err(ControlValidationError::Unsupported(i)) if i == id
+        ));
+        assert!(matches!(
+            control(ControlFlags::GRABBED).check_value(0),
+            Err(ControlValidationError::NotWritable(i)) if i == id
+        ));
+        assert!(matches!(
+            control(ControlFlags::READ_ONLY).check_value(0),
+            Err(ControlValidationError::NotWritable(_))
+        ));
+    }
+}
@@ -0,0 +1,269 @@
+//! Definition of FLASH class controls, and helpers to drive the flash or torch LED of a camera.
+//!
+//! These controls are usually exposed by the sub-device of the flash controller rather than by
+//! the video device, so the helpers accept any file descriptor.
+//!
+//! ```no_run
+//! # use std::fs::File;
+//! #
+//! # use v4l2r::controls::flash;
+//! # use v4l2r::controls::flash::LedMode;
+//! #
+//! # let subdev = File::open("/dev/v4l-subdev2").unwrap();
+//! #
+//! flash::set_led_mode(&subdev, LedMode::Flash).unwrap();
+//! flash::strobe(&subdev).unwrap();
+//! let faults = flash::faults(&subdev).unwrap();
+//! if !faults.is_empty() {
+//!     println!("flash faults: {:?}", faults);
+//! }
+//! ```
+use std::convert::TryFrom;
+use std::os::unix::io::AsRawFd;
+
+use bitflags::bitflags;
+use nix::errno::Errno;
+use thiserror::Error;
+
+use crate::bindings;
+use crate::controls;
+use crate::controls::ExtControlTrait;
+use crate::ioctl::ExtControlError;
+use crate::ioctl::OsError;
+
+pub struct FlashLedMode;
+impl ExtControlTrait for FlashLedMode {
+    const ID: u32 = bindings::V4L2_CID_FLASH_LED_MODE;
+    type PAYLOAD = i32;
+}
+
+pub struct FlashStrobeSource;
+impl ExtControlTrait for FlashStrobeSource {
+    const ID: u32 = bindings::V4L2_CID_FLASH_STROBE_SOURCE;
+    type PAYLOAD = i32;
+}
+
+/// Button control: writing any value strobes the flash.
+pub struct FlashStrobe;
+impl ExtControlTrait for FlashStrobe {
+    const ID: u32 = bindings::V4L2_CID_FLASH_STROBE;
+    type PAYLOAD = i32;
+}
+
+/// Button control: writing any value stops the strobe immediately.
+pub struct FlashStrobeStop;
+impl ExtControlTrait for FlashStrobeStop {
+    const ID: u32 = bindings::V4L2_CID_FLASH_STROBE_STOP;
+    type PAYLOAD = i32;
+}
+
+pub struct FlashStrobeStatus;
+impl ExtControlTrait for FlashStrobeStatus {
+    const ID: u32 = bindings::V4L2_CID_FLASH_STROBE_STATUS;
+    type PAYLOAD = i32;
+}
+
+/// Hardware strobe timeout, in microseconds.
+pub struct FlashTimeout;
+impl ExtControlTrait for FlashTimeout {
+    const ID: u32 = bindings::V4L2_CID_FLASH_TIMEOUT;
+    type PAYLOAD = i32;
+}
+
+/// Intensity of the flash strobe, in microamperes.
+pub struct FlashIntensity;
+impl ExtControlTrait for FlashIntensity {
+    const ID: u32 = bindings::V4L2_CID_FLASH_INTENSITY;
+    type PAYLOAD = i32;
+}
+
+/// Intensity of the flash in torch mode, in microamperes.
+pub struct FlashTorchIntensity;
+impl ExtControlTrait for FlashTorchIntensity {
+    const ID: u32 = bindings::V4L2_CID_FLASH_TORCH_INTENSITY;
+    type PAYLOAD = i32;
+}
+
+/// Intensity of the indicator LED, in microamperes.
+pub struct FlashIndicatorIntensity;
+impl ExtControlTrait for FlashIndicatorIntensity {
+    const ID: u32 = bindings::V4L2_CID_FLASH_INDICATOR_INTENSITY;
+    type PAYLOAD = i32;
+}
+
+pub struct FlashFault;
+impl ExtControlTrait for FlashFault {
+    const ID: u32 = bindings::V4L2_CID_FLASH_FAULT;
+    type PAYLOAD = i32;
+}
+
+pub struct FlashReady;
+impl ExtControlTrait for FlashReady {
+    const ID: u32 = bindings::V4L2_CID_FLASH_READY;
+    type PAYLOAD = i32;
+}
+
+/// Values of the `FlashLedMode` control.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum LedMode {
+    /// Off.
+    None = bindings::v4l2_flash_led_mode_V4L2_FLASH_LED_MODE_NONE as i32,
+    /// Flash mode, the LED lights up when strobed.
+    Flash = bindings::v4l2_flash_led_mode_V4L2_FLASH_LED_MODE_FLASH as i32,
+    /// Torch mode, the LED stays on.
+    Torch = bindings::v4l2_flash_led_mode_V4L2_FLASH_LED_MODE_TORCH as i32,
+}
+
+impl TryFrom<i32> for LedMode {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        [LedMode::None, LedMode::Flash, LedMode::Torch]
+            .iter()
+            .copied()
+            .find(|mode| *mode as i32 == value)
+            .ok_or(value)
+    }
+}
+
+/// Values of the `FlashStrobeSource` control.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum StrobeSource {
+    /// The flash is strobed using the `FlashStrobe` control.
+    Software = bindings::v4l2_flash_strobe_source_V4L2_FLASH_STROBE_SOURCE_SOFTWARE as i32,
+    /// The flash is strobed by an external signal, typically from the sensor.
+    External = bindings::v4l2_flash_strobe_source_V4L2_FLASH_STROBE_SOURCE_EXTERNAL as i32,
+}
+
+bitflags! {
+    /// Faults reported by the `FlashFault` control.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct FlashFaults: u32 {
+        const OVER_VOLTAGE = bindings::V4L2_FLASH_FAULT_OVER_VOLTAGE;
+        const TIMEOUT = bindings::V4L2_FLASH_FAULT_TIMEOUT;
+        const OVER_TEMPERATURE = bindings::V4L2_FLASH_FAULT_OVER_TEMPERATURE;
+        const SHORT_CIRCUIT = bindings::V4L2_FLASH_FAULT_SHORT_CIRCUIT;
+        const OVER_CURRENT = bindings::V4L2_FLASH_FAULT_OVER_CURRENT;
+        const INDICATOR = bindings::V4L2_FLASH_FAULT_INDICATOR;
+        const UNDER_VOLTAGE = bindings::V4L2_FLASH_FAULT_UNDER_VOLTAGE;
+        const INPUT_VOLTAGE = bindings::V4L2_FLASH_FAULT_INPUT_VOLTAGE;
+        const LED_OVER_TEMPERATURE = bindings::V4L2_FLASH_FAULT_LED_OVER_TEMPERATURE;
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FlashError {
+    #[error("failed to get control 0x{0:08x}: {1}")]
+    GetControl(u32, #[source] ExtControlError),
+    #[error("failed to set control 0x{0:08x}: {1}")]
+    SetControl(u32, #[source] ExtControlError),
+    #[error("unknown LED mode {0}")]
+    UnknownLedMode(i32),
+}
+
+impl OsError for FlashError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            FlashError::GetControl(_, e) | FlashError::SetControl(_, e) => e.errno(),
+            FlashError::UnknownLedMode(_) => None,
+        }
+    }
+}
+
+crate::ioctl::impl_into_io_error!(FlashError);
+
+fn get_control<T: ExtControlTrait<PAYLOAD = i32>>(fd: &impl AsRawFd) -> Result<i32, FlashError> {
+    controls::get_current_value::<T>(fd).map_err(|e| FlashError::GetControl(T::ID, e))
+}
+
+fn set_control<T: ExtControlTrait<PAYLOAD = i32>>(
+    fd: &impl AsRawFd,
+    value: i32,
+) -> Result<i32, FlashError> {
+    controls::set_current_value::<T>(fd, value).map_err(|e| FlashError::SetControl(T::ID, e))
+}
+
+/// Returns the current mode of the flash LED.
+pub fn led_mode(fd: &impl AsRawFd) -> Result<LedMode, FlashError> {
+    let value = get_control::<FlashLedMode>(fd)?;
+    LedMode::try_from(value).map_err(FlashError::UnknownLedMode)
+}
+
+/// Sets the mode of the flash LED.
+pub fn set_led_mode(fd: &impl AsRawFd, mode: LedMode) -> Result<(), FlashError> {
+    set_control::<FlashLedMode>(fd, mode as i32).map(|_| ())
+}
+
+/// Sets the strobe source of the flash.
+pub fn set_strobe_source(fd: &impl AsRawFd, source: StrobeSource) -> Result<(), FlashError> {
+    set_control::<FlashStrobeSource>(fd, source as i32).map(|_| ())
+}
+
+/// Strobes the flash. The LED must be in `LedMode::Flash` and the strobe source must be
+/// `StrobeSource::Software`.
+pub fn strobe(fd: &impl AsRawFd) -> Result<(), FlashError> {
+    set_control::<FlashStrobe>(fd, 0).map(|_| ())
+}
+
+/// Stops the flash strobe before its timeout.
+pub fn strobe_stop(fd: &impl AsRawFd) -> Result<(), FlashError> {
+    set_control::<FlashStrobeStop>(fd, 0).map(|_| ())
+}
+
+/// Returns whether the flash is currently strobing.
+pub fn is_strobing(fd: &impl AsRawFd) -> Result<bool, FlashError> {
+    get_control::<FlashStrobeStatus>(fd).map(|status| status != 0)
+}
+
+/// Returns whether the flash is ready to strobe.
+pub fn is_ready(fd: &impl AsRawFd) -> Result<bool, FlashError> {
+    get_control::<FlashReady>(fd).map(|ready| ready != 0)
+}
+
+/// Sets the hardware timeout of the strobe, in microseconds, and returns the value actually
+/// applied by the driver.
+pub fn set_timeout(fd: &impl AsRawFd, timeout_us: i32) -> Result<i32, FlashError> {
+    set_control::<FlashTimeout>(fd, timeout_us)
+}
+
+/// Sets the intensity of the flash strobe, in microamperes, and returns the value actually
+/// applied by the driver.
+pub fn set_flash_intensity(fd: &impl AsRawFd, intensity_ua: i32) -> Result<i32, FlashError> {
+    set_control::<FlashIntensity>(fd, intensity_ua)
+}
+
+/// Sets the intensity of the torch, in microamperes, and returns the value actually applied by
+/// the driver.
+pub fn set_torch_intensity(fd: &impl AsRawFd, intensity_ua: i32) -> Result<i32, FlashError> {
+    set_control::<FlashTorchIntensity>(fd, intensity_ua)
+}
+
+/// Sets the intensity of the indicator LED, in microamperes, and returns the value actually
+/// applied by the driver.
+pub fn set_indicator_intensity(fd: &impl AsRawFd, intensity_ua: i32) -> Result<i32, FlashError> {
+    set_control::<FlashIndicatorIntensity>(fd, intensity_ua)
+}
+
+/// Returns the faults of the flash controller.
+///
+/// Reading the faults clears them on the hardware, so the returned value must not be discarded if
+/// it matters to the caller.
+pub fn faults(fd: &impl AsRawFd) -> Result<FlashFaults, FlashError> {
+    get_control::<FlashFault>(fd).map(|faults| FlashFaults::from_bits_truncate(faults as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::LedMode;
+
+    #[test]
+    fn led_mode_from_value() {
+        assert_eq!(LedMode::try_from(0), Ok(LedMode::None));
+        assert_eq!(LedMode::try_from(2), Ok(LedMode::Torch));
+        assert_eq!(LedMode::try_from(3), Err(3));
+    }
+}
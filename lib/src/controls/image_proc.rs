@@ -0,0 +1,20 @@
+//! Definition of IMAGE_PROC class controls.
+
+use crate::bindings;
+use crate::controls::ExtControlTrait;
+
+/// Pixel rate of the pixel array of a sensor, in pixels per second. Read-only.
+pub struct PixelRate;
+impl ExtControlTrait for PixelRate {
+    const ID: u32 = bindings::V4L2_CID_PIXEL_RATE;
+    type PAYLOAD = i64;
+}
+
+/// Gain all the color components are multiplied by. The value is usually a fixed-point number,
+/// e.g. with 8 fractional bits in which case 0x100 applies no gain, which is also the usual
+/// default.
+pub struct DigitalGain;
+impl ExtControlTrait for DigitalGain {
+    const ID: u32 = bindings::V4L2_CID_DIGITAL_GAIN;
+    type PAYLOAD = i32;
+}
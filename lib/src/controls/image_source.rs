@@ -0,0 +1,338 @@
+//! Definition of IMAGE_SOURCE class controls, and helpers to control the exposure, frame duration
+//! and gain of raw image sensors in physical units.
+//!
+//! Raw sensors express their exposure time in lines and their frame duration through the
+//! horizontal and vertical blanking, while the duration of a line depends on the pixel rate and
+//! the line length. [`SensorTiming`] gathers these values and converts between lines and
+//! microseconds, and [`AnalogueGainModel`] converts between the gain codes programmed into the
+//! sensor and gain multipliers.
+//!
+//! ```no_run
+//! # use std::fs::File;
+//! #
+//! # use v4l2r::controls::image_source;
+//! # use v4l2r::controls::image_source::AnalogueGainModel;
+//! # use v4l2r::controls::image_source::SensorTiming;
+//! #
+//! # let subdev = File::open("/dev/v4l-subdev0").unwrap();
+//! #
+//! let mut timing = SensorTiming::query(&subdev, 1920, 1080).unwrap();
+//! // 30 fps, 10ms exposure.
+//! image_source::set_frame_duration_us(&subdev, &mut timing, 33_333.0).unwrap();
+//! image_source::set_exposure_us(&subdev, &timing, 10_000.0).unwrap();
+//! // This sensor's gain is code / 16.
+//! let model = AnalogueGainModel::Linear {
+//!     m0: 1.0,
+//!     c0: 0.0,
+//!     m1: 0.0,
+//!     c1: 16.0,
+//! };
+//! image_source::set_analogue_gain(&subdev, &model, 2.0).unwrap();
+//! ```
+use std::os::unix::io::AsRawFd;
+
+use nix::errno::Errno;
+use thiserror::Error;
+
+use crate::bindings;
+use crate::controls;
+use crate::controls::image_proc::PixelRate;
+use crate::controls::user::Exposure;
+use crate::controls::{ExtControlTrait, SafeExtControl};
+use crate::ioctl;
+use crate::ioctl::{
+    ControlInfo, CtrlWhich, ExtControlError, OsError, QueryCtrlError, QueryCtrlFlags,
+};
+
+/// Vertical blanking, in lines.
+pub struct VBlank;
+impl ExtControlTrait for VBlank {
+    const ID: u32 = bindings::V4L2_CID_VBLANK;
+    type PAYLOAD = i32;
+}
+
+/// Horizontal blanking, in pixels.
+pub struct HBlank;
+impl ExtControlTrait for HBlank {
+    const ID: u32 = bindings::V4L2_CID_HBLANK;
+    type PAYLOAD = i32;
+}
+
+/// Analogue gain, as a sensor-specific code.
+pub struct AnalogueGain;
+impl ExtControlTrait for AnalogueGain {
+    const ID: u32 = bindings::V4L2_CID_ANALOGUE_GAIN;
+    type PAYLOAD = i32;
+}
+
+#[derive(Debug, Error)]
+pub enum SensorControlError {
+    #[error("failed to get control 0x{0:08x}: {1}")]
+    GetControl(u32, #[source] ExtControlError),
+    #[error("failed to set control 0x{0:08x}: {1}")]
+    SetControl(u32, #[source] ExtControlError),
+    #[error("failed to query control 0x{0:08x}: {1}")]
+    QueryControl(u32, #[source] QueryCtrlError),
+    #[error("invalid pixel rate {0}")]
+    InvalidPixelRate(i64),
+}
+
+impl OsError for SensorControlError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            SensorControlError::GetControl(_, e) | SensorControlError::SetControl(_, e) => {
+                e.errno()
+            }
+            SensorControlError::QueryControl(_, e) => e.errno(),
+            SensorControlError::InvalidPixelRate(_) => None,
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(SensorControlError);
+
+fn get_control<T: ExtControlTrait<PAYLOAD = i32>>(
+    fd: &impl AsRawFd,
+) -> Result<i32, SensorControlError> {
+    controls::get_current_value::<T>(fd).map_err(|e| SensorControlError::GetControl(T::ID, e))
+}
+
+fn set_control<T: ExtControlTrait<PAYLOAD = i32>>(
+    fd: &impl AsRawFd,
+    value: i32,
+) -> Result<i32, SensorControlError> {
+    controls::set_current_value::<T>(fd, value)
+        .map_err(|e| SensorControlError::SetControl(T::ID, e))
+}
+
+fn query_control<T: ExtControlTrait>(fd: &impl AsRawFd) -> Result<ControlInfo, SensorControlError> {
+    ioctl::query_ext_ctrl(fd, controls::ctrl_id::<T>(), QueryCtrlFlags::empty())
+        .map_err(|e| SensorControlError::QueryControl(T::ID, e))
+}
+
+/// Returns the closest value to `value` that is valid for the control described by `info`.
+fn clamp_to_range(value: i64, info: &ControlInfo) -> i64 {
+    let value = value.clamp(info.minimum, info.maximum);
+    let step = info.step.max(1) as i64;
+    let steps = (value - info.minimum + step / 2) / step;
+
+    (info.minimum + steps * step).min(info.maximum)
+}
+
+/// Timings of a raw sensor for a given output size, used to convert between lines and
+/// microseconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SensorTiming {
+    /// Pixel rate of the pixel array, in pixels per second.
+    pub pixel_rate: u64,
+    /// Width of the active area, in pixels.
+    pub width: u32,
+    /// Height of the active area, in lines.
+    pub height: u32,
+    /// Horizontal blanking, in pixels.
+    pub hblank: u32,
+    /// Vertical blanking, in lines.
+    pub vblank: u32,
+}
+
+impl SensorTiming {
+    /// Reads the current pixel rate and blanking of the sensor, for an output of `width`x`height`
+    /// pixels (i.e. the format currently set on the source pad of the sensor).
+    pub fn query(fd: &impl AsRawFd, width: u32, height: u32) -> Result<Self, SensorControlError> {
+        let mut pixel_rate = SafeExtControl::<PixelRate>::from_value64(0);
+        ioctl::g_ext_ctrls(fd, CtrlWhich::Current, &mut pixel_rate)
+            .map_err(|e| SensorControlError::GetControl(PixelRate::ID, e))?;
+        let pixel_rate = match pixel_rate.value64() {
+            rate if rate > 0 => rate as u64,
+            rate => return Err(SensorControlError::InvalidPixelRate(rate)),
+        };
+
+        Ok(SensorTiming {
+            pixel_rate,
+            width,
+            height,
+            hblank: get_control::<HBlank>(fd)?.max(0) as u32,
+            vblank: get_control::<VBlank>(fd)?.max(0) as u32,
+        })
+    }
+
+    /// Returns the total length of a line, blanking included, in pixels.
+    pub fn line_length(&self) -> u32 {
+        self.width + self.hblank
+    }
+
+    /// Returns the total length of a frame, blanking included, in lines.
+    pub fn frame_length(&self) -> u32 {
+        self.height + self.vblank
+    }
+
+    /// Returns the duration of one line, in microseconds.
+    pub fn line_duration_us(&self) -> f64 {
+        self.line_length() as f64 * 1_000_000.0 / self.pixel_rate as f64
+    }
+
+    /// Converts a duration of `lines` lines into microseconds.
+    pub fn lines_to_us(&self, lines: u32) -> f64 {
+        lines as f64 * self.line_duration_us()
+    }
+
+    /// Converts a duration of `us` microseconds into the closest number of lines.
+    pub fn us_to_lines(&self, us: f64) -> u32 {
+        (us / self.line_duration_us()).round().max(0.0) as u32
+    }
+
+    /// Returns the duration of a frame, in microseconds.
+    pub fn frame_duration_us(&self) -> f64 {
+        self.lines_to_us(self.frame_length())
+    }
+
+    /// Returns the vertical blanking needed to achieve a frame duration of `us` microseconds.
+    pub fn vblank_for_frame_duration(&self, us: f64) -> u32 {
+        self.us_to_lines(us).saturating_sub(self.height)
+    }
+}
+
+/// Sets the exposure time of the sensor to the closest possible value to `us` microseconds.
+///
+/// Returns the exposure time actually applied, in microseconds.
+pub fn set_exposure_us(
+    fd: &impl AsRawFd,
+    timing: &SensorTiming,
+    us: f64,
+) -> Result<f64, SensorControlError> {
+    let info = query_control::<Exposure>(fd)?;
+    let lines = clamp_to_range(timing.us_to_lines(us) as i64, &info);
+    let lines = set_control::<Exposure>(fd, lines as i32)?;
+
+    Ok(timing.lines_to_us(lines.max(0) as u32))
+}
+
+/// Adjusts the vertical blanking of the sensor to get a frame duration as close as possible to
+/// `us` microseconds, and updates `timing` accordingly.
+///
+/// Since the maximum exposure time usually depends on the frame length, this should be called
+/// before [`set_exposure_us`] when both are changed.
+///
+/// Returns the frame duration actually applied, in microseconds.
+pub fn set_frame_duration_us(
+    fd: &impl AsRawFd,
+    timing: &mut SensorTiming,
+    us: f64,
+) -> Result<f64, SensorControlError> {
+    let info = query_control::<VBlank>(fd)?;
+    let vblank = clamp_to_range(timing.vblank_for_frame_duration(us) as i64, &info);
+    timing.vblank = set_control::<VBlank>(fd, vblank as i32)?.max(0) as u32;
+
+    Ok(timing.frame_duration_us())
+}
+
+/// Relation between the analogue gain code of a sensor and its gain multiplier. The model and its
+/// parameters are sensor-specific and can usually be found in the sensor's datasheet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnalogueGainModel {
+    /// `gain = (m0 * code + c0) / (m1 * code + c1)`.
+    Linear { m0: f64, c0: f64, m1: f64, c1: f64 },
+    /// `gain = a * 2 ^ (m * code)`.
+    Exponential { a: f64, m: f64 },
+}
+
+impl AnalogueGainModel {
+    /// Returns the gain multiplier corresponding to `code`.
+    pub fn code_to_gain(&self, code: i32) -> f64 {
+        let code = code as f64;
+        match *self {
+            AnalogueGainModel::Linear { m0, c0, m1, c1 } => (m0 * code + c0) / (m1 * code + c1),
+            AnalogueGainModel::Exponential { a, m } => a * (m * code).exp2(),
+        }
+    }
+
+    /// Returns the code corresponding to the gain multiplier `gain`, rounded to the closest
+    /// integer.
+    pub fn gain_to_code(&self, gain: f64) -> i32 {
+        let code = match *self {
+            AnalogueGainModel::Linear { m0, c0, m1, c1 } => (c0 - c1 * gain) / (m1 * gain - m0),
+            AnalogueGainModel::Exponential { a, m } => (gain / a).log2() / m,
+        };
+
+        code.round() as i32
+    }
+}
+
+/// Sets the analogue gain of the sensor to the closest possible value to the multiplier `gain`,
+/// using `model` to compute the gain code.
+///
+/// Returns the gain multiplier actually applied.
+pub fn set_analogue_gain(
+    fd: &impl AsRawFd,
+    model: &AnalogueGainModel,
+    gain: f64,
+) -> Result<f64, SensorControlError> {
+    let info = query_control::<AnalogueGain>(fd)?;
+    let code = clamp_to_range(model.gain_to_code(gain) as i64, &info);
+    let code = set_control::<AnalogueGain>(fd, code as i32)?;
+
+    Ok(model.code_to_gain(code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctl::ControlFlags;
+
+    #[test]
+    fn timing_conversions() {
+        let timing = SensorTiming {
+            pixel_rate: 100_000_000,
+            width: 1800,
+            height: 1000,
+            hblank: 200,
+            vblank: 100,
+        };
+
+        // 2000 pixels at 100MHz.
+        assert_eq!(timing.line_duration_us(), 20.0);
+        assert_eq!(timing.lines_to_us(500), 10_000.0);
+        assert_eq!(timing.us_to_lines(10_009.0), 500);
+        assert_eq!(timing.frame_duration_us(), 22_000.0);
+        assert_eq!(timing.vblank_for_frame_duration(40_000.0), 1000);
+        // Frame durations shorter than the active area cannot be achieved.
+        assert_eq!(timing.vblank_for_frame_duration(1_000.0), 0);
+    }
+
+    #[test]
+    fn gain_models() {
+        let linear = AnalogueGainModel::Linear {
+            m0: 0.0,
+            c0: 256.0,
+            m1: -1.0,
+            c1: 256.0,
+        };
+        assert_eq!(linear.code_to_gain(128), 2.0);
+        assert_eq!(linear.gain_to_code(2.0), 128);
+
+        let exponential = AnalogueGainModel::Exponential { a: 1.0, m: 0.25 };
+        assert_eq!(exponential.code_to_gain(8), 4.0);
+        assert_eq!(exponential.gain_to_code(4.0), 8);
+    }
+
+    #[test]
+    fn range_clamping() {
+        let info = ControlInfo {
+            id: bindings::V4L2_CID_ANALOGUE_GAIN,
+            ctrl_type: bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER,
+            name: "Analogue Gain".into(),
+            minimum: 16,
+            maximum: 100,
+            step: 4,
+            default_value: 16,
+            flags: ControlFlags::empty(),
+            elem_size: 4,
+            elems: 1,
+        };
+
+        assert_eq!(clamp_to_range(0, &info), 16);
+        assert_eq!(clamp_to_range(21, &info), 20);
+        assert_eq!(clamp_to_range(22, &info), 24);
+        assert_eq!(clamp_to_range(200, &info), 100);
+    }
+}
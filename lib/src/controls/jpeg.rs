@@ -0,0 +1,198 @@
+//! Definition of JPEG class controls, and helpers to configure the compression of MJPEG cameras
+//! and JPEG encoders.
+//!
+//! ```no_run
+//! # use std::fs::File;
+//! #
+//! # use v4l2r::controls::jpeg;
+//! # use v4l2r::controls::jpeg::ActiveMarkers;
+//! #
+//! # let device = File::open("/dev/video0").unwrap();
+//! #
+//! let quality = jpeg::set_quality(&device, 90).unwrap();
+//! println!("compression quality set to {}", quality);
+//! jpeg::set_active_markers(&device, ActiveMarkers::DQT | ActiveMarkers::DHT).unwrap();
+//! ```
+use std::convert::TryFrom;
+use std::os::unix::io::AsRawFd;
+
+use bitflags::bitflags;
+use nix::errno::Errno;
+use thiserror::Error;
+
+use crate::bindings;
+use crate::controls;
+use crate::controls::ExtControlTrait;
+use crate::ioctl::ExtControlError;
+use crate::ioctl::OsError;
+
+pub struct JpegChromaSubsampling;
+impl ExtControlTrait for JpegChromaSubsampling {
+    const ID: u32 = bindings::V4L2_CID_JPEG_CHROMA_SUBSAMPLING;
+    type PAYLOAD = i32;
+}
+
+/// Interval between RSTm markers, in MCUs. 0 disables the markers.
+pub struct JpegRestartInterval;
+impl ExtControlTrait for JpegRestartInterval {
+    const ID: u32 = bindings::V4L2_CID_JPEG_RESTART_INTERVAL;
+    type PAYLOAD = i32;
+}
+
+/// Compression quality, from 1 (smallest size) to 100 (best quality).
+pub struct JpegCompressionQuality;
+impl ExtControlTrait for JpegCompressionQuality {
+    const ID: u32 = bindings::V4L2_CID_JPEG_COMPRESSION_QUALITY;
+    type PAYLOAD = i32;
+}
+
+pub struct JpegActiveMarker;
+impl ExtControlTrait for JpegActiveMarker {
+    const ID: u32 = bindings::V4L2_CID_JPEG_ACTIVE_MARKER;
+    type PAYLOAD = i32;
+}
+
+/// Values of the `JpegChromaSubsampling` control.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum ChromaSubsampling {
+    Yuv444 = bindings::v4l2_jpeg_chroma_subsampling_V4L2_JPEG_CHROMA_SUBSAMPLING_444 as i32,
+    Yuv422 = bindings::v4l2_jpeg_chroma_subsampling_V4L2_JPEG_CHROMA_SUBSAMPLING_422 as i32,
+    Yuv420 = bindings::v4l2_jpeg_chroma_subsampling_V4L2_JPEG_CHROMA_SUBSAMPLING_420 as i32,
+    Yuv411 = bindings::v4l2_jpeg_chroma_subsampling_V4L2_JPEG_CHROMA_SUBSAMPLING_411 as i32,
+    Yuv410 = bindings::v4l2_jpeg_chroma_subsampling_V4L2_JPEG_CHROMA_SUBSAMPLING_410 as i32,
+    /// Only the luminance is encoded.
+    Gray = bindings::v4l2_jpeg_chroma_subsampling_V4L2_JPEG_CHROMA_SUBSAMPLING_GRAY as i32,
+}
+
+impl TryFrom<i32> for ChromaSubsampling {
+    type Error = i32;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        [
+            ChromaSubsampling::Yuv444,
+            ChromaSubsampling::Yuv422,
+            ChromaSubsampling::Yuv420,
+            ChromaSubsampling::Yuv411,
+            ChromaSubsampling::Yuv410,
+            ChromaSubsampling::Gray,
+        ]
+        .iter()
+        .copied()
+        .find(|subsampling| *subsampling as i32 == value)
+        .ok_or(value)
+    }
+}
+
+bitflags! {
+    /// Markers written into the JPEG stream, as set by the `JpegActiveMarker` control.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct ActiveMarkers: u32 {
+        const APP0 = bindings::V4L2_JPEG_ACTIVE_MARKER_APP0;
+        const APP1 = bindings::V4L2_JPEG_ACTIVE_MARKER_APP1;
+        const COM = bindings::V4L2_JPEG_ACTIVE_MARKER_COM;
+        const DQT = bindings::V4L2_JPEG_ACTIVE_MARKER_DQT;
+        const DHT = bindings::V4L2_JPEG_ACTIVE_MARKER_DHT;
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum JpegError {
+    #[error("failed to get control 0x{0:08x}: {1}")]
+    GetControl(u32, #[source] ExtControlError),
+    #[error("failed to set control 0x{0:08x}: {1}")]
+    SetControl(u32, #[source] ExtControlError),
+    #[error("unknown chroma subsampling {0}")]
+    UnknownChromaSubsampling(i32),
+}
+
+impl OsError for JpegError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            JpegError::GetControl(_, e) | JpegError::SetControl(_, e) => e.errno(),
+            JpegError::UnknownChromaSubsampling(_) => None,
+        }
+    }
+}
+
+crate::ioctl::impl_into_io_error!(JpegError);
+
+fn get_control<T: ExtControlTrait<PAYLOAD = i32>>(fd: &impl AsRawFd) -> Result<i32, JpegError> {
+    controls::get_current_value::<T>(fd).map_err(|e| JpegError::GetControl(T::ID, e))
+}
+
+fn set_control<T: ExtControlTrait<PAYLOAD = i32>>(
+    fd: &impl AsRawFd,
+    value: i32,
+) -> Result<i32, JpegError> {
+    controls::set_current_value::<T>(fd, value).map_err(|e| JpegError::SetControl(T::ID, e))
+}
+
+/// Returns the current compression quality, from 1 to 100.
+pub fn quality(fd: &impl AsRawFd) -> Result<i32, JpegError> {
+    get_control::<JpegCompressionQuality>(fd)
+}
+
+/// Sets the compression quality, from 1 to 100, and returns the value actually applied by the
+/// driver.
+pub fn set_quality(fd: &impl AsRawFd, quality: i32) -> Result<i32, JpegError> {
+    set_control::<JpegCompressionQuality>(fd, quality)
+}
+
+/// Returns the current interval between restart markers, in MCUs.
+pub fn restart_interval(fd: &impl AsRawFd) -> Result<i32, JpegError> {
+    get_control::<JpegRestartInterval>(fd)
+}
+
+/// Sets the interval between restart markers, in MCUs, and returns the value actually applied by
+/// the driver. 0 disables restart markers.
+pub fn set_restart_interval(fd: &impl AsRawFd, interval: i32) -> Result<i32, JpegError> {
+    set_control::<JpegRestartInterval>(fd, interval)
+}
+
+/// Returns the current chroma subsampling of the encoded stream.
+pub fn chroma_subsampling(fd: &impl AsRawFd) -> Result<ChromaSubsampling, JpegError> {
+    let value = get_control::<JpegChromaSubsampling>(fd)?;
+    ChromaSubsampling::try_from(value).map_err(JpegError::UnknownChromaSubsampling)
+}
+
+/// Sets the chroma subsampling of the encoded stream.
+pub fn set_chroma_subsampling(
+    fd: &impl AsRawFd,
+    subsampling: ChromaSubsampling,
+) -> Result<(), JpegError> {
+    set_control::<JpegChromaSubsampling>(fd, subsampling as i32).map(|_| ())
+}
+
+/// Returns the markers currently written into the JPEG stream.
+pub fn active_markers(fd: &impl AsRawFd) -> Result<ActiveMarkers, JpegError> {
+    get_control::<JpegActiveMarker>(fd)
+        .map(|markers| ActiveMarkers::from_bits_truncate(markers as u32))
+}
+
+/// Selects the markers to write into the JPEG stream, and returns the ones actually enabled by
+/// the driver.
+pub fn set_active_markers(
+    fd: &impl AsRawFd,
+    markers: ActiveMarkers,
+) -> Result<ActiveMarkers, JpegError> {
+    set_control::<JpegActiveMarker>(fd, markers.bits() as i32)
+        .map(|markers| ActiveMarkers::from_bits_truncate(markers as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::ChromaSubsampling;
+
+    #[test]
+    fn chroma_subsampling_from_value() {
+        assert_eq!(
+            ChromaSubsampling::try_from(2),
+            Ok(ChromaSubsampling::Yuv420)
+        );
+        assert_eq!(ChromaSubsampling::try_from(5), Ok(ChromaSubsampling::Gray));
+        assert_eq!(ChromaSubsampling::try_from(6), Err(6));
+    }
+}
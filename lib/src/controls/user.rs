@@ -3,6 +3,18 @@
 use crate::bindings;
 use crate::controls::ExtControlTrait;
 
+pub struct MinBuffersForCapture;
+impl ExtControlTrait for MinBuffersForCapture {
+    const ID: u32 = bindings::V4L2_CID_MIN_BUFFERS_FOR_CAPTURE;
+    type PAYLOAD = i32;
+}
+
+pub struct MinBuffersForOutput;
+impl ExtControlTrait for MinBuffersForOutput {
+    const ID: u32 = bindings::V4L2_CID_MIN_BUFFERS_FOR_OUTPUT;
+    type PAYLOAD = i32;
+}
+
 pub struct Brightness;
 impl ExtControlTrait for Brightness {
     const ID: u32 = bindings::V4L2_CID_BRIGHTNESS;
@@ -14,3 +26,34 @@ impl ExtControlTrait for Contrast {
     const ID: u32 = bindings::V4L2_CID_CONTRAST;
     type PAYLOAD = i32;
 }
+
+pub struct AutoWhiteBalance;
+impl ExtControlTrait for AutoWhiteBalance {
+    const ID: u32 = bindings::V4L2_CID_AUTO_WHITE_BALANCE;
+    type PAYLOAD = i32;
+}
+
+pub struct WhiteBalanceTemperature;
+impl ExtControlTrait for WhiteBalanceTemperature {
+    const ID: u32 = bindings::V4L2_CID_WHITE_BALANCE_TEMPERATURE;
+    type PAYLOAD = i32;
+}
+
+pub struct Autogain;
+impl ExtControlTrait for Autogain {
+    const ID: u32 = bindings::V4L2_CID_AUTOGAIN;
+    type PAYLOAD = i32;
+}
+
+pub struct Gain;
+impl ExtControlTrait for Gain {
+    const ID: u32 = bindings::V4L2_CID_GAIN;
+    type PAYLOAD = i32;
+}
+
+/// Exposure time of raw sensors, in lines.
+pub struct Exposure;
+impl ExtControlTrait for Exposure {
+    const ID: u32 = bindings::V4L2_CID_EXPOSURE;
+    type PAYLOAD = i32;
+}
@@ -0,0 +1,292 @@
+//! Deadline-aware scheduling of the frames submitted to memory-to-memory
+//! devices, for real-time clients.
+//!
+//! [`DeadlineScheduler`] works like a [`TimestampTracker`]: each frame is
+//! submitted along with its data and the instant by which it must have been
+//! processed, and is given the timestamp to set on its OUTPUT buffer. The
+//! scheduler measures the latency of the device as frames complete, and uses
+//! it to predict whether the next frames will meet their deadline. Depending on
+//! the [`DeadlinePolicy`], frames that cannot make it are either rejected at
+//! submission time, or accepted and reported as late when they complete.
+//!
+//! ```
+//! # use std::time::{Duration, Instant};
+//! # use v4l2r::deadline::{DeadlinePolicy, DeadlineScheduler};
+//! let mut scheduler = DeadlineScheduler::new(DeadlinePolicy::Drop);
+//!
+//! let deadline = Instant::now() + Duration::from_millis(33);
+//! match scheduler.submit("frame 0", deadline) {
+//!     // Set `timestamp` on the OUTPUT buffer and queue it, then pass the
+//!     // CAPTURE buffer produced from it to `complete()`.
+//!     Ok(timestamp) => (),
+//!     // The frame would be late anyway, so skip it.
+//!     Err(rejected) => (),
+//! }
+//! ```
+use std::time::{Duration, Instant};
+
+use nix::sys::time::TimeVal;
+
+use crate::{
+    bindings,
+    device::queue::{
+        direction::{Capture, Output},
+        dqbuf::DqBuffer,
+    },
+    memory::BufferHandles,
+    timestamp::TimestampTracker,
+};
+
+/// What to do with frames that are not expected to meet their deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadlinePolicy {
+    /// Accept them anyway, and report them as late upon completion.
+    Report,
+    /// Reject them at submission time so they are not queued at all.
+    Drop,
+}
+
+/// Stages of the processing of a frame whose latency is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Time spent by OUTPUT buffers in the driver, until they can be reused.
+    Output,
+    /// Time between the submission of a frame and the dequeueing of the
+    /// CAPTURE buffer produced from it.
+    EndToEnd,
+}
+
+/// Exponentially weighted moving average of the latency of a stage.
+#[derive(Default)]
+struct Latency(Option<Duration>);
+
+impl Latency {
+    /// Weight of the previous average against a new sample.
+    const HISTORY_WEIGHT: u32 = 7;
+
+    fn record(&mut self, sample: Duration) {
+        self.0 = Some(match self.0 {
+            None => sample,
+            Some(average) => (average * Self::HISTORY_WEIGHT + sample) / (Self::HISTORY_WEIGHT + 1),
+        });
+    }
+}
+
+struct Pending<T> {
+    data: T,
+    deadline: Instant,
+    submitted_at: Instant,
+}
+
+/// A frame that has been processed by the device.
+#[derive(Debug)]
+pub struct CompletedFrame<T> {
+    pub data: T,
+    pub deadline: Instant,
+    /// Time between the submission of the frame and its completion.
+    pub latency: Duration,
+    /// By how much the frame missed its deadline, if it did.
+    pub late_by: Option<Duration>,
+}
+
+/// A frame rejected by `DeadlineScheduler::submit` as it is not expected to
+/// meet its deadline.
+#[derive(Debug)]
+pub struct RejectedFrame<T> {
+    pub data: T,
+    pub deadline: Instant,
+    /// When the frame would have been expected to complete.
+    pub expected_completion: Instant,
+}
+
+/// Keeps track of the deadlines of the frames submitted to a device, and of
+/// its latency to predict which frames can meet theirs.
+pub struct DeadlineScheduler<T> {
+    policy: DeadlinePolicy,
+    pending: TimestampTracker<Pending<T>>,
+    output_latency: Latency,
+    end_to_end_latency: Latency,
+}
+
+impl<T> DeadlineScheduler<T> {
+    pub fn new(policy: DeadlinePolicy) -> Self {
+        DeadlineScheduler {
+            policy,
+            pending: TimestampTracker::new(),
+            output_latency: Default::default(),
+            end_to_end_latency: Default::default(),
+        }
+    }
+
+    pub fn policy(&self) -> DeadlinePolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: DeadlinePolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the average latency measured for `stage`, or `None` if no frame
+    /// went through it yet.
+    pub fn latency(&self, stage: Stage) -> Option<Duration> {
+        match stage {
+            Stage::Output => self.output_latency.0,
+            Stage::EndToEnd => self.end_to_end_latency.0,
+        }
+    }
+
+    /// Returns when a frame submitted at `now` is expected to complete, or
+    /// `None` if no frame completed yet.
+    pub fn expected_completion(&self, now: Instant) -> Option<Instant> {
+        self.latency(Stage::EndToEnd).map(|latency| now + latency)
+    }
+
+    /// Submit a frame carrying `data` which must be processed by `deadline`.
+    ///
+    /// Returns the timestamp to set on the OUTPUT buffer of the frame, or the
+    /// frame if the policy is `DeadlinePolicy::Drop` and it is not expected to
+    /// meet its deadline, in which case it should not be queued.
+    pub fn submit(&mut self, data: T, deadline: Instant) -> Result<TimeVal, RejectedFrame<T>> {
+        self.submit_at(data, deadline, Instant::now())
+    }
+
+    fn submit_at(
+        &mut self,
+        data: T,
+        deadline: Instant,
+        now: Instant,
+    ) -> Result<TimeVal, RejectedFrame<T>> {
+        if self.policy == DeadlinePolicy::Drop {
+            match self.expected_completion(now) {
+                Some(expected_completion) if expected_completion > deadline => {
+                    return Err(RejectedFrame {
+                        data,
+                        deadline,
+                        expected_completion,
+                    })
+                }
+                _ => (),
+            }
+        }
+
+        Ok(self.pending.submit(Pending {
+            data,
+            deadline,
+            submitted_at: now,
+        }))
+    }
+
+    /// Record the time the dequeued OUTPUT `buffer` has spent in the driver.
+    pub fn output_done<P: BufferHandles>(&mut self, buffer: &DqBuffer<Output, P>) {
+        if let Some(time_in_driver) = buffer.time_in_driver() {
+            self.output_latency.record(time_in_driver);
+        }
+    }
+
+    /// Complete the frame the dequeued CAPTURE `buffer` has been produced
+    /// from.
+    ///
+    /// Returns `None` if the buffer does not correspond to a submitted frame,
+    /// e.g. because it is a LAST buffer without data.
+    pub fn complete<P: BufferHandles>(
+        &mut self,
+        buffer: &DqBuffer<Capture, P>,
+    ) -> Option<CompletedFrame<T>> {
+        self.complete_timestamp(&buffer.timestamp(), buffer.dequeued_at())
+    }
+
+    /// Complete the frame submitted with `timestamp` at `completed_at`, for
+    /// clients that do not have access to the dequeued CAPTURE buffer.
+    pub fn complete_timestamp(
+        &mut self,
+        timestamp: &bindings::timeval,
+        completed_at: Instant,
+    ) -> Option<CompletedFrame<T>> {
+        let pending = self.pending.take(timestamp)?;
+        let latency = completed_at.saturating_duration_since(pending.submitted_at);
+        self.end_to_end_latency.record(latency);
+
+        Some(CompletedFrame {
+            data: pending.data,
+            deadline: pending.deadline,
+            latency,
+            late_by: completed_at
+                .checked_duration_since(pending.deadline)
+                .filter(|late_by| !late_by.is_zero()),
+        })
+    }
+
+    /// Returns the number of submitted frames that have not completed yet.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Forget all the pending frames, e.g. after a flush. The measured
+    /// latencies are preserved.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeval(timestamp: TimeVal) -> bindings::timeval {
+        bindings::timeval {
+            tv_sec: timestamp.tv_sec() as _,
+            tv_usec: timestamp.tv_usec() as _,
+        }
+    }
+
+    #[test]
+    fn deadline_scheduler() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let mut scheduler = DeadlineScheduler::new(DeadlinePolicy::Drop);
+        assert_eq!(scheduler.latency(Stage::EndToEnd), None);
+        assert_eq!(scheduler.expected_completion(start), None);
+
+        // Without measurement, all frames are accepted.
+        let t0 = timeval(scheduler.submit_at(0, start, start).unwrap());
+        let t1 = timeval(scheduler.submit_at(1, start + ms(100), start).unwrap());
+        assert_eq!(scheduler.len(), 2);
+
+        let completed = scheduler.complete_timestamp(&t0, start + ms(20)).unwrap();
+        assert_eq!(completed.data, 0);
+        assert_eq!(completed.latency, ms(20));
+        assert_eq!(completed.late_by, Some(ms(20)));
+        assert_eq!(scheduler.latency(Stage::EndToEnd), Some(ms(20)));
+
+        let completed = scheduler.complete_timestamp(&t1, start + ms(60)).unwrap();
+        assert_eq!(completed.data, 1);
+        assert_eq!(completed.late_by, None);
+        // The average moves slowly towards the new sample.
+        assert_eq!(scheduler.latency(Stage::EndToEnd), Some(ms(25)));
+        assert!(scheduler.complete_timestamp(&t1, start + ms(60)).is_none());
+        assert!(scheduler.is_empty());
+
+        // Frames that cannot complete in time are rejected...
+        let now = start + ms(100);
+        let rejected = scheduler.submit_at(2, now + ms(10), now).unwrap_err();
+        assert_eq!(rejected.data, 2);
+        assert_eq!(rejected.expected_completion, now + ms(25));
+        assert!(scheduler.submit_at(3, now + ms(25), now).is_ok());
+
+        // ... unless they should just be reported.
+        scheduler.set_policy(DeadlinePolicy::Report);
+        let t4 = timeval(scheduler.submit_at(4, now + ms(10), now).unwrap());
+        let completed = scheduler.complete_timestamp(&t4, now + ms(25)).unwrap();
+        assert_eq!(completed.late_by, Some(ms(15)));
+
+        assert_eq!(scheduler.len(), 1);
+        scheduler.clear();
+        assert!(scheduler.is_empty());
+        assert!(scheduler.latency(Stage::EndToEnd).is_some());
+        assert_eq!(scheduler.latency(Stage::Output), None);
+    }
+}
@@ -1,9 +1,10 @@
 //! High-level interface for a V4L2 video decoder. Currently only supports the
-//! [stateful interface](https://www.kernel.org/doc/html/latest/userspace-api/media/v4l/dev-encoder.html).
+//! [stateful interface](https://www.kernel.org/doc/html/latest/userspace-api/media/v4l/dev-encoder.html),
+//! plus a few helpers for the stateless one.
 use crate::{
     device::queue::{
         direction::{Capture, Output},
-        dqbuf::DqBuffer,
+        dqbuf::{DqBuffer, FrameError},
         handles_provider::HandlesProvider,
         CanceledBuffer, FormatBuilder,
     },
@@ -12,7 +13,9 @@ use crate::{
 };
 
 pub mod format;
+pub mod reorder;
 pub mod stateful;
+pub mod stateless;
 
 pub enum CompletedInputBuffer<OP: BufferHandles> {
     Dequeued(DqBuffer<Output, OP>),
@@ -31,13 +34,26 @@ where
 pub enum DecoderEvent<P: HandlesProvider> {
     /// Emitted when a frame is decoded.
     ///
-    /// The parameter is the dequeued buffer, containing the plane handles of
-    /// the decoded frame as well as its V4L2 parameters such as flags. The
+    /// The first parameter is the dequeued buffer, containing the plane handles
+    /// of the decoded frame as well as its V4L2 parameters such as flags. The
     /// flags remain untouched, but the client should not take action on some
     /// of them: for instance, when the `V4L2_BUF_FLAG_LAST` is set, the proper
     /// corresponding event (resolution change or end of stream) will be
     /// signaled appropriately.
-    FrameDecoded(DqBuffer<Capture, P::HandleType>),
+    ///
+    /// The second parameter is the visible rectangle of the frame, i.e. the
+    /// part of the buffer that contains the actual picture. The coded size of
+    /// the frame is usually larger due to alignment requirements.
+    ///
+    /// The buffer carries the timestamp of the OUTPUT buffer the frame has
+    /// been decoded from, which [`crate::timestamp::TimestampTracker`] can
+    /// use to retrieve the data attached to that OUTPUT buffer.
+    FrameDecoded(DqBuffer<Capture, P::HandleType>, Rect),
+    /// Emitted instead of `FrameDecoded` when the driver reported an error
+    /// while decoding a frame. The picture may be corrupted, but the buffer is
+    /// still returned so the client can recover its handles or display it
+    /// anyway. Such frames are counted by `Decoder::num_frame_errors()`.
+    FrameCorrupted(FrameError<DqBuffer<Capture, P::HandleType>>, Rect),
     /// Emitted when a previously requested `drain` request completes.
     ///
     /// When this event is emitted, the client knows that all the frames
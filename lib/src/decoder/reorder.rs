@@ -0,0 +1,118 @@
+//! Reordering of decoded frames into presentation order.
+//!
+//! Decoders of codecs using bidirectional prediction may return frames in the
+//! order they have been decoded rather than the one they must be displayed in.
+//! As the timestamp of each OUTPUT buffer is copied into the frame decoded from
+//! it, clients queueing their input with increasing timestamps can recover the
+//! presentation order by holding a few frames and always emitting the one with
+//! the lowest timestamp. [`ReorderBuffer`] does exactly that.
+use std::collections::BTreeMap;
+
+use crate::{bindings, timestamp::timestamp_key};
+
+/// Holds up to `depth` frames and releases them by increasing timestamp.
+///
+/// The depth must be at least the maximum number of frames the decoder can
+/// return ahead of a frame that precedes them in presentation order, which is
+/// e.g. the DPB size for H.264.
+pub struct ReorderBuffer<T> {
+    depth: usize,
+    /// Frames indexed by timestamp, then by arrival order for frames sharing
+    /// the same timestamp.
+    frames: BTreeMap<(i64, u64), T>,
+    sequence: u64,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new(depth: usize) -> Self {
+        ReorderBuffer {
+            depth,
+            frames: BTreeMap::new(),
+            sequence: 0,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the number of frames currently held.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Add `frame` with `timestamp`, and return the frame with the lowest
+    /// timestamp if more than `depth` frames are held.
+    pub fn push(&mut self, timestamp: &bindings::timeval, frame: T) -> Option<T> {
+        self.frames
+            .insert((timestamp_key(timestamp), self.sequence), frame);
+        self.sequence += 1;
+
+        if self.frames.len() > self.depth {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Remove and return the frame with the lowest timestamp, if any.
+    pub fn pop(&mut self) -> Option<T> {
+        let key = *self.frames.keys().next()?;
+        self.frames.remove(&key)
+    }
+
+    /// Remove and return all the frames by increasing timestamp, e.g. at the
+    /// end of the stream.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> {
+        std::mem::take(&mut self.frames).into_values()
+    }
+
+    /// Drop all the frames held, e.g. after a flush.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeval(usec: i64) -> bindings::timeval {
+        bindings::timeval {
+            tv_sec: (usec / 1_000_000) as _,
+            tv_usec: (usec % 1_000_000) as _,
+        }
+    }
+
+    #[test]
+    fn reorder_buffer() {
+        let mut reorder = ReorderBuffer::new(2);
+
+        // Frames in decode order of an I P B B P B sequence.
+        let mut out = Vec::new();
+        for pts in [0, 3, 1, 2, 5, 4] {
+            out.extend(reorder.push(&timeval(pts), pts));
+        }
+        assert_eq!(out, vec![0, 1, 2, 3]);
+        assert_eq!(reorder.len(), 2);
+        out.extend(reorder.drain());
+        assert_eq!(out, vec![0, 1, 2, 3, 4, 5]);
+        assert!(reorder.is_empty());
+
+        // Frames with the same timestamp keep their arrival order.
+        assert_eq!(reorder.push(&timeval(2_000_000), 10), None);
+        assert_eq!(reorder.push(&timeval(1_000_000), 11), None);
+        assert_eq!(reorder.push(&timeval(1_000_000), 12), Some(11));
+        assert_eq!(reorder.pop(), Some(12));
+        reorder.clear();
+        assert_eq!(reorder.pop(), None);
+
+        // A depth of zero releases frames immediately.
+        let mut passthrough = ReorderBuffer::new(0);
+        assert_eq!(passthrough.push(&timeval(1), 1), Some(1));
+    }
+}
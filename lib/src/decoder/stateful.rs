@@ -2,6 +2,11 @@ mod capture_thread;
 
 use crate::{
     bindings,
+    controls::{
+        codec::{VideoDecDisplayDelay, VideoDecDisplayDelayEnable},
+        descriptor::{validate_control, ControlValidationError},
+        AsV4l2ControlSlice, ExtControlTrait, SafeExtControl,
+    },
     device::{
         poller::{DeviceEvent, PollError, PollEvent, Poller, Waker},
         queue::{
@@ -10,27 +15,33 @@ use crate::{
             qbuf::{
                 get_free::{GetFreeBufferError, GetFreeCaptureBuffer, GetFreeOutputBuffer},
                 get_indexed::GetCaptureBufferByIndex,
-                OutputQueueableProvider,
+                OutputQueueable, OutputQueueableProvider,
             },
-            BuffersAllocated, CreateQueueError, FormatBuilder, Queue, QueueInit,
-            RequestBuffersError,
+            watermark::{QueueLevel, QueueWatermark},
+            BuffersAllocated, CreateQueueError, FormatBuilder, FormatOutcome, Queue, QueueInit,
+            RequestBuffersError, SetFormatError,
         },
         AllocatedQueue, Device, DeviceConfig, DeviceOpenError, Stream, TryDequeue,
     },
     ioctl::{
-        self, subscribe_event, BufferCapabilities, DqBufError, FormatFlags, StreamOnError,
+        self, subscribe_event, CtrlWhich, DqBufError, FormatFlags, OsError, StreamOnError,
         V4l2BufferFromError,
     },
     memory::{BufferHandles, PrimitiveBufferHandles},
+    shutdown::ShutdownToken,
 };
 
 use capture_thread::CaptureThread;
 use log::{debug, error, info, trace};
+use nix::errno::Errno;
 use std::{
-    convert::TryFrom,
+    convert::{Infallible, TryFrom},
     io,
     path::Path,
-    sync::{atomic::AtomicUsize, mpsc, Arc},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
     task::Wake,
     thread::JoinHandle,
 };
@@ -62,6 +73,110 @@ pub enum DecoderOpenError {
     NotAStatefulDecoder,
 }
 
+impl OsError for DecoderOpenError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            DecoderOpenError::DeviceOpenError(e) => e.errno(),
+            DecoderOpenError::CreateQueueError(e) => e.errno(),
+            DecoderOpenError::NotAStatefulDecoder => None,
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(DecoderOpenError);
+
+#[derive(Debug, Error)]
+pub enum DecoderControlError {
+    #[error("invalid control for the decoder")]
+    InvalidControl(#[from] ControlValidationError),
+    #[error("error while accessing controls")]
+    ExtControlError(#[from] ioctl::ExtControlError),
+}
+
+impl OsError for DecoderControlError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            DecoderControlError::InvalidControl(e) => e.errno(),
+            DecoderControlError::ExtControlError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(DecoderControlError);
+
+/// Methods of the decoder that are available no matter the state.
+impl<S: DecoderState> Decoder<S> {
+    /// Returns the current display delay of the decoder, or `None` if
+    /// decoded frames are returned in display order.
+    pub fn display_delay(&self) -> Result<Option<u32>, DecoderControlError> {
+        let mut enable = SafeExtControl::<VideoDecDisplayDelayEnable>::from_value(0);
+        ioctl::g_ext_ctrls(&*self.device, CtrlWhich::Current, &mut enable)?;
+        if enable.value() == 0 {
+            return Ok(None);
+        }
+
+        let mut delay = SafeExtControl::<VideoDecDisplayDelay>::from_value(0);
+        ioctl::g_ext_ctrls(&*self.device, CtrlWhich::Current, &mut delay)?;
+
+        Ok(Some(delay.value().max(0) as u32))
+    }
+
+    /// Sets the display delay of the decoder.
+    ///
+    /// With `Some(delay)`, the CAPTURE buffer of a frame is returned at most
+    /// `delay` OUTPUT buffers after the one containing it, even if that means
+    /// returning frames out of display order. `Some(0)` makes the decoder
+    /// return each frame as soon as it is decoded, which is useful for
+    /// low-latency streams without frame reordering. `None` restores the
+    /// default behavior of returning frames in display order.
+    ///
+    /// Drivers usually only accept a new delay before the decoder is started,
+    /// and report the controls as grabbed afterwards, in which case
+    /// `ControlValidationError::NotWritable` is returned. An error is returned
+    /// without any control being changed if the decoder does not support the
+    /// controls or the requested delay.
+    pub fn set_display_delay(&self, delay: Option<u32>) -> Result<(), DecoderControlError> {
+        validate_control(
+            &*self.device,
+            VideoDecDisplayDelayEnable::ID,
+            delay.is_some() as i64,
+        )?;
+
+        match delay {
+            None => {
+                let mut enable = SafeExtControl::<VideoDecDisplayDelayEnable>::from_value(0);
+                ioctl::s_ext_ctrls(&*self.device, CtrlWhich::Current, &mut enable)?;
+            }
+            Some(delay) => {
+                validate_control(&*self.device, VideoDecDisplayDelay::ID, delay as i64)?;
+
+                #[repr(C)]
+                struct DisplayDelayControls {
+                    enable: SafeExtControl<VideoDecDisplayDelayEnable>,
+                    delay: SafeExtControl<VideoDecDisplayDelay>,
+                }
+
+                impl AsV4l2ControlSlice for &mut DisplayDelayControls {
+                    fn as_v4l2_control_slice(&mut self) -> &mut [bindings::v4l2_ext_control] {
+                        let ptr =
+                            (*self) as *mut DisplayDelayControls as *mut bindings::v4l2_ext_control;
+                        unsafe { std::slice::from_raw_parts_mut(ptr, 2) }
+                    }
+                }
+
+                // Set both controls in the same call so they are applied atomically.
+                let mut controls = DisplayDelayControls {
+                    enable: SafeExtControl::from_value(1),
+                    delay: SafeExtControl::from_value(delay as i32),
+                };
+                ioctl::s_ext_ctrls(&*self.device, CtrlWhich::Current, &mut controls)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl Decoder<AwaitingOutputFormat> {
     pub fn open(path: &Path) -> Result<Self, DecoderOpenError> {
         let config = DeviceConfig::new().non_blocking_dqbuf();
@@ -86,10 +201,7 @@ impl Decoder<AwaitingOutputFormat> {
 
         // A stateful decoder won't expose the requests capability on the OUTPUT
         // queue, a stateless one will.
-        if output_queue
-            .get_capabilities()
-            .contains(BufferCapabilities::SUPPORTS_REQUESTS)
-        {
+        if output_queue.supports_requests() {
             return Err(DecoderOpenError::NotAStatefulDecoder);
         }
 
@@ -102,20 +214,27 @@ impl Decoder<AwaitingOutputFormat> {
         })
     }
 
-    pub fn set_output_format<F>(mut self, f: F) -> anyhow::Result<Decoder<AwaitingOutputBuffers>>
+    /// Apply the OUTPUT format configured by `f`. The returned outcome tells
+    /// whether the driver adjusted the requested format.
+    pub fn set_output_format<F>(
+        mut self,
+        f: F,
+    ) -> Result<(Decoder<AwaitingOutputBuffers>, FormatOutcome), SetFormatError>
     where
-        F: FnOnce(FormatBuilder) -> anyhow::Result<()>,
+        F: FnOnce(FormatBuilder) -> FormatBuilder,
     {
-        let builder = self.state.output_queue.change_format()?;
-        f(builder)?;
-
-        Ok(Decoder {
-            device: self.device,
-            state: AwaitingOutputBuffers {
-                output_queue: self.state.output_queue,
-                capture_queue: self.state.capture_queue,
+        let outcome = f(self.state.output_queue.change_format()?).apply_checked()?;
+
+        Ok((
+            Decoder {
+                device: self.device,
+                state: AwaitingOutputBuffers {
+                    output_queue: self.state.output_queue,
+                    capture_queue: self.state.capture_queue,
+                },
             },
-        })
+            outcome,
+        ))
     }
 }
 
@@ -131,6 +250,8 @@ impl Decoder<AwaitingOutputBuffers> {
         memory_type: OP::SupportedMemoryType,
         num_buffers: usize,
     ) -> Result<Decoder<ReadyToDecode<OP>>, RequestBuffersError> {
+        let num_buffers = self.state.output_queue.clamp_num_buffers(num_buffers);
+
         Ok(Decoder {
             device: self.device,
             state: ReadyToDecode {
@@ -140,6 +261,10 @@ impl Decoder<AwaitingOutputBuffers> {
                     .request_buffers_generic::<OP>(memory_type, num_buffers as u32)?,
                 capture_queue: self.state.capture_queue,
                 poll_wakeups_counter: None,
+                empty_output_handles: None,
+                capture_watermark: None,
+                reorder_depth: None,
+                shutdown_token: None,
             },
         })
     }
@@ -156,31 +281,108 @@ pub struct ReadyToDecode<OP: BufferHandles> {
     output_queue: Queue<Output, BuffersAllocated<OP>>,
     capture_queue: Queue<Capture, QueueInit>,
     poll_wakeups_counter: Option<Arc<AtomicUsize>>,
+    empty_output_handles: Option<EmptyHandlesCb<OP>>,
+    capture_watermark: Option<QueueWatermark>,
+    reorder_depth: Option<usize>,
+    shutdown_token: Option<ShutdownToken>,
 }
 impl<OP: BufferHandles> DecoderState for ReadyToDecode<OP> {}
 
+/// Callback returning the handles to use for an empty OUTPUT buffer.
+type EmptyHandlesCb<OP> = Box<dyn Fn() -> OP + Send>;
+
 #[derive(Debug, Error)]
 pub enum StartDecoderError {
     #[error("error while creating poller")]
-    CannotCreatePoller(nix::Error),
+    CannotCreatePoller(#[source] nix::Error),
     #[error("cannot subscribe to decoder event")]
     SubscribeEventError(#[from] ioctl::SubscribeEventError),
     #[error("error while enabling event")]
-    CannotEnableEvent(nix::Error),
+    CannotEnableEvent(#[source] nix::Error),
+    #[error("error while registering the shutdown token")]
+    CannotSetShutdownToken(#[source] nix::Error),
     #[error("error while creating capture thread")]
-    CannotCreateCaptureThread(io::Error),
+    CannotCreateCaptureThread(#[source] io::Error),
     #[error("error while activating capture thread")]
-    CannotStartCaptureThread(io::Error),
+    CannotStartCaptureThread(#[source] io::Error),
     #[error("error while starting the output queue")]
     StreamOnError(#[from] StreamOnError),
 }
 
+impl OsError for StartDecoderError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            StartDecoderError::CannotCreatePoller(e)
+            | StartDecoderError::CannotEnableEvent(e)
+            | StartDecoderError::CannotSetShutdownToken(e) => Some(*e),
+            StartDecoderError::SubscribeEventError(e) => e.errno(),
+            StartDecoderError::CannotCreateCaptureThread(e)
+            | StartDecoderError::CannotStartCaptureThread(e) => {
+                e.raw_os_error().map(Errno::from_i32)
+            }
+            StartDecoderError::StreamOnError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(StartDecoderError);
+
 impl<OP: BufferHandles> Decoder<ReadyToDecode<OP>> {
     pub fn set_poll_counter(mut self, poll_wakeups_counter: Arc<AtomicUsize>) -> Self {
         self.state.poll_wakeups_counter = Some(poll_wakeups_counter);
         self
     }
 
+    /// Sets the callback used to obtain the handles of an empty OUTPUT buffer.
+    ///
+    /// Drivers that do not support `VIDIOC_DECODER_CMD` signal the end of the
+    /// stream by queueing an OUTPUT buffer with no data. [`Decoder::drain`]
+    /// automatically does so when the command is not supported, using this
+    /// callback to obtain the handles to queue the empty buffer with. Since such
+    /// drivers may not set the LAST flag, an empty CAPTURE buffer then also
+    /// completes the drain.
+    pub fn set_empty_output_handles<F: Fn() -> OP + Send + 'static>(mut self, f: F) -> Self {
+        self.state.empty_output_handles = Some(Box::new(f));
+        self
+    }
+
+    /// Makes the decoder invoke `cb` when less than `watermark` CAPTURE
+    /// buffers are queued, and again once the queue recovers, so the client
+    /// can prioritize returning decoded frames before the driver stalls.
+    pub fn set_capture_watermark<F: FnMut(QueueLevel) + Send + 'static>(
+        mut self,
+        watermark: usize,
+        cb: F,
+    ) -> Self {
+        self.state.capture_watermark = Some(QueueWatermark::new(watermark, cb));
+        self
+    }
+
+    /// Makes the decoder emit decoded frames in presentation order, for
+    /// drivers returning them in decode order.
+    ///
+    /// Up to `depth` decoded frames are held and released by increasing
+    /// timestamp, so the OUTPUT buffers must be queued with timestamps
+    /// following the presentation order. All held frames are released before
+    /// the end of the stream, a resolution change or the decoder stopping, and
+    /// dropped on `flush`.
+    /// As held frames keep their CAPTURE buffer, the format change callback
+    /// should allocate `depth` more buffers than the driver requires.
+    pub fn set_reorder_depth(mut self, depth: usize) -> Self {
+        self.state.reorder_depth = Some(depth);
+        self
+    }
+
+    /// Makes `get_buffer()` fail with `PollError::Interrupted` instead of
+    /// waiting once shutdown is requested on `token`, and a blocking drain in
+    /// progress fail with `DrainError::Interrupted`. The decoder keeps
+    /// emitting frames, so the client can still drain it explicitly before
+    /// calling `stop()`.
+    pub fn set_shutdown_token(mut self, token: ShutdownToken) -> Self {
+        self.state.shutdown_token = Some(token);
+        self
+    }
+
     #[allow(clippy::type_complexity)]
     pub fn start<P, InputDoneCb, DecoderEventCb, FormatChangedCb>(
         self,
@@ -220,12 +422,28 @@ impl<OP: BufferHandles> Decoder<ReadyToDecode<OP>> {
             self.state.capture_queue,
             decoder_event_cb,
             set_capture_format_cb,
+            self.state.capture_watermark,
             command_receiver,
             response_sender,
         )
         .map_err(StartDecoderError::CannotCreateCaptureThread)?;
 
         let command_waker = Arc::clone(&decoder_thread.command_waker);
+        let frame_errors = decoder_thread.frame_errors();
+
+        if let Some(depth) = self.state.reorder_depth {
+            decoder_thread.set_reorder_depth(depth);
+        }
+
+        if let Some(token) = &self.state.shutdown_token {
+            output_poller
+                .set_shutdown_token(token.clone())
+                .map_err(StartDecoderError::CannotSetShutdownToken)?;
+            decoder_thread
+                .poller
+                .set_shutdown_token(token.clone())
+                .map_err(StartDecoderError::CannotSetShutdownToken)?;
+        }
 
         if let Some(counter) = &self.state.poll_wakeups_counter {
             output_poller.set_poll_counter(Arc::clone(counter));
@@ -245,9 +463,11 @@ impl<OP: BufferHandles> Decoder<ReadyToDecode<OP>> {
                 output_queue: self.state.output_queue,
                 input_done_cb,
                 output_poller,
+                empty_output_handles: self.state.empty_output_handles,
                 command_waker,
                 command_sender,
                 response_receiver,
+                frame_errors,
                 handle,
             },
         })
@@ -257,12 +477,18 @@ impl<OP: BufferHandles> Decoder<ReadyToDecode<OP>> {
 #[derive(Debug)]
 enum DecoderCommand {
     Drain(bool),
+    /// Drain without sending the STOP command, because the client signals the
+    /// end of the stream with an empty OUTPUT buffer.
+    DrainWithEmptyBuffer(bool),
     Flush,
     Stop,
 }
 
 #[derive(Debug)]
 enum CaptureThreadResponse {
+    /// The capture thread is ready to process a drain signaled by an empty
+    /// OUTPUT buffer.
+    DrainStarted,
     DrainDone(Result<bool, DrainError>),
     FlushDone(anyhow::Result<()>),
 }
@@ -278,12 +504,15 @@ where
     output_queue: Queue<Output, BuffersAllocated<OP>>,
     input_done_cb: InputDoneCb,
     output_poller: Poller,
+    empty_output_handles: Option<EmptyHandlesCb<OP>>,
 
     command_waker: Arc<Waker>,
     command_sender: mpsc::Sender<DecoderCommand>,
     response_receiver: mpsc::Receiver<CaptureThreadResponse>,
+    /// Number of frames emitted as `DecoderEvent::FrameCorrupted`.
+    frame_errors: Arc<AtomicUsize>,
 
-    handle: JoinHandle<CaptureThread<P, DecoderEventCb, FormatChangedCb>>,
+    handle: JoinHandle<Option<CaptureThread<P, DecoderEventCb, FormatChangedCb>>>,
 }
 impl<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb> DecoderState
     for Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>
@@ -302,6 +531,14 @@ pub enum SendCommandError {
     SendError,
 }
 
+impl OsError for SendCommandError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
+ioctl::impl_into_io_error!(SendCommandError);
+
 #[derive(Debug, Error)]
 pub enum StopError {
     #[error("error while sending the stop command to the capture thread")]
@@ -312,18 +549,66 @@ pub enum StopError {
     Streamoff(#[from] ioctl::StreamOffError),
 }
 
+impl OsError for StopError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            StopError::SendCommand(_) | StopError::Join => None,
+            StopError::Streamoff(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(StopError);
+
 #[derive(Debug, Error)]
 pub enum DrainError {
     #[error("cannot drain now: output format not yet determined")]
     TryAgain,
+    #[error("blocking drain interrupted by a shutdown request")]
+    Interrupted,
     #[error("error while sending the flush command to the capture thread")]
     SendCommand(#[from] SendCommandError),
     #[error("error while waiting for the decoder thread to drain")]
     RecvError(#[from] mpsc::RecvError),
     #[error("error while draining on the capture thread")]
-    CaptureThreadError(anyhow::Error),
+    CaptureThreadError(#[source] anyhow::Error),
+    #[error("STOP command not supported and no empty OUTPUT handles callback set")]
+    NoEmptyOutputHandles,
+    #[error("error while dequeueing OUTPUT buffers")]
+    DequeueError(#[from] DqBufError<V4l2BufferFromError>),
+    #[error("error while obtaining an empty OUTPUT buffer")]
+    GetEmptyBufferError(#[from] GetFreeBufferError),
+    #[error("error while queueing an empty OUTPUT buffer")]
+    QueueEmptyBufferError(#[source] ioctl::QBufError<Infallible>),
+    #[error("error while sending the STOP command")]
+    StopCommandError(#[source] ioctl::DecoderCmdError<Infallible>),
+    #[error("error while stopping the CAPTURE queue")]
+    StreamOffError(#[from] ioctl::StreamOffError),
+    #[error("error while restarting the CAPTURE queue")]
+    StreamOnError(#[from] ioctl::StreamOnError),
+}
+
+impl OsError for DrainError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            DrainError::Interrupted => Some(Errno::EINTR),
+            DrainError::TryAgain
+            | DrainError::SendCommand(_)
+            | DrainError::RecvError(_)
+            | DrainError::CaptureThreadError(_)
+            | DrainError::NoEmptyOutputHandles => None,
+            DrainError::DequeueError(e) => e.errno(),
+            DrainError::GetEmptyBufferError(e) => e.errno(),
+            DrainError::QueueEmptyBufferError(e) => e.errno(),
+            DrainError::StopCommandError(e) => e.errno(),
+            DrainError::StreamOffError(e) => e.errno(),
+            DrainError::StreamOnError(e) => e.errno(),
+        }
+    }
 }
 
+ioctl::impl_into_io_error!(DrainError);
+
 #[derive(Debug, Error)]
 pub enum FlushError {
     #[error("error while stopping the OUTPUT queue")]
@@ -333,11 +618,25 @@ pub enum FlushError {
     #[error("error while waiting for the decoder thread to flush")]
     RecvError(#[from] mpsc::RecvError),
     #[error("error while flushing on the capture thread")]
-    CaptureThreadError(anyhow::Error),
+    CaptureThreadError(#[source] anyhow::Error),
     #[error("error while starting the OUTPUT queue")]
     StreamonError(#[from] ioctl::StreamOnError),
 }
 
+impl OsError for FlushError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            FlushError::StreamoffError(e) => e.errno(),
+            FlushError::SendCommand(_)
+            | FlushError::RecvError(_)
+            | FlushError::CaptureThreadError(_) => None,
+            FlushError::StreamonError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(FlushError);
+
 #[allow(type_alias_bounds)]
 type CanceledBuffers<OP: BufferHandles> =
     Vec<<Queue<Output, BuffersAllocated<OP>> as Stream>::Canceled>;
@@ -355,6 +654,12 @@ where
         self.state.output_queue.num_buffers()
     }
 
+    /// Returns the number of frames the driver reported an error for, which
+    /// have been emitted as `DecoderEvent::FrameCorrupted`.
+    pub fn num_frame_errors(&self) -> usize {
+        self.state.frame_errors.load(Ordering::Relaxed)
+    }
+
     /// Send a command to the capture thread.
     fn send_command(&self, command: DecoderCommand) -> Result<(), SendCommandError> {
         trace!("Sending command: {:?}", command);
@@ -417,18 +722,83 @@ where
     /// ongoing. They will be processed in order and their frames will come
     /// after the ones still in the pipeline. For a way to cancel all the
     /// pending jobs, see the [`Decoder::flush`] method.
-    pub fn drain(&self, blocking: bool) -> Result<bool, DrainError> {
+    ///
+    /// If the STOP command fails because the driver does not support
+    /// `VIDIOC_DECODER_CMD` (`ENOTTY`) or the command itself (`EINVAL`), the
+    /// end of the stream is signaled by queueing an empty OUTPUT buffer
+    /// instead, using the handles provided by the callback given to
+    /// [`Decoder::set_empty_output_handles`]. In this case a free OUTPUT buffer
+    /// must be available, or [`DrainError::GetEmptyBufferError`] is returned.
+    /// Other errors of the STOP command are returned as
+    /// [`DrainError::StopCommandError`].
+    pub fn drain(&self, blocking: bool) -> Result<bool, DrainError>
+    where
+        for<'a> Queue<Output, BuffersAllocated<OP>>: GetFreeOutputBuffer<'a, OP>,
+    {
         debug!("Drain requested");
+
         self.send_command(DecoderCommand::Drain(blocking))?;
+        // Drivers may accept `VIDIOC_TRY_DECODER_CMD` and still fail the actual
+        // command, so the fallback is decided from the result of the latter.
+        match self.wait_drain_done() {
+            Err(DrainError::StopCommandError(ioctl::DecoderCmdError::IoctlError(
+                ioctl::DecoderCmdIoctlError::Other(Errno::ENOTTY)
+                | ioctl::DecoderCmdIoctlError::UnsupportedCommand,
+            ))) => {
+                debug!("STOP command not supported, using empty OUTPUT buffer");
+                self.drain_with_empty_buffer(blocking)
+            }
+            res => res,
+        }
+    }
 
+    /// Drains the decoder by queueing an empty OUTPUT buffer, for drivers that
+    /// do not support the STOP command.
+    fn drain_with_empty_buffer(&self, blocking: bool) -> Result<bool, DrainError>
+    where
+        for<'a> Queue<Output, BuffersAllocated<OP>>: GetFreeOutputBuffer<'a, OP>,
+    {
+        let handles = match &self.state.empty_output_handles {
+            Some(f) => f(),
+            None => return Err(DrainError::NoEmptyOutputHandles),
+        };
+        // Obtain the buffer first so we do not start a drain we cannot signal.
+        self.dequeue_output_buffers()?;
+        let buffer = self.state.output_queue.try_get_free_buffer()?;
+
+        self.send_command(DecoderCommand::DrainWithEmptyBuffer(blocking))?;
         match self.state.response_receiver.recv()? {
-            CaptureThreadResponse::DrainDone(response) => match response {
-                Ok(completed) => Ok(completed),
-                Err(e) => {
-                    error!("Error while draining on the capture thread: {}", e);
-                    Err(e)
-                }
-            },
+            CaptureThreadResponse::DrainStarted => (),
+            CaptureThreadResponse::DrainDone(Err(e)) => return Err(e),
+            r => {
+                error!(
+                    "Unexpected capture thread response received while draining: {:?}",
+                    r
+                );
+                return Err(DrainError::CaptureThreadError(anyhow::anyhow!(
+                    "Unexpected response while draining"
+                )));
+            }
+        }
+
+        let bytes_used = vec![0; handles.len()];
+        buffer
+            .queue_with_handles(handles, &bytes_used)
+            .map_err(|e| DrainError::QueueEmptyBufferError(e.error))?;
+
+        if blocking {
+            self.wait_drain_done()
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Waits for the capture thread to signal the result of a drain.
+    fn wait_drain_done(&self) -> Result<bool, DrainError> {
+        match self.state.response_receiver.recv()? {
+            // Errors are left for the caller to report, as some of them
+            // trigger a fallback.
+            CaptureThreadResponse::DrainDone(response) => response,
             r => {
                 error!(
                     "Unexpected capture thread response received while draining: {:?}",
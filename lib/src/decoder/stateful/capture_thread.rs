@@ -1,28 +1,36 @@
 use crate::{
     decoder::{
+        reorder::ReorderBuffer,
         stateful::{CaptureThreadResponse, DecoderCommand, DecoderEvent, DrainError},
         DecoderEventCallback, FormatChangedCallback, FormatChangedReply,
     },
     device::{
-        poller::{DeviceEvent, PollEvent, Poller, Waker},
+        poller::{DeviceEvent, PollError, PollEvent, Poller, Waker},
         queue::{
             self,
             direction::Capture,
+            dqbuf::DqBuffer,
             handles_provider::HandlesProvider,
             qbuf::{
                 get_free::GetFreeCaptureBuffer, get_indexed::GetCaptureBufferByIndex,
                 CaptureQueueable,
             },
+            watermark::QueueWatermark,
             BuffersAllocated, Queue, QueueInit,
         },
         AllocatedQueue, Device, Stream, TryDequeue,
     },
     ioctl::{self, SelectionTarget},
+    memory::BufferHandles,
+    Rect,
 };
 
 use std::{
     io,
-    sync::{mpsc, Arc},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
     task::Wake,
 };
 
@@ -57,6 +65,9 @@ fn is_drc_event_pending(device: &Device) -> Result<bool, ioctl::DqEventError> {
             ioctl::Event::Eos => {
                 debug!("Received EOS event");
             }
+            event => {
+                debug!("Ignoring unexpected event {:?}", event);
+            }
         }
     }
 }
@@ -69,11 +80,107 @@ enum CaptureQueue<P: HandlesProvider> {
         capture_queue: Queue<Capture, BuffersAllocated<P::HandleType>>,
         provider: P,
         cap_buffer_waker: Arc<Waker>,
+        // Visible rectangle of the current format, attached to decoded frames.
+        visible_rect: Rect,
         // TODO not super elegant...
         blocking_drain_in_progress: bool,
+        // Set while draining with an empty OUTPUT buffer. Drivers that need it
+        // may return an empty CAPTURE buffer without the LAST flag to signal
+        // the end of the stream.
+        empty_buffer_drain_in_progress: bool,
     },
 }
 
+/// A decoded frame along with its visible rectangle.
+type DecodedFrame<P> = (DqBuffer<Capture, <P as HandlesProvider>::HandleType>, Rect);
+
+/// Passes the decoded frames to the client, in presentation order if
+/// requested.
+struct FrameSink<P, DecoderEventCb>
+where
+    P: HandlesProvider,
+    DecoderEventCb: DecoderEventCallback<P>,
+{
+    event_cb: DecoderEventCb,
+    // Holds decoded frames to emit them in presentation order, if requested.
+    reorder: Option<ReorderBuffer<DecodedFrame<P>>>,
+    // Number of frames the driver reported an error for.
+    frame_errors: Arc<AtomicUsize>,
+}
+
+impl<P, DecoderEventCb> FrameSink<P, DecoderEventCb>
+where
+    P: HandlesProvider,
+    DecoderEventCb: DecoderEventCallback<P>,
+{
+    fn new(event_cb: DecoderEventCb) -> Self {
+        FrameSink {
+            event_cb,
+            reorder: None,
+            frame_errors: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Passes a dequeued frame to the client. The LAST buffer releases the
+    /// frames held for reordering, and is emitted after them if it carries no
+    /// frame.
+    fn push(&mut self, frame: DecodedFrame<P>, is_last: bool) {
+        let reorder = match &mut self.reorder {
+            None => return self.emit(frame),
+            Some(reorder) => reorder,
+        };
+
+        if is_last && *frame.0.data.get_first_plane().bytesused == 0 {
+            self.release_held_frames();
+            return self.emit(frame);
+        }
+
+        let timestamp = frame.0.timestamp();
+        if let Some(frame) = reorder.push(&timestamp, frame) {
+            self.emit(frame);
+        }
+        if is_last {
+            self.release_held_frames();
+        }
+    }
+
+    /// Emits the frames held for reordering, by increasing timestamp.
+    fn release_held_frames(&mut self) {
+        let held: Vec<_> = match &mut self.reorder {
+            Some(reorder) => reorder.drain().collect(),
+            None => return,
+        };
+        for frame in held {
+            self.emit(frame);
+        }
+    }
+
+    /// Drops the frames held for reordering.
+    fn clear(&mut self) {
+        if let Some(reorder) = &mut self.reorder {
+            reorder.clear();
+        }
+    }
+
+    /// Passes `frame` to the client, as `FrameCorrupted` if the driver
+    /// reported an error while decoding it.
+    fn emit(&mut self, (buffer, rect): DecodedFrame<P>) {
+        let event = match buffer.into_result() {
+            Ok(buffer) => DecoderEvent::FrameDecoded(buffer, rect),
+            Err(error) => {
+                warn!("CAPTURE buffer {} has the ERROR flag", error.buffer.index());
+                self.frame_errors.fetch_add(1, Ordering::Relaxed);
+                DecoderEvent::FrameCorrupted(error, rect)
+            }
+        };
+        (self.event_cb)(event);
+    }
+
+    fn end_of_stream(&mut self) {
+        (self.event_cb)(DecoderEvent::EndOfStream);
+    }
+}
+
 pub(super) struct CaptureThread<P, DecoderEventCb, FormatChangedCb>
 where
     P: HandlesProvider,
@@ -84,8 +191,10 @@ where
     capture_queue: CaptureQueue<P>,
     pub(super) poller: Poller,
 
-    event_cb: DecoderEventCb,
+    frames: FrameSink<P, DecoderEventCb>,
     set_capture_format_cb: FormatChangedCb,
+    // Notified of the number of queued CAPTURE buffers crossing its watermark.
+    capture_watermark: Option<QueueWatermark>,
 
     // Waker signaled when the main thread has commands pending for us.
     pub(super) command_waker: Arc<Waker>,
@@ -146,6 +255,7 @@ where
         capture_queue: Queue<Capture, QueueInit>,
         event_cb: DecoderEventCb,
         set_capture_format_cb: FormatChangedCb,
+        capture_watermark: Option<QueueWatermark>,
         command_receiver: mpsc::Receiver<DecoderCommand>,
         response_sender: mpsc::Sender<CaptureThreadResponse>,
     ) -> io::Result<Self> {
@@ -160,8 +270,9 @@ where
             device: Arc::clone(device),
             capture_queue: CaptureQueue::AwaitingResolution { capture_queue },
             poller,
-            event_cb,
+            frames: FrameSink::new(event_cb),
             set_capture_format_cb,
+            capture_watermark,
             command_waker,
             command_receiver,
             response_sender,
@@ -170,14 +281,28 @@ where
         Ok(decoder_thread)
     }
 
+    /// Emit decoded frames in presentation order, holding up to `depth` of
+    /// them.
+    pub(super) fn set_reorder_depth(&mut self, depth: usize) {
+        self.frames.reorder = Some(ReorderBuffer::new(depth));
+    }
+
+    /// Returns the counter of the frames the driver reported an error for.
+    pub(super) fn frame_errors(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.frames.frame_errors)
+    }
+
     fn send_response(&self, response: CaptureThreadResponse) {
         trace!("Sending response: {:?}", response);
 
         self.response_sender.send(response).unwrap();
     }
 
-    fn drain(&mut self, blocking: bool) {
-        trace!("Processing Drain({}) command", blocking);
+    /// Starts a drain sequence. If `send_stop_cmd` is false, the client is
+    /// responsible for signaling the end of the stream to the decoder and
+    /// only needs to know when to do so.
+    fn drain(&mut self, blocking: bool, send_stop_cmd: bool) {
+        trace!("Processing Drain({}, {}) command", blocking, send_stop_cmd);
         let response = match &mut self.capture_queue {
             // We cannot initiate the flush sequence before receiving the initial
             // resolution.
@@ -186,19 +311,34 @@ where
             }
             CaptureQueue::Decoding {
                 blocking_drain_in_progress,
+                empty_buffer_drain_in_progress,
                 ..
             } => {
+                if !send_stop_cmd {
+                    *empty_buffer_drain_in_progress = true;
+                }
                 // We can receive the LAST buffer, send the STOP command
                 // and exit the loop once the buffer with the LAST tag is received.
-                ioctl::decoder_cmd::<_, ()>(&*self.device, ioctl::DecoderCmd::stop()).unwrap();
-                if blocking {
-                    // If we are blocking, we will send the answer when the drain
-                    // is completed.
-                    *blocking_drain_in_progress = true;
-                    None
+                let stop_res = if send_stop_cmd {
+                    ioctl::decoder_cmd::<_, ()>(&*self.device, ioctl::DecoderCmd::stop())
                 } else {
+                    Ok(())
+                };
+                match stop_res {
+                    // The client decides whether to fall back to an empty
+                    // OUTPUT buffer.
+                    Err(e) => Some(CaptureThreadResponse::DrainDone(Err(
+                        DrainError::StopCommandError(e),
+                    ))),
+                    Ok(()) if blocking => {
+                        // If we are blocking, we will send the answer when the drain
+                        // is completed.
+                        *blocking_drain_in_progress = true;
+                        (!send_stop_cmd).then_some(CaptureThreadResponse::DrainStarted)
+                    }
+                    Ok(()) if !send_stop_cmd => Some(CaptureThreadResponse::DrainStarted),
                     // If not blocking, send the response now so the client can keep going.
-                    Some(CaptureThreadResponse::DrainDone(Ok(false)))
+                    Ok(()) => Some(CaptureThreadResponse::DrainDone(Ok(false))),
                 }
             }
         };
@@ -208,25 +348,51 @@ where
         }
     }
 
+    /// Aborts the blocking drain in progress, if any, once shutdown has been
+    /// requested. We then keep running without the shutdown token so frames
+    /// keep being emitted until the client stops the decoder.
+    fn handle_shutdown(&mut self) {
+        debug!("Shutdown requested");
+        if let Err(e) = self.poller.remove_shutdown_token() {
+            warn!("Failed to remove shutdown token: {}", e);
+        }
+
+        if let CaptureQueue::Decoding {
+            blocking_drain_in_progress,
+            ..
+        } = &mut self.capture_queue
+        {
+            if std::mem::take(blocking_drain_in_progress) {
+                debug!("Aborting blocking drain");
+                self.send_response(CaptureThreadResponse::DrainDone(Err(
+                    DrainError::Interrupted,
+                )));
+            }
+        }
+    }
+
     fn flush(&mut self) {
         trace!("Processing flush command");
-        match &mut self.capture_queue {
-            CaptureQueue::AwaitingResolution { .. } => {}
+        let res = match &mut self.capture_queue {
+            CaptureQueue::AwaitingResolution { .. } => Ok(()),
             CaptureQueue::Decoding {
                 capture_queue,
                 blocking_drain_in_progress,
+                empty_buffer_drain_in_progress,
                 ..
             } => {
+                // Frames held for reordering belong to the flushed stream.
+                self.frames.clear();
                 // Stream the capture queue off and back on, dropping any queued
                 // buffer, and making the decoder ready to work again if it was
                 // halted.
-                capture_queue.stream_off().unwrap();
-                capture_queue.stream_on().unwrap();
                 *blocking_drain_in_progress = false;
+                *empty_buffer_drain_in_progress = false;
+                restart_queue(capture_queue).map_err(anyhow::Error::from)
             }
-        }
+        };
 
-        self.send_response(CaptureThreadResponse::FlushDone(Ok(())));
+        self.send_response(CaptureThreadResponse::FlushDone(res));
         self.enqueue_capture_buffers()
     }
 
@@ -272,18 +438,18 @@ where
         }
     }
 
-    fn process_v4l2_event(mut self) -> Self {
+    fn process_v4l2_event(mut self) -> Result<Self, ProcessEventsError> {
         trace!("Processing V4L2 event");
         match self.capture_queue {
             CaptureQueue::AwaitingResolution { .. } => {
-                if is_drc_event_pending(&self.device).unwrap() {
-                    self = self.update_capture_format().unwrap()
+                if is_drc_event_pending(&self.device)? {
+                    self = self.update_capture_format()?
                 }
             }
             CaptureQueue::Decoding { .. } => unreachable!(),
         }
 
-        self
+        Ok(self)
     }
 
     fn update_capture_format(mut self) -> Result<Self, UpdateCaptureError> {
@@ -323,8 +489,14 @@ where
         // Now get the parameters of the new format and build our new CAPTURE
         // queue.
 
-        // TODO use the proper control to get the right value.
-        let min_num_buffers = 4usize;
+        // Use a reasonable default if the driver does not tell us.
+        let min_num_buffers = capture_queue
+            .get_min_buffers()
+            .unwrap_or_else(|e| {
+                warn!("Cannot get minimum number of CAPTURE buffers: {}", e);
+                None
+            })
+            .unwrap_or(4);
         debug!("Stream requires {} capture buffers", min_num_buffers);
 
         let visible_rect = capture_queue.get_selection(SelectionTarget::Compose)?;
@@ -365,7 +537,9 @@ where
                 capture_queue,
                 provider,
                 cap_buffer_waker,
+                visible_rect,
                 blocking_drain_in_progress: false,
+                empty_buffer_drain_in_progress: false,
             },
             ..self
         })
@@ -375,25 +549,42 @@ where
     ///
     /// If a buffer can be dequeued, then the following processing takes place:
     /// * Invoke the event callback with a `FrameDecoded` event containing the
-    ///   dequeued buffer,
-    /// * If the buffer has the LAST flag set:
+    ///   dequeued buffer and the current visible rectangle,
+    /// * If the buffer has the LAST flag set, or is empty while draining with an
+    ///   empty OUTPUT buffer:
     ///   * If a resolution change event is pending, start the resolution change
     ///     procedure,
     ///   * If a resolution change event is not pending, invoke the event
     ///     callback with an 'EndOfStream` event,
     ///   * If a blocking drain was in progress, complete it.
-    fn dequeue_capture_buffer(mut self) -> Self {
+    ///
+    /// An error is returned if the resolution change could not be detected or
+    /// performed, after which the thread cannot keep decoding.
+    fn dequeue_capture_buffer(mut self) -> Result<Self, ProcessEventsError> {
         trace!("Dequeueing decoded CAPTURE buffers");
-        let (capture_queue, cap_buffer_waker, blocking_drain_in_progress) =
-            match &mut self.capture_queue {
-                CaptureQueue::AwaitingResolution { .. } => unreachable!(),
-                CaptureQueue::Decoding {
-                    capture_queue,
-                    cap_buffer_waker,
-                    blocking_drain_in_progress,
-                    ..
-                } => (capture_queue, cap_buffer_waker, blocking_drain_in_progress),
-            };
+        let (
+            capture_queue,
+            cap_buffer_waker,
+            visible_rect,
+            blocking_drain_in_progress,
+            empty_buffer_drain_in_progress,
+        ) = match &mut self.capture_queue {
+            CaptureQueue::AwaitingResolution { .. } => unreachable!(),
+            CaptureQueue::Decoding {
+                capture_queue,
+                cap_buffer_waker,
+                visible_rect,
+                blocking_drain_in_progress,
+                empty_buffer_drain_in_progress,
+                ..
+            } => (
+                capture_queue,
+                cap_buffer_waker,
+                *visible_rect,
+                blocking_drain_in_progress,
+                empty_buffer_drain_in_progress,
+            ),
+        };
 
         let mut cap_buf = match capture_queue.try_dequeue() {
             Ok(cap_buf) => cap_buf,
@@ -402,11 +593,17 @@ where
                     "Expected a CAPTURE buffer but none available, possible driver bug: {}",
                     e
                 );
-                return self;
+                return Ok(self);
             }
         };
 
-        let is_last = cap_buf.data.is_last();
+        // When draining with an empty OUTPUT buffer, an empty CAPTURE buffer
+        // also ends the stream, with or without the LAST flag.
+        let is_last = cap_buf.data.is_last()
+            || (*empty_buffer_drain_in_progress && *cap_buf.data.get_first_plane().bytesused == 0);
+        if is_last {
+            *empty_buffer_drain_in_progress = false;
+        }
 
         // Add a drop callback to the dequeued buffer so we
         // re-queue it as soon as it is dropped.
@@ -416,14 +613,13 @@ where
             cap_waker.wake();
         });
 
-        // Pass buffers to the client
-        (self.event_cb)(DecoderEvent::FrameDecoded(cap_buf));
+        self.frames.push((cap_buf, visible_rect), is_last);
 
         if is_last {
-            debug!("CAPTURE buffer marked with LAST flag");
-            if is_drc_event_pending(&self.device).unwrap() {
+            debug!("CAPTURE buffer marked with LAST flag or ending an empty buffer drain");
+            if is_drc_event_pending(&self.device)? {
                 debug!("DRC event pending, updating CAPTURE format");
-                self = self.update_capture_format().unwrap()
+                self = self.update_capture_format()?
             }
             // No DRC event pending, this is the end of the stream.
             // We need to stop and restart the CAPTURE queue, otherwise
@@ -435,24 +631,33 @@ where
                 // instead, but with vicodec the CAPTURE queue reports
                 // as ready in subsequent polls() and DQBUF returns
                 // -EPIPE...
-                capture_queue.stream_off().unwrap();
-                capture_queue.stream_on().unwrap();
-                (self.event_cb)(DecoderEvent::EndOfStream);
+                let res = restart_queue(capture_queue);
+                if let Err(e) = &res {
+                    error!("Failed to restart the CAPTURE queue: {}", e);
+                }
+                self.frames.end_of_stream();
                 if *blocking_drain_in_progress {
                     debug!("Signaling end of blocking drain");
                     *blocking_drain_in_progress = false;
-                    self.send_response(CaptureThreadResponse::DrainDone(Ok(true)));
+                    self.send_response(CaptureThreadResponse::DrainDone(res.map(|()| true)));
                 }
             }
         }
 
-        self
+        Ok(self)
     }
 
-    pub(super) fn run(mut self) -> Self {
+    /// Runs the thread until the decoder is stopped, and returns it in the
+    /// awaiting resolution state. Returns `None` if a fatal error occurred.
+    pub(super) fn run(mut self) -> Option<Self> {
         'mainloop: loop {
             if let CaptureQueue::Decoding { capture_queue, .. } = &self.capture_queue {
-                match capture_queue.num_queued_buffers() {
+                let num_queued = capture_queue.num_queued_buffers();
+                if let Some(watermark) = &mut self.capture_watermark {
+                    watermark.update(num_queued);
+                }
+
+                match num_queued {
                     // If there are no buffers on the CAPTURE queue, poll() will return
                     // immediately with EPOLLERR and we would loop indefinitely.
                     // Prevent this by temporarily disabling polling the CAPTURE queue
@@ -474,18 +679,22 @@ where
             trace!("Polling...");
             let events = match self.poller.poll(None) {
                 Ok(events) => events,
+                Err(PollError::Interrupted) => {
+                    self.handle_shutdown();
+                    continue 'mainloop;
+                }
                 Err(e) => {
                     error!("Polling failure, exiting capture thread: {}", e);
                     break 'mainloop;
                 }
             };
             for event in events {
-                self = match event {
+                let res = match event {
                     PollEvent::Device(DeviceEvent::V4L2Event) => self.process_v4l2_event(),
                     PollEvent::Device(DeviceEvent::CaptureReady) => self.dequeue_capture_buffer(),
                     PollEvent::Waker(CAPTURE_READY) => {
                         self.enqueue_capture_buffers();
-                        self
+                        Ok(self)
                     }
                     PollEvent::Waker(COMMAND_WAITING) => {
                         loop {
@@ -499,7 +708,10 @@ where
                                     }
                                 };
                             match command {
-                                DecoderCommand::Drain(blocking) => self.drain(blocking),
+                                DecoderCommand::Drain(blocking) => self.drain(blocking, true),
+                                DecoderCommand::DrainWithEmptyBuffer(blocking) => {
+                                    self.drain(blocking, false)
+                                }
                                 DecoderCommand::Flush => self.flush(),
                                 DecoderCommand::Stop => {
                                     trace!("Processing stop command");
@@ -507,15 +719,28 @@ where
                                 }
                             }
                         }
-                        self
+                        Ok(self)
                     }
                     _ => panic!("Unexpected event!"),
-                }
+                };
+                self = match res {
+                    Ok(thread) => thread,
+                    // The client notices the thread is gone when its next
+                    // command gets no response.
+                    Err(e) => {
+                        error!("Fatal error, exiting capture thread: {}", e);
+                        return None;
+                    }
+                };
             }
         }
 
+        // Frames still held for reordering have been decoded before the
+        // decoder was stopped, so the client receives them.
+        self.frames.release_held_frames();
+
         // Return the decoder to the awaiting resolution state.
-        match self.capture_queue {
+        Some(match self.capture_queue {
             CaptureQueue::AwaitingResolution { .. } => self,
             CaptureQueue::Decoding { capture_queue, .. } => Self {
                 capture_queue: CaptureQueue::AwaitingResolution {
@@ -533,6 +758,134 @@ where
                 },
                 ..self
             },
+        })
+    }
+}
+
+/// Streams `queue` off and back on, dropping its queued buffers and making the
+/// decoder ready to work again if it was halted.
+fn restart_queue<P: BufferHandles>(
+    queue: &Queue<Capture, BuffersAllocated<P>>,
+) -> Result<(), DrainError> {
+    queue.stream_off()?;
+    queue.stream_on()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        bindings,
+        device::queue::handles_provider::MmapProvider,
+        ioctl::{BufferFlags, V4l2Buffer},
+        memory::MemoryType,
+        timestamp::timestamp_key,
+        QueueType,
+    };
+
+    /// Events received by the client, as the timestamp of each frame and
+    /// whether it is corrupted.
+    #[derive(Debug, PartialEq, Eq)]
+    enum Received {
+        Frame(i64),
+        Corrupted(i64),
+        EndOfStream,
+    }
+
+    fn frame_sink() -> (
+        FrameSink<MmapProvider, impl DecoderEventCallback<MmapProvider>>,
+        Arc<Mutex<Vec<Received>>>,
+    ) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let events = Arc::clone(&received);
+        let sink = FrameSink::new(move |event: DecoderEvent<MmapProvider>| {
+            let received = match event {
+                DecoderEvent::FrameDecoded(buffer, _) => {
+                    Received::Frame(timestamp_key(&buffer.timestamp()))
+                }
+                DecoderEvent::FrameCorrupted(error, _) => {
+                    Received::Corrupted(timestamp_key(&error.buffer.timestamp()))
+                }
+                DecoderEvent::EndOfStream => Received::EndOfStream,
+            };
+            events.lock().unwrap().push(received);
+        });
+
+        (sink, received)
+    }
+
+    fn decoded_frame(timestamp: i64, bytes_used: u32, error: bool) -> DecodedFrame<MmapProvider> {
+        let mut data = V4l2Buffer::new(QueueType::VideoCaptureMplane, 0, MemoryType::Mmap);
+        data.set_timestamp(bindings::timeval {
+            tv_sec: 0,
+            tv_usec: timestamp as _,
+        });
+        *data.get_first_plane_mut().bytesused = bytes_used;
+        if error {
+            data.add_flags(BufferFlags::ERROR);
         }
+
+        (
+            DqBuffer::new_detached(data, Vec::new()),
+            Rect::new(0, 0, 16, 16),
+        )
+    }
+
+    #[test]
+    fn frame_sink_reorder() {
+        let (mut sink, received) = frame_sink();
+        sink.reorder = Some(ReorderBuffer::new(2));
+
+        for timestamp in [2, 1, 4, 3] {
+            sink.push(decoded_frame(timestamp, 16, false), false);
+        }
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![Received::Frame(1), Received::Frame(2)]
+        );
+
+        // An empty LAST buffer releases the held frames before itself.
+        sink.push(decoded_frame(5, 0, false), true);
+        assert_eq!(
+            received.lock().unwrap()[2..],
+            [Received::Frame(3), Received::Frame(4), Received::Frame(5)]
+        );
+
+        // Frames still held when stopping are released, and dropped on flush.
+        sink.push(decoded_frame(7, 16, false), false);
+        sink.push(decoded_frame(6, 16, false), false);
+        sink.release_held_frames();
+        sink.push(decoded_frame(8, 16, false), false);
+        sink.clear();
+        sink.release_held_frames();
+        assert_eq!(
+            received.lock().unwrap()[5..],
+            [Received::Frame(6), Received::Frame(7)]
+        );
+    }
+
+    #[test]
+    fn frame_sink_errors() {
+        let (mut sink, received) = frame_sink();
+
+        sink.push(decoded_frame(1, 16, false), false);
+        sink.push(decoded_frame(2, 16, true), false);
+        sink.push(decoded_frame(3, 0, false), true);
+        sink.end_of_stream();
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![
+                Received::Frame(1),
+                Received::Corrupted(2),
+                Received::Frame(3),
+                Received::EndOfStream
+            ]
+        );
+        assert_eq!(sink.frame_errors.load(Ordering::Relaxed), 1);
     }
 }
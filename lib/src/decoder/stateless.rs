@@ -0,0 +1,193 @@
+//! Helpers for the [stateless decoder
+//! interface](https://www.kernel.org/doc/html/latest/userspace-api/media/v4l/dev-stateless-decoder.html).
+//!
+//! Stateless decoders are driven with one media request per unit of
+//! submission. A unit is normally a whole frame, but H.264 decoders can also
+//! work on individual slices, in which case the CAPTURE buffer of a frame must
+//! be held by the driver until its last slice has been submitted.
+use std::os::unix::io::AsRawFd;
+
+use nix::errno::Errno;
+use thiserror::Error;
+
+use crate::{
+    controls::{
+        codec::{H264DecodeMode, StatelessH264DecodeMode},
+        SafeExtControl,
+    },
+    device::queue::{
+        direction::Output,
+        qbuf::{OutputQueueable, QBuffer, QueueError},
+    },
+    ioctl::{self, BufferCapabilities, CtrlWhich, OsError, Request, RequestError},
+    memory::{BufferHandles, PrimitiveBufferHandles},
+};
+
+/// How the encoded stream is submitted to a stateless decoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmissionMode {
+    /// One request per frame.
+    FrameBased,
+    /// One request per slice. The CAPTURE buffer is held until the last slice
+    /// of the frame has been decoded.
+    SliceBased,
+}
+
+#[derive(Debug, Error)]
+pub enum SubmissionModeError {
+    #[error("OUTPUT queue does not support requests")]
+    RequestsUnsupported,
+    #[error("OUTPUT queue does not support holding CAPTURE buffers")]
+    HoldCaptureBufUnsupported,
+    #[error("error while setting the decode mode")]
+    ExtControlError(#[from] ioctl::ExtControlError),
+}
+
+impl OsError for SubmissionModeError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            SubmissionModeError::RequestsUnsupported
+            | SubmissionModeError::HoldCaptureBufUnsupported => None,
+            SubmissionModeError::ExtControlError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(SubmissionModeError);
+
+impl SubmissionMode {
+    /// Configures the H.264 stateless decoder behind `fd` for this submission
+    /// mode.
+    ///
+    /// `output_caps` are the capabilities of the OUTPUT queue, as returned by
+    /// `Queue::get_capabilities()`. They must include `SUPPORTS_REQUESTS`, and
+    /// slice-based submission also requires the `SUPPORTS_M2M_HOLD_CAPTURE_BUF`
+    /// capability.
+    pub fn configure_h264(
+        self,
+        fd: &impl AsRawFd,
+        output_caps: BufferCapabilities,
+    ) -> Result<(), SubmissionModeError> {
+        if !output_caps.contains(BufferCapabilities::SUPPORTS_REQUESTS) {
+            return Err(SubmissionModeError::RequestsUnsupported);
+        }
+
+        let mode = match self {
+            SubmissionMode::FrameBased => H264DecodeMode::FrameBased,
+            SubmissionMode::SliceBased => {
+                if !output_caps.contains(BufferCapabilities::SUPPORTS_M2M_HOLD_CAPTURE_BUF) {
+                    return Err(SubmissionModeError::HoldCaptureBufUnsupported);
+                }
+                H264DecodeMode::SliceBased
+            }
+        };
+
+        let mut control = SafeExtControl::<StatelessH264DecodeMode>::from_value(mode as i32);
+        ioctl::s_ext_ctrls(fd, CtrlWhich::Current, &mut control)?;
+
+        Ok(())
+    }
+
+    /// Queues `buffer` with `handles` as part of `request`, and then queues
+    /// `request`.
+    ///
+    /// The controls of the unit (e.g. the slice parameters) must have been set
+    /// on `request` beforehand. `last_of_frame` tells whether this is the last
+    /// unit of the current frame: in slice-based mode, the CAPTURE buffer is
+    /// held for all the other slices.
+    ///
+    /// The last slice of a frame is queued without
+    /// `V4L2_BUF_FLAG_M2M_HOLD_CAPTURE_BUF`, so the CAPTURE buffer is returned
+    /// as soon as it has been decoded, including for the last frame of the
+    /// stream.
+    pub fn queue_unit<P, Q>(
+        self,
+        buffer: QBuffer<'_, Output, P, Q>,
+        handles: Q,
+        bytes_used: &[usize],
+        request: &Request,
+        last_of_frame: bool,
+    ) -> Result<(), QueueUnitError<Q>>
+    where
+        P: PrimitiveBufferHandles,
+        Q: BufferHandles + From<P>,
+    {
+        buffer
+            .set_request(request)
+            .set_hold_capture_buffer(self.holds_capture_buffer(last_of_frame))
+            .queue_with_handles(handles, bytes_used)?;
+        request.queue()?;
+
+        Ok(())
+    }
+
+    /// Whether the CAPTURE buffer must be held after decoding a unit.
+    fn holds_capture_buffer(self, last_of_frame: bool) -> bool {
+        self == SubmissionMode::SliceBased && !last_of_frame
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum QueueUnitError<Q: BufferHandles> {
+    #[error("error while queueing OUTPUT buffer")]
+    QueueError(#[from] QueueError<Q>),
+    #[error("error while queueing request")]
+    RequestError(#[from] RequestError),
+}
+
+impl<Q: BufferHandles> OsError for QueueUnitError<Q> {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            QueueUnitError::QueueError(e) => e.errno(),
+            QueueUnitError::RequestError(e) => e.errno(),
+        }
+    }
+}
+
+impl<Q: BufferHandles> From<QueueUnitError<Q>> for std::io::Error {
+    fn from(err: QueueUnitError<Q>) -> Self {
+        match err {
+            QueueUnitError::QueueError(e) => e.into(),
+            QueueUnitError::RequestError(e) => e.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    #[test]
+    fn holds_capture_buffer() {
+        for (mode, last_of_frame, hold) in [
+            (SubmissionMode::FrameBased, false, false),
+            (SubmissionMode::FrameBased, true, false),
+            (SubmissionMode::SliceBased, false, true),
+            (SubmissionMode::SliceBased, true, false),
+        ] {
+            assert_eq!(
+                mode.holds_capture_buffer(last_of_frame),
+                hold,
+                "{:?}, last_of_frame: {}",
+                mode,
+                last_of_frame
+            );
+        }
+    }
+
+    #[test]
+    fn configure_h264_requires_capabilities() {
+        let fd = File::open("/dev/null").unwrap();
+
+        assert!(matches!(
+            SubmissionMode::FrameBased.configure_h264(&fd, BufferCapabilities::empty()),
+            Err(SubmissionModeError::RequestsUnsupported)
+        ));
+        assert!(matches!(
+            SubmissionMode::SliceBased.configure_h264(&fd, BufferCapabilities::SUPPORTS_REQUESTS),
+            Err(SubmissionModeError::HoldCaptureBufUnsupported)
+        ));
+    }
+}
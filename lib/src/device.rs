@@ -8,6 +8,10 @@
 //! A `Queue` object can be assigned a format and allocated buffers, from which
 //! point it can be streamed on and off, and buffers queued to it.
 //!
+//! Queues are independent objects that can be moved to different threads. For
+//! memory-to-memory devices, `queue::M2mQueues` obtains both the OUTPUT and
+//! CAPTURE queues at once so each of them can be driven by its own thread.
+//!
 //! The emphasis of this interface is to limit the actions and data that are
 //! accessible at a given point in time to those that make sense. For instance,
 //! the compiler wil reject any code that tries to stream a queue on before it
@@ -17,17 +21,26 @@
 //! Using this interface, the user does not have to worry about which fields of
 //! a V4L2 structure make sense - if it is relevant, then it will be visible,
 //! and if it is required, then the code won't compile unless it is provided.
+use super::controls::descriptor::{ControlSnapshot, ControlSnapshotError};
 use super::ioctl;
 use super::ioctl::Capability;
+use super::ioctl::OsError;
 use super::QueueType;
-use std::collections::BTreeSet;
+use nix::errno::Errno;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
 use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
-use std::{path::Path, sync::Mutex};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
 use thiserror::Error;
 
+pub mod frame_rate;
 pub mod poller;
 pub mod queue;
+pub mod radio;
+pub mod rds;
 mod traits;
 
 pub use traits::*;
@@ -50,11 +63,37 @@ impl DeviceConfig {
     }
 }
 
+/// Snapshot of the information reported by a device, as returned by
+/// `Device::info`.
+#[derive(Debug)]
+pub struct DeviceInfo {
+    /// Result of QUERYCAP.
+    pub caps: Capability,
+    /// Formats enumerated on each of the queues of the device.
+    pub formats: BTreeMap<QueueType, Vec<ioctl::FmtDesc>>,
+}
+
+impl DeviceInfo {
+    fn query(fd: &impl AsRawFd) -> Result<Self, ioctl::QueryCapError> {
+        let caps: Capability = ioctl::querycap(fd)?;
+        let formats = caps
+            .device_caps()
+            .queue_types()
+            .into_iter()
+            .map(|queue| (queue, ioctl::FormatIterator::new(fd, queue).collect()))
+            .collect();
+
+        Ok(DeviceInfo { caps, formats })
+    }
+}
+
 /// An opened V4L2 device. `Queue` objects can be instantiated from it.
 pub struct Device {
     capability: Capability,
     fd: File,
     used_queues: Mutex<BTreeSet<QueueType>>,
+    /// Cached result of `info`, `None` if it needs to be queried again.
+    info: Mutex<Option<Arc<DeviceInfo>>>,
 }
 
 #[derive(Debug, Error)]
@@ -65,12 +104,24 @@ pub enum DeviceOpenError {
     QueryCapError(#[from] ioctl::QueryCapError),
 }
 
+impl OsError for DeviceOpenError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            DeviceOpenError::OpenError(e) => Some(*e),
+            DeviceOpenError::QueryCapError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(DeviceOpenError);
+
 impl Device {
     fn new(fd: File) -> Result<Self, ioctl::QueryCapError> {
         Ok(Device {
             capability: ioctl::querycap(&fd)?,
             fd,
             used_queues: Mutex::new(BTreeSet::new()),
+            info: Mutex::new(None),
         })
     }
 
@@ -92,10 +143,64 @@ impl Device {
         Ok(Device::new(unsafe { File::from_raw_fd(fd) })?)
     }
 
-    /// Returns the capabilities of the device, i.e. the result of QUERYCAPS.
+    /// Returns the capabilities of the device, i.e. the result of QUERYCAPS
+    /// when the device was opened.
     pub fn caps(&self) -> &Capability {
         &self.capability
     }
+
+    /// Returns the capabilities and formats of the device.
+    ///
+    /// The information is only queried the first time this method is called,
+    /// or after it has been invalidated by `invalidate_info` or
+    /// `notify_event`. Subsequent calls return the same snapshot without
+    /// performing any ioctl, which makes this cheap enough to call e.g. every
+    /// time device details are displayed.
+    pub fn info(&self) -> Result<Arc<DeviceInfo>, ioctl::QueryCapError> {
+        let mut info = self.info.lock().unwrap();
+        match &*info {
+            Some(info) => Ok(Arc::clone(info)),
+            None => {
+                let new_info = Arc::new(DeviceInfo::query(&self.fd)?);
+                *info = Some(Arc::clone(&new_info));
+                Ok(new_info)
+            }
+        }
+    }
+
+    /// Query the capabilities and formats of the device again, and return
+    /// the new snapshot.
+    pub fn refresh(&self) -> Result<Arc<DeviceInfo>, ioctl::QueryCapError> {
+        self.invalidate_info();
+        self.info()
+    }
+
+    /// Discard the cached result of `info`, so the next call queries the
+    /// device again.
+    pub fn invalidate_info(&self) {
+        *self.info.lock().unwrap() = None;
+    }
+
+    /// Save the current value of all the controls of the device that can be restored, e.g.
+    /// before temporarily changing some camera settings.
+    pub fn save_controls(&self) -> Result<ControlSnapshot, ControlSnapshotError> {
+        ControlSnapshot::save(&self.fd)
+    }
+
+    /// Restore the control values saved in `snapshot` by `save_controls`.
+    pub fn restore_controls(&self, snapshot: &ControlSnapshot) -> Result<(), ControlSnapshotError> {
+        snapshot.restore(&self.fd)
+    }
+
+    /// Let the device know about an `event` dequeued by the client, so the
+    /// cached result of `info` can be discarded if the event may have changed
+    /// it (e.g. a source change, after which the supported formats may be
+    /// different).
+    pub fn notify_event(&self, event: &ioctl::Event) {
+        if let ioctl::Event::SrcChangeEvent(_) = event {
+            self.invalidate_info();
+        }
+    }
 }
 
 impl AsFd for Device {
@@ -109,3 +214,65 @@ impl AsRawFd for Device {
         self.fd.as_raw_fd()
     }
 }
+
+#[cfg(all(test, feature = "ioctl-trace"))]
+mod tests {
+    use super::*;
+    use crate::ioctl::trace::testing::{entry, querycap_entry, ENUM_FMT};
+    use crate::ioctl::trace::{replay, Trace, TraceEntry};
+    use crate::ioctl::Capabilities;
+
+    const M2M_CAPS: Capabilities = Capabilities::VIDEO_M2M_MPLANE.union(Capabilities::STREAMING);
+
+    /// Returns the ioctls performed to query the information of a
+    /// memory-to-memory device without any format.
+    fn info_entries() -> Vec<TraceEntry> {
+        // One ENUM_FMT reaching the end of the formats of each queue.
+        vec![
+            querycap_entry("test", M2M_CAPS),
+            entry(ENUM_FMT, Err(Errno::EINVAL)),
+            entry(ENUM_FMT, Err(Errno::EINVAL)),
+        ]
+    }
+
+    #[test]
+    fn info_cache() {
+        let mut open_entries = info_entries();
+        open_entries.truncate(1);
+        let device = replay(
+            &Trace {
+                entries: open_entries,
+            },
+            || Device::new(File::open("/dev/null").unwrap()),
+        )
+        .unwrap()
+        .unwrap();
+
+        let trace = Trace {
+            entries: info_entries(),
+        };
+        let info = replay(&trace, || device.info()).unwrap().unwrap();
+        assert_eq!(info.caps.driver, "test");
+        assert_eq!(
+            info.formats.keys().copied().collect::<Vec<_>>(),
+            vec![QueueType::VideoCaptureMplane, QueueType::VideoOutputMplane]
+        );
+
+        // Cached snapshots are returned without performing any ioctl.
+        let no_ioctl = Trace::default();
+        let cached = replay(&no_ioctl, || device.info()).unwrap().unwrap();
+        assert!(Arc::ptr_eq(&info, &cached));
+        device.notify_event(&ioctl::Event::Eos);
+        let cached = replay(&no_ioctl, || device.info()).unwrap().unwrap();
+        assert!(Arc::ptr_eq(&info, &cached));
+
+        // A source change invalidates the snapshot.
+        device.notify_event(&ioctl::Event::SrcChangeEvent(ioctl::SrcChanges::RESOLUTION));
+        let refreshed = replay(&trace, || device.info()).unwrap().unwrap();
+        assert!(!Arc::ptr_eq(&info, &refreshed));
+
+        device.invalidate_info();
+        let refreshed_again = replay(&trace, || device.info()).unwrap().unwrap();
+        assert!(!Arc::ptr_eq(&refreshed, &refreshed_again));
+    }
+}
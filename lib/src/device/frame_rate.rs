@@ -0,0 +1,151 @@
+//! Measurement of the frame rate actually achieved by a stream.
+//!
+//! `FrameRateMonitor` is fed the timestamps of dequeued buffers, typically
+//! from the capture callback, and computes the frame rate and inter-frame
+//! jitter over a sliding window of recent frames. This allows detecting e.g.
+//! sensors delivering frames below the rate they have been configured for.
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::bindings;
+use crate::device::queue::direction::Direction;
+use crate::device::queue::dqbuf::DqBuffer;
+use crate::memory::BufferHandles;
+
+/// Frame rate statistics computed over the window of a `FrameRateMonitor`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameRateStats {
+    /// Number of frames the statistics have been computed from.
+    pub num_frames: usize,
+    /// Average number of frames per second.
+    pub fps: f64,
+    /// Average interval between two consecutive frames.
+    pub mean_interval: Duration,
+    /// Standard deviation of the interval between two consecutive frames.
+    pub jitter: Duration,
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+}
+
+impl FrameRateStats {
+    /// Returns whether the measured rate is below `expected_fps` by more than
+    /// `tolerance`, expressed as a ratio of `expected_fps` (e.g. `0.05` for
+    /// 5%).
+    pub fn is_below(&self, expected_fps: f64, tolerance: f64) -> bool {
+        self.fps < expected_fps * (1.0 - tolerance)
+    }
+}
+
+/// Computes frame rate statistics over the last frames of a stream.
+pub struct FrameRateMonitor {
+    window: usize,
+    timestamps: VecDeque<Duration>,
+}
+
+impl FrameRateMonitor {
+    /// Creates a monitor computing its statistics over the last `window`
+    /// frames. At least two frames are needed to measure an interval, so
+    /// smaller values are rounded up to 2.
+    pub fn new(window: usize) -> Self {
+        let window = window.max(2);
+
+        FrameRateMonitor {
+            window,
+            timestamps: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// Records a new frame with timestamp `timestamp`.
+    ///
+    /// A timestamp that is not greater than the previous one means that the
+    /// stream has been restarted, and resets the window.
+    pub fn record(&mut self, timestamp: Duration) {
+        if matches!(self.timestamps.back(), Some(last) if *last >= timestamp) {
+            self.timestamps.clear();
+        }
+        if self.timestamps.len() == self.window {
+            self.timestamps.pop_front();
+        }
+        self.timestamps.push_back(timestamp);
+    }
+
+    /// Records a new frame with the `timeval` timestamp of a V4L2 buffer.
+    pub fn record_timeval(&mut self, timestamp: &bindings::timeval) {
+        self.record(
+            Duration::from_secs(timestamp.tv_sec.max(0) as u64)
+                + Duration::from_micros(timestamp.tv_usec.max(0) as u64),
+        );
+    }
+
+    /// Records the frame contained in `buffer`, using its timestamp.
+    pub fn record_buffer<D: Direction, P: BufferHandles>(&mut self, buffer: &DqBuffer<D, P>) {
+        self.record_timeval(&buffer.timestamp());
+    }
+
+    /// Discards all the recorded frames.
+    pub fn reset(&mut self) {
+        self.timestamps.clear();
+    }
+
+    /// Returns the statistics over the frames currently in the window, or
+    /// `None` if less than two frames have been recorded.
+    pub fn stats(&self) -> Option<FrameRateStats> {
+        let first = *self.timestamps.front()?;
+        let last = *self.timestamps.back()?;
+        let num_intervals = self.timestamps.len().checked_sub(1).filter(|n| *n > 0)?;
+
+        let intervals = || {
+            self.timestamps
+                .iter()
+                .zip(self.timestamps.iter().skip(1))
+                .map(|(prev, next)| *next - *prev)
+        };
+
+        let mean = (last - first).as_secs_f64() / num_intervals as f64;
+        let variance = intervals()
+            .map(|interval| (interval.as_secs_f64() - mean).powi(2))
+            .sum::<f64>()
+            / num_intervals as f64;
+
+        Some(FrameRateStats {
+            num_frames: self.timestamps.len(),
+            fps: 1.0 / mean,
+            mean_interval: Duration::from_secs_f64(mean),
+            jitter: Duration::from_secs_f64(variance.sqrt()),
+            min_interval: intervals().min()?,
+            max_interval: intervals().max()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::FrameRateMonitor;
+
+    #[test]
+    fn frame_rate_stats() {
+        let mut monitor = FrameRateMonitor::new(4);
+        monitor.record(Duration::from_millis(0));
+        assert!(monitor.stats().is_none());
+
+        // The frame at 0ms drops out of the window.
+        for ms in [10, 20, 40, 70] {
+            monitor.record(Duration::from_millis(ms));
+        }
+        let stats = monitor.stats().unwrap();
+        assert_eq!(stats.num_frames, 4);
+        assert_eq!(stats.mean_interval, Duration::from_millis(20));
+        assert!((stats.fps - 50.0).abs() < 1e-9);
+        assert_eq!(stats.min_interval, Duration::from_millis(10));
+        assert_eq!(stats.max_interval, Duration::from_millis(30));
+        assert!(stats.jitter > Duration::from_millis(8) && stats.jitter < Duration::from_millis(9));
+        assert!(stats.is_below(60.0, 0.1));
+        assert!(!stats.is_below(52.0, 0.1));
+
+        // A timestamp going backwards resets the window.
+        monitor.record(Duration::from_millis(5));
+        assert!(monitor.stats().is_none());
+    }
+}
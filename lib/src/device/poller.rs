@@ -4,6 +4,9 @@
 //!
 //! It also provides a `Waker` companion that allows other threads to interrupt
 //! an ongoing (or coming) poll. Useful to implement an event-based loop.
+//!
+//! The conditions that woke a poll are available as a [`Readiness`] set, which
+//! is also used to select the conditions the poller is interested in.
 
 use std::{
     collections::BTreeMap,
@@ -17,7 +20,9 @@ use std::{
     task::Wake,
 };
 
+use bitflags::bitflags;
 use log::{error, warn};
+use nix::errno::Errno;
 use nix::sys::{
     epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags},
     eventfd::{eventfd, EfdFlags},
@@ -25,6 +30,8 @@ use nix::sys::{
 use thiserror::Error;
 
 use crate::device::Device;
+use crate::ioctl::OsError;
+use crate::shutdown::ShutdownToken;
 
 #[derive(Debug, PartialEq)]
 pub enum DeviceEvent {
@@ -39,10 +46,36 @@ pub enum PollEvent {
     Waker(u32),
 }
 
+bitflags! {
+    /// Set of conditions that can wake a `Poller`.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Readiness: u32 {
+        /// A CAPTURE buffer can be dequeued.
+        const CAPTURE = 1 << 0;
+        /// An OUTPUT buffer can be dequeued.
+        const OUTPUT = 1 << 1;
+        /// A V4L2 event is pending.
+        const EVENT = 1 << 2;
+        /// At least one `Waker` has been signaled.
+        const WAKER = 1 << 3;
+    }
+}
+
+impl From<DeviceEvent> for Readiness {
+    fn from(event: DeviceEvent) -> Self {
+        match event {
+            DeviceEvent::CaptureReady => Readiness::CAPTURE,
+            DeviceEvent::OutputReady => Readiness::OUTPUT,
+            DeviceEvent::V4L2Event => Readiness::EVENT,
+        }
+    }
+}
+
 pub struct PollEvents {
     events: [EpollEvent; 4],
     nb_events: usize,
     cur_event: usize,
+    readiness: Readiness,
 }
 
 impl PollEvents {
@@ -56,8 +89,38 @@ impl PollEvents {
             ],
             nb_events: 0,
             cur_event: 0,
+            readiness: Readiness::empty(),
         }
     }
+
+    /// Compute `readiness` from the events returned by `epoll_wait`.
+    fn update_readiness(&mut self) {
+        let mut readiness = Readiness::empty();
+        for event in &self.events[0..self.nb_events] {
+            match event.data() {
+                DEVICE_ID => {
+                    for (flag, condition) in [
+                        (EpollFlags::EPOLLIN, Readiness::CAPTURE),
+                        (EpollFlags::EPOLLOUT, Readiness::OUTPUT),
+                        (EpollFlags::EPOLLPRI, Readiness::EVENT),
+                    ] {
+                        if event.events().contains(flag) {
+                            readiness.insert(condition);
+                        }
+                    }
+                }
+                FIRST_WAKER_ID..=LAST_WAKER_ID => readiness.insert(Readiness::WAKER),
+                _ => (),
+            }
+        }
+        self.readiness = readiness;
+    }
+
+    /// Returns the set of all the conditions reported by the poll, regardless
+    /// of how many events have been consumed from the iterator.
+    pub fn readiness(&self) -> Readiness {
+        self.readiness
+    }
 }
 
 impl Iterator for PollEvents {
@@ -109,7 +172,7 @@ pub struct Waker {
 }
 
 impl Waker {
-    fn new() -> io::Result<Self> {
+    pub(crate) fn new() -> io::Result<Self> {
         let fd = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
 
         Ok(Waker { fd: File::from(fd) })
@@ -159,6 +222,9 @@ pub struct Poller {
 
     // If set, incremented every time we wake up from a poll.
     poll_wakeups_counter: Option<Arc<AtomicUsize>>,
+
+    // If set, polling fails with `Interrupted` once shutdown is requested.
+    shutdown_token: Option<ShutdownToken>,
 }
 
 /// Wakers IDs range.
@@ -166,17 +232,33 @@ const FIRST_WAKER_ID: u64 = 0;
 const LAST_WAKER_ID: u64 = DEVICE_ID - 1;
 /// Give us a comfortable range of 4 billion ids usable for wakers.
 const DEVICE_ID: u64 = 1 << 32;
+const SHUTDOWN_ID: u64 = DEVICE_ID + 1;
 
 #[derive(Debug, Error)]
 pub enum PollError {
     #[error("error during call to epoll_wait: {0}")]
-    EPollWait(nix::Error),
+    EPollWait(#[source] nix::Error),
     #[error("error while resetting the waker: {0}")]
-    WakerReset(io::Error),
+    WakerReset(#[source] io::Error),
     #[error("V4L2 device returned EPOLLERR")]
     V4L2Device,
+    #[error("shutdown has been requested")]
+    Interrupted,
 }
 
+impl OsError for PollError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            PollError::EPollWait(e) => Some(*e),
+            PollError::WakerReset(e) => e.raw_os_error().map(Errno::from_i32),
+            PollError::V4L2Device => None,
+            PollError::Interrupted => Some(Errno::EINTR),
+        }
+    }
+}
+
+crate::ioctl::impl_into_io_error!(PollError);
+
 impl Poller {
     pub fn new(device: Arc<Device>) -> nix::Result<Self> {
         let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
@@ -210,6 +292,7 @@ impl Poller {
             output_enabled: false,
             events_enabled: false,
             poll_wakeups_counter: None,
+            shutdown_token: None,
         })
     }
 
@@ -255,6 +338,33 @@ impl Poller {
         self.poll_wakeups_counter = Some(poll_wakeup_counter);
     }
 
+    /// Makes `poll()` fail with `PollError::Interrupted` once shutdown is
+    /// requested on `token`, instead of waiting for events.
+    pub fn set_shutdown_token(&mut self, token: ShutdownToken) -> nix::Result<()> {
+        self.remove_shutdown_token()?;
+        self.epoll
+            .add(&token, EpollEvent::new(EpollFlags::EPOLLIN, SHUTDOWN_ID))?;
+        self.shutdown_token = Some(token);
+
+        Ok(())
+    }
+
+    /// Stop honoring the shutdown token set with `set_shutdown_token()`, e.g.
+    /// to wait for the last buffers while shutting down.
+    pub fn remove_shutdown_token(&mut self) -> nix::Result<Option<ShutdownToken>> {
+        if let Some(token) = &self.shutdown_token {
+            self.epoll.delete(token)?;
+        }
+
+        Ok(self.shutdown_token.take())
+    }
+
+    fn shutdown_requested(&self) -> bool {
+        self.shutdown_token
+            .as_ref()
+            .map_or(false, ShutdownToken::is_requested)
+    }
+
     fn update_device_registration(&mut self) -> nix::Result<()> {
         let mut epoll_flags = EpollFlags::empty();
         if self.capture_enabled {
@@ -309,6 +419,42 @@ impl Poller {
         }
     }
 
+    /// Returns the set of device conditions currently listened to.
+    ///
+    /// Wakers are always listened to, so `Readiness::WAKER` is never part of
+    /// the returned set.
+    pub fn interest(&self) -> Readiness {
+        let mut interest = Readiness::empty();
+        interest.set(Readiness::CAPTURE, self.capture_enabled);
+        interest.set(Readiness::OUTPUT, self.output_enabled);
+        interest.set(Readiness::EVENT, self.events_enabled);
+        interest
+    }
+
+    /// Listen to exactly the device conditions of `interest`, e.g. to stop
+    /// polling the OUTPUT queue while no input is pending and avoid useless
+    /// wakeups. `Readiness::WAKER` is ignored as wakers are always listened
+    /// to.
+    pub fn set_interest(&mut self, interest: Readiness) -> nix::Result<()> {
+        let capture_enabled = interest.contains(Readiness::CAPTURE);
+        let output_enabled = interest.contains(Readiness::OUTPUT);
+        let events_enabled = interest.contains(Readiness::EVENT);
+        if (capture_enabled, output_enabled, events_enabled)
+            == (
+                self.capture_enabled,
+                self.output_enabled,
+                self.events_enabled,
+            )
+        {
+            return Ok(());
+        }
+
+        self.capture_enabled = capture_enabled;
+        self.output_enabled = output_enabled;
+        self.events_enabled = events_enabled;
+        self.update_device_registration()
+    }
+
     pub fn poll(&mut self, duration: Option<std::time::Duration>) -> Result<PollEvents, PollError> {
         let mut events = PollEvents::new();
         let duration: isize = match duration {
@@ -316,16 +462,29 @@ impl Poller {
             Some(d) => d.as_millis() as isize,
         };
 
-        events.nb_events = self
-            .epoll
-            .wait(&mut events.events, duration)
-            .map_err(PollError::EPollWait)?;
+        if self.shutdown_requested() {
+            return Err(PollError::Interrupted);
+        }
+
+        events.nb_events = match self.epoll.wait(&mut events.events, duration) {
+            Ok(nb_events) => nb_events,
+            Err(Errno::EINTR) if self.shutdown_requested() => return Err(PollError::Interrupted),
+            Err(e) => return Err(PollError::EPollWait(e)),
+        };
+        events.update_readiness();
 
         // Update our wake up stats
         if let Some(wakeup_counter) = &self.poll_wakeups_counter {
             wakeup_counter.fetch_add(1, Ordering::SeqCst);
         }
 
+        if events.events[0..events.nb_events]
+            .iter()
+            .any(|event| event.data() == SHUTDOWN_ID)
+        {
+            return Err(PollError::Interrupted);
+        }
+
         // Reset all the wakers that have been signaled.
         for event in &events.events[0..events.nb_events] {
             if event.data() <= LAST_WAKER_ID {
@@ -355,7 +514,7 @@ impl AsFd for Poller {
 
 #[cfg(test)]
 mod tests {
-    use super::{DeviceEvent::*, PollEvent::*, PollEvents};
+    use super::{DeviceEvent::*, PollEvent::*, PollEvents, Readiness};
     use super::{DEVICE_ID, FIRST_WAKER_ID};
     use nix::sys::epoll::{EpollEvent, EpollFlags};
 
@@ -436,4 +595,37 @@ mod tests {
         assert_eq!(poll_events.next(), Some(Waker(0)));
         assert_eq!(poll_events.next(), None);
     }
+
+    #[test]
+    fn test_pollevents_readiness() {
+        let mut poll_events = PollEvents::new();
+        poll_events.update_readiness();
+        assert_eq!(poll_events.readiness(), Readiness::empty());
+
+        let mut poll_events = PollEvents::new();
+        poll_events.events[0] = EpollEvent::new(EpollFlags::empty(), FIRST_WAKER_ID + 20);
+        poll_events.events[1] =
+            EpollEvent::new(EpollFlags::EPOLLPRI | EpollFlags::EPOLLOUT, DEVICE_ID);
+        poll_events.nb_events = 2;
+        poll_events.update_readiness();
+        assert_eq!(
+            poll_events.readiness(),
+            Readiness::WAKER | Readiness::OUTPUT | Readiness::EVENT
+        );
+
+        // Consuming the events does not change the readiness.
+        assert_eq!(poll_events.by_ref().count(), 3);
+        assert_eq!(
+            poll_events.readiness(),
+            Readiness::WAKER | Readiness::OUTPUT | Readiness::EVENT
+        );
+
+        // Only the slots filled by epoll are considered.
+        let mut poll_events = PollEvents::new();
+        poll_events.events[0] = EpollEvent::new(EpollFlags::EPOLLIN, DEVICE_ID);
+        poll_events.events[1] = EpollEvent::new(EpollFlags::EPOLLOUT, DEVICE_ID);
+        poll_events.nb_events = 1;
+        poll_events.update_readiness();
+        assert_eq!(poll_events.readiness(), Readiness::CAPTURE);
+    }
 }
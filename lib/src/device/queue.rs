@@ -4,28 +4,43 @@ pub mod dqbuf;
 pub mod generic;
 pub mod handles_provider;
 pub mod qbuf;
+pub mod watermark;
 
 use self::qbuf::{get_free::GetFreeOutputBuffer, get_indexed::GetOutputBufferByIndex};
 
-use super::{AllocatedQueue, Device, FreeBuffersResult, Stream, TryDequeue};
+use super::{AllocatedQueue, Device, FreeBuffersError, FreeBuffersResult, Stream, TryDequeue};
 use crate::ioctl::{DqBufResult, QueryBufError, V4l2BufferFromError};
-use crate::{bindings, memory::*};
+use crate::vbi::{SlicedVbiFormat, VbiFormat};
+use crate::{
+    bindings,
+    controls::{
+        user::{MinBuffersForCapture, MinBuffersForOutput},
+        ExtControlTrait,
+    },
+    memory::*,
+};
 use crate::{
     ioctl::{
-        self, GFmtError, QueryBuffer, ReqbufsError, SFmtError, SelectionTarget, SelectionType,
-        StreamOffError, StreamOnError, TryFmtError,
+        self, GFmtError, MemoryFlags, OsError, QueryBuffer, ReqbufsError, SFmtError,
+        SelectionTarget, SelectionType, StreamOffError, StreamOnError, TryFmtError,
     },
     PlaneLayout, Rect,
 };
-use crate::{Format, PixelFormat, QueueType};
+use crate::{
+    Colorimetry, Colorspace, Format, FormatConversionError, FormatDiff, FormatField, PixelFormat,
+    Quantization, QueueDirection, QueueType, XferFunc, YCbCrEncoding,
+};
 use buffer::*;
 use direction::*;
 use dqbuf::*;
 use generic::{GenericBufferHandles, GenericQBuffer, GenericSupportedMemoryType};
 use log::debug;
+use nix::errno::Errno;
 use qbuf::{
-    get_free::{GetFreeBufferError, GetFreeCaptureBuffer},
-    get_indexed::{GetCaptureBufferByIndex, TryGetBufferError},
+    get_free::{FreeBufferPolicy, GetFreeBuffer, GetFreeBufferError, GetFreeCaptureBuffer},
+    get_indexed::{
+        GetBufferByIndex, GetCaptureBufferByIndex, ReserveBufferByIndex, TryGetBufferError,
+    },
     *,
 };
 
@@ -43,6 +58,7 @@ pub struct QueueBase {
     device: Arc<Device>,
     type_: QueueType,
     capabilities: ioctl::BufferCapabilities,
+    max_num_buffers: u32,
 }
 
 impl AsRawFd for QueueBase {
@@ -85,10 +101,29 @@ where
         self.inner.capabilities
     }
 
+    /// Returns whether buffers of this queue can be queued as part of a media
+    /// request.
+    pub fn supports_requests(&self) -> bool {
+        self.inner
+            .capabilities
+            .contains(ioctl::BufferCapabilities::SUPPORTS_REQUESTS)
+    }
+
+    /// Returns the maximum number of buffers this queue can hold. Requests for more buffers are
+    /// clamped to this value.
+    pub fn max_num_buffers(&self) -> u32 {
+        self.inner.max_num_buffers
+    }
+
     pub fn get_type(&self) -> QueueType {
         self.inner.type_
     }
 
+    /// Returns the direction of this queue, for code that is generic over it.
+    pub fn get_direction(&self) -> QueueDirection {
+        D::DIRECTION
+    }
+
     pub fn get_format<T: TryFrom<bindings::v4l2_format>>(&self) -> Result<T, GFmtError> {
         ioctl::g_fmt(&self.inner, self.inner.type_)
     }
@@ -108,6 +143,23 @@ where
         ioctl::try_fmt(&self.inner, (self.inner.type_, &format))
     }
 
+    /// Sets the format of a raw VBI queue. The returned format is the one
+    /// actually applied by the driver.
+    pub fn set_vbi_format(&mut self, format: VbiFormat) -> Result<VbiFormat, SFmtError> {
+        let type_ = self.inner.type_;
+        ioctl::s_fmt(&mut self.inner, (type_, &format))
+    }
+
+    /// Sets the format of a sliced VBI queue. The returned format is the one
+    /// actually applied by the driver.
+    pub fn set_sliced_vbi_format(
+        &mut self,
+        format: SlicedVbiFormat,
+    ) -> Result<SlicedVbiFormat, SFmtError> {
+        let type_ = self.inner.type_;
+        ioctl::s_fmt(&mut self.inner, (type_, &format))
+    }
+
     /// Returns a `FormatBuilder` which is set to the currently active format
     /// and can be modified and eventually applied. The `FormatBuilder` holds
     /// a mutable reference to this `Queue`.
@@ -129,6 +181,44 @@ where
 
         ioctl::g_selection(&self.inner, selection, target)
     }
+
+    /// Returns the minimum number of buffers the driver needs on this queue,
+    /// as reported by the `MIN_BUFFERS_FOR_CAPTURE` or `MIN_BUFFERS_FOR_OUTPUT`
+    /// control, or `None` if the driver does not expose it.
+    ///
+    /// For decoders, the value of the CAPTURE control depends on the stream
+    /// and is only meaningful after the format has been determined.
+    pub fn get_min_buffers(&self) -> Result<Option<usize>, ioctl::GCtrlError> {
+        let id = match D::DIRECTION {
+            QueueDirection::Capture => MinBuffersForCapture::ID,
+            QueueDirection::Output => MinBuffersForOutput::ID,
+        };
+
+        match ioctl::g_ctrl(&self.inner, id) {
+            Ok(min) => Ok(Some(min.max(0) as usize)),
+            Err(ioctl::GCtrlError::Invalid) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns `count` raised to the minimum number of buffers required by the
+    /// driver if it is lower.
+    ///
+    /// If the minimum cannot be obtained, `count` is returned unchanged.
+    pub fn clamp_num_buffers(&self, count: usize) -> usize {
+        match self.get_min_buffers() {
+            Ok(Some(min)) if min > count => {
+                debug!(
+                    "Raising number of {:?} buffers from {} to driver minimum {}",
+                    D::DIRECTION,
+                    count,
+                    min
+                );
+                min
+            }
+            _ => count,
+        }
+    }
 }
 
 /// Builder for a V4L2 format. This takes a mutable reference on the queue, so
@@ -136,12 +226,183 @@ where
 pub struct FormatBuilder<'a> {
     queue: &'a mut QueueBase,
     format: Format,
+    /// Colorimetry to request, or `None` to let the driver choose it.
+    colorimetry: Option<Colorimetry>,
+    requested: RequestedFields,
+}
+
+/// Fields of a `FormatBuilder` explicitly set by the client.
+#[derive(Debug, Clone, Copy, Default)]
+struct RequestedFields {
+    size: bool,
+    pixelformat: bool,
+    planes_layout: bool,
+    colorimetry: bool,
+}
+
+impl RequestedFields {
+    fn contains(&self, field: FormatField) -> bool {
+        match field {
+            FormatField::Width | FormatField::Height => self.size,
+            FormatField::PixelFormat => self.pixelformat,
+            FormatField::NumPlanes | FormatField::SizeImage(_) | FormatField::BytesPerLine(_) => {
+                self.planes_layout
+            }
+            FormatField::Colorimetry => self.colorimetry,
+        }
+    }
+}
+
+/// Returns the differences between `requested` and `applied` on the fields
+/// set by the client. The colorimetry fields left to `Default` in `requested`
+/// are chosen by the driver and thus never reported.
+fn adjusted_fields(
+    requested: (&Format, Colorimetry),
+    fields: RequestedFields,
+    applied: (&Format, Colorimetry),
+) -> FormatDiff {
+    let (requested, requested_colorimetry) = requested;
+    let (applied, applied_colorimetry) = applied;
+    let requested_colorimetry = Colorimetry {
+        colorspace: match requested_colorimetry.colorspace {
+            Colorspace::Default => applied_colorimetry.colorspace,
+            colorspace => colorspace,
+        },
+        ycbcr_enc: match requested_colorimetry.ycbcr_enc {
+            YCbCrEncoding::Default => applied_colorimetry.ycbcr_enc,
+            ycbcr_enc => ycbcr_enc,
+        },
+        quantization: match requested_colorimetry.quantization {
+            Quantization::Default => applied_colorimetry.quantization,
+            quantization => quantization,
+        },
+        xfer_func: match requested_colorimetry.xfer_func {
+            XferFunc::Default => applied_colorimetry.xfer_func,
+            xfer_func => xfer_func,
+        },
+    };
+
+    let mut diff = FormatDiff::new(requested, applied)
+        .with_colorimetry(requested_colorimetry, applied_colorimetry);
+    diff.retain(|change| fields.contains(change.field()));
+
+    diff
 }
 
+/// Result of successfully applying a format with `FormatBuilder::apply_checked`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatOutcome {
+    /// The driver applied the requested format without changing any of the
+    /// fields set by the client.
+    Exact(Format),
+    /// The driver adjusted some of the fields set by the client.
+    Adjusted {
+        requested: Format,
+        applied: Format,
+        diff: FormatDiff,
+    },
+}
+
+#[derive(Debug, Error)]
+#[error("driver adjusted the requested format: {diff}")]
+pub struct FormatAdjustedError {
+    pub applied: Format,
+    pub diff: FormatDiff,
+}
+
+impl OsError for FormatAdjustedError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
+ioctl::impl_into_io_error!(FormatAdjustedError);
+
+impl FormatOutcome {
+    /// Returns the format actually applied by the driver.
+    pub fn format(&self) -> &Format {
+        match self {
+            FormatOutcome::Exact(format) => format,
+            FormatOutcome::Adjusted { applied, .. } => applied,
+        }
+    }
+
+    pub fn into_format(self) -> Format {
+        match self {
+            FormatOutcome::Exact(format) => format,
+            FormatOutcome::Adjusted { applied, .. } => applied,
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        matches!(self, FormatOutcome::Exact(_))
+    }
+
+    /// Returns the changes made by the driver to the fields set by the
+    /// client, if any.
+    pub fn diff(&self) -> Option<&FormatDiff> {
+        match self {
+            FormatOutcome::Exact(_) => None,
+            FormatOutcome::Adjusted { diff, .. } => Some(diff),
+        }
+    }
+
+    /// Returns the applied format, or an error if the driver adjusted any
+    /// field for which `critical` returns `true`.
+    pub fn ensure_unchanged<F: Fn(&FormatField) -> bool>(
+        self,
+        critical: F,
+    ) -> Result<Format, FormatAdjustedError> {
+        match self {
+            FormatOutcome::Exact(format) => Ok(format),
+            FormatOutcome::Adjusted {
+                applied, mut diff, ..
+            } => {
+                diff.retain(|change| critical(&change.field()));
+                if diff.is_empty() {
+                    Ok(applied)
+                } else {
+                    Err(FormatAdjustedError { applied, diff })
+                }
+            }
+        }
+    }
+
+    /// Returns the applied format, or an error if the driver adjusted any of
+    /// the fields set by the client.
+    pub fn ensure_exact(self) -> Result<Format, FormatAdjustedError> {
+        self.ensure_unchanged(|_| true)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SetFormatError {
+    #[error("error while getting the current format")]
+    GFmtError(#[from] GFmtError),
+    #[error("error while setting the format")]
+    SFmtError(#[from] SFmtError),
+}
+
+impl OsError for SetFormatError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            SetFormatError::GFmtError(e) => e.errno(),
+            SetFormatError::SFmtError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(SetFormatError);
+
 impl<'a> FormatBuilder<'a> {
     fn new(queue: &'a mut QueueBase) -> Result<Self, GFmtError> {
         let format = ioctl::g_fmt(queue, queue.type_)?;
-        Ok(Self { queue, format })
+        Ok(Self {
+            queue,
+            format,
+            colorimetry: None,
+            requested: Default::default(),
+        })
     }
 
     /// Get a reference to the format built so far. Useful for checking the
@@ -154,24 +415,78 @@ impl<'a> FormatBuilder<'a> {
     pub fn set_size(mut self, width: usize, height: usize) -> Self {
         self.format.width = width as u32;
         self.format.height = height as u32;
+        self.requested.size = true;
         self
     }
 
     pub fn set_pixelformat(mut self, pixel_format: impl Into<PixelFormat>) -> Self {
         self.format.pixelformat = pixel_format.into();
+        self.requested.pixelformat = true;
         self
     }
 
+    /// Set the layout of the planes. Fields left to zero are chosen by the
+    /// driver.
     pub fn set_planes_layout<P: IntoIterator<Item = PlaneLayout>>(mut self, planes: P) -> Self {
         self.format.plane_fmt = planes.into_iter().collect();
+        self.requested.planes_layout = true;
         self
     }
 
+    /// Set the colorimetry of the format. Fields left to `Default` are chosen
+    /// by the driver. Only video queues have a colorimetry, this is ignored on
+    /// other queues.
+    pub fn set_colorimetry(mut self, colorimetry: Colorimetry) -> Self {
+        self.colorimetry = Some(colorimetry);
+        self.requested.colorimetry = true;
+        self
+    }
+
+    /// Returns the format built so far in the form expected by the ioctls.
+    fn raw_format(&self) -> Result<bindings::v4l2_format, FormatConversionError> {
+        let mut raw_format = bindings::v4l2_format::try_from((self.queue.type_, &self.format))?;
+        if let Some(colorimetry) = self.colorimetry {
+            ioctl::set_format_colorimetry(&mut raw_format, colorimetry);
+        }
+
+        Ok(raw_format)
+    }
+
     /// Apply the format built so far. The kernel will adjust the format to fit
     /// the driver's capabilities if needed, and the format actually applied will
     /// be returned.
     pub fn apply<O: TryFrom<bindings::v4l2_format>>(self) -> Result<O, SFmtError> {
-        ioctl::s_fmt(self.queue, (self.queue.type_, &self.format))
+        let raw_format = self
+            .raw_format()
+            .map_err(|_| SFmtError::ToV4L2FormatConversionError)?;
+        ioctl::s_fmt(self.queue, raw_format)
+    }
+
+    /// Apply the format built so far like `apply()`, and report whether the
+    /// driver adjusted any of the fields explicitly set on this builder.
+    pub fn apply_checked(self) -> Result<FormatOutcome, SFmtError> {
+        let raw_format = self
+            .raw_format()
+            .map_err(|_| SFmtError::ToV4L2FormatConversionError)?;
+        let raw_applied: bindings::v4l2_format = ioctl::s_fmt(self.queue, raw_format)?;
+        let applied =
+            Format::try_from(raw_applied).map_err(|_| SFmtError::FromV4L2FormatConversionError)?;
+        let applied_colorimetry = ioctl::format_colorimetry(&raw_applied).unwrap_or_default();
+        let diff = adjusted_fields(
+            (&self.format, self.colorimetry.unwrap_or_default()),
+            self.requested,
+            (&applied, applied_colorimetry),
+        );
+
+        Ok(if diff.is_empty() {
+            FormatOutcome::Exact(applied)
+        } else {
+            FormatOutcome::Adjusted {
+                requested: self.format,
+                applied,
+                diff,
+            }
+        })
     }
 
     /// Try to apply the format built so far. The kernel will adjust the format
@@ -181,9 +496,16 @@ impl<'a> FormatBuilder<'a> {
     /// Calling `apply()` right after this method is guaranteed to successfully
     /// apply the format without further change.
     pub fn try_apply(&mut self) -> Result<(), TryFmtError> {
-        let new_format = ioctl::try_fmt(self.queue, (self.queue.type_, &self.format))?;
-
-        self.format = new_format;
+        let raw_format = self
+            .raw_format()
+            .map_err(|_| TryFmtError::ToV4L2FormatConversionError)?;
+        let raw_format: bindings::v4l2_format = ioctl::try_fmt(self.queue, raw_format)?;
+
+        self.format =
+            Format::try_from(raw_format).map_err(|_| TryFmtError::FromV4L2FormatConversionError)?;
+        if self.colorimetry.is_some() {
+            self.colorimetry = ioctl::format_colorimetry(&raw_format);
+        }
         Ok(())
     }
 }
@@ -198,10 +520,46 @@ impl QueueState for QueueInit {}
 pub enum CreateQueueError {
     #[error("queue is already in use")]
     AlreadyBorrowed,
+    #[error("device lacks the {1:?} capability required by {0} queues")]
+    MissingCapability(QueueType, ioctl::Capabilities),
+    #[error("device does not support {0} buffers")]
+    BufferTypeUnavailable(QueueType, #[source] GFmtError),
     #[error("error while querying queue capabilities")]
     ReqbufsError(#[from] ioctl::ReqbufsError),
 }
 
+impl OsError for CreateQueueError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            CreateQueueError::AlreadyBorrowed | CreateQueueError::MissingCapability(..) => None,
+            CreateQueueError::BufferTypeUnavailable(_, e) => e.errno(),
+            CreateQueueError::ReqbufsError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(CreateQueueError);
+
+/// Checks that a device with capabilities `device_caps` can stream buffers of
+/// type `queue_type`.
+fn check_queue_capabilities(
+    queue_type: QueueType,
+    device_caps: ioctl::Capabilities,
+) -> Result<(), CreateQueueError> {
+    let required = ioctl::Capabilities::for_queue(queue_type);
+    if !device_caps.intersects(required) {
+        return Err(CreateQueueError::MissingCapability(queue_type, required));
+    }
+    if !device_caps.contains(ioctl::Capabilities::STREAMING) {
+        return Err(CreateQueueError::MissingCapability(
+            queue_type,
+            ioctl::Capabilities::STREAMING,
+        ));
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Error)]
 pub enum RequestBuffersError {
     #[error("error while requesting buffers")]
@@ -210,13 +568,26 @@ pub enum RequestBuffersError {
     QueryBufferError(#[from] QueryBufError<Infallible>),
 }
 
+impl OsError for RequestBuffersError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            RequestBuffersError::ReqbufsError(e) => e.errno(),
+            RequestBuffersError::QueryBufferError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(RequestBuffersError);
+
 impl<D: Direction> Queue<D, QueueInit> {
     /// Create a queue for type `queue_type` on `device`. A queue of a specific type
     /// can be requested only once.
     ///
-    /// Not all devices support all kinds of queue. To test whether the queue is supported,
-    /// a REQBUFS(0) is issued on the device. If it is not successful, the device is
-    /// deemed to not support this kind of queue and this method will fail.
+    /// Not all devices support all kinds of queue. The device capabilities are first checked
+    /// for the ones required by the queue, and a G_FMT is issued to ensure the buffer type is
+    /// available. Then a REQBUFS(0) is issued on the device. If any of these is not
+    /// successful, the device is deemed to not support this kind of queue and this method
+    /// will fail.
     fn create(
         device: Arc<Device>,
         queue_type: QueueType,
@@ -227,18 +598,35 @@ impl<D: Direction> Queue<D, QueueInit> {
             return Err(CreateQueueError::AlreadyBorrowed);
         }
 
+        check_queue_capabilities(queue_type, device.caps().device_caps())?;
+        ioctl::g_fmt::<bindings::v4l2_format>(&*device, queue_type)
+            .map_err(|e| CreateQueueError::BufferTypeUnavailable(queue_type, e))?;
+
         // Check that the queue is valid for this device by doing a dummy REQBUFS.
         // Obtain its capacities while we are at it.
-        let capabilities: ioctl::BufferCapabilities =
+        let (capabilities, memory): (ioctl::BufferCapabilities, _) =
             ioctl::reqbufs(&*device, queue_type, MemoryType::Mmap, 0)
+                .map(|caps| (caps, MemoryType::Mmap))
                 // In the unlikely case that MMAP buffers are not supported, try DMABUF.
                 .or_else(|e| match e {
                     ReqbufsError::InvalidBufferType(_, _) => {
                         ioctl::reqbufs(&*device, queue_type, MemoryType::DmaBuf, 0)
+                            .map(|caps| (caps, MemoryType::DmaBuf))
                     }
                     _ => Err(e),
                 })?;
 
+        // Not all drivers support CREATE_BUFS, in which case the legacy limit applies.
+        let max_num_buffers = ioctl::query_create_bufs_capabilities(&*device, queue_type, memory)
+            .map(|caps| caps.max_num_buffers)
+            .unwrap_or_else(|e| {
+                debug!(
+                    "Cannot query maximum number of buffers of {} queue: {}",
+                    queue_type, e
+                );
+                ioctl::LEGACY_MAX_NUM_BUFFERS
+            });
+
         used_queues.insert(queue_type);
 
         drop(used_queues);
@@ -248,6 +636,7 @@ impl<D: Direction> Queue<D, QueueInit> {
                 device,
                 type_: queue_type,
                 capabilities,
+                max_num_buffers,
             },
             _d: std::marker::PhantomData,
             state: QueueInit {},
@@ -259,13 +648,61 @@ impl<D: Direction> Queue<D, QueueInit> {
         memory_type: P::SupportedMemoryType,
         count: u32,
     ) -> Result<Queue<D, BuffersAllocated<P>>, RequestBuffersError> {
+        self.request_buffers_generic_with_flags(memory_type, count, MemoryFlags::empty())
+    }
+
+    /// Same as `request_buffers_generic`, but also passes memory allocation
+    /// `flags` to the driver, e.g. to request non-coherent MMAP memory.
+    ///
+    /// Flags are only honored if the queue has the `SUPPORTS_MMAP_CACHE_HINTS`
+    /// capability and MMAP memory is used ; they are silently dropped otherwise.
+    pub fn request_buffers_generic_with_flags<P: BufferHandles>(
+        self,
+        memory_type: P::SupportedMemoryType,
+        count: u32,
+        flags: MemoryFlags,
+    ) -> Result<Queue<D, BuffersAllocated<P>>, RequestBuffersError> {
+        let state = self.allocate(memory_type, count, flags)?;
+
+        Ok(Queue {
+            inner: self.inner,
+            _d: std::marker::PhantomData,
+            state,
+        })
+    }
+
+    /// Allocate the buffers of the queue without consuming it, so it can be
+    /// returned to the caller in case of failure.
+    fn allocate<P: BufferHandles>(
+        &self,
+        memory_type: P::SupportedMemoryType,
+        count: u32,
+        flags: MemoryFlags,
+    ) -> Result<BuffersAllocated<P>, RequestBuffersError> {
         let type_ = self.inner.type_;
-        let num_buffers: usize = ioctl::reqbufs(&self.inner, type_, memory_type.into(), count)?;
+        let count = if count > self.inner.max_num_buffers {
+            debug!(
+                "Clamping {} buffers requested on {} queue to its maximum of {}",
+                count, type_, self.inner.max_num_buffers
+            );
+            self.inner.max_num_buffers
+        } else {
+            count
+        };
+        let reqbufs: ioctl::RequestBuffers =
+            ioctl::reqbufs_with_flags(&self.inner, type_, memory_type.into(), count, flags)?;
+        let num_buffers = reqbufs.count as usize;
 
         debug!(
             "Requested {} buffers on {} queue, obtained {}",
             count, type_, num_buffers
         );
+        if reqbufs.flags != flags {
+            debug!(
+                "Requested memory flags {:?} on {} queue, obtained {:?}",
+                flags, type_, reqbufs.flags
+            );
+        }
 
         // The buffers have been allocated, now let's get their features.
         // We cannot use functional programming here because we need to return
@@ -284,14 +721,12 @@ impl<D: Direction> Queue<D, QueueInit> {
             })
             .collect();
 
-        Ok(Queue {
-            inner: self.inner,
-            _d: std::marker::PhantomData,
-            state: BuffersAllocated {
-                memory_type,
-                buffer_info,
-                buffer_stats,
-            },
+        Ok(BuffersAllocated {
+            memory_type,
+            memory_flags: reqbufs.flags,
+            buffer_info,
+            buffer_stats,
+            free_buffer_policy: Default::default(),
         })
     }
 
@@ -303,6 +738,16 @@ impl<D: Direction> Queue<D, QueueInit> {
     ) -> Result<Queue<D, BuffersAllocated<P>>, RequestBuffersError> {
         self.request_buffers_generic(P::MEMORY_TYPE, count)
     }
+
+    /// Same as `request_buffers`, but also passes memory allocation `flags` to
+    /// the driver. See `request_buffers_generic_with_flags` for details.
+    pub fn request_buffers_with_flags<P: PrimitiveBufferHandles>(
+        self,
+        count: u32,
+        flags: MemoryFlags,
+    ) -> Result<Queue<D, BuffersAllocated<P>>, RequestBuffersError> {
+        self.request_buffers_generic_with_flags(P::MEMORY_TYPE, count, flags)
+    }
 }
 
 impl Queue<Output, QueueInit> {
@@ -339,20 +784,315 @@ impl Queue<Capture, QueueInit> {
     pub fn get_capture_mplane_queue(device: Arc<Device>) -> Result<Self, CreateQueueError> {
         Queue::<Capture, QueueInit>::create(device, QueueType::VideoCaptureMplane)
     }
+
+    /// Acquires the raw VBI CAPTURE queue from `device`.
+    ///
+    /// This method will fail if the queue has already been obtained and has not
+    /// yet been released.
+    pub fn get_vbi_capture_queue(device: Arc<Device>) -> Result<Self, CreateQueueError> {
+        Queue::<Capture, QueueInit>::create(device, QueueType::VbiCapture)
+    }
+
+    /// Acquires the sliced VBI CAPTURE queue from `device`.
+    ///
+    /// This method will fail if the queue has already been obtained and has not
+    /// yet been released.
+    pub fn get_sliced_vbi_capture_queue(device: Arc<Device>) -> Result<Self, CreateQueueError> {
+        Queue::<Capture, QueueInit>::create(device, QueueType::SlicedVbiCapture)
+    }
+}
+
+/// The OUTPUT and CAPTURE queues of a memory-to-memory device (e.g. a decoder
+/// or an encoder).
+///
+/// Both queues are independent objects that are `Send`, so the natural
+/// threading model for m2m devices is supported: one thread can own the OUTPUT
+/// queue and feed it, while another owns the CAPTURE queue and processes its
+/// buffers. Each queue only gives access to the operations on its own buffers,
+/// and the device itself remains alive as long as either queue does.
+pub struct M2mQueues {
+    pub output: Queue<Output, QueueInit>,
+    pub capture: Queue<Capture, QueueInit>,
+}
+
+#[derive(Debug, Error)]
+pub enum SplitM2mError {
+    #[error("device is not a memory-to-memory device")]
+    NotM2m,
+    #[error("error while creating queue: {0}")]
+    CreateQueueError(#[from] CreateQueueError),
+}
+
+impl OsError for SplitM2mError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            SplitM2mError::NotM2m => None,
+            SplitM2mError::CreateQueueError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(SplitM2mError);
+
+impl M2mQueues {
+    /// Acquires both queues of `device`, using the multi-planar variants if the
+    /// device supports them.
+    pub fn new(device: Arc<Device>) -> Result<Self, SplitM2mError> {
+        use ioctl::Capabilities;
+
+        let caps = device.caps().device_caps();
+        let mplane = caps.contains(Capabilities::VIDEO_M2M_MPLANE)
+            || caps
+                .contains(Capabilities::VIDEO_CAPTURE_MPLANE | Capabilities::VIDEO_OUTPUT_MPLANE);
+        let splane = caps.contains(Capabilities::VIDEO_M2M)
+            || caps.contains(Capabilities::VIDEO_CAPTURE | Capabilities::VIDEO_OUTPUT);
+
+        let (output, capture) = if mplane {
+            (
+                Queue::get_output_mplane_queue(Arc::clone(&device))?,
+                Queue::get_capture_mplane_queue(device)?,
+            )
+        } else if splane {
+            (
+                Queue::get_output_queue(Arc::clone(&device))?,
+                Queue::get_capture_queue(device)?,
+            )
+        } else {
+            return Err(SplitM2mError::NotM2m);
+        };
+
+        Ok(M2mQueues { output, capture })
+    }
 }
 
 /// Allocated state for a queue. A queue with its buffers allocated can be
 /// streamed on and off, and buffers can be queued and dequeued.
 pub struct BuffersAllocated<P: BufferHandles> {
     memory_type: P::SupportedMemoryType,
+    /// Memory flags granted by the driver when the buffers were allocated.
+    memory_flags: MemoryFlags,
     /// Keep one `Arc` per buffer. This allows us to invalidate this buffer only in case it gets
     /// deallocated alone (V4L2 currently does not allow this, but might in the future).
     buffer_info: Vec<Arc<BufferInfo<P>>>,
     buffer_stats: Arc<BufferStats>,
+    free_buffer_policy: FreeBufferPolicy,
 }
 impl<P: BufferHandles> QueueState for BuffersAllocated<P> {}
 
+/// Successful result of `Queue::renegotiate`.
+pub struct RenegotiateResult<D: Direction, P: BufferHandles> {
+    /// The queue with its new buffers allocated.
+    pub queue: Queue<D, BuffersAllocated<P>>,
+    /// The format actually applied by the driver.
+    pub format: Format,
+    /// Buffers that were queued when the queue has been streamed off.
+    pub canceled_buffers: Vec<CanceledBuffer<P>>,
+}
+
+/// Error returned by `Queue::renegotiate`. Each variant returns the queue in
+/// the state it was when the error occurred, along with the buffers canceled
+/// so far, so no handle is lost and the client can decide how to recover.
+#[derive(Error)]
+pub enum RenegotiateError<D: Direction, P: BufferHandles> {
+    #[error("error while streaming off: {error}")]
+    StreamOff {
+        #[source]
+        error: StreamOffError,
+        queue: Queue<D, BuffersAllocated<P>>,
+    },
+    #[error("error while freeing buffers: {error}")]
+    FreeBuffers {
+        #[source]
+        error: ReqbufsError,
+        queue: Queue<D, BuffersAllocated<P>>,
+        canceled_buffers: Vec<CanceledBuffer<P>>,
+    },
+    #[error("error while setting format: {error}")]
+    SetFormat {
+        #[source]
+        error: SFmtError,
+        queue: Queue<D, QueueInit>,
+        canceled_buffers: Vec<CanceledBuffer<P>>,
+    },
+    #[error("error while requesting buffers: {error}")]
+    RequestBuffers {
+        #[source]
+        error: RequestBuffersError,
+        queue: Queue<D, QueueInit>,
+        canceled_buffers: Vec<CanceledBuffer<P>>,
+    },
+}
+
+impl<D: Direction, P: BufferHandles> OsError for RenegotiateError<D, P> {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            RenegotiateError::StreamOff { error, .. } => error.errno(),
+            RenegotiateError::FreeBuffers { error, .. } => error.errno(),
+            RenegotiateError::SetFormat { error, .. } => error.errno(),
+            RenegotiateError::RequestBuffers { error, .. } => error.errno(),
+        }
+    }
+}
+
+/// The queue is dropped in the conversion.
+impl<D: Direction, P: BufferHandles> From<RenegotiateError<D, P>> for std::io::Error {
+    fn from(err: RenegotiateError<D, P>) -> Self {
+        match err {
+            RenegotiateError::StreamOff { error, .. } => error.into(),
+            RenegotiateError::FreeBuffers { error, .. } => error.into(),
+            RenegotiateError::SetFormat { error, .. } => error.into(),
+            RenegotiateError::RequestBuffers { error, .. } => error.into(),
+        }
+    }
+}
+
+impl<D: Direction, P: BufferHandles> std::fmt::Debug for RenegotiateError<D, P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RenegotiateError::StreamOff { error, .. } => {
+                f.debug_tuple("StreamOff").field(error).finish()
+            }
+            RenegotiateError::FreeBuffers { error, .. } => {
+                f.debug_tuple("FreeBuffers").field(error).finish()
+            }
+            RenegotiateError::SetFormat { error, .. } => {
+                f.debug_tuple("SetFormat").field(error).finish()
+            }
+            RenegotiateError::RequestBuffers { error, .. } => {
+                f.debug_tuple("RequestBuffers").field(error).finish()
+            }
+        }
+    }
+}
+
 impl<D: Direction, P: BufferHandles> Queue<D, BuffersAllocated<P>> {
+    /// Sets the policy deciding which buffer is returned by `get_free` when
+    /// several buffers are free. Meant to be called right after allocating
+    /// the buffers, e.g. `queue.request_buffers(4)?.set_free_buffer_policy(..)`.
+    pub fn set_free_buffer_policy(mut self, policy: FreeBufferPolicy) -> Self {
+        self.state.free_buffer_policy = policy;
+        self
+    }
+
+    /// Returns the policy set with `set_free_buffer_policy`.
+    pub fn free_buffer_policy(&self) -> FreeBufferPolicy {
+        self.state.free_buffer_policy
+    }
+
+    /// Returns a snapshot of the current state of each buffer of the queue.
+    ///
+    /// The states may change as soon as this method returns if buffers are
+    /// being queued or dequeued from other threads.
+    pub fn buffer_states(&self) -> BufferStates {
+        BufferStates(
+            self.state
+                .buffer_info
+                .iter()
+                .map(|buffer_info| buffer_info.do_with_state(|state| BufferStatus::from(state)))
+                .collect(),
+        )
+    }
+
+    /// Change the format of the queue and reallocate `count` buffers of the
+    /// same memory type, without having to drop the queue and the objects
+    /// built on top of it.
+    ///
+    /// The queue is streamed off and its buffers freed, then `format` is set
+    /// and the new buffers allocated with the memory flags and free buffer
+    /// policy of the previous ones. The buffers that were queued are returned
+    /// as canceled along with the new queue, which is left streamed off.
+    ///
+    /// Buffers reserved with `ReserveBufferByIndex` stay reserved if a buffer
+    /// with the same index is allocated again, and their reservation is
+    /// dropped otherwise.
+    ///
+    /// The buffers cannot be freed while the client still holds some of them,
+    /// e.g. as `DqBuffer`s or mappings obtained from them: the driver then
+    /// fails with `EBUSY` and `RenegotiateError::FreeBuffers` is returned
+    /// with the streamed off queue. Dropping these buffers before retrying lets
+    /// the renegotiation succeed.
+    pub fn renegotiate(
+        self,
+        format: Format,
+        count: u32,
+    ) -> Result<RenegotiateResult<D, P>, RenegotiateError<D, P>> {
+        let mut canceled_buffers = match self.stream_off() {
+            Ok(canceled_buffers) => canceled_buffers,
+            Err(error) => return Err(RenegotiateError::StreamOff { error, queue: self }),
+        };
+
+        let memory_type = self.state.memory_type;
+        let memory_flags = self.state.memory_flags;
+        let free_buffer_policy = self.state.free_buffer_policy;
+        let reserved: Vec<usize> = self
+            .state
+            .buffer_info
+            .iter()
+            .enumerate()
+            .filter(|(_, buffer_info)| buffer_info.is_reserved())
+            .map(|(index, _)| index)
+            .collect();
+
+        let mut queue = match self.try_free_buffers() {
+            Ok(result) => {
+                canceled_buffers.extend(result.canceled_buffers);
+                result.queue
+            }
+            Err(FreeBuffersError { error, queue }) => {
+                return Err(RenegotiateError::FreeBuffers {
+                    error,
+                    queue,
+                    canceled_buffers,
+                })
+            }
+        };
+
+        let format = match queue.set_format(format) {
+            Ok(format) => format,
+            Err(error) => {
+                return Err(RenegotiateError::SetFormat {
+                    error,
+                    queue,
+                    canceled_buffers,
+                })
+            }
+        };
+
+        match queue.allocate(memory_type, count, memory_flags) {
+            Ok(state) => {
+                for index in reserved {
+                    if let Some(buffer_info) = state.buffer_info.get(index) {
+                        buffer_info.reserve();
+                    }
+                }
+
+                Ok(RenegotiateResult {
+                    queue: Queue {
+                        inner: queue.inner,
+                        _d: std::marker::PhantomData,
+                        state: BuffersAllocated {
+                            free_buffer_policy,
+                            ..state
+                        },
+                    },
+                    format,
+                    canceled_buffers,
+                })
+            }
+            Err(error) => Err(RenegotiateError::RequestBuffers {
+                error,
+                queue,
+                canceled_buffers,
+            }),
+        }
+    }
+
+    /// Returns the number of buffers dequeued with the `ERROR` flag set since
+    /// the buffers have been allocated.
+    pub fn num_error_buffers(&self) -> u64 {
+        self.state.buffer_stats.num_errors()
+    }
+
     /// Return all the currently queued buffers as CanceledBuffers. This can
     /// be called after a explicit or implicit streamoff to inform the client
     /// of which buffers have been canceled and return their handles.
@@ -434,9 +1174,13 @@ impl<'a, D: Direction, P: BufferHandles + 'a> AllocatedQueue<'a, D>
         self.state.buffer_stats.num_free()
     }
 
-    fn free_buffers(self) -> Result<FreeBuffersResult<D, Self>, ioctl::ReqbufsError> {
+    fn try_free_buffers(self) -> Result<FreeBuffersResult<D, Self>, FreeBuffersError<Self>> {
         let type_ = self.inner.type_;
-        ioctl::reqbufs(&self.inner, type_, self.state.memory_type.into(), 0)?;
+        if let Err(error) =
+            ioctl::reqbufs::<()>(&self.inner, type_, self.state.memory_type.into(), 0)
+        {
+            return Err(FreeBuffersError { error, queue: self });
+        }
 
         debug!("Freed all buffers on {} queue", type_);
 
@@ -455,6 +1199,38 @@ impl<'a, D: Direction, P: BufferHandles + 'a> AllocatedQueue<'a, D>
     }
 }
 
+impl<D: Direction, P: BufferHandles> ReserveBufferByIndex for Queue<D, BuffersAllocated<P>> {
+    fn reserve_buffer(&self, index: usize) -> Result<(), TryGetBufferError> {
+        let buffer_info = self
+            .state
+            .buffer_info
+            .get(index)
+            .ok_or(TryGetBufferError::InvalidIndex(index))?;
+
+        if buffer_info.reserve() {
+            Ok(())
+        } else {
+            Err(TryGetBufferError::AlreadyReserved(index))
+        }
+    }
+
+    fn unreserve_buffer(&self, index: usize) -> Result<bool, TryGetBufferError> {
+        self.state
+            .buffer_info
+            .get(index)
+            .map(|buffer_info| buffer_info.unreserve())
+            .ok_or(TryGetBufferError::InvalidIndex(index))
+    }
+
+    fn is_buffer_reserved(&self, index: usize) -> bool {
+        self.state
+            .buffer_info
+            .get(index)
+            .map(|buffer_info| buffer_info.is_reserved())
+            .unwrap_or(false)
+    }
+}
+
 /// Represents a queued buffer which has not been processed due to `streamoff`
 /// being called on a queue.
 pub struct CanceledBuffer<P: BufferHandles> {
@@ -489,6 +1265,14 @@ impl<D: Direction, P: BufferHandles> TryDequeue for Queue<D, BuffersAllocated<P>
         let dqbuf: ioctl::V4l2Buffer = ioctl::dqbuf(&self.inner, self.inner.type_)?;
 
         let id = dqbuf.index() as usize;
+        if dqbuf.flags().contains(ioctl::BufferFlags::ERROR) {
+            debug!(
+                "Buffer {} of {} queue dequeued with error",
+                id,
+                self.get_type()
+            );
+            self.state.buffer_stats.record_error();
+        }
 
         let buffer_info = self
             .state
@@ -571,16 +1355,19 @@ mod private {
         Self: GetBufferByIndex<'a>,
     {
         fn try_get_free_buffer(&'a self) -> Result<Self::Queueable, GetFreeBufferError> {
-            let res = self
+            let candidates = self
                 .state
                 .buffer_info
                 .iter()
                 .enumerate()
-                .find(|(_, s)| s.do_with_state(|s| matches!(s, BufferState::Free)));
+                .filter(|(_, s)| {
+                    !s.is_reserved() && s.do_with_state(|s| matches!(s, BufferState::Free))
+                })
+                .map(|(i, s)| (i, s.freed_at()));
 
-            match res {
+            match self.state.free_buffer_policy.select(candidates) {
                 None => Err(GetFreeBufferError::NoFreeBuffer),
-                Some((i, _)) => Ok(self.try_get_buffer(i).unwrap()),
+                Some(i) => Ok(self.try_get_buffer(i).unwrap()),
             }
         }
     }
@@ -604,6 +1391,36 @@ where
     type Queueable = <Self as private::GetBufferByIndex<'a>>::Queueable;
 }
 
+impl<'a, D: Direction, P: PrimitiveBufferHandles> QueueableProvider<'a, P>
+    for Queue<D, BuffersAllocated<P>>
+where
+    Self: private::GetBufferByIndex<'a>,
+    <Self as private::GetBufferByIndex<'a>>::Queueable: Queueable<P>,
+{
+    type Queueable = <Self as private::GetBufferByIndex<'a>>::Queueable;
+}
+
+impl<'a, D: Direction, P: BufferHandles, R> GetBufferByIndex<'a, P>
+    for Queue<D, BuffersAllocated<P>>
+where
+    Self: private::GetBufferByIndex<'a, Queueable = R>,
+    Self: QueueableProvider<'a, P, Queueable = R>,
+{
+    fn try_get_buffer(&'a self, index: usize) -> Result<Self::Queueable, TryGetBufferError> {
+        <Self as private::GetBufferByIndex<'a>>::try_get_buffer(self, index)
+    }
+}
+
+impl<'a, D: Direction, P: BufferHandles, R> GetFreeBuffer<'a, P> for Queue<D, BuffersAllocated<P>>
+where
+    Self: private::GetFreeBuffer<'a, Queueable = R>,
+    Self: QueueableProvider<'a, P, Queueable = R>,
+{
+    fn try_get_free_buffer(&'a self) -> Result<Self::Queueable, GetFreeBufferError> {
+        <Self as private::GetFreeBuffer<'a>>::try_get_free_buffer(self)
+    }
+}
+
 impl<'a, P: BufferHandles, R> GetOutputBufferByIndex<'a, P> for Queue<Output, BuffersAllocated<P>>
 where
     Self: private::GetBufferByIndex<'a, Queueable = R>,
@@ -686,3 +1503,240 @@ impl<P: BufferHandles> Drop for BufferStateFuse<P> {
         self.trigger();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_are_send() {
+        fn assert_send<T: Send>() {}
+
+        assert_send::<M2mQueues>();
+        assert_send::<Queue<Output, BuffersAllocated<Vec<MmapHandle>>>>();
+        assert_send::<Queue<Capture, BuffersAllocated<Vec<MmapHandle>>>>();
+    }
+
+    #[test]
+    fn format_adjusted_fields() {
+        let requested = Format {
+            width: 640,
+            height: 480,
+            pixelformat: b"NV12".into(),
+            plane_fmt: vec![PlaneLayout {
+                sizeimage: 0,
+                bytesperline: 640,
+            }],
+        };
+        let mut applied = requested.clone();
+        applied.plane_fmt[0].sizeimage = 460800;
+        let all = RequestedFields {
+            size: true,
+            pixelformat: true,
+            planes_layout: true,
+            colorimetry: true,
+        };
+        let colorimetry = Colorimetry::default();
+        assert!(
+            adjusted_fields((&requested, colorimetry), all, (&applied, colorimetry)).is_empty()
+        );
+
+        applied.height = 720;
+        applied.plane_fmt[0].bytesperline = 704;
+        let diff = adjusted_fields((&requested, colorimetry), all, (&applied, colorimetry));
+        assert_eq!(
+            diff.changes().iter().map(|c| c.field()).collect::<Vec<_>>(),
+            vec![FormatField::Height, FormatField::BytesPerLine(0)]
+        );
+        // Fields not set by the client are not reported.
+        let fields = RequestedFields {
+            pixelformat: true,
+            ..Default::default()
+        };
+        assert!(
+            adjusted_fields((&requested, colorimetry), fields, (&applied, colorimetry)).is_empty()
+        );
+
+        // Colorimetry fields left to `Default` are chosen by the driver.
+        let requested_colorimetry = Colorimetry {
+            colorspace: Colorspace::Rec709,
+            quantization: Quantization::LimRange,
+            ..Default::default()
+        };
+        let mut applied_colorimetry = Colorimetry {
+            xfer_func: XferFunc::F709,
+            ycbcr_enc: YCbCrEncoding::E709,
+            ..requested_colorimetry
+        };
+        let applied_format = requested.clone();
+        assert!(adjusted_fields(
+            (&requested, requested_colorimetry),
+            all,
+            (&applied_format, applied_colorimetry)
+        )
+        .is_empty());
+        applied_colorimetry.quantization = Quantization::FullRange;
+        let colorimetry_diff = adjusted_fields(
+            (&requested, requested_colorimetry),
+            all,
+            (&applied_format, applied_colorimetry),
+        );
+        assert_eq!(
+            colorimetry_diff
+                .changes()
+                .iter()
+                .map(|c| c.field())
+                .collect::<Vec<_>>(),
+            vec![FormatField::Colorimetry]
+        );
+        assert!(adjusted_fields(
+            (&requested, requested_colorimetry),
+            RequestedFields::default(),
+            (&applied_format, applied_colorimetry)
+        )
+        .is_empty());
+
+        let mut diff = diff;
+        diff.retain(|c| c.field() != FormatField::Height);
+        let outcome = FormatOutcome::Adjusted {
+            requested,
+            applied: applied.clone(),
+            diff,
+        };
+        assert!(!outcome.is_exact());
+        assert_eq!(
+            outcome
+                .clone()
+                .ensure_unchanged(|f| *f == FormatField::PixelFormat)
+                .unwrap(),
+            applied
+        );
+        assert_eq!(outcome.ensure_exact().unwrap_err().diff.len(), 1);
+    }
+
+    #[test]
+    fn queue_capabilities_check() {
+        use ioctl::Capabilities;
+
+        let m2m = Capabilities::VIDEO_M2M_MPLANE | Capabilities::STREAMING;
+        assert!(check_queue_capabilities(QueueType::VideoCaptureMplane, m2m).is_ok());
+        assert!(check_queue_capabilities(QueueType::VideoOutputMplane, m2m).is_ok());
+        assert!(matches!(
+            check_queue_capabilities(QueueType::VideoCapture, m2m),
+            Err(CreateQueueError::MissingCapability(
+                QueueType::VideoCapture,
+                caps
+            )) if caps.bits() == (Capabilities::VIDEO_CAPTURE | Capabilities::VIDEO_M2M).bits()
+        ));
+        assert!(matches!(
+            check_queue_capabilities(QueueType::VbiCapture, Capabilities::VBI_CAPTURE),
+            Err(CreateQueueError::MissingCapability(_, caps))
+                if caps.bits() == Capabilities::STREAMING.bits()
+        ));
+        assert_eq!(
+            (Capabilities::VBI_CAPTURE | Capabilities::VIDEO_OUTPUT).queue_types(),
+            vec![QueueType::VideoOutput, QueueType::VbiCapture]
+        );
+        assert!(Capabilities::RADIO.queue_types().is_empty());
+    }
+
+    #[cfg(feature = "ioctl-trace")]
+    #[test]
+    fn renegotiate() {
+        use std::fs::File;
+
+        use crate::ioctl::trace::testing::*;
+        use crate::ioctl::trace::{replay, Trace, TraceEntry};
+
+        let ok = |request| entry(request, Ok(0));
+        // Ioctls performed when reallocating `count` buffers.
+        let renegotiate_entries = |count| {
+            let mut entries = vec![ok(STREAMOFF), ok(REQBUFS), ok(S_FMT), ok(REQBUFS)];
+            entries.extend((0..count).map(|_| ok(QUERYBUF)));
+            Trace { entries }
+        };
+
+        let mut open_entries: Vec<TraceEntry> = vec![
+            querycap_entry(
+                "test",
+                ioctl::Capabilities::VIDEO_CAPTURE | ioctl::Capabilities::STREAMING,
+            ),
+            ok(G_FMT),
+            ok(REQBUFS),
+            entry(CREATE_BUFS, Err(Errno::ENOTTY)),
+        ];
+        open_entries.extend(renegotiate_entries(2).entries.drain(3..));
+        let queue = replay(
+            &Trace {
+                entries: open_entries,
+            },
+            || {
+                let device = Arc::new(Device::new(File::open("/dev/null").unwrap()).unwrap());
+                Queue::get_capture_queue(device)
+                    .unwrap()
+                    .request_buffers::<Vec<MmapHandle>>(2)
+                    .unwrap()
+            },
+        )
+        .unwrap();
+        assert_eq!(queue.num_buffers(), 2);
+        queue.reserve_buffer(1).unwrap();
+
+        // The buffers are reallocated with the new format and keep their
+        // reservations.
+        let format = Format::from((b"NV12", (64, 64)));
+        let result = replay(&renegotiate_entries(3), || {
+            queue.renegotiate(format.clone(), 3)
+        })
+        .unwrap()
+        .map_err(|e| e.to_string())
+        .unwrap();
+        assert_eq!(result.format.pixelformat, format.pixelformat);
+        assert!(result.canceled_buffers.is_empty());
+        let queue = result.queue;
+        assert_eq!(queue.num_buffers(), 3);
+        assert!(queue.is_buffer_reserved(1));
+        assert!(!queue.is_buffer_reserved(0));
+
+        // Reservations of buffers that are not allocated again are dropped.
+        let queue = replay(&renegotiate_entries(1), || {
+            queue.renegotiate(format.clone(), 1)
+        })
+        .unwrap()
+        .map_err(|e| e.to_string())
+        .unwrap()
+        .queue;
+        let queue = replay(&renegotiate_entries(2), || {
+            queue.renegotiate(format.clone(), 2)
+        })
+        .unwrap()
+        .map_err(|e| e.to_string())
+        .unwrap()
+        .queue;
+        assert!(!queue.is_buffer_reserved(1));
+
+        // Buffers still held by the client cannot be freed.
+        let busy = Trace {
+            entries: vec![ok(STREAMOFF), entry(REQBUFS, Err(Errno::EBUSY))],
+        };
+        let queue = match replay(&busy, || queue.renegotiate(format.clone(), 2)).unwrap() {
+            Err(RenegotiateError::FreeBuffers { error, queue, .. }) => {
+                assert_eq!(error.errno(), Some(Errno::EBUSY));
+                queue
+            }
+            _ => panic!("freeing the buffers should have failed"),
+        };
+        assert_eq!(queue.num_buffers(), 2);
+
+        // A format error leaves the queue without buffers.
+        let invalid_format = Trace {
+            entries: vec![ok(STREAMOFF), ok(REQBUFS), entry(S_FMT, Err(Errno::EBUSY))],
+        };
+        match replay(&invalid_format, || queue.renegotiate(format.clone(), 2)).unwrap() {
+            Err(RenegotiateError::SetFormat { error, .. }) => {
+                assert_eq!(error.errno(), Some(Errno::EBUSY));
+            }
+            _ => panic!("setting the format should have failed"),
+        }
+    }
+}
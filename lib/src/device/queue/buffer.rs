@@ -1,10 +1,12 @@
 use super::BufferHandles;
 use crate::ioctl;
 
+use std::fmt;
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     Arc, Mutex,
 };
+use std::time::Instant;
 
 /// Represents the current state of an allocated buffer.
 pub(super) enum BufferState<P: BufferHandles> {
@@ -19,12 +21,88 @@ pub(super) enum BufferState<P: BufferHandles> {
     Dequeued,
 }
 
+/// Public snapshot of the state of a buffer, as returned by
+/// `Queue::buffer_states()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferStatus {
+    /// The buffer can be obtained and queued.
+    Free,
+    /// The buffer has been obtained but is not queued yet.
+    PreQueue,
+    /// The buffer is queued and waiting to be dequeued.
+    Queued,
+    /// The buffer has been dequeued and is still in use by the client.
+    Dequeued,
+}
+
+impl<P: BufferHandles> From<&BufferState<P>> for BufferStatus {
+    fn from(state: &BufferState<P>) -> Self {
+        match state {
+            BufferState::Free => BufferStatus::Free,
+            BufferState::PreQueue => BufferStatus::PreQueue,
+            BufferState::Queued(_) => BufferStatus::Queued,
+            BufferState::Dequeued => BufferStatus::Dequeued,
+        }
+    }
+}
+
+impl fmt::Display for BufferStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BufferStatus::Free => "free",
+            BufferStatus::PreQueue => "pre-queue",
+            BufferStatus::Queued => "queued",
+            BufferStatus::Dequeued => "dequeued",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Snapshot of the state of all the buffers of a queue, indexed by buffer
+/// index.
+///
+/// Its `Display` implementation lists where each buffer is, which is useful to
+/// debug stuck pipelines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufferStates(pub Vec<BufferStatus>);
+
+impl BufferStates {
+    /// Returns the number of buffers currently in state `status`.
+    pub fn count(&self, status: BufferStatus) -> usize {
+        self.0.iter().filter(|s| **s == status).count()
+    }
+}
+
+impl fmt::Display for BufferStates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} free, {} pre-queue, {} queued, {} dequeued [",
+            self.count(BufferStatus::Free),
+            self.count(BufferStatus::PreQueue),
+            self.count(BufferStatus::Queued),
+            self.count(BufferStatus::Dequeued),
+        )?;
+        for (index, status) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}: {}", index, status)?;
+        }
+        f.write_str("]")
+    }
+}
+
 /// Structure that allows a queue and its users to keep track of how many buffers are available for
 /// use and currently queued.
 #[derive(Default)]
 pub(super) struct BufferStats {
     num_free: AtomicUsize,
     num_queued: AtomicUsize,
+    /// Incremented every time a buffer becomes free.
+    free_counter: AtomicU64,
+    /// Number of buffers dequeued with the `ERROR` flag set.
+    num_errors: AtomicU64,
 }
 
 impl BufferStats {
@@ -34,6 +112,8 @@ impl BufferStats {
         Self {
             num_free: AtomicUsize::new(0),
             num_queued: AtomicUsize::new(0),
+            free_counter: AtomicU64::new(0),
+            num_errors: AtomicU64::new(0),
         }
     }
 
@@ -44,6 +124,14 @@ impl BufferStats {
     pub fn num_queued(&self) -> usize {
         self.num_queued.load(Ordering::Relaxed)
     }
+
+    pub fn num_errors(&self) -> u64 {
+        self.num_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn record_error(&self) {
+        self.num_errors.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 pub(super) struct BufferInfo<P: BufferHandles> {
@@ -51,8 +139,18 @@ pub(super) struct BufferInfo<P: BufferHandles> {
     pub(super) features: ioctl::QueryBuffer,
     /// Current state of the buffer.
     state: Mutex<BufferState<P>>,
+    /// Whether the buffer has been reserved by the client, in which case it
+    /// can only be obtained by index until it is queued.
+    reserved: AtomicBool,
+    /// Value of the queue's free counter when the buffer last became free.
+    freed_at: AtomicU64,
+    /// When the buffer has last been queued.
+    queued_at: Mutex<Option<Instant>>,
     /// Link to the queue's buffer stats, so we can update them as the buffer state changes.
     stats: Arc<BufferStats>,
+    /// Mappings of the memory objects of the buffer, created the first time
+    /// all its planes are mapped and kept until the buffer is freed.
+    mappings: Mutex<Option<Arc<[ioctl::PlaneMapping]>>>,
 }
 
 impl<P: BufferHandles> Drop for BufferInfo<P> {
@@ -66,6 +164,10 @@ impl<P: BufferHandles> BufferInfo<P> {
         stats.num_free.fetch_add(1, Ordering::Relaxed);
         Self {
             state: Mutex::new(BufferState::Free),
+            reserved: AtomicBool::new(false),
+            freed_at: AtomicU64::new(stats.free_counter.fetch_add(1, Ordering::Relaxed)),
+            queued_at: Mutex::new(None),
+            mappings: Mutex::new(None),
             features,
             stats: Arc::clone(&stats),
         }
@@ -77,6 +179,46 @@ impl<P: BufferHandles> BufferInfo<P> {
         f(&*self.state.lock().unwrap())
     }
 
+    /// Reserve the buffer. Returns `false` if it was already reserved.
+    pub(super) fn reserve(&self) -> bool {
+        !self.reserved.swap(true, Ordering::SeqCst)
+    }
+
+    /// Cancel the reservation of the buffer. Returns `false` if it was not
+    /// reserved.
+    pub(super) fn unreserve(&self) -> bool {
+        self.reserved.swap(false, Ordering::SeqCst)
+    }
+
+    pub(super) fn is_reserved(&self) -> bool {
+        self.reserved.load(Ordering::SeqCst)
+    }
+
+    /// Returns the value of the queue's free counter when the buffer last
+    /// became free, which allows to order free buffers by age.
+    pub(super) fn freed_at(&self) -> u64 {
+        self.freed_at.load(Ordering::Relaxed)
+    }
+
+    /// Returns when the buffer has last been queued, if it ever was.
+    pub(super) fn queued_at(&self) -> Option<Instant> {
+        *self.queued_at.lock().unwrap()
+    }
+
+    /// Returns the cached mappings of the buffer, creating them with `map` if
+    /// they do not exist yet. Nothing is cached if `map` fails.
+    pub(super) fn mappings<F>(&self, map: F) -> Option<Arc<[ioctl::PlaneMapping]>>
+    where
+        F: FnOnce() -> Option<Vec<ioctl::PlaneMapping>>,
+    {
+        let mut mappings = self.mappings.lock().unwrap();
+        if mappings.is_none() {
+            *mappings = Some(map()?.into());
+        }
+
+        mappings.clone()
+    }
+
     /// Update the buffer's state. The queue's stats will be updated to reflect the new state
     /// decided by `f`.
     pub(super) fn update_state<R, F: FnOnce(&mut BufferState<P>) -> R>(&self, f: F) -> R {
@@ -91,8 +233,17 @@ impl<P: BufferHandles> BufferInfo<P> {
         let res = f(&mut *state);
 
         match *state {
-            BufferState::Free => self.stats.num_free.fetch_add(1, Ordering::Relaxed),
-            BufferState::Queued(_) => self.stats.num_queued.fetch_add(1, Ordering::Relaxed),
+            BufferState::Free => {
+                self.freed_at.store(
+                    self.stats.free_counter.fetch_add(1, Ordering::Relaxed),
+                    Ordering::Relaxed,
+                );
+                self.stats.num_free.fetch_add(1, Ordering::Relaxed)
+            }
+            BufferState::Queued(_) => {
+                *self.queued_at.lock().unwrap() = Some(Instant::now());
+                self.stats.num_queued.fetch_add(1, Ordering::Relaxed)
+            }
             _ => 0,
         };
 
@@ -178,4 +329,81 @@ mod tests {
         assert_eq!(buffer_stats.num_free(), NUM_BUFFERS);
         assert_eq!(buffer_stats.num_queued(), 0);
     }
+
+    #[test]
+    fn test_buffer_freed_at() {
+        let buffer_stats = Arc::new(BufferStats::new());
+        let buffers = (0..2)
+            .map(|i| {
+                let querybuf = ioctl::QueryBuffer {
+                    index: i,
+                    flags: ioctl::BufferFlags::empty(),
+                    planes: Default::default(),
+                };
+                BufferInfo::<Vec<MmapHandle>>::new(querybuf, Arc::clone(&buffer_stats))
+            })
+            .collect::<Vec<_>>();
+        assert!(buffers[0].freed_at() < buffers[1].freed_at());
+
+        buffers[0].update_state(|s| *s = BufferState::Dequeued);
+        assert!(buffers[0].freed_at() < buffers[1].freed_at());
+        buffers[0].update_state(|s| *s = BufferState::Free);
+        assert!(buffers[0].freed_at() > buffers[1].freed_at());
+    }
+
+    #[test]
+    fn test_buffer_queued_at() {
+        let querybuf = ioctl::QueryBuffer {
+            index: 0,
+            flags: ioctl::BufferFlags::empty(),
+            planes: Default::default(),
+        };
+        let buffer: BufferInfo<Vec<MmapHandle>> =
+            BufferInfo::new(querybuf, Arc::new(BufferStats::new()));
+        assert_eq!(buffer.queued_at(), None);
+
+        let before = Instant::now();
+        buffer.update_state(|s| *s = BufferState::Queued(Default::default()));
+        let queued_at = buffer.queued_at().unwrap();
+        assert!(queued_at >= before);
+
+        // Leaving the queued state keeps the time around for the dequeued
+        // buffer.
+        buffer.update_state(|s| *s = BufferState::Dequeued);
+        assert_eq!(buffer.queued_at(), Some(queued_at));
+    }
+
+    #[test]
+    fn test_buffer_reservation() {
+        let querybuf = ioctl::QueryBuffer {
+            index: 0,
+            flags: ioctl::BufferFlags::empty(),
+            planes: Default::default(),
+        };
+        let buffer: BufferInfo<Vec<MmapHandle>> =
+            BufferInfo::new(querybuf, Arc::new(BufferStats::new()));
+
+        assert!(!buffer.is_reserved());
+        assert!(!buffer.unreserve());
+        assert!(buffer.reserve());
+        assert!(buffer.is_reserved());
+        assert!(!buffer.reserve());
+        assert!(buffer.unreserve());
+        assert!(!buffer.is_reserved());
+    }
+
+    #[test]
+    fn test_buffer_states_display() {
+        let states = BufferStates(vec![
+            BufferStatus::Free,
+            BufferStatus::Queued,
+            BufferStatus::Queued,
+            BufferStatus::Dequeued,
+        ]);
+        assert_eq!(states.count(BufferStatus::Queued), 2);
+        assert_eq!(
+            states.to_string(),
+            "1 free, 0 pre-queue, 2 queued, 1 dequeued [0: free, 1: queued, 2: queued, 3: dequeued]"
+        );
+    }
 }
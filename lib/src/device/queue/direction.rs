@@ -2,14 +2,24 @@
 
 use std::fmt::Debug;
 
+use crate::QueueDirection;
+
 /// Represents the direction of a `Queue` (`Capture` or `Output`). The direction
 /// of a queue limits the operations that are possible on it.
-pub trait Direction: Debug + Send + 'static {}
+pub trait Direction: Debug + Send + 'static {
+    /// Runtime value of the direction, useful for code that is generic over
+    /// the direction but needs to behave differently depending on it.
+    const DIRECTION: QueueDirection;
+}
 /// Type for `OUTPUT` queues.
 #[derive(Debug)]
 pub struct Output;
-impl Direction for Output {}
+impl Direction for Output {
+    const DIRECTION: QueueDirection = QueueDirection::Output;
+}
 /// Type for `CAPTURE` queues.
 #[derive(Debug)]
 pub struct Capture;
-impl Direction for Capture {}
+impl Direction for Capture {
+    const DIRECTION: QueueDirection = QueueDirection::Capture;
+}
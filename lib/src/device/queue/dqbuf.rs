@@ -1,18 +1,49 @@
 //! Provides types related to dequeuing buffers from a `Queue` object.
 use super::{
     buffer::BufferInfo,
-    direction::{Capture, Direction},
+    direction::{Capture, Direction, Output},
     BufferStateFuse, BuffersAllocated, Queue,
 };
-use crate::ioctl::{self, PlaneMapping};
+use crate::bindings;
+use crate::ioctl::{self, BufferField, BufferFlags, PlaneMapping, QueryBufPlane, V4l2PlaneInfo};
 use crate::{
     device::Device,
     memory::{BufferHandles, Mappable, PrimitiveBufferHandles},
 };
 use std::{
+    cmp::min,
     fmt::Debug,
+    io::{self, IoSlice, Write},
+    ops::Range,
     sync::{Arc, Weak},
+    time::{Duration, Instant},
 };
+use thiserror::Error;
+
+/// A dequeued buffer for which the driver reported an error. Its content may be
+/// corrupted, but it is returned so its handles can be recovered or its data
+/// salvaged.
+#[derive(Debug, Error)]
+#[error("driver reported an error while processing the buffer")]
+pub struct FrameError<B> {
+    pub buffer: B,
+}
+
+/// The driver reports the error through a flag of the buffer, not through the
+/// result of a system call.
+impl<B: Debug> ioctl::OsError for FrameError<B> {
+    fn errno(&self) -> Option<nix::errno::Errno> {
+        None
+    }
+}
+
+/// Buffers are not `Sync`, so the error is converted into its message and the
+/// buffer is dropped, which returns it to its queue.
+impl<B: Debug> From<FrameError<B>> for io::Error {
+    fn from(err: FrameError<B>) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+    }
+}
 
 pub type DropCallback<D, P> = Box<dyn FnOnce(&mut DqBuffer<D, P>) + Send>;
 
@@ -20,11 +51,20 @@ pub type DropCallback<D, P> = Box<dyn FnOnce(&mut DqBuffer<D, P>) + Send>;
 /// information as what the `ioctl` interface provides, but it also includes
 /// the plane handles that have been provided when the buffer was queued to
 /// return their ownership to the user.
+///
+/// A `DqBuffer` is `Send` and can be moved to another thread, e.g. one that
+/// writes frames to a file or sends them over the network. It only keeps weak
+/// references to the device and the queue's buffer information, so the buffer
+/// returns to the `Free` state of its queue when dropped, no matter which thread
+/// drops it.
 pub struct DqBuffer<D: Direction, P: BufferHandles> {
     /// Dequeued buffer information as reported by V4L2.
     pub data: ioctl::V4l2Buffer,
     /// The backing memory that has been provided for this buffer.
     plane_handles: Option<P>,
+    /// When the buffer has been queued and dequeued.
+    queued_at: Option<Instant>,
+    dequeued_at: Instant,
 
     device: Weak<Device>,
     buffer_info: Weak<BufferInfo<P>>,
@@ -52,6 +92,8 @@ impl<D: Direction, P: BufferHandles> DqBuffer<D, P> {
     ) -> Self {
         DqBuffer {
             plane_handles: Some(plane_handles),
+            queued_at: buffer.queued_at(),
+            dequeued_at: Instant::now(),
             data,
             device: Arc::downgrade(&queue.inner.device),
             buffer_info: Arc::downgrade(buffer),
@@ -61,6 +103,23 @@ impl<D: Direction, P: BufferHandles> DqBuffer<D, P> {
         }
     }
 
+    /// Create a buffer that does not belong to any queue, so the code handling
+    /// dequeued buffers can be tested without a device.
+    #[cfg(test)]
+    pub(crate) fn new_detached(data: ioctl::V4l2Buffer, plane_handles: P) -> Self {
+        DqBuffer {
+            plane_handles: Some(plane_handles),
+            queued_at: None,
+            dequeued_at: Instant::now(),
+            data,
+            device: Weak::new(),
+            buffer_info: Weak::new(),
+            fuse: BufferStateFuse::new(Weak::new()),
+            drop_callbacks: Default::default(),
+            _d: std::marker::PhantomData,
+        }
+    }
+
     /// Attach a callback that will be called when the DQBuffer is destroyed,
     /// and after the buffer has been returned to the free list.
     /// This method can be called several times, the callback will be run in
@@ -74,6 +133,124 @@ impl<D: Direction, P: BufferHandles> DqBuffer<D, P> {
     pub fn take_handles(&mut self) -> Option<P> {
         self.plane_handles.take()
     }
+
+    /// Returns the index of the buffer in its queue.
+    pub fn index(&self) -> usize {
+        self.data.index() as usize
+    }
+
+    /// Returns the flags set by the driver on this buffer.
+    pub fn flags(&self) -> BufferFlags {
+        self.data.flags()
+    }
+
+    /// Returns the raw flags set by the driver, including those unknown to
+    /// `BufferFlags`.
+    pub fn raw_flags(&self) -> u32 {
+        self.data.raw_flags()
+    }
+
+    /// Returns whether the driver reported an error while processing this
+    /// buffer. Its content may be corrupted then.
+    pub fn has_error(&self) -> bool {
+        self.flags().contains(BufferFlags::ERROR)
+    }
+
+    /// Returns the buffer as a `FrameError` if the driver reported an error
+    /// while processing it, so it cannot be used without checking.
+    pub fn into_result(self) -> Result<Self, FrameError<Self>> {
+        if self.has_error() {
+            Err(FrameError { buffer: self })
+        } else {
+            Ok(self)
+        }
+    }
+
+    /// Returns whether this buffer contains a keyframe.
+    pub fn is_keyframe(&self) -> bool {
+        self.flags().contains(BufferFlags::KEYFRAME)
+    }
+
+    /// Returns whether this is the last buffer before a drain or resolution
+    /// change.
+    pub fn is_last(&self) -> bool {
+        self.data.is_last()
+    }
+
+    pub fn field(&self) -> BufferField {
+        self.data.field()
+    }
+
+    pub fn timestamp(&self) -> bindings::timeval {
+        self.data.timestamp()
+    }
+
+    /// Returns when the buffer has been queued to the driver.
+    pub fn queued_at(&self) -> Option<Instant> {
+        self.queued_at
+    }
+
+    /// Returns when the buffer has been dequeued from the driver.
+    pub fn dequeued_at(&self) -> Instant {
+        self.dequeued_at
+    }
+
+    /// Returns how long the buffer has spent in the driver, i.e. between its
+    /// queueing and dequeueing. This is the latency of the stage of the
+    /// pipeline handled by the driver for this queue.
+    pub fn time_in_driver(&self) -> Option<Duration> {
+        self.queued_at
+            .map(|queued_at| self.dequeued_at.saturating_duration_since(queued_at))
+    }
+
+    /// Returns the timecode of the buffer, if the driver provided one.
+    pub fn timecode(&self) -> Option<bindings::v4l2_timecode> {
+        self.data.timecode()
+    }
+
+    pub fn sequence(&self) -> u32 {
+        self.data.sequence()
+    }
+
+    pub fn num_planes(&self) -> usize {
+        self.data.num_planes()
+    }
+
+    /// Returns the `bytesused`, `length` and `data_offset` of plane
+    /// `plane_index`, or `None` if the buffer has no such plane.
+    pub fn plane_info(&self, plane_index: usize) -> Option<V4l2PlaneInfo> {
+        self.data
+            .planes_iter()
+            .nth(plane_index)
+            .map(|plane| plane.info())
+    }
+
+    /// Returns the plane information of all the planes of the buffer.
+    pub fn planes_info(&self) -> Vec<V4l2PlaneInfo> {
+        self.data.planes_iter().map(|plane| plane.info()).collect()
+    }
+}
+
+impl<P: BufferHandles> DqBuffer<Output, P> {
+    /// Return the plane handles of the buffer and immediately put the V4L2
+    /// buffer back into the `Free` state, without waiting for this object to
+    /// be dropped.
+    ///
+    /// This lets buffer pools recycle the memory of OUTPUT buffers as soon
+    /// as they are dequeued, while the metadata of the buffer (`data`) remains
+    /// accessible. Nothing refers to the V4L2 buffer anymore after this call,
+    /// so it can be obtained and queued again right away. Drop callbacks are
+    /// still run when this object is dropped, but will find no plane handles.
+    ///
+    /// Like `take_handles`, this returns `None` if the handles have already
+    /// been taken.
+    pub fn release_handles(&mut self) -> Option<P> {
+        let handles = self.plane_handles.take();
+        // Disarms the fuse, so dropping this object will not touch the state
+        // of the buffer anymore.
+        self.fuse.trigger();
+        handles
+    }
 }
 
 impl<P> DqBuffer<Capture, P>
@@ -95,6 +272,185 @@ where
 
         Some(P::HandleType::map(device.as_ref(), plane)?.restrict(start, end))
     }
+
+    /// Same as `get_plane_mapping`, but only maps `range` of the data of the plane, e.g. to read
+    /// the header of a large frame. `range` is relative to the start of the data of the plane and
+    /// is clamped to the number of bytes used.
+    pub fn get_plane_mapping_range(
+        &self,
+        plane_index: usize,
+        range: Range<u32>,
+    ) -> Option<PlaneMapping> {
+        let buffer_info = self.buffer_info.upgrade()?;
+        let plane = buffer_info.features.planes.get(plane_index)?;
+        let plane_data = self.data.planes_iter().nth(plane_index)?;
+        let device = self.device.upgrade()?;
+
+        let data_start = *plane_data.data_offset.unwrap_or(&0);
+        let data_end = data_start.saturating_add(*plane_data.bytesused);
+        let start = min(data_start.saturating_add(range.start), data_end);
+        let end = min(data_start.saturating_add(range.end), data_end).max(start);
+
+        P::HandleType::map_range(device.as_ref(), plane, start..end)
+    }
+
+    /// Returns views of the content of all the planes of the buffer, e.g. to
+    /// write a whole multi-planar frame with `PlaneMappings::write_to`.
+    ///
+    /// Planes sharing the same memory object are mapped only once, and the
+    /// mappings are cached with the buffer, so only the first call for a
+    /// given buffer performs any `mmap`. Returns `None` if any plane could not
+    /// be mapped.
+    pub fn get_plane_mappings(&self) -> Option<PlaneMappings> {
+        let buffer_info = self.buffer_info.upgrade()?;
+        let device = self.device.upgrade()?;
+
+        let (objects, plane_objects) = memory_objects(&buffer_info.features.planes);
+        let mappings = buffer_info.mappings(|| {
+            objects
+                .iter()
+                .map(|object| P::HandleType::map(device.as_ref(), object))
+                .collect()
+        })?;
+
+        let planes = plane_objects
+            .into_iter()
+            .zip(self.data.planes_iter())
+            .map(|(object, plane_data)| {
+                let len = mappings[object].data.len();
+                let info = plane_data.info();
+                let start = (info.data_offset as usize).min(len);
+                let end = (start + info.bytesused as usize).min(len);
+                (object, start..end)
+            })
+            .collect();
+
+        Some(PlaneMappings { mappings, planes })
+    }
+}
+
+/// Returns the distinct memory objects backing `planes`, identified by their
+/// memory offset, and the index of the object of each plane.
+fn memory_objects(planes: &[QueryBufPlane]) -> (Vec<QueryBufPlane>, Vec<usize>) {
+    let mut objects: Vec<QueryBufPlane> = Vec::new();
+    let plane_objects = planes
+        .iter()
+        .map(|plane| {
+            match objects
+                .iter()
+                .position(|o| o.mem_offset == plane.mem_offset)
+            {
+                Some(index) => {
+                    // Map the object entirely even if the planes report different lengths.
+                    objects[index].length = objects[index].length.max(plane.length);
+                    index
+                }
+                None => {
+                    objects.push(QueryBufPlane {
+                        mem_offset: plane.mem_offset,
+                        length: plane.length,
+                    });
+                    objects.len() - 1
+                }
+            }
+        })
+        .collect();
+
+    (objects, plane_objects)
+}
+
+/// Views of the content of all the planes of a dequeued buffer, as returned by
+/// `DqBuffer::get_plane_mappings`.
+pub struct PlaneMappings {
+    /// One mapping per distinct memory object of the buffer.
+    mappings: Arc<[PlaneMapping]>,
+    /// For each plane, index of its mapping and range of its data within it.
+    planes: Vec<(usize, Range<usize>)>,
+}
+
+impl PlaneMappings {
+    /// Returns the number of planes of the buffer.
+    pub fn len(&self) -> usize {
+        self.planes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.planes.is_empty()
+    }
+
+    /// Returns the data of plane `plane_index`.
+    pub fn plane(&self, plane_index: usize) -> Option<&[u8]> {
+        let (mapping, range) = self.planes.get(plane_index)?;
+        Some(&self.mappings[*mapping].data[range.clone()])
+    }
+
+    /// Returns an iterator over the data of each plane.
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        let mappings = &self.mappings;
+        self.planes
+            .iter()
+            .map(move |(mapping, range)| &mappings[*mapping].data[range.clone()])
+    }
+
+    /// Returns the data of all the planes as a single slice, if they share
+    /// the same mapping and each one directly follows the previous one. This
+    /// is the case of single-planar buffers, and of multi-planar buffers whose
+    /// planes the driver allocated together.
+    pub fn contiguous(&self) -> Option<&[u8]> {
+        let (first_mapping, first_range) = self.planes.first()?;
+        let mut end = first_range.end;
+        for (mapping, range) in &self.planes[1..] {
+            if mapping != first_mapping || range.start != end {
+                return None;
+            }
+            end = range.end;
+        }
+
+        Some(&self.mappings[*first_mapping].data[first_range.start..end])
+    }
+
+    /// Copies the data of all the planes, one after the other, into a single
+    /// buffer.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.iter().map(|p| p.len()).sum());
+        for plane in self.iter() {
+            data.extend_from_slice(plane);
+        }
+
+        data
+    }
+
+    /// Write the data of all the planes into `writer` using vectored writes,
+    /// without copying it into an intermediate buffer first. Returns the
+    /// number of bytes written.
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> io::Result<usize> {
+        let mut planes: Vec<&[u8]> = self.iter().filter(|p| !p.is_empty()).collect();
+        let total = planes.iter().map(|p| p.len()).sum();
+
+        // Index of the first plane that has not been fully written yet.
+        let mut first = 0;
+        while first < planes.len() {
+            let slices: Vec<IoSlice> = planes[first..].iter().map(|p| IoSlice::new(p)).collect();
+            let mut written = match writer.write_vectored(&slices) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(written) => written,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            // Skip the planes that have been fully written, and the written
+            // part of the next one.
+            while first < planes.len() && written >= planes[first].len() {
+                written -= planes[first].len();
+                first += 1;
+            }
+            if first < planes.len() {
+                planes[first] = &planes[first][written..];
+            }
+        }
+
+        Ok(total)
+    }
 }
 
 impl<D: Direction, P: BufferHandles> Drop for DqBuffer<D, P> {
@@ -107,3 +463,109 @@ impl<D: Direction, P: BufferHandles> Drop for DqBuffer<D, P> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{MmapHandle, UserPtrHandle};
+
+    #[test]
+    fn dqbuffer_into_result() {
+        let data = ioctl::V4l2Buffer::new(
+            crate::QueueType::VideoCapture,
+            1,
+            crate::memory::MemoryType::Mmap,
+        );
+        let buffer = DqBuffer::<Capture, Vec<MmapHandle>>::new_detached(data.clone(), Vec::new());
+        assert!(buffer.into_result().is_ok());
+
+        let mut data = data;
+        data.add_flags(BufferFlags::ERROR);
+        let buffer = DqBuffer::<Capture, Vec<MmapHandle>>::new_detached(data, Vec::new());
+        let error = buffer.into_result().unwrap_err();
+        assert_eq!(error.buffer.index(), 1);
+        assert_eq!(ioctl::OsError::errno(&error), None);
+        assert_eq!(io::Error::from(error).kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn dqbuffer_is_send() {
+        fn assert_send<T: Send>() {}
+
+        assert_send::<DqBuffer<Capture, Vec<MmapHandle>>>();
+        assert_send::<DqBuffer<Output, Vec<UserPtrHandle<Vec<u8>>>>>();
+    }
+
+    /// Writer accepting at most 3 bytes per call.
+    struct ShortWriter(Vec<u8>);
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn memory_objects_dedup() {
+        let plane = |mem_offset, length| QueryBufPlane { mem_offset, length };
+        let (objects, plane_objects) =
+            memory_objects(&[plane(0, 100), plane(4096, 50), plane(0, 200)]);
+        assert_eq!(
+            objects
+                .iter()
+                .map(|o| (o.mem_offset, o.length))
+                .collect::<Vec<_>>(),
+            vec![(0, 200), (4096, 50)]
+        );
+        assert_eq!(plane_objects, vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn plane_mappings_write_to() {
+        let path = std::env::temp_dir().join(format!("v4l2r-write-to-{}", std::process::id()));
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"0123456789abcdef").unwrap();
+        let map = || ioctl::mmap(&file, 0, 16).unwrap();
+        let mappings = PlaneMappings {
+            mappings: vec![map(), map()].into(),
+            planes: vec![(0, 0..5), (0, 8..8), (1, 10..14)],
+        };
+        let single = PlaneMappings {
+            mappings: vec![map()].into(),
+            planes: vec![(0, 2..6)],
+        };
+        // Planes of a multi-planar buffer sharing a single memory object.
+        let shared = PlaneMappings {
+            mappings: vec![map()].into(),
+            planes: vec![(0, 2..6), (0, 6..8), (0, 8..11)],
+        };
+        let gap = PlaneMappings {
+            mappings: vec![map()].into(),
+            planes: vec![(0, 2..6), (0, 7..8)],
+        };
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mappings.contiguous(), None);
+        assert_eq!(single.contiguous(), Some(&b"2345"[..]));
+        assert_eq!(shared.contiguous(), Some(&b"23456789a"[..]));
+        assert_eq!(shared.plane(1), Some(&b"67"[..]));
+        assert_eq!(gap.contiguous(), None);
+        assert_eq!(mappings.to_vec(), b"01234abcd");
+        assert_eq!(shared.to_vec(), b"23456789a");
+
+        let mut writer = ShortWriter(Vec::new());
+        assert_eq!(mappings.write_to(&mut writer).unwrap(), 9);
+        assert_eq!(writer.0, b"01234abcd");
+    }
+}
@@ -3,7 +3,7 @@ use crate::{
         direction::{Capture, Direction, Output},
         qbuf::{
             CaptureQueueable, CaptureQueueableProvider, OutputQueueable, OutputQueueableProvider,
-            QBuffer, QueueResult,
+            QBuffer, QueueResult, Queueable, QueueableProvider,
         },
         BuffersAllocated, Queue,
     },
@@ -143,6 +143,26 @@ impl OutputQueueable<GenericBufferHandles> for GenericQBuffer<'_, Output> {
     }
 }
 
+impl Queueable<GenericBufferHandles> for GenericQBuffer<'_, Capture> {
+    fn queue_with_handles_and_bytes_used(
+        self,
+        handles: GenericBufferHandles,
+        _: &[usize],
+    ) -> QueueResult<(), GenericBufferHandles> {
+        CaptureQueueable::queue_with_handles(self, handles)
+    }
+}
+
+impl Queueable<GenericBufferHandles> for GenericQBuffer<'_, Output> {
+    fn queue_with_handles_and_bytes_used(
+        self,
+        handles: GenericBufferHandles,
+        bytes_used: &[usize],
+    ) -> QueueResult<(), GenericBufferHandles> {
+        OutputQueueable::queue_with_handles(self, handles, bytes_used)
+    }
+}
+
 impl<'a> CaptureQueueableProvider<'a, GenericBufferHandles>
     for Queue<Capture, BuffersAllocated<GenericBufferHandles>>
 {
@@ -154,3 +174,11 @@ impl<'a> OutputQueueableProvider<'a, GenericBufferHandles>
 {
     type Queueable = GenericQBuffer<'a, Output>;
 }
+
+impl<'a, D: Direction> QueueableProvider<'a, GenericBufferHandles>
+    for Queue<D, BuffersAllocated<GenericBufferHandles>>
+where
+    GenericQBuffer<'a, D>: Queueable<GenericBufferHandles>,
+{
+    type Queueable = GenericQBuffer<'a, D>;
+}
@@ -3,13 +3,16 @@ use std::{
     fmt::Debug,
     sync::{Arc, Mutex, Weak},
     task::Wake,
+    time::{Duration, Instant},
 };
 
 use log::error;
+use nix::errno::Errno;
 
 use crate::{
     bindings,
     device::poller::Waker,
+    ioctl::OsError,
     memory::{BufferHandles, MmapHandle, PrimitiveBufferHandles},
     Format,
 };
@@ -30,6 +33,14 @@ pub enum GetSuitableBufferError {
     TryGetIndexed(#[from] TryGetBufferError),
 }
 
+impl OsError for GetSuitableBufferError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
+crate::ioctl::impl_into_io_error!(GetSuitableBufferError);
+
 pub trait HandlesProvider: Send + 'static {
     type HandleType: BufferHandles;
 
@@ -117,19 +128,112 @@ impl HandlesProvider for MmapProvider {
     }
 }
 
+/// Policy deciding when a `PooledHandlesProvider` releases the buffers it is
+/// not using, so a long-running service does not hold its peak allocation
+/// forever after a burst.
+///
+/// Only idle buffers, i.e. buffers that are currently in the pool, are ever
+/// released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShrinkPolicy {
+    /// Number of idle buffers that are always kept, no matter their age.
+    pub low_watermark: usize,
+    /// Maximum number of idle buffers. Excess buffers are released as soon as
+    /// they return to the pool.
+    pub high_watermark: usize,
+    /// Buffers idle for longer than this are released, as long as more than
+    /// `low_watermark` buffers are idle.
+    pub max_idle: Duration,
+}
+
+impl Default for ShrinkPolicy {
+    /// A policy that never releases buffers.
+    fn default() -> Self {
+        ShrinkPolicy {
+            low_watermark: usize::MAX,
+            high_watermark: usize::MAX,
+            max_idle: Duration::MAX,
+        }
+    }
+}
+
+/// Callback invoked with the handles of the buffers released by a
+/// `PooledHandlesProvider`.
+type ReleaseHook<H> = Box<dyn FnMut(H) + Send>;
+
 /// Internals of `PooledHandlesProvider`, which acts just as a protected wrapper
 /// around this structure.
 struct PooledHandlesProviderInternal<H: BufferHandles> {
-    buffers: VecDeque<H>,
+    /// Idle buffers, with the time at which they returned to the pool.
+    buffers: VecDeque<(H, Instant)>,
     waker: Option<Arc<Waker>>,
+    policy: ShrinkPolicy,
+    // Kept behind its own lock so it can be called without holding the pool
+    // lock, e.g. by a hook that needs to use the pool.
+    release_hook: Option<Arc<Mutex<ReleaseHook<H>>>>,
 }
 
 unsafe impl<H: BufferHandles> Send for PooledHandlesProviderInternal<H> {}
 
+/// Buffers removed from the pool by `shrink`, to be passed to the release hook
+/// once the pool lock is dropped.
+#[must_use]
+struct ReleasedHandles<H: BufferHandles> {
+    handles: Vec<H>,
+    hook: Option<Arc<Mutex<ReleaseHook<H>>>>,
+}
+
+impl<H: BufferHandles> ReleasedHandles<H> {
+    /// Passes the handles to the release hook, or drops them if there is none,
+    /// and returns how many have been released.
+    fn release(self) -> usize {
+        let released = self.handles.len();
+        if let Some(hook) = self.hook {
+            let mut hook = hook.lock().unwrap();
+            for handles in self.handles {
+                hook(handles);
+            }
+        }
+
+        released
+    }
+}
+
+impl<H: BufferHandles> PooledHandlesProviderInternal<H> {
+    /// Removes the idle buffers that `policy` allows to release at `now` from
+    /// the pool.
+    fn shrink(&mut self, now: Instant) -> ReleasedHandles<H> {
+        let mut released = Vec::new();
+
+        while self.buffers.len() > self.policy.low_watermark {
+            let expired = match self.buffers.front() {
+                Some((_, idle_since)) => now.duration_since(*idle_since) > self.policy.max_idle,
+                None => break,
+            };
+            if !expired && self.buffers.len() <= self.policy.high_watermark {
+                break;
+            }
+
+            // The oldest idle buffer is at the front of the queue.
+            let (handles, _) = self.buffers.pop_front().unwrap();
+            released.push(handles);
+        }
+
+        ReleasedHandles {
+            handles: released,
+            hook: self.release_hook.clone(),
+        }
+    }
+}
+
 /// A handles provider that recycles buffers from a fixed set in a pool.
 /// Provided `PooledHandles` will not be recycled for as long as the instance is
 /// alive. Once it is dropped, it the underlying buffer returns into the pool to
 /// be reused later.
+///
+/// By default the pool keeps all its buffers. `with_shrink_policy` makes it
+/// release idle buffers according to a `ShrinkPolicy`, either when buffers
+/// return to the pool or when `shrink` is called.
 pub struct PooledHandlesProvider<H: BufferHandles> {
     d: Arc<Mutex<PooledHandlesProviderInternal<H>>>,
 }
@@ -137,13 +241,48 @@ pub struct PooledHandlesProvider<H: BufferHandles> {
 impl<H: BufferHandles> PooledHandlesProvider<H> {
     /// Create a new `PooledMemoryProvider`, using the set in `buffers`.
     pub fn new<B: IntoIterator<Item = H>>(buffers: B) -> Self {
+        let now = Instant::now();
+
         Self {
             d: Arc::new(Mutex::new(PooledHandlesProviderInternal {
-                buffers: buffers.into_iter().collect(),
+                buffers: buffers.into_iter().map(|h| (h, now)).collect(),
                 waker: None,
+                policy: Default::default(),
+                release_hook: None,
             })),
         }
     }
+
+    /// Makes the pool release its idle buffers according to `policy`.
+    pub fn with_shrink_policy(self, policy: ShrinkPolicy) -> Self {
+        self.d.lock().unwrap().policy = policy;
+        self
+    }
+
+    /// Makes the pool pass the handles of the buffers it releases to `hook`
+    /// instead of just dropping them. This can be used to also free the
+    /// corresponding V4L2 buffers, e.g. with `VIDIOC_REMOVE_BUFS` on drivers
+    /// that support it.
+    pub fn with_release_hook<F: FnMut(H) + Send + 'static>(self, hook: F) -> Self {
+        self.d.lock().unwrap().release_hook = Some(Arc::new(Mutex::new(Box::new(hook))));
+        self
+    }
+
+    /// Returns the number of buffers currently idle in the pool.
+    pub fn num_idle(&self) -> usize {
+        self.d.lock().unwrap().buffers.len()
+    }
+
+    /// Releases the idle buffers allowed by the shrink policy, and returns how
+    /// many have been released.
+    ///
+    /// Buffers returning to the pool already trigger this, but services with
+    /// bursty workloads should also call it periodically so buffers left idle
+    /// after the last burst are eventually released.
+    pub fn shrink(&self) -> usize {
+        let released = self.d.lock().unwrap().shrink(Instant::now());
+        released.release()
+    }
 }
 
 impl<H: BufferHandles> HandlesProvider for PooledHandlesProvider<H> {
@@ -151,8 +290,11 @@ impl<H: BufferHandles> HandlesProvider for PooledHandlesProvider<H> {
 
     fn get_handles(&self, waker: &Arc<Waker>) -> Option<PooledHandles<H>> {
         let mut d = self.d.lock().unwrap();
-        match d.buffers.pop_front() {
-            Some(handles) => Some(PooledHandles::new(&self.d, handles)),
+        // Reuse the most recently returned buffer, so the buffers that are not
+        // needed in steady state age at the front of the queue and can be
+        // released by the shrink policy.
+        match d.buffers.pop_back() {
+            Some((handles, _)) => Some(PooledHandles::new(&self.d, handles)),
             None => {
                 d.waker = Some(Arc::clone(waker));
                 None
@@ -182,6 +324,25 @@ impl<H: BufferHandles> PooledHandles<H> {
     pub fn handles(&self) -> &H {
         self.handles.as_ref().unwrap()
     }
+
+    /// Returns the handles to the pool at `now` if it still exists, otherwise
+    /// the handles themselves are destroyed.
+    fn return_to_pool(&mut self, now: Instant) {
+        let (handles, provider) = match (self.handles.take(), self.provider.upgrade()) {
+            (Some(handles), Some(provider)) => (handles, provider),
+            _ => return,
+        };
+
+        let (released, waker) = {
+            let mut provider = provider.lock().unwrap();
+            provider.buffers.push_back((handles, now));
+            (provider.shrink(now), provider.waker.take())
+        };
+        released.release();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
 }
 
 impl<H: BufferHandles + Debug> Debug for PooledHandles<H> {
@@ -194,16 +355,7 @@ impl<H: BufferHandles> Drop for PooledHandles<H> {
     /// Return the handles to the pool if it still exists, otherwise the handles
     /// themselves are destroyed.
     fn drop(&mut self) {
-        match self.provider.upgrade() {
-            None => (),
-            Some(provider) => {
-                let mut provider = provider.lock().unwrap();
-                provider.buffers.push_back(self.handles.take().unwrap());
-                if let Some(waker) = provider.waker.take() {
-                    waker.wake();
-                }
-            }
-        }
+        self.return_to_pool(Instant::now());
     }
 }
 
@@ -223,3 +375,111 @@ impl<H: PrimitiveBufferHandles> PrimitiveBufferHandles for PooledHandles<H> {
     type HandleType = H::HandleType;
     const MEMORY_TYPE: Self::SupportedMemoryType = H::MEMORY_TYPE;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::memory::UserPtrHandle;
+
+    type Handles = Vec<UserPtrHandle<Vec<u8>>>;
+
+    fn pool(num_buffers: usize) -> (PooledHandlesProvider<Handles>, Arc<AtomicUsize>) {
+        let released = Arc::new(AtomicUsize::new(0));
+        let hook_released = Arc::clone(&released);
+        let provider = PooledHandlesProvider::new(
+            (0..num_buffers).map(|_| vec![UserPtrHandle(vec![0u8; 16])]),
+        )
+        .with_release_hook(move |_| {
+            hook_released.fetch_add(1, Ordering::SeqCst);
+        });
+
+        (provider, released)
+    }
+
+    #[test]
+    fn shrink_policy() {
+        // Without a policy, nothing is ever released.
+        let (provider, released) = pool(4);
+        assert_eq!(provider.shrink(), 0);
+        assert_eq!(provider.num_idle(), 4);
+        assert_eq!(released.load(Ordering::SeqCst), 0);
+
+        // Buffers above the high watermark are released even if recent.
+        let (provider, released) = pool(6);
+        let provider = provider.with_shrink_policy(ShrinkPolicy {
+            low_watermark: 1,
+            high_watermark: 4,
+            max_idle: Duration::from_secs(1),
+        });
+        assert_eq!(provider.shrink(), 2);
+        assert_eq!(provider.num_idle(), 4);
+        assert_eq!(released.load(Ordering::SeqCst), 2);
+
+        // Expired buffers are released down to the low watermark.
+        let now = Instant::now();
+        let released_handles = {
+            let mut d = provider.d.lock().unwrap();
+            d.buffers[0].1 = now - Duration::from_secs(2);
+            d.buffers[1].1 = now - Duration::from_secs(2);
+            d.shrink(now)
+        };
+        assert_eq!(released_handles.release(), 2);
+        assert_eq!(provider.num_idle(), 2);
+
+        let released_handles = {
+            let mut d = provider.d.lock().unwrap();
+            for (_, idle_since) in d.buffers.iter_mut() {
+                *idle_since = now - Duration::from_secs(2);
+            }
+            d.shrink(now)
+        };
+        assert_eq!(released_handles.release(), 1);
+        assert_eq!(provider.num_idle(), 1);
+        assert_eq!(released.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn release_hook_can_use_pool() {
+        let provider =
+            PooledHandlesProvider::new((0..2).map(|_| vec![UserPtrHandle(vec![0u8; 16])]))
+                .with_shrink_policy(ShrinkPolicy {
+                    low_watermark: 0,
+                    high_watermark: 1,
+                    max_idle: Duration::MAX,
+                });
+        let pool = Arc::downgrade(&provider.d);
+        let provider: PooledHandlesProvider<Handles> = provider.with_release_hook(move |_| {
+            // Would deadlock if called with the pool lock held.
+            assert_eq!(pool.upgrade().unwrap().lock().unwrap().buffers.len(), 1);
+        });
+
+        assert_eq!(provider.shrink(), 1);
+    }
+
+    #[test]
+    fn shrink_steady_state() {
+        const MAX_IDLE: Duration = Duration::from_millis(20);
+
+        // A client only ever using one buffer at a time.
+        let (provider, released) = pool(4);
+        let provider = provider.with_shrink_policy(ShrinkPolicy {
+            low_watermark: 1,
+            high_watermark: 4,
+            max_idle: MAX_IDLE,
+        });
+        let waker = Arc::new(Waker::new().unwrap());
+
+        let start = Instant::now();
+        for i in 1..=40 {
+            let mut handles = provider.get_handles(&waker).unwrap();
+            handles.return_to_pool(start + Duration::from_millis(2) * i);
+        }
+
+        // The unused buffers have aged and been released when the buffer in
+        // use returned to the pool, instead of all being used in turn.
+        assert_eq!(provider.num_idle(), 1);
+        assert_eq!(released.load(Ordering::SeqCst), 3);
+    }
+}
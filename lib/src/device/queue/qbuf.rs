@@ -1,14 +1,18 @@
 //! Provides types related to queuing buffers on a `Queue` object.
 use super::{buffer::BufferInfo, Capture, Direction, Output};
 use super::{BufferState, BufferStateFuse, BuffersAllocated, Queue};
-use crate::ioctl::{self, QBufIoctlError, QBufResult};
+use crate::ioctl::{self, OsError, QBufIoctlError, QBufResult};
 use crate::memory::*;
+use crate::QueueDirection;
 use std::convert::Infallible;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::{
     fmt::{self, Debug},
+    ops::Range,
     sync::Arc,
 };
 
+use nix::errno::Errno;
 use nix::sys::time::{TimeVal, TimeValLike};
 use thiserror::Error;
 
@@ -20,10 +24,24 @@ pub mod get_indexed;
 #[derive(Error)]
 #[error("{}", self.error)]
 pub struct QueueError<P: BufferHandles> {
+    #[source]
     pub error: ioctl::QBufError<Infallible>,
     pub plane_handles: P,
 }
 
+impl<P: BufferHandles> OsError for QueueError<P> {
+    fn errno(&self) -> Option<Errno> {
+        self.error.errno()
+    }
+}
+
+/// The plane handles are dropped in the conversion.
+impl<P: BufferHandles> From<QueueError<P>> for std::io::Error {
+    fn from(err: QueueError<P>) -> Self {
+        err.error.into()
+    }
+}
+
 impl<P: BufferHandles> Debug for QueueError<P> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         Debug::fmt(&self.error, f)
@@ -66,10 +84,22 @@ pub struct QBuffer<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles
     index: usize,
     num_planes: usize,
     timestamp: TimeVal,
+    request: Option<RawFd>,
+    flags: ioctl::BufferFlags,
+    /// Planes added with `add_plane`.
+    added_planes: Vec<AddedPlane<P::HandleType>>,
     fuse: BufferStateFuse<Q>,
     _p: std::marker::PhantomData<P>,
 }
 
+/// A plane added to a `QBuffer` with `add_plane`, waiting for the buffer to be
+/// queued.
+struct AddedPlane<H> {
+    handle: H,
+    bytes_used: usize,
+    data_offset: usize,
+}
+
 impl<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QBuffer<'a, D, P, Q> {
     pub(super) fn new(
         queue: &'a Queue<D, BuffersAllocated<Q>>,
@@ -83,6 +113,9 @@ impl<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QB
             index: buffer.index,
             num_planes: buffer.planes.len(),
             timestamp: TimeVal::zero(),
+            request: None,
+            flags: ioctl::BufferFlags::empty(),
+            added_planes: Vec::new(),
             fuse,
             _p: std::marker::PhantomData,
         }
@@ -104,6 +137,55 @@ impl<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QB
         self
     }
 
+    /// Queues this buffer as part of `request` instead of immediately. The
+    /// buffer will be processed once the request itself is queued.
+    pub fn set_request(mut self, request: &impl AsRawFd) -> Self {
+        self.request = Some(request.as_raw_fd());
+        self
+    }
+
+    /// Checks that the memory attached to each of `planes` is large enough to
+    /// hold the data of the plane, as computed by the driver from the
+    /// negotiated format.
+    ///
+    /// Only imported memory is checked ; MMAP buffers are allocated by the
+    /// driver and thus always have the required size.
+    fn check_planes_size(&self, planes: &[ioctl::QBufPlane]) -> Result<(), QBufIoctlError> {
+        let memory_type: MemoryType = self.queue.state.memory_type.into();
+        if memory_type == MemoryType::Mmap {
+            return Ok(());
+        }
+
+        let buffer_info = self
+            .queue
+            .state
+            .buffer_info
+            .get(self.index)
+            .expect("Inconsistent buffer state!");
+
+        for (i, (plane, plane_info)) in planes
+            .iter()
+            .zip(buffer_info.features.planes.iter())
+            .enumerate()
+        {
+            // A length of 0 for a DMABUF plane lets the driver use the size of
+            // the whole buffer, so we cannot validate it here.
+            if memory_type == MemoryType::DmaBuf && plane.0.length == 0 {
+                continue;
+            }
+
+            if plane.0.length < plane_info.length {
+                return Err(QBufIoctlError::PlaneTooSmall {
+                    plane: i,
+                    size: plane.0.length as usize,
+                    required: plane_info.length as usize,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
     // R is meant to mean "either P or Q".
     // Caller is responsible for making sure that the number of planes and
     // plane_handles is the same as the number of expected planes for this
@@ -113,10 +195,27 @@ impl<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QB
         planes: Vec<ioctl::QBufPlane>,
         plane_handles: R,
     ) -> QueueResult<(), R> {
+        if let Err(error) = self.check_planes_size(&planes) {
+            return Err(QueueError {
+                error: error.into(),
+                plane_handles,
+            });
+        }
+
+        // The kernel would fail with an obscure EBADR in that case.
+        if self.request.is_some() && !self.queue.supports_requests() {
+            return Err(QueueError {
+                error: QBufIoctlError::RequestsNotSupported.into(),
+                plane_handles,
+            });
+        }
+
         let mut qbuffer =
             ioctl::QBuffer::<P::HandleType>::new(self.queue.inner.type_, self.index as u32);
         qbuffer.planes = planes;
         qbuffer.timestamp = self.timestamp;
+        qbuffer.request = self.request;
+        qbuffer.flags = self.flags;
 
         match ioctl::qbuf(&self.queue.inner, qbuffer) {
             Ok(()) => (),
@@ -131,19 +230,122 @@ impl<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QB
         // We got this now.
         self.fuse.disarm();
 
-        self.queue
+        let buffer_info = self
+            .queue
             .state
             .buffer_info
             .get(self.index)
-            .expect("Inconsistent buffer state!")
-            .update_state(|state| {
-                *state = BufferState::Queued(plane_handles.into());
-            });
+            .expect("Inconsistent buffer state!");
+        buffer_info.update_state(|state| {
+            *state = BufferState::Queued(plane_handles.into());
+        });
+        // Queueing a reserved buffer ends its reservation.
+        buffer_info.unreserve();
 
         Ok(())
     }
 }
 
+/// Plane-by-plane construction of buffers whose handles are a `Vec` of plane
+/// handles, as an alternative to assembling all the handles and the bytes used
+/// by each plane before calling `queue_with_handles`:
+///
+/// ```no_run
+/// # use v4l2r::device::queue::{direction::Output, qbuf::QBuffer};
+/// # use v4l2r::memory::DmaBufHandle;
+/// # fn queue(qbuf: QBuffer<Output, Vec<DmaBufHandle<std::fs::File>>, Vec<DmaBufHandle<std::fs::File>>>,
+/// #          luma: DmaBufHandle<std::fs::File>, chroma: DmaBufHandle<std::fs::File>) {
+/// qbuf.add_plane(luma, 640 * 480)
+///     .add_plane(chroma, 640 * 240)
+///     .queue_planes()
+///     .unwrap();
+/// # }
+/// ```
+impl<'a, D, H, Q> QBuffer<'a, D, Vec<H>, Q>
+where
+    D: Direction,
+    H: PlaneHandle,
+    Q: BufferHandles + From<Vec<H>>,
+{
+    /// Adds the next plane of the buffer, backed by `handle` and containing
+    /// `bytes_used` bytes of data. `bytes_used` is ignored for CAPTURE buffers.
+    pub fn add_plane(self, handle: H, bytes_used: usize) -> Self {
+        self.add_plane_with_offset(handle, bytes_used, 0)
+    }
+
+    /// Same as `add_plane`, but the data of the plane starts `data_offset`
+    /// bytes after the start of its memory. `bytes_used` includes these
+    /// `data_offset` bytes. Only supported by the multi-planar API.
+    pub fn add_plane_with_offset(
+        mut self,
+        handle: H,
+        bytes_used: usize,
+        data_offset: usize,
+    ) -> Self {
+        self.added_planes.push(AddedPlane {
+            handle,
+            bytes_used,
+            data_offset,
+        });
+        self
+    }
+
+    /// Returns the number of planes added so far with `add_plane`.
+    pub fn num_added_planes(&self) -> usize {
+        self.added_planes.len()
+    }
+
+    /// Queue the buffer with the planes added using `add_plane`, consuming the
+    /// object.
+    ///
+    /// Fails if the number of added planes does not match the buffer's expected
+    /// number of planes, or if a data offset is specified for a single-planar
+    /// queue. The handles of the added planes are returned on failure.
+    pub fn queue_planes(mut self) -> QueueResult<(), Vec<H>> {
+        let added_planes = std::mem::take(&mut self.added_planes);
+        let mut handles = Vec::with_capacity(added_planes.len());
+        let mut planes = Vec::with_capacity(added_planes.len());
+        for plane in added_planes {
+            let bytes_used = match D::DIRECTION {
+                QueueDirection::Output => plane.bytes_used,
+                QueueDirection::Capture => 0,
+            };
+            let mut qbuf_plane = ioctl::QBufPlane::new_from_handle(&plane.handle, bytes_used);
+            qbuf_plane.0.data_offset = plane.data_offset as u32;
+            handles.push(plane.handle);
+            planes.push(qbuf_plane);
+        }
+
+        if handles.len() != self.num_expected_planes() {
+            return Err(QueueError {
+                error: QBufIoctlError::NumPlanesMismatch(handles.len(), self.num_expected_planes())
+                    .into(),
+                plane_handles: handles,
+            });
+        }
+
+        if !self.queue.inner.type_.is_multiplanar() && planes.iter().any(|p| p.0.data_offset != 0) {
+            return Err(QueueError {
+                error: QBufIoctlError::DataOffsetNotSupported.into(),
+                plane_handles: handles,
+            });
+        }
+
+        self.queue_bound_planes(planes, handles)
+    }
+}
+
+impl<'a, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QBuffer<'a, Output, P, Q> {
+    /// Asks a stateless decoder to keep the CAPTURE buffer of the current frame
+    /// after processing this buffer, because more slices of the same frame are
+    /// to follow. Requires the `SUPPORTS_M2M_HOLD_CAPTURE_BUF` capability.
+    pub fn set_hold_capture_buffer(mut self, hold: bool) -> Self {
+        self.flags
+            .set(ioctl::BufferFlags::M2M_HOLD_CAPTURE_BUF, hold);
+        self
+    }
+}
+
 impl<'a, P, Q> QBuffer<'a, Output, P, Q>
 where
     P: PrimitiveBufferHandles,
@@ -155,6 +357,18 @@ where
         let plane_info = buffer_info.features.planes.get(plane)?;
         P::HandleType::map(self.queue.inner.device.as_ref(), plane_info)
     }
+
+    /// Same as `get_plane_mapping`, but only maps `range` of the plane, e.g. to write a header
+    /// without mapping a whole large buffer.
+    pub fn get_plane_mapping_range(
+        &self,
+        plane: usize,
+        range: Range<u32>,
+    ) -> Option<ioctl::PlaneMapping> {
+        let buffer_info = self.queue.state.buffer_info.get(self.index)?;
+        let plane_info = buffer_info.features.planes.get(plane)?;
+        P::HandleType::map_range(self.queue.inner.device.as_ref(), plane_info, range)
+    }
 }
 
 /// Trait for queueable CAPTURE buffers. These buffers only require handles to
@@ -175,6 +389,56 @@ pub trait OutputQueueable<Q: BufferHandles> {
     fn queue_with_handles(self, handles: Q, bytes_used: &[usize]) -> QueueResult<(), Q>;
 }
 
+/// Direction-agnostic counterpart of `CaptureQueueable` and `OutputQueueable`,
+/// for code that needs to queue buffers regardless of their direction.
+pub trait Queueable<Q: BufferHandles> {
+    /// Queue the buffer after binding `handles`, consuming the object.
+    /// The number of handles must match the buffer's expected number of planes.
+    ///
+    /// `bytes_used` follows the same rules as `OutputQueueable::queue_with_handles`
+    /// for OUTPUT buffers, and is ignored for CAPTURE buffers.
+    fn queue_with_handles_and_bytes_used(
+        self,
+        handles: Q,
+        bytes_used: &[usize],
+    ) -> QueueResult<(), Q>;
+}
+
+/// A buffer that can be queued on either a CAPTURE or an OUTPUT queue. Useful
+/// to hold buffers of both directions in the same collection.
+pub enum AnyDirectionQBuffer<C, O> {
+    Capture(C),
+    Output(O),
+}
+
+impl<C, O> AnyDirectionQBuffer<C, O> {
+    /// Returns the direction of the queue this buffer belongs to.
+    pub fn direction(&self) -> QueueDirection {
+        match self {
+            AnyDirectionQBuffer::Capture(_) => QueueDirection::Capture,
+            AnyDirectionQBuffer::Output(_) => QueueDirection::Output,
+        }
+    }
+}
+
+impl<Q, C, O> Queueable<Q> for AnyDirectionQBuffer<C, O>
+where
+    Q: BufferHandles,
+    C: CaptureQueueable<Q>,
+    O: OutputQueueable<Q>,
+{
+    fn queue_with_handles_and_bytes_used(
+        self,
+        handles: Q,
+        bytes_used: &[usize],
+    ) -> QueueResult<(), Q> {
+        match self {
+            AnyDirectionQBuffer::Capture(c) => c.queue_with_handles(handles),
+            AnyDirectionQBuffer::Output(o) => o.queue_with_handles(handles, bytes_used),
+        }
+    }
+}
+
 /// Trait for all objects that are capable of providing objects that can be
 /// queued to the CAPTURE queue.
 pub trait CaptureQueueableProvider<'a, Q: BufferHandles> {
@@ -257,6 +521,32 @@ impl<P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> OutputQueueable<Q>
     }
 }
 
+/// Direction-agnostic counterpart of `CaptureQueueableProvider` and
+/// `OutputQueueableProvider`.
+pub trait QueueableProvider<'a, Q: BufferHandles> {
+    type Queueable: 'a + Queueable<Q>;
+}
+
+impl<P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> Queueable<Q>
+    for QBuffer<'_, Capture, P, Q>
+{
+    fn queue_with_handles_and_bytes_used(self, handles: Q, _: &[usize]) -> QueueResult<(), Q> {
+        CaptureQueueable::queue_with_handles(self, handles)
+    }
+}
+
+impl<P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> Queueable<Q>
+    for QBuffer<'_, Output, P, Q>
+{
+    fn queue_with_handles_and_bytes_used(
+        self,
+        handles: Q,
+        bytes_used: &[usize],
+    ) -> QueueResult<(), Q> {
+        OutputQueueable::queue_with_handles(self, handles, bytes_used)
+    }
+}
+
 /// Shortcut to quickly queue self-backed CAPTURE buffers without specifying
 /// empty handles.
 /// Since we don't receive plane handles, we also don't need to return any, so
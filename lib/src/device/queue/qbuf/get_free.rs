@@ -2,13 +2,18 @@
 //! interface leave the choice of which buffer to return to the implementor,
 //! which must define its own allocation policy.
 //!
+//! Queues let the client choose their policy among the ones of
+//! [`FreeBufferPolicy`].
+//!
 //! The returned buffer shall not outlive the object that produced it.
 
+use nix::errno::Errno;
 use thiserror::Error;
 
+use crate::ioctl::OsError;
 use crate::memory::BufferHandles;
 
-use super::{CaptureQueueableProvider, OutputQueueableProvider};
+use super::{CaptureQueueableProvider, OutputQueueableProvider, QueueableProvider};
 
 #[derive(Debug, Error)]
 pub enum GetFreeBufferError {
@@ -16,6 +21,47 @@ pub enum GetFreeBufferError {
     NoFreeBuffer,
 }
 
+impl OsError for GetFreeBufferError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
+crate::ioctl::impl_into_io_error!(GetFreeBufferError);
+
+/// Policy deciding which buffer is returned when several are free.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FreeBufferPolicy {
+    /// Return the free buffer with the lowest index.
+    #[default]
+    LowestIndex,
+    /// Return the buffer that has been free for the longest time, so all the
+    /// buffers are used in turn. Useful when buffers are shared with consumers
+    /// that may still read a buffer for a while after returning it.
+    LeastRecentlyUsed,
+    /// Return the buffer that has been freed last, whose memory is the most
+    /// likely to still be in the CPU caches.
+    MostRecentlyUsed,
+}
+
+impl FreeBufferPolicy {
+    /// Select a buffer among `candidates`, an iterator of the index of free
+    /// buffers along with a counter increasing every time a buffer is freed,
+    /// in the order of their index.
+    pub(crate) fn select<I: Iterator<Item = (usize, u64)>>(
+        &self,
+        mut candidates: I,
+    ) -> Option<usize> {
+        let selected = match self {
+            FreeBufferPolicy::LowestIndex => candidates.next(),
+            FreeBufferPolicy::LeastRecentlyUsed => candidates.min_by_key(|(_, freed_at)| *freed_at),
+            FreeBufferPolicy::MostRecentlyUsed => candidates.max_by_key(|(_, freed_at)| *freed_at),
+        };
+
+        selected.map(|(index, _)| index)
+    }
+}
+
 pub trait GetFreeOutputBuffer<'a, P: BufferHandles, ErrorType = GetFreeBufferError>
 where
     Self: OutputQueueableProvider<'a, P>,
@@ -29,3 +75,38 @@ where
 {
     fn try_get_free_buffer(&'a self) -> Result<Self::Queueable, ErrorType>;
 }
+
+/// Direction-agnostic version of `GetFreeCaptureBuffer` and `GetFreeOutputBuffer`.
+pub trait GetFreeBuffer<'a, P: BufferHandles, ErrorType = GetFreeBufferError>
+where
+    Self: QueueableProvider<'a, P>,
+{
+    fn try_get_free_buffer(&'a self) -> Result<Self::Queueable, ErrorType>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_buffer_policy() {
+        let candidates = [(1, 12), (3, 4), (4, 20), (6, 7)];
+
+        assert_eq!(
+            FreeBufferPolicy::LowestIndex.select(candidates.iter().copied()),
+            Some(1)
+        );
+        assert_eq!(
+            FreeBufferPolicy::LeastRecentlyUsed.select(candidates.iter().copied()),
+            Some(3)
+        );
+        assert_eq!(
+            FreeBufferPolicy::MostRecentlyUsed.select(candidates.iter().copied()),
+            Some(4)
+        );
+        assert_eq!(
+            FreeBufferPolicy::LowestIndex.select(std::iter::empty()),
+            None
+        );
+    }
+}
@@ -3,13 +3,20 @@
 //! `try_get_buffer()` returns the buffer with specified `index`, provided that
 //! this buffer is currently available for use.
 //!
+//! Buffers can also be reserved with `reserve_buffer()` ahead of time, for
+//! clients that need a frame to land in a particular buffer. A reserved buffer
+//! is never returned by the `get_free` traits, and can only be obtained by
+//! index until it is queued.
+//!
 //! The returned buffer shall not outlive the object that produced it.
 
+use nix::errno::Errno;
 use thiserror::Error;
 
+use crate::ioctl::OsError;
 use crate::memory::BufferHandles;
 
-use super::{CaptureQueueableProvider, OutputQueueableProvider};
+use super::{CaptureQueueableProvider, OutputQueueableProvider, QueueableProvider};
 
 #[derive(Debug, Error)]
 pub enum TryGetBufferError {
@@ -17,8 +24,18 @@ pub enum TryGetBufferError {
     InvalidIndex(usize),
     #[error("buffer is already in use")]
     AlreadyUsed,
+    #[error("buffer with index {0} is already reserved")]
+    AlreadyReserved(usize),
+}
+
+impl OsError for TryGetBufferError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
 }
 
+crate::ioctl::impl_into_io_error!(TryGetBufferError);
+
 pub trait GetOutputBufferByIndex<'a, P: BufferHandles, ErrorType = TryGetBufferError>
 where
     Self: OutputQueueableProvider<'a, P>,
@@ -32,3 +49,25 @@ where
 {
     fn try_get_buffer(&'a self, index: usize) -> Result<Self::Queueable, ErrorType>;
 }
+
+/// Direction-agnostic version of `GetCaptureBufferByIndex` and `GetOutputBufferByIndex`.
+pub trait GetBufferByIndex<'a, P: BufferHandles, ErrorType = TryGetBufferError>
+where
+    Self: QueueableProvider<'a, P>,
+{
+    fn try_get_buffer(&'a self, index: usize) -> Result<Self::Queueable, ErrorType>;
+}
+
+/// Trait for buffer providers allowing buffers to be reserved by index.
+pub trait ReserveBufferByIndex {
+    /// Reserve the buffer with `index`, which can be in any state. The
+    /// reservation ends once the buffer is queued, or with `unreserve_buffer`.
+    fn reserve_buffer(&self, index: usize) -> Result<(), TryGetBufferError>;
+
+    /// Cancel the reservation of the buffer with `index`, making it available
+    /// to the `get_free` traits again. Returns whether it was reserved.
+    fn unreserve_buffer(&self, index: usize) -> Result<bool, TryGetBufferError>;
+
+    /// Returns whether the buffer with `index` is reserved.
+    fn is_buffer_reserved(&self, index: usize) -> bool;
+}
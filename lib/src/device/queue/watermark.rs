@@ -0,0 +1,99 @@
+//! Notification of the client when the number of buffers queued into a queue
+//! runs low.
+//!
+//! A driver that runs out of CAPTURE buffers stalls until the client returns
+//! one, so applications holding on to decoded or encoded frames may want to
+//! know when this is about to happen in order to prioritize returning them.
+
+/// Level of a queue relative to its watermark, passed to the watermark
+/// callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueLevel {
+    /// The number of queued buffers dropped below the watermark.
+    Starving { queued: usize },
+    /// The number of queued buffers went back to the watermark or above after
+    /// the queue was starving.
+    Recovered { queued: usize },
+}
+
+/// Callback invoked when a queue starts or stops starving.
+pub type QueueLevelCb = Box<dyn FnMut(QueueLevel) + Send>;
+
+/// Tracks the number of buffers queued into a queue and invokes a callback
+/// whenever it crosses a watermark.
+///
+/// The callback is only invoked on transitions, i.e. once when the queue
+/// starts starving and once when it recovers.
+pub struct QueueWatermark {
+    watermark: usize,
+    starving: bool,
+    cb: QueueLevelCb,
+}
+
+impl QueueWatermark {
+    /// Create a new watermark, considering the queue as starving when less
+    /// than `watermark` buffers are queued.
+    pub fn new<F: FnMut(QueueLevel) + Send + 'static>(watermark: usize, cb: F) -> Self {
+        Self {
+            watermark,
+            starving: false,
+            cb: Box::new(cb),
+        }
+    }
+
+    /// Returns the watermark below which the queue is considered starving.
+    pub fn watermark(&self) -> usize {
+        self.watermark
+    }
+
+    /// Returns whether the queue is currently considered starving.
+    pub fn is_starving(&self) -> bool {
+        self.starving
+    }
+
+    /// Update the watermark with the current number of queued buffers,
+    /// invoking the callback if the queue started or stopped starving.
+    pub fn update(&mut self, queued: usize) {
+        let starving = queued < self.watermark;
+        if starving == self.starving {
+            return;
+        }
+
+        self.starving = starving;
+        (self.cb)(if starving {
+            QueueLevel::Starving { queued }
+        } else {
+            QueueLevel::Recovered { queued }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn queue_watermark() {
+        let levels = Arc::new(Mutex::new(Vec::new()));
+        let cb_levels = Arc::clone(&levels);
+        let mut watermark = QueueWatermark::new(2, move |level| {
+            cb_levels.lock().unwrap().push(level);
+        });
+
+        for queued in [4, 3, 2, 1, 0, 1, 2, 3, 1].iter().copied() {
+            watermark.update(queued);
+        }
+
+        assert!(watermark.is_starving());
+        assert_eq!(
+            *levels.lock().unwrap(),
+            vec![
+                QueueLevel::Starving { queued: 1 },
+                QueueLevel::Recovered { queued: 2 },
+                QueueLevel::Starving { queued: 1 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Hardware frequency seek of radio tuners.
+//!
+//! `VIDIOC_S_HW_FREQ_SEEK` blocks until the tuner has found a station, which can take several
+//! seconds. `FreqSeek` runs it on a dedicated thread and signals its completion through a
+//! `Waker`, so an event loop polling a `Poller` is woken up when the seek is over and can keep
+//! handling other events in the meantime. `FreqSeek::cancel` interrupts the ioctl with a signal
+//! sent to the seek thread, for clients that want to give up on the seek.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::Wake;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::libc::c_int;
+use nix::sys::pthread::pthread_kill;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::os::unix::thread::JoinHandleExt;
+use thiserror::Error;
+
+use crate::device::poller::Waker;
+use crate::device::Device;
+use crate::ioctl;
+use crate::ioctl::Capabilities;
+use crate::ioctl::HwFreqSeek;
+use crate::ioctl::HwFreqSeekError;
+use crate::ioctl::OsError;
+
+#[derive(Debug, Error)]
+pub enum StartFreqSeekError {
+    #[error("device does not support hardware frequency seek")]
+    NotSupported,
+    #[error("error while installing the cancellation signal handler")]
+    SignalHandlerError(#[source] Errno),
+    #[error("error while spawning the seek thread")]
+    SpawnError(#[source] std::io::Error),
+}
+
+impl OsError for StartFreqSeekError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            StartFreqSeekError::NotSupported => None,
+            StartFreqSeekError::SignalHandlerError(e) => Some(*e),
+            StartFreqSeekError::SpawnError(e) => e.raw_os_error().map(Errno::from_i32),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(StartFreqSeekError);
+
+/// Signal interrupting the seek thread. It is ignored by default, so clients are unlikely to use
+/// it already and stray instances of it are harmless.
+const CANCEL_SIGNAL: Signal = Signal::SIGURG;
+
+/// How often `cancel` signals the seek thread until it returns. The signal may be received right
+/// before the thread enters the ioctl, in which case it does not interrupt it.
+const CANCEL_RETRY_PERIOD: Duration = Duration::from_millis(5);
+
+extern "C" fn handle_cancel_signal(_: c_int) {}
+
+/// Installs a handler for `CANCEL_SIGNAL`, without `SA_RESTART` so it makes blocking system calls
+/// fail with `EINTR`. An existing handler installed by the client is kept.
+fn install_cancel_handler() -> Result<(), Errno> {
+    static INSTALLED: OnceLock<Result<(), Errno>> = OnceLock::new();
+
+    *INSTALLED.get_or_init(|| {
+        let action = SigAction::new(
+            SigHandler::Handler(handle_cancel_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        // SAFETY: the handler does nothing.
+        let previous = unsafe { sigaction(CANCEL_SIGNAL, &action) }?;
+        match previous.handler() {
+            SigHandler::SigDfl | SigHandler::SigIgn => Ok(()),
+            _ => {
+                // SAFETY: this is the handler the client had installed.
+                unsafe { sigaction(CANCEL_SIGNAL, &previous) }?;
+                Ok(())
+            }
+        }
+    })
+}
+
+/// A blocking operation running on a dedicated thread, which can be interrupted by a signal.
+struct Interruptible<T: Send + 'static> {
+    handle: JoinHandle<T>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<T: Send + 'static> Interruptible<T> {
+    /// Runs `f` on a thread named `name`. `f` receives a flag set once cancellation has been
+    /// requested, which it must check before blocking.
+    fn spawn<F>(name: &str, f: F) -> Result<Self, StartFreqSeekError>
+    where
+        F: FnOnce(&AtomicBool) -> T + Send + 'static,
+    {
+        install_cancel_handler().map_err(StartFreqSeekError::SignalHandlerError)?;
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let thread_cancelled = Arc::clone(&cancelled);
+        let handle = std::thread::Builder::new()
+            .name(name.into())
+            .spawn(move || f(&thread_cancelled))
+            .map_err(StartFreqSeekError::SpawnError)?;
+
+        Ok(Interruptible { handle, cancelled })
+    }
+
+    fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    fn join(self) -> T {
+        // The thread cannot panic.
+        self.handle.join().unwrap()
+    }
+
+    /// Signals the thread until it returns, and returns its result.
+    fn cancel(self) -> T {
+        self.cancelled.store(true, Ordering::SeqCst);
+        let thread = self.handle.as_pthread_t();
+        while !self.handle.is_finished() {
+            // The thread cannot have been joined yet, so its ID is still valid.
+            if pthread_kill(thread, CANCEL_SIGNAL).is_err() {
+                break;
+            }
+            std::thread::sleep(CANCEL_RETRY_PERIOD);
+        }
+
+        self.join()
+    }
+}
+
+/// A hardware frequency seek running in the background.
+pub struct FreqSeek {
+    seek: Interruptible<Result<(), HwFreqSeekError>>,
+}
+
+impl FreqSeek {
+    /// Starts seeking with parameters `seek` on a dedicated thread. `waker` is woken once the seek
+    /// is over, successfully or not.
+    ///
+    /// `device` must have been opened in blocking mode, otherwise the seek fails with
+    /// `HwFreqSeekError::WouldBlock`.
+    ///
+    /// Cancellation relies on a handler for `SIGURG`, which is installed the first time a seek is
+    /// started unless the client already handles this signal. In the latter case, the client's
+    /// handler must not use `SA_RESTART` for `cancel` to work.
+    pub fn start(
+        device: Arc<Device>,
+        seek: HwFreqSeek,
+        waker: Arc<Waker>,
+    ) -> Result<Self, StartFreqSeekError> {
+        if !device
+            .caps()
+            .device_caps()
+            .contains(Capabilities::HW_FREQ_SEEK)
+        {
+            return Err(StartFreqSeekError::NotSupported);
+        }
+
+        let seek = Interruptible::spawn("V4L2 freq seek", move |cancelled| {
+            let res = if cancelled.load(Ordering::SeqCst) {
+                Err(HwFreqSeekError::Interrupted)
+            } else {
+                ioctl::s_hw_freq_seek(&*device, &seek)
+            };
+            waker.wake();
+            res
+        })?;
+
+        Ok(FreqSeek { seek })
+    }
+
+    /// Returns whether the seek is over, in which case `wait` returns immediately.
+    pub fn is_finished(&self) -> bool {
+        self.seek.is_finished()
+    }
+
+    /// Waits for the seek to be over and returns its result. On success, the tuner is set to the
+    /// frequency of the station that has been found and can be read with `ioctl::g_frequency`.
+    pub fn wait(self) -> Result<(), HwFreqSeekError> {
+        self.seek.join()
+    }
+
+    /// Interrupts the seek and returns its result, which is `HwFreqSeekError::Interrupted` unless
+    /// the seek was over already. The frequency the tuner is left at after an interrupted seek
+    /// depends on the driver.
+    pub fn cancel(self) -> Result<(), HwFreqSeekError> {
+        self.seek.cancel()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interruptible_cancel() {
+        // A pipe nobody writes into blocks its reader, like a seek that never finds a station.
+        let (reader, writer) = nix::unistd::pipe().unwrap();
+        let blocked = Interruptible::spawn("test blocked read", move |cancelled| {
+            let mut buf = [0u8; 1];
+            if cancelled.load(Ordering::SeqCst) {
+                return Err(Errno::EINTR);
+            }
+            nix::unistd::read(reader, &mut buf)
+        })
+        .unwrap();
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(!blocked.is_finished());
+        assert_eq!(blocked.cancel(), Err(Errno::EINTR));
+
+        nix::unistd::close(reader).unwrap();
+        nix::unistd::close(writer).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+//! Reception and transmission of RDS (Radio Data System) data.
+//!
+//! Unlike video, RDS data is not exchanged through buffer queues but by calling `read()` or
+//! `write()` on the radio device, as a sequence of `v4l2_rds_data` blocks. Receivers (e.g.
+//! si470x) advertise `Capabilities::RDS_CAPTURE` and transmitters (e.g. si4713)
+//! `Capabilities::RDS_OUTPUT`. Transmission also requires `TunerTransmissionFlags::RDS` to be
+//! set on the modulator with `ioctl::s_modulator`.
+use std::os::unix::io::AsRawFd;
+
+use enumn::N;
+use nix::errno::Errno;
+use thiserror::Error;
+
+use crate::bindings;
+use crate::bindings::v4l2_rds_data;
+use crate::device::Device;
+use crate::ioctl;
+use crate::ioctl::Capabilities;
+use crate::ioctl::OsError;
+
+/// Position of a block in an RDS group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, N)]
+#[repr(u8)]
+pub enum RdsBlockId {
+    A = bindings::V4L2_RDS_BLOCK_A as u8,
+    B = bindings::V4L2_RDS_BLOCK_B as u8,
+    C = bindings::V4L2_RDS_BLOCK_C as u8,
+    D = bindings::V4L2_RDS_BLOCK_D as u8,
+    /// Block C', used instead of C by type B groups.
+    CAlt = bindings::V4L2_RDS_BLOCK_C_ALT as u8,
+    /// The receiver could not identify the block.
+    Invalid = bindings::V4L2_RDS_BLOCK_INVALID as u8,
+}
+
+/// Safe variant of `struct v4l2_rds_data`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RdsBlock {
+    pub id: RdsBlockId,
+    pub data: u16,
+    /// Errors in the block have been corrected by the receiver.
+    pub corrected: bool,
+    /// The block contains uncorrectable errors.
+    pub error: bool,
+}
+
+impl RdsBlock {
+    pub fn new(id: RdsBlockId, data: u16) -> Self {
+        RdsBlock {
+            id,
+            data,
+            corrected: false,
+            error: false,
+        }
+    }
+}
+
+impl From<v4l2_rds_data> for RdsBlock {
+    fn from(rds: v4l2_rds_data) -> Self {
+        let block = rds.block as u32;
+
+        RdsBlock {
+            // All 3-bit values except 5 and 6 are valid identifiers.
+            id: RdsBlockId::n((block & bindings::V4L2_RDS_BLOCK_MSK) as u8)
+                .unwrap_or(RdsBlockId::Invalid),
+            data: u16::from_le_bytes([rds.lsb, rds.msb]),
+            corrected: block & bindings::V4L2_RDS_BLOCK_CORRECTED != 0,
+            error: block & bindings::V4L2_RDS_BLOCK_ERROR != 0,
+        }
+    }
+}
+
+impl From<&RdsBlock> for v4l2_rds_data {
+    fn from(block: &RdsBlock) -> Self {
+        let [lsb, msb] = block.data.to_le_bytes();
+
+        v4l2_rds_data {
+            lsb,
+            msb,
+            block: block.id as u8,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RdsError {
+    #[error("device does not support RDS {0}")]
+    NotSupported(&'static str),
+    #[error("error while exchanging RDS data: {0}")]
+    IoError(#[source] Errno),
+}
+
+impl OsError for RdsError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            RdsError::NotSupported(_) => None,
+            RdsError::IoError(e) => Some(*e),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(RdsError);
+
+const RDS_BLOCK_SIZE: usize = std::mem::size_of::<v4l2_rds_data>();
+
+/// Reads up to `max_blocks` RDS blocks from a radio receiver.
+///
+/// This blocks until data is available, unless the device has been opened with
+/// `DeviceConfig::non_blocking_dqbuf`, in which case `EAGAIN` is returned if no data is
+/// available.
+pub fn read_blocks(device: &Device, max_blocks: usize) -> Result<Vec<RdsBlock>, RdsError> {
+    if !device
+        .caps()
+        .device_caps()
+        .contains(Capabilities::RDS_CAPTURE)
+    {
+        return Err(RdsError::NotSupported("capture"));
+    }
+
+    let mut data = vec![0u8; max_blocks * RDS_BLOCK_SIZE];
+    let len = nix::unistd::read(device.as_raw_fd(), &mut data).map_err(RdsError::IoError)?;
+
+    Ok(data[..len]
+        .chunks_exact(RDS_BLOCK_SIZE)
+        .map(|block| {
+            RdsBlock::from(v4l2_rds_data {
+                lsb: block[0],
+                msb: block[1],
+                block: block[2],
+            })
+        })
+        .collect())
+}
+
+/// Writes `blocks` to a radio transmitter, and returns the number of blocks actually written.
+pub fn write_blocks(device: &Device, blocks: &[RdsBlock]) -> Result<usize, RdsError> {
+    if !device
+        .caps()
+        .device_caps()
+        .contains(Capabilities::RDS_OUTPUT)
+    {
+        return Err(RdsError::NotSupported("output"));
+    }
+
+    let data: Vec<u8> = blocks
+        .iter()
+        .flat_map(|block| {
+            let rds = v4l2_rds_data::from(block);
+            [rds.lsb, rds.msb, rds.block]
+        })
+        .collect();
+    let len = nix::unistd::write(device.as_raw_fd(), &data).map_err(RdsError::IoError)?;
+
+    Ok(len / RDS_BLOCK_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rds_block_conversion() {
+        let rds = v4l2_rds_data {
+            lsb: 0x34,
+            msb: 0x12,
+            block: (bindings::V4L2_RDS_BLOCK_C_ALT | bindings::V4L2_RDS_BLOCK_CORRECTED) as u8,
+        };
+        let block = RdsBlock::from(rds);
+        assert_eq!(block.id, RdsBlockId::CAlt);
+        assert_eq!(block.data, 0x1234);
+        assert!(block.corrected);
+        assert!(!block.error);
+
+        let rds = v4l2_rds_data::from(&RdsBlock::new(RdsBlockId::B, 0xabcd));
+        assert_eq!((rds.lsb, rds.msb, rds.block), (0xcd, 0xab, 1));
+
+        // Reserved identifiers are reported as invalid blocks.
+        let rds = v4l2_rds_data {
+            block: 5 | bindings::V4L2_RDS_BLOCK_ERROR as u8,
+            ..Default::default()
+        };
+        let block = RdsBlock::from(rds);
+        assert_eq!(block.id, RdsBlockId::Invalid);
+        assert!(block.error);
+    }
+}
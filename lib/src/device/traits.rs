@@ -1,6 +1,8 @@
 use super::queue::{direction::Direction, Queue, QueueInit};
-use crate::ioctl::{self, DqBufResult, V4l2BufferFromError};
+use crate::ioctl::{self, DqBufResult, OsError, V4l2BufferFromError};
+use nix::errno::Errno;
 use std::fmt::Debug;
+use thiserror::Error;
 
 /// Trait for trying to dequeue a readable buffer from a queue.
 pub trait TryDequeue {
@@ -39,6 +41,44 @@ pub struct FreeBuffersResult<D: Direction, S: Stream> {
     pub canceled_buffers: Vec<S::Canceled>,
 }
 
+/// Error returned when freeing the buffers of a queue failed. The queue is
+/// returned untouched, so the handles of its queued buffers are not lost and can
+/// still be recovered, e.g. by trying again.
+#[derive(Error)]
+#[error("error while freeing buffers: {error}")]
+pub struct FreeBuffersError<Q> {
+    #[source]
+    pub error: ioctl::ReqbufsError,
+    pub queue: Q,
+}
+
+impl<Q> OsError for FreeBuffersError<Q> {
+    fn errno(&self) -> Option<Errno> {
+        self.error.errno()
+    }
+}
+
+/// The queue is dropped in the conversion.
+impl<Q> From<FreeBuffersError<Q>> for std::io::Error {
+    fn from(err: FreeBuffersError<Q>) -> Self {
+        err.error.into()
+    }
+}
+
+impl<Q> Debug for FreeBuffersError<Q> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("FreeBuffersError")
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl<Q> From<FreeBuffersError<Q>> for ioctl::ReqbufsError {
+    fn from(err: FreeBuffersError<Q>) -> Self {
+        err.error
+    }
+}
+
 /// Trait for a configured queue, i.e. a queue on which we can queue and dequeue
 /// buffers.
 pub trait AllocatedQueue<'a, D: Direction>: TryDequeue + Stream + Sized {
@@ -55,5 +95,13 @@ pub trait AllocatedQueue<'a, D: Direction>: TryDequeue + Stream + Sized {
     /// Release all the allocated buffers and returns the queue to the `Init` state.
     /// If successful, any queued buffer is also returned as canceled.
     /// In case of failure, the queue and its currently queued buffers are lost.
-    fn free_buffers(self) -> Result<FreeBuffersResult<D, Self>, ioctl::ReqbufsError>;
+    /// Use `try_free_buffers` to recover them in that case.
+    fn free_buffers(self) -> Result<FreeBuffersResult<D, Self>, ioctl::ReqbufsError> {
+        self.try_free_buffers().map_err(|e| e.error)
+    }
+
+    /// Same as `free_buffers`, but returns the queue along with the error in
+    /// case of failure, so the handles of its queued buffers can still be
+    /// recovered instead of being dropped with the queue.
+    fn try_free_buffers(self) -> Result<FreeBuffersResult<D, Self>, FreeBuffersError<Self>>;
 }
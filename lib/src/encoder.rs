@@ -1,41 +1,65 @@
 //! High-level interface for a [V4L2 video
 //! encoder](https://www.kernel.org/doc/html/latest/userspace-api/media/v4l/dev-encoder.html).
 use crate::{
+    bindings,
+    callback::{self, ChannelCloser, WorkerCallback},
+    controls::{
+        codec::{VideoBitrate, VideoBitratePeak, VideoHeaderMode},
+        descriptor::{validate_control, ControlValidationError},
+        AsV4l2ControlSlice, ExtControlTrait, SafeExtControl,
+    },
     device::{
-        poller::{DeviceEvent, PollError, PollEvent, Poller, Waker},
+        poller::{DeviceEvent, PollError, Poller, Readiness, Waker},
         queue::{
             direction::{Capture, Output},
-            dqbuf::DqBuffer,
+            dqbuf::{DqBuffer, FrameError},
             handles_provider::HandlesProvider,
             qbuf::{
                 get_free::{GetFreeBufferError, GetFreeCaptureBuffer, GetFreeOutputBuffer},
                 get_indexed::GetCaptureBufferByIndex,
-                CaptureQueueable, OutputQueueableProvider,
+                CaptureQueueable, OutputQueueable, OutputQueueableProvider,
             },
-            BuffersAllocated, CanceledBuffer, CreateQueueError, FormatBuilder, Queue, QueueInit,
-            RequestBuffersError,
+            watermark::{QueueLevel, QueueWatermark},
+            BuffersAllocated, CanceledBuffer, CreateQueueError, FormatBuilder, FormatOutcome,
+            Queue, QueueInit, RequestBuffersError, SetFormatError,
         },
         AllocatedQueue, Device, DeviceConfig, DeviceOpenError, Stream, TryDequeue,
     },
     ioctl::{
-        self, DqBufError, DqBufIoctlError, EncoderCommand, FormatFlags, GFmtError,
-        V4l2BufferFromError,
+        self, CtrlWhich, DqBufError, DqBufIoctlError, EncoderCommand, FormatFlags, GFmtError,
+        OsError, V4l2BufferFromError,
     },
-    memory::{BufferHandles, PrimitiveBufferHandles},
-    Format,
+    memory::{BufferHandles, Mappable, PrimitiveBufferHandles},
+    shutdown::ShutdownToken,
+    timestamp::timestamp_key,
+    Format, PixelFormat, QueueType,
 };
 
-use log::warn;
+use log::{debug, error, warn};
+use nix::errno::Errno;
 use std::{
     any::Any,
-    io,
+    cell::Cell,
+    convert::Infallible,
+    io::{self, Write},
+    ops::Deref,
     path::Path,
-    sync::{atomic::AtomicUsize, Arc},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     task::Wake,
     thread::JoinHandle,
+    time::Instant,
 };
 use thiserror::Error;
 
+pub mod multi;
+pub mod simple;
+pub mod stats;
+
+use stats::{EncoderStats, StatsTracker};
+
 /// Trait implemented by all states of the encoder.
 pub trait EncoderState {}
 
@@ -45,6 +69,487 @@ pub struct Encoder<S: EncoderState> {
     state: S,
 }
 
+#[derive(Debug, Error)]
+pub enum EncoderControlError {
+    #[error("invalid control for the encoder")]
+    InvalidControl(#[from] ControlValidationError),
+    #[error("encoded format {0} does not support this feature")]
+    UnsupportedCodec(PixelFormat),
+    #[error("too many layers requested: {0}")]
+    TooManyLayers(usize),
+    #[error("error while getting the encoded format")]
+    GetFormatError(#[from] GFmtError),
+    #[error("error while setting controls")]
+    ExtControlError(#[from] ioctl::ExtControlError),
+}
+
+impl OsError for EncoderControlError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            EncoderControlError::UnsupportedCodec(_) | EncoderControlError::TooManyLayers(_) => {
+                None
+            }
+            EncoderControlError::InvalidControl(e) => e.errno(),
+            EncoderControlError::GetFormatError(e) => e.errno(),
+            EncoderControlError::ExtControlError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(EncoderControlError);
+
+/// Maximum number of temporal layers supported by the V4L2 hierarchical coding controls.
+pub const MAX_TEMPORAL_LAYERS: usize = 7;
+
+/// Prediction structure used between the temporal layers of a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HierarchicalCodingType {
+    B,
+    P,
+}
+
+/// Parameters of a single temporal layer. Parameters left to `None` are not
+/// changed and keep whatever value the encoder currently uses.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TemporalLayerParams {
+    /// Bitrate of the layer in bits per second.
+    pub bitrate: Option<u32>,
+    /// Quantization parameter of the layer.
+    pub qp: Option<u32>,
+}
+
+/// Hierarchical coding configuration, used to produce streams with temporal
+/// scalability.
+#[derive(Debug, Clone)]
+pub struct TemporalLayers {
+    pub coding_type: HierarchicalCodingType,
+    /// Parameters of each layer, starting from the base layer. An empty vector
+    /// disables hierarchical coding.
+    pub layers: Vec<TemporalLayerParams>,
+}
+
+/// A set of encoder controls to be applied in one shot using
+/// `Encoder::apply_params()`.
+#[derive(Debug, Clone, Default)]
+pub struct EncoderParams {
+    controls: Vec<(u32, i32)>,
+}
+
+impl EncoderParams {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds control `id` with value `value` to the set. If the control is
+    /// already part of the set, its value is replaced.
+    pub fn with_control(mut self, id: u32, value: i32) -> Self {
+        match self.controls.iter_mut().find(|(ctrl_id, _)| *ctrl_id == id) {
+            Some(control) => control.1 = value,
+            None => self.controls.push((id, value)),
+        }
+        self
+    }
+
+    /// Returns the controls of the set, as `(id, value)` pairs.
+    pub fn controls(&self) -> &[(u32, i32)] {
+        &self.controls
+    }
+
+    /// Returns a preset suitable for low-latency use-cases like video calls:
+    /// no B-frames so frames are output in display order, cyclic intra refresh
+    /// instead of periodic key frames, small rate control buffers, and output
+    /// split into slices of limited size so they can be sent before the whole
+    /// frame is encoded.
+    ///
+    /// Not all encoders support all these controls, so the report returned by
+    /// `Encoder::apply_params()` should be checked to know which ones were
+    /// actually applied.
+    pub fn low_latency() -> Self {
+        /// Intra refresh period, in frames.
+        const INTRA_REFRESH_PERIOD: i32 = 30;
+        /// Size of the VBV and CPB buffers, in kilobytes.
+        const BUFFER_SIZE_KB: i32 = 32;
+        /// Maximum slice size, chosen to fit in a single network packet.
+        const SLICE_MAX_BYTES: i32 = 1200;
+
+        Self::new()
+            .with_control(bindings::V4L2_CID_MPEG_VIDEO_B_FRAMES, 0)
+            .with_control(
+                bindings::V4L2_CID_MPEG_VIDEO_INTRA_REFRESH_PERIOD_TYPE,
+                bindings::v4l2_mpeg_video_intra_refresh_period_type_V4L2_CID_MPEG_VIDEO_INTRA_REFRESH_PERIOD_TYPE_CYCLIC as i32,
+            )
+            .with_control(
+                bindings::V4L2_CID_MPEG_VIDEO_INTRA_REFRESH_PERIOD,
+                INTRA_REFRESH_PERIOD,
+            )
+            .with_control(bindings::V4L2_CID_MPEG_VIDEO_VBV_SIZE, BUFFER_SIZE_KB)
+            .with_control(bindings::V4L2_CID_MPEG_VIDEO_H264_CPB_SIZE, BUFFER_SIZE_KB)
+            .with_control(
+                bindings::V4L2_CID_MPEG_VIDEO_MULTI_SLICE_MODE,
+                bindings::v4l2_mpeg_video_multi_slice_mode_V4L2_MPEG_VIDEO_MULTI_SLICE_MODE_MAX_BYTES
+                    as i32,
+            )
+            .with_control(
+                bindings::V4L2_CID_MPEG_VIDEO_MULTI_SLICE_MAX_BYTES,
+                SLICE_MAX_BYTES,
+            )
+    }
+}
+
+/// Result of `Encoder::apply_params()`, listing which controls have been
+/// accepted by the driver and which ones have been rejected.
+#[derive(Debug, Default)]
+pub struct EncoderParamsReport {
+    /// Controls that have been applied, with the value reported by the driver
+    /// afterwards, which may differ from the requested one.
+    pub accepted: Vec<(u32, i32)>,
+    /// Controls that could not be applied, along with the reason.
+    pub rejected: Vec<(u32, EncoderControlError)>,
+}
+
+/// How the encoder outputs the stream headers (e.g. SPS and PPS for H.264).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum HeaderMode {
+    /// The headers are output in their own CAPTURE buffer, before the first
+    /// frame.
+    Separate = bindings::v4l2_mpeg_video_header_mode_V4L2_MPEG_VIDEO_HEADER_MODE_SEPARATE as i32,
+    /// The headers are output in the same CAPTURE buffer as the first frame.
+    JoinedWithFirstFrame =
+        bindings::v4l2_mpeg_video_header_mode_V4L2_MPEG_VIDEO_HEADER_MODE_JOINED_WITH_1ST_FRAME
+            as i32,
+}
+
+/// Returns the parameter sets (VPS, SPS and PPS) found in `data`, an Annex B
+/// H.264 or HEVC stream, or `None` if `data` contains none or `fourcc` is not
+/// one of these codecs.
+///
+/// The returned parameter sets are prefixed with a 4-byte start code each.
+fn extract_parameter_sets(data: &[u8], fourcc: &[u8; 4]) -> Option<Vec<u8>> {
+    let is_parameter_set: fn(u8) -> bool = match fourcc {
+        // SPS and PPS.
+        b"H264" => |header| matches!(header & 0x1f, 7 | 8),
+        // VPS, SPS and PPS.
+        b"HEVC" => |header| matches!((header >> 1) & 0x3f, 32..=34),
+        _ => return None,
+    };
+
+    // Positions right after each start code.
+    let nal_starts: Vec<usize> = data
+        .windows(3)
+        .enumerate()
+        .filter(|(_, window)| *window == [0, 0, 1])
+        .map(|(pos, _)| pos + 3)
+        .collect();
+
+    let mut parameter_sets = Vec::new();
+    for (i, &start) in nal_starts.iter().enumerate() {
+        let end = match nal_starts.get(i + 1) {
+            // Exclude the next start code, including the leading zero of a
+            // 4-byte one.
+            Some(&next) => {
+                let end = next - 3;
+                if end > start && data[end - 1] == 0 {
+                    end - 1
+                } else {
+                    end
+                }
+            }
+            None => data.len(),
+        };
+
+        if end > start && is_parameter_set(data[start]) {
+            parameter_sets.extend_from_slice(&[0, 0, 0, 1]);
+            parameter_sets.extend_from_slice(&data[start..end]);
+        }
+    }
+
+    if parameter_sets.is_empty() {
+        None
+    } else {
+        Some(parameter_sets)
+    }
+}
+
+/// Methods of the encoder that are available no matter the state.
+impl<S: EncoderState> Encoder<S> {
+    /// Sets the target bitrate of the encoded stream to `bps` bits per second,
+    /// and optionally its peak bitrate to `peak_bps`.
+    ///
+    /// This can be called while encoding to adapt the bitrate of the stream to
+    /// changing conditions. An error is returned without any control being
+    /// changed if the encoder does not support the controls or the requested
+    /// values.
+    pub fn set_bitrate(&self, bps: u32, peak_bps: Option<u32>) -> Result<(), EncoderControlError> {
+        validate_control(&*self.device, VideoBitrate::ID, bps as i64)?;
+
+        match peak_bps {
+            None => {
+                let mut bitrate = SafeExtControl::<VideoBitrate>::from_value(bps as i32);
+                ioctl::s_ext_ctrls(&*self.device, CtrlWhich::Current, &mut bitrate)?;
+            }
+            Some(peak_bps) => {
+                validate_control(&*self.device, VideoBitratePeak::ID, peak_bps as i64)?;
+
+                #[repr(C)]
+                struct BitrateControls {
+                    bitrate: SafeExtControl<VideoBitrate>,
+                    peak: SafeExtControl<VideoBitratePeak>,
+                }
+
+                impl AsV4l2ControlSlice for &mut BitrateControls {
+                    fn as_v4l2_control_slice(&mut self) -> &mut [bindings::v4l2_ext_control] {
+                        let ptr =
+                            (*self) as *mut BitrateControls as *mut bindings::v4l2_ext_control;
+                        unsafe { std::slice::from_raw_parts_mut(ptr, 2) }
+                    }
+                }
+
+                // Set both controls in the same call so they are applied atomically.
+                let mut controls = BitrateControls {
+                    bitrate: SafeExtControl::from_value(bps as i32),
+                    peak: SafeExtControl::from_value(peak_bps as i32),
+                };
+                ioctl::s_ext_ctrls(&*self.device, CtrlWhich::Current, &mut controls)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Selects how the stream headers are output by the encoder. This must be
+    /// done before the encoder is started.
+    pub fn set_header_mode(&self, mode: HeaderMode) -> Result<(), EncoderControlError> {
+        validate_control(&*self.device, VideoHeaderMode::ID, mode as i64)?;
+
+        let mut control = SafeExtControl::<VideoHeaderMode>::from_value(mode as i32);
+        ioctl::s_ext_ctrls(&*self.device, CtrlWhich::Current, &mut control)?;
+
+        Ok(())
+    }
+
+    /// Configures the temporal layers of the encoded stream, as used by
+    /// scalable video coding.
+    ///
+    /// The hierarchical coding controls to use are selected from the current
+    /// CAPTURE format, which must be H.264 or HEVC. All the controls are
+    /// validated before any of them is changed.
+    pub fn set_temporal_layers(&self, config: &TemporalLayers) -> Result<(), EncoderControlError> {
+        const H264_LAYER_BR: [u32; MAX_TEMPORAL_LAYERS] = [
+            bindings::V4L2_CID_MPEG_VIDEO_H264_HIER_CODING_L0_BR,
+            bindings::V4L2_CID_MPEG_VIDEO_H264_HIER_CODING_L1_BR,
+            bindings::V4L2_CID_MPEG_VIDEO_H264_HIER_CODING_L2_BR,
+            bindings::V4L2_CID_MPEG_VIDEO_H264_HIER_CODING_L3_BR,
+            bindings::V4L2_CID_MPEG_VIDEO_H264_HIER_CODING_L4_BR,
+            bindings::V4L2_CID_MPEG_VIDEO_H264_HIER_CODING_L5_BR,
+            bindings::V4L2_CID_MPEG_VIDEO_H264_HIER_CODING_L6_BR,
+        ];
+        const HEVC_LAYER_BR: [u32; MAX_TEMPORAL_LAYERS] = [
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L0_BR,
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L1_BR,
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L2_BR,
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L3_BR,
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L4_BR,
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L5_BR,
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L6_BR,
+        ];
+        const HEVC_LAYER_QP: [u32; MAX_TEMPORAL_LAYERS] = [
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L0_QP,
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L1_QP,
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L2_QP,
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L3_QP,
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L4_QP,
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L5_QP,
+            bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_L6_QP,
+        ];
+
+        let num_layers = config.layers.len();
+        if num_layers > MAX_TEMPORAL_LAYERS {
+            return Err(EncoderControlError::TooManyLayers(num_layers));
+        }
+
+        let format: Format = ioctl::g_fmt(&*self.device, QueueType::VideoCaptureMplane)?;
+
+        // Controls that can be set in a single call, as (id, value) pairs.
+        let mut controls: Vec<(u32, i32)> = Vec::new();
+        // H.264 layer QPs all use the same control and thus need one call each.
+        let mut h264_layer_qps: Vec<i32> = Vec::new();
+
+        match &format.pixelformat.to_fourcc() {
+            b"H264" => {
+                controls.push((
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_HIERARCHICAL_CODING,
+                    (num_layers > 0) as i32,
+                ));
+                if num_layers > 0 {
+                    controls.push((
+                        bindings::V4L2_CID_MPEG_VIDEO_H264_HIERARCHICAL_CODING_TYPE,
+                        match config.coding_type {
+                            HierarchicalCodingType::B => bindings::v4l2_mpeg_video_h264_hierarchical_coding_type_V4L2_MPEG_VIDEO_H264_HIERARCHICAL_CODING_B,
+                            HierarchicalCodingType::P => bindings::v4l2_mpeg_video_h264_hierarchical_coding_type_V4L2_MPEG_VIDEO_H264_HIERARCHICAL_CODING_P,
+                        } as i32,
+                    ));
+                    controls.push((
+                        bindings::V4L2_CID_MPEG_VIDEO_H264_HIERARCHICAL_CODING_LAYER,
+                        num_layers as i32,
+                    ));
+                }
+                for (i, layer) in config.layers.iter().enumerate() {
+                    if let Some(bitrate) = layer.bitrate {
+                        controls.push((H264_LAYER_BR[i], bitrate as i32));
+                    }
+                    // The layer index is encoded in the upper 16 bits of the value.
+                    if let Some(qp) = layer.qp {
+                        h264_layer_qps.push(((i as i32) << 16) | qp as i32);
+                    }
+                }
+            }
+            b"HEVC" => {
+                if num_layers > 0 {
+                    controls.push((
+                        bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_TYPE,
+                        match config.coding_type {
+                            HierarchicalCodingType::B => bindings::v4l2_mpeg_video_hevc_hier_coding_type_V4L2_MPEG_VIDEO_HEVC_HIERARCHICAL_CODING_B,
+                            HierarchicalCodingType::P => bindings::v4l2_mpeg_video_hevc_hier_coding_type_V4L2_MPEG_VIDEO_HEVC_HIERARCHICAL_CODING_P,
+                        } as i32,
+                    ));
+                }
+                controls.push((
+                    bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_CODING_LAYER,
+                    num_layers as i32,
+                ));
+                controls.push((
+                    bindings::V4L2_CID_MPEG_VIDEO_HEVC_HIER_QP,
+                    config.layers.iter().any(|layer| layer.qp.is_some()) as i32,
+                ));
+                for (i, layer) in config.layers.iter().enumerate() {
+                    if let Some(bitrate) = layer.bitrate {
+                        controls.push((HEVC_LAYER_BR[i], bitrate as i32));
+                    }
+                    if let Some(qp) = layer.qp {
+                        controls.push((HEVC_LAYER_QP[i], qp as i32));
+                    }
+                }
+            }
+            _ => return Err(EncoderControlError::UnsupportedCodec(format.pixelformat)),
+        }
+
+        for (id, value) in &controls {
+            validate_control(&*self.device, *id, *value as i64)?;
+        }
+        for value in &h264_layer_qps {
+            validate_control(
+                &*self.device,
+                bindings::V4L2_CID_MPEG_VIDEO_H264_HIERARCHICAL_CODING_LAYER_QP,
+                *value as i64,
+            )?;
+        }
+
+        let mut ext_controls: Vec<_> = controls
+            .into_iter()
+            .map(|(id, value)| bindings::v4l2_ext_control {
+                id,
+                __bindgen_anon_1: bindings::v4l2_ext_control__bindgen_ty_1 { value },
+                ..Default::default()
+            })
+            .collect();
+        ioctl::s_ext_ctrls(
+            &*self.device,
+            CtrlWhich::Current,
+            ext_controls.as_mut_slice(),
+        )?;
+
+        for value in h264_layer_qps {
+            let mut layer_qp = bindings::v4l2_ext_control {
+                id: bindings::V4L2_CID_MPEG_VIDEO_H264_HIERARCHICAL_CODING_LAYER_QP,
+                __bindgen_anon_1: bindings::v4l2_ext_control__bindgen_ty_1 { value },
+                ..Default::default()
+            };
+            ioctl::s_ext_ctrls(
+                &*self.device,
+                CtrlWhich::Current,
+                std::slice::from_mut(&mut layer_qp),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies all the controls of `params` that are supported by the encoder.
+    ///
+    /// Unlike the other control methods of the encoder, unsupported controls
+    /// do not make the whole operation fail: they are skipped and listed in
+    /// the returned report, while the supported ones are applied together.
+    pub fn apply_params(&self, params: &EncoderParams) -> EncoderParamsReport {
+        let mut report = EncoderParamsReport::default();
+
+        let to_ext_control = |(id, value): (u32, i32)| bindings::v4l2_ext_control {
+            id,
+            __bindgen_anon_1: bindings::v4l2_ext_control__bindgen_ty_1 { value },
+            ..Default::default()
+        };
+
+        let mut valid = Vec::new();
+        for &(id, value) in params.controls() {
+            match validate_control(&*self.device, id, value as i64) {
+                Ok(()) => valid.push((id, value)),
+                Err(e) => report.rejected.push((id, e.into())),
+            }
+        }
+
+        let mut ext_controls: Vec<_> = valid.iter().copied().map(to_ext_control).collect();
+        let batch_res = if ext_controls.is_empty() {
+            Ok(())
+        } else {
+            ioctl::s_ext_ctrls(
+                &*self.device,
+                CtrlWhich::Current,
+                ext_controls.as_mut_slice(),
+            )
+        };
+
+        // If the driver rejected the batch, find out which controls it accepts
+        // by setting them one by one.
+        let accepted: Vec<_> = match batch_res {
+            Ok(()) => valid,
+            Err(_) => valid
+                .into_iter()
+                .filter(|&control| {
+                    let mut ext_control = to_ext_control(control);
+                    match ioctl::s_ext_ctrls(
+                        &*self.device,
+                        CtrlWhich::Current,
+                        std::slice::from_mut(&mut ext_control),
+                    ) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            report.rejected.push((control.0, e.into()));
+                            false
+                        }
+                    }
+                })
+                .collect(),
+        };
+
+        // Read the values back, as the driver may have adjusted them.
+        for (id, value) in accepted {
+            let mut ext_control = to_ext_control((id, value));
+            let value = match ioctl::g_ext_ctrls(
+                &*self.device,
+                CtrlWhich::Current,
+                std::slice::from_mut(&mut ext_control),
+            ) {
+                // SAFETY: all the controls we set are of integer type.
+                Ok(()) => unsafe { ext_control.__bindgen_anon_1.value },
+                Err(_) => value,
+            };
+            report.accepted.push((id, value));
+        }
+
+        report
+    }
+}
+
 pub struct AwaitingCaptureFormat {
     output_queue: Queue<Output, QueueInit>,
     capture_queue: Queue<Capture, QueueInit>,
@@ -61,6 +566,18 @@ pub enum EncoderOpenError {
     NotAnEncoder,
 }
 
+impl OsError for EncoderOpenError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            EncoderOpenError::DeviceOpenError(e) => e.errno(),
+            EncoderOpenError::CreateQueueError(e) => e.errno(),
+            EncoderOpenError::NotAnEncoder => None,
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(EncoderOpenError);
+
 impl Encoder<AwaitingCaptureFormat> {
     pub fn open(path: &Path) -> Result<Self, EncoderOpenError> {
         let config = DeviceConfig::new().non_blocking_dqbuf();
@@ -92,20 +609,27 @@ impl Encoder<AwaitingCaptureFormat> {
         })
     }
 
-    pub fn set_capture_format<F>(mut self, f: F) -> anyhow::Result<Encoder<AwaitingOutputFormat>>
+    /// Apply the CAPTURE format configured by `f`. The returned outcome tells
+    /// whether the driver adjusted the requested format.
+    pub fn set_capture_format<F>(
+        mut self,
+        f: F,
+    ) -> Result<(Encoder<AwaitingOutputFormat>, FormatOutcome), SetFormatError>
     where
-        F: FnOnce(FormatBuilder) -> anyhow::Result<()>,
+        F: FnOnce(FormatBuilder) -> FormatBuilder,
     {
-        let builder = self.state.capture_queue.change_format()?;
-        f(builder)?;
+        let outcome = f(self.state.capture_queue.change_format()?).apply_checked()?;
 
-        Ok(Encoder {
-            device: self.device,
-            state: AwaitingOutputFormat {
-                output_queue: self.state.output_queue,
-                capture_queue: self.state.capture_queue,
+        Ok((
+            Encoder {
+                device: self.device,
+                state: AwaitingOutputFormat {
+                    output_queue: self.state.output_queue,
+                    capture_queue: self.state.capture_queue,
+                },
             },
-        })
+            outcome,
+        ))
     }
 }
 
@@ -116,20 +640,27 @@ pub struct AwaitingOutputFormat {
 impl EncoderState for AwaitingOutputFormat {}
 
 impl Encoder<AwaitingOutputFormat> {
-    pub fn set_output_format<F>(mut self, f: F) -> anyhow::Result<Encoder<AwaitingOutputBuffers>>
+    /// Apply the OUTPUT format configured by `f`. The returned outcome tells
+    /// whether the driver adjusted the requested format.
+    pub fn set_output_format<F>(
+        mut self,
+        f: F,
+    ) -> Result<(Encoder<AwaitingOutputBuffers>, FormatOutcome), SetFormatError>
     where
-        F: FnOnce(FormatBuilder) -> anyhow::Result<()>,
+        F: FnOnce(FormatBuilder) -> FormatBuilder,
     {
-        let builder = self.state.output_queue.change_format()?;
-        f(builder)?;
+        let outcome = f(self.state.output_queue.change_format()?).apply_checked()?;
 
-        Ok(Encoder {
-            device: self.device,
-            state: AwaitingOutputBuffers {
-                output_queue: self.state.output_queue,
-                capture_queue: self.state.capture_queue,
+        Ok((
+            Encoder {
+                device: self.device,
+                state: AwaitingOutputBuffers {
+                    output_queue: self.state.output_queue,
+                    capture_queue: self.state.capture_queue,
+                },
             },
-        })
+            outcome,
+        ))
     }
 }
 
@@ -145,6 +676,8 @@ impl Encoder<AwaitingOutputBuffers> {
         memory_type: OP::SupportedMemoryType,
         num_output: usize,
     ) -> Result<Encoder<AwaitingCaptureBuffers<OP>>, RequestBuffersError> {
+        let num_output = self.state.output_queue.clamp_num_buffers(num_output);
+
         Ok(Encoder {
             device: self.device,
             state: AwaitingCaptureBuffers {
@@ -190,6 +723,8 @@ impl<OP: BufferHandles> Encoder<AwaitingCaptureBuffers<OP>> {
         for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
             GetFreeCaptureBuffer<'a, P::HandleType>,
     {
+        let num_capture = self.state.capture_queue.clamp_num_buffers(num_capture);
+
         Ok(Encoder {
             device: self.device,
             state: ReadyToEncode {
@@ -200,6 +735,11 @@ impl<OP: BufferHandles> Encoder<AwaitingCaptureBuffers<OP>> {
                     .request_buffers_generic::<P::HandleType>(memory_type, num_capture as u32)?,
                 capture_memory_provider,
                 poll_wakeups_counter: None,
+                empty_output_handles: None,
+                header_extractor: None,
+                capture_watermark: None,
+                frame_limit: None,
+                shutdown_token: None,
             },
         })
     }
@@ -227,9 +767,24 @@ pub struct ReadyToEncode<OP: BufferHandles, P: HandlesProvider> {
     capture_queue: Queue<Capture, BuffersAllocated<P::HandleType>>,
     capture_memory_provider: P,
     poll_wakeups_counter: Option<Arc<AtomicUsize>>,
+    empty_output_handles: Option<EmptyHandlesCb<OP>>,
+    header_extractor: Option<HeaderExtractor<P::HandleType>>,
+    capture_watermark: Option<QueueWatermark>,
+    frame_limit: Option<usize>,
+    shutdown_token: Option<ShutdownToken>,
 }
 impl<OP: BufferHandles, P: HandlesProvider> EncoderState for ReadyToEncode<OP, P> {}
 
+/// Callback returning the handles to use for an empty OUTPUT buffer.
+type EmptyHandlesCb<OP> = Box<dyn Fn() -> OP + Send>;
+
+/// Callback extracting the stream headers from an encoded buffer, if it
+/// contains any.
+type HeaderExtractor<H> = Box<dyn Fn(&DqBuffer<Capture, H>) -> Option<Vec<u8>> + Send>;
+
+/// Stream headers captured by the encoder thread.
+type StreamHeaders = Arc<Mutex<Option<Vec<u8>>>>;
+
 impl<OP: BufferHandles, P: HandlesProvider> Encoder<ReadyToEncode<OP, P>>
 where
     for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
@@ -240,6 +795,76 @@ where
         self
     }
 
+    /// Sets the callback used to obtain the handles of an empty OUTPUT buffer.
+    ///
+    /// Drivers that do not support `VIDIOC_ENCODER_CMD` signal the end of the
+    /// stream by queueing an OUTPUT buffer with no data. `stop()` automatically
+    /// does so when the command is not supported, using this callback to obtain
+    /// the handles to queue the empty buffer with. Since such drivers may not
+    /// set the LAST flag, an empty CAPTURE buffer then also ends the stream.
+    pub fn set_empty_output_handles<F: Fn() -> OP + Send + 'static>(mut self, f: F) -> Self {
+        self.state.empty_output_handles = Some(Box::new(f));
+        self
+    }
+
+    /// Makes the encoder capture the stream headers from the encoded buffers,
+    /// so they can be obtained with `get_stream_headers()` once encoding has
+    /// started.
+    ///
+    /// The headers are found either in the separate header buffer or in the
+    /// first frame, depending on the `HeaderMode` of the encoder. Only H.264
+    /// and HEVC are supported.
+    pub fn capture_stream_headers(mut self) -> Result<Self, GFmtError>
+    where
+        P::HandleType: PrimitiveBufferHandles,
+        <P::HandleType as PrimitiveBufferHandles>::HandleType: Mappable,
+    {
+        let format: Format = self.state.capture_queue.get_format()?;
+        let fourcc = format.pixelformat.to_fourcc();
+
+        self.state.header_extractor = Some(Box::new(move |buffer| {
+            let mapping = buffer.get_plane_mapping(0)?;
+            let bytes_used = *buffer.data.get_first_plane().bytesused as usize;
+            extract_parameter_sets(&mapping[..bytes_used.min(mapping.len())], &fourcc)
+        }));
+
+        Ok(self)
+    }
+
+    /// Makes the encoder invoke `cb` when less than `watermark` CAPTURE
+    /// buffers are queued, and again once the queue recovers, so the client
+    /// can prioritize returning encoded buffers before the driver stalls.
+    pub fn set_capture_watermark<F: FnMut(QueueLevel) + Send + 'static>(
+        mut self,
+        watermark: usize,
+        cb: F,
+    ) -> Self {
+        self.state.capture_watermark = Some(QueueWatermark::new(watermark, cb));
+        self
+    }
+
+    /// Makes the encoder accept at most `limit` frames, after which
+    /// `get_buffer()` fails with `FrameLimitReached`.
+    ///
+    /// Frames are counted once their OUTPUT buffer is queued, so a buffer
+    /// dropped without being queued or canceled by `discard_pending_inputs()`
+    /// does not count. Once the limit is reached, the encoder starts draining
+    /// on its own: the frames still being encoded are delivered and the encoder
+    /// thread exits after the last one. The client then calls `stop()` to get
+    /// the encoder back.
+    pub fn set_frame_limit(mut self, limit: usize) -> Self {
+        self.state.frame_limit = Some(limit);
+        self
+    }
+
+    /// Makes `get_buffer()` fail with `PollError::Interrupted` instead of
+    /// waiting once shutdown is requested on `token`. The client can then call
+    /// `stop()`, which still waits for the frames being encoded.
+    pub fn set_shutdown_token(mut self, token: ShutdownToken) -> Self {
+        self.state.shutdown_token = Some(token);
+        self
+    }
+
     pub fn start<InputDoneCb, OutputReadyCb>(
         self,
         input_done_cb: InputDoneCb,
@@ -247,20 +872,31 @@ where
     ) -> io::Result<Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>>
     where
         InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-        OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send + 'static,
+        OutputReadyCb: FnMut(CapturedFrame<P::HandleType>) + Send + 'static,
     {
         self.state.output_queue.stream_on().unwrap();
         self.state.capture_queue.stream_on().unwrap();
 
         let mut output_poller = Poller::new(Arc::clone(&self.device))?;
         output_poller.enable_event(DeviceEvent::OutputReady)?;
+        if let Some(token) = &self.state.shutdown_token {
+            output_poller.set_shutdown_token(token.clone())?;
+        }
 
+        let stream_headers: StreamHeaders = Default::default();
+        let stats: Arc<Mutex<StatsTracker>> = Default::default();
+        let empty_buffer_eos: Arc<AtomicBool> = Default::default();
         let mut encoder_thread = EncoderThread::new(
             &self.device,
             self.state.capture_queue,
             self.state.capture_memory_provider,
             output_ready_cb,
+            self.state.header_extractor,
+            Arc::clone(&stream_headers),
+            Arc::clone(&stats),
+            self.state.capture_watermark,
         )?;
+        encoder_thread.empty_buffer_eos = Arc::clone(&empty_buffer_eos);
 
         if let Some(counter) = &self.state.poll_wakeups_counter {
             output_poller.set_poll_counter(Arc::clone(counter));
@@ -277,21 +913,352 @@ where
                 output_queue: self.state.output_queue,
                 input_done_cb,
                 output_poller,
+                empty_output_handles: self.state.empty_output_handles,
+                stream_headers,
+                stats,
+                frame_limit: FrameLimit::new(self.state.frame_limit),
+                drain_started: Cell::new(false),
+                output_streamed_off: Cell::new(false),
+                shutdown_token: self.state.shutdown_token,
+                output_channel_closer: None,
+                empty_buffer_eos,
                 handle,
             },
         })
     }
+
+    /// Starts the encoder like `start()`, but reassembles the encoded frames
+    /// that the driver splits across several CAPTURE buffers so that
+    /// `frame_ready_cb` always receives complete frames.
+    #[allow(clippy::type_complexity)]
+    pub fn start_with_frame_reassembly<InputDoneCb, FrameReadyCb>(
+        self,
+        config: FrameReassemblyConfig,
+        input_done_cb: InputDoneCb,
+        frame_ready_cb: FrameReadyCb,
+    ) -> io::Result<
+        Encoder<
+            Encoding<OP, P, InputDoneCb, impl FnMut(CapturedFrame<P::HandleType>) + Send + 'static>,
+        >,
+    >
+    where
+        InputDoneCb: Fn(CompletedOutputBuffer<OP>),
+        FrameReadyCb: FnMut(EncodedFrame<P::HandleType>) + Send + 'static,
+    {
+        let mut reassembler = FrameReassembler::new(config, frame_ready_cb);
+        self.start(input_done_cb, move |frame| reassembler.push(frame.detach()))
+    }
+
+    /// Starts the encoder like `start()`, but runs `input_done_cb` and
+    /// `output_ready_cb` on dedicated worker threads, so heavyweight callbacks
+    /// do not delay the dequeuing of buffers.
+    ///
+    /// Stopping the encoder waits for the worker threads to process all the
+    /// buffers they have received.
+    #[allow(clippy::type_complexity)]
+    pub fn start_with_worker_threads<InputDoneCb, OutputReadyCb>(
+        self,
+        input_done_cb: InputDoneCb,
+        output_ready_cb: OutputReadyCb,
+    ) -> io::Result<
+        Encoder<
+            Encoding<
+                OP,
+                P,
+                impl Fn(CompletedOutputBuffer<OP>),
+                impl FnMut(CapturedFrame<P::HandleType>) + Send + 'static,
+            >,
+        >,
+    >
+    where
+        InputDoneCb: FnMut(CompletedOutputBuffer<OP>) + Send + 'static,
+        OutputReadyCb: FnMut(CapturedFrame<P::HandleType>) + Send + 'static,
+        CompletedOutputBuffer<OP>: Send + 'static,
+        CapturedFrame<P::HandleType>: Send + 'static,
+    {
+        let input_worker = WorkerCallback::new("V4L2 Encoder input", input_done_cb)?;
+        let output_worker = WorkerCallback::new("V4L2 Encoder output", output_ready_cb)?;
+
+        self.start(
+            move |buffer| input_worker.call(buffer),
+            move |buffer| output_worker.call(buffer),
+        )
+    }
+
+    /// Starts the encoder like `start()`, but sends the encoded buffers into a
+    /// channel that can hold `capacity` buffers instead of invoking a
+    /// callback. The client drains the returned receiver at its own pace.
+    ///
+    /// The encoder thread stops dequeuing buffers while the channel is full.
+    /// `stop()` closes the channel, after which the buffers that do not fit in
+    /// it are dropped instead, so stopping does not wait on a client that no
+    /// longer drains the receiver. The buffers already in the channel can still
+    /// be received after `stop()` returns.
+    ///
+    /// `capacity` must be smaller than the number of CAPTURE buffers, so the
+    /// driver always has a buffer to signal the end of the stream with.
+    #[allow(clippy::type_complexity)]
+    pub fn start_with_output_channel<InputDoneCb>(
+        self,
+        input_done_cb: InputDoneCb,
+        capacity: usize,
+    ) -> io::Result<(
+        Encoder<
+            Encoding<OP, P, InputDoneCb, impl FnMut(CapturedFrame<P::HandleType>) + Send + 'static>,
+        >,
+        mpsc::Receiver<CapturedFrame<P::HandleType>>,
+    )>
+    where
+        InputDoneCb: Fn(CompletedOutputBuffer<OP>),
+        CapturedFrame<P::HandleType>: Send + 'static,
+    {
+        let num_capture_buffers = self.state.capture_queue.num_buffers();
+        if capacity >= num_capture_buffers {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "channel capacity {} is not smaller than the number of CAPTURE buffers ({})",
+                    capacity, num_capture_buffers
+                ),
+            ));
+        }
+
+        let (output_channel, receiver) = callback::bounded_channel(capacity);
+        let closer = output_channel.closer();
+        let mut encoder = self.start(input_done_cb, move |buffer| output_channel.call(buffer))?;
+        encoder.state.output_channel_closer = Some(closer);
+
+        Ok((encoder, receiver))
+    }
+}
+
+/// Configuration of the reassembly of encoded frames split across several
+/// CAPTURE buffers.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameReassemblyConfig {
+    /// Maximum size in bytes of a reassembled frame. Frames that grow larger
+    /// than this are discarded, which also prevents all the CAPTURE buffers
+    /// from being held by a frame that never completes.
+    pub max_frame_size: usize,
+}
+
+/// A complete encoded frame, made of one or more CAPTURE buffers in stream
+/// order.
+pub struct EncodedFrame<H: BufferHandles> {
+    pub buffers: Vec<DqBuffer<Capture, H>>,
+}
+
+impl<H: BufferHandles> EncodedFrame<H> {
+    /// Returns the total number of bytes of encoded data in the frame.
+    pub fn bytes_used(&self) -> usize {
+        self.buffers
+            .iter()
+            .map(|buffer| *buffer.data.get_first_plane().bytesused as usize)
+            .sum()
+    }
+
+    /// Returns the timestamp of the frame.
+    pub fn timestamp(&self) -> bindings::timeval {
+        // A frame always has at least one buffer.
+        self.buffers[0].data.timestamp()
+    }
+}
+
+/// Accumulates the CAPTURE buffers making up an encoded frame until it is
+/// complete, and then passes the whole frame to `frame_ready_cb`.
+///
+/// A frame is assumed to be complete when its last buffer is not entirely
+/// filled (a driver only moves to the next buffer when the current one is
+/// full), when the buffer has the LAST flag, or when a buffer with a
+/// different timestamp is received.
+struct FrameReassembler<H: BufferHandles, FrameReadyCb: FnMut(EncodedFrame<H>)> {
+    config: FrameReassemblyConfig,
+    pending: Vec<DqBuffer<Capture, H>>,
+    pending_size: usize,
+    /// Timestamp of a discarded frame whose remaining buffers must be skipped.
+    discarding: Option<i64>,
+    frame_ready_cb: FrameReadyCb,
+}
+
+impl<H: BufferHandles, FrameReadyCb: FnMut(EncodedFrame<H>)> FrameReassembler<H, FrameReadyCb> {
+    fn new(config: FrameReassemblyConfig, frame_ready_cb: FrameReadyCb) -> Self {
+        Self {
+            config,
+            pending: Vec::new(),
+            pending_size: 0,
+            discarding: None,
+            frame_ready_cb,
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            self.pending_size = 0;
+            (self.frame_ready_cb)(EncodedFrame {
+                buffers: std::mem::take(&mut self.pending),
+            });
+        }
+    }
+
+    fn push(&mut self, buffer: DqBuffer<Capture, H>) {
+        let timestamp = timestamp_key(&buffer.data.timestamp());
+        let plane = buffer.data.get_first_plane();
+        let (bytes_used, length) = (*plane.bytesused as usize, *plane.length as usize);
+        // Whether this buffer is the last one of its frame.
+        let ends_frame = bytes_used < length || buffer.data.is_last();
+
+        // Skip the rest of a discarded frame, so it is not emitted truncated.
+        if let Some(discarded) = self.discarding {
+            if discarded == timestamp {
+                if ends_frame {
+                    self.discarding = None;
+                }
+                return;
+            }
+            self.discarding = None;
+        }
+
+        // A new timestamp means the pending frame is over.
+        if let Some(first) = self.pending.first() {
+            if timestamp_key(&first.data.timestamp()) != timestamp {
+                self.flush();
+            }
+        }
+
+        self.pending_size += bytes_used;
+        self.pending.push(buffer);
+
+        if self.pending_size > self.config.max_frame_size {
+            warn!(
+                "Discarding encoded frame larger than the maximum size of {} bytes",
+                self.config.max_frame_size
+            );
+            self.pending.clear();
+            self.pending_size = 0;
+            if !ends_frame {
+                self.discarding = Some(timestamp);
+            }
+        } else if ends_frame {
+            self.flush();
+        }
+    }
+}
+
+impl<H: BufferHandles, FrameReadyCb: FnMut(EncodedFrame<H>)> Drop
+    for FrameReassembler<H, FrameReadyCb>
+{
+    fn drop(&mut self) {
+        // Do not lose a frame that completely filled its last buffer at the
+        // end of the stream.
+        self.flush();
+    }
+}
+
+/// An encoded CAPTURE buffer, as passed to the `output_ready_cb` of the
+/// encoder.
+///
+/// The buffer is lent to the client: dropping the `CapturedFrame` re-queues it
+/// so the encoder can fill it again. Clients that need to keep the encoded
+/// data for longer can either hold on to the buffer with `detach()`, at the
+/// cost of keeping it away from the encoder, or copy its data with
+/// `detach_copy()` and return the buffer right away.
+///
+/// The timestamp of the frame is the one of the OUTPUT buffer it has been
+/// encoded from, which [`crate::timestamp::TimestampTracker`] can use to
+/// retrieve the data attached to that OUTPUT buffer.
+pub struct CapturedFrame<H: BufferHandles> {
+    buffer: DqBuffer<Capture, H>,
+}
+
+impl<H: BufferHandles> Deref for CapturedFrame<H> {
+    type Target = DqBuffer<Capture, H>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buffer
+    }
+}
+
+impl<H: BufferHandles> std::fmt::Debug for CapturedFrame<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.buffer.fmt(f)
+    }
+}
+
+impl<H: BufferHandles> CapturedFrame<H> {
+    /// Returns the frame as a `FrameError` if the driver reported an error
+    /// while encoding it, in which case its data may be corrupted.
+    pub fn into_result(self) -> Result<Self, FrameError<Self>> {
+        if self.buffer.has_error() {
+            Err(FrameError { buffer: self })
+        } else {
+            Ok(self)
+        }
+    }
+
+    /// Returns the number of bytes of encoded data in the frame.
+    pub fn bytes_used(&self) -> usize {
+        *self.buffer.data.get_first_plane().bytesused as usize
+    }
+
+    /// Keep the underlying buffer for as long as needed. The buffer is only
+    /// re-queued once the returned `DqBuffer` is dropped, so the encoder has
+    /// one less buffer to work with in the meantime.
+    pub fn detach(self) -> DqBuffer<Capture, H> {
+        self.buffer
+    }
+}
+
+impl<H> CapturedFrame<H>
+where
+    H: PrimitiveBufferHandles,
+    H::HandleType: Mappable,
+{
+    /// Write the encoded data of all the planes of the frame into `writer`
+    /// directly from their mappings, e.g. to save the stream to a file or
+    /// send it over a socket. Returns the number of bytes written.
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> io::Result<usize> {
+        let mappings = self
+            .buffer
+            .get_plane_mappings()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to map CAPTURE buffer"))?;
+
+        mappings.write_to(writer)
+    }
+
+    /// Copy the encoded data of all the planes of the frame, one after the
+    /// other, and re-queue the buffer immediately. Returns `None` if the
+    /// buffer could not be mapped.
+    pub fn detach_copy(self) -> Option<Vec<u8>> {
+        Some(self.buffer.get_plane_mappings()?.to_vec())
+    }
 }
 
 pub struct Encoding<OP: BufferHandles, P, InputDoneCb, OutputReadyCb>
 where
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(CapturedFrame<P::HandleType>) + Send,
 {
     output_queue: Queue<Output, BuffersAllocated<OP>>,
     input_done_cb: InputDoneCb,
     output_poller: Poller,
+    empty_output_handles: Option<EmptyHandlesCb<OP>>,
+    stream_headers: StreamHeaders,
+    stats: Arc<Mutex<StatsTracker>>,
+    frame_limit: FrameLimit,
+    /// Set once the end of the stream has been signaled to the driver.
+    drain_started: Cell<bool>,
+    /// Set if `discard_pending_inputs()` failed to stream the OUTPUT queue
+    /// back on.
+    output_streamed_off: Cell<bool>,
+    shutdown_token: Option<ShutdownToken>,
+    /// Set if the encoded buffers are sent into a channel, which must stop
+    /// blocking the encoder thread while stopping.
+    output_channel_closer: Option<ChannelCloser>,
+    /// Set once the end of the stream has been signaled with an empty OUTPUT
+    /// buffer, which makes the encoder thread also stop on an empty CAPTURE
+    /// buffer.
+    empty_buffer_eos: Arc<AtomicBool>,
 
     handle: JoinHandle<EncoderThread<P, OutputReadyCb>>,
 }
@@ -300,7 +1267,7 @@ where
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(CapturedFrame<P::HandleType>) + Send,
 {
 }
 
@@ -320,18 +1287,128 @@ pub enum GetBufferError {
     PollError(#[from] PollError),
     #[error("error while obtaining buffer")]
     GetFreeBufferError(#[from] GetFreeBufferError),
+    #[error("the frame limit of the encoder has been reached")]
+    FrameLimitReached,
+    #[error("error while sending STOP command after the last frame")]
+    EncoderCmdError(#[source] ioctl::EncoderCmdError),
+    #[error("error while queueing an empty OUTPUT buffer after the last frame")]
+    QueueEmptyBufferError(#[source] ioctl::QBufError<Infallible>),
 }
 
+impl OsError for GetBufferError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            GetBufferError::DequeueError(e) => e.errno(),
+            GetBufferError::PollError(e) => e.errno(),
+            GetBufferError::GetFreeBufferError(e) => e.errno(),
+            GetBufferError::FrameLimitReached => None,
+            GetBufferError::EncoderCmdError(e) => e.errno(),
+            GetBufferError::QueueEmptyBufferError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(GetBufferError);
+
 #[derive(Debug, Error)]
 pub enum EncoderStopError {
     #[error("error while sending STOP command")]
     EncoderCmdError(#[from] ioctl::EncoderCmdError),
+    #[error("STOP command not supported and no empty OUTPUT handles callback set")]
+    NoEmptyOutputHandles,
+    #[error("error while obtaining an empty OUTPUT buffer")]
+    GetEmptyBufferError(#[from] GetBufferError),
+    #[error("error while queueing an empty OUTPUT buffer")]
+    QueueEmptyBufferError(#[source] ioctl::QBufError<Infallible>),
     #[error("thread has panicked")]
     ThreadPanickedError(Box<dyn Any + Send + 'static>),
     #[error("cannot streamoff capture queue")]
-    CaptureQueueStreamoffError(ioctl::StreamOffError),
+    CaptureQueueStreamoffError(#[source] ioctl::StreamOffError),
+    #[error("cannot streamoff output queue")]
+    OutputQueueStreamoffError(#[source] ioctl::StreamOffError),
+}
+
+impl OsError for EncoderStopError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            EncoderStopError::EncoderCmdError(e) => e.errno(),
+            EncoderStopError::NoEmptyOutputHandles => None,
+            EncoderStopError::GetEmptyBufferError(e) => e.errno(),
+            EncoderStopError::QueueEmptyBufferError(e) => e.errno(),
+            EncoderStopError::ThreadPanickedError(_) => None,
+            EncoderStopError::CaptureQueueStreamoffError(e)
+            | EncoderStopError::OutputQueueStreamoffError(e) => e.errno(),
+        }
+    }
+}
+
+/// The panic payload of `ThreadPanickedError` is not `Sync`, so errors that do not wrap another
+/// error are converted into their message.
+impl From<EncoderStopError> for io::Error {
+    fn from(err: EncoderStopError) -> Self {
+        match err {
+            EncoderStopError::EncoderCmdError(e) => e.into(),
+            EncoderStopError::GetEmptyBufferError(e) => e.into(),
+            EncoderStopError::QueueEmptyBufferError(e) => e.into(),
+            EncoderStopError::CaptureQueueStreamoffError(e)
+            | EncoderStopError::OutputQueueStreamoffError(e) => e.into(),
+            EncoderStopError::NoEmptyOutputHandles | EncoderStopError::ThreadPanickedError(_) => {
+                io::Error::new(io::ErrorKind::Other, err.to_string())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DiscardInputsError {
+    #[error("error while dequeueing OUTPUT buffers")]
+    DequeueError(#[from] DqBufError<V4l2BufferFromError>),
     #[error("cannot streamoff output queue")]
-    OutputQueueStreamoffError(ioctl::StreamOffError),
+    StreamOffError(#[from] ioctl::StreamOffError),
+    #[error("cannot streamon output queue")]
+    StreamOnError(#[from] ioctl::StreamOnError),
+}
+
+impl OsError for DiscardInputsError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            DiscardInputsError::DequeueError(e) => e.errno(),
+            DiscardInputsError::StreamOffError(e) => e.errno(),
+            DiscardInputsError::StreamOnError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(DiscardInputsError);
+
+/// Cancels the buffers queued to `queue` by streaming it off and back on, and
+/// passes them to `canceled_cb`. Returns the number of canceled buffers.
+///
+/// `streamed_off` is set while the queue is streamed off, so a call following
+/// a failure to stream it back on only retries that.
+fn cancel_queued_buffers<S: Stream>(
+    queue: &S,
+    num_queued: usize,
+    streamed_off: &Cell<bool>,
+    mut canceled_cb: impl FnMut(S::Canceled),
+) -> Result<usize, DiscardInputsError> {
+    let mut num_canceled = 0;
+    if num_queued > 0 && !streamed_off.get() {
+        let canceled_buffers = queue.stream_off()?;
+        streamed_off.set(true);
+        num_canceled = canceled_buffers.len();
+        debug!("Discarding {} pending OUTPUT buffers", num_canceled);
+        for buffer in canceled_buffers {
+            canceled_cb(buffer);
+        }
+    }
+
+    if streamed_off.get() {
+        queue.stream_on()?;
+        streamed_off.set(false);
+    }
+
+    Ok(num_canceled)
 }
 
 impl<OP, P, InputDoneCb, OutputReadyCb> Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>
@@ -339,11 +1416,77 @@ where
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(CapturedFrame<P::HandleType>) + Send,
 {
+    /// Drop the frames queued to the encoder that it has not consumed yet, so
+    /// a live source falling behind can skip to its most recent frames.
+    ///
+    /// The OUTPUT buffers the driver is already done with are returned through
+    /// `input_done_cb` as usual. If some are still queued, the OUTPUT queue is
+    /// then streamed off and back on, and these buffers are returned as
+    /// `CompletedOutputBuffer::Canceled` so their handles can be reused. The
+    /// CAPTURE queue is not affected, and no encoded frame is produced for the
+    /// canceled buffers.
+    ///
+    /// Canceled frames do not count toward the limit set with
+    /// `ReadyToEncode::set_frame_limit()`.
+    ///
+    /// Returns the number of canceled buffers. If the OUTPUT queue cannot be
+    /// streamed back on, `StreamOnError` is returned after the buffers have
+    /// been canceled, and the encoder does not consume new frames until a
+    /// later call to this method succeeds in streaming the queue on.
+    pub fn discard_pending_inputs(&self) -> Result<usize, DiscardInputsError> {
+        self.dequeue_output_buffers()?;
+
+        let output_queue = &self.state.output_queue;
+        cancel_queued_buffers(
+            output_queue,
+            output_queue.num_queued_buffers(),
+            &self.state.output_streamed_off,
+            |buffer| {
+                self.state.stats.lock().unwrap().inputs_discarded(1);
+                (self.state.input_done_cb)(CompletedOutputBuffer::Canceled(buffer));
+            },
+        )
+    }
+
     /// Stop the encoder, and returns the encoder ready to be started again.
-    pub fn stop(self) -> Result<Encoder<ReadyToEncode<OP, P>>, EncoderStopError> {
-        ioctl::encoder_cmd::<_, ()>(&*self.device, &EncoderCommand::Stop(false))?;
+    ///
+    /// If the driver does not support `VIDIOC_ENCODER_CMD`, the end of the
+    /// stream is signaled by queueing an empty OUTPUT buffer instead, using the
+    /// handles provided by the callback given to
+    /// `ReadyToEncode::set_empty_output_handles()`.
+    pub fn stop(mut self) -> Result<Encoder<ReadyToEncode<OP, P>>, EncoderStopError>
+    where
+        for<'a> Queue<Output, BuffersAllocated<OP>>: GetFreeOutputBuffer<'a, OP>,
+    {
+        // We may have to wait for an OUTPUT buffer even if shutdown has been
+        // requested.
+        if let Err(e) = self.state.output_poller.remove_shutdown_token() {
+            warn!("Failed to remove shutdown token: {}", e);
+        }
+
+        // The drain may have been started already after the last frame
+        // allowed by the frame limit.
+        if !self.state.drain_started.get() {
+            match ioctl::encoder_cmd::<_, ()>(&*self.device, &EncoderCommand::Stop(false)) {
+                Ok(()) => (),
+                Err(ioctl::EncoderCmdError::IoctlError(Errno::ENOTTY)) => {
+                    debug!("ENCODER_CMD not supported, queueing empty OUTPUT buffer");
+                    // Set before queueing so the encoder thread cannot miss the
+                    // empty CAPTURE buffer that may come without the LAST flag.
+                    self.state.empty_buffer_eos.store(true, Ordering::SeqCst);
+                    self.queue_empty_output_buffer()?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // The client may not be draining the output channel anymore, which
+        // would block the encoder thread before it receives the LAST buffer.
+        if let Some(closer) = &self.state.output_channel_closer {
+            closer.close();
+        }
 
         // The encoder thread should receive the LAST buffer and exit on its own.
         let encoding_thread = self
@@ -373,10 +1516,113 @@ where
                 capture_queue: encoding_thread.capture_queue,
                 capture_memory_provider: encoding_thread.capture_memory_provider,
                 poll_wakeups_counter: None,
+                empty_output_handles: self.state.empty_output_handles,
+                header_extractor: encoding_thread.header_extractor,
+                capture_watermark: encoding_thread.capture_watermark,
+                frame_limit: self.state.frame_limit.limit,
+                shutdown_token: self.state.shutdown_token,
             },
         })
     }
 
+    /// Queues an OUTPUT buffer with no data, waiting for one to be free if
+    /// needed.
+    fn queue_empty_output_buffer(&mut self) -> Result<(), EncoderStopError>
+    where
+        for<'a> Queue<Output, BuffersAllocated<OP>>: GetFreeOutputBuffer<'a, OP>,
+    {
+        let handles = match &self.state.empty_output_handles {
+            Some(f) => f(),
+            None => return Err(EncoderStopError::NoEmptyOutputHandles),
+        };
+
+        let output_queue = &self.state.output_queue;
+        if output_queue.num_queued_buffers() == output_queue.num_buffers() {
+            self.wait_for_output_buffer()?;
+        }
+        self.dequeue_output_buffers()
+            .map_err(GetBufferError::from)?;
+
+        let buffer = self
+            .state
+            .output_queue
+            .try_get_free_buffer()
+            .map_err(GetBufferError::from)?;
+        let bytes_used = vec![0; handles.len()];
+        buffer
+            .queue_with_handles(handles, &bytes_used)
+            .map_err(|e| EncoderStopError::QueueEmptyBufferError(e.error))?;
+
+        Ok(())
+    }
+
+    /// Returns the stream headers (e.g. SPS and PPS for H.264) if they have
+    /// been produced by the encoder yet.
+    ///
+    /// `ReadyToEncode::capture_stream_headers()` must have been called before
+    /// starting the encoder for the headers to be captured.
+    pub fn get_stream_headers(&self) -> Option<Vec<u8>> {
+        self.state.stream_headers.lock().unwrap().clone()
+    }
+
+    /// Returns whether as many frames as allowed by
+    /// `ReadyToEncode::set_frame_limit()` have been queued.
+    pub fn frame_limit_reached(&self) -> bool {
+        self.state
+            .frame_limit
+            .reached(self.state.output_queue.num_queued_buffers())
+    }
+
+    /// Returns the statistics of the encoder since it has been started.
+    pub fn stats(&self) -> EncoderStats {
+        self.state
+            .stats
+            .lock()
+            .unwrap()
+            .snapshot(Instant::now(), self.state.output_queue.num_queued_buffers())
+    }
+
+    /// Signals the end of the stream once the last frame allowed by the frame
+    /// limit has been queued, so the encoder thread delivers the remaining
+    /// frames and exits without waiting for `stop()`.
+    ///
+    /// If the driver needs an empty OUTPUT buffer and none is free yet, the
+    /// drain is attempted again on the next call, or left to `stop()`.
+    fn start_drain(&self) -> Result<(), GetBufferError>
+    where
+        for<'a> Queue<Output, BuffersAllocated<OP>>: GetFreeOutputBuffer<'a, OP>,
+    {
+        if self.state.drain_started.get() {
+            return Ok(());
+        }
+
+        match ioctl::encoder_cmd::<_, ()>(&*self.device, &EncoderCommand::Stop(false)) {
+            Ok(()) => (),
+            Err(ioctl::EncoderCmdError::IoctlError(Errno::ENOTTY)) => {
+                // `stop()` reports the missing callback.
+                let handles = match &self.state.empty_output_handles {
+                    Some(f) => f,
+                    None => return Ok(()),
+                };
+                let buffer = match self.state.output_queue.try_get_free_buffer() {
+                    Ok(buffer) => buffer,
+                    Err(GetFreeBufferError::NoFreeBuffer) => return Ok(()),
+                };
+                debug!("Frame limit reached, queueing empty OUTPUT buffer");
+                let handles = handles();
+                let bytes_used = vec![0; handles.len()];
+                self.state.empty_buffer_eos.store(true, Ordering::SeqCst);
+                buffer
+                    .queue_with_handles(handles, &bytes_used)
+                    .map_err(|e| GetBufferError::QueueEmptyBufferError(e.error))?;
+            }
+            Err(e) => return Err(GetBufferError::EncoderCmdError(e)),
+        }
+
+        self.state.drain_started.set(true);
+        Ok(())
+    }
+
     /// Attempts to dequeue and release output buffers that the driver is done with.
     fn dequeue_output_buffers(&self) -> Result<(), DqBufError<V4l2BufferFromError>> {
         let output_queue = &self.state.output_queue;
@@ -384,6 +1630,8 @@ where
         while output_queue.num_queued_buffers() > 0 {
             match output_queue.try_dequeue() {
                 Ok(buf) => {
+                    self.state.stats.lock().unwrap().input_done();
+                    self.state.frame_limit.frame_consumed();
                     (self.state.input_done_cb)(CompletedOutputBuffer::Dequeued(buf));
                 }
                 Err(DqBufError::IoctlError(DqBufIoctlError::NotReady)) => break,
@@ -399,16 +1647,13 @@ where
     // Make this thread sleep until at least one OUTPUT buffer is ready to be
     // obtained through `try_get_buffer()`, dequeuing buffers if necessary.
     fn wait_for_output_buffer(&mut self) -> Result<(), GetBufferError> {
-        for event in self.state.output_poller.poll(None)? {
-            match event {
-                PollEvent::Device(DeviceEvent::OutputReady) => {
-                    self.dequeue_output_buffers()?;
-                }
-                _ => panic!("Unexpected return from OUTPUT queue poll!"),
+        loop {
+            let readiness = self.state.output_poller.poll(None)?.readiness();
+            if readiness.contains(Readiness::OUTPUT) {
+                return Ok(self.dequeue_output_buffers()?);
             }
+            debug!("Spurious wakeup while waiting for an OUTPUT buffer");
         }
-
-        Ok(())
     }
 }
 
@@ -419,7 +1664,7 @@ where
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(CapturedFrame<P::HandleType>) + Send,
 {
     type Queueable =
         <Queue<Output, BuffersAllocated<OP>> as OutputQueueableProvider<'a, OP>>::Queueable;
@@ -429,19 +1674,28 @@ where
 impl<'a, OP, P, InputDoneCb, OutputReadyCb> GetFreeOutputBuffer<'a, OP, GetBufferError>
     for Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>
 where
-    Queue<Output, BuffersAllocated<OP>>: GetFreeOutputBuffer<'a, OP>,
+    for<'b> Queue<Output, BuffersAllocated<OP>>: GetFreeOutputBuffer<'b, OP>,
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(CapturedFrame<P::HandleType>) + Send,
 {
     /// Returns a V4L2 buffer to be filled with a frame to encode if one
     /// is available.
     ///
     /// This method will return None immediately if all the allocated buffers
     /// are currently queued.
+    ///
+    /// Fails with `FrameLimitReached` once the limit set with
+    /// `ReadyToEncode::set_frame_limit()` has been reached, after starting to
+    /// drain the encoder.
     fn try_get_free_buffer(&'a self) -> Result<Self::Queueable, GetBufferError> {
         self.dequeue_output_buffers()?;
+        if self.frame_limit_reached() {
+            self.start_drain()?;
+            return Err(GetBufferError::FrameLimitReached);
+        }
+
         Ok(self.state.output_queue.try_get_free_buffer()?)
     }
 }
@@ -454,7 +1708,7 @@ where
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(CapturedFrame<P::HandleType>) + Send,
 {
     /// Returns a V4L2 buffer to be filled with a frame to encode, waiting for
     /// one to be available if needed.
@@ -466,8 +1720,11 @@ where
     ) -> Result<<Self as OutputQueueableProvider<'a, OP>>::Queueable, GetBufferError> {
         let output_queue = &self.state.output_queue;
 
-        // If all our buffers are queued, wait until we can dequeue some.
-        if output_queue.num_queued_buffers() == output_queue.num_buffers() {
+        // If all our buffers are queued, wait until we can dequeue some. No
+        // buffer is needed once the frame limit is reached.
+        if output_queue.num_queued_buffers() == output_queue.num_buffers()
+            && !self.frame_limit_reached()
+        {
             self.wait_for_output_buffer()?;
         }
 
@@ -475,30 +1732,70 @@ where
     }
 }
 
+/// Counts the frames submitted to an encoder against its frame limit.
+struct FrameLimit {
+    limit: Option<usize>,
+    /// Number of OUTPUT buffers dequeued after being consumed by the encoder.
+    /// Canceled buffers are not counted.
+    frames_consumed: Cell<usize>,
+}
+
+impl FrameLimit {
+    fn new(limit: Option<usize>) -> Self {
+        FrameLimit {
+            limit,
+            frames_consumed: Cell::new(0),
+        }
+    }
+
+    fn frame_consumed(&self) {
+        self.frames_consumed.set(self.frames_consumed.get() + 1);
+    }
+
+    /// Returns whether the limit is reached with `num_queued` frames still
+    /// queued to the encoder.
+    fn reached(&self, num_queued: usize) -> bool {
+        self.limit.map_or(false, |limit| {
+            self.frames_consumed.get() + num_queued >= limit
+        })
+    }
+}
+
 struct EncoderThread<P, OutputReadyCb>
 where
     P: HandlesProvider,
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(CapturedFrame<P::HandleType>) + Send,
 {
     capture_queue: Queue<Capture, BuffersAllocated<P::HandleType>>,
     capture_memory_provider: P,
     poller: Poller,
     waker: Arc<Waker>,
     output_ready_cb: OutputReadyCb,
+    header_extractor: Option<HeaderExtractor<P::HandleType>>,
+    stream_headers: StreamHeaders,
+    stats: Arc<Mutex<StatsTracker>>,
+    capture_watermark: Option<QueueWatermark>,
+    /// See `Encoding::empty_buffer_eos`.
+    empty_buffer_eos: Arc<AtomicBool>,
 }
 
 impl<P, OutputReadyCb> EncoderThread<P, OutputReadyCb>
 where
     P: HandlesProvider,
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(CapturedFrame<P::HandleType>) + Send,
     for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
         GetFreeCaptureBuffer<'a, P::HandleType> + GetCaptureBufferByIndex<'a, P::HandleType>,
 {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         device: &Arc<Device>,
         capture_queue: Queue<Capture, BuffersAllocated<P::HandleType>>,
         capture_memory_provider: P,
         output_ready_cb: OutputReadyCb,
+        header_extractor: Option<HeaderExtractor<P::HandleType>>,
+        stream_headers: StreamHeaders,
+        stats: Arc<Mutex<StatsTracker>>,
+        capture_watermark: Option<QueueWatermark>,
     ) -> io::Result<Self> {
         let mut poller = Poller::new(Arc::clone(device))?;
 
@@ -511,6 +1808,11 @@ where
             poller,
             waker,
             output_ready_cb,
+            header_extractor,
+            stream_headers,
+            stats,
+            capture_watermark,
+            empty_buffer_eos: Default::default(),
         })
     }
 
@@ -522,7 +1824,13 @@ where
         self.enqueue_capture_buffers();
 
         'polling: loop {
-            match self.capture_queue.num_queued_buffers() {
+            let num_queued = self.capture_queue.num_queued_buffers();
+            self.stats.lock().unwrap().set_capture_queued(num_queued);
+            if let Some(watermark) = &mut self.capture_watermark {
+                watermark.update(num_queued);
+            }
+
+            match num_queued {
                 // If there are no buffers on the CAPTURE queue, poll() will return
                 // immediately with EPOLLERR and we would loop indefinitely.
                 // Prevent this by temporarily disabling polling the device in such
@@ -540,52 +1848,110 @@ where
                 }
             }
 
-            // TODO handle errors - this system call can be interrupted and we
-            // should leave in this case.
-            for event in self.poller.poll(None).unwrap() {
-                match event {
-                    // A CAPTURE buffer has been released by the client.
-                    PollEvent::Waker(0) => {
-                        // Requeue all available CAPTURE buffers.
-                        self.enqueue_capture_buffers();
+            let readiness = match self.poller.poll(None) {
+                Ok(events) => events.readiness(),
+                Err(PollError::EPollWait(Errno::EINTR)) => continue 'polling,
+                Err(PollError::Interrupted) => {
+                    self.handle_shutdown();
+                    break 'polling;
+                }
+                Err(e) => {
+                    error!("Polling failure, exiting encoder thread: {}", e);
+                    break 'polling;
+                }
+            };
+
+            // A CAPTURE buffer has been released by the client.
+            if readiness.contains(Readiness::WAKER) {
+                // Requeue all available CAPTURE buffers.
+                self.enqueue_capture_buffers();
+            }
+
+            // A CAPTURE buffer is ready to be dequeued.
+            if readiness.contains(Readiness::CAPTURE) {
+                match self.dequeue_capture_buffer() {
+                    // Last buffer of the stream? Time for us to terminate.
+                    Ok(true) => break 'polling,
+                    Ok(false) => (),
+                    Err(DqBufError::IoctlError(DqBufIoctlError::NotReady)) => {
+                        warn!("Expected a CAPTURE buffer but none available, possible driver bug");
                     }
-                    // A CAPTURE buffer is ready to be dequeued.
-                    PollEvent::Device(DeviceEvent::CaptureReady) => {
-                        // Get the encoded buffer
-                        // TODO Manage errors here, including corrupted buffers!
-                        if let Ok(mut cap_buf) = self.capture_queue.try_dequeue() {
-                            let is_last = cap_buf.data.is_last();
-                            let is_empty = *cap_buf.data.get_first_plane().bytesused == 0;
-
-                            // Add a drop callback to the dequeued buffer so we
-                            // re-queue it as soon as it is dropped.
-                            let cap_waker = Arc::clone(&self.waker);
-                            cap_buf.add_drop_callback(move |_dqbuf| {
-                                cap_waker.wake();
-                            });
-
-                            // Empty buffers do not need to be passed to the client.
-                            if !is_empty {
-                                (self.output_ready_cb)(cap_buf);
-                            }
-
-                            // Last buffer of the stream? Time for us to terminate.
-                            if is_last {
-                                break 'polling;
-                            }
-                        } else {
-                            // TODO we should not crash here.
-                            panic!("Expected a CAPTURE buffer but none available!");
-                        }
+                    Err(e) => {
+                        error!(
+                            "Error while dequeuing CAPTURE buffer, exiting encoder thread: {}",
+                            e
+                        );
+                        break 'polling;
                     }
-                    _ => panic!("Unexpected return from CAPTURE queue poll!"),
                 }
             }
+
+            if !readiness.intersects(Readiness::WAKER | Readiness::CAPTURE) {
+                debug!("Spurious wakeup of the encoder thread");
+            }
         }
 
         self
     }
 
+    /// Stops honoring the shutdown token, which would otherwise fail every
+    /// subsequent poll. The thread exits, dropping the frames still being
+    /// encoded.
+    fn handle_shutdown(&mut self) {
+        debug!("Shutdown requested");
+        if let Err(e) = self.poller.remove_shutdown_token() {
+            warn!("Failed to remove shutdown token: {}", e);
+        }
+    }
+
+    /// Dequeues a CAPTURE buffer and passes it to the client if it is not
+    /// empty. Returns `true` if it was the last buffer of the stream.
+    fn dequeue_capture_buffer(&mut self) -> Result<bool, DqBufError<V4l2BufferFromError>> {
+        let mut cap_buf = self.capture_queue.try_dequeue()?;
+        if cap_buf.has_error() {
+            self.stats.lock().unwrap().frame_corrupted();
+        }
+        let bytes_used = *cap_buf.data.get_first_plane().bytesused as usize;
+        let is_empty = bytes_used == 0;
+        // Drivers without ENCODER_CMD may end the stream
+        // with an empty buffer that is not marked LAST.
+        let is_last =
+            cap_buf.data.is_last() || (is_empty && self.empty_buffer_eos.load(Ordering::SeqCst));
+
+        // Add a drop callback to the dequeued buffer so we
+        // re-queue it as soon as it is dropped.
+        let cap_waker = Arc::clone(&self.waker);
+        cap_buf.add_drop_callback(move |_dqbuf| {
+            cap_waker.wake();
+        });
+
+        // Empty buffers do not need to be passed to the client.
+        if !is_empty {
+            self.stats
+                .lock()
+                .unwrap()
+                .output_ready(Instant::now(), bytes_used);
+            self.extract_stream_headers(&cap_buf);
+            (self.output_ready_cb)(CapturedFrame { buffer: cap_buf });
+        }
+
+        Ok(is_last)
+    }
+
+    /// Captures the stream headers from `buffer` if we have not found them
+    /// yet.
+    fn extract_stream_headers(&self, buffer: &DqBuffer<Capture, P::HandleType>) {
+        let extractor = match &self.header_extractor {
+            Some(extractor) => extractor,
+            None => return,
+        };
+
+        let mut stream_headers = self.stream_headers.lock().unwrap();
+        if stream_headers.is_none() {
+            *stream_headers = extractor(buffer);
+        }
+    }
+
     fn enqueue_capture_buffers(&mut self) {
         'enqueue: while let Some(handles) = self.capture_memory_provider.get_handles(&self.waker) {
             if let Ok(buffer) = self
@@ -600,3 +1966,202 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        ioctl::V4l2Buffer,
+        memory::{MemoryType, MmapHandle},
+    };
+
+    /// Returns a CAPTURE buffer of `length` bytes, `bytes_used` of which are
+    /// used, belonging to the frame with timestamp `timestamp`.
+    fn capture_buffer(
+        timestamp: i64,
+        bytes_used: u32,
+        length: u32,
+    ) -> DqBuffer<Capture, Vec<MmapHandle>> {
+        let mut data = V4l2Buffer::new(QueueType::VideoCapture, 0, MemoryType::Mmap);
+        data.set_timestamp(bindings::timeval {
+            tv_sec: 0,
+            tv_usec: timestamp as _,
+        });
+        let plane = data.get_first_plane_mut();
+        *plane.bytesused = bytes_used;
+        *plane.length = length;
+
+        DqBuffer::new_detached(data, Vec::new())
+    }
+
+    #[test]
+    fn frame_reassembly() {
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let cb_frames = Rc::clone(&frames);
+        let mut reassembler = FrameReassembler::new(
+            FrameReassemblyConfig { max_frame_size: 8 },
+            move |frame: EncodedFrame<Vec<MmapHandle>>| {
+                cb_frames
+                    .borrow_mut()
+                    .push((timestamp_key(&frame.timestamp()), frame.bytes_used()))
+            },
+        );
+
+        // A frame split across two buffers, the first one being full.
+        reassembler.push(capture_buffer(1, 4, 4));
+        reassembler.push(capture_buffer(1, 2, 4));
+        // A frame growing larger than the maximum size is discarded, including
+        // its buffers following the one that made it too large.
+        reassembler.push(capture_buffer(2, 4, 4));
+        reassembler.push(capture_buffer(2, 4, 4));
+        reassembler.push(capture_buffer(2, 4, 4));
+        reassembler.push(capture_buffer(2, 1, 4));
+        // The next frame is emitted normally.
+        reassembler.push(capture_buffer(3, 3, 4));
+        // A discarded frame also ends when the next one starts.
+        reassembler.push(capture_buffer(4, 4, 4));
+        reassembler.push(capture_buffer(4, 4, 4));
+        reassembler.push(capture_buffer(4, 4, 4));
+        reassembler.push(capture_buffer(5, 4, 4));
+        // The pending frame is emitted when the reassembler is dropped.
+        drop(reassembler);
+
+        assert_eq!(*frames.borrow(), vec![(1, 6), (3, 3), (5, 4)]);
+    }
+
+    #[test]
+    fn extract_h264_parameter_sets() {
+        let stream = [
+            0, 0, 0, 1, 0x67, 0x42, 0x0a, // SPS
+            0, 0, 1, 0x68, 0xce, // PPS
+            0, 0, 0, 1, 0x65, 0x88, 0x84, // IDR slice
+        ];
+        assert_eq!(
+            extract_parameter_sets(&stream, b"H264"),
+            Some(vec![0, 0, 0, 1, 0x67, 0x42, 0x0a, 0, 0, 0, 1, 0x68, 0xce])
+        );
+
+        // A frame without parameter sets.
+        assert_eq!(
+            extract_parameter_sets(&[0, 0, 1, 0x41, 0x9a], b"H264"),
+            None
+        );
+        // Parameter sets are only looked for in supported codecs.
+        assert_eq!(extract_parameter_sets(&stream, b"VP80"), None);
+    }
+
+    #[test]
+    fn extract_hevc_parameter_sets() {
+        let stream = [
+            0, 0, 0, 1, 0x40, 0x01, 0x0c, // VPS
+            0, 0, 0, 1, 0x42, 0x01, 0x01, // SPS
+            0, 0, 0, 1, 0x44, 0x01, 0xc1, // PPS
+            0, 0, 0, 1, 0x26, 0x01, 0xaf, // IDR slice
+        ];
+        assert_eq!(
+            extract_parameter_sets(&stream, b"HEVC"),
+            Some(vec![
+                0, 0, 0, 1, 0x40, 0x01, 0x0c, 0, 0, 0, 1, 0x42, 0x01, 0x01, 0, 0, 0, 1, 0x44, 0x01,
+                0xc1
+            ])
+        );
+    }
+
+    #[test]
+    fn captured_frame_into_result() {
+        let frame = CapturedFrame {
+            buffer: capture_buffer(0, 4, 8),
+        };
+        assert_eq!(frame.into_result().unwrap().bytes_used(), 4);
+
+        let mut buffer = capture_buffer(0, 4, 8);
+        buffer.data.add_flags(ioctl::BufferFlags::ERROR);
+        let error = CapturedFrame { buffer }.into_result().unwrap_err();
+        assert_eq!(error.buffer.bytes_used(), 4);
+    }
+
+    /// Queue streaming its buffers off and failing to stream on when asked to.
+    #[derive(Default)]
+    struct FakeQueue {
+        queued: RefCell<Vec<usize>>,
+        streaming: Cell<bool>,
+        fail_stream_on: Cell<bool>,
+    }
+
+    impl Stream for FakeQueue {
+        type Canceled = usize;
+
+        fn stream_on(&self) -> Result<(), ioctl::StreamOnError> {
+            if self.fail_stream_on.get() {
+                return Err(ioctl::StreamOnError::IoctlError(Errno::EIO));
+            }
+            self.streaming.set(true);
+            Ok(())
+        }
+
+        fn stream_off(&self) -> Result<Vec<usize>, ioctl::StreamOffError> {
+            self.streaming.set(false);
+            Ok(self.queued.take())
+        }
+    }
+
+    #[test]
+    fn cancel_queued_buffers_restarts_stream() {
+        let queue = FakeQueue::default();
+        let streamed_off = Cell::new(false);
+        let canceled = RefCell::new(Vec::new());
+        let cancel = |queue: &FakeQueue| {
+            let num_queued = queue.queued.borrow().len();
+            cancel_queued_buffers(queue, num_queued, &streamed_off, |buffer| {
+                canceled.borrow_mut().push(buffer)
+            })
+        };
+
+        // Nothing to cancel, the queue is left alone.
+        assert_eq!(cancel(&queue).unwrap(), 0);
+        assert!(!queue.streaming.get());
+
+        queue.queued.replace(vec![1, 2]);
+        assert_eq!(cancel(&queue).unwrap(), 2);
+        assert_eq!(canceled.take(), vec![1, 2]);
+        assert!(queue.streaming.get());
+
+        // The buffers are returned even if the queue cannot be restarted...
+        queue.queued.replace(vec![3]);
+        queue.fail_stream_on.set(true);
+        assert!(matches!(
+            cancel(&queue),
+            Err(DiscardInputsError::StreamOnError(_))
+        ));
+        assert_eq!(canceled.take(), vec![3]);
+        assert!(streamed_off.get());
+
+        // ... and the next call streams it on without canceling anything.
+        queue.queued.replace(vec![4]);
+        queue.fail_stream_on.set(false);
+        assert_eq!(cancel(&queue).unwrap(), 0);
+        assert!(canceled.take().is_empty());
+        assert!(queue.streaming.get());
+        assert!(!streamed_off.get());
+    }
+
+    #[test]
+    fn frame_limit() {
+        let limit = FrameLimit::new(Some(3));
+        assert!(!limit.reached(2));
+        assert!(limit.reached(3));
+
+        // Consumed frames count, buffers canceled from the queue do not.
+        limit.frame_consumed();
+        assert!(!limit.reached(1));
+        limit.frame_consumed();
+        assert!(limit.reached(1));
+        assert!(!limit.reached(0));
+        limit.frame_consumed();
+        assert!(limit.reached(0));
+
+        assert!(!FrameLimit::new(None).reached(usize::MAX));
+    }
+}
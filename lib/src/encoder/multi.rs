@@ -0,0 +1,351 @@
+//! Load-balancing of frames across several instances of the same encoder.
+//!
+//! Some hardware exposes several independent encoding contexts, and opening
+//! the encoder device several times lets them work in parallel. `MultiEncoder`
+//! manages a set of such instances: frames are submitted to the least busy one,
+//! and the encoded frames are delivered in submission order regardless of the
+//! instance that produced them.
+//!
+//! Each instance is created by a user-provided factory, which receives a
+//! `FrameSink` that the output callback of the instance must use to report
+//! its encoded frames. Since the position of a frame in the stream is deduced
+//! from the order in which each instance completes its frames, every submitted
+//! frame must result in exactly one call to `FrameSink::frame_done` or
+//! `FrameSink::frame_dropped`. In practice this means that B-frames must be
+//! disabled, the headers must be joined with the first frame, and frames split
+//! across several CAPTURE buffers must be reassembled (see
+//! `ReadyToEncode::start_with_frame_reassembly`).
+use log::warn;
+use nix::errno::Errno;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+
+use crate::ioctl::{self, OsError};
+
+/// Reorders items tagged with a sequence number so they are delivered in
+/// sequence order.
+struct Reorderer<T> {
+    next: u64,
+    /// Completed items that cannot be delivered yet. `None` entries are frames
+    /// that have been dropped and are just skipped.
+    pending: BTreeMap<u64, Option<T>>,
+}
+
+impl<T> Reorderer<T> {
+    fn new() -> Self {
+        Reorderer {
+            next: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Registers `item` for sequence number `seq` and passes all the items
+    /// that can now be delivered in order to `deliver`.
+    fn push<F: FnMut(T)>(&mut self, seq: u64, item: Option<T>, mut deliver: F) {
+        self.pending.insert(seq, item);
+
+        while let Some(item) = self.pending.remove(&self.next) {
+            self.next += 1;
+            if let Some(item) = item {
+                deliver(item);
+            }
+        }
+    }
+}
+
+struct Shared<T> {
+    reorderer: Reorderer<T>,
+    frame_ready_cb: Box<dyn FnMut(T) + Send>,
+}
+
+/// Handle given to each encoder instance to report its encoded frames to the
+/// `MultiEncoder`. It is meant to be moved into the output callback of the
+/// instance.
+pub struct FrameSink<T> {
+    /// Sequence numbers of the frames currently being encoded by the instance,
+    /// in submission order.
+    in_flight: Arc<Mutex<VecDeque<u64>>>,
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Clone for FrameSink<T> {
+    fn clone(&self) -> Self {
+        FrameSink {
+            in_flight: Arc::clone(&self.in_flight),
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> FrameSink<T> {
+    fn complete(&self, item: Option<T>) {
+        let seq = match self.in_flight.lock().unwrap().pop_front() {
+            Some(seq) => seq,
+            None => {
+                warn!("Encoded frame reported with no frame in flight, ignoring");
+                return;
+            }
+        };
+
+        let mut shared = self.shared.lock().unwrap();
+        let Shared {
+            reorderer,
+            frame_ready_cb,
+        } = &mut *shared;
+        reorderer.push(seq, item, frame_ready_cb);
+    }
+
+    /// Reports the next frame submitted to this instance as encoded. It will
+    /// be passed to the frame-ready callback once all the frames submitted
+    /// before it have been delivered.
+    pub fn frame_done(&self, frame: T) {
+        self.complete(Some(frame))
+    }
+
+    /// Reports that the next frame submitted to this instance will not produce
+    /// any output (e.g. because of an encoding error), so the frames after it
+    /// can be delivered.
+    pub fn frame_dropped(&self) {
+        self.complete(None)
+    }
+}
+
+struct Instance<E> {
+    encoder: E,
+    in_flight: Arc<Mutex<VecDeque<u64>>>,
+}
+
+/// Error returned by `MultiEncoder::new`.
+#[derive(Debug, Error)]
+pub enum NewMultiEncoderError<E: Debug> {
+    #[error("at least one encoder instance is required")]
+    NoInstance,
+    #[error("error while creating encoder instance")]
+    InstanceError(#[source] E),
+}
+
+impl<E: Debug + OsError + 'static> OsError for NewMultiEncoderError<E> {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            NewMultiEncoderError::NoInstance => None,
+            NewMultiEncoderError::InstanceError(e) => e.errno(),
+        }
+    }
+}
+
+impl<E> From<NewMultiEncoderError<E>> for std::io::Error
+where
+    E: Debug,
+    NewMultiEncoderError<E>: OsError + Send + Sync + 'static,
+{
+    fn from(err: NewMultiEncoderError<E>) -> Self {
+        ioctl::into_io_error(err)
+    }
+}
+
+/// Set of encoder instances among which submitted frames are balanced, with
+/// in-order delivery of the encoded frames.
+///
+/// `E` is the type of an encoder instance, typically a started `Encoder`, and
+/// `T` the type of the encoded frames reported through `FrameSink`.
+pub struct MultiEncoder<E, T> {
+    instances: Vec<Instance<E>>,
+    next_seq: u64,
+    _t: std::marker::PhantomData<T>,
+}
+
+impl<E, T> MultiEncoder<E, T> {
+    /// Creates `num_instances` encoder instances using `factory`, which is
+    /// called with the index of the instance and the `FrameSink` its output
+    /// callback must report encoded frames to. `num_instances` must not be
+    /// zero.
+    ///
+    /// `frame_ready_cb` is called with the encoded frames, in submission order.
+    /// It is run from the thread that reported the frame that made delivery
+    /// possible, i.e. typically the encoding thread of one of the instances.
+    pub fn new<F, Err, FrameReadyCb>(
+        num_instances: usize,
+        mut factory: F,
+        frame_ready_cb: FrameReadyCb,
+    ) -> Result<Self, NewMultiEncoderError<Err>>
+    where
+        F: FnMut(usize, FrameSink<T>) -> Result<E, Err>,
+        Err: Debug,
+        FrameReadyCb: FnMut(T) + Send + 'static,
+    {
+        if num_instances == 0 {
+            return Err(NewMultiEncoderError::NoInstance);
+        }
+
+        let shared = Arc::new(Mutex::new(Shared {
+            reorderer: Reorderer::new(),
+            frame_ready_cb: Box::new(frame_ready_cb),
+        }));
+
+        let instances = (0..num_instances)
+            .map(|index| {
+                let in_flight: Arc<Mutex<VecDeque<u64>>> = Default::default();
+                let sink = FrameSink {
+                    in_flight: Arc::clone(&in_flight),
+                    shared: Arc::clone(&shared),
+                };
+                Ok(Instance {
+                    encoder: factory(index, sink).map_err(NewMultiEncoderError::InstanceError)?,
+                    in_flight,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(MultiEncoder {
+            instances,
+            next_seq: 0,
+            _t: std::marker::PhantomData,
+        })
+    }
+
+    /// Returns the number of instances.
+    pub fn num_instances(&self) -> usize {
+        self.instances.len()
+    }
+
+    /// Returns the total number of frames submitted and not yet reported.
+    pub fn num_frames_in_flight(&self) -> usize {
+        self.instances
+            .iter()
+            .map(|instance| instance.in_flight.lock().unwrap().len())
+            .sum()
+    }
+
+    /// Submits a frame to the instance with the fewest frames in flight.
+    ///
+    /// `submit` is called with the selected instance and must queue exactly
+    /// one frame to it. If it returns an error, the frame is considered as not
+    /// submitted.
+    pub fn submit<R, Err, F>(&mut self, submit: F) -> Result<R, Err>
+    where
+        F: FnOnce(&E) -> Result<R, Err>,
+    {
+        let instance = self
+            .instances
+            .iter()
+            .min_by_key(|instance| instance.in_flight.lock().unwrap().len())
+            // `new` does not create a `MultiEncoder` without instances.
+            .expect("MultiEncoder has no instance");
+
+        let seq = self.next_seq;
+        // Register the frame before submitting it, since it may be reported
+        // before `submit` returns.
+        instance.in_flight.lock().unwrap().push_back(seq);
+
+        match submit(&instance.encoder) {
+            Ok(res) => {
+                self.next_seq += 1;
+                Ok(res)
+            }
+            Err(e) => {
+                let mut in_flight = instance.in_flight.lock().unwrap();
+                if in_flight.back() == Some(&seq) {
+                    in_flight.pop_back();
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns an iterator over the encoder instances.
+    pub fn instances(&self) -> impl Iterator<Item = &E> {
+        self.instances.iter().map(|instance| &instance.encoder)
+    }
+
+    /// Returns the encoder instances, e.g. in order to stop them.
+    ///
+    /// Frames reported after this call are still delivered in order to the
+    /// frame-ready callback.
+    pub fn into_instances(self) -> Vec<E> {
+        self.instances
+            .into_iter()
+            .map(|instance| instance.encoder)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{FrameSink, MultiEncoder, NewMultiEncoderError};
+
+    #[test]
+    fn no_instance() {
+        let res = MultiEncoder::new(
+            0,
+            |_, sink: FrameSink<u32>| Ok::<_, Infallible>(sink),
+            |_| (),
+        );
+        assert!(matches!(res, Err(NewMultiEncoderError::NoInstance)));
+    }
+
+    #[test]
+    fn instance_error() {
+        let res = MultiEncoder::<(), u32>::new(
+            2,
+            |_, _| Err(std::io::Error::from(std::io::ErrorKind::NotFound)),
+            |_| (),
+        );
+        let err = match res {
+            Err(err @ NewMultiEncoderError::InstanceError(_)) => err,
+            _ => panic!("expected an instance error"),
+        };
+        assert_eq!(
+            std::error::Error::source(&err)
+                .and_then(|e| e.downcast_ref::<std::io::Error>())
+                .map(|e| e.kind()),
+            Some(std::io::ErrorKind::NotFound)
+        );
+    }
+
+    #[test]
+    fn in_order_delivery() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let delivered_cb = Arc::clone(&delivered);
+
+        let mut encoder = MultiEncoder::new(
+            2,
+            |_, sink: FrameSink<u32>| Ok::<_, Infallible>(sink),
+            move |frame| delivered_cb.lock().unwrap().push(frame),
+        )
+        .unwrap();
+
+        // Frames alternate between both instances since they have the same
+        // load.
+        for _ in 0..4 {
+            encoder.submit(|_| Ok::<_, Infallible>(())).unwrap();
+        }
+        assert_eq!(encoder.num_frames_in_flight(), 4);
+
+        let sinks: Vec<_> = encoder.instances().cloned().collect();
+        // The second instance completes its frames (1 and 3) first.
+        sinks[1].frame_done(1);
+        sinks[1].frame_done(3);
+        assert!(delivered.lock().unwrap().is_empty());
+        sinks[0].frame_done(0);
+        assert_eq!(*delivered.lock().unwrap(), vec![0, 1]);
+        // Dropped frames are skipped.
+        sinks[0].frame_dropped();
+        assert_eq!(*delivered.lock().unwrap(), vec![0, 1, 3]);
+        assert_eq!(encoder.num_frames_in_flight(), 0);
+
+        // A failed submission does not create a hole in the sequence.
+        assert!(encoder.submit(|_| Err::<(), _>(())).is_err());
+        encoder.submit(|_| Ok::<_, Infallible>(())).unwrap();
+        sinks[0].frame_done(4);
+        assert_eq!(*delivered.lock().unwrap(), vec![0, 1, 3, 4]);
+    }
+}
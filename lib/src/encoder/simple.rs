@@ -0,0 +1,443 @@
+//! One-call interface to a stateful encoder.
+//!
+//! `SimpleEncoder` negotiates the formats, allocates MMAP buffers on both
+//! queues and starts the encoder thread, leaving the client with a blocking
+//! `encode()` method and a final `drain()`. Clients that need control over the
+//! memory types, the number of buffers or the encoding parameters should use
+//! the typestate API of [`Encoder`] directly.
+use std::{convert::Infallible, io, path::Path, sync::mpsc};
+
+use nix::{
+    errno::Errno,
+    sys::time::{TimeVal, TimeValLike},
+};
+use thiserror::Error;
+
+use super::{
+    CapturedFrame, CompletedOutputBuffer, Encoder, EncoderOpenError, EncoderStopError, Encoding,
+    GetBufferError,
+};
+use crate::{
+    bindings,
+    device::queue::{
+        handles_provider::MmapProvider, FormatAdjustedError, RequestBuffersError, SetFormatError,
+    },
+    format_info::FrameCopyError,
+    ioctl::{self, OsError, QBufError},
+    memory::MmapHandle,
+    Format, FormatField, PixelFormat,
+};
+
+/// Number of buffers allocated on each queue.
+const NUM_BUFFERS: usize = 4;
+
+type InputDoneCb = fn(CompletedOutputBuffer<Vec<MmapHandle>>);
+type OutputReadyCb = Box<dyn FnMut(CapturedFrame<Vec<MmapHandle>>) + Send>;
+
+/// A piece of the encoded stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedChunk {
+    /// Encoded data.
+    pub data: Vec<u8>,
+    /// Index of the frame this chunk has been encoded from, in the order the
+    /// frames have been passed to `SimpleEncoder::encode()`.
+    pub frame: u64,
+    pub keyframe: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum SimpleEncoderError {
+    #[error("error while opening encoder")]
+    OpenError(#[from] EncoderOpenError),
+    #[error("error while setting format")]
+    SetFormatError(#[from] SetFormatError),
+    #[error("format not supported by encoder")]
+    FormatAdjustedError(#[from] FormatAdjustedError),
+    #[error("error while allocating buffers")]
+    RequestBuffersError(#[from] RequestBuffersError),
+    #[error("error while starting encoder")]
+    StartError(#[source] io::Error),
+}
+
+impl OsError for SimpleEncoderError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            SimpleEncoderError::OpenError(e) => e.errno(),
+            SimpleEncoderError::SetFormatError(e) => e.errno(),
+            SimpleEncoderError::FormatAdjustedError(e) => e.errno(),
+            SimpleEncoderError::RequestBuffersError(e) => e.errno(),
+            SimpleEncoderError::StartError(e) => e.raw_os_error().map(Errno::from_i32),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(SimpleEncoderError);
+
+#[derive(Debug, Error)]
+pub enum EncodeError {
+    #[error("frame of {size} bytes while the OUTPUT format expects {expected} bytes")]
+    InvalidFrameSize { size: usize, expected: usize },
+    #[error("error while copying frame into OUTPUT buffer")]
+    CopyError(#[from] FrameCopyError),
+    #[error("error while obtaining OUTPUT buffer")]
+    GetBufferError(#[from] GetBufferError),
+    #[error("cannot map OUTPUT buffer")]
+    MappingError,
+    #[error("error while queueing OUTPUT buffer")]
+    QueueError(#[source] QBufError<Infallible>),
+}
+
+impl OsError for EncodeError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            EncodeError::InvalidFrameSize { .. } | EncodeError::MappingError => None,
+            EncodeError::CopyError(e) => e.errno(),
+            EncodeError::GetBufferError(e) => e.errno(),
+            EncodeError::QueueError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(EncodeError);
+
+/// OUTPUT buffers carry the index of their frame as timestamp, which the
+/// driver copies into the CAPTURE buffers encoded from them.
+fn frame_timestamp(frame: u64) -> TimeVal {
+    TimeVal::microseconds(frame as i64)
+}
+
+fn frame_index(timestamp: &bindings::timeval) -> u64 {
+    timestamp.tv_sec as u64 * 1_000_000 + timestamp.tv_usec as u64
+}
+
+/// Size of the frames of `format` accepted by `SimpleEncoder::encode()`.
+fn frame_size(format: &Format) -> usize {
+    match format.plane_sizes() {
+        Some(planes) => planes.iter().map(|plane| plane.sizeimage as usize).sum(),
+        None => format
+            .plane_fmt
+            .iter()
+            .map(|plane| plane.sizeimage as usize)
+            .sum(),
+    }
+}
+
+/// Copy `frame` into the memory `planes` of an OUTPUT buffer of `format`, and
+/// return the number of bytes used in each of them.
+fn copy_frame<M: AsMut<[u8]>>(
+    format: &Format,
+    frame: &[u8],
+    planes: &mut [M],
+) -> Result<Vec<usize>, EncodeError> {
+    let expected = frame_size(format);
+    if frame.len() != expected {
+        return Err(EncodeError::InvalidFrameSize {
+            size: frame.len(),
+            expected,
+        });
+    }
+
+    if format.pixelformat.info().is_some() {
+        return Ok(format.copy_packed_frame(frame, planes)?);
+    }
+
+    // The layout of pixel formats outside of the database is unknown, so
+    // their planes are copied as is.
+    let mut bytes_used = Vec::with_capacity(planes.len());
+    let mut remaining = frame;
+    for (plane, layout) in planes.iter_mut().zip(&format.plane_fmt) {
+        let (data, rest) = remaining.split_at(layout.sizeimage as usize);
+        plane
+            .as_mut()
+            .get_mut(..data.len())
+            .ok_or(EncodeError::MappingError)?
+            .copy_from_slice(data);
+        bytes_used.push(data.len());
+        remaining = rest;
+    }
+
+    Ok(bytes_used)
+}
+
+/// Build the chunk encoded into the first `bytes_used` bytes of `data`, or
+/// return `None` for the empty buffers signaling the end of the stream.
+fn encoded_chunk(
+    data: &[u8],
+    bytes_used: usize,
+    timestamp: &bindings::timeval,
+    keyframe: bool,
+) -> Option<EncodedChunk> {
+    if bytes_used == 0 {
+        return None;
+    }
+
+    Some(EncodedChunk {
+        data: data[..bytes_used.min(data.len())].to_vec(),
+        frame: frame_index(timestamp),
+        keyframe,
+    })
+}
+
+/// Send the chunk encoded into a CAPTURE buffer to `sender`, mapping the
+/// buffer with `map`. Empty buffers, returned at the end of the stream, are
+/// not mapped.
+fn send_chunk<M: AsRef<[u8]>>(
+    sender: &mpsc::Sender<EncodedChunk>,
+    bytes_used: usize,
+    timestamp: &bindings::timeval,
+    keyframe: bool,
+    map: impl FnOnce() -> Option<M>,
+) {
+    if bytes_used == 0 {
+        return;
+    }
+    let mapping = match map() {
+        Some(mapping) => mapping,
+        None => {
+            log::error!("cannot map CAPTURE buffer, dropping encoded data");
+            return;
+        }
+    };
+    if let Some(chunk) = encoded_chunk(mapping.as_ref(), bytes_used, timestamp, keyframe) {
+        // The receiver is only dropped along with the encoder.
+        let _ = sender.send(chunk);
+    }
+}
+
+/// A stateful encoder set up with default parameters.
+pub struct SimpleEncoder {
+    encoder: Encoder<Encoding<Vec<MmapHandle>, MmapProvider, InputDoneCb, OutputReadyCb>>,
+    chunks: mpsc::Receiver<EncodedChunk>,
+    output_format: Format,
+    capture_format: Format,
+    num_frames: u64,
+}
+
+impl SimpleEncoder {
+    /// Open the encoder at `device_path` to encode frames of `input_fourcc`
+    /// and `size` into `output_fourcc`, and start it.
+    ///
+    /// The driver may align `size`, use `output_format()` to obtain the layout
+    /// of the frames to pass to `encode()`. Fails with `FormatAdjustedError`
+    /// if the encoder does not support one of the pixel formats.
+    pub fn new(
+        device_path: &Path,
+        input_fourcc: impl Into<PixelFormat>,
+        output_fourcc: impl Into<PixelFormat>,
+        size: (usize, usize),
+    ) -> Result<Self, SimpleEncoderError> {
+        let is_pixelformat = |f: &FormatField| *f == FormatField::PixelFormat;
+
+        let (encoder, outcome) = Encoder::open(device_path)?
+            .set_capture_format(|f| f.set_pixelformat(output_fourcc).set_size(size.0, size.1))?;
+        outcome.ensure_unchanged(is_pixelformat)?;
+        let (encoder, outcome) = encoder
+            .set_output_format(|f| f.set_pixelformat(input_fourcc).set_size(size.0, size.1))?;
+        let output_format = outcome.ensure_unchanged(is_pixelformat)?;
+        // The OUTPUT format may have changed the CAPTURE one.
+        let capture_format = encoder.get_capture_format().map_err(SetFormatError::from)?;
+
+        let num_planes = output_format.plane_fmt.len();
+        let (sender, chunks) = mpsc::channel();
+        let output_ready_cb: OutputReadyCb = Box::new(move |frame| {
+            send_chunk(
+                &sender,
+                frame.bytes_used(),
+                &frame.timestamp(),
+                frame.is_keyframe(),
+                || frame.get_plane_mapping(0),
+            )
+        });
+
+        let encoder = encoder
+            .allocate_output_buffers::<Vec<MmapHandle>>(NUM_BUFFERS)?
+            .allocate_capture_buffers(NUM_BUFFERS, MmapProvider::new(&capture_format))?
+            .set_empty_output_handles(move || vec![MmapHandle; num_planes])
+            .start((|_| ()) as InputDoneCb, output_ready_cb)
+            .map_err(SimpleEncoderError::StartError)?;
+
+        Ok(SimpleEncoder {
+            encoder,
+            chunks,
+            output_format,
+            capture_format,
+            num_frames: 0,
+        })
+    }
+
+    /// Format of the frames passed to `encode()`.
+    pub fn output_format(&self) -> &Format {
+        &self.output_format
+    }
+
+    /// Format of the encoded stream.
+    pub fn capture_format(&self) -> &Format {
+        &self.capture_format
+    }
+
+    /// Size in bytes of the frames passed to `encode()`.
+    pub fn frame_size(&self) -> usize {
+        frame_size(&self.output_format)
+    }
+
+    /// Submit `frame` for encoding, waiting for an OUTPUT buffer to be
+    /// available if needed.
+    ///
+    /// If the pixel format of the OUTPUT format is part of the
+    /// [`format_info`](crate::format_info) database, `frame` must be tightly
+    /// packed, i.e. store its color planes one after the other without any line
+    /// padding, and it is copied using the strides of the OUTPUT format.
+    /// Otherwise `frame` must contain the whole `sizeimage` of each plane of the
+    /// OUTPUT format, one after the other. Frames of any other size than
+    /// `frame_size()` are rejected with `EncodeError::InvalidFrameSize`.
+    ///
+    /// Returns the chunks that have been encoded so far, which may be from
+    /// previous frames since encoding is asynchronous.
+    pub fn encode(
+        &mut self,
+        frame: &[u8],
+    ) -> Result<impl Iterator<Item = EncodedChunk> + '_, EncodeError> {
+        // Check the frame before taking an OUTPUT buffer.
+        let expected = self.frame_size();
+        if frame.len() != expected {
+            return Err(EncodeError::InvalidFrameSize {
+                size: frame.len(),
+                expected,
+            });
+        }
+
+        let timestamp = frame_timestamp(self.num_frames);
+        let buffer = self.encoder.get_buffer()?;
+
+        let mut mappings = (0..self.output_format.plane_fmt.len())
+            .map(|i| buffer.get_plane_mapping(i))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(EncodeError::MappingError)?;
+        let bytes_used = copy_frame(&self.output_format, frame, &mut mappings)?;
+        drop(mappings);
+
+        buffer
+            .set_timestamp(timestamp)
+            .queue(&bytes_used)
+            .map_err(EncodeError::QueueError)?;
+        self.num_frames += 1;
+
+        Ok(self.chunks.try_iter())
+    }
+
+    /// Stop the encoder once all submitted frames are encoded, and return the
+    /// chunks that have not been returned by `encode()` yet.
+    pub fn drain(self) -> Result<impl Iterator<Item = EncodedChunk>, EncoderStopError> {
+        // Stopping joins the encoder thread, which drops the sender of the
+        // channel so the iterator ends after the last chunk.
+        self.encoder.stop()?;
+
+        Ok(self.chunks.into_iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_timestamps() {
+        for frame in [0, 1, 999_999, 1_000_000, 123_456_789] {
+            let timestamp = frame_timestamp(frame);
+            let timestamp = bindings::timeval {
+                tv_sec: timestamp.tv_sec(),
+                tv_usec: timestamp.tv_usec(),
+            };
+            assert_eq!(frame_index(&timestamp), frame);
+        }
+    }
+
+    #[test]
+    fn copy_frame_validation() {
+        // The driver pads the lines of the OUTPUT format to 8 bytes.
+        let mut format = Format::from((b"NV12", (4, 2)));
+        format.plane_fmt = vec![crate::PlaneLayout {
+            sizeimage: 8 * 3,
+            bytesperline: 8,
+        }];
+        assert_eq!(frame_size(&format), 4 * 2 * 3 / 2);
+
+        let mut planes = vec![vec![0u8; 8 * 3]];
+        for size in [11, 13, 24] {
+            assert!(matches!(
+                copy_frame(&format, &vec![0u8; size], &mut planes),
+                Err(EncodeError::InvalidFrameSize { expected: 12, .. })
+            ));
+        }
+
+        let frame: Vec<u8> = (1..=12).collect();
+        assert_eq!(copy_frame(&format, &frame, &mut planes).unwrap(), vec![24]);
+        assert_eq!(
+            planes[0],
+            [1, 2, 3, 4, 0, 0, 0, 0, 5, 6, 7, 8, 0, 0, 0, 0, 9, 10, 11, 12, 0, 0, 0, 0]
+        );
+
+        // Formats outside of the database are split by `sizeimage`.
+        let mut format = Format::from((b"MJPG", (4, 2)));
+        format.plane_fmt = vec![crate::PlaneLayout {
+            sizeimage: 6,
+            bytesperline: 0,
+        }];
+        assert!(matches!(
+            copy_frame(&format, &[0u8; 5], &mut planes),
+            Err(EncodeError::InvalidFrameSize {
+                size: 5,
+                expected: 6
+            })
+        ));
+        assert_eq!(
+            copy_frame(&format, &[7u8; 6], &mut planes).unwrap(),
+            vec![6]
+        );
+        assert_eq!(planes[0][..7], [7, 7, 7, 7, 7, 7, 0]);
+        assert!(matches!(
+            copy_frame(&format, &[7u8; 6], &mut [vec![0u8; 4]]),
+            Err(EncodeError::MappingError)
+        ));
+    }
+
+    #[test]
+    fn send_chunks() {
+        let (sender, chunks) = mpsc::channel();
+        let timestamp = |frame| {
+            let timestamp = frame_timestamp(frame);
+            bindings::timeval {
+                tv_sec: timestamp.tv_sec(),
+                tv_usec: timestamp.tv_usec(),
+            }
+        };
+
+        send_chunk(&sender, 3, &timestamp(0), true, || Some(&b"key!"[..]));
+        // Buffers that cannot be mapped are dropped.
+        send_chunk(&sender, 4, &timestamp(1), false, || None::<&[u8]>);
+        send_chunk(&sender, 5, &timestamp(2), false, || Some(&b"delta"[..]));
+        // The empty buffer signaling the end of the stream is not mapped.
+        send_chunk(&sender, 0, &timestamp(0), false, || -> Option<&[u8]> {
+            panic!("empty buffer mapped")
+        });
+        // Stopping the encoder drops the output callback owning the sender,
+        // after which `drain()` returns the chunks not received yet.
+        drop(sender);
+
+        assert_eq!(
+            chunks.into_iter().collect::<Vec<_>>(),
+            vec![
+                EncodedChunk {
+                    data: b"key".to_vec(),
+                    frame: 0,
+                    keyframe: true,
+                },
+                EncodedChunk {
+                    data: b"delta".to_vec(),
+                    frame: 2,
+                    keyframe: false,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Statistics of a running encoder, computed from the buffers it dequeues.
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Duration of the window over which the bitrate and frame rate are averaged.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Snapshot of the activity of an encoder, returned by `Encoder::stats()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EncoderStats {
+    /// Number of OUTPUT buffers the encoder is done with.
+    pub frames_in: u64,
+    /// Number of OUTPUT buffers canceled by `Encoder::discard_pending_inputs`.
+    pub frames_discarded: u64,
+    /// Number of non-empty CAPTURE buffers produced by the encoder.
+    pub frames_out: u64,
+    /// Total size in bytes of the encoded data.
+    pub bytes_out: u64,
+    /// Number of CAPTURE buffers the driver reported an error for.
+    pub frames_corrupted: u64,
+    /// Bitrate of the encoded stream in bits per second, averaged over the
+    /// last second.
+    pub bitrate: u64,
+    /// Rate at which encoded frames are produced, averaged over the last
+    /// second.
+    pub fps: f64,
+    /// Number of OUTPUT buffers currently queued to the driver.
+    pub output_queued: usize,
+    /// Number of CAPTURE buffers currently queued to the driver.
+    pub capture_queued: usize,
+}
+
+/// Accumulates the events of the encoder to compute `EncoderStats`.
+#[derive(Default)]
+pub(super) struct StatsTracker {
+    frames_in: u64,
+    frames_discarded: u64,
+    frames_out: u64,
+    bytes_out: u64,
+    frames_corrupted: u64,
+    /// Time and size of the frames encoded during the last `WINDOW`.
+    window: VecDeque<(Instant, usize)>,
+    /// Time the first encoded frame has been dequeued at.
+    first_output: Option<Instant>,
+    capture_queued: usize,
+}
+
+impl StatsTracker {
+    /// Drop the frames of the window that are not within `WINDOW` of `now`.
+    fn expire(&mut self, now: Instant) {
+        while let Some((time, _)) = self.window.front() {
+            if now.saturating_duration_since(*time) < WINDOW {
+                break;
+            }
+            self.window.pop_front();
+        }
+    }
+
+    /// Records an OUTPUT buffer dequeued from the driver.
+    pub(super) fn input_done(&mut self) {
+        self.frames_in += 1;
+    }
+
+    /// Records OUTPUT buffers canceled before being encoded.
+    pub(super) fn inputs_discarded(&mut self, count: usize) {
+        self.frames_discarded += count as u64;
+    }
+
+    /// Records an encoded frame of `bytes_used` bytes dequeued at `time`.
+    pub(super) fn output_ready(&mut self, time: Instant, bytes_used: usize) {
+        self.frames_out += 1;
+        self.bytes_out += bytes_used as u64;
+        self.window.push_back((time, bytes_used));
+        self.first_output.get_or_insert(time);
+        self.expire(time);
+    }
+
+    /// Records a CAPTURE buffer dequeued with the `ERROR` flag set.
+    pub(super) fn frame_corrupted(&mut self) {
+        self.frames_corrupted += 1;
+    }
+
+    pub(super) fn set_capture_queued(&mut self, num_queued: usize) {
+        self.capture_queued = num_queued;
+    }
+
+    pub(super) fn snapshot(&mut self, now: Instant, output_queued: usize) -> EncoderStats {
+        self.expire(now);
+
+        // The rates are measured over the window ending at `now`, so they
+        // decrease as soon as the encoder stops producing frames. Until a
+        // whole window has elapsed, the interval starts at the first frame,
+        // which is thus not part of it.
+        let span = self
+            .first_output
+            .map(|first| now.saturating_duration_since(first).min(WINDOW))
+            .unwrap_or_default();
+        let skip = if span < WINDOW { 1 } else { 0 };
+        let (bitrate, fps) = if span.is_zero() {
+            (0, 0.0)
+        } else {
+            let span = span.as_secs_f64();
+            let bytes: usize = self.window.iter().skip(skip).map(|(_, size)| size).sum();
+            let frames = self.window.len().saturating_sub(skip);
+
+            ((bytes as f64 * 8.0 / span) as u64, frames as f64 / span)
+        };
+
+        EncoderStats {
+            frames_in: self.frames_in,
+            frames_discarded: self.frames_discarded,
+            frames_out: self.frames_out,
+            bytes_out: self.bytes_out,
+            frames_corrupted: self.frames_corrupted,
+            bitrate,
+            fps,
+            output_queued,
+            capture_queued: self.capture_queued,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_tracker() {
+        let start = Instant::now();
+        let mut tracker = StatsTracker::default();
+
+        assert_eq!(tracker.snapshot(start, 0), EncoderStats::default());
+
+        // 30 frames of 1000 bytes per second.
+        let frame_time = |i: u64| start + Duration::from_millis(i * 100 / 3);
+        for i in 0..16 {
+            tracker.input_done();
+            tracker.output_ready(frame_time(i), 1000);
+        }
+        // The first frames are measured from the time of the first one.
+        let stats = tracker.snapshot(frame_time(15), 0);
+        assert!((stats.fps - 30.0).abs() < 0.5, "{}", stats.fps);
+        for i in 16..61 {
+            tracker.input_done();
+            tracker.output_ready(frame_time(i), 1000);
+        }
+        tracker.frame_corrupted();
+        tracker.inputs_discarded(2);
+        tracker.set_capture_queued(3);
+
+        let stats = tracker.snapshot(start + Duration::from_secs(2), 2);
+        assert_eq!(stats.frames_in, 61);
+        assert_eq!(stats.frames_discarded, 2);
+        assert_eq!(stats.frames_out, 61);
+        assert_eq!(stats.bytes_out, 61000);
+        assert_eq!(stats.frames_corrupted, 1);
+        assert!((stats.fps - 30.0).abs() < 0.5, "{}", stats.fps);
+        assert!(stats.bitrate.abs_diff(240_000) < 4000, "{}", stats.bitrate);
+        assert_eq!(stats.output_queued, 2);
+        assert_eq!(stats.capture_queued, 3);
+
+        // Half of the last second without any frame encoded.
+        let stats = tracker.snapshot(start + Duration::from_millis(2500), 0);
+        assert!((stats.fps - 15.0).abs() < 0.5, "{}", stats.fps);
+        assert!(stats.bitrate.abs_diff(120_000) < 4000, "{}", stats.bitrate);
+
+        // No frame encoded during the last second.
+        let stats = tracker.snapshot(start + Duration::from_secs(4), 0);
+        assert_eq!(stats.fps, 0.0);
+        assert_eq!(stats.bitrate, 0);
+        assert_eq!(stats.frames_out, 61);
+    }
+}
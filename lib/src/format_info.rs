@@ -0,0 +1,762 @@
+//! Database of the memory layout of common uncompressed pixel formats.
+//!
+//! V4L2 drivers compute the `bytesperline` and `sizeimage` of each plane
+//! themselves, but it is often useful to know in advance what these values
+//! will be, e.g. to allocate buffers before the format is negotiated, or to
+//! make sure that memory provided by the user is large enough to hold a frame.
+//!
+//! This module provides the information required to do so for the most common
+//! uncompressed formats, along with the `Format::plane_sizes()` and
+//! `Format::estimate_buffer_size()` methods that make use of it, and
+//! `Format::copy_packed_frame()` to copy tightly-packed images into the padded
+//! planes of driver buffers. Compressed formats do not have a layout that can
+//! be derived from their resolution and are thus not part of the database.
+use std::convert::TryFrom;
+
+use nix::errno::Errno;
+use thiserror::Error;
+
+use crate::ioctl::{copy_with_stride, StrideCopyError};
+use crate::{Format, PixelFormat, PlaneLayout};
+
+/// Layout of a single color plane (or component) of a pixel format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorPlaneInfo {
+    /// Number of bits used by one sample of this plane.
+    pub bits_per_pixel: u32,
+    /// Horizontal subsampling factor of this plane relative to the image width.
+    pub horizontal_subsampling: u32,
+    /// Vertical subsampling factor of this plane relative to the image height.
+    pub vertical_subsampling: u32,
+}
+
+impl ColorPlaneInfo {
+    const fn new(
+        bits_per_pixel: u32,
+        horizontal_subsampling: u32,
+        vertical_subsampling: u32,
+    ) -> Self {
+        Self {
+            bits_per_pixel,
+            horizontal_subsampling,
+            vertical_subsampling,
+        }
+    }
+
+    /// Returns the minimum number of bytes per line of this plane for an image
+    /// of width `width`, or `None` if it does not fit in a `u32`.
+    pub fn bytesperline(&self, width: u32) -> Option<u32> {
+        let samples = width.div_ceil(self.horizontal_subsampling);
+        let bits = samples as u64 * self.bits_per_pixel as u64;
+        u32::try_from(bits.div_ceil(8)).ok()
+    }
+
+    /// Returns the minimum number of bytes of this plane for an image of
+    /// `width` by `height`, or `None` if it does not fit in a `u32`.
+    pub fn size(&self, width: u32, height: u32) -> Option<u32> {
+        self.bytesperline(width)?.checked_mul(self.lines(height))
+    }
+
+    /// Returns the number of lines of this plane for an image of height `height`.
+    pub fn lines(&self, height: u32) -> u32 {
+        height.div_ceil(self.vertical_subsampling)
+    }
+}
+
+/// Description of the memory layout of a pixel format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelFormatInfo {
+    /// The pixel format described.
+    pub pixelformat: PixelFormat,
+    /// Number of memory planes, i.e. separate buffers, used by this format. This
+    /// is either `1` if all the color planes are stored contiguously in the
+    /// same buffer, or the number of color planes.
+    pub num_mem_planes: usize,
+    /// Layout of each color plane of the format.
+    pub color_planes: &'static [ColorPlaneInfo],
+}
+
+const fn info(
+    fourcc: &[u8; 4],
+    num_mem_planes: usize,
+    color_planes: &'static [ColorPlaneInfo],
+) -> PixelFormatInfo {
+    PixelFormatInfo {
+        pixelformat: PixelFormat::from_fourcc(fourcc),
+        num_mem_planes,
+        color_planes,
+    }
+}
+
+const Y8: ColorPlaneInfo = ColorPlaneInfo::new(8, 1, 1);
+const Y16: ColorPlaneInfo = ColorPlaneInfo::new(16, 1, 1);
+const UV_420: ColorPlaneInfo = ColorPlaneInfo::new(16, 2, 2);
+const UV_420_16: ColorPlaneInfo = ColorPlaneInfo::new(32, 2, 2);
+const UV_422: ColorPlaneInfo = ColorPlaneInfo::new(16, 2, 1);
+const UV_444: ColorPlaneInfo = ColorPlaneInfo::new(16, 1, 1);
+const U_420: ColorPlaneInfo = ColorPlaneInfo::new(8, 2, 2);
+const U_422: ColorPlaneInfo = ColorPlaneInfo::new(8, 2, 1);
+const PACKED_16: ColorPlaneInfo = ColorPlaneInfo::new(16, 1, 1);
+const PACKED_24: ColorPlaneInfo = ColorPlaneInfo::new(24, 1, 1);
+const PACKED_32: ColorPlaneInfo = ColorPlaneInfo::new(32, 1, 1);
+const PACKED_10: ColorPlaneInfo = ColorPlaneInfo::new(10, 1, 1);
+const PACKED_12: ColorPlaneInfo = ColorPlaneInfo::new(12, 1, 1);
+
+static FORMATS: &[PixelFormatInfo] = &[
+    // Semi-planar YUV.
+    info(b"NV12", 1, &[Y8, UV_420]),
+    info(b"NV21", 1, &[Y8, UV_420]),
+    info(b"NM12", 2, &[Y8, UV_420]),
+    info(b"NM21", 2, &[Y8, UV_420]),
+    info(b"NV16", 1, &[Y8, UV_422]),
+    info(b"NV61", 1, &[Y8, UV_422]),
+    info(b"NM16", 2, &[Y8, UV_422]),
+    info(b"NM61", 2, &[Y8, UV_422]),
+    info(b"NV24", 1, &[Y8, UV_444]),
+    info(b"NV42", 1, &[Y8, UV_444]),
+    info(b"P010", 1, &[Y16, UV_420_16]),
+    // Planar YUV.
+    info(b"YU12", 1, &[Y8, U_420, U_420]),
+    info(b"YV12", 1, &[Y8, U_420, U_420]),
+    info(b"YM12", 3, &[Y8, U_420, U_420]),
+    info(b"YM21", 3, &[Y8, U_420, U_420]),
+    info(b"422P", 1, &[Y8, U_422, U_422]),
+    info(b"YM16", 3, &[Y8, U_422, U_422]),
+    info(b"YM61", 3, &[Y8, U_422, U_422]),
+    info(b"YM24", 3, &[Y8, Y8, Y8]),
+    // Packed YUV.
+    info(b"YUYV", 1, &[PACKED_16]),
+    info(b"YVYU", 1, &[PACKED_16]),
+    info(b"UYVY", 1, &[PACKED_16]),
+    info(b"VYUY", 1, &[PACKED_16]),
+    // Luma only.
+    info(b"GREY", 1, &[Y8]),
+    info(b"Y16 ", 1, &[Y16]),
+    // RGB.
+    info(b"RGBP", 1, &[PACKED_16]),
+    info(b"RGB3", 1, &[PACKED_24]),
+    info(b"BGR3", 1, &[PACKED_24]),
+    info(b"RGB4", 1, &[PACKED_32]),
+    info(b"BGR4", 1, &[PACKED_32]),
+    info(b"AR24", 1, &[PACKED_32]),
+    info(b"XR24", 1, &[PACKED_32]),
+    info(b"AB24", 1, &[PACKED_32]),
+    info(b"XB24", 1, &[PACKED_32]),
+    info(b"RA24", 1, &[PACKED_32]),
+    info(b"RX24", 1, &[PACKED_32]),
+    info(b"BA24", 1, &[PACKED_32]),
+    info(b"BX24", 1, &[PACKED_32]),
+    // Raw Bayer, 8 bits per sample.
+    info(b"BA81", 1, &[Y8]),
+    info(b"GBRG", 1, &[Y8]),
+    info(b"GRBG", 1, &[Y8]),
+    info(b"RGGB", 1, &[Y8]),
+    // Raw Bayer, 10 bits per sample stored in 16 bits.
+    info(b"BG10", 1, &[Y16]),
+    info(b"GB10", 1, &[Y16]),
+    info(b"BA10", 1, &[Y16]),
+    info(b"RG10", 1, &[Y16]),
+    // Raw Bayer, 10 bits per sample, MIPI CSI-2 packing (4 samples in 5 bytes).
+    info(b"pBAA", 1, &[PACKED_10]),
+    info(b"pGAA", 1, &[PACKED_10]),
+    info(b"pgAA", 1, &[PACKED_10]),
+    info(b"pRAA", 1, &[PACKED_10]),
+    // Raw Bayer, 12 bits per sample stored in 16 bits.
+    info(b"BG12", 1, &[Y16]),
+    info(b"GB12", 1, &[Y16]),
+    info(b"BA12", 1, &[Y16]),
+    info(b"RG12", 1, &[Y16]),
+    // Raw Bayer, 12 bits per sample, MIPI CSI-2 packing (2 samples in 3 bytes).
+    info(b"pBCC", 1, &[PACKED_12]),
+    info(b"pGCC", 1, &[PACKED_12]),
+    info(b"pgCC", 1, &[PACKED_12]),
+    info(b"pRCC", 1, &[PACKED_12]),
+    // Raw Bayer, 16 bits per sample.
+    info(b"BYR2", 1, &[Y16]),
+    info(b"GB16", 1, &[Y16]),
+    info(b"GR16", 1, &[Y16]),
+    info(b"RG16", 1, &[Y16]),
+];
+
+impl PixelFormat {
+    /// Returns the layout information of this pixel format, or `None` if the
+    /// format is not part of the database (e.g. compressed formats).
+    pub fn info(&self) -> Option<&'static PixelFormatInfo> {
+        FORMATS.iter().find(|info| info.pixelformat == *self)
+    }
+}
+
+impl Format {
+    /// Computes the minimum layout of each memory plane of this format from its
+    /// pixel format and resolution, ignoring `plane_fmt`.
+    ///
+    /// The returned vector has one entry per memory plane, which is the layout
+    /// that a driver without any alignment constraint would return. Returns
+    /// `None` if the pixel format is not part of the database, or if the
+    /// resolution is too large for the sizes to fit in a `u32`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use v4l2r::{Format, PlaneLayout};
+    /// let f = Format::from((b"NV12", (640, 480)));
+    /// assert_eq!(
+    ///     f.plane_sizes(),
+    ///     Some(vec![PlaneLayout {
+    ///         sizeimage: 640 * 480 * 3 / 2,
+    ///         bytesperline: 640,
+    ///     }])
+    /// );
+    ///
+    /// let f = Format::from((b"NM12", (640, 480)));
+    /// assert_eq!(
+    ///     f.plane_sizes(),
+    ///     Some(vec![
+    ///         PlaneLayout {
+    ///             sizeimage: 640 * 480,
+    ///             bytesperline: 640,
+    ///         },
+    ///         PlaneLayout {
+    ///             sizeimage: 640 * 480 / 2,
+    ///             bytesperline: 640,
+    ///         },
+    ///     ])
+    /// );
+    /// ```
+    pub fn plane_sizes(&self) -> Option<Vec<PlaneLayout>> {
+        let info = self.pixelformat.info()?;
+
+        let color_planes = info
+            .color_planes
+            .iter()
+            .map(|plane| {
+                Some(PlaneLayout {
+                    sizeimage: plane.size(self.width, self.height)?,
+                    bytesperline: plane.bytesperline(self.width)?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        if info.num_mem_planes == 1 {
+            // All color planes are contiguous in the same buffer, whose stride
+            // is that of the first one.
+            Some(vec![PlaneLayout {
+                sizeimage: color_planes
+                    .iter()
+                    .try_fold(0u32, |total, plane| total.checked_add(plane.sizeimage))?,
+                bytesperline: color_planes.first()?.bytesperline,
+            }])
+        } else {
+            Some(color_planes)
+        }
+    }
+
+    /// Returns the total number of bytes required to hold one frame of this
+    /// format.
+    ///
+    /// If `plane_fmt` is set (typically after the format has been negotiated
+    /// with the driver), the sum of its `sizeimage` is returned. Otherwise the
+    /// size is estimated from the pixel format database using `plane_sizes()`,
+    /// and `None` is returned if the pixel format is unknown.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use v4l2r::Format;
+    /// let f = Format::from((b"YUYV", (640, 480)));
+    /// assert_eq!(f.estimate_buffer_size(), Some(640 * 480 * 2));
+    ///
+    /// let f = Format::from((b"H264", (640, 480)));
+    /// assert_eq!(f.estimate_buffer_size(), None);
+    /// ```
+    pub fn estimate_buffer_size(&self) -> Option<usize> {
+        let total = |planes: &[PlaneLayout]| -> usize {
+            planes.iter().map(|plane| plane.sizeimage as usize).sum()
+        };
+
+        if !self.plane_fmt.is_empty() {
+            Some(total(&self.plane_fmt))
+        } else {
+            self.plane_sizes().map(|planes| total(&planes))
+        }
+    }
+}
+
+/// Equivalent DRM fourcc of the V4L2 pixel formats, for importing buffers
+/// into DRM/KMS, EGL or Vulkan. This covers all the color plane layouts of the
+/// database: the multi-planar V4L2 variants map to the same DRM format as
+/// their single-planar counterpart.
+static DRM_FOURCCS: &[(&[u8; 4], &[u8; 4])] = &[
+    (b"NV12", b"NV12"),
+    (b"NM12", b"NV12"),
+    (b"NV21", b"NV21"),
+    (b"NM21", b"NV21"),
+    (b"NV16", b"NV16"),
+    (b"NM16", b"NV16"),
+    (b"NV61", b"NV61"),
+    (b"NM61", b"NV61"),
+    (b"NV24", b"NV24"),
+    (b"NV42", b"NV42"),
+    (b"P010", b"P010"),
+    (b"YU12", b"YU12"),
+    (b"YM12", b"YU12"),
+    (b"YV12", b"YV12"),
+    (b"YM21", b"YV12"),
+    (b"422P", b"YU16"),
+    (b"YM16", b"YU16"),
+    (b"YM61", b"YV16"),
+    (b"YM24", b"YU24"),
+    (b"YUYV", b"YUYV"),
+    (b"YVYU", b"YVYU"),
+    (b"UYVY", b"UYVY"),
+    (b"VYUY", b"VYUY"),
+    (b"GREY", b"R8  "),
+    (b"Y16 ", b"R16 "),
+    (b"RGBP", b"RG16"),
+    // V4L2 names RGB formats after their order in memory, DRM after their
+    // order in a little-endian word.
+    (b"RGB3", b"BG24"),
+    (b"BGR3", b"RG24"),
+    // Both use the same codes for the non-deprecated 32-bit RGB formats.
+    (b"AR24", b"AR24"),
+    (b"XR24", b"XR24"),
+    (b"AB24", b"AB24"),
+    (b"XB24", b"XB24"),
+    (b"RA24", b"RA24"),
+    (b"RX24", b"RX24"),
+    (b"BA24", b"BA24"),
+    (b"BX24", b"BX24"),
+];
+
+impl PixelFormat {
+    /// Returns the DRM fourcc (as defined in `drm_fourcc.h`) describing the
+    /// same layout as this format, if there is one.
+    ///
+    /// ```
+    /// # use v4l2r::PixelFormat;
+    /// assert_eq!(
+    ///     PixelFormat::from(b"NM12").to_drm_fourcc(),
+    ///     Some(u32::from_le_bytes(*b"NV12"))
+    /// );
+    /// assert_eq!(PixelFormat::from(b"H264").to_drm_fourcc(), None);
+    /// ```
+    pub fn to_drm_fourcc(&self) -> Option<u32> {
+        DRM_FOURCCS
+            .iter()
+            .find(|(v4l2, _)| PixelFormat::from_fourcc(v4l2) == *self)
+            .map(|(_, drm)| u32::from_le_bytes(**drm))
+    }
+
+    /// Returns the single-planar V4L2 pixel format corresponding to the DRM
+    /// fourcc `drm_fourcc`, if there is one.
+    pub fn from_drm_fourcc(drm_fourcc: u32) -> Option<Self> {
+        DRM_FOURCCS
+            .iter()
+            .find(|(_, drm)| u32::from_le_bytes(**drm) == drm_fourcc)
+            .map(|(v4l2, _)| PixelFormat::from_fourcc(v4l2))
+    }
+}
+
+/// Location of a color plane in the memory planes of a buffer, as required to
+/// import the buffer into APIs describing each color plane separately like
+/// DRM, EGL or Vulkan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorPlaneLayout {
+    /// Index of the memory plane containing the color plane.
+    pub mem_plane: usize,
+    /// Offset of the color plane in its memory plane.
+    pub offset: u32,
+    /// Number of bytes between two lines of the color plane.
+    pub pitch: u32,
+}
+
+impl Format {
+    /// Returns the location of each color plane of this format, computed from
+    /// the `plane_fmt` returned by the driver. Returns `None` if the pixel
+    /// format is not part of the database, `plane_fmt` does not have one
+    /// entry per memory plane, or the offsets do not fit in a `u32`.
+    ///
+    /// ```
+    /// # use v4l2r::{format_info::ColorPlaneLayout, Format, PlaneLayout};
+    /// let mut f = Format::from((b"NV12", (640, 480)));
+    /// f.plane_fmt = vec![PlaneLayout {
+    ///     sizeimage: 704 * 480 * 3 / 2,
+    ///     bytesperline: 704,
+    /// }];
+    /// assert_eq!(
+    ///     f.color_plane_layouts().unwrap()[1],
+    ///     ColorPlaneLayout {
+    ///         mem_plane: 0,
+    ///         offset: 704 * 480,
+    ///         pitch: 704,
+    ///     }
+    /// );
+    /// ```
+    pub fn color_plane_layouts(&self) -> Option<Vec<ColorPlaneLayout>> {
+        let info = self.pixelformat.info()?;
+        if self.plane_fmt.len() != info.num_mem_planes {
+            return None;
+        }
+
+        if info.num_mem_planes > 1 {
+            return Some(
+                self.plane_fmt
+                    .iter()
+                    .enumerate()
+                    .map(|(mem_plane, plane)| ColorPlaneLayout {
+                        mem_plane,
+                        offset: 0,
+                        pitch: plane.bytesperline,
+                    })
+                    .collect(),
+            );
+        }
+
+        // Color planes sharing a memory plane are stored one after the other,
+        // with a pitch proportional to that of the first plane.
+        let first_stride = info.color_planes.first()?.bytesperline(self.width)?;
+        let mut offset = 0u32;
+        info.color_planes
+            .iter()
+            .map(|plane| {
+                let pitch = u32::try_from(
+                    self.plane_fmt[0].bytesperline as u64 * plane.bytesperline(self.width)? as u64
+                        / first_stride.max(1) as u64,
+                )
+                .ok()?;
+                let layout = ColorPlaneLayout {
+                    mem_plane: 0,
+                    offset,
+                    pitch,
+                };
+                offset = offset.checked_add(pitch.checked_mul(plane.lines(self.height))?)?;
+                Some(layout)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum FrameCopyError {
+    #[error("pixel format {0} is not part of the database")]
+    UnknownPixelFormat(PixelFormat),
+    #[error("format has {format} planes but {provided} were provided")]
+    PlaneCountMismatch { format: usize, provided: usize },
+    #[error("source frame is too small: {needed} bytes needed, {available} available")]
+    SourceTooSmall { needed: usize, available: usize },
+    #[error("frame size does not fit in a u32")]
+    SizeOverflow,
+    #[error("error while copying memory plane {0}: {1}")]
+    CopyError(usize, #[source] StrideCopyError),
+}
+
+impl crate::ioctl::OsError for FrameCopyError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
+crate::ioctl::impl_into_io_error!(FrameCopyError);
+
+impl Format {
+    /// Copy the tightly-packed frame `src`, i.e. with all its color planes stored one after the
+    /// other without any line padding, into the memory `planes` of a buffer of this format.
+    ///
+    /// The lines of each color plane are written using the `bytesperline` of `plane_fmt`, so
+    /// this format must be the one returned by the driver. Returns the number of bytes used in
+    /// each memory plane.
+    pub fn copy_packed_frame<M: AsMut<[u8]>>(
+        &self,
+        src: &[u8],
+        planes: &mut [M],
+    ) -> Result<Vec<usize>, FrameCopyError> {
+        let info = self
+            .pixelformat
+            .info()
+            .ok_or(FrameCopyError::UnknownPixelFormat(self.pixelformat))?;
+        for provided in [self.plane_fmt.len(), planes.len()] {
+            if provided != info.num_mem_planes {
+                return Err(FrameCopyError::PlaneCountMismatch {
+                    format: info.num_mem_planes,
+                    provided,
+                });
+            }
+        }
+
+        let needed = info
+            .color_planes
+            .iter()
+            .try_fold(0usize, |needed, plane| {
+                needed.checked_add(plane.size(self.width, self.height)? as usize)
+            })
+            .ok_or(FrameCopyError::SizeOverflow)?;
+        if src.len() < needed {
+            return Err(FrameCopyError::SourceTooSmall {
+                needed,
+                available: src.len(),
+            });
+        }
+
+        let mut bytes_used = vec![0usize; info.num_mem_planes];
+        let mut src_offset = 0;
+        for (i, color_plane) in info.color_planes.iter().enumerate() {
+            // Sizes have been checked above already.
+            let src_stride = color_plane.bytesperline(self.width).unwrap() as usize;
+            let src_len = src_stride * color_plane.lines(self.height) as usize;
+            // Color planes sharing a memory plane are stored one after the other, with a stride
+            // proportional to that of the first plane.
+            let (mem_plane, dst_stride) = if info.num_mem_planes == 1 {
+                let first_stride = info.color_planes[0].bytesperline(self.width).unwrap() as usize;
+                (
+                    0,
+                    self.plane_fmt[0].bytesperline as usize * src_stride / first_stride,
+                )
+            } else {
+                (i, self.plane_fmt[i].bytesperline as usize)
+            };
+
+            let dst = planes[mem_plane].as_mut();
+            let dst_offset = bytes_used[mem_plane].min(dst.len());
+            bytes_used[mem_plane] += copy_with_stride(
+                &mut dst[dst_offset..],
+                dst_stride,
+                &src[src_offset..src_offset + src_len],
+                src_stride,
+            )
+            .map_err(|e| FrameCopyError::CopyError(mem_plane, e))?;
+            src_offset += src_len;
+        }
+
+        Ok(bytes_used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Format, PlaneLayout};
+
+    #[test]
+    fn plane_sizes() {
+        // Odd resolutions must round the chroma planes up.
+        let f = Format::from((b"YM12", (641, 481)));
+        assert_eq!(
+            f.plane_sizes(),
+            Some(vec![
+                PlaneLayout {
+                    sizeimage: 641 * 481,
+                    bytesperline: 641,
+                },
+                PlaneLayout {
+                    sizeimage: 321 * 241,
+                    bytesperline: 321,
+                },
+                PlaneLayout {
+                    sizeimage: 321 * 241,
+                    bytesperline: 321,
+                },
+            ])
+        );
+
+        let f = Format::from((b"YU12", (640, 480)));
+        assert_eq!(
+            f.plane_sizes(),
+            Some(vec![PlaneLayout {
+                sizeimage: 640 * 480 * 3 / 2,
+                bytesperline: 640,
+            }])
+        );
+
+        let f = Format::from((b"P010", (64, 64)));
+        assert_eq!(
+            f.plane_sizes(),
+            Some(vec![PlaneLayout {
+                sizeimage: 128 * 64 + 128 * 32,
+                bytesperline: 128,
+            }])
+        );
+
+        let f = Format::from((b"AR24", (64, 64)));
+        assert_eq!(
+            f.plane_sizes(),
+            Some(vec![PlaneLayout {
+                sizeimage: 256 * 64,
+                bytesperline: 256,
+            }])
+        );
+
+        // Packed Bayer formats round partial bytes up.
+        let f = Format::from((b"pRAA", (642, 4)));
+        assert_eq!(
+            f.plane_sizes(),
+            Some(vec![PlaneLayout {
+                sizeimage: 803 * 4,
+                bytesperline: 803,
+            }])
+        );
+
+        let f = Format::from((b"pgCC", (640, 4)));
+        assert_eq!(
+            f.plane_sizes(),
+            Some(vec![PlaneLayout {
+                sizeimage: 960 * 4,
+                bytesperline: 960,
+            }])
+        );
+
+        let f = Format::from((b"FWHT", (64, 64)));
+        assert_eq!(f.plane_sizes(), None);
+        // Sizes that do not fit in a u32.
+        assert_eq!(PACKED_32.bytesperline(u32::MAX), None);
+        assert_eq!(PACKED_32.bytesperline(1 << 30), None);
+        assert_eq!(PACKED_32.bytesperline((1 << 30) - 1), Some(u32::MAX - 3));
+        assert_eq!(PACKED_32.size(1 << 16, 1 << 14), None);
+        let f = Format::from((b"AR24", (1 << 16, 1 << 14)));
+        assert_eq!(f.plane_sizes(), None);
+        assert_eq!(f.estimate_buffer_size(), None);
+        // Each plane fits, but not their sum.
+        let f = Format::from((b"NV12", (1 << 16, 0xc000)));
+        assert_eq!(f.plane_sizes(), None);
+    }
+
+    #[test]
+    fn drm_fourccs() {
+        let drm = |fourcc: &[u8; 4]| PixelFormat::from(fourcc).to_drm_fourcc();
+
+        assert_eq!(drm(b"YM12"), Some(u32::from_le_bytes(*b"YU12")));
+        assert_eq!(drm(b"RGB3"), Some(u32::from_le_bytes(*b"BG24")));
+        assert_eq!(drm(b"FWHT"), None);
+        assert_eq!(
+            PixelFormat::from_drm_fourcc(u32::from_le_bytes(*b"NV12")),
+            Some(PixelFormat::from(b"NV12"))
+        );
+        // All the mapped formats are in the database.
+        for (v4l2, _) in DRM_FOURCCS {
+            assert!(PixelFormat::from(*v4l2).info().is_some(), "{:?}", v4l2);
+        }
+    }
+
+    #[test]
+    fn color_plane_layouts() {
+        let mut f = Format::from((b"YU12", (640, 480)));
+        assert_eq!(f.color_plane_layouts(), None);
+
+        f.plane_fmt = vec![PlaneLayout {
+            sizeimage: 1024 * 480 * 3 / 2,
+            bytesperline: 1024,
+        }];
+        assert_eq!(
+            f.color_plane_layouts(),
+            Some(vec![
+                ColorPlaneLayout {
+                    mem_plane: 0,
+                    offset: 0,
+                    pitch: 1024,
+                },
+                ColorPlaneLayout {
+                    mem_plane: 0,
+                    offset: 1024 * 480,
+                    pitch: 512,
+                },
+                ColorPlaneLayout {
+                    mem_plane: 0,
+                    offset: 1024 * 480 + 512 * 240,
+                    pitch: 512,
+                },
+            ])
+        );
+
+        let mut f = Format::from((b"NM12", (640, 480)));
+        f.plane_fmt = vec![
+            PlaneLayout {
+                sizeimage: 640 * 480,
+                bytesperline: 640,
+            },
+            PlaneLayout {
+                sizeimage: 640 * 240,
+                bytesperline: 640,
+            },
+        ];
+        assert_eq!(
+            f.color_plane_layouts().unwrap()[1],
+            ColorPlaneLayout {
+                mem_plane: 1,
+                offset: 0,
+                pitch: 640,
+            }
+        );
+    }
+
+    #[test]
+    fn estimate_buffer_size() {
+        // Negotiated plane layouts take precedence over the estimate.
+        let mut f = Format::from((b"NV12", (640, 480)));
+        assert_eq!(f.estimate_buffer_size(), Some(640 * 480 * 3 / 2));
+        f.plane_fmt = vec![PlaneLayout {
+            sizeimage: 655360,
+            bytesperline: 1024,
+        }];
+        assert_eq!(f.estimate_buffer_size(), Some(655360));
+    }
+
+    #[test]
+    fn copy_packed_frame() {
+        // 2x2 NV12 frame with lines padded to 4 bytes.
+        let mut f = Format::from((b"NV12", (2, 2)));
+        f.plane_fmt = vec![PlaneLayout {
+            sizeimage: 12,
+            bytesperline: 4,
+        }];
+        let src = [1u8, 2, 3, 4, 5, 6];
+        let mut planes = [vec![0u8; 12]];
+        assert_eq!(f.copy_packed_frame(&src, &mut planes), Ok(vec![12]));
+        assert_eq!(planes[0], [1, 2, 0, 0, 3, 4, 0, 0, 5, 6, 0, 0]);
+
+        // Same frame in YM12, with one memory plane per color plane.
+        let mut f = Format::from((b"YM12", (2, 2)));
+        f.plane_fmt = vec![
+            PlaneLayout {
+                sizeimage: 8,
+                bytesperline: 4,
+            },
+            PlaneLayout {
+                sizeimage: 2,
+                bytesperline: 2,
+            },
+            PlaneLayout {
+                sizeimage: 2,
+                bytesperline: 2,
+            },
+        ];
+        let mut planes = [vec![0u8; 8], vec![0u8; 2], vec![0u8; 2]];
+        assert_eq!(f.copy_packed_frame(&src, &mut planes), Ok(vec![8, 2, 2]));
+        assert_eq!(planes[0], [1, 2, 0, 0, 3, 4, 0, 0]);
+        assert_eq!(planes[1], [5, 0]);
+        assert_eq!(planes[2], [6, 0]);
+
+        assert_eq!(
+            f.copy_packed_frame(&src[..5], &mut planes),
+            Err(FrameCopyError::SourceTooSmall {
+                needed: 6,
+                available: 5
+            })
+        );
+
+        let mut large = Format::from((b"AR24", (1 << 16, 1 << 14)));
+        large.plane_fmt = vec![PlaneLayout {
+            sizeimage: 0,
+            bytesperline: 0,
+        }];
+        assert_eq!(
+            large.copy_packed_frame(&src, &mut planes[..1]),
+            Err(FrameCopyError::SizeOverflow)
+        );
+        assert_eq!(
+            f.copy_packed_frame(&src, &mut planes[..1]),
+            Err(FrameCopyError::PlaneCountMismatch {
+                format: 3,
+                provided: 1
+            })
+        );
+    }
+}
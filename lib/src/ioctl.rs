@@ -41,7 +41,8 @@
 //! can return -EAGAIN if no buffer is available to dequeue, which is not an error and thus is
 //! represented by its own variant. Actual errors are captured by the `IoctlError` variant, and all
 //! error types can be converted to their original error code using their `Into<Errno>`
-//! implementation.
+//! implementation, or queried for it using the [`OsError`] trait. They can also be converted into
+//! `std::io::Error`.
 
 mod decoder_cmd;
 mod dqbuf;
@@ -58,6 +59,7 @@ mod g_input;
 mod g_jpegcomp;
 mod g_parm;
 mod g_selection;
+mod media;
 mod mmap;
 mod qbuf;
 mod querybuf;
@@ -66,7 +68,10 @@ mod queryctrl;
 mod reqbufs;
 mod request;
 mod streamon;
+mod subdev;
 mod subscribe_event;
+#[cfg(feature = "ioctl-trace")]
+pub mod trace;
 
 pub use decoder_cmd::*;
 pub use dqbuf::*;
@@ -83,6 +88,7 @@ pub use g_input::*;
 pub use g_jpegcomp::*;
 pub use g_parm::*;
 pub use g_selection::*;
+pub use media::*;
 pub use mmap::*;
 pub use qbuf::*;
 pub use querybuf::*;
@@ -91,6 +97,7 @@ pub use queryctrl::*;
 pub use reqbufs::*;
 pub use request::*;
 pub use streamon::*;
+pub use subdev::*;
 pub use subscribe_event::*;
 
 use std::convert::Infallible;
@@ -112,6 +119,7 @@ use crate::memory::Memory;
 use crate::memory::MemoryType;
 use crate::memory::Mmap;
 use crate::memory::UserPtr;
+use crate::Colorimetry;
 use crate::Colorspace;
 use crate::PixelFormat;
 use crate::Quantization;
@@ -151,6 +159,203 @@ where
     }
 }
 
+/// Trait implemented by the errors of this crate, giving access to the error code they originate
+/// from without consuming them. This allows applications to react to e.g. `EBUSY` or `EINVAL`
+/// regardless of how deeply the ioctl error is nested.
+///
+/// The errors of the ioctl wrappers always have an error code, while higher-level errors return
+/// `None` if they have not been caused by a failing system call.
+pub trait OsError: std::error::Error {
+    /// Returns the error code this error originates from, if any.
+    fn errno(&self) -> Option<Errno>;
+
+    /// Returns the raw error code this error originates from, if any, like
+    /// `std::io::Error::raw_os_error()`.
+    fn raw_os_error(&self) -> Option<i32> {
+        self.errno().map(|errno| errno as i32)
+    }
+}
+
+impl<T> OsError for T
+where
+    T: std::error::Error + Clone + Into<Errno>,
+{
+    fn errno(&self) -> Option<Errno> {
+        Some(self.clone().into())
+    }
+}
+
+/// Converts `err` into an `io::Error` of the kind matching its error code, keeping `err` as the
+/// inner error so the chain of sources is preserved.
+pub(crate) fn into_io_error<E>(err: E) -> std::io::Error
+where
+    E: OsError + Send + Sync + 'static,
+{
+    let kind = err
+        .raw_os_error()
+        .map(|code| std::io::Error::from_raw_os_error(code).kind())
+        .unwrap_or(std::io::ErrorKind::Other);
+
+    std::io::Error::new(kind, err)
+}
+
+/// Implements `From<T> for io::Error` for each of the given error types using `into_io_error`.
+macro_rules! impl_into_io_error {
+    ($($t:ty),+ $(,)?) => {
+        $(
+            impl From<$t> for std::io::Error {
+                fn from(err: $t) -> Self {
+                    $crate::ioctl::into_io_error(err)
+                }
+            }
+        )+
+    };
+}
+pub(crate) use impl_into_io_error;
+
+/// Declares the function performing an ioctl, like the `nix` macro of the
+/// same name. With the `ioctl-trace` feature the function goes through the
+/// [`trace`] module, so the ioctl can be recorded or replayed.
+macro_rules! ioctl_read {
+    ($name:ident, $ioty:expr, $nr:expr, $ty:ty) => {
+        #[cfg(not(feature = "ioctl-trace"))]
+        nix::ioctl_read!($name, $ioty, $nr, $ty);
+        #[cfg(feature = "ioctl-trace")]
+        #[allow(dead_code)]
+        pub unsafe fn $name(fd: nix::libc::c_int, data: *mut $ty) -> nix::Result<nix::libc::c_int> {
+            $crate::ioctl::trace::ioctl(
+                fd,
+                nix::request_code_read!($ioty, $nr, std::mem::size_of::<$ty>()),
+                data as *mut _,
+                std::mem::size_of::<$ty>(),
+                true,
+            )
+        }
+    };
+}
+pub(crate) use ioctl_read;
+
+/// Declares the function performing an ioctl, like the `nix` macro of the
+/// same name. See [`ioctl_read`].
+macro_rules! ioctl_readwrite {
+    ($name:ident, $ioty:expr, $nr:expr, $ty:ty) => {
+        #[cfg(not(feature = "ioctl-trace"))]
+        nix::ioctl_readwrite!($name, $ioty, $nr, $ty);
+        #[cfg(feature = "ioctl-trace")]
+        #[allow(dead_code)]
+        pub unsafe fn $name(fd: nix::libc::c_int, data: *mut $ty) -> nix::Result<nix::libc::c_int> {
+            $crate::ioctl::trace::ioctl(
+                fd,
+                nix::request_code_readwrite!($ioty, $nr, std::mem::size_of::<$ty>()),
+                data as *mut _,
+                std::mem::size_of::<$ty>(),
+                true,
+            )
+        }
+    };
+}
+pub(crate) use ioctl_readwrite;
+
+/// Declares the function performing an ioctl, like the `nix` macro of the
+/// same name. See [`ioctl_read`].
+macro_rules! ioctl_write_ptr {
+    ($name:ident, $ioty:expr, $nr:expr, $ty:ty) => {
+        #[cfg(not(feature = "ioctl-trace"))]
+        nix::ioctl_write_ptr!($name, $ioty, $nr, $ty);
+        #[cfg(feature = "ioctl-trace")]
+        #[allow(dead_code)]
+        pub unsafe fn $name(
+            fd: nix::libc::c_int,
+            data: *const $ty,
+        ) -> nix::Result<nix::libc::c_int> {
+            $crate::ioctl::trace::ioctl(
+                fd,
+                nix::request_code_write!($ioty, $nr, std::mem::size_of::<$ty>()),
+                data as *mut _,
+                std::mem::size_of::<$ty>(),
+                false,
+            )
+        }
+    };
+}
+pub(crate) use ioctl_write_ptr;
+
+/// Declares the function performing an ioctl, like the `nix` macro of the
+/// same name. See [`ioctl_read`].
+macro_rules! ioctl_none {
+    ($name:ident, $ioty:expr, $nr:expr) => {
+        #[cfg(not(feature = "ioctl-trace"))]
+        nix::ioctl_none!($name, $ioty, $nr);
+        #[cfg(feature = "ioctl-trace")]
+        #[allow(dead_code)]
+        pub unsafe fn $name(fd: nix::libc::c_int) -> nix::Result<nix::libc::c_int> {
+            $crate::ioctl::trace::ioctl(
+                fd,
+                nix::request_code_none!($ioty, $nr),
+                std::ptr::null_mut(),
+                0,
+                false,
+            )
+        }
+    };
+}
+pub(crate) use ioctl_none;
+
+impl_into_io_error!(
+    BuildDecoderCmdError,
+    CreateBufsError,
+    CtrlIdError,
+    DecoderCmdIoctlError,
+    DqBufIoctlError,
+    DqEventError,
+    DvTimingsCapError,
+    EncoderCmdError,
+    EnumDvTimingsError,
+    EnumFmtError,
+    EnumFreqBandsError,
+    EnumStdError,
+    EventConversionError,
+    ExpbufError,
+    ExtControlError,
+    ExtControlErrorType,
+    FrameIntervalsError,
+    FrameSizeError,
+    FwhtParamsCtrlError,
+    GAudioError,
+    GCtrlError,
+    GDvTimingsError,
+    GEncIndexError,
+    GFmtError,
+    GJpegCompError,
+    GParmError,
+    GSelectionError,
+    HwFreqSeekError,
+    MenuItemIteratorError,
+    MmapError,
+    QBufIoctlError,
+    QueryBufIoctlError,
+    QueryCapError,
+    QueryCtrlError,
+    QueryDvTimingsError,
+    QueryMenuError,
+    ReqbufsError,
+    RequestError,
+    RoutingError,
+    SFmtError,
+    SSelectionError,
+    SStdError,
+    SelectionError,
+    StreamOffError,
+    StreamOnError,
+    StrideCopyError,
+    SubdevFmtError,
+    SubscribeEventError,
+    TryFmtError,
+    V4l2BufferFromError,
+    V4l2BufferResizePlanesError,
+    V4l2MplaneFormatFromError,
+);
+
 /// Error type for a "run ioctl and try to convert to safer type" operation.
 ///
 /// [`IoctlError`] means that the ioctl itself has failed, while [`ConversionError`] indicates that
@@ -176,6 +381,31 @@ where
     }
 }
 
+impl<IE, CE> OsError for IoctlConvertError<IE, CE>
+where
+    Self: std::error::Error,
+    IE: Debug + OsError,
+    CE: Debug,
+{
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            IoctlConvertError::IoctlError(e) => e.errno(),
+            IoctlConvertError::ConversionError(_) => None,
+        }
+    }
+}
+
+impl<IE, CE> From<IoctlConvertError<IE, CE>> for std::io::Error
+where
+    IoctlConvertError<IE, CE>: OsError + Send + Sync + 'static,
+    IE: Debug,
+    CE: Debug,
+{
+    fn from(err: IoctlConvertError<IE, CE>) -> Self {
+        into_io_error(err)
+    }
+}
+
 // We need a bound here, otherwise we cannot use `O::Error`.
 #[allow(type_alias_bounds)]
 pub type IoctlConvertResult<O, IE, CE> = Result<O, IoctlConvertError<IE, CE>>;
@@ -281,7 +511,7 @@ impl AsMut<bindings::v4l2_buffer> for UncheckedV4l2Buffer {
 type V4l2BufferPlanes = [bindings::v4l2_plane; bindings::VIDEO_MAX_PLANES as usize];
 
 bitflags! {
-    #[derive(Clone, Copy, Debug, Default)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
     /// `flags` member of `struct `v4l2_buffer`.
     pub struct BufferFlags: u32 {
         const MAPPED = bindings::V4L2_BUF_FLAG_MAPPED;
@@ -300,6 +530,9 @@ bitflags! {
         const TIMESTAMP_COPY = bindings::V4L2_BUF_FLAG_TIMESTAMP_COPY;
         const TSTAMP_SRC_EOF = bindings::V4L2_BUF_FLAG_TSTAMP_SRC_EOF;
         const TSTAMP_SRC_SOE = bindings::V4L2_BUF_FLAG_TSTAMP_SRC_SOE;
+        const IN_REQUEST = bindings::V4L2_BUF_FLAG_IN_REQUEST;
+        const M2M_HOLD_CAPTURE_BUF = bindings::V4L2_BUF_FLAG_M2M_HOLD_CAPTURE_BUF;
+        const REQUEST_FD = bindings::V4L2_BUF_FLAG_REQUEST_FD;
     }
 }
 
@@ -328,6 +561,12 @@ pub enum V4l2BufferResizePlanesError {
     TooManyPlanes,
 }
 
+impl OsError for V4l2BufferResizePlanesError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
 /// Safe-ish representation of a `struct v4l2_buffer`. It owns its own planes array and can only be
 /// constructed from valid data.
 ///
@@ -410,6 +649,12 @@ impl V4l2Buffer {
         BufferFlags::from_bits_truncate(self.buffer.flags)
     }
 
+    /// Returns the raw `flags` member of the buffer, including the bits that are not known to
+    /// `BufferFlags` (e.g. flags added by more recent kernels).
+    pub fn raw_flags(&self) -> u32 {
+        self.buffer.flags
+    }
+
     /// Sets the flags of this buffer.
     pub fn set_flags(&mut self, flags: BufferFlags) {
         self.buffer.flags = flags.bits();
@@ -445,6 +690,15 @@ impl V4l2Buffer {
         self.buffer.timestamp = timestamp;
     }
 
+    /// Returns the timecode of the buffer, if the `TIMECODE` flag is set.
+    pub fn timecode(&self) -> Option<bindings::v4l2_timecode> {
+        if self.flags().contains(BufferFlags::TIMECODE) {
+            Some(self.buffer.timecode)
+        } else {
+            None
+        }
+    }
+
     pub fn sequence(&self) -> u32 {
         self.buffer.sequence
     }
@@ -689,6 +943,15 @@ impl V4l2Buffer {
     }
 }
 
+/// Copy of a buffer's plane information, as returned by `V4l2PlaneAccessor::info`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct V4l2PlaneInfo {
+    pub bytesused: u32,
+    pub length: u32,
+    /// Always `0` for single-planar buffers.
+    pub data_offset: u32,
+}
+
 /// Accessor to a buffer's plane information.
 ///
 /// This is just a set of references, that are set to point to the right location depending on
@@ -717,6 +980,17 @@ impl<'a> V4l2PlaneAccessor<'a> {
     }
 }
 
+impl<'a> V4l2PlaneAccessor<'a> {
+    /// Returns a copy of the plane information.
+    pub fn info(&self) -> V4l2PlaneInfo {
+        V4l2PlaneInfo {
+            bytesused: *self.bytesused,
+            length: *self.length,
+            data_offset: self.data_offset.copied().unwrap_or(0),
+        }
+    }
+}
+
 /// Mutable accessor to a buffer's plane information.
 ///
 /// This is just a set of references, that are set to point to the right location depending on
@@ -917,6 +1191,12 @@ pub enum V4l2BufferFromError {
     UnknownMemoryType(u32),
 }
 
+impl OsError for V4l2BufferFromError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
 impl TryFrom<UncheckedV4l2Buffer> for V4l2Buffer {
     type Error = V4l2BufferFromError;
 
@@ -975,6 +1255,12 @@ pub enum V4l2MplaneFormatFromError {
     InvalidXferFunc(u8),
 }
 
+impl OsError for V4l2MplaneFormatFromError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
 /// Turn a `struct v4l2_format` into its validated version, returning an error if any of the fields
 /// cannot be validated.
 impl TryFrom<bindings::v4l2_format> for V4l2MplaneFormat {
@@ -1000,9 +1286,8 @@ impl TryFrom<bindings::v4l2_format> for V4l2MplaneFormat {
         let _ = Colorspace::n(pix_mp.colorspace).ok_or(
             V4l2MplaneFormatFromError::InvalidColorSpace(pix_mp.colorspace),
         )?;
-        let ycbcr_enc = unsafe { pix_mp.__bindgen_anon_1.ycbcr_enc };
-        let _ = YCbCrEncoding::n(ycbcr_enc as u32)
-            .ok_or(V4l2MplaneFormatFromError::InvalidYCbCr(ycbcr_enc));
+        // The Y'CbCr encoding is not validated, since HSV formats store their HSV encoding in
+        // the same field.
 
         let _ = Quantization::n(pix_mp.quantization as u32).ok_or(
             V4l2MplaneFormatFromError::InvalidQuantization(pix_mp.quantization),
@@ -1019,12 +1304,12 @@ impl TryFrom<bindings::v4l2_format> for V4l2MplaneFormat {
 impl From<(QueueDirection, bindings::v4l2_pix_format_mplane)> for V4l2MplaneFormat {
     fn from((direction, mut pix_mp): (QueueDirection, bindings::v4l2_pix_format_mplane)) -> Self {
         pix_mp.field = BufferField::n(pix_mp.field).unwrap_or_default() as u32;
-        pix_mp.colorspace = Colorspace::n(pix_mp.colorspace).unwrap_or_default() as u32;
-        let ycbcr_enc = unsafe { pix_mp.__bindgen_anon_1.ycbcr_enc };
-        pix_mp.__bindgen_anon_1.ycbcr_enc =
-            YCbCrEncoding::n(ycbcr_enc as u32).unwrap_or_default() as u8;
-        pix_mp.quantization = Quantization::n(pix_mp.quantization as u32).unwrap_or_default() as u8;
-        pix_mp.xfer_func = XferFunc::n(pix_mp.xfer_func as u32).unwrap_or_default() as u8;
+        pix_mp.colorspace = Colorspace::n(pix_mp.colorspace).unwrap_or_default().into();
+        // The Y'CbCr encoding may be an HSV encoding, so it is kept as is.
+        pix_mp.quantization =
+            u32::from(Quantization::n(pix_mp.quantization as u32).unwrap_or_default()) as u8;
+        pix_mp.xfer_func =
+            u32::from(XferFunc::n(pix_mp.xfer_func as u32).unwrap_or_default()) as u8;
 
         Self(bindings::v4l2_format {
             type_: QueueType::from_dir_and_class(direction, crate::QueueClass::VideoMplane) as u32,
@@ -1061,10 +1346,11 @@ impl V4l2MplaneFormat {
         Colorspace::n(pix_mp.colorspace).unwrap()
     }
 
+    /// Returns the Y'CbCr encoding of the format. This is `Unknown` for HSV formats, which use
+    /// this field for their HSV encoding.
     pub fn ycbcr_enc(&self) -> YCbCrEncoding {
         let pix_mp: &bindings::v4l2_pix_format_mplane = self.as_ref();
-        // Safe because we checked the boundaries at construction time.
-        YCbCrEncoding::n(unsafe { pix_mp.__bindgen_anon_1.ycbcr_enc as u32 }).unwrap()
+        YCbCrEncoding::from(unsafe { pix_mp.__bindgen_anon_1.ycbcr_enc } as u32)
     }
 
     pub fn quantization(&self) -> Quantization {
@@ -1077,6 +1363,16 @@ impl V4l2MplaneFormat {
         XferFunc::n(pix_mp.xfer_func as u32).unwrap()
     }
 
+    /// Returns all the colorimetry fields of the format at once.
+    pub fn colorimetry(&self) -> Colorimetry {
+        Colorimetry {
+            colorspace: self.colorspace(),
+            ycbcr_enc: self.ycbcr_enc(),
+            quantization: self.quantization(),
+            xfer_func: self.xfer_func(),
+        }
+    }
+
     pub fn planes(&self) -> &[bindings::v4l2_plane_pix_format] {
         let pix_mp: &bindings::v4l2_pix_format_mplane = self.as_ref();
         &pix_mp.plane_fmt[0..pix_mp.num_planes.min(bindings::VIDEO_MAX_PLANES as u8) as usize]
@@ -1118,6 +1414,39 @@ mod tests {
         };
     }
 
+    #[test]
+    fn test_v4l2_buffer_state() {
+        use super::{BufferFlags, V4l2Buffer, V4l2PlaneInfo};
+        use crate::memory::MemoryType;
+
+        let mut buffer = V4l2Buffer::new(QueueType::VideoCaptureMplane, 0, MemoryType::Mmap);
+        // Unknown flags are preserved by `raw_flags`.
+        buffer.buffer.flags = bindings::V4L2_BUF_FLAG_KEYFRAME | (1 << 31);
+        assert_eq!(buffer.flags(), BufferFlags::KEYFRAME);
+        assert_eq!(
+            buffer.raw_flags(),
+            bindings::V4L2_BUF_FLAG_KEYFRAME | (1 << 31)
+        );
+
+        // The timecode is only valid if the corresponding flag is set.
+        buffer.buffer.timecode.frames = 12;
+        assert!(buffer.timecode().is_none());
+        buffer.add_flags(BufferFlags::TIMECODE);
+        assert_eq!(buffer.timecode().map(|t| t.frames), Some(12));
+
+        buffer.planes[0].bytesused = 100;
+        buffer.planes[0].length = 4096;
+        buffer.planes[0].data_offset = 16;
+        assert_eq!(
+            buffer.get_first_plane().info(),
+            V4l2PlaneInfo {
+                bytesused: 100,
+                length: 4096,
+                data_offset: 16,
+            }
+        );
+    }
+
     #[test]
     fn test_unchecked_v4l2_buffer() {
         // Single-planar.
@@ -1139,4 +1468,75 @@ mod tests {
         let v4l2_buf_ref = v4l2_buf.as_mut();
         assert_eq!(unsafe { v4l2_buf_ref.m.planes }, planes_ptr);
     }
+
+    #[test]
+    fn test_mplane_format_hsv() {
+        use std::convert::TryFrom;
+
+        use super::V4l2MplaneFormat;
+        use crate::{Colorspace, YCbCrEncoding};
+
+        let mut format = bindings::v4l2_format {
+            type_: QueueType::VideoCaptureMplane as u32,
+            ..Default::default()
+        };
+        let pix_mp = unsafe { &mut format.fmt.pix_mp };
+        pix_mp.pixelformat = u32::from_le_bytes(*b"HSV4");
+        pix_mp.num_planes = 1;
+        pix_mp.colorspace = bindings::v4l2_colorspace_V4L2_COLORSPACE_SRGB;
+        pix_mp.__bindgen_anon_1.hsv_enc = bindings::v4l2_hsv_encoding_V4L2_HSV_ENC_256 as u8;
+
+        // The HSV encoding is accepted and reported as an unknown Y'CbCr encoding.
+        let format = V4l2MplaneFormat::try_from(format).unwrap();
+        assert_eq!(format.colorspace(), Colorspace::Srgb);
+        assert_eq!(
+            format.ycbcr_enc(),
+            YCbCrEncoding::Unknown(bindings::v4l2_hsv_encoding_V4L2_HSV_ENC_256)
+        );
+    }
+
+    #[test]
+    fn os_error() {
+        use std::error::Error;
+        use std::io;
+
+        use nix::errno::Errno;
+
+        use super::{DqBufError, OsError, SFmtError, V4l2BufferFromError};
+        use crate::device::queue::CreateQueueError;
+        use crate::encoder::EncoderOpenError;
+        use crate::ioctl::ReqbufsError;
+
+        let err = SFmtError::DeviceBusy;
+        assert_eq!(err.errno(), Some(Errno::EBUSY));
+        assert_eq!(err.raw_os_error(), Some(Errno::EBUSY as i32));
+
+        // The error code of nested errors is reachable from the outer one.
+        let err = EncoderOpenError::CreateQueueError(CreateQueueError::ReqbufsError(
+            ReqbufsError::IoctlError(Errno::EINVAL),
+        ));
+        assert_eq!(err.errno(), Some(Errno::EINVAL));
+        assert_eq!(
+            err.source()
+                .and_then(|e| e.source())
+                .and_then(|e| e.source())
+                .and_then(|e| e.downcast_ref::<Errno>()),
+            Some(&Errno::EINVAL)
+        );
+        assert_eq!(EncoderOpenError::NotAnEncoder.errno(), None);
+
+        let io_err = io::Error::from(err);
+        assert_eq!(io_err.kind(), io::ErrorKind::InvalidInput);
+        assert!(io_err
+            .get_ref()
+            .map(|e| e.is::<EncoderOpenError>())
+            .unwrap_or(false));
+
+        // Conversion failures do not come from the kernel and have no error code.
+        let err = DqBufError::<V4l2BufferFromError>::ConversionError(
+            V4l2BufferFromError::UnknownQueueType(0),
+        );
+        assert_eq!(err.errno(), None);
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::Other);
+    }
 }
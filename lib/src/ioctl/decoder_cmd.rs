@@ -55,6 +55,9 @@ pub enum DecoderCmd {
         flags: PauseCmdFlags,
     },
     Resume,
+    /// Release the CAPTURE buffer held by a stateless decoder after the last
+    /// OUTPUT buffer has been queued with the `M2M_HOLD_CAPTURE_BUF` flag.
+    Flush,
 }
 
 impl DecoderCmd {
@@ -86,6 +89,11 @@ impl DecoderCmd {
     pub fn resume() -> Self {
         DecoderCmd::Resume
     }
+
+    /// Returns a FLUSH command.
+    pub fn flush() -> Self {
+        DecoderCmd::Flush
+    }
 }
 
 impl From<DecoderCmd> for v4l2_decoder_cmd {
@@ -122,6 +130,11 @@ impl From<DecoderCmd> for v4l2_decoder_cmd {
                 flags: Default::default(),
                 __bindgen_anon_1: Default::default(),
             },
+            DecoderCmd::Flush => v4l2_decoder_cmd {
+                cmd: bindings::V4L2_DEC_CMD_FLUSH,
+                flags: Default::default(),
+                __bindgen_anon_1: Default::default(),
+            },
         }
     }
 }
@@ -140,6 +153,12 @@ pub enum BuildDecoderCmdError {
     InvalidPauseFlags(u32),
 }
 
+impl crate::ioctl::OsError for BuildDecoderCmdError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
 impl TryFrom<v4l2_decoder_cmd> for DecoderCmd {
     type Error = BuildDecoderCmdError;
 
@@ -163,6 +182,7 @@ impl TryFrom<v4l2_decoder_cmd> for DecoderCmd {
                 flags: PauseCmdFlags::from_bits_truncate(cmd.flags),
             },
             bindings::V4L2_DEC_CMD_RESUME => DecoderCmd::Resume,
+            bindings::V4L2_DEC_CMD_FLUSH => DecoderCmd::Flush,
             code => return Err(BuildDecoderCmdError::InvalidCommandCode(code)),
         };
 
@@ -178,14 +198,14 @@ impl TryFrom<v4l2_decoder_cmd> for () {
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum DecoderCmdIoctlError {
     #[error("drain already in progress")]
     DrainInProgress,
     #[error("command not supported by device")]
     UnsupportedCommand,
     #[error("unexpected ioctl error: {0}")]
-    Other(Errno),
+    Other(#[source] Errno),
 }
 
 impl From<DecoderCmdIoctlError> for Errno {
@@ -211,8 +231,8 @@ impl From<Errno> for DecoderCmdIoctlError {
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_decoder_cmd;
-    nix::ioctl_readwrite!(vidioc_decoder_cmd, b'V', 96, v4l2_decoder_cmd);
-    nix::ioctl_readwrite!(vidioc_try_decoder_cmd, b'V', 97, v4l2_decoder_cmd);
+    crate::ioctl::ioctl_readwrite!(vidioc_decoder_cmd, b'V', 96, v4l2_decoder_cmd);
+    crate::ioctl::ioctl_readwrite!(vidioc_try_decoder_cmd, b'V', 97, v4l2_decoder_cmd);
 }
 
 pub type DecoderCmdError<CE> = IoctlConvertError<DecoderCmdIoctlError, CE>;
@@ -331,5 +351,16 @@ mod tests {
         assert_eq!(cmd_safe, DecoderCmd::Resume);
         let cmd_rebuilt: bindings::v4l2_decoder_cmd = cmd_safe.into();
         assert_eq!(cmd_safe, DecoderCmd::try_from(cmd_rebuilt).unwrap());
+
+        // Build FLUSH command and back.
+        let cmd = bindings::v4l2_decoder_cmd {
+            cmd: bindings::V4L2_DEC_CMD_FLUSH,
+            flags: Default::default(),
+            __bindgen_anon_1: Default::default(),
+        };
+        let cmd_safe = DecoderCmd::try_from(cmd).unwrap();
+        assert_eq!(cmd_safe, DecoderCmd::Flush);
+        let cmd_rebuilt: bindings::v4l2_decoder_cmd = cmd_safe.into();
+        assert_eq!(cmd_safe, DecoderCmd::try_from(cmd_rebuilt).unwrap());
     }
 }
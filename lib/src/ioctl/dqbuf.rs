@@ -14,17 +14,17 @@ use thiserror::Error;
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_buffer;
-    nix::ioctl_readwrite!(vidioc_dqbuf, b'V', 17, v4l2_buffer);
+    crate::ioctl::ioctl_readwrite!(vidioc_dqbuf, b'V', 17, v4l2_buffer);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum DqBufIoctlError {
     #[error("end-of-stream reached")]
     Eos,
     #[error("no buffer ready for dequeue")]
     NotReady,
     #[error("unexpected ioctl error: {0}")]
-    Other(Errno),
+    Other(#[source] Errno),
 }
 
 impl From<Errno> for DqBufIoctlError {
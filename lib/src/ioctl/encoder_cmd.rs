@@ -13,15 +13,15 @@ mod ioctl {
     use crate::bindings::v4l2_enc_idx;
     use crate::bindings::v4l2_encoder_cmd;
 
-    nix::ioctl_read!(vidioc_g_enc_index, b'V', 76, v4l2_enc_idx);
-    nix::ioctl_readwrite!(vidioc_encoder_cmd, b'V', 77, v4l2_encoder_cmd);
-    nix::ioctl_readwrite!(vidioc_try_encoder_cmd, b'V', 78, v4l2_encoder_cmd);
+    crate::ioctl::ioctl_read!(vidioc_g_enc_index, b'V', 76, v4l2_enc_idx);
+    crate::ioctl::ioctl_readwrite!(vidioc_encoder_cmd, b'V', 77, v4l2_encoder_cmd);
+    crate::ioctl::ioctl_readwrite!(vidioc_try_encoder_cmd, b'V', 78, v4l2_encoder_cmd);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum GEncIndexError {
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<GEncIndexError> for Errno {
@@ -50,7 +50,7 @@ pub enum EncoderCommand {
     Resume,
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum EncoderCmdError {
     #[error("error while converting from v4l2_encoder_cmd")]
     FromV4L2CommandConversionError,
@@ -59,7 +59,7 @@ pub enum EncoderCmdError {
     #[error("command not supported by device")]
     UnsupportedCommand,
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<EncoderCmdError> for Errno {
@@ -63,10 +63,10 @@ impl From<v4l2_fmtdesc> for FmtDesc {
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_fmtdesc;
-    nix::ioctl_readwrite!(vidioc_enum_fmt, b'V', 2, v4l2_fmtdesc);
+    crate::ioctl::ioctl_readwrite!(vidioc_enum_fmt, b'V', 2, v4l2_fmtdesc);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum EnumFmtError {
     #[error("ioctl error: {0}")]
     IoctlError(#[from] nix::Error),
@@ -22,10 +22,10 @@ bitflags! {
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_exportbuffer;
-    nix::ioctl_readwrite!(vidioc_expbuf, b'V', 16, v4l2_exportbuffer);
+    crate::ioctl::ioctl_readwrite!(vidioc_expbuf, b'V', 16, v4l2_exportbuffer);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum ExpbufError {
     #[error("ioctl error: {0}")]
     IoctlError(#[from] Errno),
@@ -43,13 +43,13 @@ impl v4l2_frmivalenum {
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_frmivalenum;
-    nix::ioctl_readwrite!(vidioc_enum_frameintervals, b'V', 75, v4l2_frmivalenum);
+    crate::ioctl::ioctl_readwrite!(vidioc_enum_frameintervals, b'V', 75, v4l2_frmivalenum);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum FrameIntervalsError {
     #[error("Unexpected ioctl error: {0}")]
-    IoctlError(nix::Error),
+    IoctlError(#[source] nix::Error),
 }
 
 impl From<FrameIntervalsError> for Errno {
@@ -1,3 +1,4 @@
+use log::error;
 use nix::errno::Errno;
 use std::os::unix::io::AsRawFd;
 use thiserror::Error;
@@ -15,11 +16,11 @@ pub enum FrmSizeTypes<'a> {
 
 impl v4l2_frmsizeenum {
     /// Safely access the size member of the struct based on the
-    /// returned index.
+    /// returned type.
     pub fn size(&self) -> Option<FrmSizeTypes> {
-        match self.index {
+        match self.type_ {
             // SAFETY: the member of the union that gets used by the driver
-            // is determined by the index
+            // is determined by the type
             bindings::v4l2_frmsizetypes_V4L2_FRMSIZE_TYPE_DISCRETE => {
                 Some(FrmSizeTypes::Discrete(unsafe {
                     &self.__bindgen_anon_1.discrete
@@ -27,7 +28,7 @@ impl v4l2_frmsizeenum {
             }
 
             // SAFETY: the member of the union that gets used by the driver
-            // is determined by the index
+            // is determined by the type
             bindings::v4l2_frmsizetypes_V4L2_FRMSIZE_TYPE_CONTINUOUS
             | bindings::v4l2_frmsizetypes_V4L2_FRMSIZE_TYPE_STEPWISE => {
                 Some(FrmSizeTypes::StepWise(unsafe {
@@ -43,13 +44,13 @@ impl v4l2_frmsizeenum {
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_frmsizeenum;
-    nix::ioctl_readwrite!(vidioc_enum_framesizes, b'V', 74, v4l2_frmsizeenum);
+    crate::ioctl::ioctl_readwrite!(vidioc_enum_framesizes, b'V', 74, v4l2_frmsizeenum);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum FrameSizeError {
     #[error("Unexpected ioctl error: {0}")]
-    IoctlError(nix::Error),
+    IoctlError(#[source] nix::Error),
 }
 
 impl From<FrameSizeError> for Errno {
@@ -77,3 +78,230 @@ pub fn enum_frame_sizes<O: From<v4l2_frmsizeenum>>(
         Err(e) => Err(FrameSizeError::IoctlError(e)),
     }
 }
+
+/// Range of frame sizes supported by a device reporting
+/// `V4L2_FRMSIZE_TYPE_STEPWISE` or `V4L2_FRMSIZE_TYPE_CONTINUOUS` (which is a
+/// stepwise range with a step of 1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameSizeRange {
+    pub min_width: u32,
+    pub max_width: u32,
+    pub step_width: u32,
+    pub min_height: u32,
+    pub max_height: u32,
+    pub step_height: u32,
+}
+
+impl From<&bindings::v4l2_frmsize_stepwise> for FrameSizeRange {
+    fn from(stepwise: &bindings::v4l2_frmsize_stepwise) -> Self {
+        FrameSizeRange {
+            min_width: stepwise.min_width,
+            max_width: stepwise.max_width.max(stepwise.min_width),
+            step_width: stepwise.step_width.max(1),
+            min_height: stepwise.min_height,
+            max_height: stepwise.max_height.max(stepwise.min_height),
+            step_height: stepwise.step_height.max(1),
+        }
+    }
+}
+
+/// Resolutions commonly used by applications, tried by
+/// `FrameSizeRange::common_sizes()`.
+const COMMON_SIZES: &[(u32, u32)] = &[
+    (176, 144),
+    (320, 240),
+    (352, 288),
+    (640, 360),
+    (640, 480),
+    (720, 480),
+    (720, 576),
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1280, 960),
+    (1280, 1024),
+    (1600, 1200),
+    (1920, 1080),
+    (2560, 1440),
+    (3840, 2160),
+    (4096, 2160),
+];
+
+impl FrameSizeRange {
+    fn dimension_is_valid(value: u32, min: u32, max: u32, step: u32) -> bool {
+        (min..=max).contains(&value) && (value - min) % step == 0
+    }
+
+    /// Returns the valid dimension closest to `value`.
+    fn nearest_dimension(value: u32, min: u32, max: u32, step: u32) -> u32 {
+        let steps = (value.clamp(min, max) - min + step / 2) / step;
+        // Rounding up may take us past the maximum, which is not necessarily
+        // aligned to the step.
+        let nearest = min + steps * step;
+        if nearest > max {
+            nearest - step
+        } else {
+            nearest
+        }
+    }
+
+    /// Returns whether `width`x`height` is a valid size within this range.
+    pub fn contains(&self, width: u32, height: u32) -> bool {
+        Self::dimension_is_valid(width, self.min_width, self.max_width, self.step_width)
+            && Self::dimension_is_valid(height, self.min_height, self.max_height, self.step_height)
+    }
+
+    /// Returns the valid size closest to `width`x`height`.
+    pub fn nearest(&self, width: u32, height: u32) -> (u32, u32) {
+        (
+            Self::nearest_dimension(width, self.min_width, self.max_width, self.step_width),
+            Self::nearest_dimension(height, self.min_height, self.max_height, self.step_height),
+        )
+    }
+
+    /// Returns a bounded list of sizes within this range, suitable e.g. to
+    /// present to a user: all the common resolutions that the range contains,
+    /// plus its minimum and maximum sizes. The list is sorted by increasing
+    /// area.
+    pub fn common_sizes(&self) -> Vec<(u32, u32)> {
+        let max = self.nearest(self.max_width, self.max_height);
+        let mut sizes: Vec<(u32, u32)> = std::iter::once((self.min_width, self.min_height))
+            .chain(
+                COMMON_SIZES
+                    .iter()
+                    .copied()
+                    .filter(|(width, height)| self.contains(*width, *height)),
+            )
+            .chain(std::iter::once(max))
+            .collect();
+        sizes.sort_by_key(|(width, height)| (width * height, *width));
+        sizes.dedup();
+
+        sizes
+    }
+}
+
+/// Frame sizes supported by a device for a given pixel format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameSizes {
+    /// List of discrete `(width, height)` sizes.
+    Discrete(Vec<(u32, u32)>),
+    /// Any size within a range.
+    StepWise(FrameSizeRange),
+}
+
+impl FrameSizes {
+    /// Returns whether `width`x`height` is a supported size.
+    pub fn contains(&self, width: u32, height: u32) -> bool {
+        match self {
+            FrameSizes::Discrete(sizes) => sizes.contains(&(width, height)),
+            FrameSizes::StepWise(range) => range.contains(width, height),
+        }
+    }
+
+    /// Returns a bounded list of supported sizes: all of them for discrete
+    /// sizes, or those returned by `FrameSizeRange::common_sizes()` for ranges.
+    pub fn sizes(&self) -> Vec<(u32, u32)> {
+        match self {
+            FrameSizes::Discrete(sizes) => sizes.clone(),
+            FrameSizes::StepWise(range) => range.common_sizes(),
+        }
+    }
+}
+
+/// Enumerates all the frame sizes supported by `fd` for `pixel_format`, using
+/// as many `VIDIOC_ENUM_FRAMESIZES` calls as needed.
+pub fn frame_sizes(
+    fd: &impl AsRawFd,
+    pixel_format: PixelFormat,
+) -> Result<FrameSizes, FrameSizeError> {
+    let mut sizes = Vec::new();
+
+    for index in 0.. {
+        let frame_size: v4l2_frmsizeenum = match enum_frame_sizes(fd, index, pixel_format) {
+            Ok(frame_size) => frame_size,
+            // EINVAL marks the end of the enumeration.
+            Err(FrameSizeError::IoctlError(Errno::EINVAL)) if index > 0 => break,
+            Err(e) => return Err(e),
+        };
+
+        match frame_size.size() {
+            Some(FrmSizeTypes::Discrete(size)) => sizes.push((size.width, size.height)),
+            // Stepwise sizes are only reported at index 0.
+            Some(FrmSizeTypes::StepWise(stepwise)) => {
+                return Ok(FrameSizes::StepWise(stepwise.into()))
+            }
+            None => {
+                error!("Unexpected frame size type {}", frame_size.type_);
+                break;
+            }
+        }
+    }
+
+    Ok(FrameSizes::Discrete(sizes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_size_type() {
+        let frame_size = v4l2_frmsizeenum {
+            type_: bindings::v4l2_frmsizetypes_V4L2_FRMSIZE_TYPE_STEPWISE,
+            __bindgen_anon_1: bindings::v4l2_frmsizeenum__bindgen_ty_1 {
+                stepwise: bindings::v4l2_frmsize_stepwise {
+                    min_width: 16,
+                    max_width: 1920,
+                    step_width: 16,
+                    min_height: 16,
+                    max_height: 1080,
+                    step_height: 16,
+                },
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            frame_size.size(),
+            Some(FrmSizeTypes::StepWise(stepwise)) if stepwise.max_height == 1080
+        ));
+    }
+
+    #[test]
+    fn frame_size_range() {
+        let range = FrameSizeRange::from(&bindings::v4l2_frmsize_stepwise {
+            min_width: 48,
+            max_width: 1920,
+            step_width: 16,
+            min_height: 48,
+            max_height: 1080,
+            step_height: 16,
+        });
+
+        assert!(range.contains(640, 480));
+        assert!(!range.contains(648, 480));
+        assert!(!range.contains(32, 480));
+        // 1080 is not aligned to the step relative to the minimum height.
+        assert!(!range.contains(1920, 1080));
+        assert_eq!(range.nearest(650, 2000), (656, 1072));
+        assert_eq!(range.nearest(0, 0), (48, 48));
+
+        assert_eq!(
+            range.common_sizes(),
+            vec![
+                (48, 48),
+                (176, 144),
+                (320, 240),
+                (352, 288),
+                (640, 480),
+                (720, 480),
+                (720, 576),
+                (1024, 768),
+                (1280, 720),
+                (1280, 960),
+                (1280, 1024),
+                (1920, 1072),
+            ]
+        );
+    }
+}
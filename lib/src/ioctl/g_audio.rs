@@ -10,23 +10,60 @@ use crate::bindings::v4l2_audio;
 use crate::bindings::v4l2_audioout;
 use crate::bindings::v4l2_frequency;
 use crate::bindings::v4l2_frequency_band;
+use crate::bindings::v4l2_hw_freq_seek;
 use crate::bindings::v4l2_modulator;
 use crate::bindings::v4l2_tuner;
 
 bitflags! {
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub struct AudioCapability: u32 {
         const STEREO = bindings::V4L2_AUDCAP_STEREO;
         const AVL = bindings::V4L2_AUDCAP_AVL;
     }
 }
 
-#[derive(Debug, N)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, N)]
 #[repr(u32)]
 pub enum AudioMode {
     Avl = bindings::V4L2_AUDMODE_AVL,
 }
 
+/// Safe variant of `struct v4l2_audio`, describing an audio input of the device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AudioInput {
+    pub index: u32,
+    pub name: String,
+    pub capability: AudioCapability,
+    pub mode: Option<AudioMode>,
+}
+
+impl From<v4l2_audio> for AudioInput {
+    fn from(audio: v4l2_audio) -> Self {
+        AudioInput {
+            index: audio.index,
+            name: super::string_from_cstr(&audio.name).unwrap_or_default(),
+            capability: AudioCapability::from_bits_truncate(audio.capability),
+            mode: AudioMode::n(audio.mode & bindings::V4L2_AUDMODE_AVL),
+        }
+    }
+}
+
+/// Safe variant of `struct v4l2_audioout`, describing an audio output of the device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AudioOutput {
+    pub index: u32,
+    pub name: String,
+}
+
+impl From<v4l2_audioout> for AudioOutput {
+    fn from(audio: v4l2_audioout) -> Self {
+        AudioOutput {
+            index: audio.index,
+            name: super::string_from_cstr(&audio.name).unwrap_or_default(),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, N)]
 #[repr(u32)]
 pub enum TunerType {
@@ -66,6 +103,50 @@ bitflags! {
     }
 }
 
+impl TunerCapFlags {
+    /// Returns the unit of the frequencies of a tuner or modulator with these capabilities, in
+    /// Hz.
+    pub fn frequency_unit_hz(&self) -> f64 {
+        if self.contains(TunerCapFlags::ONE_HZ) {
+            1.0
+        } else if self.contains(TunerCapFlags::LOW) {
+            62.5
+        } else {
+            62_500.0
+        }
+    }
+}
+
+/// Safe variant of `struct v4l2_modulator`, describing a modulator of a radio transmitter.
+#[derive(Clone, Debug)]
+pub struct Modulator {
+    pub index: u32,
+    pub name: String,
+    pub capability: TunerCapFlags,
+    /// Lowest tunable frequency, in units of `capability.frequency_unit_hz()`.
+    pub rangelow: u32,
+    /// Highest tunable frequency, in units of `capability.frequency_unit_hz()`.
+    pub rangehigh: u32,
+    /// Sub-carriers currently being modulated, e.g. `TunerTransmissionFlags::RDS` if RDS data is
+    /// transmitted.
+    pub txsubchans: TunerTransmissionFlags,
+    pub tuner_type: Option<TunerType>,
+}
+
+impl From<v4l2_modulator> for Modulator {
+    fn from(modulator: v4l2_modulator) -> Self {
+        Modulator {
+            index: modulator.index,
+            name: super::string_from_cstr(&modulator.name).unwrap_or_default(),
+            capability: TunerCapFlags::from_bits_truncate(modulator.capability),
+            rangelow: modulator.rangelow,
+            rangehigh: modulator.rangehigh,
+            txsubchans: TunerTransmissionFlags::from_bits_truncate(modulator.txsubchans),
+            tuner_type: TunerType::n(modulator.type_),
+        }
+    }
+}
+
 #[derive(Debug, N)]
 #[repr(u32)]
 pub enum TunerMode {
@@ -82,36 +163,39 @@ mod ioctl {
     use crate::bindings::v4l2_audioout;
     use crate::bindings::v4l2_frequency;
     use crate::bindings::v4l2_frequency_band;
+    use crate::bindings::v4l2_hw_freq_seek;
     use crate::bindings::v4l2_modulator;
     use crate::bindings::v4l2_tuner;
 
-    nix::ioctl_readwrite!(vidioc_g_tuner, b'V', 29, v4l2_tuner);
-    nix::ioctl_write_ptr!(vidioc_s_tuner, b'V', 30, v4l2_tuner);
+    crate::ioctl::ioctl_readwrite!(vidioc_g_tuner, b'V', 29, v4l2_tuner);
+    crate::ioctl::ioctl_write_ptr!(vidioc_s_tuner, b'V', 30, v4l2_tuner);
+
+    crate::ioctl::ioctl_read!(vidioc_g_audio, b'V', 33, v4l2_audio);
+    crate::ioctl::ioctl_write_ptr!(vidioc_s_audio, b'V', 34, v4l2_audio);
 
-    nix::ioctl_read!(vidioc_g_audio, b'V', 33, v4l2_audio);
-    nix::ioctl_write_ptr!(vidioc_s_audio, b'V', 34, v4l2_audio);
+    crate::ioctl::ioctl_read!(vidioc_g_audout, b'V', 49, v4l2_audioout);
+    crate::ioctl::ioctl_write_ptr!(vidioc_s_audout, b'V', 50, v4l2_audioout);
 
-    nix::ioctl_read!(vidioc_g_audout, b'V', 49, v4l2_audioout);
-    nix::ioctl_write_ptr!(vidioc_s_audout, b'V', 50, v4l2_audioout);
+    crate::ioctl::ioctl_readwrite!(vidioc_g_modulator, b'V', 54, v4l2_modulator);
+    crate::ioctl::ioctl_write_ptr!(vidioc_s_modulator, b'V', 55, v4l2_modulator);
 
-    nix::ioctl_readwrite!(vidioc_g_modulator, b'V', 54, v4l2_modulator);
-    nix::ioctl_write_ptr!(vidioc_s_modulator, b'V', 55, v4l2_modulator);
+    crate::ioctl::ioctl_readwrite!(vidioc_g_frequency, b'V', 56, v4l2_frequency);
+    crate::ioctl::ioctl_write_ptr!(vidioc_s_frequency, b'V', 57, v4l2_frequency);
 
-    nix::ioctl_readwrite!(vidioc_g_frequency, b'V', 56, v4l2_frequency);
-    nix::ioctl_write_ptr!(vidioc_s_frequency, b'V', 57, v4l2_frequency);
+    crate::ioctl::ioctl_readwrite!(vidioc_enumaudio, b'V', 65, v4l2_audio);
+    crate::ioctl::ioctl_readwrite!(vidioc_enumaudout, b'V', 66, v4l2_audioout);
 
-    nix::ioctl_readwrite!(vidioc_enumaudio, b'V', 65, v4l2_audio);
-    nix::ioctl_readwrite!(vidioc_enumaudout, b'V', 66, v4l2_audioout);
+    crate::ioctl::ioctl_write_ptr!(vidioc_s_hw_freq_seek, b'V', 82, v4l2_hw_freq_seek);
 
-    nix::ioctl_readwrite!(vidioc_enum_freq_bands, b'V', 101, v4l2_frequency_band);
+    crate::ioctl::ioctl_readwrite!(vidioc_enum_freq_bands, b'V', 101, v4l2_frequency_band);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum GAudioError {
     #[error("invalid input index")]
     Invalid,
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<GAudioError> for Errno {
@@ -193,7 +277,7 @@ pub fn g_audout<O: From<v4l2_audioout>>(fd: &impl AsRawFd) -> Result<O, GAudioEr
     }
 }
 
-/// Safe wrapper around the `VIDIOC_S_AUDIO` ioctl.
+/// Safe wrapper around the `VIDIOC_S_AUDOUT` ioctl.
 pub fn s_audout(fd: &impl AsRawFd, index: u32) -> Result<(), GAudioError> {
     let audio = v4l2_audioout {
         index,
@@ -309,12 +393,38 @@ pub fn enumaudout<O: From<v4l2_audioout>>(fd: &impl AsRawFd, index: u32) -> Resu
     }
 }
 
-#[derive(Debug, Error)]
+/// Returns all the audio inputs of the device, by calling `VIDIOC_ENUMAUDIO` until the driver
+/// reports an invalid index.
+pub fn audio_inputs(fd: &impl AsRawFd) -> Result<Vec<AudioInput>, GAudioError> {
+    let mut inputs = Vec::new();
+    loop {
+        match enumaudio::<AudioInput>(fd, inputs.len() as u32) {
+            Ok(input) => inputs.push(input),
+            Err(GAudioError::Invalid) => break Ok(inputs),
+            Err(e) => break Err(e),
+        }
+    }
+}
+
+/// Returns all the audio outputs of the device, by calling `VIDIOC_ENUMAUDOUT` until the driver
+/// reports an invalid index.
+pub fn audio_outputs(fd: &impl AsRawFd) -> Result<Vec<AudioOutput>, GAudioError> {
+    let mut outputs = Vec::new();
+    loop {
+        match enumaudout::<AudioOutput>(fd, outputs.len() as u32) {
+            Ok(output) => outputs.push(output),
+            Err(GAudioError::Invalid) => break Ok(outputs),
+            Err(e) => break Err(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error)]
 pub enum EnumFreqBandsError {
     #[error("invalid tuner, index, or type")]
     Invalid,
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<EnumFreqBandsError> for Errno {
@@ -345,3 +455,156 @@ pub fn enum_freq_bands<O: From<v4l2_frequency_band>>(
         Err(e) => Err(EnumFreqBandsError::IoctlError(e)),
     }
 }
+
+/// Parameters of a hardware frequency seek, i.e. a `struct v4l2_hw_freq_seek`.
+#[derive(Clone, Debug)]
+pub struct HwFreqSeek {
+    pub tuner: u32,
+    pub tuner_type: TunerType,
+    /// Seek towards higher frequencies if `true`, lower ones otherwise.
+    pub seek_upward: bool,
+    /// Continue from the other end of the range once one end has been reached. Requires
+    /// `TunerCapFlags::HWSEEK_WRAP`.
+    pub wrap_around: bool,
+    /// Step of the seek in Hz, or 0 to let the driver choose.
+    pub spacing: u32,
+    /// Range to seek in, in the frequency units of the tuner, or `None` for the whole range of the
+    /// tuner. Requires `TunerCapFlags::HWSEEK_PROG_LIM`.
+    pub range: Option<(u32, u32)>,
+}
+
+impl HwFreqSeek {
+    /// Creates the parameters of an upward seek over the whole range of radio tuner `tuner`.
+    pub fn new(tuner: u32) -> Self {
+        HwFreqSeek {
+            tuner,
+            tuner_type: TunerType::Radio,
+            seek_upward: true,
+            wrap_around: false,
+            spacing: 0,
+            range: None,
+        }
+    }
+}
+
+impl From<&HwFreqSeek> for v4l2_hw_freq_seek {
+    fn from(seek: &HwFreqSeek) -> Self {
+        let (rangelow, rangehigh) = seek.range.unwrap_or((0, 0));
+
+        v4l2_hw_freq_seek {
+            tuner: seek.tuner,
+            type_: seek.tuner_type as u32,
+            seek_upward: seek.seek_upward as u32,
+            wrap_around: seek.wrap_around as u32,
+            spacing: seek.spacing,
+            rangelow,
+            rangehigh,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum HwFreqSeekError {
+    #[error("invalid tuner or seek parameters")]
+    Invalid,
+    #[error("no station found")]
+    NoStation,
+    #[error("another seek is already in progress")]
+    Busy,
+    #[error("seeking is not possible on a non-blocking file descriptor")]
+    WouldBlock,
+    #[error("seek interrupted by a signal")]
+    Interrupted,
+    #[error("ioctl error: {0}")]
+    IoctlError(#[source] Errno),
+}
+
+impl From<HwFreqSeekError> for Errno {
+    fn from(err: HwFreqSeekError) -> Self {
+        match err {
+            HwFreqSeekError::Invalid => Errno::EINVAL,
+            HwFreqSeekError::NoStation => Errno::ENODATA,
+            HwFreqSeekError::Busy => Errno::EBUSY,
+            HwFreqSeekError::WouldBlock => Errno::EAGAIN,
+            HwFreqSeekError::Interrupted => Errno::EINTR,
+            HwFreqSeekError::IoctlError(e) => e,
+        }
+    }
+}
+
+/// Safe wrapper around the `VIDIOC_S_HW_FREQ_SEEK` ioctl.
+///
+/// This blocks until a station is found and the tuner is set to its frequency, and fails with
+/// `HwFreqSeekError::WouldBlock` if `fd` has been opened with `O_NONBLOCK`.
+pub fn s_hw_freq_seek(fd: &impl AsRawFd, seek: &HwFreqSeek) -> Result<(), HwFreqSeekError> {
+    let seek = v4l2_hw_freq_seek::from(seek);
+
+    match unsafe { ioctl::vidioc_s_hw_freq_seek(fd.as_raw_fd(), &seek) } {
+        Ok(_) => Ok(()),
+        Err(Errno::EINVAL) => Err(HwFreqSeekError::Invalid),
+        Err(Errno::ENODATA) => Err(HwFreqSeekError::NoStation),
+        Err(Errno::EBUSY) => Err(HwFreqSeekError::Busy),
+        Err(Errno::EAGAIN) => Err(HwFreqSeekError::WouldBlock),
+        Err(Errno::EINTR) => Err(HwFreqSeekError::Interrupted),
+        Err(e) => Err(HwFreqSeekError::IoctlError(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_input_from_v4l2_audio() {
+        let mut audio = v4l2_audio {
+            index: 1,
+            capability: bindings::V4L2_AUDCAP_STEREO,
+            mode: bindings::V4L2_AUDMODE_AVL,
+            ..Default::default()
+        };
+        audio.name[..4].copy_from_slice(b"Line");
+
+        assert_eq!(
+            AudioInput::from(audio),
+            AudioInput {
+                index: 1,
+                name: String::from("Line"),
+                capability: AudioCapability::STEREO,
+                mode: Some(AudioMode::Avl),
+            }
+        );
+    }
+
+    #[test]
+    fn modulator_from_v4l2_modulator() {
+        let modulator = v4l2_modulator {
+            capability: bindings::V4L2_TUNER_CAP_LOW | bindings::V4L2_TUNER_CAP_RDS,
+            // 76 MHz and 108 MHz in units of 62.5 Hz.
+            rangelow: 1_216_000,
+            rangehigh: 1_728_000,
+            txsubchans: bindings::V4L2_TUNER_SUB_STEREO | bindings::V4L2_TUNER_SUB_RDS,
+            type_: bindings::v4l2_tuner_type_V4L2_TUNER_RADIO,
+            ..Default::default()
+        };
+
+        let modulator = Modulator::from(modulator);
+        assert_eq!(modulator.capability.frequency_unit_hz(), 62.5);
+        assert!(modulator.txsubchans.contains(TunerTransmissionFlags::RDS));
+        assert!(matches!(modulator.tuner_type, Some(TunerType::Radio)));
+    }
+
+    #[test]
+    fn hw_freq_seek_params() {
+        let seek = HwFreqSeek {
+            seek_upward: false,
+            range: Some((1_216_000, 1_728_000)),
+            ..HwFreqSeek::new(0)
+        };
+
+        let seek = v4l2_hw_freq_seek::from(&seek);
+        assert_eq!(seek.type_, bindings::v4l2_tuner_type_V4L2_TUNER_RADIO);
+        assert_eq!(seek.seek_upward, 0);
+        assert_eq!((seek.rangelow, seek.rangehigh), (1_216_000, 1_728_000));
+    }
+}
@@ -15,11 +15,11 @@ mod ioctl {
     use crate::bindings::v4l2_dv_timings_cap;
     use crate::bindings::v4l2_enum_dv_timings;
 
-    nix::ioctl_readwrite!(vidioc_s_dv_timings, b'V', 87, v4l2_dv_timings);
-    nix::ioctl_readwrite!(vidioc_g_dv_timings, b'V', 88, v4l2_dv_timings);
-    nix::ioctl_readwrite!(vidioc_enum_dv_timings, b'V', 98, v4l2_enum_dv_timings);
-    nix::ioctl_read!(vidioc_query_dv_timings, b'V', 99, v4l2_dv_timings);
-    nix::ioctl_readwrite!(vidioc_dv_timings_cap, b'V', 100, v4l2_dv_timings_cap);
+    crate::ioctl::ioctl_readwrite!(vidioc_s_dv_timings, b'V', 87, v4l2_dv_timings);
+    crate::ioctl::ioctl_readwrite!(vidioc_g_dv_timings, b'V', 88, v4l2_dv_timings);
+    crate::ioctl::ioctl_readwrite!(vidioc_enum_dv_timings, b'V', 98, v4l2_enum_dv_timings);
+    crate::ioctl::ioctl_read!(vidioc_query_dv_timings, b'V', 99, v4l2_dv_timings);
+    crate::ioctl::ioctl_readwrite!(vidioc_dv_timings_cap, b'V', 100, v4l2_dv_timings_cap);
 }
 
 #[derive(Debug, N)]
@@ -28,7 +28,7 @@ pub enum DvTimingsType {
     Bt6561120 = bindings::V4L2_DV_BT_656_1120,
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum GDvTimingsError {
     #[error("ioctl not supported or invalid parameters")]
     Invalid,
@@ -37,7 +37,7 @@ pub enum GDvTimingsError {
     #[error("Device is busy and cannot change timings")]
     Busy,
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<GDvTimingsError> for Errno {
@@ -82,14 +82,14 @@ pub fn g_dv_timings<O: From<v4l2_dv_timings>>(fd: &impl AsRawFd) -> Result<O, GD
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum EnumDvTimingsError {
     #[error("timing index is out of bounds")]
     Invalid,
     #[error("Digital video timings are not supported on this input or output")]
     Unsupported,
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<EnumDvTimingsError> for Errno {
@@ -120,7 +120,7 @@ pub fn enum_dv_timings<O: From<v4l2_dv_timings>>(
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum QueryDvTimingsError {
     #[error("Digital video timings are not supported on this input or output")]
     Unsupported,
@@ -129,7 +129,7 @@ pub enum QueryDvTimingsError {
     #[error("Unstable signal")]
     UnstableSignal,
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<QueryDvTimingsError> for Errno {
@@ -160,10 +160,10 @@ pub fn query_dv_timings<O: From<v4l2_dv_timings>>(
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum DvTimingsCapError {
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<DvTimingsCapError> for Errno {
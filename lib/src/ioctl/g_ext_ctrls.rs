@@ -1,5 +1,7 @@
+use log::error;
 use nix::errno::Errno;
 use std::convert::TryFrom;
+use std::ops::RangeInclusive;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::RawFd;
 use thiserror::Error;
@@ -7,10 +9,17 @@ use thiserror::Error;
 use crate::bindings;
 use crate::bindings::v4l2_control;
 use crate::bindings::v4l2_ctrl_fwht_params;
+use crate::bindings::v4l2_ext_control;
 use crate::bindings::v4l2_ext_controls;
 use crate::bindings::v4l2_querymenu;
 use crate::controls::codec::FwhtFlags;
 use crate::controls::AsV4l2ControlSlice;
+use crate::ioctl::query_ext_ctrl;
+use crate::ioctl::string_from_cstr;
+use crate::ioctl::CtrlId;
+use crate::ioctl::CtrlIdError;
+use crate::ioctl::QueryCtrlError;
+use crate::ioctl::QueryCtrlFlags;
 use crate::Colorspace;
 use crate::Quantization;
 use crate::XferFunc;
@@ -72,6 +81,12 @@ pub enum FwhtParamsCtrlError {
     InvalidQuantization(u32),
 }
 
+impl crate::ioctl::OsError for FwhtParamsCtrlError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
 /// Make sure a FwhtParams control contains valid values.
 impl TryFrom<v4l2_ctrl_fwht_params> for ValidControl<v4l2_ctrl_fwht_params> {
     type Error = FwhtParamsCtrlError;
@@ -120,20 +135,20 @@ mod ioctl {
     use crate::bindings::v4l2_control;
     use crate::bindings::v4l2_ext_controls;
     use crate::bindings::v4l2_querymenu;
-    nix::ioctl_readwrite!(vidioc_g_ctrl, b'V', 27, v4l2_control);
-    nix::ioctl_readwrite!(vidioc_s_ctrl, b'V', 28, v4l2_control);
-    nix::ioctl_readwrite!(vidioc_g_ext_ctrls, b'V', 71, v4l2_ext_controls);
-    nix::ioctl_readwrite!(vidioc_s_ext_ctrls, b'V', 72, v4l2_ext_controls);
-    nix::ioctl_readwrite!(vidioc_try_ext_ctrls, b'V', 73, v4l2_ext_controls);
-    nix::ioctl_readwrite!(vidioc_querymenu, b'V', 37, v4l2_querymenu);
+    crate::ioctl::ioctl_readwrite!(vidioc_g_ctrl, b'V', 27, v4l2_control);
+    crate::ioctl::ioctl_readwrite!(vidioc_s_ctrl, b'V', 28, v4l2_control);
+    crate::ioctl::ioctl_readwrite!(vidioc_g_ext_ctrls, b'V', 71, v4l2_ext_controls);
+    crate::ioctl::ioctl_readwrite!(vidioc_s_ext_ctrls, b'V', 72, v4l2_ext_controls);
+    crate::ioctl::ioctl_readwrite!(vidioc_try_ext_ctrls, b'V', 73, v4l2_ext_controls);
+    crate::ioctl::ioctl_readwrite!(vidioc_querymenu, b'V', 37, v4l2_querymenu);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum GCtrlError {
     #[error("Invalid control or value")]
     Invalid,
     #[error("Unexpected ioctl error: {0}")]
-    IoctlError(nix::Error),
+    IoctlError(#[source] nix::Error),
 }
 
 impl From<GCtrlError> for Errno {
@@ -170,10 +185,10 @@ pub fn s_ctrl(fd: &impl AsRawFd, id: u32, value: i32) -> Result<i32, GCtrlError>
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum ExtControlErrorType {
     #[error("Unexpected ioctl error: {0}")]
-    IoctlError(nix::Error),
+    IoctlError(#[source] nix::Error),
 }
 
 impl From<ExtControlErrorType> for Errno {
@@ -184,9 +199,10 @@ impl From<ExtControlErrorType> for Errno {
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub struct ExtControlError {
     pub error_idx: u32,
+    #[source]
     pub error: ExtControlErrorType,
 }
 
@@ -205,6 +221,7 @@ impl From<ExtControlError> for Errno {
 /// Encapsulates the `ctrl_class` and `which` enum of `v4l2_ext_controls`.
 ///
 /// Note that `Default` is an invalid value for `S_EXT_CTRLS` and `TRY_EXT_CTRLS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CtrlWhich {
     Current,
     Default,
@@ -229,6 +246,22 @@ impl CtrlWhich {
             CtrlWhich::Class(class) => v4l2_class_or_which { ctrl_class: *class },
         }
     }
+
+    /// Builds the `v4l2_ext_controls` passed to the `g/s/try_ext_ctrls` ioctls to access
+    /// `controls`, which must outlive any use of the returned value.
+    pub fn ext_controls(&self, controls: &mut [v4l2_ext_control]) -> v4l2_ext_controls {
+        v4l2_ext_controls {
+            __bindgen_anon_1: self.binding_value(),
+            count: controls.len() as u32,
+            request_fd: if let CtrlWhich::Request(fd) = self {
+                *fd
+            } else {
+                0
+            },
+            controls: controls.as_mut_ptr(),
+            ..Default::default()
+        }
+    }
 }
 
 impl TryFrom<&v4l2_ext_controls> for CtrlWhich {
@@ -267,18 +300,7 @@ pub fn g_ext_ctrls<I: AsV4l2ControlSlice>(
     which: CtrlWhich,
     mut controls: I,
 ) -> Result<(), ExtControlError> {
-    let controls_slice = controls.as_v4l2_control_slice();
-    let mut v4l2_controls = v4l2_ext_controls {
-        __bindgen_anon_1: which.binding_value(),
-        count: controls_slice.len() as u32,
-        request_fd: if let CtrlWhich::Request(fd) = which {
-            fd
-        } else {
-            0
-        },
-        controls: controls_slice.as_mut_ptr(),
-        ..Default::default()
-    };
+    let mut v4l2_controls = which.ext_controls(controls.as_v4l2_control_slice());
 
     // SAFETY: the 'controls' argument is properly set up above
     match unsafe { ioctl::vidioc_g_ext_ctrls(fd.as_raw_fd(), &mut v4l2_controls) } {
@@ -296,18 +318,7 @@ pub fn s_ext_ctrls<I: AsV4l2ControlSlice>(
     which: CtrlWhich,
     mut controls: I,
 ) -> Result<(), ExtControlError> {
-    let controls_slice = controls.as_v4l2_control_slice();
-    let mut v4l2_controls = v4l2_ext_controls {
-        __bindgen_anon_1: which.binding_value(),
-        count: controls_slice.len() as u32,
-        request_fd: if let CtrlWhich::Request(fd) = which {
-            fd
-        } else {
-            0
-        },
-        controls: controls_slice.as_mut_ptr(),
-        ..Default::default()
-    };
+    let mut v4l2_controls = which.ext_controls(controls.as_v4l2_control_slice());
 
     // SAFETY: the 'controls' argument is properly set up above
     match unsafe { ioctl::vidioc_s_ext_ctrls(fd.as_raw_fd(), &mut v4l2_controls) } {
@@ -325,18 +336,7 @@ pub fn try_ext_ctrls<I: AsV4l2ControlSlice>(
     which: CtrlWhich,
     mut controls: I,
 ) -> Result<(), ExtControlError> {
-    let controls_slice = controls.as_v4l2_control_slice();
-    let mut v4l2_controls = v4l2_ext_controls {
-        __bindgen_anon_1: which.binding_value(),
-        count: controls_slice.len() as u32,
-        request_fd: if let CtrlWhich::Request(fd) = which {
-            fd
-        } else {
-            0
-        },
-        controls: controls_slice.as_mut_ptr(),
-        ..Default::default()
-    };
+    let mut v4l2_controls = which.ext_controls(controls.as_v4l2_control_slice());
 
     // SAFETY: the 'controls' argument is properly set up above
     match unsafe { ioctl::vidioc_try_ext_ctrls(fd.as_raw_fd(), &mut v4l2_controls) } {
@@ -348,12 +348,12 @@ pub fn try_ext_ctrls<I: AsV4l2ControlSlice>(
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum QueryMenuError {
     #[error("invalid id or index value")]
     InvalidIdOrIndex,
     #[error("ioctl error: {0}")]
-    IoctlError(nix::Error),
+    IoctlError(#[source] nix::Error),
 }
 
 impl From<QueryMenuError> for Errno {
@@ -383,3 +383,215 @@ pub fn querymenu<O: From<v4l2_querymenu>>(
         Err(e) => Err(QueryMenuError::IoctlError(e)),
     }
 }
+
+/// Value of a menu item, as returned by `VIDIOC_QUERYMENU`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MenuItemValue {
+    /// Name of the item, for `V4L2_CTRL_TYPE_MENU` controls.
+    Name(String),
+    /// Value of the item, for `V4L2_CTRL_TYPE_INTEGER_MENU` controls.
+    Value(i64),
+}
+
+/// A valid item of a menu control.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MenuItem {
+    /// Index of the item, i.e. the value to set the control to in order to
+    /// select it.
+    pub index: u32,
+    pub value: MenuItemValue,
+}
+
+impl MenuItem {
+    fn from_querymenu(querymenu: v4l2_querymenu, integer_menu: bool) -> Self {
+        // SAFETY: the kind of control tells us which member of the union is valid.
+        let value = if integer_menu {
+            MenuItemValue::Value(unsafe { querymenu.__bindgen_anon_1.value })
+        } else {
+            MenuItemValue::Name(
+                string_from_cstr(unsafe { &querymenu.__bindgen_anon_1.name })
+                    .unwrap_or_else(|_| "".into()),
+            )
+        };
+
+        MenuItem {
+            index: querymenu.index,
+            value,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MenuItemIteratorError {
+    #[error("invalid control ID: {0}")]
+    InvalidControl(#[from] CtrlIdError),
+    #[error("error while querying control: {0}")]
+    QueryCtrlError(#[from] QueryCtrlError),
+    #[error("control 0x{0:08x} is not a menu control")]
+    NotAMenu(u32),
+}
+
+impl crate::ioctl::OsError for MenuItemIteratorError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            MenuItemIteratorError::QueryCtrlError(e) => e.errno(),
+            MenuItemIteratorError::InvalidControl(_) | MenuItemIteratorError::NotAMenu(_) => None,
+        }
+    }
+}
+
+/// Iterator over the valid items of a menu or integer menu control.
+///
+/// Drivers may leave holes in the range of menu indices, e.g. for H.264
+/// profiles that are not supported. These are skipped, so only the items that
+/// can actually be selected are returned.
+pub struct MenuItemIterator<'a, F: AsRawFd> {
+    fd: &'a F,
+    id: u32,
+    integer_menu: bool,
+    /// Indices left to query. A range is used so the iteration stops at the
+    /// maximum index without overflowing if it is `u32::MAX`.
+    indices: RangeInclusive<u32>,
+}
+
+impl<'a, F: AsRawFd> MenuItemIterator<'a, F> {
+    /// Create a new iterator listing all the valid items of menu control `id`.
+    pub fn new(fd: &'a F, id: u32) -> Result<Self, MenuItemIteratorError> {
+        let query: bindings::v4l2_query_ext_ctrl =
+            query_ext_ctrl(fd, CtrlId::new(id)?, QueryCtrlFlags::empty())?;
+
+        let integer_menu = match query.type_ {
+            bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_MENU => false,
+            bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER_MENU => true,
+            _ => return Err(MenuItemIteratorError::NotAMenu(id)),
+        };
+
+        Ok(MenuItemIterator {
+            fd,
+            id,
+            integer_menu,
+            indices: query.minimum as u32..=query.maximum as u32,
+        })
+    }
+}
+
+impl<'a, F: AsRawFd> Iterator for MenuItemIterator<'a, F> {
+    type Item = MenuItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for index in self.indices.by_ref() {
+            match querymenu::<v4l2_querymenu>(self.fd, self.id, index) {
+                Ok(querymenu) => {
+                    return Some(MenuItem::from_querymenu(querymenu, self.integer_menu))
+                }
+                // EINVAL means this index is a hole in the menu.
+                Err(QueryMenuError::InvalidIdOrIndex) => continue,
+                Err(e) => {
+                    error!("Unexpected return value for VIDIOC_QUERYMENU: {}", e);
+                    return None;
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ext_controls() {
+        let mut controls = [v4l2_ext_control::default(); 2];
+        for which in [
+            CtrlWhich::Current,
+            CtrlWhich::Default,
+            CtrlWhich::Request(42),
+            CtrlWhich::Class(bindings::V4L2_CTRL_CLASS_CODEC),
+        ] {
+            let ext_controls = which.ext_controls(&mut controls);
+            assert_eq!(ext_controls.count, 2);
+            assert_eq!(ext_controls.controls, controls.as_mut_ptr());
+            assert_eq!(CtrlWhich::try_from(&ext_controls), Ok(which));
+        }
+        assert_eq!(CtrlWhich::Current.ext_controls(&mut controls).request_fd, 0);
+    }
+
+    #[test]
+    fn menu_item_from_querymenu() {
+        let mut name = [0u8; 32];
+        name[..4].copy_from_slice(b"High");
+        let querymenu = v4l2_querymenu {
+            id: bindings::V4L2_CID_MPEG_VIDEO_H264_PROFILE,
+            index: 4,
+            __bindgen_anon_1: bindings::v4l2_querymenu__bindgen_ty_1 { name },
+            ..Default::default()
+        };
+        assert_eq!(
+            MenuItem::from_querymenu(querymenu, false),
+            MenuItem {
+                index: 4,
+                value: MenuItemValue::Name("High".into()),
+            }
+        );
+
+        let querymenu = v4l2_querymenu {
+            index: 2,
+            __bindgen_anon_1: bindings::v4l2_querymenu__bindgen_ty_1 { value: 1 << 40 },
+            ..Default::default()
+        };
+        assert_eq!(
+            MenuItem::from_querymenu(querymenu, true),
+            MenuItem {
+                index: 2,
+                value: MenuItemValue::Value(1 << 40),
+            }
+        );
+    }
+
+    #[cfg(feature = "ioctl-trace")]
+    #[test]
+    fn menu_item_iterator_last_index() {
+        use std::fs::File;
+
+        use crate::ioctl::trace::testing::*;
+        use crate::ioctl::trace::{replay, Trace};
+
+        let query = bindings::v4l2_query_ext_ctrl {
+            id: bindings::V4L2_CID_MPEG_VIDEO_H264_PROFILE,
+            type_: bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER_MENU,
+            minimum: u32::MAX as i64 - 1,
+            maximum: u32::MAX as i64,
+            ..Default::default()
+        };
+        let item = v4l2_querymenu {
+            index: u32::MAX,
+            __bindgen_anon_1: bindings::v4l2_querymenu__bindgen_ty_1 { value: 42 },
+            ..Default::default()
+        };
+        let trace = Trace {
+            entries: vec![
+                arg_entry(QUERY_EXT_CTRL, &query),
+                entry(QUERYMENU, Err(Errno::EINVAL)),
+                arg_entry(QUERYMENU, &item),
+            ],
+        };
+
+        let null = File::open("/dev/null").unwrap();
+        // The iteration ends after the maximum index instead of wrapping around.
+        let items = replay(&trace, || {
+            MenuItemIterator::new(&null, bindings::V4L2_CID_MPEG_VIDEO_H264_PROFILE)
+                .unwrap()
+                .collect::<Vec<_>>()
+        })
+        .unwrap();
+        assert_eq!(
+            items,
+            vec![MenuItem {
+                index: u32::MAX,
+                value: MenuItemValue::Value(42),
+            }]
+        );
+    }
+}
@@ -7,6 +7,7 @@ use thiserror::Error;
 
 use crate::bindings;
 use crate::bindings::v4l2_format;
+use crate::Colorimetry;
 use crate::Format;
 use crate::FormatConversionError;
 use crate::PlaneLayout;
@@ -75,6 +76,52 @@ impl TryFrom<(QueueType, &Format)> for v4l2_format {
     }
 }
 
+/// Returns the colorimetry of `fmt`, or `None` if it is not the format of a video queue.
+pub(crate) fn format_colorimetry(fmt: &v4l2_format) -> Option<Colorimetry> {
+    match QueueType::n(fmt.type_)? {
+        QueueType::VideoCapture | QueueType::VideoOutput => {
+            let pix = unsafe { &fmt.fmt.pix };
+            Some(Colorimetry::from_raw(
+                pix.colorspace,
+                unsafe { pix.__bindgen_anon_1.ycbcr_enc },
+                pix.quantization,
+                pix.xfer_func,
+            ))
+        }
+        QueueType::VideoCaptureMplane | QueueType::VideoOutputMplane => {
+            let pix_mp = unsafe { &fmt.fmt.pix_mp };
+            Some(Colorimetry::from_raw(
+                pix_mp.colorspace,
+                unsafe { pix_mp.__bindgen_anon_1.ycbcr_enc } as u32,
+                pix_mp.quantization as u32,
+                pix_mp.xfer_func as u32,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Sets the colorimetry of `fmt`, if it is the format of a video queue.
+pub(crate) fn set_format_colorimetry(fmt: &mut v4l2_format, colorimetry: Colorimetry) {
+    match QueueType::n(fmt.type_) {
+        Some(QueueType::VideoCapture) | Some(QueueType::VideoOutput) => {
+            let pix = unsafe { &mut fmt.fmt.pix };
+            pix.colorspace = colorimetry.colorspace.into();
+            pix.__bindgen_anon_1.ycbcr_enc = colorimetry.ycbcr_enc.into();
+            pix.quantization = colorimetry.quantization.into();
+            pix.xfer_func = colorimetry.xfer_func.into();
+        }
+        Some(QueueType::VideoCaptureMplane) | Some(QueueType::VideoOutputMplane) => {
+            let pix_mp = unsafe { &mut fmt.fmt.pix_mp };
+            pix_mp.colorspace = colorimetry.colorspace.into();
+            pix_mp.__bindgen_anon_1.ycbcr_enc = u32::from(colorimetry.ycbcr_enc) as u8;
+            pix_mp.quantization = u32::from(colorimetry.quantization) as u8;
+            pix_mp.xfer_func = u32::from(colorimetry.xfer_func) as u8;
+        }
+        _ => (),
+    }
+}
+
 impl From<&PlaneLayout> for bindings::v4l2_plane_pix_format {
     fn from(plane: &PlaneLayout) -> Self {
         bindings::v4l2_plane_pix_format {
@@ -88,19 +135,19 @@ impl From<&PlaneLayout> for bindings::v4l2_plane_pix_format {
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_format;
-    nix::ioctl_readwrite!(vidioc_g_fmt, b'V', 4, v4l2_format);
-    nix::ioctl_readwrite!(vidioc_s_fmt, b'V', 5, v4l2_format);
-    nix::ioctl_readwrite!(vidioc_try_fmt, b'V', 64, v4l2_format);
+    crate::ioctl::ioctl_readwrite!(vidioc_g_fmt, b'V', 4, v4l2_format);
+    crate::ioctl::ioctl_readwrite!(vidioc_s_fmt, b'V', 5, v4l2_format);
+    crate::ioctl::ioctl_readwrite!(vidioc_try_fmt, b'V', 64, v4l2_format);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum GFmtError {
     #[error("error while converting from V4L2 format")]
     FromV4L2FormatConversionError,
     #[error("invalid buffer type requested")]
     InvalidBufferType,
     #[error("unexpected ioctl error: {0}")]
-    IoctlError(nix::Error),
+    IoctlError(#[source] nix::Error),
 }
 
 impl From<GFmtError> for Errno {
@@ -129,7 +176,7 @@ pub fn g_fmt<O: TryFrom<v4l2_format>>(fd: &impl AsRawFd, queue: QueueType) -> Re
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum SFmtError {
     #[error("error while converting from V4L2 format")]
     FromV4L2FormatConversionError,
@@ -140,7 +187,7 @@ pub enum SFmtError {
     #[error("device currently busy")]
     DeviceBusy,
     #[error("ioctl error: {0}")]
-    IoctlError(nix::Error),
+    IoctlError(#[source] nix::Error),
 }
 
 impl From<SFmtError> for Errno {
@@ -174,7 +221,7 @@ pub fn s_fmt<I: TryInto<v4l2_format>, O: TryFrom<v4l2_format>>(
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum TryFmtError {
     #[error("error while converting from V4L2 format")]
     FromV4L2FormatConversionError,
@@ -183,7 +230,7 @@ pub enum TryFmtError {
     #[error("invalid buffer type requested")]
     InvalidBufferType,
     #[error("ioctl error: {0}")]
-    IoctlError(nix::Error),
+    IoctlError(#[source] nix::Error),
 }
 
 impl From<TryFmtError> for Errno {
@@ -250,6 +297,33 @@ mod test {
         assert_eq!(mplane, mplane2);
     }
 
+    #[test]
+    // Set and read back the colorimetry of single and multi-planar formats.
+    fn v4l2_format_colorimetry() {
+        let colorimetry = Colorimetry {
+            colorspace: crate::Colorspace::Rec709,
+            ycbcr_enc: crate::YCbCrEncoding::E709,
+            quantization: crate::Quantization::LimRange,
+            xfer_func: crate::XferFunc::F709,
+        };
+        let format = Format::from((b"NV12", (640, 480)));
+        for queue in [QueueType::VideoOutput, QueueType::VideoOutputMplane] {
+            let mut v4l2_format: v4l2_format = (queue, &format).try_into().unwrap();
+            assert_eq!(
+                format_colorimetry(&v4l2_format),
+                Some(Colorimetry::default())
+            );
+            set_format_colorimetry(&mut v4l2_format, colorimetry);
+            assert_eq!(format_colorimetry(&v4l2_format), Some(colorimetry));
+        }
+
+        let vbi = v4l2_format {
+            type_: QueueType::VbiCapture as u32,
+            ..Default::default()
+        };
+        assert_eq!(format_colorimetry(&vbi), None);
+    }
+
     #[test]
     // Convert from Format to single-planar v4l2_format and back.
     fn splane_to_v4l2_format() {
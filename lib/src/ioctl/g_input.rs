@@ -14,21 +14,21 @@ mod ioctl {
     use crate::bindings::v4l2_input;
     use crate::bindings::v4l2_output;
 
-    nix::ioctl_readwrite!(vidioc_enuminput, b'V', 26, v4l2_input);
-    nix::ioctl_read!(vidioc_g_input, b'V', 38, c_int);
-    nix::ioctl_readwrite!(vidioc_s_input, b'V', 39, c_int);
+    crate::ioctl::ioctl_readwrite!(vidioc_enuminput, b'V', 26, v4l2_input);
+    crate::ioctl::ioctl_read!(vidioc_g_input, b'V', 38, c_int);
+    crate::ioctl::ioctl_readwrite!(vidioc_s_input, b'V', 39, c_int);
 
-    nix::ioctl_read!(vidioc_g_output, b'V', 46, c_int);
-    nix::ioctl_readwrite!(vidioc_s_output, b'V', 47, c_int);
-    nix::ioctl_readwrite!(vidioc_enumoutput, b'V', 48, v4l2_output);
+    crate::ioctl::ioctl_read!(vidioc_g_output, b'V', 46, c_int);
+    crate::ioctl::ioctl_readwrite!(vidioc_s_output, b'V', 47, c_int);
+    crate::ioctl::ioctl_readwrite!(vidioc_enumoutput, b'V', 48, v4l2_output);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum SelectionError {
     #[error("selection {0} is out of range")]
     OutOfRange(usize),
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<SelectionError> for Errno {
@@ -1,22 +1,102 @@
 use std::os::unix::io::AsRawFd;
 
+use bitflags::bitflags;
 use nix::errno::Errno;
 use thiserror::Error;
 
+use crate::bindings;
 use crate::bindings::v4l2_jpegcompression;
 
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_jpegcompression;
 
-    nix::ioctl_read!(vidioc_g_jpegcomp, b'V', 61, v4l2_jpegcompression);
-    nix::ioctl_write_ptr!(vidioc_s_jpegcomp, b'V', 62, v4l2_jpegcompression);
+    crate::ioctl::ioctl_read!(vidioc_g_jpegcomp, b'V', 61, v4l2_jpegcompression);
+    crate::ioctl::ioctl_write_ptr!(vidioc_s_jpegcomp, b'V', 62, v4l2_jpegcompression);
 }
 
-#[derive(Debug, Error)]
+bitflags! {
+    /// Markers to include in the JPEG stream produced by the driver.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct JpegMarkers: u32 {
+        const DHT = bindings::V4L2_JPEG_MARKER_DHT;
+        const DQT = bindings::V4L2_JPEG_MARKER_DQT;
+        const DRI = bindings::V4L2_JPEG_MARKER_DRI;
+        const COM = bindings::V4L2_JPEG_MARKER_COM;
+        const APP = bindings::V4L2_JPEG_MARKER_APP;
+    }
+}
+
+/// Maximum length of the APP and COM segment data of `JpegCompression`.
+pub const JPEG_SEGMENT_DATA_MAX_LEN: usize = 60;
+
+/// Safe variant of `struct v4l2_jpegcompression`.
+///
+/// This interface is deprecated by the kernel in favor of the `V4L2_CID_JPEG_*` controls (see
+/// `controls::jpeg`), but older MJPEG webcams only support it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JpegCompression {
+    pub quality: i32,
+    /// Index `n` of the APPn segment to write `app_data` into.
+    pub app_n: i32,
+    /// Data of the APPn segment. Only the first `JPEG_SEGMENT_DATA_MAX_LEN` bytes are passed to
+    /// the driver.
+    pub app_data: Vec<u8>,
+    /// Data of the COM segment. Only the first `JPEG_SEGMENT_DATA_MAX_LEN` bytes are passed to
+    /// the driver.
+    pub com_data: Vec<u8>,
+    pub jpeg_markers: JpegMarkers,
+}
+
+fn segment_data(data: &[std::os::raw::c_char], len: std::os::raw::c_int) -> Vec<u8> {
+    let len = (len.max(0) as usize).min(data.len());
+    data[..len].iter().map(|c| *c as u8).collect()
+}
+
+impl From<v4l2_jpegcompression> for JpegCompression {
+    fn from(jpegcomp: v4l2_jpegcompression) -> Self {
+        JpegCompression {
+            quality: jpegcomp.quality,
+            app_n: jpegcomp.APPn,
+            app_data: segment_data(&jpegcomp.APP_data, jpegcomp.APP_len),
+            com_data: segment_data(&jpegcomp.COM_data, jpegcomp.COM_len),
+            jpeg_markers: JpegMarkers::from_bits_truncate(jpegcomp.jpeg_markers),
+        }
+    }
+}
+
+impl From<&JpegCompression> for v4l2_jpegcompression {
+    fn from(jpegcomp: &JpegCompression) -> Self {
+        let mut raw = v4l2_jpegcompression {
+            quality: jpegcomp.quality,
+            APPn: jpegcomp.app_n,
+            jpeg_markers: jpegcomp.jpeg_markers.bits(),
+            ..Default::default()
+        };
+
+        for (dst, src) in raw.APP_data.iter_mut().zip(jpegcomp.app_data.iter()) {
+            *dst = *src as std::os::raw::c_char;
+        }
+        raw.APP_len = jpegcomp.app_data.len().min(JPEG_SEGMENT_DATA_MAX_LEN) as i32;
+        for (dst, src) in raw.COM_data.iter_mut().zip(jpegcomp.com_data.iter()) {
+            *dst = *src as std::os::raw::c_char;
+        }
+        raw.COM_len = jpegcomp.com_data.len().min(JPEG_SEGMENT_DATA_MAX_LEN) as i32;
+
+        raw
+    }
+}
+
+impl From<JpegCompression> for v4l2_jpegcompression {
+    fn from(jpegcomp: JpegCompression) -> Self {
+        v4l2_jpegcompression::from(&jpegcomp)
+    }
+}
+
+#[derive(Debug, Clone, Error)]
 pub enum GJpegCompError {
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<GJpegCompError> for Errno {
@@ -49,3 +129,28 @@ pub fn s_jpegcomp<I: Into<v4l2_jpegcompression>>(
         Err(e) => Err(GJpegCompError::IoctlError(e)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jpegcomp_round_trip() {
+        let jpegcomp = JpegCompression {
+            quality: 75,
+            app_n: 1,
+            app_data: b"v4l2r".to_vec(),
+            com_data: vec![0x55; 80],
+            jpeg_markers: JpegMarkers::DHT | JpegMarkers::DQT,
+        };
+
+        let raw = v4l2_jpegcompression::from(&jpegcomp);
+        assert_eq!(raw.APP_len, 5);
+        assert_eq!(raw.COM_len, JPEG_SEGMENT_DATA_MAX_LEN as i32);
+
+        let converted = JpegCompression::from(raw);
+        assert_eq!(converted.app_data, jpegcomp.app_data);
+        assert_eq!(converted.com_data, vec![0x55; JPEG_SEGMENT_DATA_MAX_LEN]);
+        assert_eq!(converted.jpeg_markers, jpegcomp.jpeg_markers);
+    }
+}
@@ -14,18 +14,18 @@ mod ioctl {
     use crate::bindings::v4l2_std_id;
     use crate::bindings::v4l2_streamparm;
 
-    nix::ioctl_readwrite!(vidioc_g_parm, b'V', 21, v4l2_streamparm);
-    nix::ioctl_readwrite!(vidioc_s_parm, b'V', 22, v4l2_streamparm);
-    nix::ioctl_read!(vidioc_g_std, b'V', 23, v4l2_std_id);
-    nix::ioctl_write_ptr!(vidioc_s_std, b'V', 24, v4l2_std_id);
-    nix::ioctl_readwrite!(vidioc_enumstd, b'V', 25, v4l2_standard);
-    nix::ioctl_read!(vidioc_querystd, b'V', 63, v4l2_std_id);
+    crate::ioctl::ioctl_readwrite!(vidioc_g_parm, b'V', 21, v4l2_streamparm);
+    crate::ioctl::ioctl_readwrite!(vidioc_s_parm, b'V', 22, v4l2_streamparm);
+    crate::ioctl::ioctl_read!(vidioc_g_std, b'V', 23, v4l2_std_id);
+    crate::ioctl::ioctl_write_ptr!(vidioc_s_std, b'V', 24, v4l2_std_id);
+    crate::ioctl::ioctl_readwrite!(vidioc_enumstd, b'V', 25, v4l2_standard);
+    crate::ioctl::ioctl_read!(vidioc_querystd, b'V', 63, v4l2_std_id);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum GParmError {
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<GParmError> for Errno {
@@ -75,12 +75,12 @@ pub fn g_std<O: From<v4l2_std_id>>(fd: &impl AsRawFd) -> Result<O, GParmError> {
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum SStdError {
     #[error("unsupported standard requested")]
     Unsupported,
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<SStdError> for Errno {
@@ -103,14 +103,14 @@ pub fn s_std<I: Into<v4l2_std_id>>(fd: &impl AsRawFd, std_id: I) -> Result<(), S
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum EnumStdError {
     #[error("requested index is out of bounds")]
     OutOfBounds,
     #[error("standard video timings are not supported for this input or output")]
     Unsupported,
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<EnumStdError> for Errno {
@@ -41,16 +41,16 @@ bitflags! {
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_selection;
-    nix::ioctl_readwrite!(vidioc_g_selection, b'V', 94, v4l2_selection);
-    nix::ioctl_readwrite!(vidioc_s_selection, b'V', 95, v4l2_selection);
+    crate::ioctl::ioctl_readwrite!(vidioc_g_selection, b'V', 94, v4l2_selection);
+    crate::ioctl::ioctl_readwrite!(vidioc_s_selection, b'V', 95, v4l2_selection);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum GSelectionError {
     #[error("invalid type or target requested")]
     Invalid,
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<GSelectionError> for Errno {
@@ -81,7 +81,7 @@ pub fn g_selection<R: From<v4l2_rect>>(
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum SSelectionError {
     #[error("invalid type or target requested")]
     Invalid,
@@ -90,7 +90,7 @@ pub enum SSelectionError {
     #[error("cannot change selection rectangle currently")]
     Busy,
     #[error("ioctl error: {0}")]
-    IoctlError(nix::Error),
+    IoctlError(#[source] nix::Error),
 }
 
 impl From<SSelectionError> for Errno {
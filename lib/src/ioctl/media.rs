@@ -0,0 +1,174 @@
+//! Safe wrappers for the media controller `MEDIA_IOC_DEVICE_INFO` and
+//! `MEDIA_IOC_ENUM_ENTITIES` ioctls.
+//!
+//! `linux/media.h` is not part of the generated bindings, so the structures
+//! passed to these ioctls are defined here, following the layout of the kernel
+//! UAPI.
+use std::os::unix::io::AsRawFd;
+
+use nix::errno::Errno;
+
+use super::string_from_cstr;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct media_device_info {
+    driver: [u8; 16],
+    model: [u8; 32],
+    serial: [u8; 40],
+    bus_info: [u8; 32],
+    media_version: u32,
+    hw_revision: u32,
+    driver_version: u32,
+    reserved: [u32; 31],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct media_entity_desc {
+    id: u32,
+    name: [u8; 32],
+    type_: u32,
+    revision: u32,
+    flags: u32,
+    group_id: u32,
+    pads: u16,
+    links: u16,
+    reserved: [u32; 4],
+    // Start of the `dev` member of the union, which is 184 bytes large.
+    dev_major: u32,
+    dev_minor: u32,
+    raw: [u32; 44],
+}
+
+const MEDIA_ENT_ID_FLAG_NEXT: u32 = 1 << 31;
+
+/// Function of the entities representing a V4L2 video device node.
+pub const MEDIA_ENT_F_IO_V4L: u32 = 0x0001_0001;
+
+#[doc(hidden)]
+mod ioctl {
+    use super::{media_device_info, media_entity_desc};
+    crate::ioctl::ioctl_readwrite!(media_ioc_device_info, b'|', 0x00, media_device_info);
+    crate::ioctl::ioctl_readwrite!(media_ioc_enum_entities, b'|', 0x01, media_entity_desc);
+}
+
+/// Information about a media controller device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaDeviceInfo {
+    pub driver: String,
+    pub model: String,
+    pub serial: String,
+    pub bus_info: String,
+    pub media_version: u32,
+    pub hw_revision: u32,
+    pub driver_version: u32,
+}
+
+impl From<&media_device_info> for MediaDeviceInfo {
+    fn from(info: &media_device_info) -> Self {
+        MediaDeviceInfo {
+            driver: string_from_cstr(&info.driver).unwrap_or_default(),
+            model: string_from_cstr(&info.model).unwrap_or_default(),
+            serial: string_from_cstr(&info.serial).unwrap_or_default(),
+            bus_info: string_from_cstr(&info.bus_info).unwrap_or_default(),
+            media_version: info.media_version,
+            hw_revision: info.hw_revision,
+            driver_version: info.driver_version,
+        }
+    }
+}
+
+/// An entity of a media controller device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaEntity {
+    pub id: u32,
+    pub name: String,
+    /// Function of the entity, e.g. `MEDIA_ENT_F_IO_V4L`.
+    pub function: u32,
+    pub flags: u32,
+    pub pads: u16,
+    pub links: u16,
+    /// Major and minor numbers of the device node of the entity, if it has
+    /// one.
+    pub dev: Option<(u32, u32)>,
+}
+
+impl From<&media_entity_desc> for MediaEntity {
+    fn from(desc: &media_entity_desc) -> Self {
+        MediaEntity {
+            id: desc.id,
+            name: string_from_cstr(&desc.name).unwrap_or_default(),
+            function: desc.type_,
+            flags: desc.flags,
+            pads: desc.pads,
+            links: desc.links,
+            dev: match (desc.dev_major, desc.dev_minor) {
+                (0, 0) => None,
+                dev => Some(dev),
+            },
+        }
+    }
+}
+
+/// Safe wrapper around the `MEDIA_IOC_DEVICE_INFO` ioctl.
+pub fn media_device_info(fd: &impl AsRawFd) -> Result<MediaDeviceInfo, Errno> {
+    // SAFETY: the structure only contains integers, for which zero is valid.
+    let mut info: media_device_info = unsafe { std::mem::zeroed() };
+
+    unsafe { ioctl::media_ioc_device_info(fd.as_raw_fd(), &mut info) }?;
+
+    Ok((&info).into())
+}
+
+/// Safe wrapper around the `MEDIA_IOC_ENUM_ENTITIES` ioctl.
+///
+/// Returns all the entities of the media device, in id order.
+pub fn media_enum_entities(fd: &impl AsRawFd) -> Result<Vec<MediaEntity>, Errno> {
+    let mut entities = Vec::new();
+    let mut id = 0;
+
+    loop {
+        // SAFETY: the structure only contains integers, for which zero is valid.
+        let mut desc: media_entity_desc = unsafe { std::mem::zeroed() };
+        desc.id = id | MEDIA_ENT_ID_FLAG_NEXT;
+
+        match unsafe { ioctl::media_ioc_enum_entities(fd.as_raw_fd(), &mut desc) } {
+            Ok(_) => {
+                id = desc.id;
+                entities.push((&desc).into());
+            }
+            // No entity after `id`.
+            Err(Errno::EINVAL) => return Ok(entities),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_struct_layouts() {
+        // Ioctl numbers depend on the size of the structures, which must match
+        // the kernel's.
+        assert_eq!(std::mem::size_of::<media_device_info>(), 256);
+        assert_eq!(std::mem::size_of::<media_entity_desc>(), 256);
+    }
+
+    #[test]
+    fn test_entity_conversion() {
+        // SAFETY: the structure only contains integers, for which zero is valid.
+        let mut desc: media_entity_desc = unsafe { std::mem::zeroed() };
+        desc.id = 3;
+        desc.name[..5].copy_from_slice(b"video");
+        desc.type_ = MEDIA_ENT_F_IO_V4L;
+        assert_eq!(MediaEntity::from(&desc).name, "video");
+        assert_eq!(MediaEntity::from(&desc).dev, None);
+
+        desc.dev_major = 81;
+        desc.dev_minor = 2;
+        assert_eq!(MediaEntity::from(&desc).dev, Some((81, 2)));
+    }
+}
@@ -1,7 +1,7 @@
 use core::num::NonZeroUsize;
 use std::{
     cmp::{max, min},
-    ops::Deref,
+    ops::{Deref, Range},
     slice,
 };
 use std::{ops::DerefMut, os::unix::io::AsFd};
@@ -34,6 +34,66 @@ impl PlaneMapping {
 
         self
     }
+
+    /// Copy the image in `src`, whose lines are `src_stride` bytes apart, into this mapping
+    /// using a stride of `dst_stride` bytes, typically the `bytesperline` of the plane.
+    ///
+    /// This is needed whenever the driver pads its lines, as copying a tightly-packed image
+    /// in one go would then skew it. Returns the number of bytes covered in the mapping, to be
+    /// used as the `bytes_used` of the plane.
+    pub fn copy_from_with_stride(
+        &mut self,
+        src: &[u8],
+        src_stride: usize,
+        dst_stride: usize,
+    ) -> Result<usize, StrideCopyError> {
+        copy_with_stride(self.as_mut(), dst_stride, src, src_stride)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum StrideCopyError {
+    #[error("stride cannot be zero")]
+    ZeroStride,
+    #[error("destination is too small: {needed} bytes needed, {available} available")]
+    DestinationTooSmall { needed: usize, available: usize },
+}
+
+impl crate::ioctl::OsError for StrideCopyError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
+/// Copy the lines of `src`, `src_stride` bytes apart, into `dst` with a stride of `dst_stride`
+/// bytes. Only the first `min(src_stride, dst_stride)` bytes of each line are copied, and the
+/// padding of `dst` is left untouched. Returns the number of bytes covered in `dst`.
+pub(crate) fn copy_with_stride(
+    dst: &mut [u8],
+    dst_stride: usize,
+    src: &[u8],
+    src_stride: usize,
+) -> Result<usize, StrideCopyError> {
+    if src_stride == 0 || dst_stride == 0 {
+        return Err(StrideCopyError::ZeroStride);
+    }
+
+    let lines = src.len().div_ceil(src_stride);
+    let needed = lines * dst_stride;
+    if needed > dst.len() {
+        return Err(StrideCopyError::DestinationTooSmall {
+            needed,
+            available: dst.len(),
+        });
+    }
+
+    let line_len = min(src_stride, dst_stride);
+    for (src_line, dst_line) in src.chunks(src_stride).zip(dst.chunks_mut(dst_stride)) {
+        let len = min(line_len, src_line.len());
+        dst_line[..len].copy_from_slice(&src_line[..len]);
+    }
+
+    Ok(needed)
 }
 
 impl AsRef<[u8]> for PlaneMapping {
@@ -73,10 +133,12 @@ impl Drop for PlaneMapping {
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum MmapError {
     #[error("provided length was 0")]
     ZeroLength,
+    #[error("range {0:?} is outside of the {1} bytes of the plane")]
+    InvalidRange(Range<u32>, u32),
     #[error("ioctl error: {0}")]
     IoctlError(#[from] Errno),
 }
@@ -84,7 +146,7 @@ pub enum MmapError {
 impl From<MmapError> for Errno {
     fn from(err: MmapError) -> Self {
         match err {
-            MmapError::ZeroLength => Errno::EINVAL,
+            MmapError::ZeroLength | MmapError::InvalidRange(..) => Errno::EINVAL,
             MmapError::IoctlError(e) => e,
         }
     }
@@ -113,3 +175,80 @@ pub fn mmap(fd: &impl AsFd, mem_offset: u32, length: u32) -> Result<PlaneMapping
         end: length as usize,
     })
 }
+
+/// Returns the page size of the system, which mapping offsets must be aligned to.
+pub(crate) fn page_size() -> u32 {
+    // Safe because sysconf does not access memory.
+    match unsafe { nix::libc::sysconf(nix::libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as u32,
+        _ => 4096,
+    }
+}
+
+/// Returns the page-aligned offset to map from and the length to map in order to cover `range`.
+fn aligned_range(range: &Range<u32>, page_size: u32) -> (u32, u32) {
+    let start = range.start - range.start % page_size;
+    (start, range.end - start)
+}
+
+/// Map only `range` of the `length` bytes of memory at `mem_offset`, for users that do not need
+/// to access a whole (and possibly large) plane.
+///
+/// Mappings must start on a page boundary, so the memory actually mapped may start up to a page
+/// before `range`, but the returned `PlaneMapping` only gives access to `range`.
+///
+/// Note that V4L2 MMAP buffers can only be mapped from their start, so `range.start` must be
+/// zero for them. DMABUFs can be mapped from any offset.
+pub fn mmap_range(
+    fd: &impl AsFd,
+    mem_offset: u32,
+    length: u32,
+    range: Range<u32>,
+) -> Result<PlaneMapping, MmapError> {
+    if range.start > range.end || range.end > length {
+        return Err(MmapError::InvalidRange(range, length));
+    }
+
+    let (map_start, map_length) = aligned_range(&range, page_size());
+    let start = (range.start - map_start) as usize;
+    let end = (range.end - map_start) as usize;
+
+    Ok(mmap(fd, mem_offset + map_start, map_length)?.restrict(start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_with_stride() {
+        let src = [1u8, 2, 3, 4, 5, 6];
+        let mut dst = [0u8; 12];
+        assert_eq!(copy_with_stride(&mut dst, 4, &src, 3), Ok(8));
+        assert_eq!(dst, [1, 2, 3, 0, 4, 5, 6, 0, 0, 0, 0, 0]);
+
+        // Source lines wider than the destination ones are cut.
+        let mut dst = [0u8; 4];
+        assert_eq!(copy_with_stride(&mut dst, 2, &src, 3), Ok(4));
+        assert_eq!(dst, [1, 2, 4, 5]);
+
+        assert_eq!(
+            copy_with_stride(&mut [0u8; 7], 4, &src, 3),
+            Err(StrideCopyError::DestinationTooSmall {
+                needed: 8,
+                available: 7
+            })
+        );
+        assert_eq!(
+            copy_with_stride(&mut dst, 0, &src, 3),
+            Err(StrideCopyError::ZeroStride)
+        );
+    }
+
+    #[test]
+    fn test_aligned_range() {
+        assert_eq!(aligned_range(&(0..100), 4096), (0, 100));
+        assert_eq!(aligned_range(&(4096..8192), 4096), (4096, 4096));
+        assert_eq!(aligned_range(&(5000..6000), 4096), (4096, 1904));
+    }
+}
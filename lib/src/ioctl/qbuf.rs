@@ -18,14 +18,22 @@ use crate::memory::Memory;
 use crate::memory::PlaneHandle;
 use crate::QueueType;
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum QBufIoctlError {
     #[error("invalid number of planes specified for the buffer: got {0}, expected {1}")]
     NumPlanesMismatch(usize, usize),
     #[error("data offset specified while using the single-planar API")]
     DataOffsetNotSupported,
+    #[error("memory backing plane {plane} is too small: got {size} bytes, required {required}")]
+    PlaneTooSmall {
+        plane: usize,
+        size: usize,
+        required: usize,
+    },
+    #[error("buffer queued as part of a request, but the queue does not support requests")]
+    RequestsNotSupported,
     #[error("unexpected ioctl error: {0}")]
-    Other(Errno),
+    Other(#[source] Errno),
 }
 
 impl From<Errno> for QBufIoctlError {
@@ -39,6 +47,8 @@ impl From<QBufIoctlError> for Errno {
         match err {
             QBufIoctlError::NumPlanesMismatch(_, _) => Errno::EINVAL,
             QBufIoctlError::DataOffsetNotSupported => Errno::EINVAL,
+            QBufIoctlError::PlaneTooSmall { .. } => Errno::EINVAL,
+            QBufIoctlError::RequestsNotSupported => Errno::EBADR,
             QBufIoctlError::Other(e) => e,
         }
     }
@@ -127,6 +137,7 @@ impl<H: PlaneHandle> From<QBuffer<H>> for UncheckedV4l2Buffer {
         v4l2_buf.0.timestamp.tv_usec = qbuf.timestamp.tv_usec();
         if let Some(request) = &qbuf.request {
             v4l2_buf.0.__bindgen_anon_1.request_fd = *request;
+            v4l2_buf.0.flags |= BufferFlags::REQUEST_FD.bits();
         }
         if let Some(planes) = &mut v4l2_buf.1 {
             for (dst_plane, src_plane) in planes.iter_mut().zip(qbuf.planes.into_iter()) {
@@ -147,10 +158,10 @@ impl<H: PlaneHandle> From<QBuffer<H>> for UncheckedV4l2Buffer {
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_buffer;
-    nix::ioctl_readwrite!(vidioc_querybuf, b'V', 9, v4l2_buffer);
-    nix::ioctl_readwrite!(vidioc_qbuf, b'V', 15, v4l2_buffer);
-    nix::ioctl_readwrite!(vidioc_dqbuf, b'V', 17, v4l2_buffer);
-    nix::ioctl_readwrite!(vidioc_prepare_buf, b'V', 93, v4l2_buffer);
+    crate::ioctl::ioctl_readwrite!(vidioc_querybuf, b'V', 9, v4l2_buffer);
+    crate::ioctl::ioctl_readwrite!(vidioc_qbuf, b'V', 15, v4l2_buffer);
+    crate::ioctl::ioctl_readwrite!(vidioc_dqbuf, b'V', 17, v4l2_buffer);
+    crate::ioctl::ioctl_readwrite!(vidioc_prepare_buf, b'V', 93, v4l2_buffer);
 }
 
 pub type QBufError<CE> = IoctlConvertError<QBufIoctlError, CE>;
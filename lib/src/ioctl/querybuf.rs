@@ -65,15 +65,15 @@ impl TryFrom<UncheckedV4l2Buffer> for QueryBuffer {
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_buffer;
-    nix::ioctl_readwrite!(vidioc_querybuf, b'V', 9, v4l2_buffer);
+    crate::ioctl::ioctl_readwrite!(vidioc_querybuf, b'V', 9, v4l2_buffer);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum QueryBufIoctlError {
     #[error("unsupported queue or out-of-bounds index")]
     InvalidInput,
     #[error("unexpected ioctl error: {0}")]
-    Other(Errno),
+    Other(#[source] Errno),
 }
 
 impl From<Errno> for QueryBufIoctlError {
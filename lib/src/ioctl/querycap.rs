@@ -2,6 +2,7 @@
 use super::string_from_cstr;
 use crate::bindings;
 use crate::bindings::v4l2_capability;
+use crate::QueueType;
 use bitflags::bitflags;
 use nix::errno::Errno;
 use std::fmt;
@@ -51,6 +52,39 @@ bitflags! {
     }
 }
 
+impl Capabilities {
+    /// Returns the capabilities that allow a device to have queues of type
+    /// `queue_type`. Having any of them is enough.
+    pub fn for_queue(queue_type: QueueType) -> Self {
+        match queue_type {
+            QueueType::VideoCapture => Self::VIDEO_CAPTURE | Self::VIDEO_M2M,
+            QueueType::VideoOutput => Self::VIDEO_OUTPUT | Self::VIDEO_M2M,
+            QueueType::VideoOverlay => Self::VIDEO_OVERLAY,
+            QueueType::VbiCapture => Self::VBI_CAPTURE,
+            QueueType::VbiOutput => Self::VBI_OUTPUT,
+            QueueType::SlicedVbiCapture => Self::SLICED_VBI_CAPTURE,
+            QueueType::SlicedVbiOutput => Self::SLICED_VBI_OUTPUT,
+            QueueType::VideoOutputOverlay => Self::VIDEO_OUTPUT_OVERLAY,
+            QueueType::VideoCaptureMplane => Self::VIDEO_CAPTURE_MPLANE | Self::VIDEO_M2M_MPLANE,
+            QueueType::VideoOutputMplane => Self::VIDEO_OUTPUT_MPLANE | Self::VIDEO_M2M_MPLANE,
+            QueueType::SdrCapture => Self::SDR_CAPTURE,
+            QueueType::SdrOutput => Self::SDR_OUTPUT,
+            QueueType::MetaCapture => Self::META_CAPTURE,
+            QueueType::MetaOutput => Self::META_OUTPUT,
+        }
+    }
+
+    /// Returns the types of the queues a device with these capabilities has,
+    /// in the order of their V4L2 buffer type.
+    pub fn queue_types(&self) -> Vec<QueueType> {
+        (bindings::v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE
+            ..=bindings::v4l2_buf_type_V4L2_BUF_TYPE_META_OUTPUT)
+            .filter_map(QueueType::n)
+            .filter(|&queue_type| self.intersects(Self::for_queue(queue_type)))
+            .collect()
+    }
+}
+
 impl fmt::Display for Capabilities {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Debug::fmt(self, f)
@@ -108,13 +142,13 @@ impl From<v4l2_capability> for Capability {
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_capability;
-    nix::ioctl_read!(vidioc_querycap, b'V', 0, v4l2_capability);
+    crate::ioctl::ioctl_read!(vidioc_querycap, b'V', 0, v4l2_capability);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum QueryCapError {
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<QueryCapError> for Errno {
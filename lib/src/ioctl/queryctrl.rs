@@ -1,13 +1,16 @@
 //! Safe wrapper for the `VIDIOC_QUERYCTRL` and `VIDIOC_QUERY_EXT_CTRL` ioctls.
+use std::iter::Peekable;
 use std::os::unix::io::AsRawFd;
 
 use bitflags::bitflags;
+use log::error;
 use nix::errno::Errno;
 use thiserror::Error;
 
 use crate::bindings;
 use crate::bindings::v4l2_query_ext_ctrl;
 use crate::bindings::v4l2_queryctrl;
+use crate::ioctl::string_from_cstr;
 
 /// Index of a control that has been validated, i.e. which ID is within the range of
 /// `V4L2_CTRL_ID_MASK`.
@@ -20,6 +23,12 @@ pub enum CtrlIdError {
     InvalidControl(u32),
 }
 
+impl crate::ioctl::OsError for CtrlIdError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
 impl CtrlId {
     /// Create a new control index from its u32 representation, after validation.
     pub fn new(ctrl: u32) -> Result<Self, CtrlIdError> {
@@ -39,6 +48,26 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Flags describing the state of a control, as returned in the `flags` field of
+    /// `v4l2_queryctrl` and `v4l2_query_ext_ctrl`.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct ControlFlags: u32 {
+        const DISABLED = bindings::V4L2_CTRL_FLAG_DISABLED;
+        const GRABBED = bindings::V4L2_CTRL_FLAG_GRABBED;
+        const READ_ONLY = bindings::V4L2_CTRL_FLAG_READ_ONLY;
+        const UPDATE = bindings::V4L2_CTRL_FLAG_UPDATE;
+        const INACTIVE = bindings::V4L2_CTRL_FLAG_INACTIVE;
+        const SLIDER = bindings::V4L2_CTRL_FLAG_SLIDER;
+        const WRITE_ONLY = bindings::V4L2_CTRL_FLAG_WRITE_ONLY;
+        const VOLATILE = bindings::V4L2_CTRL_FLAG_VOLATILE;
+        const HAS_PAYLOAD = bindings::V4L2_CTRL_FLAG_HAS_PAYLOAD;
+        const EXECUTE_ON_WRITE = bindings::V4L2_CTRL_FLAG_EXECUTE_ON_WRITE;
+        const MODIFY_LAYOUT = bindings::V4L2_CTRL_FLAG_MODIFY_LAYOUT;
+        const DYNAMIC_ARRAY = bindings::V4L2_CTRL_FLAG_DYNAMIC_ARRAY;
+    }
+}
+
 /// Decompose a u32 between its control ID and query flags parts.
 pub fn parse_ctrl_id_and_flags(ctrl: u32) -> (CtrlId, QueryCtrlFlags) {
     (
@@ -50,16 +79,16 @@ pub fn parse_ctrl_id_and_flags(ctrl: u32) -> (CtrlId, QueryCtrlFlags) {
 #[doc(hidden)]
 mod ioctl {
     use crate::bindings::v4l2_queryctrl;
-    nix::ioctl_readwrite!(vidioc_queryctrl, b'V', 36, v4l2_queryctrl);
+    crate::ioctl::ioctl_readwrite!(vidioc_queryctrl, b'V', 36, v4l2_queryctrl);
 
     use crate::bindings::v4l2_query_ext_ctrl;
-    nix::ioctl_readwrite!(vidioc_query_ext_ctrl, b'V', 103, v4l2_query_ext_ctrl);
+    crate::ioctl::ioctl_readwrite!(vidioc_query_ext_ctrl, b'V', 103, v4l2_query_ext_ctrl);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum QueryCtrlError {
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<QueryCtrlError> for Errno {
@@ -104,10 +133,216 @@ pub fn query_ext_ctrl<T: From<v4l2_query_ext_ctrl>>(
     }
 }
 
+/// Safe variant of the `v4l2_query_ext_ctrl` struct, describing a control.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlInfo {
+    pub id: u32,
+    /// One of the `V4L2_CTRL_TYPE_*` values.
+    pub ctrl_type: u32,
+    pub name: String,
+    pub minimum: i64,
+    pub maximum: i64,
+    pub step: u64,
+    pub default_value: i64,
+    pub flags: ControlFlags,
+    /// Size in bytes of a single element of the value of the control.
+    pub elem_size: u32,
+    /// Number of elements of the value of the control, 1 for non-array controls.
+    pub elems: u32,
+}
+
+impl ControlInfo {
+    /// Returns the class of this control, i.e. one of the `V4L2_CTRL_CLASS_*`
+    /// values.
+    pub fn class(&self) -> u32 {
+        // Equivalent of the V4L2_CTRL_ID2CLASS macro.
+        self.id & 0x0fff_0000
+    }
+
+    /// Returns whether this control is the one holding the name of its class.
+    pub fn is_class_control(&self) -> bool {
+        self.ctrl_type == bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_CTRL_CLASS
+    }
+}
+
+impl From<v4l2_query_ext_ctrl> for ControlInfo {
+    fn from(qctrl: v4l2_query_ext_ctrl) -> Self {
+        let name: Vec<u8> = qctrl.name.iter().map(|&c| c as u8).collect();
+
+        ControlInfo {
+            id: qctrl.id,
+            ctrl_type: qctrl.type_,
+            name: string_from_cstr(&name).unwrap_or_else(|_| "".into()),
+            minimum: qctrl.minimum,
+            maximum: qctrl.maximum,
+            step: qctrl.step,
+            default_value: qctrl.default_value,
+            flags: ControlFlags::from_bits_truncate(qctrl.flags),
+            elem_size: qctrl.elem_size,
+            elems: qctrl.elems,
+        }
+    }
+}
+
+/// Iterator over all the controls of a device, including compound ones, in
+/// increasing ID order. This takes a reference to the device's file descriptor
+/// so it cannot be closed while the iterator exists.
+pub struct ControlIterator<'a, F: AsRawFd> {
+    fd: &'a F,
+    id: u32,
+}
+
+impl<'a, F: AsRawFd> ControlIterator<'a, F> {
+    /// Create a new iterator listing all the controls of `fd`.
+    pub fn new(fd: &'a F) -> Self {
+        ControlIterator { fd, id: 0 }
+    }
+
+    /// Turn this iterator into one returning the controls grouped by class.
+    pub fn by_class(self) -> ControlClassIterator<Self> {
+        ControlClassIterator::new(self)
+    }
+}
+
+impl<'a, F: AsRawFd> Iterator for ControlIterator<'a, F> {
+    type Item = ControlInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match query_ext_ctrl::<ControlInfo>(
+            self.fd,
+            CtrlId(self.id),
+            QueryCtrlFlags::NEXT | QueryCtrlFlags::COMPOUND,
+        ) {
+            Ok(ctrl) => {
+                self.id = ctrl.id;
+                Some(ctrl)
+            }
+            // EINVAL means we have reached the last control.
+            Err(QueryCtrlError::IoctlError(Errno::EINVAL)) => None,
+            Err(e) => {
+                error!("Unexpected return value for VIDIOC_QUERY_EXT_CTRL: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Controls of a device belonging to the same class.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlClass {
+    /// One of the `V4L2_CTRL_CLASS_*` values.
+    pub id: u32,
+    /// Name of the class, as reported by its class control. `None` if the
+    /// driver does not expose a class control for this class.
+    pub name: Option<String>,
+    /// Controls of this class, not including the class control.
+    pub controls: Vec<ControlInfo>,
+}
+
+/// Iterator grouping controls by class, the way they are presented by tools
+/// like `v4l2-ctl` or `qv4l2`. The controls must be sorted by ID, which is the
+/// case of those returned by `ControlIterator`.
+pub struct ControlClassIterator<I: Iterator<Item = ControlInfo>> {
+    controls: Peekable<I>,
+}
+
+impl<I: Iterator<Item = ControlInfo>> ControlClassIterator<I> {
+    pub fn new(controls: I) -> Self {
+        ControlClassIterator {
+            controls: controls.peekable(),
+        }
+    }
+}
+
+impl<I: Iterator<Item = ControlInfo>> Iterator for ControlClassIterator<I> {
+    type Item = ControlClass;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.controls.next()?;
+        let mut class = ControlClass {
+            id: first.class(),
+            name: None,
+            controls: Vec::new(),
+        };
+
+        let mut ctrl = Some(first);
+        while let Some(c) = ctrl {
+            if c.is_class_control() {
+                class.name = Some(c.name);
+            } else {
+                class.controls.push(c);
+            }
+
+            ctrl = self.controls.next_if(|next| next.class() == class.id);
+        }
+
+        Some(class)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn ctrl(id: u32, ctrl_type: u32, name: &str) -> ControlInfo {
+        ControlInfo {
+            id,
+            ctrl_type,
+            name: name.into(),
+            minimum: 0,
+            maximum: 0,
+            step: 0,
+            default_value: 0,
+            flags: ControlFlags::empty(),
+            elem_size: 4,
+            elems: 1,
+        }
+    }
+
+    #[test]
+    fn test_control_classes() {
+        let controls = vec![
+            ctrl(
+                bindings::V4L2_CTRL_CLASS_USER | 1,
+                bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_CTRL_CLASS,
+                "User Controls",
+            ),
+            ctrl(
+                bindings::V4L2_CID_BRIGHTNESS,
+                bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER,
+                "Brightness",
+            ),
+            ctrl(
+                bindings::V4L2_CID_CONTRAST,
+                bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER,
+                "Contrast",
+            ),
+            // Class without a class control.
+            ctrl(
+                bindings::V4L2_CID_MPEG_VIDEO_BITRATE,
+                bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER,
+                "Video Bitrate",
+            ),
+        ];
+
+        let classes: Vec<_> = ControlClassIterator::new(controls.clone().into_iter()).collect();
+        assert_eq!(
+            classes,
+            vec![
+                ControlClass {
+                    id: bindings::V4L2_CTRL_CLASS_USER,
+                    name: Some("User Controls".into()),
+                    controls: controls[1..3].to_vec(),
+                },
+                ControlClass {
+                    id: bindings::V4L2_CTRL_CLASS_CODEC,
+                    name: None,
+                    controls: controls[3..].to_vec(),
+                },
+            ]
+        );
+    }
+
     #[test]
     fn test_ctrlid() {
         assert_eq!(
@@ -10,6 +10,12 @@ use nix::{self, errno::Errno};
 use std::os::unix::io::AsRawFd;
 use thiserror::Error;
 
+/// Maximum number of buffers of a queue for drivers that do not report it.
+pub const LEGACY_MAX_NUM_BUFFERS: u32 = bindings::VIDEO_MAX_FRAME;
+
+/// Not part of our bindings yet, as it has been introduced in Linux 6.8.
+const V4L2_BUF_CAP_SUPPORTS_MAX_NUM_BUFFERS: u32 = 1 << 7;
+
 bitflags! {
     /// Flags returned by the `VIDIOC_REQBUFS` ioctl into the `capabilities`
     /// field of `struct v4l2_requestbuffers`.
@@ -20,7 +26,24 @@ bitflags! {
         const SUPPORTS_DMABUF = bindings::V4L2_BUF_CAP_SUPPORTS_DMABUF;
         const SUPPORTS_REQUESTS = bindings::V4L2_BUF_CAP_SUPPORTS_REQUESTS;
         const SUPPORTS_ORPHANED_BUFS = bindings::V4L2_BUF_CAP_SUPPORTS_ORPHANED_BUFS;
-        //const SUPPORTS_M2M_HOLD_CAPTURE_BUF = bindings::V4L2_BUF_CAP_SUPPORTS_M2M_HOLD_CAPTURE_BUF;
+        const SUPPORTS_M2M_HOLD_CAPTURE_BUF = bindings::V4L2_BUF_CAP_SUPPORTS_M2M_HOLD_CAPTURE_BUF;
+        const SUPPORTS_MMAP_CACHE_HINTS = bindings::V4L2_BUF_CAP_SUPPORTS_MMAP_CACHE_HINTS;
+        /// The `max_num_buffers` field of `struct v4l2_create_buffers` is valid.
+        const SUPPORTS_MAX_NUM_BUFFERS = V4L2_BUF_CAP_SUPPORTS_MAX_NUM_BUFFERS;
+    }
+}
+
+bitflags! {
+    /// Flags that can be passed into the `flags` field of `struct v4l2_requestbuffers`
+    /// and `struct v4l2_create_buffers` to control how buffer memory is allocated.
+    ///
+    /// These flags are ignored (and cleared by the kernel) unless the queue
+    /// has the `SUPPORTS_MMAP_CACHE_HINTS` capability and uses MMAP memory.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct MemoryFlags: u32 {
+        /// Allocate non-coherent memory, for buffers that are mostly accessed
+        /// by the device and rarely by the CPU.
+        const NON_COHERENT = bindings::V4L2_MEMORY_FLAG_NON_COHERENT;
     }
 }
 
@@ -47,6 +70,8 @@ impl From<v4l2_requestbuffers> for BufferCapabilities {
 pub struct RequestBuffers {
     pub count: u32,
     pub capabilities: BufferCapabilities,
+    /// Memory flags actually applied by the driver.
+    pub flags: MemoryFlags,
 }
 
 impl From<v4l2_requestbuffers> for RequestBuffers {
@@ -54,6 +79,7 @@ impl From<v4l2_requestbuffers> for RequestBuffers {
         RequestBuffers {
             count: reqbufs.count,
             capabilities: BufferCapabilities::from_bits_truncate(reqbufs.capabilities),
+            flags: MemoryFlags::from_bits_truncate(reqbufs.flags as u32),
         }
     }
 }
@@ -63,16 +89,16 @@ mod ioctl {
     use crate::bindings::v4l2_create_buffers;
     use crate::bindings::v4l2_requestbuffers;
 
-    nix::ioctl_readwrite!(vidioc_reqbufs, b'V', 8, v4l2_requestbuffers);
-    nix::ioctl_readwrite!(vidioc_create_bufs, b'V', 92, v4l2_create_buffers);
+    crate::ioctl::ioctl_readwrite!(vidioc_reqbufs, b'V', 8, v4l2_requestbuffers);
+    crate::ioctl::ioctl_readwrite!(vidioc_create_bufs, b'V', 92, v4l2_create_buffers);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum ReqbufsError {
     #[error("invalid buffer ({0}) or memory type ({1:?}) requested")]
     InvalidBufferType(QueueType, MemoryType),
     #[error("ioctl error: {0}")]
-    IoctlError(nix::Error),
+    IoctlError(#[source] nix::Error),
 }
 
 impl From<ReqbufsError> for Errno {
@@ -90,11 +116,24 @@ pub fn reqbufs<O: From<v4l2_requestbuffers>>(
     queue: QueueType,
     memory: MemoryType,
     count: u32,
+) -> Result<O, ReqbufsError> {
+    reqbufs_with_flags(fd, queue, memory, count, MemoryFlags::empty())
+}
+
+/// Safe wrapper around the `VIDIOC_REQBUFS` ioctl, also passing memory
+/// allocation `flags`.
+pub fn reqbufs_with_flags<O: From<v4l2_requestbuffers>>(
+    fd: &impl AsRawFd,
+    queue: QueueType,
+    memory: MemoryType,
+    count: u32,
+    flags: MemoryFlags,
 ) -> Result<O, ReqbufsError> {
     let mut reqbufs = v4l2_requestbuffers {
         count,
         type_: queue as u32,
         memory: memory as u32,
+        flags: flags.bits() as u8,
         ..Default::default()
     };
 
@@ -105,14 +144,14 @@ pub fn reqbufs<O: From<v4l2_requestbuffers>>(
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum CreateBufsError {
     #[error("no memory available to allocate MMAP buffers")]
     NoMem,
     #[error("invalid format or memory type requested")]
     Invalid,
     #[error("ioctl error: {0}")]
-    IoctlError(nix::Error),
+    IoctlError(#[source] nix::Error),
 }
 
 impl From<CreateBufsError> for Errno {
@@ -131,11 +170,24 @@ pub fn create_bufs<F: Into<v4l2_format>, O: From<v4l2_create_buffers>>(
     count: u32,
     memory: MemoryType,
     format: F,
+) -> Result<O, CreateBufsError> {
+    create_bufs_with_flags(fd, count, memory, format, MemoryFlags::empty())
+}
+
+/// Safe wrapper around the `VIDIOC_CREATE_BUFS` ioctl, also passing memory
+/// allocation `flags`.
+pub fn create_bufs_with_flags<F: Into<v4l2_format>, O: From<v4l2_create_buffers>>(
+    fd: &impl AsRawFd,
+    count: u32,
+    memory: MemoryType,
+    format: F,
+    flags: MemoryFlags,
 ) -> Result<O, CreateBufsError> {
     let mut create_bufs = v4l2_create_buffers {
         count,
         memory: memory as u32,
         format: format.into(),
+        flags: flags.bits(),
         ..Default::default()
     };
 
@@ -146,3 +198,68 @@ pub fn create_bufs<F: Into<v4l2_format>, O: From<v4l2_create_buffers>>(
         Err(e) => Err(CreateBufsError::IoctlError(e)),
     }
 }
+
+/// Result of a `create_bufs` call with a count of zero, describing the buffers a queue supports.
+#[derive(Debug, Clone, Copy)]
+pub struct CreateBuffersCapabilities {
+    pub capabilities: BufferCapabilities,
+    /// Maximum number of buffers the queue can hold, `LEGACY_MAX_NUM_BUFFERS` if the driver does
+    /// not report it.
+    pub max_num_buffers: u32,
+}
+
+impl From<v4l2_create_buffers> for CreateBuffersCapabilities {
+    fn from(create_bufs: v4l2_create_buffers) -> Self {
+        let capabilities = BufferCapabilities::from_bits_truncate(create_bufs.capabilities);
+        CreateBuffersCapabilities {
+            capabilities,
+            // `max_num_buffers` takes the place of the first reserved field since Linux 6.8.
+            max_num_buffers: if capabilities.contains(BufferCapabilities::SUPPORTS_MAX_NUM_BUFFERS)
+            {
+                create_bufs.reserved[0]
+            } else {
+                LEGACY_MAX_NUM_BUFFERS
+            },
+        }
+    }
+}
+
+/// Query the buffer capabilities of `queue` for `memory`, including the maximum number of
+/// buffers it can hold, using `VIDIOC_CREATE_BUFS` without allocating any buffer.
+pub fn query_create_bufs_capabilities(
+    fd: &impl AsRawFd,
+    queue: QueueType,
+    memory: MemoryType,
+) -> Result<CreateBuffersCapabilities, CreateBufsError> {
+    // Only the type of the format is looked at when no buffer is allocated.
+    let format = v4l2_format {
+        type_: queue as u32,
+        ..Default::default()
+    };
+
+    create_bufs(fd, 0, memory, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_num_buffers() {
+        let mut create_bufs = v4l2_create_buffers {
+            capabilities: bindings::V4L2_BUF_CAP_SUPPORTS_MMAP,
+            ..Default::default()
+        };
+        create_bufs.reserved[0] = 64;
+        assert_eq!(
+            CreateBuffersCapabilities::from(create_bufs).max_num_buffers,
+            LEGACY_MAX_NUM_BUFFERS
+        );
+
+        create_bufs.capabilities |= V4L2_BUF_CAP_SUPPORTS_MAX_NUM_BUFFERS;
+        assert_eq!(
+            CreateBuffersCapabilities::from(create_bufs).max_num_buffers,
+            64
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use nix::errno::Errno;
 use nix::libc::c_int;
 use nix::poll::{poll, PollFd};
 use std::fs::File;
@@ -10,25 +11,39 @@ pub use nix::poll::PollFlags;
 #[doc(hidden)]
 mod ioctl {
     use nix::libc::c_int;
-    nix::ioctl_read!(media_ioc_request_alloc, b'|', 5, c_int);
-    nix::ioctl_none!(media_request_ioc_queue, b'|', 0x80);
-    nix::ioctl_none!(media_request_ioc_reinit, b'|', 0x81);
+    crate::ioctl::ioctl_read!(media_ioc_request_alloc, b'|', 5, c_int);
+    crate::ioctl::ioctl_none!(media_request_ioc_queue, b'|', 0x80);
+    crate::ioctl::ioctl_none!(media_request_ioc_reinit, b'|', 0x81);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum RequestError {
+    #[error("media device does not support requests")]
+    Unsupported,
     #[error("Unexpected ioctl error: {0}")]
-    IoctlError(nix::Error),
+    IoctlError(#[source] nix::Error),
     #[error("Unknown poll flag returned")]
     UnknownPollFlagReturned,
 }
 
+impl From<RequestError> for Errno {
+    fn from(err: RequestError) -> Self {
+        match err {
+            RequestError::Unsupported => Errno::ENOTTY,
+            RequestError::IoctlError(e) => e,
+            RequestError::UnknownPollFlagReturned => Errno::EIO,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Request {
     fd: File,
 }
 
 impl Request {
+    /// Allocate a new request on the media device `media_fd`. Fails with
+    /// `Unsupported` if the device does not support requests.
     pub fn alloc(media_fd: &impl AsRawFd) -> Result<Self, RequestError> {
         let mut request_fd: RawFd = 0;
         // SAFETY: the 'data' argument is an address of a variable compatible with a C integer
@@ -42,6 +57,7 @@ impl Request {
             Ok(_) => Ok(Request {
                 fd: unsafe { File::from_raw_fd(request_fd) },
             }),
+            Err(Errno::ENOTTY) => Err(RequestError::Unsupported),
             Err(e) => Err(RequestError::IoctlError(e)),
         }
     }
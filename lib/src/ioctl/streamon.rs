@@ -6,11 +6,11 @@ use thiserror::Error;
 
 #[doc(hidden)]
 mod ioctl {
-    nix::ioctl_write_ptr!(vidioc_streamon, b'V', 18, u32);
-    nix::ioctl_write_ptr!(vidioc_streamoff, b'V', 19, u32);
+    crate::ioctl::ioctl_write_ptr!(vidioc_streamon, b'V', 18, u32);
+    crate::ioctl::ioctl_write_ptr!(vidioc_streamoff, b'V', 19, u32);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum StreamOnError {
     #[error("queue type ({0}) not supported, or no buffers allocated or enqueued")]
     InvalidQueue(QueueType),
@@ -19,7 +19,7 @@ pub enum StreamOnError {
     #[error("invalid pipeline link configuration")]
     InvalidPipelineConfig,
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<StreamOnError> for Errno {
@@ -44,12 +44,12 @@ pub fn streamon(fd: &impl AsRawFd, queue: QueueType) -> Result<(), StreamOnError
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum StreamOffError {
     #[error("queue type not supported")]
     InvalidQueue,
     #[error("ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<StreamOffError> for Errno {
@@ -0,0 +1,454 @@
+//! Safe wrappers for the sub-device format and routing ioctls:
+//! `VIDIOC_SUBDEV_G_FMT`, `VIDIOC_SUBDEV_S_FMT`, `VIDIOC_SUBDEV_G_ROUTING`,
+//! `VIDIOC_SUBDEV_S_ROUTING` and `VIDIOC_SUBDEV_S_CLIENT_CAP`.
+//!
+//! Multiplexed links (e.g. CSI-2 virtual channels carrying several cameras or
+//! embedded data) are described by routes between streams of the sink and
+//! source pads of a sub-device, and formats are set per (pad, stream) pair.
+//! Using streams requires the `STREAMS` client capability to be set on the
+//! sub-device file descriptor first.
+//!
+//! `linux/v4l2-subdev.h` is not part of the generated bindings, so the
+//! structures passed to these ioctls are defined here, following the layout of
+//! the kernel UAPI.
+use std::os::unix::io::AsRawFd;
+
+use bitflags::bitflags;
+use nix::errno::Errno;
+use thiserror::Error;
+
+use crate::{Colorimetry, Colorspace, Quantization, XferFunc, YCbCrEncoding};
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct v4l2_mbus_framefmt {
+    width: u32,
+    height: u32,
+    code: u32,
+    field: u32,
+    colorspace: u32,
+    ycbcr_enc: u16,
+    quantization: u16,
+    xfer_func: u16,
+    flags: u16,
+    reserved: [u16; 10],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct v4l2_subdev_format {
+    which: u32,
+    pad: u32,
+    format: v4l2_mbus_framefmt,
+    stream: u32,
+    reserved: [u32; 7],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct v4l2_subdev_route {
+    sink_pad: u32,
+    sink_stream: u32,
+    source_pad: u32,
+    source_stream: u32,
+    flags: u32,
+    reserved: [u32; 5],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct v4l2_subdev_routing {
+    which: u32,
+    len_routes: u32,
+    routes: u64,
+    num_routes: u32,
+    reserved: [u32; 11],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct v4l2_subdev_client_capability {
+    capabilities: u64,
+}
+
+const V4L2_SUBDEV_ROUTE_FL_ACTIVE: u32 = 1 << 0;
+
+#[doc(hidden)]
+mod ioctl {
+    use super::{v4l2_subdev_client_capability, v4l2_subdev_format, v4l2_subdev_routing};
+    crate::ioctl::ioctl_readwrite!(vidioc_subdev_g_fmt, b'V', 4, v4l2_subdev_format);
+    crate::ioctl::ioctl_readwrite!(vidioc_subdev_s_fmt, b'V', 5, v4l2_subdev_format);
+    crate::ioctl::ioctl_readwrite!(vidioc_subdev_g_routing, b'V', 38, v4l2_subdev_routing);
+    crate::ioctl::ioctl_readwrite!(vidioc_subdev_s_routing, b'V', 39, v4l2_subdev_routing);
+    crate::ioctl::ioctl_readwrite!(
+        vidioc_subdev_s_client_cap,
+        b'V',
+        102,
+        v4l2_subdev_client_capability
+    );
+}
+
+/// Whether a sub-device ioctl operates on the active configuration or on the
+/// try configuration of the file handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SubdevWhich {
+    Try = 0,
+    Active = 1,
+}
+
+bitflags! {
+    /// Client capabilities of a sub-device file handle.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct SubdevClientCapabilities: u64 {
+        /// The client understands the `stream` fields, i.e. multiplexed streams.
+        const STREAMS = 1 << 0;
+        /// The client understands the `which` field of `VIDIOC_SUBDEV_G/S_FRAME_INTERVAL`.
+        const INTERVAL_USES_WHICH = 1 << 1;
+    }
+}
+
+/// Media bus format of a sub-device pad or stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MbusFrameFormat {
+    pub width: u32,
+    pub height: u32,
+    /// One of the `MEDIA_BUS_FMT_*` codes.
+    pub code: u32,
+    pub field: u32,
+    pub colorspace: Colorspace,
+    pub ycbcr_enc: YCbCrEncoding,
+    pub quantization: Quantization,
+    pub xfer_func: XferFunc,
+    pub flags: u16,
+}
+
+impl MbusFrameFormat {
+    /// Returns the colorimetry fields of the format.
+    pub fn colorimetry(&self) -> Colorimetry {
+        Colorimetry {
+            colorspace: self.colorspace,
+            ycbcr_enc: self.ycbcr_enc,
+            quantization: self.quantization,
+            xfer_func: self.xfer_func,
+        }
+    }
+}
+
+impl From<v4l2_mbus_framefmt> for MbusFrameFormat {
+    fn from(fmt: v4l2_mbus_framefmt) -> Self {
+        MbusFrameFormat {
+            width: fmt.width,
+            height: fmt.height,
+            code: fmt.code,
+            field: fmt.field,
+            colorspace: fmt.colorspace.into(),
+            ycbcr_enc: (fmt.ycbcr_enc as u32).into(),
+            quantization: (fmt.quantization as u32).into(),
+            xfer_func: (fmt.xfer_func as u32).into(),
+            flags: fmt.flags,
+        }
+    }
+}
+
+impl From<&MbusFrameFormat> for v4l2_mbus_framefmt {
+    fn from(fmt: &MbusFrameFormat) -> Self {
+        v4l2_mbus_framefmt {
+            width: fmt.width,
+            height: fmt.height,
+            code: fmt.code,
+            field: fmt.field,
+            colorspace: fmt.colorspace.into(),
+            ycbcr_enc: u32::from(fmt.ycbcr_enc) as u16,
+            quantization: u32::from(fmt.quantization) as u16,
+            xfer_func: u32::from(fmt.xfer_func) as u16,
+            flags: fmt.flags,
+            ..Default::default()
+        }
+    }
+}
+
+/// A route between a stream of a sink pad and a stream of a source pad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubdevRoute {
+    pub sink_pad: u32,
+    pub sink_stream: u32,
+    pub source_pad: u32,
+    pub source_stream: u32,
+    /// Whether data flows through this route.
+    pub active: bool,
+}
+
+impl From<&v4l2_subdev_route> for SubdevRoute {
+    fn from(route: &v4l2_subdev_route) -> Self {
+        SubdevRoute {
+            sink_pad: route.sink_pad,
+            sink_stream: route.sink_stream,
+            source_pad: route.source_pad,
+            source_stream: route.source_stream,
+            active: route.flags & V4L2_SUBDEV_ROUTE_FL_ACTIVE != 0,
+        }
+    }
+}
+
+impl From<&SubdevRoute> for v4l2_subdev_route {
+    fn from(route: &SubdevRoute) -> Self {
+        v4l2_subdev_route {
+            sink_pad: route.sink_pad,
+            sink_stream: route.sink_stream,
+            source_pad: route.source_pad,
+            source_stream: route.source_stream,
+            flags: if route.active {
+                V4L2_SUBDEV_ROUTE_FL_ACTIVE
+            } else {
+                0
+            },
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum SubdevFmtError {
+    #[error("invalid pad, stream or format")]
+    Invalid,
+    #[error("format cannot be changed while streaming")]
+    Busy,
+    #[error("ioctl error: {0}")]
+    IoctlError(#[source] Errno),
+}
+
+impl From<SubdevFmtError> for Errno {
+    fn from(err: SubdevFmtError) -> Self {
+        match err {
+            SubdevFmtError::Invalid => Errno::EINVAL,
+            SubdevFmtError::Busy => Errno::EBUSY,
+            SubdevFmtError::IoctlError(e) => e,
+        }
+    }
+}
+
+fn subdev_fmt_error(e: Errno) -> SubdevFmtError {
+    match e {
+        Errno::EINVAL => SubdevFmtError::Invalid,
+        Errno::EBUSY => SubdevFmtError::Busy,
+        e => SubdevFmtError::IoctlError(e),
+    }
+}
+
+/// Safe wrapper around the `VIDIOC_SUBDEV_G_FMT` ioctl.
+///
+/// `stream` must be `0` unless the `STREAMS` client capability has been set.
+pub fn subdev_g_fmt(
+    fd: &impl AsRawFd,
+    which: SubdevWhich,
+    pad: u32,
+    stream: u32,
+) -> Result<MbusFrameFormat, SubdevFmtError> {
+    let mut format = v4l2_subdev_format {
+        which: which as u32,
+        pad,
+        stream,
+        ..Default::default()
+    };
+
+    match unsafe { ioctl::vidioc_subdev_g_fmt(fd.as_raw_fd(), &mut format) } {
+        Ok(_) => Ok(format.format.into()),
+        Err(e) => Err(subdev_fmt_error(e)),
+    }
+}
+
+/// Safe wrapper around the `VIDIOC_SUBDEV_S_FMT` ioctl.
+///
+/// Returns the format actually applied, which the driver may have adjusted.
+pub fn subdev_s_fmt(
+    fd: &impl AsRawFd,
+    which: SubdevWhich,
+    pad: u32,
+    stream: u32,
+    format: &MbusFrameFormat,
+) -> Result<MbusFrameFormat, SubdevFmtError> {
+    let mut format = v4l2_subdev_format {
+        which: which as u32,
+        pad,
+        stream,
+        format: format.into(),
+        ..Default::default()
+    };
+
+    match unsafe { ioctl::vidioc_subdev_s_fmt(fd.as_raw_fd(), &mut format) } {
+        Ok(_) => Ok(format.format.into()),
+        Err(e) => Err(subdev_fmt_error(e)),
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum RoutingError {
+    #[error("routing is not supported by this sub-device")]
+    Unsupported,
+    #[error("invalid routing table")]
+    Invalid,
+    #[error("routing cannot be changed while streaming")]
+    Busy,
+    #[error("ioctl error: {0}")]
+    IoctlError(#[source] Errno),
+}
+
+impl From<RoutingError> for Errno {
+    fn from(err: RoutingError) -> Self {
+        match err {
+            RoutingError::Unsupported => Errno::ENOTTY,
+            RoutingError::Invalid => Errno::EINVAL,
+            RoutingError::Busy => Errno::EBUSY,
+            RoutingError::IoctlError(e) => e,
+        }
+    }
+}
+
+fn routing_error(e: Errno) -> RoutingError {
+    match e {
+        Errno::ENOTTY => RoutingError::Unsupported,
+        Errno::EINVAL => RoutingError::Invalid,
+        Errno::EBUSY => RoutingError::Busy,
+        e => RoutingError::IoctlError(e),
+    }
+}
+
+/// Safe wrapper around the `VIDIOC_SUBDEV_G_ROUTING` ioctl.
+///
+/// Returns the full routing table of the sub-device, growing the routes array
+/// as needed.
+pub fn subdev_g_routing(
+    fd: &impl AsRawFd,
+    which: SubdevWhich,
+) -> Result<Vec<SubdevRoute>, RoutingError> {
+    let mut routes: Vec<v4l2_subdev_route> = Vec::new();
+
+    loop {
+        let mut routing = v4l2_subdev_routing {
+            which: which as u32,
+            len_routes: routes.len() as u32,
+            routes: routes.as_mut_ptr() as u64,
+            ..Default::default()
+        };
+
+        // SAFETY: `routes` points to `len_routes` valid elements.
+        match unsafe { ioctl::vidioc_subdev_g_routing(fd.as_raw_fd(), &mut routing) } {
+            Ok(_) => {
+                let num_routes = (routing.num_routes as usize).min(routes.len());
+                return Ok(routes[..num_routes].iter().map(Into::into).collect());
+            }
+            // Our array was too small, the driver told us how many routes there are.
+            Err(Errno::ENOSPC) if routing.num_routes as usize > routes.len() => {
+                routes.resize(routing.num_routes as usize, Default::default());
+            }
+            Err(e) => return Err(routing_error(e)),
+        }
+    }
+}
+
+/// Safe wrapper around the `VIDIOC_SUBDEV_S_ROUTING` ioctl.
+///
+/// Returns the routing table actually applied, which the driver may have
+/// adjusted.
+pub fn subdev_s_routing(
+    fd: &impl AsRawFd,
+    which: SubdevWhich,
+    routes: &[SubdevRoute],
+) -> Result<Vec<SubdevRoute>, RoutingError> {
+    let mut v4l2_routes: Vec<v4l2_subdev_route> = routes.iter().map(Into::into).collect();
+    let mut routing = v4l2_subdev_routing {
+        which: which as u32,
+        len_routes: v4l2_routes.len() as u32,
+        routes: v4l2_routes.as_mut_ptr() as u64,
+        num_routes: v4l2_routes.len() as u32,
+        ..Default::default()
+    };
+
+    // SAFETY: `routes` points to `len_routes` valid elements.
+    match unsafe { ioctl::vidioc_subdev_s_routing(fd.as_raw_fd(), &mut routing) } {
+        Ok(_) => {
+            let num_routes = (routing.num_routes as usize).min(v4l2_routes.len());
+            Ok(v4l2_routes[..num_routes].iter().map(Into::into).collect())
+        }
+        Err(e) => Err(routing_error(e)),
+    }
+}
+
+/// Safe wrapper around the `VIDIOC_SUBDEV_S_CLIENT_CAP` ioctl.
+///
+/// Returns the capabilities actually enabled, which only include those
+/// supported by the kernel.
+pub fn subdev_s_client_cap(
+    fd: &impl AsRawFd,
+    caps: SubdevClientCapabilities,
+) -> Result<SubdevClientCapabilities, Errno> {
+    let mut client_cap = v4l2_subdev_client_capability {
+        capabilities: caps.bits(),
+    };
+
+    unsafe { ioctl::vidioc_subdev_s_client_cap(fd.as_raw_fd(), &mut client_cap) }?;
+
+    Ok(SubdevClientCapabilities::from_bits_truncate(
+        client_cap.capabilities,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_struct_layouts() {
+        // Ioctl numbers depend on the size of the structures, which must match
+        // the kernel's.
+        assert_eq!(std::mem::size_of::<v4l2_mbus_framefmt>(), 48);
+        assert_eq!(std::mem::size_of::<v4l2_subdev_format>(), 88);
+        assert_eq!(std::mem::size_of::<v4l2_subdev_route>(), 40);
+        assert_eq!(std::mem::size_of::<v4l2_subdev_routing>(), 64);
+    }
+
+    #[test]
+    fn test_mbus_framefmt_conversion() {
+        use crate::bindings;
+
+        let fmt = v4l2_mbus_framefmt {
+            width: 640,
+            height: 480,
+            colorspace: bindings::v4l2_colorspace_V4L2_COLORSPACE_REC709,
+            ycbcr_enc: bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_709 as u16,
+            // Unknown value.
+            xfer_func: 0xff,
+            ..Default::default()
+        };
+        let mbus_fmt = MbusFrameFormat::from(fmt);
+        assert_eq!(
+            mbus_fmt.colorimetry(),
+            Colorimetry {
+                colorspace: Colorspace::Rec709,
+                ycbcr_enc: YCbCrEncoding::E709,
+                quantization: Quantization::Default,
+                xfer_func: XferFunc::Unknown(0xff),
+            }
+        );
+
+        let back = v4l2_mbus_framefmt::from(&mbus_fmt);
+        assert_eq!(back.colorspace, fmt.colorspace);
+        assert_eq!(back.ycbcr_enc, fmt.ycbcr_enc);
+        assert_eq!(back.xfer_func, 0xff);
+    }
+
+    #[test]
+    fn test_route_conversion() {
+        let route = SubdevRoute {
+            sink_pad: 0,
+            sink_stream: 1,
+            source_pad: 2,
+            source_stream: 0,
+            active: true,
+        };
+        let v4l2_route = v4l2_subdev_route::from(&route);
+        assert_eq!(v4l2_route.flags, V4L2_SUBDEV_ROUTE_FL_ACTIVE);
+        assert_eq!(SubdevRoute::from(&v4l2_route), route);
+    }
+}
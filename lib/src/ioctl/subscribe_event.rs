@@ -30,6 +30,8 @@ pub enum EventType {
     FrameSync,
     SourceChange(u32),
     MotionDet,
+    /// Driver-private event, of type `V4L2_EVENT_PRIVATE_START` or above.
+    Private(u32),
 }
 
 #[derive(Debug, Error)]
@@ -40,6 +42,12 @@ pub enum EventConversionError {
     UnrecognizedSourceChange(u32),
 }
 
+impl crate::ioctl::OsError for EventConversionError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
 impl TryFrom<&v4l2_event_subscription> for EventType {
     type Error = EventConversionError;
 
@@ -51,6 +59,7 @@ impl TryFrom<&v4l2_event_subscription> for EventType {
             bindings::V4L2_EVENT_FRAME_SYNC => EventType::FrameSync,
             bindings::V4L2_EVENT_SOURCE_CHANGE => EventType::SourceChange(event.id),
             bindings::V4L2_EVENT_MOTION_DET => EventType::MotionDet,
+            e if e >= bindings::V4L2_EVENT_PRIVATE_START => EventType::Private(e),
             e => return Err(EventConversionError::UnrecognizedEvent(e)),
         })
     }
@@ -63,10 +72,25 @@ bitflags! {
     }
 }
 
+/// Payload of a `V4L2_EVENT_MOTION_DET` event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotionDetEvent {
+    /// Sequence number of the frame in which motion has been detected, if the driver reports it.
+    pub frame_sequence: Option<u32>,
+    /// Mask of the regions in which motion has been detected.
+    pub region_mask: u32,
+}
+
 #[derive(Debug)]
 pub enum Event {
     SrcChangeEvent(SrcChanges),
     Eos,
+    MotionDet(MotionDetEvent),
+    /// Driver-private event, with its raw payload. Its layout is defined by the driver.
+    Private {
+        type_: u32,
+        data: [u8; 64],
+    },
 }
 
 impl TryFrom<v4l2_event> for Event {
@@ -74,10 +98,7 @@ impl TryFrom<v4l2_event> for Event {
 
     fn try_from(value: v4l2_event) -> Result<Self, Self::Error> {
         Ok(match value.type_ {
-            bindings::V4L2_EVENT_VSYNC => todo!(),
             bindings::V4L2_EVENT_EOS => Event::Eos,
-            bindings::V4L2_EVENT_CTRL => todo!(),
-            bindings::V4L2_EVENT_FRAME_SYNC => todo!(),
             bindings::V4L2_EVENT_SOURCE_CHANGE => {
                 let changes = unsafe { value.u.src_change.changes };
                 Event::SrcChangeEvent(
@@ -85,7 +106,24 @@ impl TryFrom<v4l2_event> for Event {
                         .ok_or(EventConversionError::UnrecognizedSourceChange(changes))?,
                 )
             }
-            bindings::V4L2_EVENT_MOTION_DET => todo!(),
+            bindings::V4L2_EVENT_MOTION_DET => {
+                let motion_det = unsafe { value.u.motion_det };
+                Event::MotionDet(MotionDetEvent {
+                    frame_sequence: if motion_det.flags & bindings::V4L2_EVENT_MD_FL_HAVE_FRAME_SEQ
+                        != 0
+                    {
+                        Some(motion_det.frame_sequence)
+                    } else {
+                        None
+                    },
+                    region_mask: motion_det.region_mask,
+                })
+            }
+            t if t >= bindings::V4L2_EVENT_PRIVATE_START => Event::Private {
+                type_: t,
+                data: unsafe { value.u.data },
+            },
+            // TODO: support the other event types.
             t => return Err(EventConversionError::UnrecognizedEvent(t)),
         })
     }
@@ -103,6 +141,7 @@ fn build_v4l2_event_subscription(
             EventType::FrameSync => bindings::V4L2_EVENT_FRAME_SYNC,
             EventType::SourceChange(_) => bindings::V4L2_EVENT_SOURCE_CHANGE,
             EventType::MotionDet => bindings::V4L2_EVENT_MOTION_DET,
+            EventType::Private(type_) => type_,
         },
         id: match event {
             EventType::Ctrl(id) => id,
@@ -118,12 +157,12 @@ fn build_v4l2_event_subscription(
 mod ioctl {
     use crate::bindings::{v4l2_event, v4l2_event_subscription};
 
-    nix::ioctl_read!(vidioc_dqevent, b'V', 89, v4l2_event);
-    nix::ioctl_write_ptr!(vidioc_subscribe_event, b'V', 90, v4l2_event_subscription);
-    nix::ioctl_write_ptr!(vidioc_unsubscribe_event, b'V', 91, v4l2_event_subscription);
+    crate::ioctl::ioctl_read!(vidioc_dqevent, b'V', 89, v4l2_event);
+    crate::ioctl::ioctl_write_ptr!(vidioc_subscribe_event, b'V', 90, v4l2_event_subscription);
+    crate::ioctl::ioctl_write_ptr!(vidioc_unsubscribe_event, b'V', 91, v4l2_event_subscription);
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum SubscribeEventError {
     #[error("ioctl error: {0}")]
     IoctlError(#[from] Errno),
@@ -168,14 +207,14 @@ pub fn unsubscribe_all_events(fd: &impl AsRawFd) -> Result<(), SubscribeEventErr
     Ok(())
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum DqEventError {
     #[error("no event ready for dequeue")]
     NotReady,
     #[error("error while converting event")]
     EventConversionError,
     #[error("unexpected ioctl error: {0}")]
-    IoctlError(Errno),
+    IoctlError(#[source] Errno),
 }
 
 impl From<Errno> for DqEventError {
@@ -208,3 +247,55 @@ pub fn dqevent<O: TryFrom<v4l2_event>>(fd: &impl AsRawFd) -> Result<O, DqEventEr
         Err(e) => Err(DqEventError::IoctlError(e)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn motion_det_and_private_events() {
+        let mut event = v4l2_event {
+            type_: bindings::V4L2_EVENT_MOTION_DET,
+            ..Default::default()
+        };
+        event.u.motion_det = bindings::v4l2_event_motion_det {
+            flags: bindings::V4L2_EVENT_MD_FL_HAVE_FRAME_SEQ,
+            frame_sequence: 42,
+            region_mask: 0b101,
+        };
+        match Event::try_from(event) {
+            Ok(Event::MotionDet(motion_det)) => assert_eq!(
+                motion_det,
+                MotionDetEvent {
+                    frame_sequence: Some(42),
+                    region_mask: 0b101,
+                }
+            ),
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        let mut event = v4l2_event {
+            type_: bindings::V4L2_EVENT_PRIVATE_START + 1,
+            ..Default::default()
+        };
+        let mut data = [0u8; 64];
+        data[0] = 0xaa;
+        event.u.data = data;
+        match Event::try_from(event) {
+            Ok(Event::Private { type_, data }) => {
+                assert_eq!(type_, bindings::V4L2_EVENT_PRIVATE_START + 1);
+                assert_eq!(data[0], 0xaa);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        let subscription = build_v4l2_event_subscription(
+            EventType::Private(bindings::V4L2_EVENT_PRIVATE_START + 1),
+            SubscribeEventFlags::empty(),
+        );
+        assert!(matches!(
+            EventType::try_from(&subscription),
+            Ok(EventType::Private(t)) if t == bindings::V4L2_EVENT_PRIVATE_START + 1
+        ));
+    }
+}
@@ -0,0 +1,628 @@
+//! Recording and replaying of the ioctls performed by this crate.
+//!
+//! With the `ioctl-trace` feature, every ioctl performed by this crate can be
+//! recorded into a [`Trace`] along with the data and result returned by the
+//! driver. The trace can then be saved to a file and replayed later without
+//! the device: instead of reaching the kernel, each ioctl then returns the
+//! recorded data and result. This allows driver-specific behavior to be
+//! captured once on real hardware and guarded against in regression tests.
+//!
+//! Only ioctls are traced. A replayed device is typically opened from a file
+//! like `/dev/null`, and code relying on `mmap`, `poll`, `read` or `write`
+//! cannot be replayed. The data pointed to by the arguments of the ioctls is
+//! not traced either, with the exception of the planes of multi-planar
+//! buffers and the array of extended controls. Finally, ioctls are replayed
+//! in the order they have been recorded, so code issuing ioctls from several
+//! threads concurrently may not replay reliably.
+//!
+//! ```no_run
+//! # use std::{fs::File, io::BufReader, path::Path};
+//! # use v4l2r::device::{Device, DeviceConfig};
+//! # use v4l2r::ioctl::trace;
+//! // Record the ioctls performed while opening a device...
+//! let (_, recorded) = trace::record(|| {
+//!     Device::open(Path::new("/dev/video0"), DeviceConfig::new())
+//! });
+//! recorded.write_to(File::create("open.trace").unwrap()).unwrap();
+//!
+//! // ... and replay them later.
+//! let trace = trace::Trace::read_from(BufReader::new(File::open("open.trace").unwrap())).unwrap();
+//! let device = trace::replay(&trace, || Device::open(Path::new("/dev/null"), DeviceConfig::new()))
+//!     .unwrap()
+//!     .unwrap();
+//! ```
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+    mem::size_of,
+    os::unix::io::RawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use nix::{errno::Errno, libc, sys::ioctl::ioctl_num_type};
+use thiserror::Error;
+
+use crate::{
+    bindings,
+    ioctl::{impl_into_io_error, OsError},
+    QueueType,
+};
+
+/// A recorded ioctl.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// Request code of the ioctl.
+    pub request: u64,
+    /// Value returned by the ioctl, or its error code.
+    pub result: Result<i32, Errno>,
+    /// Content of the argument of the ioctl after it returned.
+    pub arg: Vec<u8>,
+    /// Content of the arrays pointed to by the argument of the ioctl after
+    /// it returned, e.g. the planes of a multi-planar buffer.
+    pub indirect: Vec<Vec<u8>>,
+}
+
+/// A sequence of recorded ioctls.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub entries: Vec<TraceEntry>,
+}
+
+#[derive(Debug, Error)]
+pub enum ReadTraceError {
+    #[error("error while reading trace: {0}")]
+    IoError(#[from] io::Error),
+    #[error("invalid trace entry at line {0}")]
+    InvalidEntry(usize),
+}
+
+impl OsError for ReadTraceError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            ReadTraceError::IoError(e) => e.raw_os_error().map(Errno::from_i32),
+            ReadTraceError::InvalidEntry(_) => None,
+        }
+    }
+}
+
+impl From<ReadTraceError> for io::Error {
+    fn from(err: ReadTraceError) -> Self {
+        match err {
+            // Do not wrap the error of the reader a second time.
+            ReadTraceError::IoError(e) => e,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s == "-" {
+        return Some(Vec::new());
+    }
+    if s.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn write_hex(out: &mut String, data: &[u8]) {
+    if data.is_empty() {
+        out.push('-');
+    }
+    for byte in data {
+        let _ = write!(out, "{:02x}", byte);
+    }
+}
+
+impl Trace {
+    /// Write the trace in its text form, one ioctl per line.
+    ///
+    /// Each line contains the request code, the result (the returned value if
+    /// positive, or the negated error code), the content of the argument and
+    /// then of each indirect array, all separated by spaces. Binary data is
+    /// written in hexadecimal, with `-` standing for no data.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for entry in &self.entries {
+            let result = match entry.result {
+                Ok(value) => value,
+                Err(errno) => -(errno as i32),
+            };
+            let mut line = format!("{:08x} {} ", entry.request, result);
+            write_hex(&mut line, &entry.arg);
+            for indirect in &entry.indirect {
+                line.push(' ');
+                write_hex(&mut line, indirect);
+            }
+            writeln!(writer, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a trace from its text form, as written by `write_to`. Empty lines
+    /// and lines starting with `#` are ignored, so traces can be annotated.
+    pub fn read_from<R: BufRead>(reader: R) -> Result<Self, ReadTraceError> {
+        let mut entries = Vec::new();
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || ReadTraceError::InvalidEntry(i + 1);
+            let mut fields = line.split_whitespace();
+            let request = fields
+                .next()
+                .and_then(|f| u64::from_str_radix(f, 16).ok())
+                .ok_or_else(invalid)?;
+            let result = fields
+                .next()
+                .and_then(|f| f.parse::<i32>().ok())
+                .map(|r| {
+                    if r < 0 {
+                        Err(Errno::from_i32(-r))
+                    } else {
+                        Ok(r)
+                    }
+                })
+                .ok_or_else(invalid)?;
+            let arg = fields.next().and_then(parse_hex).ok_or_else(invalid)?;
+            let indirect = fields
+                .map(parse_hex)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(invalid)?;
+
+            entries.push(TraceEntry {
+                request,
+                result,
+                arg,
+                indirect,
+            });
+        }
+
+        Ok(Trace { entries })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ReplayError {
+    #[error("ioctl #{index} has request code {got:#x}, expected {expected:#x}")]
+    Mismatch {
+        index: usize,
+        expected: u64,
+        got: u64,
+    },
+    #[error("ioctl #{index} with request code {request:#x} is past the end of the trace")]
+    Exhausted { index: usize, request: u64 },
+    #[error("{0} ioctls of the trace have not been replayed")]
+    Unconsumed(usize),
+}
+
+impl OsError for ReplayError {
+    fn errno(&self) -> Option<Errno> {
+        None
+    }
+}
+
+impl_into_io_error!(ReplayError);
+
+enum State {
+    Passthrough,
+    Recording(Vec<TraceEntry>),
+    Replaying {
+        entries: Vec<TraceEntry>,
+        position: usize,
+        error: Option<ReplayError>,
+    },
+}
+
+/// Fast path check to avoid locking `STATE` when not tracing.
+static TRACING: AtomicBool = AtomicBool::new(false);
+static STATE: Mutex<State> = Mutex::new(State::Passthrough);
+/// Serializes the recording and replaying sessions.
+static SESSION: Mutex<()> = Mutex::new(());
+
+fn set_state(state: State) -> State {
+    let mut current = STATE.lock().unwrap();
+    TRACING.store(!matches!(state, State::Passthrough), Ordering::SeqCst);
+    std::mem::replace(&mut *current, state)
+}
+
+/// Run `f` and return its result along with the trace of all the ioctls it
+/// performed, including from other threads.
+pub fn record<T, F: FnOnce() -> T>(f: F) -> (T, Trace) {
+    let _session = SESSION.lock().unwrap_or_else(|e| e.into_inner());
+
+    set_state(State::Recording(Vec::new()));
+    let ret = f();
+    let entries = match set_state(State::Passthrough) {
+        State::Recording(entries) => entries,
+        _ => unreachable!(),
+    };
+
+    (ret, Trace { entries })
+}
+
+/// Run `f`, serving all the ioctls it performs from `trace` instead of the
+/// kernel, and return its result.
+///
+/// An error is returned if the ioctls performed do not match the ones of the
+/// trace or if some of the recorded ioctls have not been performed. Starting
+/// from the first mismatching ioctl, all ioctls fail with `EINVAL`.
+pub fn replay<T, F: FnOnce() -> T>(trace: &Trace, f: F) -> Result<T, ReplayError> {
+    let _session = SESSION.lock().unwrap_or_else(|e| e.into_inner());
+
+    set_state(State::Replaying {
+        entries: trace.entries.clone(),
+        position: 0,
+        error: None,
+    });
+    let ret = f();
+    match set_state(State::Passthrough) {
+        State::Replaying {
+            error: Some(error), ..
+        } => Err(error),
+        State::Replaying {
+            entries, position, ..
+        } if position < entries.len() => Err(ReplayError::Unconsumed(entries.len() - position)),
+        State::Replaying { .. } => Ok(ret),
+        _ => unreachable!(),
+    }
+}
+
+/// Array pointed to by the argument of an ioctl that is traced along with it.
+enum IndirectArray {
+    None,
+    /// Planes of a multi-planar buffer.
+    Planes(*mut bindings::v4l2_plane, usize),
+    /// Controls of a `v4l2_ext_controls`.
+    Controls(*mut bindings::v4l2_ext_control, usize),
+}
+
+impl IndirectArray {
+    /// Returns the array pointed to by `data`, the argument of `request`.
+    ///
+    /// # Safety
+    ///
+    /// `data` must point to a valid argument of `request`.
+    unsafe fn of(request: ioctl_num_type, data: *mut u8) -> Self {
+        let buffer_requests = [
+            // VIDIOC_QUERYBUF, VIDIOC_QBUF, VIDIOC_DQBUF and VIDIOC_PREPARE_BUF.
+            nix::request_code_readwrite!(b'V', 9, size_of::<bindings::v4l2_buffer>()),
+            nix::request_code_readwrite!(b'V', 15, size_of::<bindings::v4l2_buffer>()),
+            nix::request_code_readwrite!(b'V', 17, size_of::<bindings::v4l2_buffer>()),
+            nix::request_code_readwrite!(b'V', 93, size_of::<bindings::v4l2_buffer>()),
+        ];
+        let ext_ctrls_requests = [
+            // VIDIOC_G_EXT_CTRLS, VIDIOC_S_EXT_CTRLS and VIDIOC_TRY_EXT_CTRLS.
+            nix::request_code_readwrite!(b'V', 71, size_of::<bindings::v4l2_ext_controls>()),
+            nix::request_code_readwrite!(b'V', 72, size_of::<bindings::v4l2_ext_controls>()),
+            nix::request_code_readwrite!(b'V', 73, size_of::<bindings::v4l2_ext_controls>()),
+        ];
+
+        if buffer_requests.contains(&request) {
+            let buffer = &*(data as *const bindings::v4l2_buffer);
+            let is_multiplanar = QueueType::n(buffer.type_)
+                .map(|queue| queue.is_multiplanar())
+                .unwrap_or(false);
+            if is_multiplanar && !buffer.m.planes.is_null() {
+                return IndirectArray::Planes(buffer.m.planes, buffer.length as usize);
+            }
+        } else if ext_ctrls_requests.contains(&request) {
+            let controls = &*(data as *const bindings::v4l2_ext_controls);
+            if !controls.controls.is_null() {
+                return IndirectArray::Controls(controls.controls, controls.count as usize);
+            }
+        }
+
+        IndirectArray::None
+    }
+
+    /// Returns the pointer to the array and its size in bytes.
+    fn region(&self) -> Option<(*mut u8, usize)> {
+        match *self {
+            IndirectArray::None => None,
+            IndirectArray::Planes(planes, count) => {
+                Some((planes as *mut u8, count * size_of::<bindings::v4l2_plane>()))
+            }
+            IndirectArray::Controls(controls, count) => Some((
+                controls as *mut u8,
+                count * size_of::<bindings::v4l2_ext_control>(),
+            )),
+        }
+    }
+}
+
+/// Overwrites the argument `data` of `request` with the recorded `entry`,
+/// keeping all the pointers of the argument to the memory of the caller.
+///
+/// # Safety
+///
+/// `data` must point to a valid argument of `request` of `size` bytes.
+unsafe fn replay_arg(request: ioctl_num_type, data: *mut u8, size: usize, entry: &TraceEntry) {
+    let array = IndirectArray::of(request, data);
+    // Payload pointers of compound controls, which must also be kept.
+    let saved_controls: Vec<bindings::v4l2_ext_control> = match array {
+        IndirectArray::Controls(controls, count) => (0..count)
+            .map(|i| std::ptr::read_unaligned(controls.add(i)))
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    std::ptr::copy_nonoverlapping(entry.arg.as_ptr(), data, entry.arg.len().min(size));
+
+    match array {
+        IndirectArray::None => return,
+        IndirectArray::Planes(planes, _) => {
+            (*(data as *mut bindings::v4l2_buffer)).m.planes = planes;
+        }
+        IndirectArray::Controls(controls, _) => {
+            (*(data as *mut bindings::v4l2_ext_controls)).controls = controls;
+        }
+    }
+
+    if let (Some((ptr, len)), Some(recorded)) = (array.region(), entry.indirect.first()) {
+        std::ptr::copy_nonoverlapping(recorded.as_ptr(), ptr, len.min(recorded.len()));
+    }
+
+    if let IndirectArray::Controls(controls, _) = array {
+        for (i, saved) in saved_controls.into_iter().enumerate() {
+            if saved.size > 0 {
+                let mut control = std::ptr::read_unaligned(controls.add(i));
+                control.__bindgen_anon_1 = saved.__bindgen_anon_1;
+                std::ptr::write_unaligned(controls.add(i), control);
+            }
+        }
+    }
+}
+
+/// Performs the ioctl `request` on `fd`, or records or replays it if a trace
+/// session is in progress. `size` is the size of the argument pointed to by
+/// `data`, which is written back if `writes_back` is true.
+///
+/// # Safety
+///
+/// Same as calling `libc::ioctl(fd, request, data)`.
+pub(crate) unsafe fn ioctl(
+    fd: RawFd,
+    request: ioctl_num_type,
+    data: *mut libc::c_void,
+    size: usize,
+    writes_back: bool,
+) -> nix::Result<libc::c_int> {
+    let do_ioctl = || Errno::result(libc::ioctl(fd, request, data));
+
+    if !TRACING.load(Ordering::SeqCst) {
+        return do_ioctl();
+    }
+
+    let data = data as *mut u8;
+    // `ioctl_num_type` is not `u64` on all platforms.
+    #[allow(clippy::unnecessary_cast)]
+    let code = request as u64;
+    let mut state = STATE.lock().unwrap();
+    match &mut *state {
+        State::Passthrough => {
+            drop(state);
+            do_ioctl()
+        }
+        State::Recording(_) => {
+            // Do not hold the lock while performing the ioctl, as it may block.
+            drop(state);
+            let result = do_ioctl();
+            let entry = TraceEntry {
+                request: code,
+                result,
+                arg: if data.is_null() {
+                    Vec::new()
+                } else {
+                    std::slice::from_raw_parts(data, size).to_vec()
+                },
+                indirect: if data.is_null() {
+                    Vec::new()
+                } else {
+                    IndirectArray::of(request, data)
+                        .region()
+                        .map(|(ptr, len)| std::slice::from_raw_parts(ptr, len).to_vec())
+                        .into_iter()
+                        .collect()
+                },
+            };
+            if let State::Recording(entries) = &mut *STATE.lock().unwrap() {
+                entries.push(entry);
+            }
+
+            result
+        }
+        State::Replaying {
+            entries,
+            position,
+            error,
+        } => {
+            if error.is_some() {
+                return Err(Errno::EINVAL);
+            }
+
+            let index = *position;
+            let entry = match entries.get(index) {
+                Some(entry) if entry.request == code => entry,
+                Some(entry) => {
+                    *error = Some(ReplayError::Mismatch {
+                        index,
+                        expected: entry.request,
+                        got: code,
+                    });
+                    return Err(Errno::EINVAL);
+                }
+                None => {
+                    *error = Some(ReplayError::Exhausted {
+                        index,
+                        request: code,
+                    });
+                    return Err(Errno::EINVAL);
+                }
+            };
+            *position += 1;
+
+            if writes_back && !data.is_null() {
+                replay_arg(request, data, size, entry);
+            }
+
+            entry.result
+        }
+    }
+}
+
+/// Helpers for the unit tests replaying handwritten traces.
+#[cfg(test)]
+#[allow(clippy::unnecessary_cast)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::ioctl::Capabilities;
+
+    pub(crate) const QUERYCAP: u64 =
+        nix::request_code_read!(b'V', 0, size_of::<bindings::v4l2_capability>()) as u64;
+    pub(crate) const ENUM_FMT: u64 =
+        nix::request_code_readwrite!(b'V', 2, size_of::<bindings::v4l2_fmtdesc>()) as u64;
+    pub(crate) const G_FMT: u64 =
+        nix::request_code_readwrite!(b'V', 4, size_of::<bindings::v4l2_format>()) as u64;
+    pub(crate) const S_FMT: u64 =
+        nix::request_code_readwrite!(b'V', 5, size_of::<bindings::v4l2_format>()) as u64;
+    pub(crate) const REQBUFS: u64 =
+        nix::request_code_readwrite!(b'V', 8, size_of::<bindings::v4l2_requestbuffers>()) as u64;
+    pub(crate) const QUERYBUF: u64 =
+        nix::request_code_readwrite!(b'V', 9, size_of::<bindings::v4l2_buffer>()) as u64;
+    pub(crate) const QUERYMENU: u64 =
+        nix::request_code_readwrite!(b'V', 37, size_of::<bindings::v4l2_querymenu>()) as u64;
+    pub(crate) const STREAMOFF: u64 =
+        nix::request_code_write!(b'V', 19, size_of::<libc::c_int>()) as u64;
+    pub(crate) const CREATE_BUFS: u64 =
+        nix::request_code_readwrite!(b'V', 92, size_of::<bindings::v4l2_create_buffers>()) as u64;
+    pub(crate) const QUERY_EXT_CTRL: u64 =
+        nix::request_code_readwrite!(b'V', 103, size_of::<bindings::v4l2_query_ext_ctrl>()) as u64;
+
+    /// Returns a trace entry for an ioctl returning `result` without changing
+    /// its argument.
+    pub(crate) fn entry(request: u64, result: Result<i32, Errno>) -> TraceEntry {
+        TraceEntry {
+            request,
+            result,
+            arg: Vec::new(),
+            indirect: Vec::new(),
+        }
+    }
+
+    /// Returns a trace entry for a successful ioctl setting its argument to
+    /// `arg`.
+    pub(crate) fn arg_entry<T>(request: u64, arg: &T) -> TraceEntry {
+        let arg =
+            unsafe { std::slice::from_raw_parts(arg as *const T as *const u8, size_of::<T>()) }
+                .to_vec();
+
+        TraceEntry {
+            arg,
+            ..entry(request, Ok(0))
+        }
+    }
+
+    /// Returns a trace entry for a successful `VIDIOC_QUERYCAP` reporting
+    /// `driver` as the driver name and `device_caps` as the capabilities of
+    /// the device.
+    pub(crate) fn querycap_entry(driver: &str, device_caps: Capabilities) -> TraceEntry {
+        let mut cap: bindings::v4l2_capability = unsafe { std::mem::zeroed() };
+        cap.driver[..driver.len()].copy_from_slice(driver.as_bytes());
+        if !device_caps.is_empty() {
+            cap.device_caps = device_caps.bits();
+            cap.capabilities = (device_caps | Capabilities::DEVICE_CAPS).bits();
+        }
+
+        arg_entry(QUERYCAP, &cap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::testing::QUERYCAP;
+    use super::*;
+    use crate::ioctl::{querycap, Capabilities, Capability};
+
+    fn querycap_entry(driver: &str) -> TraceEntry {
+        testing::querycap_entry(driver, Capabilities::empty())
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let trace = Trace {
+            entries: vec![
+                querycap_entry("vicodec"),
+                TraceEntry {
+                    request: 0xc0585609,
+                    result: Err(Errno::EINVAL),
+                    arg: vec![0x01, 0xab],
+                    indirect: vec![vec![], vec![0xff]],
+                },
+            ],
+        };
+
+        let mut text = b"# Annotated trace\n\n".to_vec();
+        trace.write_to(&mut text).unwrap();
+        assert_eq!(Trace::read_from(text.as_slice()).unwrap(), trace);
+
+        assert!(matches!(
+            Trace::read_from(b"c0585609 0 abc".as_ref()),
+            Err(ReadTraceError::InvalidEntry(1))
+        ));
+
+        let err: io::Error = ReadTraceError::InvalidEntry(1).into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err: io::Error =
+            ReadTraceError::from(io::Error::from(io::ErrorKind::UnexpectedEof)).into();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(err.get_ref().is_none());
+    }
+
+    #[test]
+    fn replay_querycap() {
+        let null = File::open("/dev/null").unwrap();
+        let trace = Trace {
+            entries: vec![querycap_entry("vicodec")],
+        };
+
+        let cap = replay(&trace, || querycap::<Capability>(&null))
+            .unwrap()
+            .unwrap();
+        assert_eq!(cap.driver, "vicodec");
+
+        // Another ioctl than the recorded one.
+        let trace = Trace {
+            entries: vec![TraceEntry {
+                request: QUERYCAP + 1,
+                ..querycap_entry("vicodec")
+            }],
+        };
+        assert!(matches!(
+            replay(&trace, || querycap::<Capability>(&null)),
+            Err(ReplayError::Mismatch { index: 0, .. })
+        ));
+
+        // Recorded ioctls that are never performed.
+        let trace = Trace {
+            entries: vec![querycap_entry("vicodec"), querycap_entry("vicodec")],
+        };
+        assert_eq!(
+            replay(&trace, || querycap::<Capability>(&null)).map(|_| ()),
+            Err(ReplayError::Unconsumed(1))
+        );
+    }
+}
@@ -19,12 +19,23 @@
 //!
 #[doc(hidden)]
 pub mod bindings;
+pub mod callback;
+pub mod capture;
+pub mod compliance;
 pub mod controls;
+pub mod deadline;
 pub mod decoder;
 pub mod device;
 pub mod encoder;
+pub mod format_info;
 pub mod ioctl;
+pub mod media;
 pub mod memory;
+pub mod shutdown;
+pub mod timestamp;
+#[cfg(feature = "v4l")]
+pub mod v4l_compat;
+pub mod vbi;
 
 use std::convert::TryFrom;
 use std::fmt;
@@ -311,6 +322,14 @@ pub enum FormatConversionError {
     InvalidBufferType(u32),
 }
 
+impl crate::ioctl::OsError for FormatConversionError {
+    fn errno(&self) -> Option<nix::errno::Errno> {
+        None
+    }
+}
+
+crate::ioctl::impl_into_io_error!(FormatConversionError);
+
 impl TryFrom<bindings::v4l2_format> for Format {
     type Error = FormatConversionError;
 
@@ -359,6 +378,227 @@ impl TryFrom<bindings::v4l2_format> for Format {
     }
 }
 
+/// A field of a `Format`, or of the colorimetry that goes along with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatField {
+    Width,
+    Height,
+    PixelFormat,
+    NumPlanes,
+    /// `sizeimage` of the plane with this index.
+    SizeImage(usize),
+    /// `bytesperline` of the plane with this index.
+    BytesPerLine(usize),
+    Colorimetry,
+}
+
+/// A field that differs between a requested format and the one applied by the
+/// driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatChange {
+    Width {
+        requested: u32,
+        applied: u32,
+    },
+    Height {
+        requested: u32,
+        applied: u32,
+    },
+    PixelFormat {
+        requested: PixelFormat,
+        applied: PixelFormat,
+    },
+    NumPlanes {
+        requested: usize,
+        applied: usize,
+    },
+    SizeImage {
+        plane: usize,
+        requested: u32,
+        applied: u32,
+    },
+    BytesPerLine {
+        plane: usize,
+        requested: u32,
+        applied: u32,
+    },
+    Colorimetry {
+        requested: Colorimetry,
+        applied: Colorimetry,
+    },
+}
+
+impl FormatChange {
+    /// Returns the field that has changed.
+    pub fn field(&self) -> FormatField {
+        match self {
+            FormatChange::Width { .. } => FormatField::Width,
+            FormatChange::Height { .. } => FormatField::Height,
+            FormatChange::PixelFormat { .. } => FormatField::PixelFormat,
+            FormatChange::NumPlanes { .. } => FormatField::NumPlanes,
+            FormatChange::SizeImage { plane, .. } => FormatField::SizeImage(*plane),
+            FormatChange::BytesPerLine { plane, .. } => FormatField::BytesPerLine(*plane),
+            FormatChange::Colorimetry { .. } => FormatField::Colorimetry,
+        }
+    }
+}
+
+impl Display for FormatChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatChange::Width { requested, applied } => {
+                write!(f, "width {} -> {}", requested, applied)
+            }
+            FormatChange::Height { requested, applied } => {
+                write!(f, "height {} -> {}", requested, applied)
+            }
+            FormatChange::PixelFormat { requested, applied } => {
+                write!(f, "pixelformat {} -> {}", requested, applied)
+            }
+            FormatChange::NumPlanes { requested, applied } => {
+                write!(f, "{} -> {} planes", requested, applied)
+            }
+            FormatChange::SizeImage {
+                plane,
+                requested,
+                applied,
+            } => write!(f, "plane {} sizeimage {} -> {}", plane, requested, applied),
+            FormatChange::BytesPerLine {
+                plane,
+                requested,
+                applied,
+            } => write!(
+                f,
+                "plane {} bytesperline {} -> {}",
+                plane, requested, applied
+            ),
+            FormatChange::Colorimetry { requested, applied } => {
+                write!(f, "colorimetry {:?} -> {:?}", requested, applied)
+            }
+        }
+    }
+}
+
+/// The fields that differ between a requested format and the one applied by
+/// the driver, for logging or error reporting during format negotiation.
+///
+/// ```
+/// # use v4l2r::{Format, FormatDiff, FormatField};
+/// let requested = Format::from((b"NV12", (640, 480)));
+/// let applied = Format::from((b"NV12", (640, 496)));
+/// let diff = FormatDiff::new(&requested, &applied);
+/// assert!(diff.contains(FormatField::Height));
+/// assert_eq!(diff.to_string(), "height 480 -> 496");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormatDiff(Vec<FormatChange>);
+
+impl FormatDiff {
+    /// Compare `requested` against `applied`. Plane layout fields left to zero
+    /// in `requested` are chosen by the driver and thus never reported.
+    pub fn new(requested: &Format, applied: &Format) -> Self {
+        let mut changes = Vec::new();
+
+        if requested.width != applied.width {
+            changes.push(FormatChange::Width {
+                requested: requested.width,
+                applied: applied.width,
+            });
+        }
+        if requested.height != applied.height {
+            changes.push(FormatChange::Height {
+                requested: requested.height,
+                applied: applied.height,
+            });
+        }
+        if requested.pixelformat != applied.pixelformat {
+            changes.push(FormatChange::PixelFormat {
+                requested: requested.pixelformat,
+                applied: applied.pixelformat,
+            });
+        }
+        // An empty layout lets the driver choose the planes.
+        if !requested.plane_fmt.is_empty() && requested.plane_fmt.len() != applied.plane_fmt.len() {
+            changes.push(FormatChange::NumPlanes {
+                requested: requested.plane_fmt.len(),
+                applied: applied.plane_fmt.len(),
+            });
+        }
+        for (plane, (requested, applied)) in requested
+            .plane_fmt
+            .iter()
+            .zip(applied.plane_fmt.iter())
+            .enumerate()
+        {
+            if requested.sizeimage != 0 && requested.sizeimage != applied.sizeimage {
+                changes.push(FormatChange::SizeImage {
+                    plane,
+                    requested: requested.sizeimage,
+                    applied: applied.sizeimage,
+                });
+            }
+            if requested.bytesperline != 0 && requested.bytesperline != applied.bytesperline {
+                changes.push(FormatChange::BytesPerLine {
+                    plane,
+                    requested: requested.bytesperline,
+                    applied: applied.bytesperline,
+                });
+            }
+        }
+
+        FormatDiff(changes)
+    }
+
+    /// Also compare the colorimetry of the formats, which `Format` does not
+    /// carry.
+    pub fn with_colorimetry(mut self, requested: Colorimetry, applied: Colorimetry) -> Self {
+        if requested != applied {
+            self.0
+                .push(FormatChange::Colorimetry { requested, applied });
+        }
+        self
+    }
+
+    pub fn changes(&self) -> &[FormatChange] {
+        &self.0
+    }
+
+    /// Returns whether `field` has changed.
+    pub fn contains(&self, field: FormatField) -> bool {
+        self.0.iter().any(|change| change.field() == field)
+    }
+
+    /// Only keep the changes for which `f` returns `true`.
+    pub fn retain<F: FnMut(&FormatChange) -> bool>(&mut self, f: F) {
+        self.0.retain(f);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Display for FormatDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("no change");
+        }
+
+        for (i, change) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            Display::fmt(change, f)?;
+        }
+
+        Ok(())
+    }
+}
+
 /// Quickly build a usable `Format` from a pixel format and resolution.
 ///
 /// # Examples
@@ -440,63 +680,337 @@ impl Display for Rect {
     }
 }
 
-/// Equivalent of `enum v4l2_colorspace`.
-#[repr(u32)]
-#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, N)]
-pub enum Colorspace {
-    #[default]
-    Default = bindings::v4l2_colorspace_V4L2_COLORSPACE_DEFAULT,
-    Smpte170M = bindings::v4l2_colorspace_V4L2_COLORSPACE_SMPTE170M,
-    Smpte240M = bindings::v4l2_colorspace_V4L2_COLORSPACE_SMPTE240M,
-    Rec709 = bindings::v4l2_colorspace_V4L2_COLORSPACE_REC709,
-    Bt878 = bindings::v4l2_colorspace_V4L2_COLORSPACE_BT878,
-    SystemM470 = bindings::v4l2_colorspace_V4L2_COLORSPACE_470_SYSTEM_M,
-    SystemBG470 = bindings::v4l2_colorspace_V4L2_COLORSPACE_470_SYSTEM_BG,
-    Jpeg = bindings::v4l2_colorspace_V4L2_COLORSPACE_JPEG,
-    Srgb = bindings::v4l2_colorspace_V4L2_COLORSPACE_SRGB,
-    OpRgb = bindings::v4l2_colorspace_V4L2_COLORSPACE_OPRGB,
-    Bt2020 = bindings::v4l2_colorspace_V4L2_COLORSPACE_BT2020,
-    Raw = bindings::v4l2_colorspace_V4L2_COLORSPACE_RAW,
-    DciP3 = bindings::v4l2_colorspace_V4L2_COLORSPACE_DCI_P3,
-}
-
-/// Equivalent of `enum v4l2_xfer_func`.
-#[repr(u32)]
-#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, N)]
-pub enum XferFunc {
-    #[default]
-    Default = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_DEFAULT,
-    F709 = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_709,
-    Srgb = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_SRGB,
-    OpRgb = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_OPRGB,
-    Smpte240M = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_SMPTE240M,
-    None = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_NONE,
-    DciP3 = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_DCI_P3,
-    Smpte2084 = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_SMPTE2084,
-}
-
-/// Equivalent of `enum v4l2_ycbcr_encoding`.
-#[repr(u32)]
-#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, N)]
-pub enum YCbCrEncoding {
-    #[default]
-    Default = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_DEFAULT,
-    E601 = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_601,
-    E709 = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_709,
-    Xv601 = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_XV601,
-    Xv709 = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_XV709,
-    Sycc = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_SYCC,
-    Bt2020 = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_BT2020,
-    Bt2020ConstLum = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_BT2020_CONST_LUM,
-    Smpte240M = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_SMPTE240M,
-}
-
-/// Equivalent of `enum v4l2_quantization`.
-#[repr(u32)]
-#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, N)]
-pub enum Quantization {
-    #[default]
-    Default = bindings::v4l2_quantization_V4L2_QUANTIZATION_DEFAULT,
-    FullRange = bindings::v4l2_quantization_V4L2_QUANTIZATION_FULL_RANGE,
-    LimRange = bindings::v4l2_quantization_V4L2_QUANTIZATION_LIM_RANGE,
+/// Defines an enum equivalent to a C enum of colorimetry values. Besides the
+/// values of the C enum, an `Unknown` variant keeps the values this crate does
+/// not know about, so they are not mistaken for `Default`.
+macro_rules! colorimetry_enum {
+    (
+        $(#[$attr:meta])*
+        pub enum $name:ident {
+            Default = $default:path,
+            $($variant:ident = $value:path,)*
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+        pub enum $name {
+            #[default]
+            Default,
+            $($variant,)*
+            /// A value unknown to this crate.
+            Unknown(u32),
+        }
+
+        impl $name {
+            /// Returns the variant for `value`, or `None` if it is unknown to this crate.
+            pub fn n(value: u32) -> Option<Self> {
+                match Self::from(value) {
+                    $name::Unknown(_) => None,
+                    v => Some(v),
+                }
+            }
+        }
+
+        impl From<u32> for $name {
+            fn from(value: u32) -> Self {
+                match value {
+                    $default => $name::Default,
+                    $($value => $name::$variant,)*
+                    _ => $name::Unknown(value),
+                }
+            }
+        }
+
+        impl From<$name> for u32 {
+            fn from(value: $name) -> Self {
+                match value {
+                    $name::Default => $default,
+                    $($name::$variant => $value,)*
+                    $name::Unknown(value) => value,
+                }
+            }
+        }
+    };
+}
+
+colorimetry_enum! {
+    /// Equivalent of `enum v4l2_colorspace`.
+    pub enum Colorspace {
+        Default = bindings::v4l2_colorspace_V4L2_COLORSPACE_DEFAULT,
+        Smpte170M = bindings::v4l2_colorspace_V4L2_COLORSPACE_SMPTE170M,
+        Smpte240M = bindings::v4l2_colorspace_V4L2_COLORSPACE_SMPTE240M,
+        Rec709 = bindings::v4l2_colorspace_V4L2_COLORSPACE_REC709,
+        Bt878 = bindings::v4l2_colorspace_V4L2_COLORSPACE_BT878,
+        SystemM470 = bindings::v4l2_colorspace_V4L2_COLORSPACE_470_SYSTEM_M,
+        SystemBG470 = bindings::v4l2_colorspace_V4L2_COLORSPACE_470_SYSTEM_BG,
+        Jpeg = bindings::v4l2_colorspace_V4L2_COLORSPACE_JPEG,
+        Srgb = bindings::v4l2_colorspace_V4L2_COLORSPACE_SRGB,
+        OpRgb = bindings::v4l2_colorspace_V4L2_COLORSPACE_OPRGB,
+        Bt2020 = bindings::v4l2_colorspace_V4L2_COLORSPACE_BT2020,
+        Raw = bindings::v4l2_colorspace_V4L2_COLORSPACE_RAW,
+        DciP3 = bindings::v4l2_colorspace_V4L2_COLORSPACE_DCI_P3,
+    }
+}
+
+colorimetry_enum! {
+    /// Equivalent of `enum v4l2_xfer_func`.
+    pub enum XferFunc {
+        Default = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_DEFAULT,
+        F709 = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_709,
+        Srgb = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_SRGB,
+        OpRgb = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_OPRGB,
+        Smpte240M = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_SMPTE240M,
+        None = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_NONE,
+        DciP3 = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_DCI_P3,
+        Smpte2084 = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_SMPTE2084,
+    }
+}
+
+colorimetry_enum! {
+    /// Equivalent of `enum v4l2_ycbcr_encoding`.
+    ///
+    /// HSV formats use this field for their `enum v4l2_hsv_encoding` instead, whose values
+    /// are returned as `Unknown`.
+    pub enum YCbCrEncoding {
+        Default = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_DEFAULT,
+        E601 = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_601,
+        E709 = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_709,
+        Xv601 = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_XV601,
+        Xv709 = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_XV709,
+        Sycc = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_SYCC,
+        Bt2020 = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_BT2020,
+        Bt2020ConstLum = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_BT2020_CONST_LUM,
+        Smpte240M = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_SMPTE240M,
+    }
+}
+
+colorimetry_enum! {
+    /// Equivalent of `enum v4l2_quantization`.
+    pub enum Quantization {
+        Default = bindings::v4l2_quantization_V4L2_QUANTIZATION_DEFAULT,
+        FullRange = bindings::v4l2_quantization_V4L2_QUANTIZATION_FULL_RANGE,
+        LimRange = bindings::v4l2_quantization_V4L2_QUANTIZATION_LIM_RANGE,
+    }
+}
+
+impl XferFunc {
+    /// Returns the transfer function implied by `colorspace` when the transfer
+    /// function is `Default`. Equivalent of `V4L2_MAP_XFER_FUNC_DEFAULT`.
+    pub fn default_for(colorspace: Colorspace) -> Self {
+        match colorspace {
+            Colorspace::OpRgb => XferFunc::OpRgb,
+            Colorspace::Smpte240M => XferFunc::Smpte240M,
+            Colorspace::DciP3 => XferFunc::DciP3,
+            Colorspace::Raw => XferFunc::None,
+            Colorspace::Srgb | Colorspace::Jpeg => XferFunc::Srgb,
+            _ => XferFunc::F709,
+        }
+    }
+}
+
+impl YCbCrEncoding {
+    /// Returns the Y'CbCr encoding implied by `colorspace` when the encoding
+    /// is `Default`. Equivalent of `V4L2_MAP_YCBCR_ENC_DEFAULT`.
+    pub fn default_for(colorspace: Colorspace) -> Self {
+        match colorspace {
+            Colorspace::Rec709 | Colorspace::DciP3 => YCbCrEncoding::E709,
+            Colorspace::Bt2020 => YCbCrEncoding::Bt2020,
+            Colorspace::Smpte240M => YCbCrEncoding::Smpte240M,
+            _ => YCbCrEncoding::E601,
+        }
+    }
+}
+
+impl Quantization {
+    /// Returns the quantization implied by `colorspace` when the quantization
+    /// is `Default`, for an RGB or HSV pixel format if `is_rgb_or_hsv` is
+    /// true, or a Y'CbCr one otherwise. Equivalent of
+    /// `V4L2_MAP_QUANTIZATION_DEFAULT`.
+    pub fn default_for(is_rgb_or_hsv: bool, colorspace: Colorspace) -> Self {
+        if is_rgb_or_hsv || colorspace == Colorspace::Jpeg {
+            Quantization::FullRange
+        } else {
+            Quantization::LimRange
+        }
+    }
+}
+
+/// Colorimetry-related fields of a format.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Colorimetry {
+    pub colorspace: Colorspace,
+    pub ycbcr_enc: YCbCrEncoding,
+    pub quantization: Quantization,
+    pub xfer_func: XferFunc,
+}
+
+impl Colorimetry {
+    /// Builds the colorimetry from the raw values of the fields of a format.
+    /// Values unknown to this crate are kept as `Unknown`.
+    pub fn from_raw(colorspace: u32, ycbcr_enc: u32, quantization: u32, xfer_func: u32) -> Self {
+        Colorimetry {
+            colorspace: colorspace.into(),
+            ycbcr_enc: ycbcr_enc.into(),
+            quantization: quantization.into(),
+            xfer_func: xfer_func.into(),
+        }
+    }
+
+    /// Returns the effective colorimetry of a frame, i.e. with the `Default`
+    /// Y'CbCr encoding, quantization and transfer function replaced by the
+    /// values implied by the colorspace, as a driver would do.
+    ///
+    /// `is_rgb_or_hsv` tells whether the pixel format is an RGB or HSV one,
+    /// which affects the default quantization. The colorspace itself is left
+    /// untouched, since its default value is driver-specific. Nothing can be
+    /// derived from an `Unknown` colorspace, so the colorimetry is returned
+    /// as is in that case.
+    pub fn effective(&self, is_rgb_or_hsv: bool) -> Self {
+        let colorspace = self.colorspace;
+        if let Colorspace::Unknown(_) = colorspace {
+            return *self;
+        }
+
+        Colorimetry {
+            colorspace,
+            ycbcr_enc: match self.ycbcr_enc {
+                YCbCrEncoding::Default => YCbCrEncoding::default_for(colorspace),
+                ycbcr_enc => ycbcr_enc,
+            },
+            quantization: match self.quantization {
+                Quantization::Default => Quantization::default_for(is_rgb_or_hsv, colorspace),
+                quantization => quantization,
+            },
+            xfer_func: match self.xfer_func {
+                XferFunc::Default => XferFunc::default_for(colorspace),
+                xfer_func => xfer_func,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_diff() {
+        let requested = Format {
+            width: 640,
+            height: 480,
+            pixelformat: b"NV12".into(),
+            plane_fmt: vec![PlaneLayout {
+                sizeimage: 0,
+                bytesperline: 640,
+            }],
+        };
+        let applied = Format {
+            pixelformat: b"YU12".into(),
+            plane_fmt: vec![
+                PlaneLayout {
+                    sizeimage: 307200,
+                    bytesperline: 704,
+                },
+                PlaneLayout::default(),
+            ],
+            ..requested.clone()
+        };
+
+        let diff = FormatDiff::new(&requested, &applied);
+        assert_eq!(
+            diff.changes(),
+            &[
+                FormatChange::PixelFormat {
+                    requested: b"NV12".into(),
+                    applied: b"YU12".into(),
+                },
+                FormatChange::NumPlanes {
+                    requested: 1,
+                    applied: 2,
+                },
+                FormatChange::BytesPerLine {
+                    plane: 0,
+                    requested: 640,
+                    applied: 704,
+                },
+            ]
+        );
+        assert_eq!(
+            diff.to_string(),
+            "pixelformat NV12 -> YU12, 1 -> 2 planes, plane 0 bytesperline 640 -> 704"
+        );
+
+        let diff = FormatDiff::new(&requested, &requested).with_colorimetry(
+            Colorimetry::default(),
+            Colorimetry {
+                colorspace: Colorspace::Srgb,
+                ..Default::default()
+            },
+        );
+        assert_eq!(diff.len(), 1);
+        assert!(diff.contains(FormatField::Colorimetry));
+        assert_eq!(FormatDiff::default().to_string(), "no change");
+    }
+
+    #[test]
+    fn effective_colorimetry() {
+        let colorimetry = Colorimetry::from_raw(
+            bindings::v4l2_colorspace_V4L2_COLORSPACE_REC709,
+            bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_DEFAULT,
+            bindings::v4l2_quantization_V4L2_QUANTIZATION_DEFAULT,
+            // Unknown value.
+            0xff,
+        );
+        assert_eq!(colorimetry.xfer_func, XferFunc::Unknown(0xff));
+
+        assert_eq!(
+            colorimetry.effective(false),
+            Colorimetry {
+                colorspace: Colorspace::Rec709,
+                ycbcr_enc: YCbCrEncoding::E709,
+                quantization: Quantization::LimRange,
+                xfer_func: XferFunc::Unknown(0xff),
+            }
+        );
+        assert_eq!(
+            colorimetry.effective(true).quantization,
+            Quantization::FullRange
+        );
+
+        // Explicit values are kept.
+        let colorimetry = Colorimetry {
+            colorspace: Colorspace::Jpeg,
+            quantization: Quantization::LimRange,
+            ..Default::default()
+        }
+        .effective(false);
+        assert_eq!(colorimetry.quantization, Quantization::LimRange);
+        assert_eq!(colorimetry.ycbcr_enc, YCbCrEncoding::E601);
+        assert_eq!(colorimetry.xfer_func, XferFunc::Srgb);
+
+        // Nothing is derived from an unknown colorspace.
+        let colorimetry = Colorimetry {
+            colorspace: Colorspace::Unknown(0x42),
+            ..Default::default()
+        };
+        assert_eq!(colorimetry.effective(false), colorimetry);
+    }
+
+    #[test]
+    fn colorimetry_enum_conversions() {
+        let srgb = bindings::v4l2_colorspace_V4L2_COLORSPACE_SRGB;
+        assert_eq!(Colorspace::from(srgb), Colorspace::Srgb);
+        assert_eq!(u32::from(Colorspace::Srgb), srgb);
+        assert_eq!(Colorspace::n(srgb), Some(Colorspace::Srgb));
+        assert_eq!(Colorspace::from(0), Colorspace::Default);
+
+        // HSV encodings share the field of the Y'CbCr encoding.
+        let hsv_180 = bindings::v4l2_hsv_encoding_V4L2_HSV_ENC_180;
+        assert_eq!(
+            YCbCrEncoding::from(hsv_180),
+            YCbCrEncoding::Unknown(hsv_180)
+        );
+        assert_eq!(u32::from(YCbCrEncoding::Unknown(hsv_180)), hsv_180);
+        assert_eq!(YCbCrEncoding::n(hsv_180), None);
+    }
 }
@@ -0,0 +1,158 @@
+//! Resolution of the video nodes of media controller devices.
+//!
+//! On boards described by a device tree, the numbering of `/dev/video*` nodes
+//! depends on the order in which drivers are probed, so it can change from one
+//! boot to another. The entities of a media device have stable names though,
+//! and carry the major and minor numbers of their device node, which
+//! [`find_video_node`] uses to locate the right `/dev/video*` node.
+use std::{
+    fs::File,
+    io,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use nix::{errno::Errno, sys::stat::makedev};
+use thiserror::Error;
+
+use crate::{
+    device::{Device, DeviceConfig, DeviceOpenError},
+    ioctl::{self, MediaEntity, OsError, MEDIA_ENT_F_IO_V4L},
+};
+
+#[derive(Debug, Error)]
+pub enum FindVideoNodeError {
+    #[error("error while opening media device")]
+    OpenError(#[source] io::Error),
+    #[error("error while querying media device")]
+    IoctlError(#[source] Errno),
+    #[error("no video entity matching {0:?}")]
+    EntityNotFound(String),
+    #[error("no device node with number {0}:{1}")]
+    DeviceNodeNotFound(u32, u32),
+    #[error("error while scanning device nodes")]
+    ScanError(#[source] io::Error),
+    #[error("error while opening video device")]
+    DeviceOpenError(#[from] DeviceOpenError),
+}
+
+impl OsError for FindVideoNodeError {
+    fn errno(&self) -> Option<Errno> {
+        match self {
+            FindVideoNodeError::OpenError(e) | FindVideoNodeError::ScanError(e) => {
+                e.raw_os_error().map(Errno::from_i32)
+            }
+            FindVideoNodeError::IoctlError(e) => Some(*e),
+            FindVideoNodeError::EntityNotFound(_) | FindVideoNodeError::DeviceNodeNotFound(..) => {
+                None
+            }
+            FindVideoNodeError::DeviceOpenError(e) => e.errno(),
+        }
+    }
+}
+
+ioctl::impl_into_io_error!(FindVideoNodeError);
+
+/// Returns the video entity of `entities` called `name`. If there is none and
+/// `name` is the name of the driver of the media device, returns its first
+/// video entity instead.
+fn select_video_entity<'a>(
+    entities: &'a [MediaEntity],
+    driver: &str,
+    name: &str,
+) -> Option<&'a MediaEntity> {
+    let mut video_entities = entities
+        .iter()
+        .filter(|entity| entity.function == MEDIA_ENT_F_IO_V4L && entity.dev.is_some());
+
+    video_entities
+        .clone()
+        .find(|entity| entity.name == name)
+        .or_else(|| video_entities.next().filter(|_| driver == name))
+}
+
+/// Returns the path of the character device node in `dir` with number
+/// `major:minor`.
+fn find_device_node(dir: &Path, major: u32, minor: u32) -> Result<PathBuf, FindVideoNodeError> {
+    // `dev_t` is not `u64` on all platforms.
+    #[allow(clippy::useless_conversion)]
+    let rdev = u64::from(makedev(major as u64, minor as u64));
+
+    for entry in dir.read_dir().map_err(FindVideoNodeError::ScanError)? {
+        let entry = entry.map_err(FindVideoNodeError::ScanError)?;
+        // Nodes may disappear while we scan, just skip them.
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if metadata.file_type().is_char_device() && metadata.rdev() == rdev {
+            return Ok(entry.path());
+        }
+    }
+
+    Err(FindVideoNodeError::DeviceNodeNotFound(major, minor))
+}
+
+/// Returns the path of the `/dev/video*` node of the video entity called
+/// `name` in the media device at `media_path`.
+///
+/// `name` can also be the name of the driver of the media device, in which
+/// case its first video node is returned. This is convenient for simple
+/// devices like codecs, which only have one video node.
+pub fn find_video_node(media_path: &Path, name: &str) -> Result<PathBuf, FindVideoNodeError> {
+    let media = File::open(media_path).map_err(FindVideoNodeError::OpenError)?;
+    let info = ioctl::media_device_info(&media).map_err(FindVideoNodeError::IoctlError)?;
+    let entities = ioctl::media_enum_entities(&media).map_err(FindVideoNodeError::IoctlError)?;
+
+    let (major, minor) = select_video_entity(&entities, &info.driver, name)
+        .and_then(|entity| entity.dev)
+        .ok_or_else(|| FindVideoNodeError::EntityNotFound(name.into()))?;
+
+    find_device_node(Path::new("/dev"), major, minor)
+}
+
+/// Open the video device of the entity called `name` in the media device at
+/// `media_path`, as found by `find_video_node`.
+pub fn open_video_node(
+    media_path: &Path,
+    name: &str,
+    config: DeviceConfig,
+) -> Result<Device, FindVideoNodeError> {
+    let path = find_video_node(media_path, name)?;
+
+    Ok(Device::open(&path, config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(id: u32, name: &str, function: u32) -> MediaEntity {
+        MediaEntity {
+            id,
+            name: name.into(),
+            function,
+            flags: 0,
+            pads: 1,
+            links: 1,
+            dev: Some((81, id)),
+        }
+    }
+
+    #[test]
+    fn video_entity_selection() {
+        let entities = [
+            entity(1, "sensor", 0x0002_0001),
+            entity(2, "isp-capture", MEDIA_ENT_F_IO_V4L),
+            entity(3, "isp-stats", MEDIA_ENT_F_IO_V4L),
+        ];
+
+        let select = |name| select_video_entity(&entities, "isp", name).map(|e| e.id);
+        assert_eq!(select("isp-stats"), Some(3));
+        assert_eq!(select("isp"), Some(2));
+        // Not a video node.
+        assert_eq!(select("sensor"), None);
+        assert_eq!(select("unknown"), None);
+    }
+}
@@ -38,10 +38,11 @@ pub use userptr::*;
 
 use crate::{
     bindings::{self, v4l2_buffer__bindgen_ty_1, v4l2_plane__bindgen_ty_1},
-    ioctl::{PlaneMapping, QueryBufPlane},
+    ioctl::{self, PlaneMapping, QueryBufPlane},
 };
 use enumn::N;
 use std::fmt::Debug;
+use std::ops::Range;
 use std::os::unix::io::AsFd;
 
 /// All the supported V4L2 memory types.
@@ -123,6 +124,25 @@ pub trait PlaneHandle: Debug + Send + 'static {
 pub trait Mappable: PlaneHandle {
     /// Return a `PlaneMapping` enabling access to the memory of this handle.
     fn map<D: AsFd>(device: &D, plane_info: &QueryBufPlane) -> Option<PlaneMapping>;
+
+    /// Return a `PlaneMapping` enabling access to `range` of the memory of this handle only.
+    ///
+    /// The default implementation maps the memory up to the end of `range`, which is always
+    /// possible but maps more than needed when `range` does not start at zero.
+    fn map_range<D: AsFd>(
+        device: &D,
+        plane_info: &QueryBufPlane,
+        range: Range<u32>,
+    ) -> Option<PlaneMapping> {
+        ioctl::mmap_range(
+            device,
+            plane_info.mem_offset,
+            plane_info.length,
+            0..range.end,
+        )
+        .ok()
+        .map(|mapping| mapping.restrict(range.start as usize, range.end as usize))
+    }
 }
 
 /// Trait for structures providing all the handles of a single buffer.
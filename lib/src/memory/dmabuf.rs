@@ -88,4 +88,11 @@ impl<T: DmaBufSource> DmaBufHandle<T> {
 
         ioctl::mmap(&self.0, 0, len as u32)
     }
+
+    /// Map only `range` of the buffer.
+    pub fn map_range(&self, range: Range<u32>) -> Result<PlaneMapping, ioctl::MmapError> {
+        let len = self.0.len();
+
+        ioctl::mmap_range(&self.0, 0, len as u32, range)
+    }
 }
@@ -1,5 +1,10 @@
 //! Operations specific to UserPtr-type buffers.
 
+use std::alloc::{self, Layout};
+use std::ptr::NonNull;
+
+use thiserror::Error;
+
 use super::*;
 use crate::bindings;
 
@@ -44,6 +49,17 @@ impl Imported for UserPtr {}
 /// USERPTR buffers have the particularity that the `length` field of `struct
 /// v4l2_buffer` must be set before doing a `QBUF` ioctl. This handle struct
 /// also takes care of that.
+///
+/// The memory of a plane must be a single contiguous area, and many drivers
+/// have additional requirements on its alignment: those doing DMA from or to
+/// it usually require its start to be aligned to a cache line or even to a
+/// page, and fail to queue the buffer otherwise. A page-aligned `AlignedBuffer`
+/// satisfies all these requirements, and `check_alignment` can be used to
+/// validate other memory before queueing it.
+///
+/// These checks are advisory: V4L2 has no way to report the alignment a driver
+/// expects, so queueing a buffer does not check it and a misaligned plane is
+/// only rejected by the driver, usually with `EINVAL` or `EFAULT`.
 #[derive(Debug)]
 pub struct UserPtrHandle<T: AsRef<[u8]> + Debug + Send + 'static>(pub T);
 
@@ -59,6 +75,55 @@ impl<T: AsRef<[u8]> + Debug + Send + 'static> AsRef<[u8]> for UserPtrHandle<T> {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum UserPtrAlignmentError {
+    #[error("invalid alignment {0}: must be a power of two")]
+    InvalidAlignment(usize),
+    #[error("address 0x{address:x} is not aligned to {alignment} bytes")]
+    Address { address: usize, alignment: usize },
+    #[error("length {length} is not a multiple of {alignment} bytes")]
+    Length { length: usize, alignment: usize },
+}
+
+impl crate::ioctl::OsError for UserPtrAlignmentError {
+    fn errno(&self) -> Option<nix::errno::Errno> {
+        None
+    }
+}
+
+crate::ioctl::impl_into_io_error!(UserPtrAlignmentError);
+
+impl<T: AsRef<[u8]> + Debug + Send + 'static> UserPtrHandle<T> {
+    /// Check that the memory of this handle starts at an address aligned to
+    /// `alignment` bytes, and that its length is a multiple of it. `alignment`
+    /// must be a power of two.
+    pub fn check_alignment(&self, alignment: usize) -> Result<(), UserPtrAlignmentError> {
+        if !alignment.is_power_of_two() {
+            return Err(UserPtrAlignmentError::InvalidAlignment(alignment));
+        }
+
+        let slice = self.as_ref();
+        let address = slice.as_ptr() as usize;
+
+        if address % alignment != 0 {
+            Err(UserPtrAlignmentError::Address { address, alignment })
+        } else if slice.len() % alignment != 0 {
+            Err(UserPtrAlignmentError::Length {
+                length: slice.len(),
+                alignment,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that the memory of this handle covers whole pages, the most
+    /// stringent requirement drivers have.
+    pub fn check_page_alignment(&self) -> Result<(), UserPtrAlignmentError> {
+        self.check_alignment(crate::ioctl::page_size() as usize)
+    }
+}
+
 impl<T: AsRef<[u8]> + Debug + Send> From<T> for UserPtrHandle<T> {
     fn from(buffer: T) -> Self {
         UserPtrHandle(buffer)
@@ -75,3 +140,194 @@ impl<T: AsRef<[u8]> + Debug + Send + 'static> PlaneHandle for UserPtrHandle<T> {
         plane.length = slice.len() as u32;
     }
 }
+
+/// Alignment used by `AlignedBuffer::cache_aligned`, large enough for the
+/// cache lines of all common architectures.
+pub const CACHE_LINE_SIZE: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AllocError {
+    #[error("invalid alignment {0}: must be a power of two")]
+    InvalidAlignment(usize),
+    #[error("buffer size {0} is too large")]
+    TooLarge(usize),
+    #[error("out of memory")]
+    OutOfMemory,
+}
+
+impl crate::ioctl::OsError for AllocError {
+    fn errno(&self) -> Option<nix::errno::Errno> {
+        match self {
+            AllocError::InvalidAlignment(_) | AllocError::TooLarge(_) => None,
+            AllocError::OutOfMemory => Some(nix::errno::Errno::ENOMEM),
+        }
+    }
+}
+
+crate::ioctl::impl_into_io_error!(AllocError);
+
+/// Contiguous, zero-initialized memory whose start and end are aligned to a
+/// given boundary, suitable to back USERPTR planes.
+///
+/// Since the allocation does not share its first and last blocks with any
+/// other memory, the driver can safely perform cache maintenance on them.
+/// Page-aligned buffers satisfy the requirements of all drivers.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+// The buffer exclusively owns its memory.
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocate a buffer of `len` bytes aligned to `alignment` bytes. The
+    /// allocation is rounded up to a multiple of `alignment`.
+    pub fn new(len: usize, alignment: usize) -> Result<Self, AllocError> {
+        if !alignment.is_power_of_two() {
+            return Err(AllocError::InvalidAlignment(alignment));
+        }
+        // Allocate at least one block, as zero-sized allocations are invalid.
+        let size = len
+            .max(1)
+            .checked_next_multiple_of(alignment)
+            .ok_or(AllocError::TooLarge(len))?;
+        let layout =
+            Layout::from_size_align(size, alignment).map_err(|_| AllocError::TooLarge(len))?;
+        // Safe because the layout has a non-zero size.
+        let ptr =
+            NonNull::new(unsafe { alloc::alloc_zeroed(layout) }).ok_or(AllocError::OutOfMemory)?;
+
+        Ok(AlignedBuffer { ptr, len, layout })
+    }
+
+    /// Allocate a buffer of `len` bytes covering whole pages.
+    pub fn page_aligned(len: usize) -> Result<Self, AllocError> {
+        Self::new(len, crate::ioctl::page_size() as usize)
+    }
+
+    /// Allocate a buffer of `len` bytes aligned to `CACHE_LINE_SIZE`.
+    pub fn cache_aligned(len: usize) -> Result<Self, AllocError> {
+        Self::new(len, CACHE_LINE_SIZE)
+    }
+
+    /// Allocate a page-aligned buffer holding the concatenation of `slices`,
+    /// e.g. to submit data scattered in memory as a single USERPTR plane.
+    pub fn from_slices(slices: &[&[u8]]) -> Result<Self, AllocError> {
+        let mut buffer = Self::page_aligned(slices.iter().map(|slice| slice.len()).sum())?;
+
+        let mut offset = 0;
+        for slice in slices {
+            buffer.as_mut()[offset..offset + slice.len()].copy_from_slice(slice);
+            offset += slice.len();
+        }
+
+        Ok(buffer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the boundary the start and end of the allocation are aligned to.
+    pub fn alignment(&self) -> usize {
+        self.layout.align()
+    }
+}
+
+impl AsRef<[u8]> for AlignedBuffer {
+    fn as_ref(&self) -> &[u8] {
+        // Safe because the allocation is at least `len` bytes long.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl AsMut<[u8]> for AlignedBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        // Safe because the allocation is at least `len` bytes long.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlignedBuffer")
+            .field("ptr", &self.ptr)
+            .field("len", &self.len)
+            .field("alignment", &self.alignment())
+            .finish()
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // Safe because the memory has been allocated with this layout.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligned_buffer() {
+        let page_size = crate::ioctl::page_size() as usize;
+
+        let buffer = AlignedBuffer::from_slices(&[&[1, 2], &[], &[3]]).unwrap();
+        assert_eq!(buffer.as_ref(), &[1, 2, 3]);
+        assert_eq!(buffer.ptr.as_ptr() as usize % page_size, 0);
+        assert_eq!(buffer.layout.size(), page_size);
+
+        let mut cache_aligned = AlignedBuffer::cache_aligned(100).unwrap();
+        assert_eq!(
+            cache_aligned.as_ref().as_ptr() as usize % CACHE_LINE_SIZE,
+            0
+        );
+        assert!(cache_aligned.as_ref().iter().all(|&b| b == 0));
+        cache_aligned.as_mut()[99] = 1;
+        assert!(AlignedBuffer::new(0, 64).unwrap().is_empty());
+        assert_eq!(
+            AlignedBuffer::new(16, 3).unwrap_err(),
+            AllocError::InvalidAlignment(3)
+        );
+        assert_eq!(
+            AlignedBuffer::new(usize::MAX, 64).unwrap_err(),
+            AllocError::TooLarge(usize::MAX)
+        );
+
+        let handle = UserPtrHandle(AlignedBuffer::page_aligned(page_size * 2).unwrap());
+        assert_eq!(handle.check_page_alignment(), Ok(()));
+        let handle = UserPtrHandle(buffer);
+        assert_eq!(
+            handle.check_page_alignment(),
+            Err(UserPtrAlignmentError::Length {
+                length: 3,
+                alignment: page_size,
+            })
+        );
+
+        #[repr(align(16))]
+        struct Aligned([u8; 32]);
+        static DATA: Aligned = Aligned([0u8; 32]);
+        let handle = UserPtrHandle(&DATA.0[1..17]);
+        assert!(matches!(
+            handle.check_alignment(16),
+            Err(UserPtrAlignmentError::Address { .. })
+        ));
+        assert_eq!(
+            handle.check_alignment(0),
+            Err(UserPtrAlignmentError::InvalidAlignment(0))
+        );
+        assert_eq!(
+            handle.check_alignment(24),
+            Err(UserPtrAlignmentError::InvalidAlignment(24))
+        );
+    }
+}
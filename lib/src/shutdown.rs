@@ -0,0 +1,150 @@
+//! Cooperative shutdown of the blocking loops of V4L2 clients.
+//!
+//! A [`ShutdownToken`] is a flag that, once requested, makes every `Poller` it
+//! has been given to fail with `PollError::Interrupted` instead of blocking.
+//! [`ShutdownToken::install_signal_handlers`] requests it upon `SIGINT` or
+//! `SIGTERM`, so command-line tools can exit their main loop on Ctrl-C and
+//! still stop their encoder or decoder properly, draining the buffers that
+//! are being processed.
+//!
+//! ```no_run
+//! # use v4l2r::shutdown::ShutdownToken;
+//! let token = ShutdownToken::install_signal_handlers().expect("cannot handle signals");
+//! while !token.is_requested() {
+//!     // Process frames...
+//! }
+//! ```
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+};
+
+use nix::{
+    libc::c_int,
+    sys::{
+        eventfd::{eventfd, EfdFlags},
+        signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+    },
+    unistd,
+};
+
+struct Inner {
+    requested: AtomicBool,
+    /// Becomes readable once shutdown is requested, and stays so.
+    fd: OwnedFd,
+}
+
+/// Flag telling the blocking loops of the crate to stop waiting.
+///
+/// Clones of a token share the same flag. Shutdown cannot be cancelled once
+/// requested.
+#[derive(Clone)]
+pub struct ShutdownToken(Arc<Inner>);
+
+/// Token requested by the signal handlers.
+static SIGNAL_TOKEN: OnceLock<ShutdownToken> = OnceLock::new();
+
+extern "C" fn handle_signal(_: c_int) {
+    // Only atomic operations and `write` are performed, which are safe to use
+    // in a signal handler.
+    if let Some(token) = SIGNAL_TOKEN.get() {
+        token.request();
+    }
+}
+
+impl ShutdownToken {
+    pub fn new() -> io::Result<Self> {
+        let fd = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
+
+        Ok(ShutdownToken(Arc::new(Inner {
+            requested: AtomicBool::new(false),
+            fd,
+        })))
+    }
+
+    /// Returns the token requested upon `SIGINT` or `SIGTERM`, installing the
+    /// signal handlers the first time this is called.
+    ///
+    /// Only the first signal requests shutdown: the handlers are then reset,
+    /// so a second signal terminates the process as usual if the client does
+    /// not exit.
+    pub fn install_signal_handlers() -> io::Result<Self> {
+        if let Some(token) = SIGNAL_TOKEN.get() {
+            return Ok(token.clone());
+        }
+
+        let token = SIGNAL_TOKEN.get_or_init(|| {
+            // Failing to create an eventfd means we are out of file
+            // descriptors, in which case there is little we can do anyway.
+            ShutdownToken::new().expect("failed to create shutdown token")
+        });
+
+        let action = SigAction::new(
+            SigHandler::Handler(handle_signal),
+            SaFlags::SA_RESETHAND | SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        for signal in [Signal::SIGINT, Signal::SIGTERM] {
+            // SAFETY: the handler only performs async-signal-safe operations.
+            unsafe { sigaction(signal, &action) }?;
+        }
+
+        Ok(token.clone())
+    }
+
+    /// Request shutdown, waking up all the pollers using this token.
+    pub fn request(&self) {
+        self.0.requested.store(true, Ordering::SeqCst);
+        // Nothing can be done if this fails, and the flag is set anyway.
+        let _ = unistd::write(self.0.fd.as_raw_fd(), &1u64.to_ne_bytes());
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.0.requested.load(Ordering::SeqCst)
+    }
+}
+
+/// The file descriptor becomes readable once shutdown is requested.
+impl AsFd for ShutdownToken {
+    fn as_fd(&self) -> BorrowedFd {
+        self.0.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nix::poll::{poll, PollFd, PollFlags};
+
+    use super::*;
+
+    #[test]
+    fn shutdown_token() {
+        let token = ShutdownToken::new().unwrap();
+        let clone = token.clone();
+        let readable = || {
+            let mut fds = [PollFd::new(&token, PollFlags::POLLIN)];
+            poll(&mut fds, 0).unwrap() == 1
+        };
+
+        assert!(!clone.is_requested());
+        assert!(!readable());
+
+        let requester = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            clone.request();
+        });
+        let mut fds = [PollFd::new(&token, PollFlags::POLLIN)];
+        assert_eq!(poll(&mut fds, 1000).unwrap(), 1);
+        requester.join().unwrap();
+
+        assert!(token.is_requested());
+        // The token stays readable for all the pollers using it.
+        assert!(readable());
+    }
+}
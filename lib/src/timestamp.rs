@@ -0,0 +1,191 @@
+//! Association of the CAPTURE buffers of memory-to-memory devices with the
+//! OUTPUT buffers they have been produced from.
+//!
+//! Codec drivers setting `V4L2_BUF_FLAG_TIMESTAMP_COPY` on their buffers copy
+//! the timestamp of each OUTPUT buffer into the CAPTURE buffers produced from
+//! it. [`TimestampTracker`] takes advantage of this to let clients attach data
+//! (e.g. the presentation timestamp of a frame, or any other user data) to the
+//! OUTPUT buffers they queue, and retrieve it when the corresponding CAPTURE
+//! buffer is dequeued by the encoder or decoder:
+//!
+//! ```
+//! # use nix::sys::time::TimeValLike;
+//! # use v4l2r::{bindings, timestamp::TimestampTracker};
+//! let mut tracker = TimestampTracker::new();
+//!
+//! // To be passed to `set_timestamp` when queueing the OUTPUT buffer...
+//! let timestamp = tracker.submit("frame 0");
+//!
+//! // ... and found back in the CAPTURE buffer produced from it.
+//! let capture_timestamp = bindings::timeval {
+//!     tv_sec: timestamp.tv_sec() as _,
+//!     tv_usec: timestamp.tv_usec() as _,
+//! };
+//! assert_eq!(tracker.take(&capture_timestamp), Some("frame 0"));
+//! ```
+use std::collections::BTreeMap;
+
+use nix::sys::time::{TimeVal, TimeValLike};
+
+use crate::{
+    bindings,
+    device::queue::{direction::Capture, dqbuf::DqBuffer},
+    ioctl::BufferFlags,
+    memory::BufferHandles,
+};
+
+/// Returns the value of `timestamp` in microseconds, which is how the kernel
+/// keeps track of it.
+// The fields of `timeval` are not `i64` on 32-bit platforms.
+#[allow(clippy::unnecessary_cast)]
+pub(crate) fn timestamp_key(timestamp: &bindings::timeval) -> i64 {
+    timestamp.tv_sec as i64 * 1_000_000 + timestamp.tv_usec as i64
+}
+
+/// Keeps the data attached to the OUTPUT buffers of a codec until their
+/// CAPTURE buffers are dequeued, using their timestamp as key.
+///
+/// Data whose CAPTURE buffer is never produced, e.g. because the driver
+/// dropped the frame or merged several OUTPUT buffers into a single frame,
+/// remains in the tracker until it is removed by `take_older_than` or `clear`.
+pub struct TimestampTracker<T> {
+    /// Next timestamp returned by `submit`, in microseconds.
+    next: i64,
+    pending: BTreeMap<i64, T>,
+}
+
+impl<T> Default for TimestampTracker<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> TimestampTracker<T> {
+    pub fn new() -> Self {
+        TimestampTracker {
+            // Buffers returned with no data (e.g. the LAST buffer) may have a
+            // zero timestamp, so never hand it out.
+            next: 1,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Attach `data` to a new unique timestamp and return it. The timestamp is
+    /// to be set on the OUTPUT buffer `data` relates to before it is queued.
+    pub fn submit(&mut self, data: T) -> TimeVal {
+        while self.pending.contains_key(&self.next) {
+            self.next += 1;
+        }
+        let timestamp = self.next;
+        self.next += 1;
+        self.pending.insert(timestamp, data);
+
+        TimeVal::microseconds(timestamp)
+    }
+
+    /// Attach `data` to a timestamp chosen by the client, e.g. the actual
+    /// presentation time of the frame. Returns the data previously attached to
+    /// the same timestamp, if any.
+    pub fn insert(&mut self, timestamp: TimeVal, data: T) -> Option<T> {
+        self.pending.insert(timestamp.num_microseconds(), data)
+    }
+
+    /// Returns the data attached to `timestamp` without removing it, useful
+    /// when several CAPTURE buffers are produced from the same OUTPUT buffer.
+    pub fn get(&self, timestamp: &bindings::timeval) -> Option<&T> {
+        self.pending.get(&timestamp_key(timestamp))
+    }
+
+    /// Remove and return the data attached to `timestamp`.
+    pub fn take(&mut self, timestamp: &bindings::timeval) -> Option<T> {
+        self.pending.remove(&timestamp_key(timestamp))
+    }
+
+    /// Remove and return the data attached to the timestamp of the dequeued
+    /// CAPTURE `buffer`.
+    ///
+    /// Returns `None` if the driver does not copy the timestamps of the
+    /// OUTPUT buffers into the CAPTURE buffers, as the association cannot be
+    /// made then.
+    pub fn take_for_buffer<P: BufferHandles>(
+        &mut self,
+        buffer: &DqBuffer<Capture, P>,
+    ) -> Option<T> {
+        if !buffer.flags().contains(BufferFlags::TIMESTAMP_COPY) {
+            return None;
+        }
+
+        self.take(&buffer.timestamp())
+    }
+
+    /// Remove and return the data attached to timestamps older than
+    /// `timestamp`, in timestamp order.
+    ///
+    /// Decoders produce frames in display order, so this must only be used
+    /// with timestamps that increase in display order, and once the frame of
+    /// `timestamp` is known to be the next one to display.
+    pub fn take_older_than(&mut self, timestamp: &bindings::timeval) -> Vec<(TimeVal, T)> {
+        let newer = self.pending.split_off(&timestamp_key(timestamp));
+        let older = std::mem::replace(&mut self.pending, newer);
+
+        older
+            .into_iter()
+            .map(|(timestamp, data)| (TimeVal::microseconds(timestamp), data))
+            .collect()
+    }
+
+    /// Returns the number of timestamps whose data has not been taken yet.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Remove all the attached data, e.g. after a flush or seek where the
+    /// queued OUTPUT buffers have been canceled.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeval(timestamp: TimeVal) -> bindings::timeval {
+        bindings::timeval {
+            tv_sec: timestamp.tv_sec() as _,
+            tv_usec: timestamp.tv_usec() as _,
+        }
+    }
+
+    #[test]
+    fn timestamp_tracker() {
+        let mut tracker = TimestampTracker::new();
+
+        let t0 = timeval(tracker.submit(0));
+        let t1 = timeval(tracker.submit(1));
+        assert_ne!(timestamp_key(&t0), 0);
+        assert_ne!(timestamp_key(&t0), timestamp_key(&t1));
+        // Submitted timestamps do not collide with client-chosen ones.
+        assert_eq!(tracker.insert(TimeVal::microseconds(3), 3), None);
+        let t4 = timeval(tracker.submit(4));
+        assert_eq!(timestamp_key(&t4), 4);
+        assert_eq!(tracker.len(), 4);
+
+        assert_eq!(tracker.get(&t1), Some(&1));
+        assert_eq!(tracker.take(&t1), Some(1));
+        assert_eq!(tracker.take(&t1), None);
+
+        let older = tracker.take_older_than(&t4);
+        assert_eq!(
+            older.into_iter().map(|(_, data)| data).collect::<Vec<_>>(),
+            vec![0, 3]
+        );
+        assert_eq!(tracker.len(), 1);
+        assert_eq!(tracker.take(&timeval(TimeVal::microseconds(4))), Some(4));
+        assert!(tracker.is_empty());
+    }
+}
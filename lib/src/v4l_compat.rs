@@ -0,0 +1,143 @@
+//! Conversions between the types of this crate and their equivalents in the
+//! [`v4l`](https://crates.io/crates/v4l) crate.
+//!
+//! This module is only available with the `v4l` feature. It allows projects
+//! using both crates, e.g. during a migration, to pass formats and device
+//! information from one to the other.
+//!
+//! `v4l::Format` only supports single-planar formats, so converting a
+//! multi-planar `Format` into it fails with
+//! `FormatConversionError::TooManyPlanes`.
+use std::convert::TryFrom;
+
+use crate::ioctl::{Capabilities, Capability};
+use crate::{Format, FormatConversionError, PixelFormat, PlaneLayout};
+
+impl From<PixelFormat> for v4l::FourCC {
+    fn from(pixelformat: PixelFormat) -> Self {
+        v4l::FourCC::new(&pixelformat.to_fourcc())
+    }
+}
+
+impl From<v4l::FourCC> for PixelFormat {
+    fn from(fourcc: v4l::FourCC) -> Self {
+        PixelFormat::from_fourcc(&fourcc.repr)
+    }
+}
+
+impl TryFrom<&Format> for v4l::Format {
+    type Error = FormatConversionError;
+
+    fn try_from(format: &Format) -> Result<Self, Self::Error> {
+        if format.plane_fmt.len() > 1 {
+            return Err(FormatConversionError::TooManyPlanes(format.plane_fmt.len()));
+        }
+
+        let mut v4l_format =
+            v4l::Format::new(format.width, format.height, format.pixelformat.into());
+        if let Some(plane) = format.plane_fmt.first() {
+            v4l_format.stride = plane.bytesperline;
+            v4l_format.size = plane.sizeimage;
+        }
+
+        Ok(v4l_format)
+    }
+}
+
+impl TryFrom<Format> for v4l::Format {
+    type Error = FormatConversionError;
+
+    fn try_from(format: Format) -> Result<Self, Self::Error> {
+        v4l::Format::try_from(&format)
+    }
+}
+
+impl From<&v4l::Format> for Format {
+    fn from(format: &v4l::Format) -> Self {
+        Format {
+            width: format.width,
+            height: format.height,
+            pixelformat: format.fourcc.into(),
+            // A zero size means that the layout is not known yet.
+            plane_fmt: if format.size > 0 {
+                vec![PlaneLayout {
+                    sizeimage: format.size,
+                    bytesperline: format.stride,
+                }]
+            } else {
+                Vec::new()
+            },
+        }
+    }
+}
+
+impl From<v4l::Format> for Format {
+    fn from(format: v4l::Format) -> Self {
+        Format::from(&format)
+    }
+}
+
+/// `v4l::Capabilities` only keeps the capabilities of the opened node, so
+/// they are taken from `Capability::device_caps()`.
+impl From<&Capability> for v4l::Capabilities {
+    fn from(cap: &Capability) -> Self {
+        v4l::Capabilities {
+            driver: cap.driver.clone(),
+            card: cap.card.clone(),
+            bus: cap.bus_info.clone(),
+            version: (
+                ((cap.version >> 16) & 0xff) as u8,
+                ((cap.version >> 8) & 0xff) as u8,
+                (cap.version & 0xff) as u8,
+            ),
+            capabilities: v4l::capability::Flags::from(cap.device_caps().bits()),
+        }
+    }
+}
+
+impl From<&v4l::Capabilities> for Capability {
+    fn from(cap: &v4l::Capabilities) -> Self {
+        let (major, minor, patch) = cap.version;
+        let caps = Capabilities::from_bits_truncate(u32::from(cap.capabilities));
+
+        Capability {
+            driver: cap.driver.clone(),
+            card: cap.card.clone(),
+            bus_info: cap.bus.clone(),
+            version: ((major as u32) << 16) | ((minor as u32) << 8) | patch as u32,
+            capabilities: caps | Capabilities::DEVICE_CAPS,
+            device_caps: Some(caps),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use crate::{Format, FormatConversionError, PixelFormat, PlaneLayout};
+
+    #[test]
+    fn format_round_trip() {
+        let mut format = Format::from((b"YUYV", (640, 480)));
+        format.plane_fmt = vec![PlaneLayout {
+            sizeimage: 640 * 480 * 2,
+            bytesperline: 1280,
+        }];
+
+        let v4l_format = v4l::Format::try_from(&format).unwrap();
+        assert_eq!(v4l_format.fourcc, v4l::FourCC::new(b"YUYV"));
+        assert_eq!(v4l_format.stride, 1280);
+        assert_eq!(Format::from(&v4l_format), format);
+        assert_eq!(
+            PixelFormat::from(v4l_format.fourcc),
+            PixelFormat::from_fourcc(b"YUYV")
+        );
+
+        format.plane_fmt.push(format.plane_fmt[0].clone());
+        assert_eq!(
+            v4l::Format::try_from(&format).unwrap_err(),
+            FormatConversionError::TooManyPlanes(2)
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! Formats and data of the VBI (Vertical Blanking Interval) queues, used to extract teletext,
+//! closed captions or wide-screen signaling from analog video.
+//!
+//! Raw VBI queues (`QueueType::VbiCapture`) return the samples of the VBI lines, which must be
+//! decoded by the application. Sliced VBI queues (`QueueType::SlicedVbiCapture`) return the
+//! data already decoded by the hardware, as a sequence of `v4l2_sliced_vbi_data` that can be
+//! parsed with `sliced_vbi_data`.
+//!
+//! Both kinds of queues are obtained with `Queue::get_vbi_capture_queue` and
+//! `Queue::get_sliced_vbi_capture_queue`, and are configured with `Queue::set_vbi_format` and
+//! `Queue::set_sliced_vbi_format` respectively. Buffers then work as for video queues.
+use std::convert::TryFrom;
+
+use bitflags::bitflags;
+
+use crate::bindings;
+use crate::bindings::v4l2_format;
+use crate::FormatConversionError;
+use crate::PixelFormat;
+use crate::QueueType;
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct VbiFlags: u32 {
+        /// The hardware is not synchronized to the video signal, so the lines of the two fields
+        /// may be swapped.
+        const UNSYNC = bindings::V4L2_VBI_UNSYNC;
+        /// The lines of the two fields are interlaced instead of stored one field after the other.
+        const INTERLACED = bindings::V4L2_VBI_INTERLACED;
+    }
+
+    /// Data services that can be decoded by a sliced VBI queue.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct SlicedVbiServices: u32 {
+        const TELETEXT_B = bindings::V4L2_SLICED_TELETEXT_B;
+        const VPS = bindings::V4L2_SLICED_VPS;
+        const CAPTION_525 = bindings::V4L2_SLICED_CAPTION_525;
+        const WSS_625 = bindings::V4L2_SLICED_WSS_625;
+    }
+}
+
+impl SlicedVbiServices {
+    /// All the services of 525-line (NTSC) systems.
+    pub const VBI_525: Self = Self::CAPTION_525;
+    /// All the services of 625-line (PAL/SECAM) systems.
+    pub const VBI_625: Self = Self::TELETEXT_B.union(Self::VPS).union(Self::WSS_625);
+}
+
+/// Safe variant of `struct v4l2_vbi_format`, the format of raw VBI queues.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VbiFormat {
+    /// Samples per second.
+    pub sampling_rate: u32,
+    /// Number of samples between the horizontal sync and the first sample of a line.
+    pub offset: u32,
+    pub samples_per_line: u32,
+    /// Format of the samples. Only `GREY` (one byte per sample) is defined by V4L2.
+    pub sample_format: PixelFormat,
+    /// Number of the first line captured in each field.
+    pub start: [i32; 2],
+    /// Number of lines captured in each field.
+    pub count: [u32; 2],
+    pub flags: VbiFlags,
+}
+
+impl VbiFormat {
+    /// Returns the size in bytes of the data of a frame with 8-bit samples.
+    pub fn frame_size(&self) -> usize {
+        self.samples_per_line as usize * (self.count[0] + self.count[1]) as usize
+    }
+}
+
+impl TryFrom<v4l2_format> for VbiFormat {
+    type Error = FormatConversionError;
+
+    fn try_from(fmt: v4l2_format) -> Result<Self, Self::Error> {
+        match QueueType::n(fmt.type_) {
+            Some(QueueType::VbiCapture) | Some(QueueType::VbiOutput) => {
+                let vbi = unsafe { &fmt.fmt.vbi };
+                Ok(VbiFormat {
+                    sampling_rate: vbi.sampling_rate,
+                    offset: vbi.offset,
+                    samples_per_line: vbi.samples_per_line,
+                    sample_format: PixelFormat::from(vbi.sample_format),
+                    start: vbi.start,
+                    count: vbi.count,
+                    flags: VbiFlags::from_bits_truncate(vbi.flags),
+                })
+            }
+            _ => Err(FormatConversionError::InvalidBufferType(fmt.type_)),
+        }
+    }
+}
+
+impl TryFrom<(QueueType, &VbiFormat)> for v4l2_format {
+    type Error = FormatConversionError;
+
+    fn try_from((queue, format): (QueueType, &VbiFormat)) -> Result<Self, Self::Error> {
+        match queue {
+            QueueType::VbiCapture | QueueType::VbiOutput => Ok(v4l2_format {
+                type_: queue as u32,
+                fmt: bindings::v4l2_format__bindgen_ty_1 {
+                    vbi: bindings::v4l2_vbi_format {
+                        sampling_rate: format.sampling_rate,
+                        offset: format.offset,
+                        samples_per_line: format.samples_per_line,
+                        sample_format: format.sample_format.into(),
+                        start: format.start,
+                        count: format.count,
+                        flags: format.flags.bits(),
+                        ..Default::default()
+                    },
+                },
+            }),
+            _ => Err(FormatConversionError::InvalidBufferType(queue as u32)),
+        }
+    }
+}
+
+/// Safe variant of `struct v4l2_sliced_vbi_format`, the format of sliced VBI queues.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SlicedVbiFormat {
+    /// Services to decode. If set when setting the format, the driver selects the lines to
+    /// decode them from and `service_lines` is ignored.
+    pub service_set: SlicedVbiServices,
+    /// Services to decode from each line of each field. Index 0 of each field is unused.
+    pub service_lines: [[SlicedVbiServices; 24]; 2],
+    /// Size of the buffers, in bytes.
+    pub io_size: u32,
+}
+
+impl TryFrom<v4l2_format> for SlicedVbiFormat {
+    type Error = FormatConversionError;
+
+    fn try_from(fmt: v4l2_format) -> Result<Self, Self::Error> {
+        match QueueType::n(fmt.type_) {
+            Some(QueueType::SlicedVbiCapture) | Some(QueueType::SlicedVbiOutput) => {
+                let sliced = unsafe { &fmt.fmt.sliced };
+                let mut service_lines = [[SlicedVbiServices::empty(); 24]; 2];
+                for (field, raw_field) in service_lines.iter_mut().zip(sliced.service_lines.iter())
+                {
+                    for (line, raw_line) in field.iter_mut().zip(raw_field.iter()) {
+                        *line = SlicedVbiServices::from_bits_truncate(*raw_line as u32);
+                    }
+                }
+
+                Ok(SlicedVbiFormat {
+                    service_set: SlicedVbiServices::from_bits_truncate(sliced.service_set as u32),
+                    service_lines,
+                    io_size: sliced.io_size,
+                })
+            }
+            _ => Err(FormatConversionError::InvalidBufferType(fmt.type_)),
+        }
+    }
+}
+
+impl TryFrom<(QueueType, &SlicedVbiFormat)> for v4l2_format {
+    type Error = FormatConversionError;
+
+    fn try_from((queue, format): (QueueType, &SlicedVbiFormat)) -> Result<Self, Self::Error> {
+        match queue {
+            QueueType::SlicedVbiCapture | QueueType::SlicedVbiOutput => {
+                let mut sliced = bindings::v4l2_sliced_vbi_format {
+                    service_set: format.service_set.bits() as u16,
+                    io_size: format.io_size,
+                    ..Default::default()
+                };
+                for (raw_field, field) in sliced
+                    .service_lines
+                    .iter_mut()
+                    .zip(format.service_lines.iter())
+                {
+                    for (raw_line, line) in raw_field.iter_mut().zip(field.iter()) {
+                        *raw_line = line.bits() as u16;
+                    }
+                }
+
+                Ok(v4l2_format {
+                    type_: queue as u32,
+                    fmt: bindings::v4l2_format__bindgen_ty_1 { sliced },
+                })
+            }
+            _ => Err(FormatConversionError::InvalidBufferType(queue as u32)),
+        }
+    }
+}
+
+/// Data of one line decoded by a sliced VBI queue, i.e. a `struct v4l2_sliced_vbi_data`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlicedVbiData {
+    /// Service the data has been decoded as.
+    pub service: SlicedVbiServices,
+    /// Field of the line, 0 for the first one and 1 for the second one.
+    pub field: u32,
+    /// Number of the line in its field.
+    pub line: u32,
+    /// Decoded data, which length depends on the service.
+    pub data: [u8; 48],
+}
+
+const SLICED_VBI_DATA_SIZE: usize = std::mem::size_of::<bindings::v4l2_sliced_vbi_data>();
+
+/// Returns an iterator over the lines of data of a buffer dequeued from a sliced VBI queue.
+///
+/// `buffer` is the used part of the buffer. Entries with no service, which drivers use to signal
+/// lines that could not be decoded, are skipped.
+pub fn sliced_vbi_data(buffer: &[u8]) -> impl Iterator<Item = SlicedVbiData> + '_ {
+    let word = |entry: &[u8], i: usize| {
+        u32::from_ne_bytes([entry[i], entry[i + 1], entry[i + 2], entry[i + 3]])
+    };
+
+    buffer
+        .chunks_exact(SLICED_VBI_DATA_SIZE)
+        .map(move |entry| {
+            let mut data = [0u8; 48];
+            data.copy_from_slice(&entry[16..]);
+            SlicedVbiData {
+                service: SlicedVbiServices::from_bits_truncate(word(entry, 0)),
+                field: word(entry, 4),
+                line: word(entry, 8),
+                data,
+            }
+        })
+        .filter(|data| !data.service.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    #[test]
+    fn vbi_format_round_trip() {
+        let format = VbiFormat {
+            sampling_rate: 27_000_000,
+            offset: 248,
+            samples_per_line: 1440,
+            sample_format: PixelFormat::from_fourcc(b"GREY"),
+            start: [6, 318],
+            count: [17, 17],
+            flags: VbiFlags::empty(),
+        };
+        assert_eq!(format.frame_size(), 1440 * 34);
+
+        let fmt = v4l2_format::try_from((QueueType::VbiCapture, &format)).unwrap();
+        assert_eq!(VbiFormat::try_from(fmt).unwrap(), format);
+        assert_eq!(
+            SlicedVbiFormat::try_from(fmt).unwrap_err(),
+            FormatConversionError::InvalidBufferType(QueueType::VbiCapture as u32)
+        );
+        assert!(v4l2_format::try_from((QueueType::VideoCapture, &format)).is_err());
+    }
+
+    #[test]
+    fn sliced_vbi_format_round_trip() {
+        let mut format = SlicedVbiFormat {
+            service_set: SlicedVbiServices::VBI_625,
+            io_size: 2 * SLICED_VBI_DATA_SIZE as u32,
+            ..Default::default()
+        };
+        format.service_lines[0][23] = SlicedVbiServices::WSS_625;
+
+        let fmt = v4l2_format::try_from((QueueType::SlicedVbiCapture, &format)).unwrap();
+        assert_eq!(SlicedVbiFormat::try_from(fmt).unwrap(), format);
+    }
+
+    #[test]
+    fn parse_sliced_vbi_data() {
+        let mut buffer = Vec::new();
+        for (service, line) in [(bindings::V4L2_SLICED_WSS_625, 23u32), (0, 0)] {
+            buffer.extend_from_slice(&service.to_ne_bytes());
+            buffer.extend_from_slice(&0u32.to_ne_bytes());
+            buffer.extend_from_slice(&line.to_ne_bytes());
+            buffer.extend_from_slice(&0u32.to_ne_bytes());
+            buffer.extend_from_slice(&[0x42; 48]);
+        }
+
+        let data: Vec<_> = sliced_vbi_data(&buffer).collect();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].service, SlicedVbiServices::WSS_625);
+        assert_eq!(data[0].line, 23);
+        assert_eq!(data[0].data, [0x42; 48]);
+    }
+}
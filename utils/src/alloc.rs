@@ -0,0 +1,61 @@
+//! Allocation of host memory suitable for USERPTR buffers.
+//!
+//! Drivers doing DMA from or to USERPTR memory often require it to be aligned
+//! to a cache line or to a page, and to not share these with other data as
+//! they perform cache maintenance on them. [`AlignedBuffer`], provided by
+//! `v4l2r` and re-exported here, allocates such memory, and can be used
+//! directly as the backing of a `UserPtrHandle`.
+use anyhow::Result;
+use v4l2r::{memory::UserPtrHandle, Format};
+
+pub use v4l2r::memory::{AlignedBuffer, AllocError, CACHE_LINE_SIZE};
+
+/// Allocate page-aligned USERPTR handles for `nb_buffers` buffers of `format`,
+/// e.g. to be passed to `PooledHandlesProvider::new`.
+pub fn alloc_userptr_buffers(
+    format: &Format,
+    nb_buffers: usize,
+) -> Result<Vec<Vec<UserPtrHandle<AlignedBuffer>>>> {
+    (0..nb_buffers)
+        .map(|_| {
+            format
+                .plane_fmt
+                .iter()
+                .map(|plane| {
+                    Ok(UserPtrHandle(AlignedBuffer::page_aligned(
+                        plane.sizeimage as usize,
+                    )?))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use v4l2r::device::queue::handles_provider::PooledHandlesProvider;
+
+    use super::*;
+
+    #[test]
+    fn userptr_buffers() {
+        let format = Format {
+            width: 64,
+            height: 64,
+            pixelformat: b"GREY".into(),
+            plane_fmt: vec![v4l2r::PlaneLayout {
+                sizeimage: 64 * 64,
+                bytesperline: 64,
+            }],
+        };
+        let buffers = alloc_userptr_buffers(&format, 2).unwrap();
+        assert_eq!(buffers.len(), 2);
+        for planes in &buffers {
+            assert_eq!(planes[0].as_ref().len(), 64 * 64);
+            assert!(planes[0].check_page_alignment().is_ok());
+        }
+
+        let pool = PooledHandlesProvider::new(buffers);
+        assert_eq!(pool.num_idle(), 2);
+    }
+}
@@ -0,0 +1,239 @@
+use thiserror::Error;
+use v4l2r::{Format, PixelFormat};
+
+#[derive(Debug, Error)]
+pub enum NewDebayerError {
+    #[error("not a raw Bayer pixel format")]
+    UnsupportedFormat,
+    #[error("frame must be at least 2x2 pixels")]
+    TooSmall,
+    #[error("invalid stride")]
+    InvalidStride,
+}
+
+#[derive(Debug, Error)]
+pub enum DebayerError {
+    #[error("source buffer is too small")]
+    SourceTooSmall,
+    #[error("destination buffer is too small")]
+    DestinationTooSmall,
+}
+
+/// Color of a Bayer sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Red,
+    Green,
+    Blue,
+}
+
+/// How Bayer samples are stored in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Packing {
+    /// One byte per sample.
+    Bits8,
+    /// One little-endian 16-bit word per sample, of which the `bits` lower
+    /// bits are used.
+    Unpacked { bits: u32 },
+    /// MIPI CSI-2 10-bit packing: 4 samples in 5 bytes, the first 4 holding
+    /// the 8 most significant bits of each sample.
+    Mipi10,
+    /// MIPI CSI-2 12-bit packing: 2 samples in 3 bytes, the first 2 holding
+    /// the 8 most significant bits of each sample.
+    Mipi12,
+}
+
+fn bayer_layout(pixelformat: PixelFormat) -> Option<([Color; 4], Packing)> {
+    use Color::*;
+
+    const BGGR: [Color; 4] = [Blue, Green, Green, Red];
+    const GBRG: [Color; 4] = [Green, Blue, Red, Green];
+    const GRBG: [Color; 4] = [Green, Red, Blue, Green];
+    const RGGB: [Color; 4] = [Red, Green, Green, Blue];
+
+    let layout = match &pixelformat.to_fourcc() {
+        b"BA81" => (BGGR, Packing::Bits8),
+        b"GBRG" => (GBRG, Packing::Bits8),
+        b"GRBG" => (GRBG, Packing::Bits8),
+        b"RGGB" => (RGGB, Packing::Bits8),
+        b"BG10" => (BGGR, Packing::Unpacked { bits: 10 }),
+        b"GB10" => (GBRG, Packing::Unpacked { bits: 10 }),
+        b"BA10" => (GRBG, Packing::Unpacked { bits: 10 }),
+        b"RG10" => (RGGB, Packing::Unpacked { bits: 10 }),
+        b"pBAA" => (BGGR, Packing::Mipi10),
+        b"pGAA" => (GBRG, Packing::Mipi10),
+        b"pgAA" => (GRBG, Packing::Mipi10),
+        b"pRAA" => (RGGB, Packing::Mipi10),
+        b"BG12" => (BGGR, Packing::Unpacked { bits: 12 }),
+        b"GB12" => (GBRG, Packing::Unpacked { bits: 12 }),
+        b"BA12" => (GRBG, Packing::Unpacked { bits: 12 }),
+        b"RG12" => (RGGB, Packing::Unpacked { bits: 12 }),
+        b"pBCC" => (BGGR, Packing::Mipi12),
+        b"pGCC" => (GBRG, Packing::Mipi12),
+        b"pgCC" => (GRBG, Packing::Mipi12),
+        b"pRCC" => (RGGB, Packing::Mipi12),
+        b"BYR2" => (BGGR, Packing::Unpacked { bits: 16 }),
+        b"GB16" => (GBRG, Packing::Unpacked { bits: 16 }),
+        b"GR16" => (GRBG, Packing::Unpacked { bits: 16 }),
+        b"RG16" => (RGGB, Packing::Unpacked { bits: 16 }),
+        _ => return None,
+    };
+
+    Some(layout)
+}
+
+/// Converts raw Bayer frames into RGB3 (8 bits per component) frames.
+///
+/// The conversion is a simple nearest-neighbor interpolation over each 2x2
+/// block of the Bayer pattern, which is good enough for previews or to check
+/// that a raw sensor is capturing something sensible, but not for quality
+/// image processing. Samples of more than 8 bits are truncated to their 8
+/// most significant bits.
+pub struct Debayer {
+    /// Colors of the samples of each 2x2 block, in reading order.
+    pattern: [Color; 4],
+    packing: Packing,
+    width: usize,
+    height: usize,
+    stride: usize,
+    /// Number of bytes used by the samples of one line.
+    line_size: usize,
+}
+
+impl Debayer {
+    /// Create a converter for frames of `format`, which must be one of the raw
+    /// Bayer formats. The stride of the source frames is taken from
+    /// `format.plane_fmt` if set, or computed from the pixel format otherwise.
+    pub fn from_format(format: &Format) -> Result<Self, NewDebayerError> {
+        let (pattern, packing) =
+            bayer_layout(format.pixelformat).ok_or(NewDebayerError::UnsupportedFormat)?;
+        if format.width < 2 || format.height < 2 {
+            return Err(NewDebayerError::TooSmall);
+        }
+
+        let line_size = format
+            .pixelformat
+            .info()
+            .and_then(|info| info.color_planes.first())
+            .ok_or(NewDebayerError::UnsupportedFormat)?
+            .bytesperline(format.width)
+            .ok_or(NewDebayerError::InvalidStride)?;
+        let stride = format
+            .plane_fmt
+            .first()
+            .map(|plane| plane.bytesperline)
+            .unwrap_or(line_size);
+        if stride < line_size {
+            return Err(NewDebayerError::InvalidStride);
+        }
+
+        Ok(Debayer {
+            pattern,
+            packing,
+            width: format.width as usize,
+            height: format.height as usize,
+            stride: stride as usize,
+            line_size: line_size as usize,
+        })
+    }
+
+    /// Returns the size of the RGB3 frames produced by the conversion.
+    pub fn rgb_frame_size(&self) -> usize {
+        self.width * self.height * 3
+    }
+
+    /// Returns the 8 most significant bits of sample `x` of `line`.
+    fn sample(&self, line: &[u8], x: usize) -> u8 {
+        match self.packing {
+            Packing::Bits8 => line[x],
+            Packing::Unpacked { bits } => {
+                let sample = u16::from_le_bytes([line[x * 2], line[x * 2 + 1]]);
+                (sample >> (bits - 8)) as u8
+            }
+            Packing::Mipi10 => line[x / 4 * 5 + x % 4],
+            Packing::Mipi12 => line[x / 2 * 3 + x % 2],
+        }
+    }
+
+    /// Converts the Bayer frame `src` into the RGB3 frame `dst`, which lines
+    /// are tightly packed.
+    pub fn to_rgb(&self, src: &[u8], dst: &mut [u8]) -> Result<(), DebayerError> {
+        let min_src_size = self.stride * (self.height - 1) + self.line_size;
+        if src.len() < min_src_size {
+            return Err(DebayerError::SourceTooSmall);
+        }
+        if dst.len() < self.rgb_frame_size() {
+            return Err(DebayerError::DestinationTooSmall);
+        }
+
+        let lines: Vec<&[u8]> = src.chunks(self.stride).take(self.height).collect();
+        // Odd trailing lines and columns reuse the last full 2x2 block.
+        let block_origin = |coord: usize, size: usize| (coord & !1).min((size - 2) & !1);
+
+        for (y, dst_line) in dst
+            .chunks_exact_mut(self.width * 3)
+            .take(self.height)
+            .enumerate()
+        {
+            let by = block_origin(y, self.height);
+            for (x, pixel) in dst_line.chunks_exact_mut(3).enumerate() {
+                let bx = block_origin(x, self.width);
+
+                let (mut r, mut g, mut b) = (0u16, 0u16, 0u16);
+                for (i, color) in self.pattern.iter().enumerate() {
+                    let sample = self.sample(lines[by + i / 2], bx + i % 2) as u16;
+                    match color {
+                        Color::Red => r = sample,
+                        // Each block has two green samples.
+                        Color::Green => g += sample,
+                        Color::Blue => b = sample,
+                    }
+                }
+                pixel.copy_from_slice(&[r as u8, (g / 2) as u8, b as u8]);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use v4l2r::Format;
+
+    use super::*;
+
+    #[test]
+    fn debayer_rggb() {
+        let debayer = Debayer::from_format(&Format::from((b"RGGB", (3, 2)))).unwrap();
+        #[rustfmt::skip]
+        let src = [
+            200, 100, 50,
+            60, 10, 70,
+        ];
+        let mut dst = vec![0u8; debayer.rgb_frame_size()];
+        debayer.to_rgb(&src, &mut dst).unwrap();
+
+        assert_eq!(&dst[0..3], &[200, 80, 10]);
+        // The odd last column reuses the block of the first two ones.
+        assert_eq!(&dst[6..9], &[200, 80, 10]);
+        assert_eq!(&dst[9..12], &[200, 80, 10]);
+    }
+
+    #[test]
+    fn debayer_mipi10() {
+        let debayer = Debayer::from_format(&Format::from((b"pBAA", (4, 2)))).unwrap();
+        #[rustfmt::skip]
+        let src = [
+            // B G B G, then the 2 LSBs of each sample.
+            10, 20, 30, 40, 0xff,
+            // G R G R
+            60, 70, 80, 90, 0xff,
+        ];
+        let mut dst = vec![0u8; debayer.rgb_frame_size()];
+        debayer.to_rgb(&src, &mut dst).unwrap();
+
+        assert_eq!(&dst[0..3], &[70, 40, 10]);
+        assert_eq!(&dst[6..9], &[90, 60, 30]);
+    }
+}
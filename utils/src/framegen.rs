@@ -1,66 +1,250 @@
 use thiserror::Error;
+use v4l2r::Format;
+
 #[derive(Debug, Error)]
 pub enum NewFrameGeneratorError {
     #[error("invalid stride")]
     InvalidStride,
+    #[error("unsupported pixel format")]
+    UnsupportedFormat,
+    #[error("format planes do not match the pixel format")]
+    InvalidPlanes,
 }
 
 #[derive(Debug, Error)]
 pub enum GenerateFrameError {
     #[error("provided buffer is too small")]
     BufferTooSmall,
+    #[error("expected {0} planes, got {1}")]
+    WrongNumberOfPlanes(usize, usize),
 }
 
-pub struct FrameGenerator {
+/// Layout of a color plane within the memory planes of a frame.
+struct ColorPlane {
+    /// Index of the memory plane this color plane is stored into.
+    mem_plane: usize,
+    /// Offset of the color plane from the start of its memory plane.
+    offset: usize,
+    stride: usize,
+    /// Size in bytes of one sample.
+    sample_size: usize,
+    /// Number of samples per line.
     width: usize,
+    /// Number of lines.
     height: usize,
-    stride: usize,
+}
+
+pub struct FrameGenerator {
+    planes: Vec<ColorPlane>,
+    /// Number of bytes written into each memory plane.
+    mem_plane_sizes: Vec<usize>,
     step: u32,
 }
 
 impl FrameGenerator {
+    /// Create a generator for single-planar RGB3 frames with lines of `stride`
+    /// bytes.
     pub fn new(width: usize, height: usize, stride: usize) -> Result<Self, NewFrameGeneratorError> {
         if stride < width * 3 {
             return Err(NewFrameGeneratorError::InvalidStride);
         }
 
         Ok(FrameGenerator {
-            width,
-            height,
-            stride,
+            planes: vec![ColorPlane {
+                mem_plane: 0,
+                offset: 0,
+                stride,
+                sample_size: 3,
+                width,
+                height,
+            }],
+            mem_plane_sizes: vec![stride * height],
+            step: 0,
+        })
+    }
+
+    /// Create a generator for frames of `format`, which must be part of the
+    /// pixel format database of `v4l2r::format_info`.
+    ///
+    /// The strides of each memory plane are taken from `format.plane_fmt`, so
+    /// the format negotiated with the driver can be passed as-is. If
+    /// `plane_fmt` is empty, the minimum layout of the format is used.
+    pub fn from_format(format: &Format) -> Result<Self, NewFrameGeneratorError> {
+        let info = format
+            .pixelformat
+            .info()
+            .ok_or(NewFrameGeneratorError::UnsupportedFormat)?;
+        let layouts = if format.plane_fmt.is_empty() {
+            format
+                .plane_sizes()
+                .ok_or(NewFrameGeneratorError::UnsupportedFormat)?
+        } else {
+            format.plane_fmt.clone()
+        };
+        if layouts.len() != info.num_mem_planes
+            || info
+                .color_planes
+                .iter()
+                .any(|plane| plane.bits_per_pixel % 8 != 0)
+        {
+            return Err(NewFrameGeneratorError::InvalidPlanes);
+        }
+        let first_plane = info
+            .color_planes
+            .first()
+            .ok_or(NewFrameGeneratorError::InvalidPlanes)?;
+
+        let mut planes = Vec::new();
+        let mut mem_plane_sizes = vec![0; info.num_mem_planes];
+        for (i, color_plane) in info.color_planes.iter().enumerate() {
+            let (mem_plane, stride) = if info.num_mem_planes == 1 {
+                // All color planes are contiguous, and their stride is derived
+                // from the one of the first plane.
+                let stride = layouts[0].bytesperline * color_plane.bits_per_pixel
+                    / (first_plane.bits_per_pixel * color_plane.horizontal_subsampling);
+                (0, stride as usize)
+            } else {
+                (i, layouts[i].bytesperline as usize)
+            };
+
+            if color_plane
+                .bytesperline(format.width)
+                .map_or(true, |min_stride| stride < min_stride as usize)
+            {
+                return Err(NewFrameGeneratorError::InvalidStride);
+            }
+
+            let height = color_plane.lines(format.height) as usize;
+            planes.push(ColorPlane {
+                mem_plane,
+                offset: mem_plane_sizes[mem_plane],
+                stride,
+                sample_size: color_plane.bits_per_pixel as usize / 8,
+                width: format.width.div_ceil(color_plane.horizontal_subsampling) as usize,
+                height,
+            });
+            mem_plane_sizes[mem_plane] += stride * height;
+        }
+
+        Ok(FrameGenerator {
+            planes,
+            mem_plane_sizes,
             step: 0,
         })
     }
 
+    /// Returns the total number of bytes written for each frame.
     pub fn frame_size(&self) -> usize {
-        self.stride * self.height
+        self.mem_plane_sizes.iter().sum()
     }
 
+    /// Returns the number of bytes written into each memory plane for each
+    /// frame, i.e. the `bytesused` of each plane of the buffer to queue.
+    pub fn plane_sizes(&self) -> &[usize] {
+        &self.mem_plane_sizes
+    }
+
+    /// Write the next frame into `frame`. Only valid for formats using a
+    /// single memory plane.
     pub fn next_frame<S: AsMut<[u8]>>(&mut self, frame: &mut S) -> Result<(), GenerateFrameError> {
-        let frame = frame.as_mut();
+        let mut planes = [frame.as_mut()];
+        self.next_frame_planes(&mut planes)
+    }
 
-        if frame.len() < self.frame_size() {
+    /// Write the next frame into `planes`, which must contain one target per
+    /// memory plane, e.g. the mappings of each plane of an MMAP buffer.
+    pub fn next_frame_planes<S: AsMut<[u8]>>(
+        &mut self,
+        planes: &mut [S],
+    ) -> Result<(), GenerateFrameError> {
+        if planes.len() != self.mem_plane_sizes.len() {
+            return Err(GenerateFrameError::WrongNumberOfPlanes(
+                self.mem_plane_sizes.len(),
+                planes.len(),
+            ));
+        }
+        if planes
+            .iter_mut()
+            .zip(&self.mem_plane_sizes)
+            .any(|(plane, size)| plane.as_mut().len() < *size)
+        {
             return Err(GenerateFrameError::BufferTooSmall);
         }
 
-        self.gen_pattern(frame);
+        for color_plane in &self.planes {
+            let data = &mut planes[color_plane.mem_plane].as_mut()[color_plane.offset..];
+            self.gen_pattern(color_plane, data);
+        }
         self.step = self.step.wrapping_add(1);
 
         Ok(())
     }
 
-    fn gen_pattern(&mut self, frame: &mut [u8]) {
-        frame
-            .chunks_exact_mut(self.stride)
-            .map(|l| &mut l[0..self.width * 3])
+    fn gen_pattern(&self, plane: &ColorPlane, data: &mut [u8]) {
+        data.chunks_mut(plane.stride)
+            .take(plane.height)
+            .map(|l| &mut l[0..plane.width * plane.sample_size])
             .enumerate()
             .for_each(|(y, line)| {
-                line.chunks_exact_mut(3).enumerate().for_each(|(x, pixel)| {
-                    let rgba = self.step.wrapping_add((x ^ y) as u32).to_le_bytes();
-                    pixel[0] = rgba[0];
-                    pixel[1] = rgba[1];
-                    pixel[2] = rgba[2];
-                });
+                line.chunks_exact_mut(plane.sample_size)
+                    .enumerate()
+                    .for_each(|(x, sample)| {
+                        let rgba = self.step.wrapping_add((x ^ y) as u32).to_le_bytes();
+                        for (i, byte) in sample.iter_mut().enumerate() {
+                            *byte = rgba[i % rgba.len()];
+                        }
+                    });
             });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use v4l2r::{Format, PlaneLayout};
+
+    use super::*;
+
+    #[test]
+    fn multi_planar_frame() {
+        let mut format = Format::from((b"NM12", (4, 2)));
+        format.plane_fmt = vec![
+            PlaneLayout {
+                sizeimage: 16,
+                bytesperline: 8,
+            },
+            PlaneLayout {
+                sizeimage: 16,
+                bytesperline: 16,
+            },
+        ];
+        let mut frame_gen = FrameGenerator::from_format(&format).unwrap();
+        assert_eq!(frame_gen.plane_sizes(), &[16, 16]);
+
+        let mut planes = vec![vec![0xffu8; 16], vec![0xffu8; 16]];
+        assert!(matches!(
+            frame_gen.next_frame(&mut planes[0]),
+            Err(GenerateFrameError::WrongNumberOfPlanes(2, 1))
+        ));
+        frame_gen.next_frame_planes(&mut planes).unwrap();
+
+        // Padding bytes after each line are left untouched.
+        assert_eq!(
+            planes[0],
+            [0, 1, 2, 3, 255, 255, 255, 255, 1, 0, 3, 2, 255, 255, 255, 255]
+        );
+        // One line of two 2-byte UV samples.
+        assert_eq!(&planes[1][0..4], &[0, 0, 1, 0]);
+        assert_eq!(&planes[1][4..], &[255; 12]);
+    }
+
+    #[test]
+    fn contiguous_planes() {
+        let mut frame_gen = FrameGenerator::from_format(&Format::from((b"YU12", (4, 4)))).unwrap();
+        assert_eq!(frame_gen.frame_size(), 16 + 4 + 4);
+
+        let mut frame = vec![0u8; frame_gen.frame_size()];
+        frame_gen.next_frame(&mut frame).unwrap();
+        // The U plane starts right after the Y one, with half its stride.
+        assert_eq!(&frame[16..20], &[0, 1, 1, 0]);
+    }
+}
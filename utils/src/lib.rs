@@ -1,2 +1,5 @@
+pub mod alloc;
+pub mod debayer;
 pub mod dmabuf_exporter;
 pub mod framegen;
+pub mod segment;
@@ -0,0 +1,332 @@
+//! A `Write` sink splitting a long-running stream into several files.
+//!
+//! [`SegmentedWriter`] writes into a file until a [`SegmentLimit`] is reached,
+//! then switches to a new one. Each segment is named after the time it has
+//! been created at, so a capture session of several hours results in a series
+//! of `<stem>-<unix time in milliseconds>.<extension>` files that can be
+//! processed or discarded individually.
+//!
+//! Segments are only switched between frames, so a frame is never split across
+//! two files. Each call to `write_all()` or [`SegmentedWriter::write_frame`]
+//! is considered to be one frame, which matches how encoded buffers are usually
+//! written. `write()` and `write_vectored()` never end a frame: clients writing
+//! frames piecemeal with them, or through another writer such as a
+//! `BufWriter`, must mark the end of each frame with
+//! [`SegmentedWriter::end_frame`], otherwise the limit is never checked and
+//! everything is written into the first segment.
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, IoSlice, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// When to switch to a new segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentLimit {
+    /// After this number of frames.
+    Frames(usize),
+    /// Once a segment is at least this number of bytes large.
+    Bytes(u64),
+    /// Once a segment covers at least this duration.
+    Duration(Duration),
+}
+
+struct Segment {
+    file: File,
+    path: PathBuf,
+    created_at: Instant,
+    frames: usize,
+    bytes: u64,
+}
+
+pub struct SegmentedWriter {
+    dir: PathBuf,
+    stem: String,
+    extension: Option<String>,
+    limit: SegmentLimit,
+    current: Option<Segment>,
+    /// Whether the last written byte ended a frame.
+    at_frame_boundary: bool,
+}
+
+impl SegmentedWriter {
+    /// Create a writer whose segments are named after `path`: a `path` of
+    /// `/tmp/capture.fwht` results in segments like
+    /// `/tmp/capture-1697040000123.fwht`.
+    ///
+    /// No file is created until data is written.
+    pub fn new<P: AsRef<Path>>(path: P, limit: SegmentLimit) -> Self {
+        let path = path.as_ref();
+        SegmentedWriter {
+            dir: path
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| PathBuf::from(".")),
+            stem: path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| String::from("segment")),
+            extension: path.extension().map(|e| e.to_string_lossy().into_owned()),
+            limit,
+            current: None,
+            at_frame_boundary: true,
+        }
+    }
+
+    pub fn limit(&self) -> SegmentLimit {
+        self.limit
+    }
+
+    /// Returns the path of the segment currently written into, if any.
+    pub fn current_path(&self) -> Option<&Path> {
+        self.current.as_ref().map(|s| s.path.as_path())
+    }
+
+    /// Write a whole frame made of several slices, e.g. the planes of a
+    /// buffer, into the current segment.
+    pub fn write_frame(&mut self, bufs: &[IoSlice]) -> io::Result<()> {
+        for buf in bufs {
+            self.write_data(buf)?;
+        }
+        self.end_frame();
+
+        Ok(())
+    }
+
+    /// Mark the end of the frame currently being written.
+    pub fn end_frame(&mut self) {
+        if let Some(segment) = &mut self.current {
+            if !self.at_frame_boundary {
+                segment.frames += 1;
+            }
+        }
+        self.at_frame_boundary = true;
+    }
+
+    fn limit_reached(&self, segment: &Segment) -> bool {
+        match self.limit {
+            SegmentLimit::Frames(frames) => segment.frames >= frames,
+            SegmentLimit::Bytes(bytes) => segment.bytes >= bytes,
+            SegmentLimit::Duration(duration) => segment.created_at.elapsed() >= duration,
+        }
+    }
+
+    /// Create the file of a new segment. A numbered suffix is added if a file
+    /// with the same timestamp already exists.
+    fn create_segment(&self) -> io::Result<Segment> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        for index in 0.. {
+            let mut name = format!("{}-{}", self.stem, timestamp);
+            if index > 0 {
+                name.push_str(&format!("-{}", index));
+            }
+            if let Some(extension) = &self.extension {
+                name.push('.');
+                name.push_str(extension);
+            }
+            let path = self.dir.join(name);
+
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    log::debug!("Starting new segment {}", path.display());
+                    return Ok(Segment {
+                        file,
+                        path,
+                        created_at: Instant::now(),
+                        frames: 0,
+                        bytes: 0,
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        unreachable!()
+    }
+
+    /// Write all of `buf` without ending the current frame.
+    fn write_data(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(written) => buf = &buf[written..],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the segment to write the next bytes into, switching to a new
+    /// one if we are between two frames and the current one is complete.
+    fn segment(&mut self) -> io::Result<&mut Segment> {
+        let rotate = match &self.current {
+            None => true,
+            Some(segment) => self.at_frame_boundary && self.limit_reached(segment),
+        };
+
+        if rotate {
+            if let Some(mut segment) = self.current.take() {
+                segment.file.flush()?;
+            }
+            self.current = Some(self.create_segment()?);
+        }
+
+        // Safe because we just created a segment if there was none.
+        Ok(self.current.as_mut().unwrap())
+    }
+}
+
+impl Write for SegmentedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let segment = self.segment()?;
+        let written = segment.file.write(buf)?;
+        segment.bytes += written as u64;
+        self.at_frame_boundary = false;
+
+        Ok(written)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        if bufs.iter().all(|buf| buf.is_empty()) {
+            return Ok(0);
+        }
+
+        let segment = self.segment()?;
+        let written = segment.file.write_vectored(bufs)?;
+        segment.bytes += written as u64;
+        self.at_frame_boundary = false;
+
+        Ok(written)
+    }
+
+    /// Write a whole frame into the current segment.
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_data(buf)?;
+        self.end_frame();
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(segment) => segment.file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    /// Returns an empty directory for the test named `name`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("v4l2r-utils-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Returns the content of the segments in `dir`, in creation order.
+    fn segments(dir: &Path) -> Vec<Vec<u8>> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        // Order by timestamp, then by the suffix of segments created within
+        // the same millisecond.
+        paths.sort_by_key(|p| {
+            let stem = p.file_stem().unwrap().to_string_lossy().into_owned();
+            stem.split('-')
+                .skip(1)
+                .map(|n| n.parse::<u128>().unwrap())
+                .collect::<Vec<_>>()
+        });
+        paths.iter().map(|p| fs::read(p).unwrap()).collect()
+    }
+
+    #[test]
+    fn segment_frames() {
+        let dir = test_dir("segment_frames");
+        let mut writer = SegmentedWriter::new(dir.join("stream.fwht"), SegmentLimit::Frames(2));
+        assert_eq!(writer.current_path(), None);
+
+        for frame in [b"aa", b"bb", b"cc"].iter() {
+            writer.write_all(&frame[..]).unwrap();
+        }
+        // A frame written in several steps stays in the same segment.
+        writer.write_all(b"d").unwrap();
+        assert_eq!(writer.write(b"e").unwrap(), 1);
+        assert_eq!(writer.write(b"e").unwrap(), 1);
+        writer.end_frame();
+        writer.flush().unwrap();
+
+        let path = writer.current_path().unwrap();
+        assert!(path.starts_with(&dir));
+        let name = path.file_name().unwrap().to_string_lossy();
+        assert!(name.starts_with("stream-") && name.ends_with(".fwht"));
+
+        assert_eq!(
+            segments(&dir),
+            vec![b"aabb".to_vec(), b"ccd".to_vec(), b"ee".to_vec()]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn segment_bytes() {
+        let dir = test_dir("segment_bytes");
+        let mut writer = SegmentedWriter::new(dir.join("stream"), SegmentLimit::Bytes(4));
+
+        for frame in [&b"abc"[..], b"def", b"g", b"hijkl"].iter() {
+            writer.write_all(frame).unwrap();
+        }
+        writer.flush().unwrap();
+
+        assert_eq!(segments(&dir), vec![b"abcdef".to_vec(), b"ghijkl".to_vec()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn segment_vectored_frames() {
+        let dir = test_dir("segment_vectored_frames");
+        let mut writer = SegmentedWriter::new(dir.join("stream"), SegmentLimit::Frames(1));
+
+        // The planes of a frame stay in the same segment.
+        writer
+            .write_frame(&[IoSlice::new(b"ab"), IoSlice::new(b""), IoSlice::new(b"c")])
+            .unwrap();
+        writer.write_frame(&[IoSlice::new(b"de")]).unwrap();
+        // Frames written with `write_vectored` need to be ended explicitly.
+        assert_eq!(writer.write_vectored(&[IoSlice::new(b"f")]).unwrap(), 1);
+        assert_eq!(writer.write_vectored(&[IoSlice::new(b"g")]).unwrap(), 1);
+        writer.end_frame();
+        writer.write_all(b"h").unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(
+            segments(&dir),
+            vec![
+                b"abc".to_vec(),
+                b"de".to_vec(),
+                b"fg".to_vec(),
+                b"h".to_vec()
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}